@@ -0,0 +1,15 @@
+// Generates the types of `proto/`, along with the gRPC service of
+// `proto/chat.proto` and its client, with a vendored `protoc` unless one is
+// given with `PROTOC`.
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    if std::env::var_os("PROTOC").is_none() {
+        std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path()?);
+    }
+    // Clients connect channels themselves: the generated `connect` relies on
+    // the 2021 prelude
+    tonic_build::configure()
+        .build_transport(false)
+        .compile_protos(&["proto/chat.proto", "proto/frames.proto"], &["proto"])?;
+
+    Ok(())
+}
@@ -0,0 +1,278 @@
+CREATE TABLE chat_messages (
+    message_id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    user_id INTEGER,
+    room_name TEXT NOT NULL,
+    message TEXT NOT NULL,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    seq INTEGER,
+    edited_at TIMESTAMP,
+    deleted_at TIMESTAMP,
+    deleted_by INTEGER,
+    nickname TEXT,
+    shadowed BOOLEAN NOT NULL DEFAULT 0,
+    flagged BOOLEAN NOT NULL DEFAULT 0,
+    expires_at TIMESTAMP,
+    forwarded_id INTEGER,
+    forwarded_room TEXT,
+    forwarded_user_id INTEGER,
+    forwarded_nick TEXT
+);
+
+CREATE INDEX chat_messages_room_seq ON chat_messages (room_name, seq);
+
+CREATE TABLE users (
+    user_id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    username TEXT UNIQUE,
+    password_hash TEXT,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    totp_secret TEXT,
+    totp_pending_secret TEXT,
+    totp_last_step INTEGER,
+    email TEXT,
+    role TEXT NOT NULL DEFAULT 'member'
+);
+
+-- Nicknames reserved by users: a nickname can only be held by one user at a
+-- time, and never collides with the username of another account.
+CREATE TABLE usernames (
+    name TEXT PRIMARY KEY COLLATE NOCASE NOT NULL,
+    user_id INTEGER UNIQUE NOT NULL,
+    reserved_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL
+);
+
+CREATE TABLE profiles (
+    user_id INTEGER PRIMARY KEY NOT NULL,
+    avatar_url TEXT,
+    bio TEXT,
+    updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL
+);
+
+CREATE TABLE sessions (
+    token_hash TEXT PRIMARY KEY NOT NULL,
+    user_id INTEGER NOT NULL,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    expires_at TIMESTAMP NOT NULL,
+    session_id TEXT,
+    last_used_at TIMESTAMP,
+    user_agent TEXT
+);
+
+CREATE UNIQUE INDEX sessions_session_id ON sessions (session_id);
+
+CREATE TABLE api_tokens (
+    token_id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    user_id INTEGER NOT NULL,
+    name TEXT NOT NULL,
+    token_hash TEXT UNIQUE NOT NULL,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    last_used_at TIMESTAMP,
+    scopes TEXT NOT NULL DEFAULT 'admin'
+);
+
+CREATE TABLE login_failures (
+    counter TEXT PRIMARY KEY NOT NULL,
+    failures INTEGER NOT NULL,
+    last_failure_at INTEGER NOT NULL,
+    locked_until INTEGER
+);
+
+CREATE TABLE password_resets (
+    token_hash TEXT PRIMARY KEY NOT NULL,
+    user_id INTEGER NOT NULL,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    expires_at TIMESTAMP NOT NULL
+);
+
+CREATE TABLE oauth_identities (
+    provider TEXT NOT NULL,
+    external_id TEXT NOT NULL,
+    user_id INTEGER NOT NULL,
+    PRIMARY KEY (provider, external_id)
+);
+
+-- Rooms are recorded once created or first joined.
+CREATE TABLE rooms (
+    room_name TEXT PRIMARY KEY NOT NULL,
+    created_by INTEGER,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    topic TEXT,
+    description TEXT,
+    visibility TEXT NOT NULL DEFAULT 'public',
+    capacity INTEGER,
+    retention_secs INTEGER,
+    password_hash TEXT,
+    read_only INTEGER NOT NULL DEFAULT 0,
+    approval_required INTEGER NOT NULL DEFAULT 0,
+    announcement INTEGER NOT NULL DEFAULT 0
+);
+
+CREATE TABLE room_owners (
+    room_name TEXT NOT NULL,
+    user_id INTEGER NOT NULL,
+    added_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    PRIMARY KEY (room_name, user_id)
+);
+
+-- Users invited into a room. Private rooms only let in their members
+CREATE TABLE room_members (
+    room_name TEXT NOT NULL,
+    user_id INTEGER NOT NULL,
+    invited_by INTEGER,
+    added_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    PRIMARY KEY (room_name, user_id)
+);
+
+-- Invites making users members of a room, by the hash of their token
+CREATE TABLE room_invites (
+    invite_id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    room_name TEXT NOT NULL,
+    token_hash TEXT UNIQUE NOT NULL,
+    created_by INTEGER,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    expires_at TIMESTAMP,
+    max_uses INTEGER,
+    uses INTEGER NOT NULL DEFAULT 0
+);
+
+-- Roles given to users in a single room, on top of their server-wide role
+CREATE TABLE room_roles (
+    room_name TEXT NOT NULL,
+    user_id INTEGER NOT NULL,
+    role TEXT NOT NULL,
+    PRIMARY KEY (room_name, user_id)
+);
+
+-- Users allowed into, or kept out of, a room
+CREATE TABLE room_acl (
+    room_name TEXT NOT NULL,
+    user_id INTEGER NOT NULL,
+    allowed BOOLEAN NOT NULL,
+    PRIMARY KEY (room_name, user_id)
+);
+
+-- Bans and mutes of users in a room, by kind
+CREATE TABLE room_sanctions (
+    room_name TEXT NOT NULL,
+    user_id INTEGER NOT NULL,
+    kind TEXT NOT NULL,
+    issued_by INTEGER,
+    reason TEXT,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    expires_at TIMESTAMP,
+    PRIMARY KEY (room_name, user_id, kind)
+);
+
+-- Reports of messages by users, queued for moderators to review
+CREATE TABLE message_reports (
+    report_id INTEGER PRIMARY KEY,
+    message_id INTEGER NOT NULL,
+    room_name TEXT NOT NULL,
+    reported_by INTEGER NOT NULL,
+    reason TEXT,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    status TEXT NOT NULL DEFAULT 'open',
+    resolved_by INTEGER,
+    resolved_at TIMESTAMP,
+    UNIQUE (message_id, reported_by)
+);
+
+-- Ranges of addresses connections are refused from, in CIDR notation
+CREATE TABLE ip_bans (
+    ban_id INTEGER PRIMARY KEY,
+    cidr TEXT NOT NULL UNIQUE,
+    reason TEXT,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL
+);
+
+CREATE TABLE room_pins (
+    room_name TEXT NOT NULL,
+    message_id INTEGER NOT NULL,
+    pinned_by INTEGER NOT NULL,
+    pinned_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    PRIMARY KEY (room_name, message_id)
+);
+
+CREATE TABLE mentions (
+    message_id INTEGER NOT NULL,
+    user_id INTEGER NOT NULL,
+    room_name TEXT NOT NULL,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    PRIMARY KEY (message_id, user_id)
+);
+
+CREATE TABLE alert_keywords (
+    user_id INTEGER NOT NULL,
+    keyword TEXT COLLATE NOCASE NOT NULL,
+    PRIMARY KEY (user_id, keyword)
+);
+
+CREATE TABLE alerts (
+    alert_id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    user_id INTEGER NOT NULL,
+    message_id INTEGER NOT NULL,
+    room_name TEXT NOT NULL,
+    keyword TEXT NOT NULL,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL
+);
+
+CREATE TABLE queued_events (
+    event_id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    user_id INTEGER NOT NULL,
+    room_name TEXT NOT NULL,
+    message_id INTEGER NOT NULL,
+    event TEXT NOT NULL,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL
+);
+
+CREATE TABLE scheduled_messages (
+    scheduled_id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    room_name TEXT NOT NULL,
+    user_id INTEGER NOT NULL,
+    message TEXT NOT NULL,
+    deliver_at TIMESTAMP NOT NULL,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL
+);
+
+-- Announcement rooms followed by rooms, which their messages are cross-posted
+-- to
+CREATE TABLE announcement_follows (
+    room_name TEXT NOT NULL,
+    source_room TEXT NOT NULL,
+    followed_by INTEGER,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    PRIMARY KEY (room_name, source_room)
+);
+
+-- Options of polls, sent as messages asking their question
+CREATE TABLE poll_options (
+    message_id INTEGER NOT NULL,
+    room_name TEXT NOT NULL,
+    option_index INTEGER NOT NULL,
+    text TEXT NOT NULL,
+    PRIMARY KEY (message_id, option_index)
+);
+
+CREATE TABLE poll_votes (
+    message_id INTEGER NOT NULL,
+    room_name TEXT NOT NULL,
+    user_id INTEGER NOT NULL,
+    option_index INTEGER NOT NULL,
+    voted_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    PRIMARY KEY (message_id, user_id)
+);
+
+CREATE TABLE notification_levels (
+    user_id INTEGER NOT NULL,
+    room_name TEXT NOT NULL,
+    level TEXT NOT NULL,
+    PRIMARY KEY (user_id, room_name)
+);
+
+CREATE TABLE read_markers (
+    room_name TEXT NOT NULL,
+    user_id INTEGER NOT NULL,
+    message_id INTEGER NOT NULL,
+    seq INTEGER,
+    read_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    PRIMARY KEY (room_name, user_id)
+);
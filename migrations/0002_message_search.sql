@@ -0,0 +1,25 @@
+-- Full-text index of the text of messages, kept up to date as messages are
+-- written, edited and deleted. Rows of the index are those of chat_messages,
+-- by message ID.
+CREATE VIRTUAL TABLE message_search USING fts5(
+    message,
+    content = 'chat_messages',
+    content_rowid = 'message_id'
+);
+
+INSERT INTO message_search (message_search) VALUES ('rebuild');
+
+CREATE TRIGGER message_search_insert AFTER INSERT ON chat_messages BEGIN
+    INSERT INTO message_search (rowid, message) VALUES (new.message_id, new.message);
+END;
+
+CREATE TRIGGER message_search_delete AFTER DELETE ON chat_messages BEGIN
+    INSERT INTO message_search (message_search, rowid, message)
+        VALUES ('delete', old.message_id, old.message);
+END;
+
+CREATE TRIGGER message_search_update AFTER UPDATE OF message ON chat_messages BEGIN
+    INSERT INTO message_search (message_search, rowid, message)
+        VALUES ('delete', old.message_id, old.message);
+    INSERT INTO message_search (rowid, message) VALUES (new.message_id, new.message);
+END;
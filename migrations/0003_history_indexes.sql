@@ -0,0 +1,5 @@
+-- Messages of a room are listed by time, and those of a user looked up on
+-- their own, such as when their account is deleted.
+CREATE INDEX chat_messages_room_created ON chat_messages (room_name, created_at);
+
+CREATE INDEX chat_messages_user ON chat_messages (user_id);
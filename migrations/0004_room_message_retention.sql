@@ -0,0 +1,3 @@
+-- Rooms may keep only their most recent messages, besides only those recent
+-- enough.
+ALTER TABLE rooms ADD COLUMN retention_messages INTEGER;
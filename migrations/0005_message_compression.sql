@@ -0,0 +1,22 @@
+-- Messages may be stored compressed, as blobs: the full-text index is kept
+-- up to date with their text, through `message_text`, however they are stored.
+DROP TRIGGER message_search_insert;
+DROP TRIGGER message_search_delete;
+DROP TRIGGER message_search_update;
+
+CREATE TRIGGER message_search_insert AFTER INSERT ON chat_messages BEGIN
+    INSERT INTO message_search (rowid, message)
+        VALUES (new.message_id, message_text(new.message));
+END;
+
+CREATE TRIGGER message_search_delete AFTER DELETE ON chat_messages BEGIN
+    INSERT INTO message_search (message_search, rowid, message)
+        VALUES ('delete', old.message_id, message_text(old.message));
+END;
+
+CREATE TRIGGER message_search_update AFTER UPDATE OF message ON chat_messages BEGIN
+    INSERT INTO message_search (message_search, rowid, message)
+        VALUES ('delete', old.message_id, message_text(old.message));
+    INSERT INTO message_search (rowid, message)
+        VALUES (new.message_id, message_text(new.message));
+END;
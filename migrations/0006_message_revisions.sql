@@ -0,0 +1,16 @@
+-- Versions of messages replaced by edits, which moderators may look back on.
+CREATE TABLE message_revisions (
+    revision_id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    message_id INTEGER NOT NULL,
+    message TEXT NOT NULL,
+    -- When this version was written, by sending or editing the message
+    created_at TIMESTAMP NOT NULL,
+    replaced_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL
+);
+
+CREATE INDEX message_revisions_message ON message_revisions (message_id, revision_id);
+
+-- Revisions go along with their message, however it is deleted.
+CREATE TRIGGER message_revisions_delete AFTER DELETE ON chat_messages BEGIN
+    DELETE FROM message_revisions WHERE message_id = old.message_id;
+END;
@@ -0,0 +1,14 @@
+-- Incoming webhooks posting to a room as a bot, by the hash of their token.
+CREATE TABLE room_hooks (
+    hook_id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    room_name TEXT NOT NULL,
+    token_hash TEXT UNIQUE NOT NULL,
+    -- Nickname the bot posts as, unless a payload gives another
+    name TEXT NOT NULL,
+    -- Account of the bot
+    user_id INTEGER NOT NULL,
+    created_by INTEGER,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL
+);
+
+CREATE INDEX room_hooks_room ON room_hooks (room_name, hook_id);
@@ -0,0 +1,3 @@
+-- JWTs issued to a user before this time, in seconds since the epoch, are no
+-- longer accepted, e.g. once their password was reset.
+ALTER TABLE users ADD COLUMN tokens_valid_after INTEGER;
@@ -0,0 +1,12 @@
+-- Guests, given an account for each connection made without credentials, by
+-- the address they connected from. Their accounts are deleted a while after
+-- they were last seen online.
+CREATE TABLE guests (
+    user_id INTEGER PRIMARY KEY NOT NULL,
+    addr TEXT,
+    last_seen_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL
+);
+
+-- Address of the guest a sanction was imposed on, through which it applies to
+-- every guest connecting from there.
+ALTER TABLE room_sanctions ADD COLUMN addr TEXT;
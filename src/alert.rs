@@ -0,0 +1,263 @@
+use anyhow::anyhow;
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::{authz, compression::MessageText};
+
+// Most keywords a user may watch.
+pub const MAX_KEYWORDS: usize = 20;
+pub const MAX_KEYWORD_LENGTH: usize = 64;
+
+// Most alerts listed at once, newest first.
+pub const MAX_LISTED_ALERTS: usize = 100;
+
+// Request body of the route replacing the keywords a user watches.
+#[derive(Debug, Default, Deserialize, ToSchema)]
+pub struct KeywordUpdate {
+    pub keywords: Vec<String>,
+}
+
+impl KeywordUpdate {
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        if self.keywords.len() > MAX_KEYWORDS {
+            return Err(anyhow!("At most {} keywords may be watched", MAX_KEYWORDS));
+        }
+
+        for keyword in &self.keywords {
+            if keyword.trim().is_empty() || keyword.chars().count() > MAX_KEYWORD_LENGTH {
+                return Err(anyhow!(
+                    "Keywords must be between 1 and {} characters long",
+                    MAX_KEYWORD_LENGTH
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// A message that matched one of the keywords a user watches.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Alert {
+    pub id: i64,
+    pub room: String,
+    pub message_id: i64,
+    // Author of the message, and what it said
+    pub author_id: usize,
+    pub text: String,
+    pub keyword: String,
+    pub created_at: String,
+}
+
+// Replaces the keywords `user_id` watches. Keywords are kept trimmed, and
+// once regardless of case.
+pub fn set_keywords(
+    conn: &Connection,
+    user_id: usize,
+    keywords: &[String],
+) -> Result<(), rusqlite::Error> {
+    conn.execute(
+        "DELETE FROM alert_keywords WHERE user_id = ?1",
+        params![user_id],
+    )?;
+    for keyword in keywords {
+        conn.execute(
+            "INSERT OR IGNORE INTO alert_keywords (user_id, keyword) VALUES (?1, ?2)",
+            params![user_id, keyword.trim()],
+        )?;
+    }
+
+    Ok(())
+}
+
+// Keywords `user_id` watches, in the order they were given.
+pub fn keywords(conn: &Connection, user_id: usize) -> Result<Vec<String>, rusqlite::Error> {
+    let mut stmt = conn
+        .prepare_cached("SELECT keyword FROM alert_keywords WHERE user_id = ?1 ORDER BY rowid")?;
+    let keywords = stmt
+        .query_map(params![user_id], |row| row.get(0))?
+        .collect();
+
+    keywords
+}
+
+// Records an alert for each user watching a keyword found in message
+// `message_id`, sent to `room_name` by `author_id`, as whole words regardless
+// of case. Only users who may join the room, other than the author, are
+// alerted, once per message. Returns who was alerted, and of what.
+pub fn record_alerts(
+    conn: &Connection,
+    message_id: i64,
+    room_name: &str,
+    author_id: usize,
+    text: &str,
+) -> Result<Vec<(usize, Alert)>, rusqlite::Error> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT user_id, keyword FROM alert_keywords WHERE user_id != ?1
+            ORDER BY user_id, rowid",
+    )?;
+    let watched = stmt
+        .query_map(params![author_id], |row| {
+            Ok((row.get::<_, usize>(0)?, row.get::<_, String>(1)?))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let text_lower = text.to_lowercase();
+    let mut alerted = Vec::new();
+    for (user_id, keyword) in watched {
+        let already_alerted = alerted.last().is_some_and(|(last, _)| *last == user_id);
+        if already_alerted || !contains_word(&text_lower, &keyword.to_lowercase()) {
+            continue;
+        }
+        if !authz::may_join(conn, user_id, room_name)? {
+            continue;
+        }
+
+        conn.execute(
+            "INSERT INTO alerts (user_id, message_id, room_name, keyword)
+                VALUES (?1, ?2, ?3, ?4)",
+            params![user_id, message_id, room_name, keyword],
+        )?;
+        let id = conn.last_insert_rowid();
+        let alert = Alert {
+            id,
+            room: String::from(room_name),
+            message_id,
+            author_id,
+            text: String::from(text),
+            keyword,
+            created_at: conn.query_row(
+                "SELECT created_at FROM alerts WHERE alert_id = ?1",
+                params![id],
+                |row| row.get(0),
+            )?,
+        };
+        alerted.push((user_id, alert));
+    }
+
+    Ok(alerted)
+}
+
+// The latest alerts of `user_id`, newest first, including those raised while
+// they were offline. Alerts of messages deleted since are left out.
+pub fn alerts(conn: &Connection, user_id: usize) -> Result<Vec<Alert>, rusqlite::Error> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT a.alert_id, a.room_name, a.message_id, m.user_id, m.message, a.keyword,
+                a.created_at
+            FROM alerts a
+            JOIN chat_messages m ON m.message_id = a.message_id
+            WHERE a.user_id = ?1 AND m.deleted_at IS NULL
+            ORDER BY a.alert_id DESC
+            LIMIT ?2",
+    )?;
+    let alerts = stmt
+        .query_map(params![user_id, MAX_LISTED_ALERTS], |row| {
+            Ok(Alert {
+                id: row.get(0)?,
+                room: row.get(1)?,
+                message_id: row.get(2)?,
+                author_id: row.get(3)?,
+                text: row.get::<_, MessageText>(4)?.into(),
+                keyword: row.get(5)?,
+                created_at: row.get(6)?,
+            })
+        })?
+        .collect();
+
+    alerts
+}
+
+// Whether `keyword` appears in `text` as whole words, i.e. not as part of a
+// longer word. Both are expected lowercased.
+fn contains_word(text: &str, keyword: &str) -> bool {
+    let is_word_char = |c: char| c.is_alphanumeric() || c == '_';
+
+    text.match_indices(keyword).any(|(start, _)| {
+        let end = start + keyword.len();
+        !text[..start].chars().next_back().is_some_and(is_word_char)
+            && !text[end..].chars().next().is_some_and(is_word_char)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+
+    #[test]
+    fn test_validate() {
+        let update = KeywordUpdate {
+            keywords: vec![String::from("rust"), String::from("release notes")],
+        };
+        assert!(update.validate().is_ok());
+
+        let update = KeywordUpdate {
+            keywords: vec![String::from("  ")],
+        };
+        assert!(update.validate().is_err());
+
+        let update = KeywordUpdate {
+            keywords: vec![String::from("a"); MAX_KEYWORDS + 1],
+        };
+        assert!(update.validate().is_err());
+    }
+
+    #[test]
+    fn test_contains_word() {
+        assert!(contains_word("rust is fun", "rust"));
+        assert!(contains_word("i like rust!", "rust"));
+        assert!(contains_word("see the release notes.", "release notes"));
+        assert!(!contains_word("trusty", "rust"));
+        assert!(!contains_word("rusty, but rust_lang", "rust"));
+    }
+
+    #[test]
+    fn test_alerts() {
+        let conn = Connection::open_in_memory().unwrap();
+        db::init_schema(&conn).unwrap();
+
+        set_keywords(
+            &conn,
+            2,
+            &[
+                String::from("Rust"),
+                String::from(" deploy "),
+                String::from("rust"),
+            ],
+        )
+        .unwrap();
+        set_keywords(&conn, 3, &[String::from("deploy")]).unwrap();
+        assert_eq!(keywords(&conn, 2).unwrap(), vec!["Rust", "deploy"]);
+
+        conn.execute(
+            "INSERT INTO chat_messages (message_id, user_id, room_name, message) VALUES
+                (1, 1, 'room1', 'Rust deploy today'), (2, 3, 'room1', 'deploying')",
+            [],
+        )
+        .unwrap();
+
+        // Users are alerted once per message, and not of their own messages
+        let alerted = record_alerts(&conn, 1, "room1", 1, "Rust deploy today").unwrap();
+        assert_eq!(
+            alerted
+                .iter()
+                .map(|(user_id, alert)| (*user_id, alert.keyword.as_str()))
+                .collect::<Vec<_>>(),
+            vec![(2, "Rust"), (3, "deploy")]
+        );
+        assert!(record_alerts(&conn, 2, "room1", 3, "deploying")
+            .unwrap()
+            .is_empty());
+
+        let listed = alerts(&conn, 3).unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].message_id, 1);
+        assert_eq!(listed[0].author_id, 1);
+        assert_eq!(listed[0].text, "Rust deploy today");
+
+        // Alerts of deleted messages are no longer listed
+        db::delete_message(&conn, 1, "room1", 1, None).unwrap();
+        assert!(alerts(&conn, 3).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,202 @@
+use anyhow::anyhow;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::{authz, room};
+
+// Most announcement rooms a room may follow.
+pub const MAX_FOLLOWED_PER_ROOM: usize = 20;
+
+// Request body of the route making a room an announcement room, or a regular
+// room again.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct AnnouncementUpdate {
+    pub announcement: bool,
+}
+
+// An announcement room followed by a room, its messages being cross-posted to
+// it.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Follow {
+    pub source: String,
+    // Unknown if the user who followed it has since been deleted
+    pub followed_by: Option<usize>,
+    pub created_at: String,
+}
+
+// Makes `room_name` an announcement room, or a regular room again. Returns
+// whether the room exists.
+pub fn set_announcement(
+    conn: &Connection,
+    room_name: &str,
+    announcement: bool,
+) -> Result<bool, rusqlite::Error> {
+    let updated = conn.execute(
+        "UPDATE rooms SET announcement = ?2 WHERE room_name = ?1",
+        params![room_name, announcement],
+    )?;
+
+    Ok(updated > 0)
+}
+
+// Has `room_name` follow announcement room `source` on behalf of `user_id`,
+// returning the follow. Returns None if either room does not exist, or if the
+// user may not join `source`, and an error if `source` is not an announcement
+// room or `room_name` already follows too many.
+pub fn follow(
+    conn: &Connection,
+    room_name: &str,
+    source: &str,
+    user_id: usize,
+) -> Result<Option<Follow>, anyhow::Error> {
+    if room::settings(conn, room_name)?.is_none() || !authz::may_join(conn, user_id, source)? {
+        return Ok(None);
+    }
+    match room::settings(conn, source)? {
+        Some(settings) if settings.announcement => {}
+        Some(_) => return Err(anyhow!("Room {} is not an announcement room", source)),
+        None => return Ok(None),
+    }
+    if room_name == source {
+        return Err(anyhow!("Rooms can not follow themselves"));
+    }
+
+    let followed = following(conn, room_name)?;
+    if followed.len() >= MAX_FOLLOWED_PER_ROOM && !followed.iter().any(|f| f.source == source) {
+        return Err(anyhow!(
+            "Rooms can not follow more than {} announcement rooms",
+            MAX_FOLLOWED_PER_ROOM
+        ));
+    }
+
+    // Following a room again keeps the first follow
+    conn.execute(
+        "INSERT OR IGNORE INTO announcement_follows (room_name, source_room, followed_by)
+            VALUES (?1, ?2, ?3)",
+        params![room_name, source, user_id],
+    )?;
+
+    let follow = conn
+        .query_row(
+            &format!(
+                "SELECT {} FROM announcement_follows WHERE room_name = ?1 AND source_room = ?2",
+                FOLLOW_COLUMNS
+            ),
+            params![room_name, source],
+            follow_from_row,
+        )
+        .optional()?;
+
+    Ok(follow)
+}
+
+// Stops `room_name` from following `source`, returning whether it did.
+pub fn unfollow(conn: &Connection, room_name: &str, source: &str) -> Result<bool, rusqlite::Error> {
+    let deleted = conn.execute(
+        "DELETE FROM announcement_follows WHERE room_name = ?1 AND source_room = ?2",
+        params![room_name, source],
+    )?;
+
+    Ok(deleted > 0)
+}
+
+// Announcement rooms `room_name` follows, oldest follow first.
+pub fn following(conn: &Connection, room_name: &str) -> Result<Vec<Follow>, rusqlite::Error> {
+    let mut stmt = conn.prepare_cached(&format!(
+        "SELECT {} FROM announcement_follows WHERE room_name = ?1 ORDER BY created_at, rowid",
+        FOLLOW_COLUMNS
+    ))?;
+    let follows = stmt
+        .query_map(params![room_name], follow_from_row)?
+        .collect();
+
+    follows
+}
+
+// Rooms the messages of `source` are cross-posted to, by name. None are
+// unless it is an announcement room.
+pub fn followers(conn: &Connection, source: &str) -> Result<Vec<String>, rusqlite::Error> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT f.room_name FROM announcement_follows f
+            JOIN rooms r ON r.room_name = f.source_room
+            WHERE f.source_room = ?1 AND r.announcement
+            ORDER BY f.room_name",
+    )?;
+    let followers = stmt.query_map(params![source], |row| row.get(0))?.collect();
+
+    followers
+}
+
+const FOLLOW_COLUMNS: &str = "source_room, followed_by, created_at";
+
+fn follow_from_row(row: &rusqlite::Row) -> Result<Follow, rusqlite::Error> {
+    Ok(Follow {
+        source: row.get(0)?,
+        followed_by: row.get(1)?,
+        created_at: row.get(2)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{auth, db};
+
+    fn setup() -> (Connection, usize) {
+        let conn = Connection::open_in_memory().unwrap();
+        db::init_schema(&conn).unwrap();
+        let alice = auth::create_user(&conn, "alice", "hash").unwrap().unwrap();
+        for room_name in &["news", "room1", "room2"] {
+            room::record_room(&conn, room_name, alice, true).unwrap();
+        }
+
+        (conn, alice)
+    }
+
+    #[test]
+    fn test_follow() {
+        let (conn, alice) = setup();
+
+        // Only announcement rooms may be followed, by other rooms
+        assert!(follow(&conn, "room1", "news", alice).is_err());
+        assert!(set_announcement(&conn, "news", true).unwrap());
+        assert!(!set_announcement(&conn, "nowhere", true).unwrap());
+        assert!(follow(&conn, "news", "news", alice).is_err());
+        assert!(follow(&conn, "room1", "nowhere", alice).unwrap().is_none());
+        assert!(follow(&conn, "nowhere", "news", alice).unwrap().is_none());
+
+        let followed = follow(&conn, "room1", "news", alice).unwrap().unwrap();
+        assert_eq!(followed.source, "news");
+        assert_eq!(followed.followed_by, Some(alice));
+        assert_eq!(
+            follow(&conn, "room1", "news", alice).unwrap(),
+            Some(followed)
+        );
+        follow(&conn, "room2", "news", alice).unwrap();
+        assert_eq!(following(&conn, "room1").unwrap().len(), 1);
+        assert_eq!(followers(&conn, "news").unwrap(), vec!["room1", "room2"]);
+
+        // Rooms no longer announcing anything have their follows kept, unused
+        set_announcement(&conn, "news", false).unwrap();
+        assert!(followers(&conn, "news").unwrap().is_empty());
+        set_announcement(&conn, "news", true).unwrap();
+
+        assert!(unfollow(&conn, "room1", "news").unwrap());
+        assert!(!unfollow(&conn, "room1", "news").unwrap());
+        assert_eq!(followers(&conn, "news").unwrap(), vec!["room2"]);
+    }
+
+    #[test]
+    fn test_follow_limit() {
+        let (conn, alice) = setup();
+
+        for i in 0..=MAX_FOLLOWED_PER_ROOM {
+            let source = format!("source{}", i);
+            room::record_room(&conn, &source, alice, true).unwrap();
+            set_announcement(&conn, &source, true).unwrap();
+            let followed = follow(&conn, "room1", &source, alice);
+            assert_eq!(followed.is_ok(), i < MAX_FOLLOWED_PER_ROOM);
+        }
+    }
+}
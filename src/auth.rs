@@ -0,0 +1,932 @@
+use std::{
+    collections::HashSet,
+    net::IpAddr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::anyhow;
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use rand::RngCore;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use utoipa::ToSchema;
+
+use crate::{
+    db::{self, DbTx},
+    guest,
+};
+
+use self::scope::Scope;
+
+pub mod api_token;
+pub mod oauth;
+pub mod reset;
+pub mod scope;
+pub mod throttle;
+pub mod totp;
+
+pub const MIN_PASSWORD_LENGTH: usize = 8;
+pub const MAX_USERNAME_LENGTH: usize = 32;
+pub const MAX_EMAIL_LENGTH: usize = 254;
+
+// Author of the messages of deleted users that were kept. User IDs start at 1,
+// so it is never given out.
+pub const DELETED_USER_ID: usize = 0;
+
+// Name of the cookie holding the session token issued on login.
+pub const SESSION_COOKIE: &str = "session";
+
+// Request body of the registration, login and second factor enrollment routes.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+    // Second factor code, for users that enrolled one
+    #[serde(default)]
+    pub code: Option<String>,
+    // Address password reset tokens are sent to, given on registration
+    #[serde(default)]
+    pub email: Option<String>,
+}
+
+// Hashes `password` with a fresh salt, in PHC string format.
+// This is deliberately slow, and should not be run on the async executor.
+pub fn hash_password(password: &str) -> Result<String, anyhow::Error> {
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|e| anyhow!("Failed to hash password: {}", e))?;
+
+    Ok(hash.to_string())
+}
+
+// Checks `password` against a hash produced by `hash_password`.
+// This is deliberately slow, and should not be run on the async executor.
+pub fn verify_password(password: &str, password_hash: &str) -> bool {
+    match PasswordHash::new(password_hash) {
+        Ok(hash) => Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok(),
+        Err(_) => false,
+    }
+}
+
+// Generates a random token, hex-encoded.
+pub fn new_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+// Tokens are only ever stored hashed, so that a leaked DB can not be used to
+// impersonate users.
+pub fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+// Checks that a username is acceptable for registration.
+pub fn validate_username(username: &str) -> Result<(), anyhow::Error> {
+    if username.is_empty() || username.len() > MAX_USERNAME_LENGTH {
+        return Err(anyhow!(
+            "Username must be between 1 and {} characters long",
+            MAX_USERNAME_LENGTH
+        ));
+    }
+
+    if !username
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        return Err(anyhow!(
+            "Username may only contain letters, digits, '_' and '-'"
+        ));
+    }
+
+    if guest::is_guest_name(username) {
+        return Err(anyhow!(
+            "Usernames starting with '{}' are reserved for guests",
+            guest::GUEST_PREFIX
+        ));
+    }
+
+    Ok(())
+}
+
+// What becomes of the messages of a deleted user.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum MessageRetention {
+    // Messages stay in history, attributed to `DELETED_USER_ID`
+    #[default]
+    Anonymize,
+    // Messages are removed from history
+    Delete,
+}
+
+// Checks that a password is acceptable for registration.
+pub fn validate_password(password: &str) -> Result<(), anyhow::Error> {
+    if password.len() < MIN_PASSWORD_LENGTH {
+        return Err(anyhow!(
+            "Password must be at least {} characters long",
+            MIN_PASSWORD_LENGTH
+        ));
+    }
+
+    Ok(())
+}
+
+// Checks that an email address looks deliverable. Whether it actually is only
+// shows once a password reset is sent to it.
+pub fn validate_email(email: &str) -> Result<(), anyhow::Error> {
+    let valid = match email.split_once('@') {
+        Some((local, domain)) => {
+            !local.is_empty()
+                && domain.contains('.')
+                && !domain.starts_with('.')
+                && !domain.ends_with('.')
+        }
+        None => false,
+    };
+
+    if !valid || email.len() > MAX_EMAIL_LENGTH || email.chars().any(char::is_whitespace) {
+        return Err(anyhow!("Invalid email address"));
+    }
+
+    Ok(())
+}
+
+// Registers a new user, returning its ID, or `None` if `username` is taken.
+pub fn create_user(
+    conn: &Connection,
+    username: &str,
+    password_hash: &str,
+) -> Result<Option<usize>, rusqlite::Error> {
+    // Usernames may not collide with the nickname of another user either
+    let inserted = conn.execute(
+        "INSERT OR IGNORE INTO users (username, password_hash)
+            SELECT ?1, ?2 WHERE NOT EXISTS (SELECT 1 FROM usernames WHERE name = ?1)",
+        params![username, password_hash],
+    )?;
+
+    if inserted == 0 {
+        return Ok(None);
+    }
+
+    Ok(Some(conn.last_insert_rowid() as usize))
+}
+
+// Sets the address password reset tokens are sent to for `user_id`.
+pub fn set_email(conn: &Connection, user_id: usize, email: &str) -> Result<(), rusqlite::Error> {
+    conn.execute(
+        "UPDATE users SET email = ?1 WHERE user_id = ?2",
+        params![email, user_id],
+    )?;
+
+    Ok(())
+}
+
+// Creates an anonymous user connecting from `addr`, returning its ID.
+// Connections without credentials are given one of these, so that their IDs
+// never collide with those of registered users.
+pub fn create_guest(conn: &Connection, addr: Option<IpAddr>) -> Result<usize, rusqlite::Error> {
+    conn.execute("INSERT INTO users (username) VALUES (NULL)", [])?;
+    let user_id = conn.last_insert_rowid() as usize;
+    conn.execute(
+        "INSERT INTO guests (user_id, addr) VALUES (?1, ?2)",
+        params![user_id, addr.map(|addr| addr.to_string())],
+    )?;
+
+    Ok(user_id)
+}
+
+// Deletes the guests last seen online more than `retention` ago, those in
+// `online` being seen now, along with their nicknames and what they were
+// given. Returns how many were deleted. Their messages are kept, as IDs are
+// never given out again, and sanctions on them keep applying to their address.
+pub fn expire_guests(
+    conn: &Connection,
+    online: &HashSet<usize>,
+    retention: Duration,
+) -> Result<usize, rusqlite::Error> {
+    let mut stmt = conn
+        .prepare_cached("UPDATE guests SET last_seen_at = CURRENT_TIMESTAMP WHERE user_id = ?1")?;
+    for user_id in online {
+        stmt.execute(params![user_id])?;
+    }
+
+    let mut stmt = conn
+        .prepare_cached("SELECT user_id FROM guests WHERE last_seen_at <= datetime('now', ?1)")?;
+    let expired = stmt
+        .query_map(
+            params![format!("-{} seconds", retention.as_secs())],
+            |row| row.get::<_, usize>(0),
+        )?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    for user_id in &expired {
+        for table in &[
+            "users",
+            "guests",
+            "usernames",
+            "profiles",
+            "room_roles",
+            "room_acl",
+            "room_members",
+            "read_markers",
+            "mentions",
+            "alerts",
+            "notification_levels",
+            "queued_events",
+        ] {
+            conn.execute(
+                &format!("DELETE FROM {} WHERE user_id = ?1", table),
+                params![user_id],
+            )?;
+        }
+    }
+
+    Ok(expired.len())
+}
+
+// Whether `user_id` exists, as a registered user or guest.
+pub fn user_exists(conn: &Connection, user_id: usize) -> Result<bool, rusqlite::Error> {
+    conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM users WHERE user_id = ?1)",
+        params![user_id],
+        |row| row.get(0),
+    )
+}
+
+// Deletes `user_id` along with their sessions, nickname, profile, roles, room
+// access, ownership and sanctions, and linked accounts, returning whether they
+// existed. Their messages, and the copies of them forwarded to other rooms,
+// are either kept without an author, or deleted.
+pub fn delete_user(
+    conn: &Connection,
+    user_id: usize,
+    messages: MessageRetention,
+) -> Result<bool, rusqlite::Error> {
+    if conn.execute("DELETE FROM users WHERE user_id = ?1", params![user_id])? == 0 {
+        return Ok(false);
+    }
+
+    for table in &[
+        "usernames",
+        "profiles",
+        "sessions",
+        "oauth_identities",
+        "password_resets",
+        "api_tokens",
+        "room_roles",
+        "room_acl",
+        "room_owners",
+        "room_members",
+        "room_sanctions",
+        "read_markers",
+        "mentions",
+        "alert_keywords",
+        "alerts",
+        "notification_levels",
+        "queued_events",
+        "scheduled_messages",
+        "poll_votes",
+    ] {
+        conn.execute(
+            &format!("DELETE FROM {} WHERE user_id = ?1", table),
+            params![user_id],
+        )?;
+    }
+
+    // Members they invited keep their membership, and their invites stay valid
+    conn.execute(
+        "UPDATE room_members SET invited_by = NULL WHERE invited_by = ?1",
+        params![user_id],
+    )?;
+    conn.execute(
+        "UPDATE room_invites SET created_by = NULL WHERE created_by = ?1",
+        params![user_id],
+    )?;
+    conn.execute(
+        "UPDATE room_sanctions SET issued_by = NULL WHERE issued_by = ?1",
+        params![user_id],
+    )?;
+    conn.execute(
+        "DELETE FROM message_reports WHERE reported_by = ?1",
+        params![user_id],
+    )?;
+    conn.execute(
+        "UPDATE message_reports SET resolved_by = NULL WHERE resolved_by = ?1",
+        params![user_id],
+    )?;
+
+    match messages {
+        MessageRetention::Anonymize => {
+            conn.execute(
+                "UPDATE chat_messages SET user_id = ?1, nickname = NULL WHERE user_id = ?2",
+                params![DELETED_USER_ID, user_id],
+            )?;
+            conn.execute(
+                "UPDATE chat_messages SET forwarded_user_id = ?1, forwarded_nick = NULL
+                    WHERE forwarded_user_id = ?2",
+                params![DELETED_USER_ID, user_id],
+            )?;
+        }
+        MessageRetention::Delete => {
+            for table in &[
+                "room_pins",
+                "message_reports",
+                "mentions",
+                "alerts",
+                "queued_events",
+            ] {
+                conn.execute(
+                    &format!(
+                        "DELETE FROM {} WHERE message_id IN (
+                            SELECT message_id FROM chat_messages
+                                WHERE user_id = ?1 OR forwarded_user_id = ?1
+                        )",
+                        table
+                    ),
+                    params![user_id],
+                )?;
+            }
+            conn.execute(
+                "DELETE FROM chat_messages WHERE user_id = ?1 OR forwarded_user_id = ?1",
+                params![user_id],
+            )?;
+        }
+    }
+
+    Ok(true)
+}
+
+// Looks up the ID and password hash of a registered user.
+pub fn find_user(
+    conn: &Connection,
+    username: &str,
+) -> Result<Option<(usize, String)>, rusqlite::Error> {
+    conn.query_row(
+        "SELECT user_id, password_hash FROM users
+            WHERE username = ?1 AND password_hash IS NOT NULL",
+        params![username],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )
+    .optional()
+}
+
+// A valid server-side session, identified by the token in its cookie.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Session {
+    // Identifies the session to its user, who may list and end it
+    pub id: String,
+    pub token: String,
+    pub user_id: usize,
+}
+
+// A session as listed to its user.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct SessionInfo {
+    pub id: String,
+    pub created_at: String,
+    pub last_used_at: Option<String>,
+    pub expires_at: String,
+    // User agent of the client that logged in
+    pub user_agent: Option<String>,
+    // Number of WebSocket connections opened with the session
+    pub connections: usize,
+}
+
+// Starts a session for `user_id` lasting `ttl`, returning its token.
+// Expired sessions are cleaned up along the way.
+pub fn create_session(
+    conn: &Connection,
+    user_id: usize,
+    ttl: Duration,
+    user_agent: Option<&str>,
+) -> Result<String, rusqlite::Error> {
+    conn.execute(
+        "DELETE FROM sessions WHERE expires_at <= datetime('now')",
+        [],
+    )?;
+
+    let mut session_id = [0u8; 8];
+    rand::thread_rng().fill_bytes(&mut session_id);
+
+    let token = new_token();
+    conn.execute(
+        "INSERT INTO sessions (token_hash, user_id, expires_at, session_id, user_agent)
+            VALUES (?1, ?2, datetime('now', ?3), ?4, ?5)",
+        params![
+            hash_token(&token),
+            user_id,
+            ttl_modifier(ttl),
+            hex::encode(session_id),
+            user_agent
+        ],
+    )?;
+
+    Ok(token)
+}
+
+// Looks up the session of `token`, provided it has not expired, and renews
+// it for another `ttl`.
+pub fn renew_session(
+    conn: &Connection,
+    token: &str,
+    ttl: Duration,
+) -> Result<Option<Session>, rusqlite::Error> {
+    let token_hash = hash_token(token);
+    let renewed = conn.execute(
+        "UPDATE sessions SET expires_at = datetime('now', ?2), last_used_at = CURRENT_TIMESTAMP
+            WHERE token_hash = ?1 AND expires_at > datetime('now')",
+        params![token_hash, ttl_modifier(ttl)],
+    )?;
+
+    if renewed == 0 {
+        return Ok(None);
+    }
+
+    let (id, user_id) = conn.query_row(
+        "SELECT session_id, user_id FROM sessions WHERE token_hash = ?1",
+        params![token_hash],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )?;
+
+    Ok(Some(Session {
+        id,
+        token: String::from(token),
+        user_id,
+    }))
+}
+
+// Sessions of `user_id` that have not expired, oldest first. Their
+// connections are left for the caller to count.
+pub fn user_sessions(
+    conn: &Connection,
+    user_id: usize,
+) -> Result<Vec<SessionInfo>, rusqlite::Error> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT session_id, created_at, last_used_at, expires_at, user_agent FROM sessions
+            WHERE user_id = ?1 AND expires_at > datetime('now')
+            ORDER BY created_at, rowid",
+    )?;
+    let sessions = stmt
+        .query_map(params![user_id], |row| {
+            Ok(SessionInfo {
+                id: row.get(0)?,
+                created_at: row.get(1)?,
+                last_used_at: row.get(2)?,
+                expires_at: row.get(3)?,
+                user_agent: row.get(4)?,
+                connections: 0,
+            })
+        })?
+        .collect();
+
+    sessions
+}
+
+// Ends session `session_id` of `user_id`, returning whether it existed.
+pub fn revoke_session(
+    conn: &Connection,
+    user_id: usize,
+    session_id: &str,
+) -> Result<bool, rusqlite::Error> {
+    let deleted = conn.execute(
+        "DELETE FROM sessions WHERE session_id = ?1 AND user_id = ?2",
+        params![session_id, user_id],
+    )?;
+
+    Ok(deleted > 0)
+}
+
+// Ends the session of `token`, if any.
+pub fn delete_session(conn: &Connection, token: &str) -> Result<(), rusqlite::Error> {
+    conn.execute(
+        "DELETE FROM sessions WHERE token_hash = ?1",
+        params![hash_token(token)],
+    )?;
+
+    Ok(())
+}
+
+// `Set-Cookie` header value handing `token` to the client for `ttl`.
+// An empty token with a zero `ttl` clears the cookie.
+pub fn session_cookie(token: &str, ttl: Duration) -> String {
+    format!(
+        "{}={}; Max-Age={}; Path=/; HttpOnly; SameSite=Strict",
+        SESSION_COOKIE,
+        token,
+        ttl.as_secs()
+    )
+}
+
+// SQLite date modifier adding `ttl` to a timestamp.
+pub(crate) fn ttl_modifier(ttl: Duration) -> String {
+    format!("+{} seconds", ttl.as_secs())
+}
+
+// Claims of the JWTs issued on login.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Claims {
+    // ID of the user the token was issued to
+    pub sub: String,
+    pub iat: u64,
+    pub exp: u64,
+}
+
+// Issues and validates the JWTs users authenticate with.
+pub struct JwtKeys {
+    encoding: EncodingKey,
+    decoding: DecodingKey,
+    ttl: Duration,
+}
+
+impl JwtKeys {
+    // Keys signing tokens with `secret` (HS256), valid for `ttl` after issue.
+    pub fn new(secret: &[u8], ttl: Duration) -> Self {
+        JwtKeys {
+            encoding: EncodingKey::from_secret(secret),
+            decoding: DecodingKey::from_secret(secret),
+            ttl,
+        }
+    }
+
+    // Keys with a random secret: tokens do not outlive the process.
+    pub fn random(ttl: Duration) -> Self {
+        let mut secret = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut secret);
+        JwtKeys::new(&secret, ttl)
+    }
+
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    // Issues a token for `user_id`.
+    pub fn issue(&self, user_id: usize) -> Result<String, anyhow::Error> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let claims = Claims {
+            sub: user_id.to_string(),
+            iat: now,
+            exp: now + self.ttl.as_secs(),
+        };
+
+        Ok(jsonwebtoken::encode(
+            &Header::default(),
+            &claims,
+            &self.encoding,
+        )?)
+    }
+
+    // The user `token` was issued to, and when, in seconds since the epoch,
+    // provided its signature is valid and it has not expired.
+    pub fn verify(&self, token: &str) -> Option<(usize, u64)> {
+        let mut validation = Validation::new(Algorithm::HS256);
+        validation.leeway = 0;
+
+        let data = jsonwebtoken::decode::<Claims>(token, &self.decoding, &validation).ok()?;
+        Some((data.claims.sub.parse().ok()?, data.claims.iat))
+    }
+}
+
+// Who a connection or request acts as, and what they may do.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Principal {
+    pub user_id: usize,
+    // Scopes of the API token used, if any. Users that authenticated
+    // otherwise may do anything
+    pub scopes: Option<Vec<Scope>>,
+}
+
+impl Principal {
+    pub fn user(user_id: usize) -> Self {
+        Principal {
+            user_id,
+            scopes: None,
+        }
+    }
+
+    pub fn allows(&self, required: &Scope) -> bool {
+        match &self.scopes {
+            Some(scopes) => scopes.iter().any(|scope| scope.grants(required)),
+            None => true,
+        }
+    }
+}
+
+// Resolves who a connection acts as: the user `token` was issued to if given,
+// that of `session` otherwise, or a new guest connecting from `addr` if
+// neither is. Returns `None` if `token` is not a valid JWT or API token.
+pub async fn connection_user(
+    db_tx: &DbTx,
+    jwt: &JwtKeys,
+    token: Option<String>,
+    session: Option<&Session>,
+    addr: Option<IpAddr>,
+) -> Result<Option<Principal>, anyhow::Error> {
+    match (token, session) {
+        (Some(token), _) => token_user(db_tx, jwt, token).await,
+        (None, Some(session)) => Ok(Some(Principal::user(session.user_id))),
+        (None, None) => db::query(db_tx, move |conn| create_guest(conn, addr))
+            .await
+            .map(|user_id| Some(Principal::user(user_id))),
+    }
+}
+
+// Resolves who is making a REST request: the user `bearer_token` was issued
+// to if given, or that of `session` otherwise.
+pub async fn request_user(
+    db_tx: &DbTx,
+    jwt: &JwtKeys,
+    bearer_token: Option<String>,
+    session: Option<&Session>,
+) -> Result<Option<Principal>, anyhow::Error> {
+    match bearer_token {
+        Some(token) => token_user(db_tx, jwt, token).await,
+        None => Ok(session.map(|session| Principal::user(session.user_id))),
+    }
+}
+
+// Resolves the user `token` was issued to, whether an API token or a JWT.
+async fn token_user(
+    db_tx: &DbTx,
+    jwt: &JwtKeys,
+    token: String,
+) -> Result<Option<Principal>, anyhow::Error> {
+    if api_token::is_api_token(&token) {
+        return db::query(db_tx, move |conn| api_token::token_user(conn, &token)).await;
+    }
+
+    // JWTs outlive the users they were issued to, if deleted, and their
+    // passwords, if reset
+    match jwt.verify(&token) {
+        Some((user_id, issued_at)) => {
+            Ok(
+                db::query(db_tx, move |conn| token_valid(conn, user_id, issued_at))
+                    .await?
+                    .then(|| Principal::user(user_id)),
+            )
+        }
+        None => Ok(None),
+    }
+}
+
+// Whether a JWT issued to `user_id` at `issued_at`, in seconds since the
+// epoch, is still accepted: the user must exist, and not have had their
+// tokens revoked since. Tokens issued in the second they were revoked in are
+// rejected too, as they may have been issued before.
+pub fn token_valid(
+    conn: &Connection,
+    user_id: usize,
+    issued_at: u64,
+) -> Result<bool, rusqlite::Error> {
+    conn.query_row(
+        "SELECT EXISTS (
+            SELECT 1 FROM users WHERE user_id = ?1
+                AND (tokens_valid_after IS NULL OR tokens_valid_after < ?2)
+        )",
+        params![user_id, issued_at as i64],
+        |row| row.get(0),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_password_hashing() {
+        let hash = hash_password("correct horse").unwrap();
+
+        assert!(verify_password("correct horse", &hash));
+        assert!(!verify_password("battery staple", &hash));
+        assert!(!verify_password("correct horse", "not a hash"));
+    }
+
+    #[test]
+    fn test_validate_username() {
+        assert!(validate_username("alice_01").is_ok());
+        assert!(validate_username("").is_err());
+        assert!(validate_username("with space").is_err());
+        assert!(validate_username("guest-1234").is_err());
+        assert!(validate_username(&"a".repeat(MAX_USERNAME_LENGTH + 1)).is_err());
+    }
+
+    #[test]
+    fn test_validate_email() {
+        assert!(validate_email("alice@example.com").is_ok());
+        assert!(validate_email("alice").is_err());
+        assert!(validate_email("@example.com").is_err());
+        assert!(validate_email("alice@localhost").is_err());
+        assert!(validate_email("alice@example.com.").is_err());
+        assert!(validate_email("alice smith@example.com").is_err());
+    }
+
+    #[test]
+    fn test_users() {
+        let conn = Connection::open_in_memory().unwrap();
+        db::init_schema(&conn).unwrap();
+
+        let user_id = create_user(&conn, "alice", "hash").unwrap().unwrap();
+        assert_eq!(create_user(&conn, "alice", "other").unwrap(), None);
+        assert_eq!(
+            find_user(&conn, "alice").unwrap(),
+            Some((user_id, String::from("hash")))
+        );
+
+        // Guests share the ID space of registered users, but can not log in
+        let guest_id = create_guest(&conn, None).unwrap();
+        assert_ne!(guest_id, user_id);
+
+        // Nicknames held by other users can not be registered
+        assert!(db::reserve_nickname(&conn, guest_id, "Bob").unwrap());
+        assert_eq!(create_user(&conn, "bob", "hash").unwrap(), None);
+    }
+
+    #[test]
+    fn test_delete_user() {
+        let conn = Connection::open_in_memory().unwrap();
+        db::init_schema(&conn).unwrap();
+
+        let message_author = |message_id: i64| -> (usize, Option<String>) {
+            conn.query_row(
+                "SELECT user_id, nickname FROM chat_messages WHERE message_id = ?1",
+                params![message_id],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap()
+        };
+        let forwarded_author = |message_id: i64| -> (usize, Option<String>) {
+            conn.query_row(
+                "SELECT forwarded_user_id, forwarded_nick FROM chat_messages
+                    WHERE message_id = ?1",
+                params![message_id],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap()
+        };
+
+        let alice = create_user(&conn, "alice", "hash").unwrap().unwrap();
+        let bob = create_user(&conn, "bob", "hash").unwrap().unwrap();
+        for (message_id, user_id) in &[(1, alice), (2, bob)] {
+            conn.execute(
+                "INSERT INTO chat_messages (message_id, user_id, room_name, message, nickname)
+                    VALUES (?1, ?2, 'room', 'hi', 'nick')",
+                params![message_id, user_id],
+            )
+            .unwrap();
+        }
+        // Each forwards the message of the other to another room
+        for (message_id, user_id, forwarded_id, forwarded_user_id) in
+            &[(3, bob, 1, alice), (4, alice, 2, bob)]
+        {
+            conn.execute(
+                "INSERT INTO chat_messages (message_id, user_id, room_name, message, nickname,
+                        forwarded_id, forwarded_room, forwarded_user_id, forwarded_nick)
+                    VALUES (?1, ?2, 'other', 'hi', 'nick', ?3, 'room', ?4, 'nick')",
+                params![message_id, user_id, forwarded_id, forwarded_user_id],
+            )
+            .unwrap();
+        }
+        let session = create_session(&conn, alice, Duration::from_secs(60), None).unwrap();
+
+        assert!(delete_user(&conn, alice, MessageRetention::Anonymize).unwrap());
+        assert!(!user_exists(&conn, alice).unwrap());
+        assert_eq!(find_user(&conn, "alice").unwrap(), None);
+        assert_eq!(
+            renew_session(&conn, &session, Duration::from_secs(60)).unwrap(),
+            None
+        );
+        assert_eq!(message_author(1), (DELETED_USER_ID, None));
+        // Copies forwarded by others no longer credit them either
+        assert_eq!(forwarded_author(3), (DELETED_USER_ID, None));
+
+        assert!(delete_user(&conn, bob, MessageRetention::Delete).unwrap());
+        assert!(!delete_user(&conn, bob, MessageRetention::Delete).unwrap());
+        assert_eq!(db::recent_messages(&conn, "room", 1, 10).unwrap().len(), 1);
+        // Along with their messages, copies of them forwarded by others are
+        // deleted
+        assert!(db::recent_messages(&conn, "other", 1, 10)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_expire_guests() {
+        let conn = Connection::open_in_memory().unwrap();
+        db::init_schema(&conn).unwrap();
+
+        let alice = create_user(&conn, "alice", "hash").unwrap().unwrap();
+        let (gone, online) = (
+            create_guest(&conn, None).unwrap(),
+            create_guest(&conn, None).unwrap(),
+        );
+        db::reserve_nickname(&conn, gone, "guest-0001").unwrap();
+        let retention = Duration::from_secs(60);
+        assert_eq!(expire_guests(&conn, &HashSet::new(), retention).unwrap(), 0);
+
+        // Guests online are seen again, unlike those who left
+        conn.execute(
+            "UPDATE guests SET last_seen_at = datetime('now', '-120 seconds')",
+            [],
+        )
+        .unwrap();
+        let online_ids = HashSet::from([alice, online]);
+        assert_eq!(expire_guests(&conn, &online_ids, retention).unwrap(), 1);
+        assert!(!user_exists(&conn, gone).unwrap());
+        assert_eq!(db::nickname(&conn, gone).unwrap(), None);
+        assert!(user_exists(&conn, online).unwrap());
+        assert!(user_exists(&conn, alice).unwrap());
+
+        // The IDs of guests are never given out again
+        assert!(create_guest(&conn, None).unwrap() > online);
+    }
+
+    #[test]
+    fn test_sessions() {
+        let conn = Connection::open_in_memory().unwrap();
+        db::init_schema(&conn).unwrap();
+        let ttl = Duration::from_secs(60);
+
+        let user_id = create_user(&conn, "alice", "hash").unwrap().unwrap();
+        let token = create_session(&conn, user_id, ttl, None).unwrap();
+
+        let session = renew_session(&conn, &token, ttl).unwrap().unwrap();
+        assert_eq!(session.user_id, user_id);
+        assert_eq!(renew_session(&conn, "not a token", ttl).unwrap(), None);
+
+        // Tokens are not stored in the clear
+        let stored: String = conn
+            .query_row("SELECT token_hash FROM sessions", [], |row| row.get(0))
+            .unwrap();
+        assert_ne!(stored, token);
+
+        // Renewing a session pushes back its expiry
+        renew_session(&conn, &token, Duration::from_secs(0)).unwrap();
+        assert_eq!(renew_session(&conn, &token, ttl).unwrap(), None);
+
+        let token = create_session(&conn, user_id, ttl, None).unwrap();
+        delete_session(&conn, &token).unwrap();
+        assert_eq!(renew_session(&conn, &token, ttl).unwrap(), None);
+
+        // The expired session was cleaned up when the last one was created
+        let count: usize = conn
+            .query_row("SELECT COUNT(*) FROM sessions", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 0);
+    }
+
+    #[test]
+    fn test_user_sessions() {
+        let conn = Connection::open_in_memory().unwrap();
+        db::init_schema(&conn).unwrap();
+        let ttl = Duration::from_secs(60);
+
+        let alice = create_user(&conn, "alice", "hash").unwrap().unwrap();
+        let bob = create_user(&conn, "bob", "hash").unwrap().unwrap();
+        let laptop = create_session(&conn, alice, ttl, Some("laptop")).unwrap();
+        let phone = create_session(&conn, alice, ttl, Some("phone")).unwrap();
+        create_session(&conn, bob, ttl, None).unwrap();
+
+        let laptop = renew_session(&conn, &laptop, ttl).unwrap().unwrap();
+        let sessions = user_sessions(&conn, alice).unwrap();
+        let agents: Vec<_> = sessions.iter().map(|s| s.user_agent.as_deref()).collect();
+        assert_eq!(agents, vec![Some("laptop"), Some("phone")]);
+        assert_eq!(sessions[0].id, laptop.id);
+        assert!(sessions[0].last_used_at.is_some());
+        assert!(sessions[1].last_used_at.is_none());
+
+        // Users can only end their own sessions
+        assert!(!revoke_session(&conn, bob, &laptop.id).unwrap());
+        assert!(revoke_session(&conn, alice, &laptop.id).unwrap());
+        assert_eq!(renew_session(&conn, &laptop.token, ttl).unwrap(), None);
+        assert!(renew_session(&conn, &phone, ttl).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_jwt() {
+        let jwt = JwtKeys::new(b"secret", Duration::from_secs(60));
+        let token = jwt.issue(42).unwrap();
+
+        let (user_id, issued_at) = jwt.verify(&token).unwrap();
+        assert_eq!(user_id, 42);
+        assert!(issued_at > 0);
+        assert_eq!(jwt.verify("not a token"), None);
+
+        // Tokens signed with another secret are rejected
+        let other = JwtKeys::new(b"other secret", Duration::from_secs(60));
+        assert_eq!(other.verify(&token), None);
+
+        // Expired tokens are rejected
+        let expired = JwtKeys::new(b"secret", Duration::from_secs(0));
+        let token = expired.issue(42).unwrap();
+        std::thread::sleep(Duration::from_secs(1));
+        assert_eq!(jwt.verify(&token), None);
+    }
+}
@@ -0,0 +1,210 @@
+use anyhow::anyhow;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::auth::{
+    self,
+    scope::{self, Scope},
+    Principal,
+};
+
+// API tokens are told apart from JWTs by this prefix.
+pub const TOKEN_PREFIX: &str = "bic_";
+
+pub const MAX_NAME_LENGTH: usize = 64;
+
+// Request body of the route creating an API token.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct NewApiToken {
+    // What the token is used by, for its user to tell tokens apart
+    pub name: String,
+    // What the token may be used for
+    #[serde(default)]
+    #[schema(value_type = Vec<String>, example = json!(["read:lobby", "write:lobby"]))]
+    pub scopes: Vec<Scope>,
+}
+
+impl NewApiToken {
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        if self.name.trim().is_empty() || self.name.len() > MAX_NAME_LENGTH {
+            return Err(anyhow!(
+                "Token name must be between 1 and {} characters long",
+                MAX_NAME_LENGTH
+            ));
+        }
+
+        if self.scopes.is_empty() {
+            return Err(anyhow!("Tokens must be given at least one scope"));
+        }
+
+        Ok(())
+    }
+}
+
+// A long-lived token a user issued to a bot or integration, as listed to them.
+// The token itself is only ever shown once, on creation.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct ApiToken {
+    pub id: i64,
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    pub created_at: String,
+    pub last_used_at: Option<String>,
+}
+
+pub fn is_api_token(token: &str) -> bool {
+    token.starts_with(TOKEN_PREFIX)
+}
+
+// Issues a new API token named `name` to `user_id`, restricted to `scopes`,
+// returning it along with the token itself.
+pub fn create_token(
+    conn: &Connection,
+    user_id: usize,
+    name: &str,
+    scopes: &[Scope],
+) -> Result<(ApiToken, String), rusqlite::Error> {
+    let token = format!("{}{}", TOKEN_PREFIX, auth::new_token());
+    conn.execute(
+        "INSERT INTO api_tokens (user_id, name, token_hash, scopes) VALUES (?1, ?2, ?3, ?4)",
+        params![
+            user_id,
+            name,
+            auth::hash_token(&token),
+            scope::format_scopes(scopes)
+        ],
+    )?;
+
+    let api_token = conn.query_row(
+        "SELECT token_id, name, scopes, created_at, last_used_at FROM api_tokens
+            WHERE token_id = ?1",
+        params![conn.last_insert_rowid()],
+        api_token_from_row,
+    )?;
+
+    Ok((api_token, token))
+}
+
+// API tokens of `user_id`, oldest first.
+pub fn user_tokens(conn: &Connection, user_id: usize) -> Result<Vec<ApiToken>, rusqlite::Error> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT token_id, name, scopes, created_at, last_used_at FROM api_tokens
+            WHERE user_id = ?1 ORDER BY token_id",
+    )?;
+    let tokens = stmt
+        .query_map(params![user_id], api_token_from_row)?
+        .collect();
+
+    tokens
+}
+
+// Revokes API token `token_id` of `user_id`, returning whether it existed.
+pub fn revoke_token(
+    conn: &Connection,
+    user_id: usize,
+    token_id: i64,
+) -> Result<bool, rusqlite::Error> {
+    let deleted = conn.execute(
+        "DELETE FROM api_tokens WHERE token_id = ?1 AND user_id = ?2",
+        params![token_id, user_id],
+    )?;
+
+    Ok(deleted > 0)
+}
+
+// Looks up the user API token `token` was issued to, and what it may be used
+// for, recording its use.
+pub fn token_user(conn: &Connection, token: &str) -> Result<Option<Principal>, rusqlite::Error> {
+    let token_hash = auth::hash_token(token);
+    conn.execute(
+        "UPDATE api_tokens SET last_used_at = CURRENT_TIMESTAMP WHERE token_hash = ?1",
+        params![token_hash],
+    )?;
+
+    conn.query_row(
+        "SELECT user_id, scopes FROM api_tokens WHERE token_hash = ?1",
+        params![token_hash],
+        |row| {
+            Ok(Principal {
+                user_id: row.get(0)?,
+                scopes: Some(scope::parse_scopes(&row.get::<_, String>(1)?)),
+            })
+        },
+    )
+    .optional()
+}
+
+fn api_token_from_row(row: &rusqlite::Row) -> Result<ApiToken, rusqlite::Error> {
+    Ok(ApiToken {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        scopes: scope::parse_scopes(&row.get::<_, String>(2)?),
+        created_at: row.get(3)?,
+        last_used_at: row.get(4)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+
+    #[test]
+    fn test_validate() {
+        let named = |name: &str| NewApiToken {
+            name: String::from(name),
+            scopes: vec![Scope::Admin],
+        };
+
+        assert!(named("deploy bot").validate().is_ok());
+        assert!(named(" ").validate().is_err());
+        assert!(named(&"a".repeat(MAX_NAME_LENGTH + 1)).validate().is_err());
+
+        let unscoped = NewApiToken {
+            scopes: Vec::new(),
+            ..named("deploy bot")
+        };
+        assert!(unscoped.validate().is_err());
+    }
+
+    #[test]
+    fn test_api_tokens() {
+        let conn = Connection::open_in_memory().unwrap();
+        db::init_schema(&conn).unwrap();
+
+        let alice = auth::create_user(&conn, "alice", "hash").unwrap().unwrap();
+        let bob = auth::create_user(&conn, "bob", "hash").unwrap().unwrap();
+
+        let scopes = vec![Scope::Read(None), Scope::Write(Some(String::from("room1")))];
+        let (api_token, token) = create_token(&conn, alice, "bot", &scopes).unwrap();
+        assert!(is_api_token(&token));
+        assert_eq!(api_token.name, "bot");
+        assert_eq!(api_token.scopes, scopes);
+        assert_eq!(api_token.last_used_at, None);
+
+        assert_eq!(
+            token_user(&conn, &token).unwrap(),
+            Some(Principal {
+                user_id: alice,
+                scopes: Some(scopes),
+            })
+        );
+        assert_eq!(token_user(&conn, "bic_unknown").unwrap(), None);
+        let tokens = user_tokens(&conn, alice).unwrap();
+        assert_eq!(tokens.len(), 1);
+        assert!(tokens[0].last_used_at.is_some());
+
+        // Tokens are not stored in the clear
+        let stored: String = conn
+            .query_row("SELECT token_hash FROM api_tokens", [], |row| row.get(0))
+            .unwrap();
+        assert_ne!(stored, token);
+
+        // Users can only revoke their own tokens
+        assert!(!revoke_token(&conn, bob, api_token.id).unwrap());
+        assert!(revoke_token(&conn, alice, api_token.id).unwrap());
+        assert_eq!(token_user(&conn, &token).unwrap(), None);
+        assert!(user_tokens(&conn, alice).unwrap().is_empty());
+    }
+}
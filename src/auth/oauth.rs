@@ -0,0 +1,213 @@
+use std::collections::HashMap;
+
+use anyhow::anyhow;
+use reqwest::{header::ACCEPT, Client, Url};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::config::Config;
+
+// Name of the cookie holding the `state` of an authorization in progress.
+pub const STATE_COOKIE: &str = "oauth_state";
+
+// An OAuth2 provider users can log in with, using the authorization-code flow.
+#[derive(Clone, Debug)]
+pub struct OAuthProvider {
+    pub client_id: String,
+    pub client_secret: String,
+    pub authorize_url: String,
+    pub token_url: String,
+    pub userinfo_url: String,
+    pub scope: String,
+    // Field of the user info response uniquely identifying the user
+    pub id_field: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    access_token: String,
+}
+
+impl OAuthProvider {
+    pub fn github(client_id: String, client_secret: String) -> Self {
+        OAuthProvider {
+            client_id,
+            client_secret,
+            authorize_url: String::from("https://github.com/login/oauth/authorize"),
+            token_url: String::from("https://github.com/login/oauth/access_token"),
+            userinfo_url: String::from("https://api.github.com/user"),
+            scope: String::from("read:user"),
+            id_field: String::from("id"),
+        }
+    }
+
+    pub fn google(client_id: String, client_secret: String) -> Self {
+        OAuthProvider {
+            client_id,
+            client_secret,
+            authorize_url: String::from("https://accounts.google.com/o/oauth2/v2/auth"),
+            token_url: String::from("https://oauth2.googleapis.com/token"),
+            userinfo_url: String::from("https://openidconnect.googleapis.com/v1/userinfo"),
+            scope: String::from("openid"),
+            id_field: String::from("sub"),
+        }
+    }
+
+    // Where to send users to authorize the login, coming back to
+    // `redirect_uri` with a code and `state`.
+    pub fn authorization_url(&self, redirect_uri: &str, state: &str) -> Result<Url, anyhow::Error> {
+        Ok(Url::parse_with_params(
+            &self.authorize_url,
+            &[
+                ("response_type", "code"),
+                ("client_id", &self.client_id),
+                ("redirect_uri", redirect_uri),
+                ("scope", &self.scope),
+                ("state", state),
+            ],
+        )?)
+    }
+
+    // Exchanges the code the user came back with for an access token, and
+    // returns the ID of the user it grants access to.
+    pub async fn identify(
+        &self,
+        client: &Client,
+        code: &str,
+        redirect_uri: &str,
+    ) -> Result<String, anyhow::Error> {
+        let token: TokenResponse = client
+            .post(&self.token_url)
+            .header(ACCEPT, "application/json")
+            .form(&[
+                ("grant_type", "authorization_code"),
+                ("code", code),
+                ("redirect_uri", redirect_uri),
+                ("client_id", &self.client_id),
+                ("client_secret", &self.client_secret),
+            ])
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        let userinfo: Value = client
+            .get(&self.userinfo_url)
+            .header(ACCEPT, "application/json")
+            .bearer_auth(&token.access_token)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        external_id(&userinfo, &self.id_field)
+    }
+}
+
+// The providers enabled by `config`: those with a client ID and secret.
+pub fn providers(config: &Config) -> HashMap<String, OAuthProvider> {
+    let mut providers = HashMap::new();
+
+    if let (Some(id), Some(secret)) = (&config.github_client_id, &config.github_client_secret) {
+        providers.insert(
+            String::from("github"),
+            OAuthProvider::github(id.clone(), secret.clone()),
+        );
+    }
+    if let (Some(id), Some(secret)) = (&config.google_client_id, &config.google_client_secret) {
+        providers.insert(
+            String::from("google"),
+            OAuthProvider::google(id.clone(), secret.clone()),
+        );
+    }
+
+    providers
+}
+
+// Reads the ID of a user from a user info response: providers give them as
+// either numbers or strings.
+fn external_id(userinfo: &Value, id_field: &str) -> Result<String, anyhow::Error> {
+    match &userinfo[id_field] {
+        Value::String(id) if !id.is_empty() => Ok(id.clone()),
+        Value::Number(id) => Ok(id.to_string()),
+        _ => Err(anyhow!("User info has no '{}' field", id_field)),
+    }
+}
+
+// Finds the local user of an external identity, creating one on first login.
+pub fn identity_user(
+    conn: &Connection,
+    provider: &str,
+    external_id: &str,
+) -> Result<usize, rusqlite::Error> {
+    let user_id = conn
+        .query_row(
+            "SELECT user_id FROM oauth_identities WHERE provider = ?1 AND external_id = ?2",
+            params![provider, external_id],
+            |row| row.get(0),
+        )
+        .optional()?;
+
+    if let Some(user_id) = user_id {
+        return Ok(user_id);
+    }
+
+    // Users logging in externally have no password, so can only log in again
+    // through their provider
+    conn.execute("INSERT INTO users (username) VALUES (NULL)", [])?;
+    let user_id = conn.last_insert_rowid() as usize;
+    conn.execute(
+        "INSERT INTO oauth_identities (provider, external_id, user_id) VALUES (?1, ?2, ?3)",
+        params![provider, external_id, user_id],
+    )?;
+
+    Ok(user_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+    use serde_json::json;
+
+    #[test]
+    fn test_authorization_url() {
+        let provider = OAuthProvider::github(String::from("client"), String::from("secret"));
+        let url = provider
+            .authorization_url("http://localhost:3030/auth/github/callback", "xyz")
+            .unwrap();
+        let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+
+        assert!(url.as_str().starts_with(&provider.authorize_url));
+        assert_eq!(params["client_id"], "client");
+        assert_eq!(params["state"], "xyz");
+        assert_eq!(
+            params["redirect_uri"],
+            "http://localhost:3030/auth/github/callback"
+        );
+        assert!(!params.contains_key("client_secret"));
+    }
+
+    #[test]
+    fn test_external_id() {
+        assert_eq!(external_id(&json!({ "id": 42 }), "id").unwrap(), "42");
+        assert_eq!(external_id(&json!({ "sub": "abc" }), "sub").unwrap(), "abc");
+        assert!(external_id(&json!({ "sub": "" }), "sub").is_err());
+        assert!(external_id(&json!({ "login": "alice" }), "id").is_err());
+    }
+
+    #[test]
+    fn test_identity_user() {
+        let conn = Connection::open_in_memory().unwrap();
+        db::init_schema(&conn).unwrap();
+
+        let user_id = identity_user(&conn, "github", "42").unwrap();
+        assert_eq!(identity_user(&conn, "github", "42").unwrap(), user_id);
+
+        // IDs are only unique per provider
+        assert_ne!(identity_user(&conn, "google", "42").unwrap(), user_id);
+    }
+}
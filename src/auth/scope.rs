@@ -0,0 +1,134 @@
+use std::{convert::TryFrom, fmt, str::FromStr};
+
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+
+// Stands for every room in room scopes.
+const ANY_ROOM: &str = "*";
+
+// A permission API tokens may be restricted to.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum Scope {
+    // Joining a room and reading its messages: `read:<room>`, or `read:*`
+    Read(Option<String>),
+    // Sending, editing, deleting and pinning messages in a room, which implies
+    // reading it: `write:<room>`, or `write:*`
+    Write(Option<String>),
+    // Everything, including managing the account the token belongs to
+    Admin,
+}
+
+impl Scope {
+    // Whether holding this scope grants `required`.
+    pub fn grants(&self, required: &Scope) -> bool {
+        match (self, required) {
+            (Scope::Admin, _) => true,
+            (Scope::Read(held), Scope::Read(room))
+            | (Scope::Write(held), Scope::Read(room))
+            | (Scope::Write(held), Scope::Write(room)) => held.is_none() || held == room,
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Scope {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let room = |room: &str| match room {
+            "" => Err(anyhow!("Missing room in scope '{}'", s)),
+            ANY_ROOM => Ok(None),
+            room => Ok(Some(String::from(room))),
+        };
+
+        match s.split_once(':') {
+            Some(("read", room_name)) => Ok(Scope::Read(room(room_name)?)),
+            Some(("write", room_name)) => Ok(Scope::Write(room(room_name)?)),
+            None if s == "admin" => Ok(Scope::Admin),
+            _ => Err(anyhow!(
+                "Unknown scope '{}': expected read:<room>, write:<room> or admin",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let room = |room: &Option<String>| room.clone().unwrap_or_else(|| String::from(ANY_ROOM));
+
+        match self {
+            Scope::Read(room_name) => write!(f, "read:{}", room(room_name)),
+            Scope::Write(room_name) => write!(f, "write:{}", room(room_name)),
+            Scope::Admin => f.write_str("admin"),
+        }
+    }
+}
+
+impl TryFrom<String> for Scope {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<Scope> for String {
+    fn from(scope: Scope) -> Self {
+        scope.to_string()
+    }
+}
+
+// Parses scopes stored space-separated, skipping any that are unknown.
+pub fn parse_scopes(scopes: &str) -> Vec<Scope> {
+    scopes
+        .split_whitespace()
+        .filter_map(|scope| scope.parse().ok())
+        .collect()
+}
+
+// Stores scopes space-separated, as read by `parse_scopes`.
+pub fn format_scopes(scopes: &[Scope]) -> String {
+    scopes
+        .iter()
+        .map(Scope::to_string)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_scopes() {
+        assert_eq!(
+            parse_scopes("read:room1 write:* admin"),
+            vec![
+                Scope::Read(Some(String::from("room1"))),
+                Scope::Write(None),
+                Scope::Admin
+            ]
+        );
+        assert!("read:".parse::<Scope>().is_err());
+        assert!("delete:room1".parse::<Scope>().is_err());
+        assert!("admin:room1".parse::<Scope>().is_err());
+
+        let scopes = parse_scopes("read:room1 write:*");
+        assert_eq!(format_scopes(&scopes), "read:room1 write:*");
+    }
+
+    #[test]
+    fn test_grants() {
+        let room = |name: &str| Some(String::from(name));
+
+        assert!(Scope::Read(room("room1")).grants(&Scope::Read(room("room1"))));
+        assert!(!Scope::Read(room("room1")).grants(&Scope::Read(room("room2"))));
+        assert!(!Scope::Read(room("room1")).grants(&Scope::Write(room("room1"))));
+        assert!(Scope::Write(room("room1")).grants(&Scope::Read(room("room1"))));
+        assert!(Scope::Write(None).grants(&Scope::Write(room("room2"))));
+        assert!(!Scope::Write(None).grants(&Scope::Admin));
+        assert!(Scope::Admin.grants(&Scope::Write(room("room1"))));
+    }
+}
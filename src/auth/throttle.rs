@@ -0,0 +1,215 @@
+use std::{
+    net::IpAddr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::config::Config;
+
+// Longest an account or address may be locked out for. Failures are forgotten
+// once none were recorded for as long.
+const MAX_LOCKOUT: Duration = Duration::from_secs(24 * 60 * 60);
+
+// How many failed logins are tolerated before lockouts kick in, and how long
+// the first lockout lasts. Every further failure doubles it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Policy {
+    pub max_failures: u32,
+    pub lockout: Duration,
+}
+
+impl Policy {
+    // Lockout following the `failures`th failure in a row, if any.
+    fn lockout_after(&self, failures: u32) -> Option<Duration> {
+        let excess = failures.checked_sub(self.max_failures + 1)?;
+        let lockout = 2u32
+            .checked_pow(excess)
+            .and_then(|factor| self.lockout.checked_mul(factor))
+            .unwrap_or(MAX_LOCKOUT);
+
+        Some(lockout.min(MAX_LOCKOUT))
+    }
+}
+
+// Failure counters a login attempt for `username` from `remote` counts
+// against, along with the policy applying to each: one for the account, and
+// one for the address attempts come from.
+pub fn counters(config: &Config, username: &str, remote: Option<IpAddr>) -> Vec<(String, Policy)> {
+    let lockout = Duration::from_secs(config.login_lockout_secs);
+    let mut counters = vec![(
+        account_counter(username),
+        Policy {
+            max_failures: config.login_max_failures,
+            lockout,
+        },
+    )];
+
+    if let Some(remote) = remote {
+        counters.push((
+            format!("ip:{}", remote),
+            Policy {
+                max_failures: config.login_max_ip_failures,
+                lockout,
+            },
+        ));
+    }
+
+    counters
+}
+
+// Counter of the failed logins to `username`. Usernames are matched without
+// regard to case, so that variations can not be used to dodge lockouts.
+fn account_counter(username: &str) -> String {
+    format!("user:{}", username.to_lowercase())
+}
+
+// Current time, in seconds since the Unix epoch, as counters are kept in.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_secs())
+}
+
+// How long until logins counting against `counters` are allowed again, as of
+// `now` (in seconds since the Unix epoch).
+pub fn locked_for(
+    conn: &Connection,
+    counters: &[(String, Policy)],
+    now: u64,
+) -> Result<Option<Duration>, rusqlite::Error> {
+    let mut locked_for = None;
+    for (counter, _) in counters {
+        let locked_until: Option<u64> = conn
+            .query_row(
+                "SELECT locked_until FROM login_failures WHERE counter = ?1",
+                params![counter],
+                |row| row.get(0),
+            )
+            .optional()?
+            .flatten();
+
+        if let Some(locked_until) = locked_until.filter(|&until| until > now) {
+            locked_for = locked_for.max(Some(Duration::from_secs(locked_until - now)));
+        }
+    }
+
+    Ok(locked_for)
+}
+
+// Records a failed login against `counters` at `now`, returning how long
+// logins are now locked out for, if at all.
+pub fn record_failure(
+    conn: &Connection,
+    counters: &[(String, Policy)],
+    now: u64,
+) -> Result<Option<Duration>, rusqlite::Error> {
+    let mut locked_for = None;
+    for (counter, policy) in counters {
+        let previous: Option<(u32, u64)> = conn
+            .query_row(
+                "SELECT failures, last_failure_at FROM login_failures WHERE counter = ?1",
+                params![counter],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+
+        let failures = match previous {
+            Some((failures, last_failure_at)) if now < last_failure_at + MAX_LOCKOUT.as_secs() => {
+                failures + 1
+            }
+            _ => 1,
+        };
+        let lockout = policy.lockout_after(failures);
+
+        conn.execute(
+            "INSERT OR REPLACE INTO login_failures (counter, failures, last_failure_at, locked_until)
+                VALUES (?1, ?2, ?3, ?4)",
+            params![
+                counter,
+                failures,
+                now,
+                lockout.map(|lockout| now + lockout.as_secs())
+            ],
+        )?;
+
+        locked_for = locked_for.max(lockout);
+    }
+
+    Ok(locked_for)
+}
+
+// Forgets the failed logins to `username`, once they logged in. Failures
+// from their address are kept, so that logging into an account of their own
+// does not let attackers carry on.
+pub fn record_success(conn: &Connection, username: &str) -> Result<(), rusqlite::Error> {
+    conn.execute(
+        "DELETE FROM login_failures WHERE counter = ?1",
+        params![account_counter(username)],
+    )?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+
+    fn policy() -> Policy {
+        Policy {
+            max_failures: 2,
+            lockout: Duration::from_secs(60),
+        }
+    }
+
+    #[test]
+    fn test_lockout_after() {
+        let policy = policy();
+
+        assert_eq!(policy.lockout_after(2), None);
+        assert_eq!(policy.lockout_after(3), Some(Duration::from_secs(60)));
+        assert_eq!(policy.lockout_after(4), Some(Duration::from_secs(120)));
+        assert_eq!(policy.lockout_after(100), Some(MAX_LOCKOUT));
+    }
+
+    #[test]
+    fn test_lockouts() {
+        let conn = Connection::open_in_memory().unwrap();
+        db::init_schema(&conn).unwrap();
+
+        let alice = vec![(account_counter("alice"), policy())];
+        let now = 1_000_000;
+
+        assert_eq!(record_failure(&conn, &alice, now).unwrap(), None);
+        assert_eq!(record_failure(&conn, &alice, now).unwrap(), None);
+        assert_eq!(locked_for(&conn, &alice, now).unwrap(), None);
+
+        assert_eq!(
+            record_failure(&conn, &alice, now).unwrap(),
+            Some(Duration::from_secs(60))
+        );
+        assert_eq!(
+            locked_for(&conn, &alice, now + 10).unwrap(),
+            Some(Duration::from_secs(50))
+        );
+        assert_eq!(locked_for(&conn, &alice, now + 60).unwrap(), None);
+
+        // Usernames are counted regardless of case
+        let alice_upper = vec![(account_counter("ALICE"), policy())];
+        assert_eq!(
+            record_failure(&conn, &alice_upper, now + 60).unwrap(),
+            Some(Duration::from_secs(120))
+        );
+
+        // Logging in resets the count
+        record_success(&conn, "alice").unwrap();
+        assert_eq!(locked_for(&conn, &alice, now + 60).unwrap(), None);
+        assert_eq!(record_failure(&conn, &alice, now + 60).unwrap(), None);
+
+        // As does not failing for long enough
+        let later = now + MAX_LOCKOUT.as_secs() + 60;
+        record_failure(&conn, &alice, now + 60).unwrap();
+        assert_eq!(record_failure(&conn, &alice, later).unwrap(), None);
+    }
+}
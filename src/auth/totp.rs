@@ -0,0 +1,208 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use base32::Alphabet;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use rusqlite::{params, Connection};
+use sha1::Sha1;
+
+// Codes are 6 digits long, and change every 30 seconds (RFC 6238 defaults,
+// which authenticator apps expect).
+const DIGITS: u32 = 6;
+const STEP_SECS: u64 = 30;
+
+// Number of steps a code may be off by, to allow for clock drift.
+const ALLOWED_DRIFT: u64 = 1;
+
+const ISSUER: &str = "bi_chat";
+
+// Second factor enrollment of a user, as stored in the users table.
+#[derive(Debug, Default, PartialEq)]
+pub struct TotpState {
+    // Secret codes are checked against once enrollment has been confirmed
+    pub secret: Option<String>,
+    // Secret awaiting confirmation with a first code
+    pub pending_secret: Option<String>,
+}
+
+impl TotpState {
+    pub fn is_enabled(&self) -> bool {
+        self.secret.is_some()
+    }
+}
+
+// Generates a random secret, base32-encoded as authenticator apps expect.
+pub fn generate_secret() -> String {
+    let mut bytes = [0u8; 20];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    base32::encode(Alphabet::RFC4648 { padding: false }, &bytes)
+}
+
+// URI authenticator apps can be set up with (e.g. through a QR code).
+pub fn otpauth_uri(username: &str, secret: &str) -> String {
+    format!(
+        "otpauth://totp/{issuer}:{username}?secret={secret}&issuer={issuer}&digits={digits}&period={period}",
+        issuer = ISSUER,
+        username = username,
+        secret = secret,
+        digits = DIGITS,
+        period = STEP_SECS
+    )
+}
+
+// The code of `secret` (raw bytes) for time step `step` (RFC 4226).
+fn hotp(secret: &[u8], step: u64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC accepts keys of any size");
+    mac.update(&step.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let truncated = u32::from_be_bytes([
+        hash[offset] & 0x7f,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+
+    truncated % 10u32.pow(DIGITS)
+}
+
+// Checks `code` against base32 `secret` at `now` (seconds since the epoch),
+// returning the time step it matched.
+pub fn verify_code_at(secret: &str, code: &str, now: u64) -> Option<u64> {
+    let secret = base32::decode(Alphabet::RFC4648 { padding: false }, secret)?;
+    let code = code.trim();
+    if code.len() != DIGITS as usize {
+        return None;
+    }
+    let code: u32 = code.parse().ok()?;
+
+    let step = now / STEP_SECS;
+    (step.saturating_sub(ALLOWED_DRIFT)..=step + ALLOWED_DRIFT).find(|&s| hotp(&secret, s) == code)
+}
+
+// The code of base32 `secret` at `now` (seconds since the epoch), as an
+// authenticator app would show it.
+pub fn code_at(secret: &str, now: u64) -> Option<String> {
+    let secret = base32::decode(Alphabet::RFC4648 { padding: false }, secret)?;
+
+    Some(format!(
+        "{:0width$}",
+        hotp(&secret, now / STEP_SECS),
+        width = DIGITS as usize
+    ))
+}
+
+// Checks `code` against base32 `secret` at the current time.
+pub fn verify_code(secret: &str, code: &str) -> Option<u64> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs();
+    verify_code_at(secret, code, now)
+}
+
+pub fn totp_state(conn: &Connection, user_id: usize) -> Result<TotpState, rusqlite::Error> {
+    conn.query_row(
+        "SELECT totp_secret, totp_pending_secret FROM users WHERE user_id = ?1",
+        params![user_id],
+        |row| {
+            Ok(TotpState {
+                secret: row.get(0)?,
+                pending_secret: row.get(1)?,
+            })
+        },
+    )
+}
+
+// Starts enrolling `user_id` with `secret`, to be confirmed with a code.
+// Any second factor already enrolled keeps being checked until then.
+pub fn begin_enrollment(
+    conn: &Connection,
+    user_id: usize,
+    secret: &str,
+) -> Result<(), rusqlite::Error> {
+    conn.execute(
+        "UPDATE users SET totp_pending_secret = ?2 WHERE user_id = ?1",
+        params![user_id, secret],
+    )?;
+
+    Ok(())
+}
+
+// Completes enrollment, replacing any previously enrolled secret with the
+// pending one.
+pub fn confirm_enrollment(conn: &Connection, user_id: usize) -> Result<bool, rusqlite::Error> {
+    let updated = conn.execute(
+        "UPDATE users SET totp_secret = totp_pending_secret, totp_pending_secret = NULL,
+                totp_last_step = NULL
+            WHERE user_id = ?1 AND totp_pending_secret IS NOT NULL",
+        params![user_id],
+    )?;
+
+    Ok(updated > 0)
+}
+
+// Records that the code of time step `step` was used by `user_id`, returning
+// whether it had not been used already: each code may only be used once.
+pub fn use_step(conn: &Connection, user_id: usize, step: u64) -> Result<bool, rusqlite::Error> {
+    let updated = conn.execute(
+        "UPDATE users SET totp_last_step = ?2
+            WHERE user_id = ?1 AND (totp_last_step IS NULL OR totp_last_step < ?2)",
+        params![user_id, step as i64],
+    )?;
+
+    Ok(updated > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{auth, db};
+
+    // Secret of the RFC 6238 test vectors, "12345678901234567890"
+    const RFC_SECRET: &str = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+
+    #[test]
+    fn test_verify_code() {
+        // RFC 6238 test vectors, truncated to 6 digits
+        assert_eq!(verify_code_at(RFC_SECRET, "287082", 59), Some(1));
+        assert_eq!(
+            verify_code_at(RFC_SECRET, "081804", 1111111109),
+            Some(1111111109 / STEP_SECS)
+        );
+
+        // Codes of adjacent steps are accepted, but not older ones
+        assert!(verify_code_at(RFC_SECRET, "287082", 59 + STEP_SECS).is_some());
+        assert!(verify_code_at(RFC_SECRET, "287082", 59 + 3 * STEP_SECS).is_none());
+
+        assert!(verify_code_at(RFC_SECRET, "000000", 59).is_none());
+        assert!(verify_code_at(RFC_SECRET, "28708", 59).is_none());
+        assert!(verify_code_at("not base32!", "287082", 59).is_none());
+
+        assert_eq!(code_at(RFC_SECRET, 1111111109).as_deref(), Some("081804"));
+    }
+
+    #[test]
+    fn test_enrollment() {
+        let conn = Connection::open_in_memory().unwrap();
+        db::init_schema(&conn).unwrap();
+
+        let user_id = auth::create_user(&conn, "alice", "hash").unwrap().unwrap();
+        assert_eq!(totp_state(&conn, user_id).unwrap(), TotpState::default());
+        assert!(!confirm_enrollment(&conn, user_id).unwrap());
+
+        let secret = generate_secret();
+        begin_enrollment(&conn, user_id, &secret).unwrap();
+        assert!(!totp_state(&conn, user_id).unwrap().is_enabled());
+
+        assert!(confirm_enrollment(&conn, user_id).unwrap());
+        let state = totp_state(&conn, user_id).unwrap();
+        assert!(state.is_enabled());
+        assert_eq!(state.secret, Some(secret));
+        assert_eq!(state.pending_secret, None);
+
+        // Codes can not be replayed
+        assert!(use_step(&conn, user_id, 10).unwrap());
+        assert!(!use_step(&conn, user_id, 10).unwrap());
+        assert!(!use_step(&conn, user_id, 9).unwrap());
+        assert!(use_step(&conn, user_id, 11).unwrap());
+    }
+}
@@ -0,0 +1,517 @@
+use std::{convert::TryFrom, fmt, str::FromStr};
+
+use anyhow::anyhow;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::{
+    auth, conversation,
+    moderation::{self, SanctionKind},
+    room::{self, Visibility},
+};
+
+// What a user may do, server-wide or in a single room. Roles are ordered: each
+// permits everything the ones before it do.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize, ToSchema)]
+#[serde(try_from = "String", into = "String")]
+#[schema(rename_all = "lowercase")]
+pub enum Role {
+    // Sending messages, and editing and deleting their own
+    Member,
+    // Deleting any message, and pinning messages
+    Moderator,
+    // Everything, including giving roles
+    Admin,
+}
+
+// Something only some roles permit.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Action {
+    // Deleting messages sent by someone else
+    DeleteAnyMessage,
+    // Pinning messages to a room, and unpinning them
+    PinMessage,
+    // Changing the topic and description of a room
+    SetTopic,
+    // Making a room read-only, and writable again
+    SetReadOnly,
+    // Posting in a read-only room
+    PostReadOnly,
+    // Kicking, banning and muting users of a lower role in a room
+    ModerateUsers,
+    // Reading the versions of a message replaced by edits
+    ViewEditHistory,
+    // Reading the messages of a room which were deleted
+    ViewDeletedMessages,
+    // Giving roles in a room
+    AssignRoomRoles,
+    // Maintaining who may join a room
+    ManageRoomAccess,
+    // Making a room an announcement room, and following announcement rooms
+    // from it
+    ManageAnnouncements,
+    // Changing how long the messages of a room are kept, which may delete them
+    SetRetention,
+    // Exporting the whole history of a room
+    ExportHistory,
+    // Transferring a room, and adding or removing its owners. Only its owners
+    // and server-wide admins may
+    ManageRoomOwners,
+    // Giving server-wide roles, and deleting the accounts of other users
+    Administer,
+}
+
+impl Role {
+    pub fn permits(self, action: Action) -> bool {
+        match action {
+            Action::DeleteAnyMessage
+            | Action::PinMessage
+            | Action::SetTopic
+            | Action::SetReadOnly
+            | Action::PostReadOnly
+            | Action::ModerateUsers
+            | Action::ViewEditHistory
+            | Action::ViewDeletedMessages => self >= Role::Moderator,
+            Action::AssignRoomRoles
+            | Action::ManageRoomAccess
+            | Action::ManageAnnouncements
+            | Action::SetRetention
+            | Action::ExportHistory
+            | Action::ManageRoomOwners
+            | Action::Administer => self == Role::Admin,
+        }
+    }
+}
+
+impl FromStr for Role {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "member" => Ok(Role::Member),
+            "moderator" => Ok(Role::Moderator),
+            "admin" => Ok(Role::Admin),
+            _ => Err(anyhow!(
+                "Unknown role '{}': expected member, moderator or admin",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let role = match self {
+            Role::Member => "member",
+            Role::Moderator => "moderator",
+            Role::Admin => "admin",
+        };
+        f.write_str(role)
+    }
+}
+
+impl TryFrom<String> for Role {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<Role> for String {
+    fn from(role: Role) -> Self {
+        role.to_string()
+    }
+}
+
+// Request body of the routes giving roles.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct RoleUpdate {
+    pub role: Role,
+}
+
+// A role given to a user in a room.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct RoomRole {
+    pub user_id: usize,
+    pub role: Role,
+}
+
+// Whether a user is let into a room by its access control list.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Access {
+    Allow,
+    Deny,
+}
+
+// Request body of the route setting the access of a user to a room.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct AccessUpdate {
+    pub access: Access,
+}
+
+// An entry of the access control list of a room.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct AclEntry {
+    pub user_id: usize,
+    pub access: Access,
+}
+
+// Server-wide role of `user_id`. Unknown users are members.
+pub fn user_role(conn: &Connection, user_id: usize) -> Result<Role, rusqlite::Error> {
+    let role: Option<String> = conn
+        .query_row(
+            "SELECT role FROM users WHERE user_id = ?1",
+            params![user_id],
+            |row| row.get(0),
+        )
+        .optional()?;
+
+    Ok(parse_role(role))
+}
+
+// Role of `user_id` in `room_name`: the higher of their server-wide role and
+// the one they were given in the room. Owners are admins of their rooms.
+pub fn room_role(
+    conn: &Connection,
+    user_id: usize,
+    room_name: &str,
+) -> Result<Role, rusqlite::Error> {
+    if room::is_owner(conn, room_name, user_id)? {
+        return Ok(Role::Admin);
+    }
+
+    let role: Option<String> = conn
+        .query_row(
+            "SELECT role FROM room_roles WHERE room_name = ?1 AND user_id = ?2",
+            params![room_name, user_id],
+            |row| row.get(0),
+        )
+        .optional()?;
+
+    Ok(user_role(conn, user_id)?.max(parse_role(role)))
+}
+
+// Whether a user of `role` in `room_name` outranks `user_id` there. Users may
+// only be moderated by those who outrank them.
+pub fn outranks(
+    conn: &Connection,
+    room_name: &str,
+    role: Role,
+    user_id: usize,
+) -> Result<bool, rusqlite::Error> {
+    Ok(room_role(conn, user_id, room_name)? < role)
+}
+
+// Whether `user_id` may take `action`, in `room_name` if given.
+pub fn permits(
+    conn: &Connection,
+    user_id: usize,
+    room_name: Option<&str>,
+    action: Action,
+) -> Result<bool, rusqlite::Error> {
+    let role = match room_name {
+        // Admins of a room that do not own it may not manage its owners
+        Some(room_name) if action == Action::ManageRoomOwners => {
+            if room::is_owner(conn, room_name, user_id)? {
+                return Ok(true);
+            }
+            user_role(conn, user_id)?
+        }
+        Some(room_name) => room_role(conn, user_id, room_name)?,
+        None => user_role(conn, user_id)?,
+    };
+
+    Ok(role.permits(action))
+}
+
+// Gives `user_id` a server-wide role, returning whether the user exists.
+pub fn set_user_role(
+    conn: &Connection,
+    user_id: usize,
+    role: Role,
+) -> Result<bool, rusqlite::Error> {
+    let updated = conn.execute(
+        "UPDATE users SET role = ?1 WHERE user_id = ?2",
+        params![role.to_string(), user_id],
+    )?;
+
+    Ok(updated > 0)
+}
+
+// Gives `user_id` a role in `room_name`, returning whether the user exists.
+// Members hold no role in particular, so giving them that role removes theirs.
+pub fn set_room_role(
+    conn: &Connection,
+    room_name: &str,
+    user_id: usize,
+    role: Role,
+) -> Result<bool, rusqlite::Error> {
+    if !auth::user_exists(conn, user_id)? {
+        return Ok(false);
+    }
+
+    match role {
+        Role::Member => conn.execute(
+            "DELETE FROM room_roles WHERE room_name = ?1 AND user_id = ?2",
+            params![room_name, user_id],
+        )?,
+        role => conn.execute(
+            "INSERT OR REPLACE INTO room_roles (room_name, user_id, role) VALUES (?1, ?2, ?3)",
+            params![room_name, user_id, role.to_string()],
+        )?,
+    };
+
+    Ok(true)
+}
+
+// Roles given in `room_name`, by user ID.
+pub fn room_roles(conn: &Connection, room_name: &str) -> Result<Vec<RoomRole>, rusqlite::Error> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT user_id, role FROM room_roles WHERE room_name = ?1 ORDER BY user_id",
+    )?;
+    let roles = stmt
+        .query_map(params![room_name], |row| {
+            Ok(RoomRole {
+                user_id: row.get(0)?,
+                role: parse_role(row.get(1)?),
+            })
+        })?
+        .collect();
+
+    roles
+}
+
+// Whether `user_id` may join `room_name`. Users banned from it or on its deny
+// list may not, and once anyone is on its allow list, only they may. Private rooms only let in
+// their members and allowed users. Admins of the room are always let in, so
+// that they can not lock themselves out.
+pub fn may_join(
+    conn: &Connection,
+    user_id: usize,
+    room_name: &str,
+) -> Result<bool, rusqlite::Error> {
+    // Conversations only ever let in those taking part in them
+    if conversation::is_conversation(room_name) {
+        return room::is_member(conn, room_name, user_id);
+    }
+
+    if room_role(conn, user_id, room_name)? == Role::Admin {
+        return Ok(true);
+    }
+
+    if moderation::is_sanctioned(conn, room_name, user_id, SanctionKind::Ban)? {
+        return Ok(false);
+    }
+
+    let allowed: Option<bool> = conn
+        .query_row(
+            "SELECT allowed FROM room_acl WHERE room_name = ?1 AND user_id = ?2",
+            params![room_name, user_id],
+            |row| row.get(0),
+        )
+        .optional()?;
+
+    let private = room::settings(conn, room_name)?
+        .is_some_and(|settings| settings.visibility == Visibility::Private);
+
+    match allowed {
+        Some(allowed) => Ok(allowed),
+        None if private => room::is_member(conn, room_name, user_id),
+        None => conn.query_row(
+            "SELECT NOT EXISTS (SELECT 1 FROM room_acl WHERE room_name = ?1 AND allowed)",
+            params![room_name],
+            |row| row.get(0),
+        ),
+    }
+}
+
+// Puts `user_id` on the allow or deny list of `room_name`, taking them off the
+// other. Returns whether the user exists.
+pub fn set_access(
+    conn: &Connection,
+    room_name: &str,
+    user_id: usize,
+    access: Access,
+) -> Result<bool, rusqlite::Error> {
+    if !auth::user_exists(conn, user_id)? {
+        return Ok(false);
+    }
+
+    conn.execute(
+        "INSERT OR REPLACE INTO room_acl (room_name, user_id, allowed) VALUES (?1, ?2, ?3)",
+        params![room_name, user_id, access == Access::Allow],
+    )?;
+
+    Ok(true)
+}
+
+// Takes `user_id` off the access control list of `room_name`, returning
+// whether they were on it.
+pub fn remove_access(
+    conn: &Connection,
+    room_name: &str,
+    user_id: usize,
+) -> Result<bool, rusqlite::Error> {
+    let deleted = conn.execute(
+        "DELETE FROM room_acl WHERE room_name = ?1 AND user_id = ?2",
+        params![room_name, user_id],
+    )?;
+
+    Ok(deleted > 0)
+}
+
+// Access control list of `room_name`, by user ID.
+pub fn room_acl(conn: &Connection, room_name: &str) -> Result<Vec<AclEntry>, rusqlite::Error> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT user_id, allowed FROM room_acl WHERE room_name = ?1 ORDER BY user_id",
+    )?;
+    let acl = stmt
+        .query_map(params![room_name], |row| {
+            Ok(AclEntry {
+                user_id: row.get(0)?,
+                access: if row.get(1)? {
+                    Access::Allow
+                } else {
+                    Access::Deny
+                },
+            })
+        })?
+        .collect();
+
+    acl
+}
+
+// Roles are stored by name. Missing or unknown roles grant nothing.
+fn parse_role(role: Option<String>) -> Role {
+    role.and_then(|role| role.parse().ok())
+        .unwrap_or(Role::Member)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+
+    #[test]
+    fn test_permits() {
+        assert!(!Role::Member.permits(Action::DeleteAnyMessage));
+        assert!(Role::Moderator.permits(Action::DeleteAnyMessage));
+        assert!(Role::Moderator.permits(Action::PinMessage));
+        assert!(!Role::Moderator.permits(Action::AssignRoomRoles));
+        assert!(Role::Admin.permits(Action::Administer));
+
+        assert_eq!("moderator".parse::<Role>().unwrap(), Role::Moderator);
+        assert!("owner".parse::<Role>().is_err());
+    }
+
+    #[test]
+    fn test_roles() {
+        let conn = Connection::open_in_memory().unwrap();
+        db::init_schema(&conn).unwrap();
+
+        let alice = auth::create_user(&conn, "alice", "hash").unwrap().unwrap();
+        let bob = auth::create_user(&conn, "bob", "hash").unwrap().unwrap();
+        assert_eq!(user_role(&conn, alice).unwrap(), Role::Member);
+
+        // Room roles only apply to their room
+        assert!(set_room_role(&conn, "room1", alice, Role::Moderator).unwrap());
+        assert_eq!(room_role(&conn, alice, "room1").unwrap(), Role::Moderator);
+        assert_eq!(room_role(&conn, alice, "room2").unwrap(), Role::Member);
+        assert!(permits(&conn, alice, Some("room1"), Action::PinMessage).unwrap());
+        assert!(!permits(&conn, alice, None, Action::PinMessage).unwrap());
+        assert_eq!(
+            room_roles(&conn, "room1").unwrap(),
+            vec![RoomRole {
+                user_id: alice,
+                role: Role::Moderator,
+            }]
+        );
+
+        // Server-wide roles apply to every room
+        assert!(set_user_role(&conn, bob, Role::Admin).unwrap());
+        assert_eq!(room_role(&conn, bob, "room2").unwrap(), Role::Admin);
+        assert!(permits(&conn, bob, None, Action::Administer).unwrap());
+
+        assert!(set_room_role(&conn, "room1", alice, Role::Member).unwrap());
+        assert!(room_roles(&conn, "room1").unwrap().is_empty());
+        assert!(!set_room_role(&conn, "room1", 100, Role::Admin).unwrap());
+        assert!(!set_user_role(&conn, 100, Role::Admin).unwrap());
+
+        // Owners are admins of their room, but only they may manage its owners
+        room::record_room(&conn, "room3", alice, true).unwrap();
+        assert_eq!(room_role(&conn, alice, "room3").unwrap(), Role::Admin);
+        assert!(permits(&conn, alice, Some("room3"), Action::ManageRoomOwners).unwrap());
+        set_room_role(&conn, "room3", bob, Role::Admin).unwrap();
+        set_user_role(&conn, bob, Role::Member).unwrap();
+        assert!(permits(&conn, bob, Some("room3"), Action::ManageRoomAccess).unwrap());
+        assert!(!permits(&conn, bob, Some("room3"), Action::ManageRoomOwners).unwrap());
+    }
+
+    #[test]
+    fn test_room_acl() {
+        let conn = Connection::open_in_memory().unwrap();
+        db::init_schema(&conn).unwrap();
+
+        let alice = auth::create_user(&conn, "alice", "hash").unwrap().unwrap();
+        let bob = auth::create_user(&conn, "bob", "hash").unwrap().unwrap();
+        let root = auth::create_user(&conn, "root", "hash").unwrap().unwrap();
+        set_room_role(&conn, "room1", root, Role::Admin).unwrap();
+        assert!(may_join(&conn, alice, "room1").unwrap());
+
+        // Denied users are kept out
+        assert!(set_access(&conn, "room1", bob, Access::Deny).unwrap());
+        assert!(!may_join(&conn, bob, "room1").unwrap());
+        assert!(may_join(&conn, alice, "room1").unwrap());
+        assert!(may_join(&conn, bob, "room2").unwrap());
+
+        // Once anyone is allowed, everyone else is kept out but admins
+        assert!(set_access(&conn, "room1", alice, Access::Allow).unwrap());
+        assert!(may_join(&conn, alice, "room1").unwrap());
+        assert!(may_join(&conn, root, "room1").unwrap());
+        let guest = auth::create_guest(&conn, None).unwrap();
+        assert!(!may_join(&conn, guest, "room1").unwrap());
+        assert_eq!(
+            room_acl(&conn, "room1").unwrap(),
+            vec![
+                AclEntry {
+                    user_id: alice,
+                    access: Access::Allow,
+                },
+                AclEntry {
+                    user_id: bob,
+                    access: Access::Deny,
+                },
+            ]
+        );
+
+        assert!(remove_access(&conn, "room1", alice).unwrap());
+        assert!(!remove_access(&conn, "room1", alice).unwrap());
+        assert!(may_join(&conn, guest, "room1").unwrap());
+        assert!(!set_access(&conn, "room1", 100, Access::Deny).unwrap());
+
+        // Private rooms only let in allowed users, their owners, and admins
+        let private = room::RoomSettings {
+            visibility: Visibility::Private,
+            ..room::RoomSettings::default()
+        };
+        room::create_room(&conn, "room4", root, &private, None).unwrap();
+        assert!(may_join(&conn, root, "room4").unwrap());
+        assert!(!may_join(&conn, alice, "room4").unwrap());
+        set_access(&conn, "room4", alice, Access::Allow).unwrap();
+        assert!(may_join(&conn, alice, "room4").unwrap());
+        assert!(!may_join(&conn, guest, "room4").unwrap());
+        assert!(!may_join(&conn, bob, "room4").unwrap());
+        room::add_member(&conn, "room4", bob, Some(root)).unwrap();
+        assert!(may_join(&conn, bob, "room4").unwrap());
+
+        // Banned users are kept out, even if allowed
+        let ban = moderation::NewSanction::default();
+        moderation::impose(&conn, "room4", bob, SanctionKind::Ban, root, &ban).unwrap();
+        assert!(!may_join(&conn, bob, "room4").unwrap());
+        moderation::impose(&conn, "room4", root, SanctionKind::Ban, root, &ban).unwrap();
+        assert!(may_join(&conn, root, "room4").unwrap());
+    }
+}
@@ -0,0 +1,225 @@
+use std::{sync::Arc, time::Duration};
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+
+// What a classifier makes of a message.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(tag = "verdict", rename_all = "lowercase")]
+pub enum Verdict {
+    // The message is delivered as is
+    Allow,
+    // The message is delivered, but flagged for moderators to review
+    Flag,
+    // The message is refused, with an error sent back to its author
+    Block {
+        #[serde(default)]
+        reason: Option<String>,
+    },
+}
+
+// A message submitted for classification, before it is sent, or before a
+// message is edited into it.
+#[derive(Debug, Serialize)]
+pub struct Submission<'a> {
+    pub room: &'a str,
+    pub user_id: usize,
+    pub text: &'a str,
+}
+
+// Judges the content of messages, e.g. by asking an external service.
+#[async_trait]
+pub trait Classifier: Send + Sync {
+    async fn classify(&self, submission: &Submission<'_>) -> Result<Verdict, anyhow::Error>;
+}
+
+// POSTs messages as JSON to an external service, which answers with a verdict:
+// `{"verdict": "allow"}`, `{"verdict": "flag"}`, or `{"verdict": "block"}`,
+// optionally with a `reason` told to the author.
+pub struct HttpClassifier {
+    client: Client,
+    url: String,
+}
+
+impl HttpClassifier {
+    pub fn new(client: Client, url: String) -> Self {
+        HttpClassifier { client, url }
+    }
+}
+
+#[async_trait]
+impl Classifier for HttpClassifier {
+    async fn classify(&self, submission: &Submission<'_>) -> Result<Verdict, anyhow::Error> {
+        let verdict = self
+            .client
+            .post(&self.url)
+            .json(submission)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        Ok(verdict)
+    }
+}
+
+// Hook point of the message pipeline, where messages are put through the
+// classifier, if any. Classifiers failing or not answering within `timeout`
+// let messages through, unless `fail_closed` is set.
+pub struct ContentHook {
+    classifier: Option<Arc<dyn Classifier>>,
+    timeout: Duration,
+    fail_closed: bool,
+}
+
+impl ContentHook {
+    pub fn new(
+        classifier: Option<Arc<dyn Classifier>>,
+        timeout: Duration,
+        fail_closed: bool,
+    ) -> Self {
+        ContentHook {
+            classifier,
+            timeout,
+            fail_closed,
+        }
+    }
+
+    // Puts `text`, sent by `user_id` to `room`, through the classifier.
+    // Returns whether the message is to be flagged, and errs if it is to be
+    // refused.
+    pub async fn check(
+        &self,
+        room: &str,
+        user_id: usize,
+        text: &str,
+    ) -> Result<bool, anyhow::Error> {
+        let classifier = match &self.classifier {
+            Some(classifier) => classifier,
+            None => return Ok(false),
+        };
+
+        let submission = Submission {
+            room,
+            user_id,
+            text,
+        };
+        let verdict =
+            match tokio::time::timeout(self.timeout, classifier.classify(&submission)).await {
+                Ok(Ok(verdict)) => verdict,
+                Ok(Err(e)) => return self.failed(e),
+                Err(_) => return self.failed(anyhow!("No verdict within {:?}", self.timeout)),
+            };
+
+        match verdict {
+            Verdict::Allow => Ok(false),
+            Verdict::Flag => Ok(true),
+            Verdict::Block { reason } => Err(match reason {
+                Some(reason) => anyhow!("Message was blocked: {}", reason),
+                None => anyhow!("Message was blocked"),
+            }),
+        }
+    }
+
+    fn failed(&self, e: anyhow::Error) -> Result<bool, anyhow::Error> {
+        eprintln!("Failed to classify message: {}", e);
+
+        if self.fail_closed {
+            Err(anyhow!("Message could not be checked, try again later"))
+        } else {
+            Ok(false)
+        }
+    }
+}
+
+// The content hook set up by `config`: calling the classifier at
+// `--classifier-url` through `client`, if given.
+pub fn hook(config: &Config, client: &Client) -> ContentHook {
+    let classifier = config.classifier_url.as_ref().map(|url| {
+        Arc::new(HttpClassifier::new(client.clone(), url.clone())) as Arc<dyn Classifier>
+    });
+
+    ContentHook::new(
+        classifier,
+        Duration::from_millis(config.classifier_timeout_ms),
+        config.classifier_fail_closed,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Blocks messages containing "spam", flags those containing "meh", fails
+    // on those containing "broken", and takes forever with those containing
+    // "slow".
+    struct StubClassifier;
+
+    #[async_trait]
+    impl Classifier for StubClassifier {
+        async fn classify(&self, submission: &Submission<'_>) -> Result<Verdict, anyhow::Error> {
+            if submission.text.contains("slow") {
+                tokio::time::sleep(Duration::from_secs(60)).await;
+            }
+            if submission.text.contains("broken") {
+                return Err(anyhow!("Classifier is down"));
+            }
+
+            Ok(if submission.text.contains("spam") {
+                Verdict::Block {
+                    reason: Some(String::from("Spam")),
+                }
+            } else if submission.text.contains("meh") {
+                Verdict::Flag
+            } else {
+                Verdict::Allow
+            })
+        }
+    }
+
+    #[test]
+    fn test_parse_verdict() {
+        let verdict: Verdict = serde_json::from_str(r#"{"verdict": "allow"}"#).unwrap();
+        assert_eq!(verdict, Verdict::Allow);
+
+        let verdict: Verdict =
+            serde_json::from_str(r#"{"verdict": "block", "reason": "Toxic", "score": 0.9}"#)
+                .unwrap();
+        assert_eq!(
+            verdict,
+            Verdict::Block {
+                reason: Some(String::from("Toxic"))
+            }
+        );
+
+        assert!(serde_json::from_str::<Verdict>(r#"{"verdict": "maybe"}"#).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_content_hook() {
+        let timeout = Duration::from_millis(50);
+        let fail_open = ContentHook::new(Some(Arc::new(StubClassifier)), timeout, false);
+
+        assert!(!fail_open.check("room1", 1, "hello").await.unwrap());
+        assert!(fail_open.check("room1", 1, "meh").await.unwrap());
+        let blocked = fail_open.check("room1", 1, "spam").await.unwrap_err();
+        assert_eq!(blocked.to_string(), "Message was blocked: Spam");
+
+        // Failures let messages through, unless failing closed
+        assert!(!fail_open.check("room1", 1, "slow").await.unwrap());
+        assert!(!fail_open.check("room1", 1, "broken").await.unwrap());
+
+        let fail_closed = ContentHook::new(Some(Arc::new(StubClassifier)), timeout, true);
+        assert!(fail_closed.check("room1", 1, "slow").await.is_err());
+        assert!(fail_closed.check("room1", 1, "broken").await.is_err());
+        assert!(!fail_closed.check("room1", 1, "hello").await.unwrap());
+
+        let disabled = ContentHook::new(None, timeout, true);
+        assert!(!disabled.check("room1", 1, "spam").await.unwrap());
+    }
+}
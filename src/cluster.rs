@@ -0,0 +1,912 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::anyhow;
+use futures::StreamExt;
+use redis::{
+    aio::{Connection, PubSub},
+    Client, RedisError,
+};
+use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::sync::{
+    mpsc::{self, UnboundedReceiver, UnboundedSender},
+    RwLock,
+};
+use warp::ws::Message;
+
+use crate::{
+    auth::Principal,
+    authz::Role,
+    db::{self, Forwarded},
+    history::{self, HistoryFilter},
+    protocol::ServerEvent,
+    search,
+    shutdown::Shutdown,
+    user::{self, Post, Rooms},
+};
+
+// Prefixes of the Redis channels events are published to, one channel per
+// room, and one per user for events sent to a user wherever they are.
+const ROOM_CHANNEL_PREFIX: &str = "bi-chat:rooms:";
+const USER_CHANNEL_PREFIX: &str = "bi-chat:users:";
+
+// How long to wait before subscribing again once the connection to Redis is
+// lost.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+// How often peers are looked up, and asked who is connected to them, and how
+// long they have to answer.
+const PEER_REFRESH_INTERVAL: Duration = Duration::from_secs(5);
+const PEER_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Whose connections an event is delivered to.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Target {
+    // Every connection to a room
+    Room(String),
+    // Every connection of a user, in whichever room
+    User(usize),
+}
+
+impl Target {
+    fn channel(&self) -> String {
+        match self {
+            Target::Room(room_name) => format!("{}{}", ROOM_CHANNEL_PREFIX, room_name),
+            Target::User(user_id) => format!("{}{}", USER_CHANNEL_PREFIX, user_id),
+        }
+    }
+}
+
+// An event, as sent from one instance to the others.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Envelope {
+    // Instance the event was sent by
+    origin: String,
+    to: Target,
+    // Connection the event is not delivered to, for having caused it
+    #[serde(default)]
+    skip: Option<RemoteConn>,
+    event: serde_json::Value,
+}
+
+// A connection to one of the instances, as told to the others.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct RemoteConn {
+    pub instance_id: String,
+    pub conn_id: usize,
+}
+
+// Users connected to an instance, by room, as told to its peers.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct Membership {
+    pub instance_id: String,
+    pub node_id: u8,
+    pub rooms: HashMap<String, Vec<usize>>,
+}
+
+impl Membership {
+    // Whether `to` has connections to this instance.
+    fn hosts(&self, to: &Target) -> bool {
+        match to {
+            Target::Room(room_name) => self.rooms.contains_key(room_name),
+            Target::User(user_id) => self.rooms.values().any(|users| users.contains(user_id)),
+        }
+    }
+}
+
+// What peers told of who is connected to them, by address.
+type Peers = Arc<RwLock<HashMap<String, Membership>>>;
+
+// What an instance asks the owner of a room to do for a user connected to it.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RoomRequest {
+    // Sequences, persists and broadcasts a message the instance let through,
+    // answered with `Posted`
+    Post {
+        room: String,
+        user_id: usize,
+        sender: RemoteConn,
+        post: Post,
+    },
+    // Handles a frame acting on the messages of the room, e.g. editing one,
+    // as `user_id` with `role` in the room. Answered with `Sent`
+    Frame {
+        room: String,
+        user_id: usize,
+        nick: Option<String>,
+        role: Role,
+        principal: Principal,
+        sender: RemoteConn,
+        frame: String,
+    },
+    // Replays the last `limit` messages of the room, or those after `since`,
+    // as joining it does. Answered with `Sent`, along with the sequence number
+    // of the last message of the room
+    History {
+        room: String,
+        user_id: usize,
+        sender: RemoteConn,
+        limit: usize,
+        since: Option<i64>,
+    },
+    // Replays the messages of the room after sequence number `after_seq`.
+    // Answered with `Sent`
+    Missed {
+        room: String,
+        user_id: usize,
+        sender: RemoteConn,
+        after_seq: i64,
+    },
+    // Cross-posts message `original` of an announcement room the room follows,
+    // answered with `Posted`, or null if the room does not exist
+    CrossPost {
+        room: String,
+        user_id: usize,
+        original: Forwarded,
+        text: String,
+        ttl_secs: Option<u64>,
+    },
+    // Reads the messages of the room for a request the instance authorized,
+    // answered with what `RoomRead::run` returns
+    Read {
+        room: String,
+        read: RoomRead,
+    },
+}
+
+impl RoomRequest {
+    pub fn room(&self) -> &str {
+        match self {
+            RoomRequest::Post { room, .. }
+            | RoomRequest::Frame { room, .. }
+            | RoomRequest::History { room, .. }
+            | RoomRequest::Missed { room, .. }
+            | RoomRequest::CrossPost { room, .. }
+            | RoomRequest::Read { room, .. } => room,
+        }
+    }
+}
+
+// A message posted by the owner of its room.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Posted {
+    pub id: i64,
+    pub seq: i64,
+}
+
+// What the owner of a room sent the user it handled a request for.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Sent {
+    pub events: Vec<String>,
+    pub last_seq: Option<i64>,
+}
+
+// Reads of the messages of a room, as answered to HTTP requests.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RoomRead {
+    // A page of messages `viewer` may see, as `history::room_messages` lists
+    Messages {
+        viewer: usize,
+        filter: HistoryFilter,
+        limit: usize,
+        offset: usize,
+    },
+    // Messages matching `match_query`, as `search::search` finds them
+    Search {
+        viewer: usize,
+        match_query: String,
+        limit: usize,
+        offset: usize,
+    },
+    // Pinned messages
+    Pins,
+    // How far each user read
+    ReadMarkers,
+}
+
+impl RoomRead {
+    pub fn run(
+        &self,
+        conn: &rusqlite::Connection,
+        room_name: &str,
+    ) -> Result<serde_json::Value, rusqlite::Error> {
+        // Serializing these types can not fail: all keys are strings
+        let value = match self {
+            RoomRead::Messages {
+                viewer,
+                filter,
+                limit,
+                offset,
+            } => serde_json::to_value(history::room_messages(
+                conn, room_name, *viewer, filter, *limit, *offset,
+            )?),
+            RoomRead::Search {
+                viewer,
+                match_query,
+                limit,
+                offset,
+            } => serde_json::to_value(search::search(
+                conn,
+                room_name,
+                *viewer,
+                match_query,
+                *limit,
+                *offset,
+            )?),
+            RoomRead::Pins => serde_json::to_value(db::room_pins(conn, room_name)?),
+            RoomRead::ReadMarkers => serde_json::to_value(db::read_markers(conn, room_name)?),
+        };
+
+        Ok(value.expect("Failed to serialize room read"))
+    }
+}
+
+// Peers of an instance, and how it reaches them.
+#[derive(Clone, Debug)]
+struct PeerLinks {
+    memberships: Peers,
+    // Sends the cluster secret along with every request
+    client: reqwest::Client,
+}
+
+// Shares rooms between instances of the server, either through Redis pub/sub,
+// or by sending events straight to the peers with connections to their room
+// or user: what is broadcast to a room on one instance is delivered by the
+// others to the connections they have to the room. Peers also hand the rooms
+// out between them, each room having one owner sequencing and persisting its
+// messages: see `owner`.
+#[derive(Clone, Debug)]
+pub struct Cluster {
+    instance_id: Arc<str>,
+    node_id: u8,
+    // Events waiting to be sent, in order
+    tx: UnboundedSender<Envelope>,
+    // Set when peers are discovered, rather than sharing a Redis server
+    peers: Option<PeerLinks>,
+}
+
+impl Cluster {
+    // Connects to the Redis server at `url`, subscribing to the events of
+    // every room and user, which are delivered to the connections in `rooms`
+    // until shutdown.
+    pub async fn connect(
+        url: &str,
+        node_id: u8,
+        rooms: Rooms,
+        shutdown: Shutdown,
+    ) -> Result<Self, RedisError> {
+        let client = Client::open(url)?;
+        let publisher = client.get_tokio_connection().await?;
+        let subscriber = subscribe(&client).await?;
+
+        let instance_id = new_instance_id();
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::task::spawn(publish(client.clone(), publisher, rx));
+        tokio::task::spawn(receive(
+            client,
+            subscriber,
+            instance_id.clone(),
+            rooms,
+            shutdown,
+        ));
+
+        Ok(Cluster {
+            instance_id,
+            node_id,
+            tx,
+            peers: None,
+        })
+    }
+
+    // Shares rooms with the instances at `peer_addrs`, and those `peer_dns`
+    // resolves to, as `<host>:<port>`. Both are looked up again every few
+    // seconds until shutdown, asking each peer who is connected to it. Peers
+    // authenticate to each other with `secret`.
+    pub fn discover(
+        peer_addrs: Vec<String>,
+        peer_dns: Option<String>,
+        secret: &str,
+        node_id: u8,
+        shutdown: Shutdown,
+    ) -> Self {
+        let instance_id = new_instance_id();
+        let peers = Peers::default();
+        let mut credential = HeaderValue::from_str(&format!("Bearer {}", secret))
+            .expect("Cluster secret is not a valid header value");
+        credential.set_sensitive(true);
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, credential);
+        let client = reqwest::Client::builder()
+            .timeout(PEER_TIMEOUT)
+            .default_headers(headers)
+            .build()
+            .expect("Unable to set up HTTP client for peers");
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::task::spawn(send_to_peers(client.clone(), peers.clone(), rx));
+        tokio::task::spawn(refresh_peers(
+            PeerDiscovery {
+                client: client.clone(),
+                instance_id: instance_id.clone(),
+                node_id,
+                peer_addrs,
+                peer_dns,
+            },
+            peers.clone(),
+            shutdown,
+        ));
+
+        Cluster {
+            instance_id,
+            node_id,
+            tx,
+            peers: Some(PeerLinks {
+                memberships: peers,
+                client,
+            }),
+        }
+    }
+
+    // Address of the peer owning `room_name`, unless this instance owns it or
+    // rooms are shared through Redis. Owners sequence and persist the messages
+    // of their rooms, which other instances hand what is sent to the room,
+    // and ask for its history. Every instance picks the same owner among those
+    // it knows of, by rendezvous hashing of their node IDs, so that rooms only
+    // move as instances join or leave the cluster. Of instances given the same
+    // node ID, which would otherwise both claim rooms and number messages
+    // alike, only the one with the lowest instance ID owns rooms.
+    pub async fn owner(&self, room_name: &str) -> Option<String> {
+        let peers = self.peers.as_ref()?.memberships.read().await;
+        let instances: Vec<_> = peers
+            .values()
+            .map(|membership| (membership.instance_id.as_str(), membership.node_id))
+            .chain(std::iter::once((&*self.instance_id, self.node_id)))
+            .collect();
+        let own_score = may_own_rooms(&self.instance_id, self.node_id, &instances)
+            .then(|| owner_score(room_name, self.node_id));
+
+        peers
+            .iter()
+            .filter(|(_, membership)| {
+                may_own_rooms(&membership.instance_id, membership.node_id, &instances)
+            })
+            .map(|(addr, membership)| (owner_score(room_name, membership.node_id), addr))
+            .filter(|(score, _)| own_score.is_none_or(|own_score| *score > own_score))
+            .max()
+            .map(|(_, addr)| addr.clone())
+    }
+
+    // Has the peer at `owner` handle `request` for this instance, answering
+    // with `T`. Fails with the reason the peer gave for refusing it.
+    pub async fn forward<T: DeserializeOwned>(
+        &self,
+        owner: &str,
+        request: &RoomRequest,
+    ) -> Result<T, anyhow::Error> {
+        let links = self
+            .peers
+            .as_ref()
+            .ok_or_else(|| anyhow!("Rooms are not owned when sharing a Redis server"))?;
+        let response = links
+            .client
+            .post(format!("http://{}/cluster/rooms", owner))
+            .json(request)
+            .send()
+            .await?;
+
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response.json().await?);
+        }
+        let refusal: Option<serde_json::Value> = response.json().await.ok();
+        match refusal
+            .as_ref()
+            .and_then(|refusal| refusal["error"].as_str())
+        {
+            Some(reason) => Err(anyhow!("{}", reason)),
+            None => Err(anyhow!(
+                "Owner of room {} answered {}",
+                request.room(),
+                status
+            )),
+        }
+    }
+
+    // Connection `conn_id` of this instance, as told to the others.
+    pub fn remote_conn(&self, conn_id: usize) -> RemoteConn {
+        RemoteConn {
+            instance_id: self.instance_id.to_string(),
+            conn_id,
+        }
+    }
+
+    // Relays the events of `room_name` to the other instances.
+    pub fn relay(&self, room_name: &str) -> RoomRelay {
+        RoomRelay {
+            room_name: String::from(room_name),
+            cluster: self.clone(),
+        }
+    }
+
+    // Sends `event` to the connections other instances have of `user_id`.
+    pub fn notify_user(&self, user_id: usize, event: &ServerEvent) {
+        self.send(Target::User(user_id), event, None);
+    }
+
+    fn send(&self, to: Target, event: &ServerEvent, skip: Option<RemoteConn>) {
+        let envelope = Envelope {
+            origin: self.instance_id.to_string(),
+            to,
+            skip,
+            // Serializing these types can not fail: all keys are strings
+            event: serde_json::to_value(event).expect("Failed to serialize server event"),
+        };
+
+        // This will only fail once the sender is gone, on shutdown
+        if let Err(_closed) = self.tx.send(envelope) {}
+    }
+
+    // Users connected to peers, as far as they told. Unknown, and so empty,
+    // when sharing a Redis server.
+    pub async fn remote_user_ids(&self) -> HashSet<usize> {
+        match &self.peers {
+            Some(links) => links
+                .memberships
+                .read()
+                .await
+                .values()
+                .flat_map(|membership| membership.rooms.values().flatten().copied())
+                .collect(),
+            None => HashSet::new(),
+        }
+    }
+
+    // Who is connected to this instance, to tell peers.
+    pub async fn membership(&self, rooms: &Rooms) -> Membership {
+        let mut membership = Membership {
+            instance_id: self.instance_id.to_string(),
+            node_id: self.node_id,
+            rooms: HashMap::new(),
+        };
+        for (room_name, room) in rooms.read().await.iter() {
+            let room = room.lock().await;
+            if room.users.is_empty() {
+                continue;
+            }
+
+            let mut user_ids: Vec<_> = room.users.values().map(|member| member.user_id).collect();
+            user_ids.sort_unstable();
+            user_ids.dedup();
+            membership.rooms.insert(room_name.clone(), user_ids);
+        }
+
+        membership
+    }
+
+    // Delivers an event sent by a peer to the connections in `rooms`.
+    pub async fn deliver(&self, envelope: Envelope, rooms: &Rooms) {
+        deliver(envelope, &self.instance_id, rooms).await
+    }
+}
+
+fn new_instance_id() -> Arc<str> {
+    Arc::from(hex::encode(rand::random::<[u8; 16]>()))
+}
+
+// How strongly node `node_id` claims `room_name`: the node with the highest
+// score owns it.
+// Whether the instance `instance_id`, given `node_id`, may own rooms: unless
+// another of the `instances`, by instance and node ID, has its node ID and a
+// lower instance ID.
+fn may_own_rooms(instance_id: &str, node_id: u8, instances: &[(&str, u8)]) -> bool {
+    !instances
+        .iter()
+        .any(|(other, other_node_id)| *other_node_id == node_id && *other < instance_id)
+}
+
+fn owner_score(room_name: &str, node_id: u8) -> u64 {
+    let digest = Sha256::new()
+        .chain_update(room_name)
+        .chain_update([node_id])
+        .finalize();
+    let mut score = [0; 8];
+    score.copy_from_slice(&digest[..8]);
+
+    u64::from_be_bytes(score)
+}
+
+// Sends what is broadcast to a room to the other instances, for them to
+// deliver.
+#[derive(Clone, Debug)]
+pub struct RoomRelay {
+    room_name: String,
+    cluster: Cluster,
+}
+
+impl RoomRelay {
+    // Sends `event` to the other instances, for them to deliver to every
+    // connection to the room except `skip`.
+    pub fn publish(&self, event: &ServerEvent, skip: Option<&RemoteConn>) {
+        self.cluster
+            .send(Target::Room(self.room_name.clone()), event, skip.cloned());
+    }
+}
+
+// Sends an event sent by another instance to the connections this one has to
+// its room or of its user, unless this instance sent it.
+async fn deliver(envelope: Envelope, instance_id: &str, rooms: &Rooms) {
+    if envelope.origin == instance_id {
+        return;
+    }
+
+    let msg = Message::text(envelope.event.to_string());
+    let skip_conn_id = envelope
+        .skip
+        .filter(|skip| skip.instance_id == instance_id)
+        .map(|skip| skip.conn_id);
+    match &envelope.to {
+        Target::Room(room_name) => {
+            if let Some(room) = rooms.read().await.get(room_name) {
+                let mut room = room.lock().await;
+                // Messages sequenced by the owner of the room tell what users
+                // joining here missed: see `add_user_to_room`
+                if let Some(seq) = envelope.event.get("seq").and_then(|seq| seq.as_i64()) {
+                    room.observe_seq(seq);
+                }
+                room.deliver(&msg, skip_conn_id);
+            }
+        }
+        Target::User(user_id) => user::deliver_to_user(*user_id, rooms, &msg).await,
+    }
+}
+
+async fn subscribe(client: &Client) -> Result<PubSub, RedisError> {
+    let mut pubsub = client.get_tokio_connection().await?.into_pubsub();
+    pubsub
+        .psubscribe(&[
+            format!("{}*", ROOM_CHANNEL_PREFIX),
+            format!("{}*", USER_CHANNEL_PREFIX),
+        ])
+        .await?;
+
+    Ok(pubsub)
+}
+
+// Publishes what `rx` is sent to Redis, in order. Events that fail to be
+// published are dropped, connecting again for the next ones.
+async fn publish(client: Client, publisher: Connection, mut rx: UnboundedReceiver<Envelope>) {
+    let mut publisher = Some(publisher);
+    while let Some(envelope) = rx.recv().await {
+        if publisher.is_none() {
+            match client.get_tokio_connection().await {
+                Ok(conn) => publisher = Some(conn),
+                Err(e) => eprintln!("Failed to connect to Redis: {}", e),
+            }
+        }
+
+        if let Some(conn) = publisher.as_mut() {
+            let channel = envelope.to.channel();
+            // Serializing these types can not fail: all keys are strings
+            let payload = serde_json::to_string(&envelope).expect("Failed to serialize envelope");
+            let published = redis::cmd("PUBLISH")
+                .arg(&channel)
+                .arg(&payload)
+                .query_async::<_, ()>(conn)
+                .await;
+            if let Err(e) = published {
+                eprintln!("Failed to publish to {}: {}", channel, e);
+                publisher = None;
+            }
+        }
+    }
+}
+
+// Delivers the events published to Redis by other instances until shutdown,
+// subscribing again whenever the connection to Redis is lost.
+async fn receive(
+    client: Client,
+    subscriber: PubSub,
+    instance_id: Arc<str>,
+    rooms: Rooms,
+    mut shutdown: Shutdown,
+) {
+    let mut subscriber = Some(subscriber);
+    while !shutdown.is_shutdown() {
+        let pubsub = match subscriber.take() {
+            Some(pubsub) => pubsub,
+            None => {
+                tokio::select! {
+                    _ = tokio::time::sleep(RECONNECT_DELAY) => {}
+                    _ = shutdown.async_listen() => break,
+                }
+                match subscribe(&client).await {
+                    Ok(pubsub) => pubsub,
+                    Err(e) => {
+                        eprintln!("Failed to subscribe to Redis: {}", e);
+                        continue;
+                    }
+                }
+            }
+        };
+
+        let mut messages = pubsub.into_on_message();
+        loop {
+            tokio::select! {
+                msg = messages.next() => match msg {
+                    Some(msg) => {
+                        let envelope = msg
+                            .get_payload::<String>()
+                            .ok()
+                            .and_then(|payload| serde_json::from_str(&payload).ok());
+                        if let Some(envelope) = envelope {
+                            deliver(envelope, &instance_id, &rooms).await;
+                        }
+                    }
+                    None => {
+                        eprintln!("Lost connection to Redis, subscribing again");
+                        break;
+                    }
+                },
+                _ = shutdown.async_listen() => break,
+            }
+        }
+    }
+}
+
+// Sends what `rx` is sent to the peers with connections to its room or user,
+// in order. Events that fail to reach a peer are dropped.
+async fn send_to_peers(client: reqwest::Client, peers: Peers, mut rx: UnboundedReceiver<Envelope>) {
+    while let Some(envelope) = rx.recv().await {
+        let addrs: Vec<_> = peers
+            .read()
+            .await
+            .iter()
+            .filter(|(_, membership)| membership.hosts(&envelope.to))
+            .map(|(addr, _)| addr.clone())
+            .collect();
+
+        for addr in addrs {
+            let sent = client
+                .post(format!("http://{}/cluster/deliver", addr))
+                .json(&envelope)
+                .send()
+                .await
+                .and_then(|response| response.error_for_status());
+            if let Err(e) = sent {
+                eprintln!("Failed to send event to peer {}: {}", addr, e);
+            }
+        }
+    }
+}
+
+// How peers are found, and asked who is connected to them.
+struct PeerDiscovery {
+    client: reqwest::Client,
+    instance_id: Arc<str>,
+    node_id: u8,
+    peer_addrs: Vec<String>,
+    peer_dns: Option<String>,
+}
+
+impl PeerDiscovery {
+    // Addresses of the peers, some of which may be this instance itself when
+    // looked up through DNS.
+    async fn addrs(&self) -> HashSet<String> {
+        let mut addrs: HashSet<_> = self.peer_addrs.iter().cloned().collect();
+        if let Some(name) = &self.peer_dns {
+            match tokio::net::lookup_host(name.as_str()).await {
+                Ok(found) => addrs.extend(found.map(|addr| addr.to_string())),
+                Err(e) => eprintln!("Failed to look up peers at {}: {}", name, e),
+            }
+        }
+
+        addrs
+    }
+
+    async fn membership(&self, addr: &str) -> Result<Membership, reqwest::Error> {
+        self.client
+            .get(format!("http://{}/cluster/membership", addr))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await
+    }
+}
+
+// Asks every peer who is connected to it, every few seconds until shutdown.
+// Peers are dropped once they fail to answer, until they answer again. Peers
+// sharing a node ID with this instance or another peer are reported as they
+// are found, as most of them are left out of owning rooms: see `owner`.
+async fn refresh_peers(discovery: PeerDiscovery, peers: Peers, mut shutdown: Shutdown) {
+    let mut interval = tokio::time::interval(PEER_REFRESH_INTERVAL);
+    // Peers known to share their node ID
+    let mut clashing = HashSet::new();
+    while !shutdown.is_shutdown() {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = shutdown.async_listen() => break,
+        }
+
+        let mut memberships = HashMap::new();
+        for addr in discovery.addrs().await {
+            let known = peers.read().await.contains_key(&addr);
+            match discovery.membership(&addr).await {
+                Ok(membership) if membership.instance_id == *discovery.instance_id => {}
+                Ok(membership) => {
+                    if !known {
+                        eprintln!("Peer {} joined the cluster", addr);
+                    }
+                    memberships.insert(addr, membership);
+                }
+                Err(e) if known => eprintln!("Peer {} left the cluster: {}", addr, e),
+                Err(_) => {}
+            }
+        }
+
+        let mut clashes = HashSet::new();
+        for (addr, membership) in &memberships {
+            let shared = membership.node_id == discovery.node_id
+                || memberships.iter().any(|(other, other_membership)| {
+                    other != addr && other_membership.node_id == membership.node_id
+                });
+            if shared && !clashing.contains(addr) {
+                eprintln!(
+                    "Peer {} has node ID {}, as does another instance of the cluster: \
+                        give each one a different --node-id. Only the one with the \
+                        lowest instance ID among them owns rooms meanwhile",
+                    addr, membership.node_id
+                );
+            }
+            if shared {
+                clashes.insert(addr.clone());
+            }
+        }
+        clashing = clashes;
+        *peers.write().await = memberships;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_envelope() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let cluster = Cluster {
+            instance_id: Arc::from("local"),
+            node_id: 0,
+            tx,
+            peers: None,
+        };
+        let event = ServerEvent::Delete {
+            id: 1,
+            room: String::from("lobby"),
+            deleted_by: 2,
+        };
+        cluster.relay("lobby").publish(&event, None);
+        cluster.notify_user(3, &event);
+
+        let envelope = rx.try_recv().unwrap();
+        assert_eq!(envelope.origin, "local");
+        assert_eq!(envelope.to.channel(), "bi-chat:rooms:lobby");
+        assert_eq!(envelope.event, serde_json::to_value(&event).unwrap());
+
+        // Envelopes are sent as JSON, to Redis or peers alike
+        let sent = serde_json::to_string(&rx.try_recv().unwrap()).unwrap();
+        let received: Envelope = serde_json::from_str(&sent).unwrap();
+        assert_eq!(received.to, Target::User(3));
+        assert_eq!(received.to.channel(), "bi-chat:users:3");
+    }
+
+    #[test]
+    fn test_membership_hosts() {
+        let membership = Membership {
+            instance_id: String::from("remote"),
+            node_id: 1,
+            rooms: vec![(String::from("lobby"), vec![1, 2])]
+                .into_iter()
+                .collect(),
+        };
+
+        assert!(membership.hosts(&Target::Room(String::from("lobby"))));
+        assert!(!membership.hosts(&Target::Room(String::from("general"))));
+        assert!(membership.hosts(&Target::User(2)));
+        assert!(!membership.hosts(&Target::User(3)));
+    }
+
+    #[tokio::test]
+    async fn test_owner() {
+        // Nodes 0 to 2, each knowing of the others
+        let mut clusters = Vec::new();
+        for node_id in 0..3u8 {
+            let memberships: HashMap<_, _> = (0..3u8)
+                .filter(|peer| *peer != node_id)
+                .map(|peer| {
+                    let membership = Membership {
+                        instance_id: format!("instance{}", peer),
+                        node_id: peer,
+                        rooms: HashMap::new(),
+                    };
+                    (format!("node{}", peer), membership)
+                })
+                .collect();
+            let (tx, _) = mpsc::unbounded_channel();
+            clusters.push(Cluster {
+                instance_id: Arc::from(format!("instance{}", node_id)),
+                node_id,
+                tx,
+                peers: Some(PeerLinks {
+                    memberships: Arc::new(RwLock::new(memberships)),
+                    client: reqwest::Client::new(),
+                }),
+            });
+        }
+
+        // Every node picks the same owner, and rooms are spread between them
+        let mut owners = HashSet::new();
+        for i in 0..20 {
+            let room_name = format!("room{}", i);
+            let mut picked = HashSet::new();
+            for cluster in &clusters {
+                let owner = cluster.owner(&room_name).await;
+                picked.insert(owner.unwrap_or_else(|| format!("node{}", cluster.node_id)));
+            }
+            assert_eq!(picked.len(), 1);
+            owners.extend(picked);
+        }
+        assert_eq!(owners.len(), 3);
+
+        // Of instances given the same node ID, only one owns rooms
+        let memberships: HashMap<_, _> = (0..3u8)
+            .map(|peer| {
+                let membership = Membership {
+                    instance_id: format!("instance{}", peer),
+                    node_id: peer,
+                    rooms: HashMap::new(),
+                };
+                (format!("node{}", peer), membership)
+            })
+            .collect();
+        let (tx, _) = mpsc::unbounded_channel();
+        let clashing = Cluster {
+            instance_id: Arc::from("instance3"),
+            node_id: 1,
+            tx,
+            peers: Some(PeerLinks {
+                memberships: Arc::new(RwLock::new(memberships)),
+                client: reqwest::Client::new(),
+            }),
+        };
+        for i in 0..20 {
+            let room_name = format!("room{}", i);
+            let owner = clusters[0]
+                .owner(&room_name)
+                .await
+                .unwrap_or_else(|| String::from("node0"));
+            assert_eq!(clashing.owner(&room_name).await, Some(owner));
+        }
+        let instances = [("instance1", 1), ("instance3", 1), ("instance2", 2)];
+        assert!(may_own_rooms("instance1", 1, &instances));
+        assert!(!may_own_rooms("instance3", 1, &instances));
+        assert!(may_own_rooms("instance2", 2, &instances));
+
+        // Rooms are not owned when sharing a Redis server
+        let (tx, _) = mpsc::unbounded_channel();
+        let redis = Cluster {
+            instance_id: Arc::from("redis"),
+            node_id: 0,
+            tx,
+            peers: None,
+        };
+        assert_eq!(redis.owner("room0").await, None);
+    }
+}
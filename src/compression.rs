@@ -0,0 +1,100 @@
+use rusqlite::{
+    functions::FunctionFlags,
+    types::{FromSql, FromSqlError, FromSqlResult, ValueRef},
+    Connection,
+};
+
+// Messages shorter than this, in bytes, are stored as is: compressing them
+// saves little if anything.
+pub const MIN_COMPRESSED_LEN: usize = 128;
+
+const LEVEL: i32 = 3;
+
+// `text` compressed with zstd, unless too short to be worth it, or left no
+// shorter.
+pub fn compress(text: &str) -> Option<Vec<u8>> {
+    if text.len() < MIN_COMPRESSED_LEN {
+        return None;
+    }
+
+    zstd::encode_all(text.as_bytes(), LEVEL)
+        .ok()
+        .filter(|compressed| compressed.len() < text.len())
+}
+
+pub fn decompress(compressed: &[u8]) -> Result<String, anyhow::Error> {
+    let text = zstd::decode_all(compressed)?;
+
+    Ok(String::from_utf8(text)?)
+}
+
+// Text of a message as read from the DB, which stores it either as text, or
+// compressed as a blob.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MessageText(pub String);
+
+impl FromSql for MessageText {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        match value {
+            ValueRef::Blob(compressed) => decompress(compressed)
+                .map(MessageText)
+                .map_err(|e| FromSqlError::Other(e.into())),
+            value => String::column_result(value).map(MessageText),
+        }
+    }
+}
+
+impl From<MessageText> for String {
+    fn from(text: MessageText) -> Self {
+        text.0
+    }
+}
+
+// Registers `message_text(message)` with `conn`, giving the text of a message
+// however it is stored. The full-text index of messages relies on it, so every
+// connection writing messages must have it.
+pub fn register_functions(conn: &Connection) -> Result<(), rusqlite::Error> {
+    conn.create_scalar_function(
+        "message_text",
+        1,
+        FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
+        |ctx| Ok(ctx.get::<MessageText>(0)?.0),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compress() {
+        assert_eq!(compress("Hello"), None);
+
+        let text = "Hello there! ".repeat(20);
+        let compressed = compress(&text).unwrap();
+        assert!(compressed.len() < text.len());
+        assert_eq!(decompress(&compressed).unwrap(), text);
+    }
+
+    #[test]
+    fn test_message_text() {
+        let conn = Connection::open_in_memory().unwrap();
+        register_functions(&conn).unwrap();
+        let text = "Hello there! ".repeat(20);
+
+        for value in &[
+            rusqlite::types::Value::Text(text.clone()),
+            rusqlite::types::Value::Blob(compress(&text).unwrap()),
+        ] {
+            let read: MessageText = conn
+                .query_row("SELECT ?1", [value], |row| row.get(0))
+                .unwrap();
+            assert_eq!(read.0, text);
+
+            let read: String = conn
+                .query_row("SELECT message_text(?1)", [value], |row| row.get(0))
+                .unwrap();
+            assert_eq!(read, text);
+        }
+    }
+}
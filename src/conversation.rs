@@ -0,0 +1,192 @@
+use std::collections::BTreeSet;
+
+use anyhow::anyhow;
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::{auth, room};
+
+// Conversations are rooms named with this prefix, which rooms created
+// otherwise may not take, so that they never collide with other rooms.
+pub const CONVERSATION_PREFIX: &str = "dm:";
+
+// Most users in a conversation, including the one starting it.
+pub const MAX_PARTICIPANTS: usize = 10;
+
+// Whether `room_name` is that of a conversation.
+pub fn is_conversation(room_name: &str) -> bool {
+    room_name.starts_with(CONVERSATION_PREFIX)
+}
+
+// Request body of the route starting a conversation, with the users to hold
+// it with besides the logged in user.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct NewConversation {
+    pub user_ids: Vec<usize>,
+}
+
+impl NewConversation {
+    // Validates the conversation as started by `created_by`.
+    pub fn validate(&self, created_by: usize) -> Result<(), anyhow::Error> {
+        let participants = participants(created_by, &self.user_ids);
+        if participants.len() < 2 || participants.len() > MAX_PARTICIPANTS {
+            return Err(anyhow!(
+                "Conversations must have between 2 and {} participants",
+                MAX_PARTICIPANTS
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+// A private conversation among a fixed set of users, held in a room of its
+// own.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Conversation {
+    pub room: String,
+    // Unknown if the user who started it has since been deleted
+    pub created_by: Option<usize>,
+    pub created_at: String,
+    pub participants: Vec<usize>,
+}
+
+// Starts a conversation among `created_by` and `user_ids`, in a new private
+// room only they are members of. Returns it, unless some of the users do not
+// exist.
+pub fn create_conversation(
+    conn: &Connection,
+    created_by: usize,
+    user_ids: &[usize],
+) -> Result<Option<Conversation>, rusqlite::Error> {
+    let participants = participants(created_by, user_ids);
+    for &user_id in &participants {
+        if !auth::user_exists(conn, user_id)? {
+            return Ok(None);
+        }
+    }
+
+    // Conversations have no owner: no one may let anyone else into them
+    let room_name = format!("{}{}", CONVERSATION_PREFIX, &auth::new_token()[..32]);
+    conn.execute(
+        "INSERT INTO rooms (room_name, created_by, visibility) VALUES (?1, ?2, 'private')",
+        params![room_name, created_by],
+    )?;
+    for &user_id in &participants {
+        room::add_member(conn, &room_name, user_id, Some(created_by))?;
+    }
+
+    conn.query_row(
+        "SELECT created_at FROM rooms WHERE room_name = ?1",
+        params![room_name],
+        |row| {
+            Ok(Some(Conversation {
+                room: room_name.clone(),
+                created_by: Some(created_by),
+                created_at: row.get(0)?,
+                participants: participants.into_iter().collect(),
+            }))
+        },
+    )
+}
+
+// Conversations `user_id` takes part in, newest first.
+pub fn conversations(
+    conn: &Connection,
+    user_id: usize,
+) -> Result<Vec<Conversation>, rusqlite::Error> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT r.room_name, r.created_by, r.created_at FROM rooms r
+            JOIN room_members m ON m.room_name = r.room_name
+            WHERE m.user_id = ?1 AND r.room_name LIKE ?2
+            ORDER BY r.created_at DESC, r.rowid DESC",
+    )?;
+    let rooms = stmt
+        .query_map(
+            params![user_id, format!("{}%", CONVERSATION_PREFIX)],
+            |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, Option<usize>>(1)?,
+                    row.get::<_, String>(2)?,
+                ))
+            },
+        )?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut conversations = Vec::new();
+    for (room_name, created_by, created_at) in rooms {
+        let mut participants = room::members(conn, &room_name)?
+            .into_iter()
+            .map(|member| member.user_id)
+            .collect::<Vec<_>>();
+        participants.sort_unstable();
+
+        conversations.push(Conversation {
+            room: room_name,
+            created_by,
+            created_at,
+            participants,
+        });
+    }
+
+    Ok(conversations)
+}
+
+// Users taking part in a conversation started by `created_by` with `user_ids`,
+// each once.
+fn participants(created_by: usize, user_ids: &[usize]) -> BTreeSet<usize> {
+    user_ids
+        .iter()
+        .copied()
+        .chain(std::iter::once(created_by))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{authz, db};
+
+    #[test]
+    fn test_validate() {
+        assert!(NewConversation { user_ids: vec![2] }.validate(1).is_ok());
+        assert!(NewConversation { user_ids: vec![] }.validate(1).is_err());
+        assert!(NewConversation { user_ids: vec![1] }.validate(1).is_err());
+
+        let user_ids = (1..=MAX_PARTICIPANTS).collect();
+        assert!(NewConversation { user_ids }.validate(1).is_ok());
+        let user_ids = (2..=MAX_PARTICIPANTS + 1).collect();
+        assert!(NewConversation { user_ids }.validate(1).is_err());
+    }
+
+    #[test]
+    fn test_conversations() {
+        let conn = Connection::open_in_memory().unwrap();
+        db::init_schema(&conn).unwrap();
+
+        let alice = auth::create_user(&conn, "alice", "hash").unwrap().unwrap();
+        let bob = auth::create_user(&conn, "bob", "hash").unwrap().unwrap();
+        let carol = auth::create_user(&conn, "carol", "hash").unwrap().unwrap();
+
+        assert!(create_conversation(&conn, alice, &[bob, 42])
+            .unwrap()
+            .is_none());
+
+        let conversation = create_conversation(&conn, alice, &[bob, alice, bob])
+            .unwrap()
+            .unwrap();
+        assert!(is_conversation(&conversation.room));
+        assert_eq!(conversation.participants, vec![alice, bob]);
+
+        // Only participants are let in, admins of the server included
+        authz::set_user_role(&conn, carol, authz::Role::Admin).unwrap();
+        assert!(authz::may_join(&conn, bob, &conversation.room).unwrap());
+        assert!(!authz::may_join(&conn, carol, &conversation.room).unwrap());
+
+        assert_eq!(conversations(&conn, bob).unwrap(), vec![conversation]);
+        assert!(conversations(&conn, carol).unwrap().is_empty());
+        assert!(room::public_rooms(&conn).unwrap().is_empty());
+    }
+}
@@ -1,85 +1,1448 @@
-use std::path::Path;
+use std::{
+    fmt,
+    fs::{File, OpenOptions, TryLockError},
+    path::Path,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicI64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
-use rusqlite::{params, Connection, DropBehavior};
-use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use anyhow::anyhow;
+use opentelemetry::{
+    trace::{Link, Span, SpanContext, Status, Tracer},
+    Context, KeyValue,
+};
+use rusqlite::{ffi, params, Connection, OptionalExtension, Row};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{
+    mpsc::{UnboundedReceiver, UnboundedSender},
+    oneshot,
+};
 
-use crate::shutdown::Shutdown;
+use crate::{
+    compression::{self, MessageText},
+    dead_letter::DeadLetters,
+    export::{self, Imported},
+    maintenance::Maintenance,
+    migration, revision, room,
+    shutdown::Shutdown,
+    store::{
+        self, MemoryStore, MessageChange, MessageStore, PostgresOptions, SqlStore, SqliteStore,
+        StoreError, StoreOptions,
+    },
+    telemetry,
+};
 
-pub type DbTx = UnboundedSender<DBMessage>;
-pub type DbRx = UnboundedReceiver<DBMessage>;
+pub type DbTx = UnboundedSender<DbRequest>;
+pub type DbRx = UnboundedReceiver<DbRequest>;
 
-#[derive(Debug)]
+pub type DbQuery = Box<dyn FnOnce(&Connection) + Send>;
+
+// Statements changing messages already persisted, returning the changes made.
+pub type DbChange = Box<dyn FnOnce(&Connection) -> Changed + Send>;
+
+// Changes a `DbChange` made to messages, and how to reply to its requester
+// once they were mirrored, or failed to be.
+pub struct Changed {
+    pub changes: Vec<MessageChange>,
+    pub reply: Box<dyn FnOnce(Result<(), StoreError>) + Send>,
+}
+
+// How often the DB thread deletes messages that have expired.
+const EXPIRY_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+// Times the DB thread attempts a write before giving up, and how long it waits
+// before retrying it the first time, doubling every time after.
+const MAX_WRITE_ATTEMPTS: u32 = 5;
+const RETRY_BACKOFF: Duration = Duration::from_millis(50);
+
+// Work handed off to the DB thread.
+pub enum DbRequest {
+    // Persist a chat message, traced as part of the context it was sent in.
+    Insert(Box<DBMessage>, Context),
+
+    // Run arbitrary statements against the DB thread's connection.
+    // Since writes are committed in batches, reads must go through the same
+    // connection in order to observe those not committed yet.
+    Query(DbQuery),
+
+    // Run statements changing messages already persisted, such as edits and
+    // deletions, as a query, then apply the changes they made to whatever
+    // else the store writes messages to. Should that fail, the writes made
+    // since the last commit are discarded, the changes along with them.
+    Change(DbChange),
+
+    // Run statements that only read, such as history, possibly on a read-only
+    // connection of their own. Writes made so far are committed first, so that
+    // they are observed.
+    Read(DbQuery),
+
+    // Checkpoint and vacuum the DB, returning up to as many free pages to the
+    // filesystem, once writes made so far are committed.
+    Maintain(u32, oneshot::Sender<Result<Maintenance, rusqlite::Error>>),
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct DBMessage {
+    // Assigned by the DB on insertion if not set
+    pub message_id: Option<i64>,
+    // Position of the message within its room
+    pub seq: Option<i64>,
     pub user_id: usize,
+    // Nickname of the author when the message was sent, if they had one
+    pub nickname: Option<String>,
     pub room_name: String,
     pub message: String,
+    // Set once the message has been edited by its author
+    pub edited_at: Option<String>,
+    // Set once the message has been deleted -- the row is kept as a tombstone
+    pub deleted_at: Option<String>,
+    // Set for messages of shadow-banned users, which only their author sees
+    pub shadowed: bool,
+    // Set for messages the word filter or classifier flagged for moderators to
+    // review
+    pub flagged: bool,
+    // How long the message is kept once persisted, if it expires
+    pub ttl: Option<Duration>,
+    // Set for messages forwarded from another room
+    pub forwarded: Option<Forwarded>,
 }
 
+// Where a forwarded message was first sent, and who sent it.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Forwarded {
+    pub id: i64,
+    pub room: String,
+    pub user_id: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nick: Option<String>,
+}
+
+// A message flagged by the word filter or classifier.
+#[derive(Debug, Serialize)]
+pub struct FlaggedMessage {
+    pub id: i64,
+    pub user_id: usize,
+    pub nick: Option<String>,
+    pub text: String,
+    pub created_at: String,
+    pub edited_at: Option<String>,
+}
+
+// A message deleted by its author or a moderator, as listed to moderators.
+#[derive(Debug, Serialize)]
+pub struct DeletedMessage {
+    pub id: i64,
+    pub user_id: usize,
+    pub nick: Option<String>,
+    pub text: String,
+    pub created_at: String,
+    pub edited_at: Option<String>,
+    pub deleted_at: String,
+    pub deleted_by: Option<usize>,
+}
+
+// A message pinned to a room.
+#[derive(Debug, Serialize)]
+pub struct RoomPin {
+    pub id: i64,
+    pub user_id: usize,
+    pub text: String,
+    pub pinned_by: usize,
+    pub pinned_at: String,
+}
+
+// How far a user has read the messages of a room.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct ReadMarker {
+    pub user_id: usize,
+    // Last message seen
+    pub id: i64,
+    pub read_at: String,
+}
+
+// How many messages of a room a user has not read yet.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct UnreadCount {
+    pub room: String,
+    pub unread: i64,
+    // Last message seen
+    pub last_read_id: i64,
+}
+
+// Columns read by `DBMessage::from_row`, in order.
+pub const MESSAGE_COLUMNS: &str = "message_id, seq, user_id, room_name, message, edited_at,
+    deleted_at, nickname, shadowed, flagged, forwarded_id, forwarded_room, forwarded_user_id,
+    forwarded_nick";
+
 impl DBMessage {
     pub fn new(user_id: usize, room_name: &str, message: &str) -> Self {
         DBMessage {
+            message_id: None,
+            seq: None,
             user_id,
+            nickname: None,
             room_name: String::from(room_name),
             message: String::from(message),
+            edited_at: None,
+            deleted_at: None,
+            shadowed: false,
+            flagged: false,
+            ttl: None,
+            forwarded: None,
+        }
+    }
+
+    // Reads a message from a row selecting `MESSAGE_COLUMNS`.
+    pub fn from_row(row: &Row) -> Result<Self, rusqlite::Error> {
+        Ok(DBMessage {
+            message_id: row.get(0)?,
+            seq: row.get(1)?,
+            user_id: row.get(2)?,
+            room_name: row.get(3)?,
+            message: row.get::<_, MessageText>(4)?.into(),
+            edited_at: row.get(5)?,
+            deleted_at: row.get(6)?,
+            nickname: row.get(7)?,
+            shadowed: row.get(8)?,
+            flagged: row.get(9)?,
+            ttl: None,
+            forwarded: match row.get(10)? {
+                Some(id) => Some(Forwarded {
+                    id,
+                    room: row.get(11)?,
+                    user_id: row.get(12)?,
+                    nick: row.get(13)?,
+                }),
+                None => None,
+            },
+        })
+    }
+
+    pub fn with_id(mut self, message_id: i64) -> Self {
+        self.message_id = Some(message_id);
+        self
+    }
+
+    pub fn with_seq(mut self, seq: i64) -> Self {
+        self.seq = Some(seq);
+        self
+    }
+
+    pub fn with_nickname(mut self, nickname: Option<String>) -> Self {
+        self.nickname = nickname;
+        self
+    }
+
+    pub fn with_shadowed(mut self, shadowed: bool) -> Self {
+        self.shadowed = shadowed;
+        self
+    }
+
+    pub fn with_flagged(mut self, flagged: bool) -> Self {
+        self.flagged = flagged;
+        self
+    }
+
+    pub fn with_ttl(mut self, ttl: Option<Duration>) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    pub fn with_forwarded(mut self, forwarded: Option<Forwarded>) -> Self {
+        self.forwarded = forwarded;
+        self
+    }
+}
+
+// Instances sharing rooms number their messages apart, each taking the IDs
+// equal to its node ID modulo this.
+pub const MAX_NODES: i64 = 256;
+
+// Hands out message IDs ahead of persistence, so that they can be sent back to
+// clients immediately.
+#[derive(Clone, Debug)]
+pub struct MessageIds {
+    next: Arc<AtomicI64>,
+    step: i64,
+}
+
+impl MessageIds {
+    // Continues numbering after `last_id`, the largest ID already persisted,
+    // with the IDs of `node` if given.
+    pub fn new(last_id: i64, node: Option<u8>) -> Self {
+        let (first, step) = match node {
+            Some(node) => (
+                (last_id / MAX_NODES + 1) * MAX_NODES + i64::from(node),
+                MAX_NODES,
+            ),
+            None => (last_id + 1, 1),
+        };
+
+        MessageIds {
+            next: Arc::new(AtomicI64::new(first)),
+            step,
+        }
+    }
+
+    pub fn next_id(&self) -> i64 {
+        self.next.fetch_add(self.step, Ordering::Relaxed)
+    }
+}
+
+// When the DB thread commits the writes it has made.
+#[derive(Clone, Copy, Debug)]
+pub struct CommitPolicy {
+    // Number of messages persisted before committing. Other writes are
+    // committed along with them, or once they are old enough
+    pub batch_size: usize,
+    // Longest a write goes uncommitted
+    pub max_delay: Duration,
+}
+
+impl Default for CommitPolicy {
+    fn default() -> Self {
+        CommitPolicy {
+            batch_size: 100,
+            max_delay: Duration::from_millis(1000),
         }
     }
 }
 
+// What the server does once the DB can no longer be written to.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DbFailureAction {
+    // The server shuts down
+    Shutdown,
+    // The server keeps running, refusing whatever needs the DB
+    Refuse,
+}
+
+impl FromStr for DbFailureAction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "shutdown" => Ok(DbFailureAction::Shutdown),
+            "refuse" => Ok(DbFailureAction::Refuse),
+            _ => Err(anyhow!(
+                "Unknown DB failure action '{}': expected shutdown or refuse",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for DbFailureAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let action = match self {
+            DbFailureAction::Shutdown => "shutdown",
+            DbFailureAction::Refuse => "refuse",
+        };
+        f.write_str(action)
+    }
+}
+
+// Journal modes the DB may be opened with.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum JournalMode {
+    // Readers and the writer do not block each other
+    Wal,
+    // Rollback journal, deleted at the end of every transaction
+    Delete,
+    // Rollback journal, truncated at the end of every transaction
+    Truncate,
+}
+
+impl FromStr for JournalMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "wal" => Ok(JournalMode::Wal),
+            "delete" => Ok(JournalMode::Delete),
+            "truncate" => Ok(JournalMode::Truncate),
+            _ => Err(anyhow!(
+                "Unknown journal mode '{}': expected wal, delete or truncate",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for JournalMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mode = match self {
+            JournalMode::Wal => "wal",
+            JournalMode::Delete => "delete",
+            JournalMode::Truncate => "truncate",
+        };
+        f.write_str(mode)
+    }
+}
+
+// How hard SQLite makes sure commits reach the disk.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Synchronous {
+    Off,
+    // Commits may be lost on power loss, though the DB is never corrupted in
+    // WAL mode
+    Normal,
+    Full,
+    Extra,
+}
+
+impl FromStr for Synchronous {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(Synchronous::Off),
+            "normal" => Ok(Synchronous::Normal),
+            "full" => Ok(Synchronous::Full),
+            "extra" => Ok(Synchronous::Extra),
+            _ => Err(anyhow!(
+                "Unknown synchronous setting '{}': expected off, normal, full or extra",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Synchronous {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let synchronous = match self {
+            Synchronous::Off => "off",
+            Synchronous::Normal => "normal",
+            Synchronous::Full => "full",
+            Synchronous::Extra => "extra",
+        };
+        f.write_str(synchronous)
+    }
+}
+
+// Pragmas the DB connection is opened with.
+#[derive(Clone, Debug)]
+pub struct Pragmas {
+    pub journal_mode: JournalMode,
+    pub synchronous: Synchronous,
+    // How long statements wait for locks held by other connections
+    pub busy_timeout: Duration,
+    // Size of the page cache, in KiB
+    pub cache_size_kib: u64,
+    // Key the DB is encrypted with, if it is
+    pub key: Option<String>,
+}
+
+impl Default for Pragmas {
+    fn default() -> Self {
+        Pragmas {
+            journal_mode: JournalMode::Wal,
+            synchronous: Synchronous::Normal,
+            busy_timeout: Duration::from_millis(5000),
+            cache_size_kib: 2000,
+            key: None,
+        }
+    }
+}
+
+// Opens the DB at `db_path`, creating it if need be, with `pragmas` set.
+pub fn open(db_path: &Path, pragmas: &Pragmas) -> Result<Connection, rusqlite::Error> {
+    let conn = Connection::open(db_path)?;
+    if let Some(key) = &pragmas.key {
+        unlock(&conn, key)?;
+    }
+    compression::register_functions(&conn)?;
+    conn.busy_timeout(pragmas.busy_timeout)?;
+    // Only takes effect for DBs created here, before any table is
+    conn.pragma_update(None, "auto_vacuum", "INCREMENTAL")?;
+    // Setting the journal mode answers with the mode in effect
+    conn.pragma_update_and_check(
+        None,
+        "journal_mode",
+        pragmas.journal_mode.to_string(),
+        |_| Ok(()),
+    )?;
+    conn.pragma_update(None, "synchronous", pragmas.synchronous.to_string())?;
+    // Negative cache sizes are in KiB rather than pages
+    conn.pragma_update(None, "cache_size", -(pragmas.cache_size_kib as i64))?;
+
+    Ok(conn)
+}
+
+// Gives `conn` the key its DB is encrypted with, which must be done before
+// anything else. New DBs are encrypted with it. Only SQLCipher knows of keys:
+// plain SQLite ignores them, which would leave the DB unencrypted, so it is
+// refused.
+pub fn unlock(conn: &Connection, key: &str) -> Result<(), rusqlite::Error> {
+    conn.pragma_update(None, "key", key)?;
+    let cipher: Option<String> = conn
+        .query_row("PRAGMA cipher_version", [], |row| row.get(0))
+        .optional()?;
+    if cipher.is_none() {
+        return Err(rusqlite::Error::SqliteFailure(
+            ffi::Error::new(ffi::SQLITE_MISUSE),
+            Some(String::from(
+                "DB key given, but the server was built without the sqlcipher feature",
+            )),
+        ));
+    }
+
+    // Wrong keys only show once the DB is read
+    conn.query_row("SELECT COUNT(*) FROM sqlite_master", [], |row| {
+        row.get::<_, i64>(0)
+    })?;
+
+    Ok(())
+}
+
 pub fn spawn_db(
     db_path: &Path,
+    pragmas: Pragmas,
+    options: StoreOptions,
+    policy: CommitPolicy,
+    dead_letters: DeadLetters,
+    db_rx: DbRx,
+    shutdown: Shutdown,
+) -> Result<(), StoreError> {
+    let mut store = SqliteStore::open(db_path, &pragmas)
+        .and_then(|store| store.with_readers(options.read_connections))
+        .map(|store| store.with_compression(options.compress_messages))
+        .expect("Unable to establish connection to DB. Exiting");
+    if let Some(postgres) = &options.postgres {
+        let mirror =
+            store::connect_mirror(postgres).expect("Unable to connect to Postgres. Exiting");
+        store = store.with_mirror(mirror);
+    }
+
+    let result = run_store(&mut store, policy, &dead_letters, db_rx, shutdown);
+
+    eprintln!("Closing DB connection");
+    // Whatever was written before an error is kept, if it can be
+    let closed = store.close();
+    result?;
+
+    closed
+}
+
+// Persists the messages written to `dead_letters` to the DB at `db_path`, and
+// to the Postgres database `postgres` tells of if given, returning how many
+// were.
+pub fn reingest_dead_letters(
+    db_path: &Path,
+    pragmas: Pragmas,
+    postgres: Option<&PostgresOptions>,
+    dead_letters: &DeadLetters,
+) -> Result<usize, anyhow::Error> {
+    let mut store = SqliteStore::open(db_path, &pragmas)?;
+    if let Some(postgres) = postgres {
+        store = store.with_mirror(store::connect_mirror(postgres)?);
+    }
+    let reingested = dead_letters.reingest(&mut store)?;
+    store.close()?;
+
+    Ok(reingested)
+}
+
+// Persists the messages of a room exported to `export_path` to `room_name`, in
+// the DB at `db_path`. Either every message is imported, or none are.
+pub fn import_history(
+    db_path: &Path,
+    pragmas: Pragmas,
+    room_name: &str,
+    export_path: &Path,
+) -> Result<Imported, anyhow::Error> {
+    room::validate_name(room_name)?;
+    let messages = export::read_export(export_path)?;
+
+    // A server running on the DB would number its messages from before the
+    // import, giving them the IDs of those imported
+    let _lock = lock(db_path).map_err(|e| anyhow!("{}. Stop the server before importing", e))?;
+    let mut conn = open(db_path, &pragmas)?;
+    init_schema(&conn)?;
+    let tx = conn.transaction()?;
+    let imported = export::import(&tx, room_name, &messages)?;
+    tx.commit()?;
+
+    Ok(imported)
+}
+
+// Locks the DB at `db_path` for the process writing to it, through a lock file
+// next to it, until the file returned is dropped. Fails if another process
+// holds the lock, such as a server running on the DB.
+pub fn lock(db_path: &Path) -> Result<File, anyhow::Error> {
+    let mut path = db_path.as_os_str().to_owned();
+    path.push(".lock");
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path)?;
+
+    match file.try_lock() {
+        Ok(()) => Ok(file),
+        Err(TryLockError::WouldBlock) => Err(anyhow!("{} is in use", db_path.display())),
+        Err(TryLockError::Error(e)) => Err(e.into()),
+    }
+}
+
+// Same as `spawn_db`, though keeping everything in memory, lost on shutdown.
+pub fn spawn_memory_db(
+    policy: CommitPolicy,
+    dead_letters: DeadLetters,
+    db_rx: DbRx,
+    shutdown: Shutdown,
+) -> Result<(), StoreError> {
+    let mut store = MemoryStore::new()?;
+
+    run_store(&mut store, policy, &dead_letters, db_rx, shutdown)
+}
+
+// Handles the requests sent on `db_rx` with `store` until shutdown, committing
+// the writes made as told by `policy`. Writes failing for as long as the store
+// is unavailable, despite retries, end it with their error: the DB thread is
+// then gone, and further requests are refused. Messages that could not be
+// persisted, including those the store rejects, those of batches that failed
+// to be committed and those still waiting to be, are written to `dead_letters`
+// rather than dropped.
+pub fn run_store<S: SqlStore>(
+    store: &mut S,
+    policy: CommitPolicy,
+    dead_letters: &DeadLetters,
     mut db_rx: DbRx,
     mut shutdown: Shutdown,
-) -> Result<(), rusqlite::Error> {
-    let mut conn =
-        Connection::open(db_path).expect("Unable to establish connection to DB. Exiting");
+) -> Result<(), StoreError> {
+    // The thread sleeps until a request, the next expiry sweep, commit or the
+    // shutdown signal is due, rather than polling for them.
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .build()
+        .expect("Unable to start DB thread runtime. Exiting");
+    // Messages inserted since the last commit
+    let mut batch = Batch::default();
+    let result = runtime.block_on(async {
+        let mut sweep = tokio::time::interval(EXPIRY_SWEEP_INTERVAL);
+        // Messages inserted since the last commit, and when the writes made
+        // since are due to be committed. Batching them bounds how many are
+        // lost on a crash.
+        let mut pending = 0;
+        // Whether anything was written since the last commit, by inserts or
+        // by queries. Queries make a commit due without counting toward the
+        // batch, as their results are sent back before it would be made.
+        let mut uncommitted = false;
+        let commit_due = tokio::time::sleep(policy.max_delay);
+        tokio::pin!(commit_due);
+
+        loop {
+            let inserted = tokio::select! {
+                request = db_rx.recv() => match request {
+                    Some(DbRequest::Read(query)) => {
+                        if uncommitted {
+                            commit(store, dead_letters, &mut batch).await?;
+                            pending = 0;
+                            uncommitted = false;
+                        }
+                        store.read(query);
+                        continue;
+                    }
+                    Some(DbRequest::Maintain(vacuum_pages, maintained_tx)) => {
+                        if uncommitted {
+                            commit(store, dead_letters, &mut batch).await?;
+                            pending = 0;
+                            uncommitted = false;
+                        }
+                        let _ = maintained_tx.send(store.maintain(vacuum_pages));
+                        continue;
+                    }
+                    Some(request @ (DbRequest::Query(_) | DbRequest::Change(_))) => {
+                        handle_request(store, dead_letters, request, &mut batch).await?;
+                        0
+                    }
+                    Some(request) => {
+                        handle_request(store, dead_letters, request, &mut batch).await?;
+                        1
+                    }
+                    // Every sender is gone, so no more requests can arrive
+                    None => break,
+                },
+                // Finish processing remaining messages before closing
+                _ = shutdown.async_listen() => {
+                    while let Ok(request) = db_rx.try_recv() {
+                        handle_request(store, dead_letters, request, &mut batch).await?;
+                    }
+
+                    break;
+                }
+                _ = sweep.tick() => {
+                    // Expired messages are left for the next sweep
+                    if let Err(e) = with_retries(store, dead_letters, &mut batch, S::purge).await {
+                        eprintln!("Unable to delete expired messages: {}", e);
+                    }
+                    continue;
+                }
+                _ = &mut commit_due, if uncommitted => {
+                    commit(store, dead_letters, &mut batch).await?;
+                    pending = 0;
+                    uncommitted = false;
+                    continue;
+                }
+            };
+
+            if !uncommitted {
+                commit_due
+                    .as_mut()
+                    .reset(tokio::time::Instant::now() + policy.max_delay);
+                uncommitted = true;
+            }
+            pending += inserted;
+            if pending >= policy.batch_size {
+                commit(store, dead_letters, &mut batch).await?;
+                pending = 0;
+                uncommitted = false;
+            }
+        }
+
+        Ok(())
+    });
+
+    if result.is_err() {
+        // The batch is only written to the dead letters once discarded, or it
+        // would be persisted twice should closing the store commit it
+        match store.rollback() {
+            Ok(()) => batch.dead_letter(dead_letters),
+            Err(e) => eprintln!("Unable to discard uncommitted DB writes: {}", e),
+        }
+
+        db_rx.close();
+        while let Ok(request) = db_rx.try_recv() {
+            if let DbRequest::Insert(msg, _) = request {
+                dead_letter(dead_letters, &msg);
+            }
+        }
+    }
+
+    result
+}
+
+// Messages inserted since the last commit, which are lost should it fail.
+#[derive(Default)]
+struct Batch {
+    messages: Vec<DBMessage>,
+    // Spans of their inserts, which the span of the commit links to
+    spans: Vec<SpanContext>,
+}
+
+impl Batch {
+    // Writes the messages to `dead_letters`, emptying the batch.
+    fn dead_letter(&mut self, dead_letters: &DeadLetters) {
+        for msg in self.messages.drain(..) {
+            dead_letter(dead_letters, &msg);
+        }
+        self.spans.clear();
+    }
+}
+
+// Brings the schema up to date, creating it if need be.
+pub fn init_schema(conn: &Connection) -> Result<(), rusqlite::Error> {
+    // Messages are indexed through the functions, so they must be there for
+    // anything written to the DB
+    compression::register_functions(conn)?;
+    migration::migrate(conn)
+}
+
+// Handles `request` with `store`, adding the message it inserts, if any, to
+// `batch`. Messages the store rejects are written to `dead_letters`, along
+// with those it is unavailable for, which end the DB thread.
+async fn handle_request<S: SqlStore>(
+    store: &mut S,
+    dead_letters: &DeadLetters,
+    request: DbRequest,
+    batch: &mut Batch,
+) -> Result<(), StoreError> {
+    match request {
+        DbRequest::Insert(msg, cx) => {
+            let tracer = telemetry::tracer();
+            let mut span = tracer
+                .span_builder("db.insert")
+                .with_attributes(vec![KeyValue::new("room", msg.room_name.clone())])
+                .start_with_context(&tracer, &cx);
+            if let Err(e) =
+                with_retries(store, dead_letters, batch, |store| store.insert(&msg)).await
+            {
+                span.set_status(Status::error(e.to_string()));
+                dead_letter(dead_letters, &msg);
+                if e.is_transient() {
+                    return Err(e);
+                }
+
+                eprintln!("DB rejected message, writing it to dead letters: {}", e);
+                return Ok(());
+            }
+            if span.span_context().is_valid() {
+                batch.spans.push(span.span_context().clone());
+            }
+            batch.messages.push(*msg);
+        }
+        DbRequest::Query(query) => store.query(query),
+        DbRequest::Change(change) => {
+            let Changed { changes, reply } = store.change(change);
+            let mirrored = changes.iter().try_for_each(|change| store.mirror(change));
+            if let Err(e) = &mirrored {
+                // Whatever the DB commits must have been mirrored
+                eprintln!(
+                    "Unable to mirror changes to messages, discarding them: {}",
+                    e
+                );
+                match store.rollback() {
+                    Ok(()) => batch.dead_letter(dead_letters),
+                    Err(e) => eprintln!("Unable to discard uncommitted DB writes: {}", e),
+                }
+            }
+            reply(mirrored);
+        }
+        // Reads left once shut down are run on the DB thread's connection,
+        // which observes every write
+        DbRequest::Read(query) => store.query(query),
+        DbRequest::Maintain(vacuum_pages, maintained_tx) => {
+            let _ = maintained_tx.send(store.maintain(vacuum_pages));
+        }
+    }
+
+    Ok(())
+}
+
+// Commits the writes made to `store`, tracing the commit as that of the
+// messages in `batch`. Should it fail, the writes are discarded and the
+// messages written to `dead_letters`, the error only returned if the store is
+// unavailable.
+async fn commit<S: MessageStore>(
+    store: &mut S,
+    dead_letters: &DeadLetters,
+    batch: &mut Batch,
+) -> Result<(), StoreError> {
+    let tracer = telemetry::tracer();
+    let mut span = tracer
+        .span_builder("db.commit")
+        .with_attributes(vec![KeyValue::new("messages", batch.messages.len() as i64)])
+        .with_links(batch.spans.drain(..).map(Link::with_context).collect())
+        .start(&tracer);
+    let committed = with_retries(store, dead_letters, batch, S::commit).await;
+    let e = match committed {
+        Ok(()) => {
+            batch.messages.clear();
+            return Ok(());
+        }
+        Err(e) => e,
+    };
+
+    span.set_status(Status::error(e.to_string()));
+    match store.rollback() {
+        Ok(()) => batch.dead_letter(dead_letters),
+        // Left for closing the store to commit, should the thread end
+        Err(e) => eprintln!("Unable to discard uncommitted DB writes: {}", e),
+    }
+    if e.is_transient() {
+        return Err(e);
+    }
+
+    eprintln!(
+        "DB rejected commit, writing its messages to dead letters: {}",
+        e
+    );
+    Ok(())
+}
+
+// Writes `msg`, which could not be persisted, to `dead_letters`.
+fn dead_letter(dead_letters: &DeadLetters, msg: &DBMessage) {
+    if let Err(e) = dead_letters.append(msg) {
+        eprintln!(
+            "Unable to write message to {}, dropping it: {}",
+            dead_letters.path().display(),
+            e
+        );
+    }
+}
+
+// Makes `write` to `store`, retrying with backoff should it fail while the
+// store is unavailable, such as when the disk is full or the DB locked. The
+// store is reconnected to between attempts. Should that lose the writes not
+// committed yet, the messages of `batch` are written to `dead_letters`.
+// Writes the store rejects are not retried.
+async fn with_retries<S, T, F>(
+    store: &mut S,
+    dead_letters: &DeadLetters,
+    batch: &mut Batch,
+    mut write: F,
+) -> Result<T, StoreError>
+where
+    S: MessageStore,
+    F: FnMut(&mut S) -> Result<T, StoreError>,
+{
+    let mut backoff = RETRY_BACKOFF;
+    let mut attempt = 1;
+    loop {
+        match write(store) {
+            Ok(written) => return Ok(written),
+            Err(e) if e.is_transient() && attempt < MAX_WRITE_ATTEMPTS => {
+                eprintln!("DB write failed, retrying in {:?}: {}", backoff, e);
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+                attempt += 1;
+
+                match store.reconnect() {
+                    Ok(true) => {}
+                    Ok(false) => {
+                        eprintln!("Uncommitted DB writes lost, writing them to dead letters");
+                        batch.dead_letter(dead_letters);
+                    }
+                    Err(e) => eprintln!("Unable to reconnect to DB: {}", e),
+                }
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// Queues `msg` to be persisted by the DB thread.
+pub fn insert(db_tx: &DbTx, msg: DBMessage) -> Result<(), anyhow::Error> {
+    db_tx
+        .send(DbRequest::Insert(Box::new(msg), Context::current()))
+        .map_err(|_| anyhow!("DB thread has shut down"))
+}
+
+// Runs `f` on the DB thread, returning its result once complete.
+pub async fn query<T, F>(db_tx: &DbTx, f: F) -> Result<T, anyhow::Error>
+where
+    T: Send + 'static,
+    F: FnOnce(&Connection) -> Result<T, rusqlite::Error> + Send + 'static,
+{
+    request(db_tx, DbRequest::Query, f).await
+}
+
+// Runs `f`, which changes messages already persisted, on the DB thread as
+// `query` does, along with the changes it returns. Its result is returned once
+// they were applied to whatever else the DB thread writes messages to, failing
+// if they could not be.
+pub async fn change<T, F>(db_tx: &DbTx, f: F) -> Result<T, anyhow::Error>
+where
+    T: Send + 'static,
+    F: FnOnce(&Connection) -> Result<(T, Vec<MessageChange>), rusqlite::Error> + Send + 'static,
+{
+    let (reply_tx, reply_rx) = oneshot::channel();
+    // Traced as part of the context it was requested in
+    let cx = Context::current();
+    db_tx
+        .send(DbRequest::Change(Box::new(move |conn| {
+            let _span = telemetry::tracer().start_with_context("db.change", &cx);
+            let (result, changes) = match f(conn) {
+                Ok((result, changes)) => (Ok(result), changes),
+                Err(e) => (Err(e.into()), Vec::new()),
+            };
+            Changed {
+                changes,
+                // Requester may have gone away -- nothing left to do in that case
+                reply: Box::new(move |mirrored| {
+                    let _ = reply_tx.send(mirrored.map_err(anyhow::Error::from).and(result));
+                }),
+            }
+        })))
+        .map_err(|_| anyhow!("DB thread has shut down"))?;
+
+    reply_rx
+        .await
+        .map_err(|_| anyhow!("DB thread dropped query"))?
+}
+
+// Runs `f`, which must only read, on a read-only connection if there are any,
+// returning its result once complete. It observes every write made before.
+pub async fn read<T, F>(db_tx: &DbTx, f: F) -> Result<T, anyhow::Error>
+where
+    T: Send + 'static,
+    F: FnOnce(&Connection) -> Result<T, rusqlite::Error> + Send + 'static,
+{
+    request(db_tx, DbRequest::Read, f).await
+}
+
+// Checkpoints and vacuums the DB, returning up to `vacuum_pages` free pages to
+// the filesystem, all of them if 0.
+pub async fn maintain(db_tx: &DbTx, vacuum_pages: u32) -> Result<Maintenance, anyhow::Error> {
+    let (maintained_tx, maintained_rx) = oneshot::channel();
+    db_tx
+        .send(DbRequest::Maintain(vacuum_pages, maintained_tx))
+        .map_err(|_| anyhow!("DB thread has shut down"))?;
+
+    let result = maintained_rx
+        .await
+        .map_err(|_| anyhow!("DB thread dropped maintenance"))?;
+
+    Ok(result?)
+}
+
+async fn request<T, F>(
+    db_tx: &DbTx,
+    kind: fn(DbQuery) -> DbRequest,
+    f: F,
+) -> Result<T, anyhow::Error>
+where
+    T: Send + 'static,
+    F: FnOnce(&Connection) -> Result<T, rusqlite::Error> + Send + 'static,
+{
+    let (reply_tx, reply_rx) = oneshot::channel();
+    // Traced as part of the context it was requested in
+    let cx = Context::current();
+    db_tx
+        .send(kind(Box::new(move |conn| {
+            let _span = telemetry::tracer().start_with_context("db.query", &cx);
+            // Requester may have gone away -- nothing left to do in that case
+            let _ = reply_tx.send(f(conn));
+        })))
+        .map_err(|_| anyhow!("DB thread has shut down"))?;
+
+    let result = reply_rx
+        .await
+        .map_err(|_| anyhow!("DB thread dropped query"))?;
+
+    Ok(result?)
+}
+
+// Deletes the messages that have expired, along with whatever refers to them,
+// returning how many were deleted.
+pub fn delete_expired(conn: &Connection) -> Result<usize, rusqlite::Error> {
+    let expired = "SELECT message_id FROM chat_messages WHERE expires_at <= datetime('now')";
+    for table in &[
+        "mentions",
+        "alerts",
+        "queued_events",
+        "room_pins",
+        "message_reports",
+        "poll_options",
+        "poll_votes",
+    ] {
+        conn.execute(
+            &format!("DELETE FROM {} WHERE message_id IN ({})", table, expired),
+            [],
+        )?;
+    }
 
     conn.execute(
-        "CREATE TABLE IF NOT EXISTS chat_messages (
-                message_id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
-                user_id INTEGER,
-                room_name TEXT NOT NULL,
-                message TEXT NOT NULL,
-                created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL
-            )",
+        "DELETE FROM chat_messages WHERE expires_at <= datetime('now')",
+        [],
+    )
+}
+
+// Largest message ID persisted so far, or 0 if there are no messages.
+pub fn last_message_id(conn: &Connection) -> Result<i64, rusqlite::Error> {
+    conn.query_row(
+        "SELECT COALESCE(MAX(message_id), 0) FROM chat_messages",
         [],
+        |row| row.get(0),
+    )
+}
+
+// Sequence number of the last message sent to `room_name`, or 0 if there are none.
+pub fn last_room_seq(conn: &Connection, room_name: &str) -> Result<i64, rusqlite::Error> {
+    conn.query_row(
+        "SELECT COALESCE(MAX(seq), 0) FROM chat_messages WHERE room_name = ?1",
+        params![room_name],
+        |row| row.get(0),
+    )
+}
+
+// Fetches the last `limit` messages sent to `room_name` that `viewer` may see,
+// oldest first.
+pub fn recent_messages(
+    conn: &Connection,
+    room_name: &str,
+    viewer: usize,
+    limit: usize,
+) -> Result<Vec<DBMessage>, rusqlite::Error> {
+    let mut stmt = conn.prepare_cached(&format!(
+        "SELECT {} FROM (
+                SELECT * FROM chat_messages
+                WHERE room_name = ?1 AND (NOT shadowed OR user_id = ?3)
+                ORDER BY seq DESC, message_id DESC
+                LIMIT ?2
+            ) ORDER BY seq ASC, message_id ASC",
+        MESSAGE_COLUMNS
+    ))?;
+
+    let rows = stmt.query_map(
+        params![room_name, limit as i64, viewer],
+        DBMessage::from_row,
     )?;
 
-    let insert_query =
-        "INSERT INTO chat_messages (user_id, room_name, message) VALUES (?1, ?2, ?3)";
-    let mut tx = conn.transaction()?;
-    tx.set_drop_behavior(DropBehavior::Commit);
-
-    let mut stmt = tx.prepare_cached(insert_query)?;
-
-    // While shutdown signal not received, keep listening for messages.
-    while !shutdown.is_shutdown() {
-        // Update shutdown state
-        shutdown.listen();
-        // If shutdown signal has been received, finish processing remaining
-        // messages.
-        // Else, continue listening for messages on `db_rx`.
-        if shutdown.is_shutdown() {
-            while let Ok(msg) = db_rx.try_recv() {
-                stmt.execute(params![msg.user_id, msg.room_name, msg.message])?;
-            }
+    rows.collect()
+}
 
-            break;
-        } else if let Ok(msg) = db_rx.try_recv() {
-            stmt.execute(params![msg.user_id, msg.room_name, msg.message])?;
-        }
+// Fetches every message sent to `room_name` after message `after_id` that
+// `viewer` may see, oldest first, e.g. those a reconnecting client missed, or
+// None if the room has no such message. Messages are taken in sequence, as IDs
+// are only unique, not ordered, once rooms are shared between instances.
+pub fn messages_since(
+    conn: &Connection,
+    room_name: &str,
+    viewer: usize,
+    after_id: i64,
+) -> Result<Option<Vec<DBMessage>>, rusqlite::Error> {
+    let after_seq: Option<i64> = conn
+        .query_row(
+            "SELECT seq FROM chat_messages WHERE message_id = ?1 AND room_name = ?2",
+            params![after_id, room_name],
+            |row| row.get(0),
+        )
+        .optional()?
+        .flatten();
+
+    after_seq
+        .map(|after_seq| messages_after_seq(conn, room_name, viewer, after_seq))
+        .transpose()
+}
+
+// Fetches every message sent to `room_name` after sequence number `after_seq`
+// that `viewer` may see, oldest first.
+pub fn messages_after_seq(
+    conn: &Connection,
+    room_name: &str,
+    viewer: usize,
+    after_seq: i64,
+) -> Result<Vec<DBMessage>, rusqlite::Error> {
+    let mut stmt = conn.prepare_cached(&format!(
+        "SELECT {} FROM chat_messages
+            WHERE room_name = ?1 AND seq > ?2 AND (NOT shadowed OR user_id = ?3)
+            ORDER BY seq ASC, message_id ASC",
+        MESSAGE_COLUMNS
+    ))?;
+
+    let rows = stmt.query_map(params![room_name, after_seq, viewer], DBMessage::from_row)?;
+
+    rows.collect()
+}
+
+// Message `message_id` of `room_name`, provided `viewer` may see it.
+pub fn message(
+    conn: &Connection,
+    room_name: &str,
+    viewer: usize,
+    message_id: i64,
+) -> Result<Option<DBMessage>, rusqlite::Error> {
+    conn.query_row(
+        &format!(
+            "SELECT {} FROM chat_messages
+                WHERE message_id = ?1 AND room_name = ?2 AND (NOT shadowed OR user_id = ?3)",
+            MESSAGE_COLUMNS
+        ),
+        params![message_id, room_name, viewer],
+        DBMessage::from_row,
+    )
+    .optional()
+}
+
+// Whether `nickname` is held by, or is the username of, a user other than
+// `user_id`.
+pub fn nickname_taken(
+    conn: &Connection,
+    user_id: usize,
+    nickname: &str,
+) -> Result<bool, rusqlite::Error> {
+    conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM usernames WHERE name = ?1 AND user_id != ?2)
+            OR EXISTS (
+                SELECT 1 FROM users WHERE username = ?1 COLLATE NOCASE AND user_id != ?2
+            )",
+        params![nickname, user_id],
+        |row| row.get(0),
+    )
+}
+
+// Reserves `nickname` for `user_id`, releasing the one it held before.
+// Returns whether the nickname was reserved: it may be held by, or be the
+// username of, another user.
+pub fn reserve_nickname(
+    conn: &Connection,
+    user_id: usize,
+    nickname: &str,
+) -> Result<bool, rusqlite::Error> {
+    if nickname_taken(conn, user_id, nickname)? {
+        return Ok(false);
     }
 
-    eprintln!("Shutdown signal received: closing DB connection");
-    drop(stmt);
-    tx.commit()?;
-    conn.close().expect("Failed to close DB connection");
+    conn.execute("DELETE FROM usernames WHERE user_id = ?1", params![user_id])?;
+    conn.execute(
+        "INSERT INTO usernames (name, user_id) VALUES (?1, ?2)",
+        params![nickname, user_id],
+    )?;
+
+    Ok(true)
+}
+
+// Releases the nickname held by `user_id`, if any.
+pub fn release_nickname(conn: &Connection, user_id: usize) -> Result<(), rusqlite::Error> {
+    conn.execute("DELETE FROM usernames WHERE user_id = ?1", params![user_id])?;
 
     Ok(())
 }
 
+// The nickname `user_id` holds, if any.
+pub fn nickname(conn: &Connection, user_id: usize) -> Result<Option<String>, rusqlite::Error> {
+    conn.query_row(
+        "SELECT name FROM usernames WHERE user_id = ?1",
+        params![user_id],
+        |row| row.get(0),
+    )
+    .optional()
+}
+
+// Replaces the content of message `message_id`, provided it was sent to
+// `room_name` by `user_id`, keeping the version replaced as a revision.
+// Messages are flagged if `flagged` is set, and never unflagged by edits.
+// Returns whether the message was updated.
+pub fn edit_message(
+    conn: &Connection,
+    message_id: i64,
+    user_id: usize,
+    room_name: &str,
+    message: &str,
+    flagged: bool,
+) -> Result<bool, rusqlite::Error> {
+    if !revision::record(conn, message_id, user_id, room_name)? {
+        return Ok(false);
+    }
+
+    let updated = conn.execute(
+        "UPDATE chat_messages
+            SET message = ?1, edited_at = CURRENT_TIMESTAMP, flagged = flagged OR ?5
+            WHERE message_id = ?2 AND user_id = ?3 AND room_name = ?4 AND deleted_at IS NULL",
+        params![message, message_id, user_id, room_name, flagged],
+    )?;
+
+    Ok(updated > 0)
+}
+
+// Marks message `message_id` of `room_name` as deleted by `deleted_by`.
+// If `author_id` is set, the message is only deleted if it was sent by that user.
+// Returns whether the message was deleted.
+pub fn delete_message(
+    conn: &Connection,
+    message_id: i64,
+    room_name: &str,
+    deleted_by: usize,
+    author_id: Option<usize>,
+) -> Result<bool, rusqlite::Error> {
+    let updated = conn.execute(
+        "UPDATE chat_messages SET deleted_at = CURRENT_TIMESTAMP, deleted_by = ?1
+            WHERE message_id = ?2 AND room_name = ?3 AND deleted_at IS NULL
+                AND (?4 IS NULL OR user_id = ?4)",
+        params![deleted_by, message_id, room_name, author_id],
+    )?;
+
+    Ok(updated > 0)
+}
+
+// Pins message `message_id` to `room_name`, provided it is a message of that
+// room which has not been deleted.
+// Returns whether the message was newly pinned.
+pub fn pin_message(
+    conn: &Connection,
+    message_id: i64,
+    room_name: &str,
+    pinned_by: usize,
+) -> Result<bool, rusqlite::Error> {
+    let inserted = conn.execute(
+        "INSERT OR IGNORE INTO room_pins (room_name, message_id, pinned_by)
+            SELECT room_name, message_id, ?1 FROM chat_messages
+            WHERE message_id = ?2 AND room_name = ?3 AND deleted_at IS NULL",
+        params![pinned_by, message_id, room_name],
+    )?;
+
+    Ok(inserted > 0)
+}
+
+// Unpins message `message_id` from `room_name`.
+// Returns whether the message was pinned.
+pub fn unpin_message(
+    conn: &Connection,
+    message_id: i64,
+    room_name: &str,
+) -> Result<bool, rusqlite::Error> {
+    let deleted = conn.execute(
+        "DELETE FROM room_pins WHERE message_id = ?1 AND room_name = ?2",
+        params![message_id, room_name],
+    )?;
+
+    Ok(deleted > 0)
+}
+
+// Messages pinned to `room_name`, oldest pin first.
+// Pins of messages deleted since being pinned are left out.
+pub fn room_pins(conn: &Connection, room_name: &str) -> Result<Vec<RoomPin>, rusqlite::Error> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT m.message_id, m.user_id, m.message, p.pinned_by, p.pinned_at
+            FROM room_pins p
+            JOIN chat_messages m ON m.message_id = p.message_id
+            WHERE p.room_name = ?1 AND m.deleted_at IS NULL
+            ORDER BY p.pinned_at ASC, p.rowid ASC",
+    )?;
+
+    let rows = stmt.query_map(params![room_name], |row| {
+        Ok(RoomPin {
+            id: row.get(0)?,
+            user_id: row.get(1)?,
+            text: row.get::<_, MessageText>(2)?.into(),
+            pinned_by: row.get(3)?,
+            pinned_at: row.get(4)?,
+        })
+    })?;
+
+    rows.collect()
+}
+
+// Moves the read marker of `user_id` in `room_name` up to message
+// `message_id`, provided it is a message of that room. Markers only move
+// forward: messages sequenced before the last one seen are ignored. IDs are
+// not compared, as they are only unique, not ordered, once rooms are shared
+// between instances.
+// Returns whether the marker moved.
+pub fn mark_read(
+    conn: &Connection,
+    room_name: &str,
+    user_id: usize,
+    message_id: i64,
+) -> Result<bool, rusqlite::Error> {
+    let updated = conn.execute(
+        "INSERT INTO read_markers (room_name, user_id, message_id, seq)
+            SELECT room_name, ?2, message_id, seq FROM chat_messages
+            WHERE message_id = ?3 AND room_name = ?1
+            ON CONFLICT (room_name, user_id) DO UPDATE
+                SET message_id = excluded.message_id, seq = excluded.seq,
+                    read_at = CURRENT_TIMESTAMP
+                WHERE excluded.seq > read_markers.seq OR read_markers.seq IS NULL",
+        params![room_name, user_id, message_id],
+    )?;
+
+    Ok(updated > 0)
+}
+
+// Read markers of `room_name`, by user ID.
+pub fn read_markers(
+    conn: &Connection,
+    room_name: &str,
+) -> Result<Vec<ReadMarker>, rusqlite::Error> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT user_id, message_id, read_at FROM read_markers
+            WHERE room_name = ?1
+            ORDER BY user_id",
+    )?;
+
+    let rows = stmt.query_map(params![room_name], |row| {
+        Ok(ReadMarker {
+            user_id: row.get(0)?,
+            id: row.get(1)?,
+            read_at: row.get(2)?,
+        })
+    })?;
+
+    rows.collect()
+}
+
+// Number of messages of each room `user_id` has a read marker in, sent since
+// the message it points to, by room name. Counted from sequence numbers, so
+// that the messages themselves are never scanned: deleted messages count as
+// unread, as do messages of shadow-banned users.
+pub fn unread_counts(
+    conn: &Connection,
+    user_id: usize,
+) -> Result<Vec<UnreadCount>, rusqlite::Error> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT r.room_name, r.message_id,
+                MAX((SELECT COALESCE(MAX(m.seq), 0) FROM chat_messages m
+                    WHERE m.room_name = r.room_name) - COALESCE(r.seq, 0), 0)
+            FROM read_markers r
+            WHERE r.user_id = ?1
+            ORDER BY r.room_name",
+    )?;
+
+    let rows = stmt.query_map(params![user_id], |row| {
+        Ok(UnreadCount {
+            room: row.get(0)?,
+            last_read_id: row.get(1)?,
+            unread: row.get(2)?,
+        })
+    })?;
+
+    rows.collect()
+}
+
+// Messages of `room_name` flagged by the word filter or classifier, newest
+// first.
+// Deleted messages are left out.
+pub fn flagged_messages(
+    conn: &Connection,
+    room_name: &str,
+) -> Result<Vec<FlaggedMessage>, rusqlite::Error> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT message_id, user_id, nickname, message, created_at, edited_at
+            FROM chat_messages
+            WHERE room_name = ?1 AND flagged AND deleted_at IS NULL
+            ORDER BY message_id DESC",
+    )?;
+
+    let rows = stmt.query_map(params![room_name], |row| {
+        Ok(FlaggedMessage {
+            id: row.get(0)?,
+            user_id: row.get(1)?,
+            nick: row.get(2)?,
+            text: row.get::<_, MessageText>(3)?.into(),
+            created_at: row.get(4)?,
+            edited_at: row.get(5)?,
+        })
+    })?;
+
+    rows.collect()
+}
+
+// Messages of `room_name` which were deleted, as long as they are kept, most
+// recently deleted first.
+pub fn deleted_messages(
+    conn: &Connection,
+    room_name: &str,
+) -> Result<Vec<DeletedMessage>, rusqlite::Error> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT message_id, user_id, nickname, message, created_at, edited_at, deleted_at,
+                deleted_by
+            FROM chat_messages
+            WHERE room_name = ?1 AND deleted_at IS NOT NULL
+            ORDER BY deleted_at DESC, message_id DESC",
+    )?;
+
+    let rows = stmt.query_map(params![room_name], |row| {
+        Ok(DeletedMessage {
+            id: row.get(0)?,
+            user_id: row.get(1)?,
+            nick: row.get(2)?,
+            text: row.get::<_, MessageText>(3)?.into(),
+            created_at: row.get(4)?,
+            edited_at: row.get(5)?,
+            deleted_at: row.get(6)?,
+            deleted_by: row.get(7)?,
+        })
+    })?;
+
+    rows.collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::auth::{self, MessageRetention};
+    use futures::future::BoxFuture;
+    use opentelemetry::{global, trace::TraceContextExt};
+    use opentelemetry_sdk::{
+        export::trace::{ExportResult, SpanData, SpanExporter},
+        trace::TracerProvider,
+    };
     use tokio::sync::{broadcast, mpsc};
 
     #[test]
@@ -94,6 +1457,10 @@ mod tests {
         let db_conn = std::thread::spawn(move || {
             spawn_db(
                 db_path,
+                Pragmas::default(),
+                StoreOptions::default(),
+                CommitPolicy::default(),
+                DeadLetters::new(Path::new("./test_dead_letters.jsonl")),
                 db_rx,
                 Shutdown::new(shutdown_listener, shutdown_complete_tx),
             )
@@ -108,4 +1475,865 @@ mod tests {
 
         std::fs::remove_file(db_path).unwrap();
     }
+
+    // Store whose inserts fail for a while, as if the disk were full.
+    struct FlakyStore {
+        store: MemoryStore,
+        // Number of inserts that succeed before they start failing
+        fail_after: usize,
+        failures: u32,
+        // Whether reconnecting keeps the writes not committed yet
+        keeps_writes: bool,
+        inserted: usize,
+        reconnects: u32,
+    }
+
+    impl FlakyStore {
+        fn new(failures: u32) -> Self {
+            FlakyStore {
+                store: MemoryStore::new().unwrap(),
+                fail_after: 0,
+                failures,
+                keeps_writes: true,
+                inserted: 0,
+                reconnects: 0,
+            }
+        }
+    }
+
+    impl MessageStore for FlakyStore {
+        fn insert(&mut self, msg: &DBMessage) -> Result<(), StoreError> {
+            if self.inserted >= self.fail_after && self.failures > 0 {
+                self.failures -= 1;
+                return Err(rusqlite::Error::SqliteFailure(
+                    rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_FULL),
+                    None,
+                )
+                .into());
+            }
+
+            self.store.insert(msg)?;
+            self.inserted += 1;
+            Ok(())
+        }
+
+        fn purge(&mut self) -> Result<usize, StoreError> {
+            self.store.purge()
+        }
+
+        fn apply(&mut self, change: &MessageChange) -> Result<(), StoreError> {
+            self.store.apply(change)
+        }
+
+        fn commit(&mut self) -> Result<(), StoreError> {
+            self.store.commit()
+        }
+
+        fn rollback(&mut self) -> Result<(), StoreError> {
+            self.store.rollback()
+        }
+
+        fn reconnect(&mut self) -> Result<bool, StoreError> {
+            self.reconnects += 1;
+            Ok(self.keeps_writes)
+        }
+    }
+
+    impl SqlStore for FlakyStore {
+        fn query(&mut self, query: DbQuery) {
+            self.store.query(query)
+        }
+
+        fn change(&mut self, change: DbChange) -> Changed {
+            self.store.change(change)
+        }
+
+        fn maintain(&mut self, vacuum_pages: u32) -> Result<Maintenance, rusqlite::Error> {
+            self.store.maintain(vacuum_pages)
+        }
+    }
+
+    // Runs `store` until it has handled inserting `messages`.
+    fn run_inserts(
+        store: &mut FlakyStore,
+        dead_letters: &DeadLetters,
+        messages: Vec<DBMessage>,
+    ) -> Result<(), StoreError> {
+        let (db_tx, db_rx) = mpsc::unbounded_channel();
+        let (notify_shutdown, _) = broadcast::channel(1);
+        let (shutdown_complete_tx, _) = mpsc::channel(1);
+        let shutdown = Shutdown::new(notify_shutdown.subscribe(), shutdown_complete_tx);
+
+        for msg in messages {
+            insert(&db_tx, msg).unwrap();
+        }
+        drop(db_tx);
+
+        run_store(
+            store,
+            CommitPolicy::default(),
+            dead_letters,
+            db_rx,
+            shutdown,
+        )
+    }
+
+    // Runs `store` until it has handled a single insert.
+    fn run_insert(store: &mut FlakyStore, dead_letters: &DeadLetters) -> Result<(), StoreError> {
+        run_inserts(
+            store,
+            dead_letters,
+            vec![DBMessage::new(1, "room1", "Hello there")],
+        )
+    }
+
+    // Texts of the messages written to `dead_letters`, which are then removed.
+    fn take_dead_letters(dead_letters: &DeadLetters) -> Vec<String> {
+        let contents = std::fs::read_to_string(dead_letters.path()).unwrap();
+        std::fs::remove_file(dead_letters.path()).unwrap();
+
+        contents
+            .lines()
+            .map(|line| serde_json::from_str::<DBMessage>(line).unwrap().message)
+            .collect()
+    }
+
+    #[test]
+    fn test_write_retries() {
+        let dead_letters = DeadLetters::new(Path::new("./test_retries.jsonl"));
+        let mut store = FlakyStore::new(MAX_WRITE_ATTEMPTS - 1);
+        run_insert(&mut store, &dead_letters).unwrap();
+        assert_eq!(store.inserted, 1);
+        assert_eq!(store.reconnects, MAX_WRITE_ATTEMPTS - 1);
+        assert!(!dead_letters.path().exists());
+
+        // Writes failing every time end the DB thread, their message written
+        // to the dead letters
+        let mut store = FlakyStore::new(MAX_WRITE_ATTEMPTS);
+        assert!(run_insert(&mut store, &dead_letters).is_err());
+        assert_eq!(store.inserted, 0);
+
+        assert_eq!(dead_letters.reingest(&mut store).unwrap(), 1);
+        assert_eq!(store.inserted, 1);
+        assert!(!dead_letters.path().exists());
+    }
+
+    #[test]
+    fn test_lock() {
+        let db_path = Path::new("./test_lock.db");
+        let lock_path = Path::new("./test_lock.db.lock");
+
+        // Only one process at a time holds the lock, which imports need
+        let held = lock(db_path).unwrap();
+        assert!(lock(db_path).is_err());
+        let export_path = Path::new("./test_lock.json");
+        std::fs::write(export_path, "[]").unwrap();
+        assert!(import_history(db_path, Pragmas::default(), "room1", export_path).is_err());
+        assert!(!db_path.exists());
+
+        drop(held);
+        import_history(db_path, Pragmas::default(), "room1", export_path).unwrap();
+
+        for path in &[db_path, lock_path, export_path] {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_rejected_write() {
+        // Messages the store rejects are written to the dead letters without
+        // being retried, and the DB thread keeps going
+        let dead_letters = DeadLetters::new(Path::new("./test_rejected.jsonl"));
+        let mut store = FlakyStore::new(0);
+        run_inserts(
+            &mut store,
+            &dead_letters,
+            vec![
+                DBMessage::new(1, "room1", "First").with_id(1),
+                DBMessage::new(1, "room1", "Same ID").with_id(1),
+                DBMessage::new(1, "room1", "Last").with_id(2),
+            ],
+        )
+        .unwrap();
+        assert_eq!(store.inserted, 2);
+        assert_eq!(store.reconnects, 0);
+        assert_eq!(take_dead_letters(&dead_letters), vec!["Same ID"]);
+    }
+
+    #[test]
+    fn test_lost_batch() {
+        // Messages of a batch lost on reconnecting are written to the dead
+        // letters, those inserted afterwards persisted as usual
+        let dead_letters = DeadLetters::new(Path::new("./test_lost_batch.jsonl"));
+        let mut store = FlakyStore::new(1);
+        store.fail_after = 1;
+        store.keeps_writes = false;
+        run_inserts(
+            &mut store,
+            &dead_letters,
+            vec![
+                DBMessage::new(1, "room1", "Lost"),
+                DBMessage::new(1, "room1", "Retried"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(store.inserted, 2);
+        assert_eq!(store.reconnects, 1);
+        assert_eq!(take_dead_letters(&dead_letters), vec!["Lost"]);
+    }
+
+    #[tokio::test]
+    async fn test_mirrored_changes() {
+        let db_path = Path::new("./test_mirrored_changes.db");
+        let mirror_path = Path::new("./test_mirrored_changes_mirror.db");
+        let (db_tx, db_rx) = mpsc::unbounded_channel();
+        let (notify_shutdown, _) = broadcast::channel(1);
+        let (shutdown_complete_tx, _) = mpsc::channel(1);
+        let shutdown = Shutdown::new(notify_shutdown.subscribe(), shutdown_complete_tx);
+        let dead_letters = DeadLetters::new(Path::new("./test_mirrored_changes.jsonl"));
+        let db_thread = std::thread::spawn(move || {
+            let mirror = SqliteStore::open(mirror_path, &Pragmas::default()).unwrap();
+            let mut store = SqliteStore::open(db_path, &Pragmas::default())
+                .unwrap()
+                .with_mirror(Box::new(mirror));
+            run_store(
+                &mut store,
+                CommitPolicy::default(),
+                &dead_letters,
+                db_rx,
+                shutdown,
+            )
+            .unwrap();
+            store.close().unwrap();
+        });
+
+        let (alice, bob) = query(&db_tx, |conn| {
+            Ok((
+                auth::create_user(conn, "alice", "hash")?.unwrap(),
+                auth::create_user(conn, "bob", "hash")?.unwrap(),
+            ))
+        })
+        .await
+        .unwrap();
+        let forwarded = Forwarded {
+            id: 2,
+            room: String::from("room1"),
+            user_id: bob,
+            nick: None,
+        };
+        for msg in [
+            DBMessage::new(alice, "room1", "Oops").with_id(1),
+            DBMessage::new(bob, "room1", "Hello").with_id(2),
+            DBMessage::new(alice, "room2", "Hello")
+                .with_id(3)
+                .with_forwarded(Some(forwarded)),
+            DBMessage::new(alice, "room1", "Helo").with_id(4),
+        ] {
+            insert(&db_tx, msg).unwrap();
+        }
+
+        // Deletions and edits are mirrored as they are made, and erasing a user
+        // takes the copies of their messages forwarded elsewhere along
+        let deleted = change(&db_tx, move |conn| {
+            let deleted = delete_message(conn, 1, "room1", bob, None)?;
+            let changes = vec![MessageChange::Deleted {
+                id: 1,
+                deleted_by: bob,
+            }];
+            Ok((deleted, changes))
+        })
+        .await
+        .unwrap();
+        assert!(deleted);
+        let edited = change(&db_tx, move |conn| {
+            let edited = edit_message(conn, 4, alice, "room1", "Hello", false)?;
+            let changes = vec![MessageChange::Edited {
+                id: 4,
+                text: String::from("Hello"),
+                flagged: false,
+            }];
+            Ok((edited, changes))
+        })
+        .await
+        .unwrap();
+        assert!(edited);
+        let erased = change(&db_tx, move |conn| {
+            let erased = auth::delete_user(conn, bob, MessageRetention::Delete)?;
+            Ok((erased, vec![MessageChange::Erased(bob)]))
+        })
+        .await
+        .unwrap();
+        assert!(erased);
+        drop(db_tx);
+        db_thread.join().unwrap();
+
+        let conn = open(mirror_path, &Pragmas::default()).unwrap();
+        let mirrored: Vec<(i64, String, Option<usize>)> = conn
+            .prepare(
+                "SELECT message_id, message, deleted_by FROM chat_messages ORDER BY message_id",
+            )
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            mirrored,
+            vec![
+                (1, String::from("Oops"), Some(bob)),
+                (4, String::from("Hello"), None)
+            ]
+        );
+
+        drop(conn);
+        for path in &[db_path, mirror_path] {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    // Exporter keeping the spans it is given, for tests to inspect.
+    #[derive(Clone, Debug, Default)]
+    struct KeptSpans(Arc<std::sync::Mutex<Vec<SpanData>>>);
+
+    impl SpanExporter for KeptSpans {
+        fn export(&mut self, batch: Vec<SpanData>) -> BoxFuture<'static, ExportResult> {
+            self.0.lock().unwrap().extend(batch);
+            Box::pin(futures::future::ready(Ok(())))
+        }
+    }
+
+    #[test]
+    fn test_traced_commit() {
+        let kept = KeptSpans::default();
+        global::set_tracer_provider(
+            TracerProvider::builder()
+                .with_simple_exporter(kept.clone())
+                .build(),
+        );
+
+        let (db_tx, db_rx) = mpsc::unbounded_channel();
+        let (notify_shutdown, _) = broadcast::channel(1);
+        let (shutdown_complete_tx, _) = mpsc::channel(1);
+        let shutdown = Shutdown::new(notify_shutdown.subscribe(), shutdown_complete_tx);
+
+        // The message is inserted as part of the trace it was received in
+        let received = telemetry::tracer().start_with_context("chat.receive", &Context::new());
+        let received_cx = received.span_context().clone();
+        let attached = Context::new().with_span(received).attach();
+        insert(&db_tx, DBMessage::new(1, "room1", "Hello there")).unwrap();
+        drop(attached);
+        drop(db_tx);
+
+        let policy = CommitPolicy {
+            batch_size: 1,
+            ..CommitPolicy::default()
+        };
+        let dead_letters = DeadLetters::new(Path::new("./test_traced_commit.jsonl"));
+        let mut store = MemoryStore::new().unwrap();
+        run_store(&mut store, policy, &dead_letters, db_rx, shutdown).unwrap();
+
+        // Other tests may be traced alongside, in traces of their own
+        let spans = kept.0.lock().unwrap();
+        let inserted = spans
+            .iter()
+            .find(|span| {
+                span.name == "db.insert" && span.span_context.trace_id() == received_cx.trace_id()
+            })
+            .unwrap();
+        assert_eq!(inserted.parent_span_id, received_cx.span_id());
+
+        // Commits link to the inserts they commit
+        assert!(spans.iter().any(|span| {
+            span.name == "db.commit"
+                && span
+                    .links
+                    .iter()
+                    .any(|link| link.span_context == inserted.span_context)
+        }));
+    }
+
+    #[test]
+    fn test_open() {
+        let db_path = Path::new("./test_open.db");
+        let pragmas = Pragmas {
+            busy_timeout: Duration::from_millis(100),
+            cache_size_kib: 4096,
+            ..Pragmas::default()
+        };
+        let conn = open(db_path, &pragmas).unwrap();
+
+        let journal_mode: String = conn
+            .query_row("PRAGMA journal_mode", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(journal_mode, "wal");
+        let pragma = |name: &str| -> i64 {
+            conn.query_row(&format!("PRAGMA {}", name), [], |row| row.get(0))
+                .unwrap()
+        };
+        // NORMAL
+        assert_eq!(pragma("synchronous"), 1);
+        assert_eq!(pragma("busy_timeout"), 100);
+        assert_eq!(pragma("cache_size"), -4096);
+
+        conn.close().unwrap();
+        std::fs::remove_file(db_path).unwrap();
+    }
+
+    #[test]
+    fn test_open_encrypted() {
+        let db_path = Path::new("./test_open_encrypted.db");
+        let with_key = |key: &str| Pragmas {
+            key: Some(String::from(key)),
+            ..Pragmas::default()
+        };
+
+        let opened = open(db_path, &with_key("secret"));
+        if cfg!(feature = "sqlcipher") {
+            let conn = opened.unwrap();
+            init_schema(&conn).unwrap();
+            conn.close().unwrap();
+
+            // The DB is only readable with its key
+            assert!(open(db_path, &with_key("wrong")).is_err());
+            assert!(open(db_path, &Pragmas::default()).is_err());
+            let conn = open(db_path, &with_key("secret")).unwrap();
+            assert_eq!(last_message_id(&conn).unwrap(), 0);
+            conn.close().unwrap();
+        } else {
+            // The DB would be left unencrypted
+            assert!(opened.is_err());
+        }
+
+        for suffix in &["", "-wal", "-shm"] {
+            let mut path = db_path.as_os_str().to_owned();
+            path.push(suffix);
+            let _ = std::fs::remove_file(path);
+        }
+    }
+
+    #[test]
+    fn test_recent_messages() {
+        let conn = Connection::open_in_memory().unwrap();
+        init_schema(&conn).unwrap();
+
+        let rows = &[
+            ("room1", "one"),
+            ("room2", "other"),
+            ("room1", "two"),
+            ("room1", "three"),
+        ];
+        for (room, msg) in rows {
+            conn.execute(
+                "INSERT INTO chat_messages (user_id, room_name, message) VALUES (?1, ?2, ?3)",
+                params![1, room, msg],
+            )
+            .unwrap();
+        }
+
+        let messages = recent_messages(&conn, "room1", 1, 2).unwrap();
+        let messages: Vec<&str> = messages.iter().map(|m| m.message.as_str()).collect();
+
+        // Only the latest messages of the room are returned, oldest first
+        assert_eq!(messages, vec!["two", "three"]);
+
+        // Shadowed messages are only returned to their author
+        conn.execute(
+            "INSERT INTO chat_messages (user_id, room_name, message, shadowed)
+                VALUES (2, 'room1', 'troll', 1)",
+            [],
+        )
+        .unwrap();
+        let last_text = |viewer| {
+            recent_messages(&conn, "room1", viewer, 1).unwrap()[0]
+                .message
+                .clone()
+        };
+        assert_eq!(last_text(2), "troll");
+        assert_eq!(last_text(1), "three");
+    }
+
+    #[test]
+    fn test_messages_since() {
+        let conn = Connection::open_in_memory().unwrap();
+        init_schema(&conn).unwrap();
+
+        // The room moved to another instance after its second message, which
+        // numbers the later ones below it
+        conn.execute(
+            "INSERT INTO chat_messages (message_id, seq, user_id, room_name, message, shadowed)
+                VALUES (1, 1, 1, 'room1', 'one', 0), (2, 1, 1, 'room2', 'other', 0),
+                (259, 2, 1, 'room1', 'two', 0), (4, 3, 2, 'room1', 'troll', 1),
+                (5, 4, 1, 'room1', 'three', 0)",
+            [],
+        )
+        .unwrap();
+
+        let texts = |viewer, after_id| {
+            messages_since(&conn, "room1", viewer, after_id)
+                .unwrap()
+                .map(|messages| messages.into_iter().map(|m| m.message).collect::<Vec<_>>())
+        };
+
+        // Every later message of the room is returned, in sequence
+        assert_eq!(texts(1, 1).unwrap(), vec!["two", "three"]);
+        assert_eq!(texts(2, 1).unwrap(), vec!["two", "troll", "three"]);
+        assert_eq!(texts(1, 259).unwrap(), vec!["three"]);
+        assert!(texts(1, 5).unwrap().is_empty());
+        // Unless the message is not one of the room's
+        assert_eq!(texts(1, 2), None);
+        assert_eq!(texts(1, 9), None);
+    }
+
+    #[test]
+    fn test_delete_expired() {
+        let conn = Connection::open_in_memory().unwrap();
+        init_schema(&conn).unwrap();
+
+        conn.execute(
+            "INSERT INTO chat_messages (message_id, user_id, room_name, message, expires_at)
+                VALUES (1, 1, 'room1', 'kept', NULL),
+                (2, 1, 'room1', 'expired', datetime('now', '-1 seconds')),
+                (3, 1, 'room1', 'expiring', datetime('now', '+60 seconds'))",
+            [],
+        )
+        .unwrap();
+        conn.execute(
+            "INSERT INTO mentions (message_id, user_id, room_name) VALUES (1, 2, 'room1'), (2, 2, 'room1')",
+            [],
+        )
+        .unwrap();
+
+        // Only expired messages are deleted, along with their mentions
+        assert_eq!(delete_expired(&conn).unwrap(), 1);
+        assert_eq!(delete_expired(&conn).unwrap(), 0);
+        let texts: Vec<String> = recent_messages(&conn, "room1", 1, 10)
+            .unwrap()
+            .into_iter()
+            .map(|m| m.message)
+            .collect();
+        assert_eq!(texts, vec!["kept", "expiring"]);
+        let mentions: i64 = conn
+            .query_row("SELECT COUNT(*) FROM mentions", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(mentions, 1);
+    }
+
+    #[test]
+    fn test_message() {
+        let conn = Connection::open_in_memory().unwrap();
+        init_schema(&conn).unwrap();
+
+        conn.execute(
+            "INSERT INTO chat_messages (message_id, user_id, room_name, message, shadowed,
+                    forwarded_id, forwarded_room, forwarded_user_id, forwarded_nick)
+                VALUES (1, 1, 'room1', 'hello', 0, NULL, NULL, NULL, NULL),
+                (2, 2, 'room1', 'troll', 1, NULL, NULL, NULL, NULL),
+                (3, 3, 'room2', 'hello', 0, 1, 'room1', 1, 'ally')",
+            [],
+        )
+        .unwrap();
+
+        // Messages are only found in their room, by those who may see them
+        assert_eq!(
+            message(&conn, "room1", 1, 1).unwrap().unwrap().message,
+            "hello"
+        );
+        assert!(message(&conn, "room2", 1, 1).unwrap().is_none());
+        assert!(message(&conn, "room1", 1, 2).unwrap().is_none());
+        assert!(message(&conn, "room1", 2, 2).unwrap().is_some());
+
+        // Forwarded messages credit where they were first sent
+        assert!(message(&conn, "room1", 1, 1)
+            .unwrap()
+            .unwrap()
+            .forwarded
+            .is_none());
+        assert_eq!(
+            message(&conn, "room2", 1, 3).unwrap().unwrap().forwarded,
+            Some(Forwarded {
+                id: 1,
+                room: String::from("room1"),
+                user_id: 1,
+                nick: Some(String::from("ally")),
+            })
+        );
+    }
+
+    #[test]
+    fn test_message_ids() {
+        let conn = Connection::open_in_memory().unwrap();
+        init_schema(&conn).unwrap();
+
+        assert_eq!(last_message_id(&conn).unwrap(), 0);
+
+        conn.execute(
+            "INSERT INTO chat_messages (message_id, user_id, room_name, message) VALUES (?1, ?2, ?3, ?4)",
+            params![41, 1, "room1", "msg"],
+        )
+        .unwrap();
+
+        let message_ids = MessageIds::new(last_message_id(&conn).unwrap(), None);
+        assert_eq!(message_ids.next_id(), 42);
+        assert_eq!(message_ids.next_id(), 43);
+
+        // Instances sharing rooms never hand out the same IDs
+        let node0 = MessageIds::new(last_message_id(&conn).unwrap(), Some(0));
+        let node1 = MessageIds::new(41, Some(1));
+        assert_eq!(node0.next_id(), 256);
+        assert_eq!(node0.next_id(), 512);
+        assert_eq!(node1.next_id(), 257);
+        assert_eq!(node1.next_id(), 513);
+        assert_eq!(MessageIds::new(256, Some(0)).next_id(), 512);
+    }
+
+    #[test]
+    fn test_seq_backfill() {
+        let conn = Connection::open_in_memory().unwrap();
+
+        // Schema from before messages were sequenced
+        conn.execute(
+            "CREATE TABLE chat_messages (
+                message_id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+                user_id INTEGER,
+                room_name TEXT NOT NULL,
+                message TEXT NOT NULL,
+                created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL
+            )",
+            [],
+        )
+        .unwrap();
+        conn.execute(
+            "INSERT INTO chat_messages (user_id, room_name, message) VALUES (1, 'room1', 'old')",
+            [],
+        )
+        .unwrap();
+
+        init_schema(&conn).unwrap();
+
+        assert_eq!(last_room_seq(&conn, "room1").unwrap(), 1);
+        assert_eq!(last_room_seq(&conn, "room2").unwrap(), 0);
+
+        // Running again is a no-op
+        init_schema(&conn).unwrap();
+    }
+
+    #[test]
+    fn test_user_backfill() {
+        let conn = Connection::open_in_memory().unwrap();
+
+        // Schema from before users were persisted
+        conn.execute(
+            "CREATE TABLE chat_messages (
+                message_id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+                user_id INTEGER,
+                room_name TEXT NOT NULL,
+                message TEXT NOT NULL,
+                created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL
+            )",
+            [],
+        )
+        .unwrap();
+        for user_id in &[1, 3, 3] {
+            conn.execute(
+                "INSERT INTO chat_messages (user_id, room_name, message) VALUES (?1, 'room1', 'old')",
+                params![user_id],
+            )
+            .unwrap();
+        }
+
+        init_schema(&conn).unwrap();
+        init_schema(&conn).unwrap();
+
+        // New users are numbered after those who sent old messages
+        assert_eq!(crate::auth::create_guest(&conn, None).unwrap(), 4);
+    }
+
+    #[test]
+    fn test_nicknames() {
+        let conn = Connection::open_in_memory().unwrap();
+        init_schema(&conn).unwrap();
+
+        let alice = auth::create_user(&conn, "alice", "hash").unwrap().unwrap();
+        let guest = crate::auth::create_guest(&conn, None).unwrap();
+        assert_eq!(nickname(&conn, guest).unwrap(), None);
+
+        assert!(reserve_nickname(&conn, guest, "Bob").unwrap());
+        assert_eq!(nickname(&conn, guest).unwrap().as_deref(), Some("Bob"));
+        // Reserving again is a no-op
+        assert!(reserve_nickname(&conn, guest, "Bob").unwrap());
+
+        // Nicknames are unique regardless of case, and may not be the username
+        // of another account
+        assert!(!reserve_nickname(&conn, alice, "bob").unwrap());
+        assert!(!reserve_nickname(&conn, guest, "ALICE").unwrap());
+        assert!(reserve_nickname(&conn, alice, "Alice").unwrap());
+
+        // Changing nickname frees up the previous one
+        assert!(reserve_nickname(&conn, guest, "carol").unwrap());
+        assert!(reserve_nickname(&conn, alice, "bob").unwrap());
+
+        release_nickname(&conn, guest).unwrap();
+        assert_eq!(nickname(&conn, guest).unwrap(), None);
+        assert!(reserve_nickname(&conn, alice, "carol").unwrap());
+
+        // Messages keep the nickname their author had when sending them
+        conn.execute(
+            "INSERT INTO chat_messages (user_id, room_name, message, nickname)
+                VALUES (?1, 'room1', 'hi', 'carol')",
+            params![alice],
+        )
+        .unwrap();
+        assert!(reserve_nickname(&conn, alice, "dave").unwrap());
+
+        let messages = recent_messages(&conn, "room1", 1, 1).unwrap();
+        assert_eq!(messages[0].nickname.as_deref(), Some("carol"));
+    }
+
+    #[test]
+    fn test_edit_message() {
+        let conn = Connection::open_in_memory().unwrap();
+        init_schema(&conn).unwrap();
+
+        conn.execute(
+            "INSERT INTO chat_messages (message_id, user_id, room_name, message) VALUES (1, 1, 'room1', 'helo')",
+            [],
+        )
+        .unwrap();
+
+        // Only the author may edit, and only within the same room
+        assert!(!edit_message(&conn, 1, 2, "room1", "hijacked", false).unwrap());
+        assert!(!edit_message(&conn, 1, 1, "room2", "hijacked", false).unwrap());
+        assert!(edit_message(&conn, 1, 1, "room1", "hello", false).unwrap());
+
+        let messages = recent_messages(&conn, "room1", 1, 1).unwrap();
+        assert_eq!(messages[0].message, "hello");
+        assert!(messages[0].edited_at.is_some());
+
+        // Edits may flag messages, but not unflag them
+        assert!(flagged_messages(&conn, "room1").unwrap().is_empty());
+        assert!(edit_message(&conn, 1, 1, "room1", "darn", true).unwrap());
+        assert!(edit_message(&conn, 1, 1, "room1", "hello", false).unwrap());
+        let flagged = flagged_messages(&conn, "room1").unwrap();
+        assert_eq!(flagged[0].id, 1);
+        assert_eq!(flagged[0].text, "hello");
+    }
+
+    #[test]
+    fn test_delete_message() {
+        let conn = Connection::open_in_memory().unwrap();
+        init_schema(&conn).unwrap();
+
+        conn.execute(
+            "INSERT INTO chat_messages (message_id, user_id, room_name, message) VALUES (1, 1, 'room1', 'oops')",
+            [],
+        )
+        .unwrap();
+
+        // Other users can not delete the message, unless unrestricted (i.e. moderators)
+        assert!(!delete_message(&conn, 1, "room1", 2, Some(2)).unwrap());
+        assert!(delete_message(&conn, 1, "room1", 2, None).unwrap());
+
+        // Already deleted
+        assert!(!delete_message(&conn, 1, "room1", 1, Some(1)).unwrap());
+        assert!(!edit_message(&conn, 1, 1, "room1", "edited", false).unwrap());
+
+        // Tombstone is kept
+        let messages = recent_messages(&conn, "room1", 1, 1).unwrap();
+        assert!(messages[0].deleted_at.is_some());
+    }
+
+    #[test]
+    fn test_room_pins() {
+        let conn = Connection::open_in_memory().unwrap();
+        init_schema(&conn).unwrap();
+
+        conn.execute(
+            "INSERT INTO chat_messages (message_id, user_id, room_name, message) VALUES
+                (1, 1, 'room1', 'first'), (2, 1, 'room1', 'second'), (3, 1, 'room2', 'other')",
+            [],
+        )
+        .unwrap();
+
+        assert!(pin_message(&conn, 1, "room1", 2).unwrap());
+        assert!(pin_message(&conn, 2, "room1", 2).unwrap());
+        // Already pinned, or not a message of the room
+        assert!(!pin_message(&conn, 1, "room1", 2).unwrap());
+        assert!(!pin_message(&conn, 3, "room1", 2).unwrap());
+
+        let pins = room_pins(&conn, "room1").unwrap();
+        assert_eq!(pins.iter().map(|p| p.id).collect::<Vec<_>>(), vec![1, 2]);
+        assert_eq!(pins[0].text, "first");
+        assert_eq!(pins[0].pinned_by, 2);
+
+        assert!(unpin_message(&conn, 1, "room1").unwrap());
+        assert!(!unpin_message(&conn, 1, "room1").unwrap());
+
+        // Deleted messages are no longer listed
+        delete_message(&conn, 2, "room1", 1, None).unwrap();
+        assert!(room_pins(&conn, "room1").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_read_markers() {
+        let conn = Connection::open_in_memory().unwrap();
+        init_schema(&conn).unwrap();
+
+        // The room moved to another instance after its first message, whose
+        // ID is greater than that of the next one
+        conn.execute(
+            "INSERT INTO chat_messages (message_id, seq, user_id, room_name, message) VALUES
+                (257, 1, 1, 'room1', 'first'), (2, 2, 1, 'room1', 'second'),
+                (3, 1, 1, 'room2', 'other')",
+            [],
+        )
+        .unwrap();
+
+        assert!(mark_read(&conn, "room1", 2, 257).unwrap());
+        assert!(mark_read(&conn, "room1", 2, 2).unwrap());
+        assert!(mark_read(&conn, "room1", 3, 257).unwrap());
+        // Markers never move back, nor to messages of other rooms
+        assert!(!mark_read(&conn, "room1", 2, 257).unwrap());
+        assert!(!mark_read(&conn, "room1", 2, 2).unwrap());
+        assert!(!mark_read(&conn, "room1", 3, 3).unwrap());
+        assert!(!mark_read(&conn, "room1", 3, 9).unwrap());
+
+        let markers = read_markers(&conn, "room1").unwrap();
+        assert_eq!(
+            markers
+                .iter()
+                .map(|m| (m.user_id, m.id))
+                .collect::<Vec<_>>(),
+            vec![(2, 2), (3, 257)]
+        );
+        assert!(read_markers(&conn, "room2").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_unread_counts() {
+        let conn = Connection::open_in_memory().unwrap();
+        init_schema(&conn).unwrap();
+
+        conn.execute(
+            "INSERT INTO chat_messages (message_id, seq, user_id, room_name, message) VALUES
+                (1, 1, 1, 'room1', 'a'), (2, 1, 1, 'room2', 'b'), (3, 2, 1, 'room1', 'c'),
+                (4, 3, 1, 'room1', 'd')",
+            [],
+        )
+        .unwrap();
+
+        mark_read(&conn, "room1", 2, 1).unwrap();
+        mark_read(&conn, "room2", 2, 2).unwrap();
+        let unread = unread_counts(&conn, 2).unwrap();
+        assert_eq!(
+            unread
+                .iter()
+                .map(|u| (u.room.as_str(), u.unread, u.last_read_id))
+                .collect::<Vec<_>>(),
+            vec![("room1", 2, 1), ("room2", 0, 2)]
+        );
+
+        mark_read(&conn, "room1", 2, 4).unwrap();
+        assert_eq!(unread_counts(&conn, 2).unwrap()[0].unread, 0);
+        assert!(unread_counts(&conn, 3).unwrap().is_empty());
+    }
 }
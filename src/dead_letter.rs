@@ -0,0 +1,106 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use crate::{db::DBMessage, store::MessageStore};
+
+// File messages the DB thread failed to persist are written to, one JSON
+// object per line, to be re-ingested once the DB is healthy again.
+#[derive(Clone, Debug)]
+pub struct DeadLetters {
+    path: PathBuf,
+}
+
+impl DeadLetters {
+    pub fn new(path: &Path) -> Self {
+        DeadLetters {
+            path: path.to_path_buf(),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // Appends `msg` to the file, creating it if need be.
+    pub fn append(&self, msg: &DBMessage) -> Result<(), anyhow::Error> {
+        let mut line = serde_json::to_string(msg)?;
+        line.push('\n');
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        file.write_all(line.as_bytes())?;
+
+        Ok(())
+    }
+
+    // Persists the messages in the file to `store`, returning how many were.
+    // Those that fail to be persisted again are kept in the file, which is
+    // removed once empty. Messages that expire do so counting from now.
+    pub fn reingest<S: MessageStore>(&self, store: &mut S) -> Result<usize, anyhow::Error> {
+        if !self.path.exists() {
+            return Ok(0);
+        }
+
+        let mut reingested = 0;
+        let mut failed = Vec::new();
+        let contents = fs::read_to_string(&self.path)?;
+        for line in contents.lines() {
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let msg: DBMessage = serde_json::from_str(line)?;
+            match store.insert(&msg) {
+                Ok(()) => reingested += 1,
+                Err(e) => {
+                    eprintln!("Unable to re-ingest dead-lettered message: {}", e);
+                    failed.push(line);
+                }
+            }
+        }
+        store.commit()?;
+
+        if failed.is_empty() {
+            fs::remove_file(&self.path)?;
+        } else {
+            fs::write(&self.path, failed.join("\n") + "\n")?;
+        }
+
+        Ok(reingested)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStore;
+
+    #[test]
+    fn test_reingest() {
+        let dead_letters = DeadLetters::new(Path::new("./test_dead_letters.jsonl"));
+        let mut store = MemoryStore::new().unwrap();
+        assert_eq!(dead_letters.reingest(&mut store).unwrap(), 0);
+
+        for (id, text) in &[(1, "Hello"), (2, "there")] {
+            dead_letters
+                .append(&DBMessage::new(1, "room1", text).with_id(*id))
+                .unwrap();
+        }
+        // Messages persisted meanwhile are kept in the file
+        store
+            .insert(&DBMessage::new(1, "room1", "Taken").with_id(2))
+            .unwrap();
+
+        assert_eq!(dead_letters.reingest(&mut store).unwrap(), 1);
+        let kept = fs::read_to_string(dead_letters.path()).unwrap();
+        assert!(kept.contains("there"));
+        assert!(!kept.contains("Hello"));
+
+        fs::remove_file(dead_letters.path()).unwrap();
+    }
+}
@@ -0,0 +1,403 @@
+use std::{
+    collections::{HashMap, HashSet},
+    str::FromStr,
+    time::Duration,
+};
+
+use anyhow::anyhow;
+use reqwest::Client;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::sync::mpsc;
+
+use crate::{
+    auth::{oauth, Principal},
+    db,
+    server::ServerState,
+    shutdown::Shutdown,
+    user::{self, Admission, UserRx},
+};
+
+// Provider the accounts of Discord users are recorded under, as external
+// identities, by their Discord ID.
+const DISCORD_PROVIDER: &str = "discord";
+
+// External ID of the account the bridge itself joins rooms as, which is not a
+// Discord ID.
+const BRIDGE_ID: &str = "bridge";
+
+// Longest message Discord accepts, in characters.
+const MAX_DISCORD_MESSAGE_LENGTH: usize = 2000;
+
+// How long to wait before trying again to reach Discord when it could not be
+// reached to start relaying.
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+// A Discord channel mirrored to a room, given with
+// `--discord-channel <room>=<channel ID>`.
+#[derive(Clone, Debug)]
+pub struct DiscordChannel {
+    pub room: String,
+    pub channel_id: String,
+}
+
+impl FromStr for DiscordChannel {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (room, channel_id) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow!("Expected <room>=<channel ID>, got '{}'", s))?;
+        if room.is_empty()
+            || channel_id.is_empty()
+            || !channel_id.chars().all(|c| c.is_ascii_digit())
+        {
+            return Err(anyhow!("Expected <room>=<channel ID>, got '{}'", s));
+        }
+
+        Ok(DiscordChannel {
+            room: String::from(room),
+            channel_id: String::from(channel_id),
+        })
+    }
+}
+
+// A message of a Discord channel, as far as the bridge cares.
+#[derive(Debug, Deserialize)]
+struct DiscordMessage {
+    id: String,
+    #[serde(default)]
+    content: String,
+    author: DiscordUser,
+    // Set for messages posted through a Discord webhook
+    #[serde(default)]
+    webhook_id: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DiscordUser {
+    id: String,
+    username: String,
+    // Display name, if set apart from the username
+    #[serde(default)]
+    global_name: Option<String>,
+}
+
+impl DiscordUser {
+    // How the user shows up in rooms: their display name, marked as coming
+    // from Discord.
+    fn nick(&self) -> String {
+        format!(
+            "{}@discord",
+            self.global_name.as_deref().unwrap_or(&self.username)
+        )
+    }
+}
+
+// The content of the Discord message relaying what `nick` sent to a room,
+// prefixed with who sent it, and cut down to what Discord accepts.
+fn discord_content(nick: &str, text: &str) -> String {
+    let content = format!("**{}**: {}", nick, text);
+    match content.char_indices().nth(MAX_DISCORD_MESSAGE_LENGTH) {
+        Some((end, _)) => String::from(&content[..end]),
+        None => content,
+    }
+}
+
+// The Discord REST API, as a bot.
+#[derive(Clone)]
+pub struct DiscordApi {
+    client: Client,
+    // e.g. `https://discord.com/api/v10`
+    base_url: String,
+    token: String,
+}
+
+impl DiscordApi {
+    pub fn new(client: Client, base_url: &str, token: &str) -> Self {
+        DiscordApi {
+            client,
+            base_url: String::from(base_url.trim_end_matches('/')),
+            token: String::from(token),
+        }
+    }
+
+    async fn get(&self, path: &str) -> Result<Value, anyhow::Error> {
+        let value = self
+            .client
+            .get(format!("{}{}", self.base_url, path))
+            .header("Authorization", format!("Bot {}", self.token))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        Ok(value)
+    }
+
+    // Discord ID of the bot.
+    async fn bot_id(&self) -> Result<String, anyhow::Error> {
+        let me = self.get("/users/@me").await?;
+        me["id"]
+            .as_str()
+            .map(String::from)
+            .ok_or_else(|| anyhow!("Discord did not tell the ID of the bot"))
+    }
+
+    // Messages of `channel_id` after message `after`, oldest first, or its
+    // last `limit` messages if `after` is None.
+    async fn messages(
+        &self,
+        channel_id: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<DiscordMessage>, anyhow::Error> {
+        let mut path = format!("/channels/{}/messages?limit={}", channel_id, limit);
+        if let Some(after) = after {
+            path.push_str(&format!("&after={}", after));
+        }
+
+        // Discord lists messages newest first
+        let mut messages: Vec<DiscordMessage> = serde_json::from_value(self.get(&path).await?)?;
+        messages.reverse();
+        Ok(messages)
+    }
+
+    async fn post(&self, channel_id: &str, content: &str) -> Result<(), anyhow::Error> {
+        self.client
+            .post(format!(
+                "{}/channels/{}/messages",
+                self.base_url, channel_id
+            ))
+            .header("Authorization", format!("Bot {}", self.token))
+            // Relayed messages never ping anyone on Discord
+            .json(&json!({ "content": content, "allowed_mentions": { "parse": [] } }))
+            .send()
+            .await?
+            .error_for_status()?;
+
+        Ok(())
+    }
+}
+
+// Mirrors `channel` to its room until shutdown: what is posted in the
+// channel is sent to the room by an account of its author, and what is sent
+// to the room is posted in the channel by the bot, prefixed with the nickname
+// of its author. Messages relayed one way are never relayed back.
+pub async fn relay(
+    channel: DiscordChannel,
+    api: DiscordApi,
+    poll_interval: Duration,
+    state: ServerState,
+    mut shutdown: Shutdown,
+) {
+    // The bot's own messages are those it relayed from the room
+    let bot_id = loop {
+        match api.bot_id().await {
+            Ok(bot_id) => break bot_id,
+            Err(e) => eprintln!("Failed to reach Discord: {}", e),
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(RETRY_DELAY) => {}
+            _ = shutdown.async_listen() => return,
+        }
+    };
+
+    // Only what is posted from now on is relayed, not what was before
+    let mut last_id = loop {
+        match api.messages(&channel.channel_id, None, 1).await {
+            Ok(messages) => break messages.last().map(|message| message.id.clone()),
+            Err(e) => eprintln!(
+                "Failed to read Discord channel {}: {}",
+                channel.channel_id, e
+            ),
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(RETRY_DELAY) => {}
+            _ = shutdown.async_listen() => return,
+        }
+    };
+
+    let (bridge, mut user_rx) = match join(&channel.room, &state).await {
+        Ok(joined) => joined,
+        Err(e) => {
+            eprintln!(
+                "Failed to mirror Discord channel to {}: {}",
+                channel.room, e
+            );
+            return;
+        }
+    };
+    eprintln!(
+        "Mirroring Discord channel {} to {}",
+        channel.channel_id, channel.room
+    );
+
+    // Local accounts of Discord users, by their Discord ID. What they send is
+    // not relayed back to Discord
+    let mut accounts = HashMap::new();
+    let mut relayed_from_discord = HashSet::new();
+    let mut poll = tokio::time::interval(poll_interval);
+    loop {
+        tokio::select! {
+            _ = poll.tick() => {
+                let messages = match api.messages(&channel.channel_id, last_id.as_deref(), 100).await {
+                    Ok(messages) => messages,
+                    Err(e) => {
+                        eprintln!("Failed to read Discord channel {}: {}", channel.channel_id, e);
+                        continue;
+                    }
+                };
+                for message in messages {
+                    last_id = Some(message.id.clone());
+                    if message.author.id == bot_id
+                        || message.webhook_id.is_some()
+                        || message.content.trim().is_empty()
+                    {
+                        continue;
+                    }
+
+                    match post(&message, &channel.room, &mut accounts, &state).await {
+                        Ok(user_id) => {
+                            relayed_from_discord.insert(user_id);
+                        }
+                        Err(e) => eprintln!("Failed to post message from Discord: {}", e),
+                    }
+                }
+            }
+            msg = user_rx.recv() => {
+                let msg = match msg {
+                    Some(msg) if !msg.is_close() => msg,
+                    _ => break,
+                };
+                let event: Value = match msg.to_str().ok().and_then(|text| serde_json::from_str(text).ok()) {
+                    Some(event) => event,
+                    None => continue,
+                };
+                let user_id = event["user_id"].as_u64().unwrap_or_default() as usize;
+                if event["type"] != "message"
+                    || !event["deleted_at"].is_null()
+                    || user_id == bridge.user_id
+                    || relayed_from_discord.contains(&user_id)
+                {
+                    continue;
+                }
+
+                let nick = match event["nick"].as_str() {
+                    Some(nick) => String::from(nick),
+                    None => format!("user{}", user_id),
+                };
+                let content = discord_content(&nick, event["text"].as_str().unwrap_or_default());
+                if let Err(e) = api.post(&channel.channel_id, &content).await {
+                    eprintln!("Failed to post message to Discord: {}", e);
+                }
+            }
+            _ = shutdown.async_listen() => break,
+        }
+    }
+
+    user::remove_user_from_room(&bridge, &state.rooms).await;
+}
+
+// Joins `room_name` as the bridge, to hear what is sent to it.
+async fn join(room_name: &str, state: &ServerState) -> Result<(user::User, UserRx), anyhow::Error> {
+    let user_id = db::query(&state.db_tx, |conn| {
+        oauth::identity_user(conn, DISCORD_PROVIDER, BRIDGE_ID)
+    })
+    .await?;
+    state
+        .nicks
+        .write()
+        .await
+        .insert(user_id, String::from("discord"));
+
+    let (user_tx, user_rx) = mpsc::unbounded_channel();
+    let bridge = state.gateway_user(room_name, Principal::user(user_id), false, None, user_tx);
+    let joined = user::add_user_to_room(
+        &bridge,
+        &state.rooms,
+        state.config.explicit_rooms,
+        state.config.room_capacity,
+        None,
+        None,
+    )
+    .await?;
+    match joined {
+        Ok(Admission::Joined) => Ok((bridge, user_rx)),
+        Ok(Admission::Pending(_)) => {
+            user::remove_user_from_room(&bridge, &state.rooms).await;
+            Err(anyhow!("Joining {} requires approval", room_name))
+        }
+        Err(refusal) => {
+            user::remove_user_from_room(&bridge, &state.rooms).await;
+            Err(anyhow!("Refused to join {}: {:?}", room_name, refusal))
+        }
+    }
+}
+
+// Posts `message` to `room_name` as the local account of its author, created
+// when they are first relayed, returning that account.
+async fn post(
+    message: &DiscordMessage,
+    room_name: &str,
+    accounts: &mut HashMap<String, usize>,
+    state: &ServerState,
+) -> Result<usize, anyhow::Error> {
+    let user_id = match accounts.get(&message.author.id) {
+        Some(&user_id) => user_id,
+        None => {
+            let discord_id = message.author.id.clone();
+            let user_id = db::query(&state.db_tx, move |conn| {
+                oauth::identity_user(conn, DISCORD_PROVIDER, &discord_id)
+            })
+            .await?;
+            accounts.insert(message.author.id.clone(), user_id);
+            user_id
+        }
+    };
+    state
+        .nicks
+        .write()
+        .await
+        .insert(user_id, message.author.nick());
+
+    let user = state.detached_user(room_name, user_id).await?;
+    user.send_message(&message.content, None, None, None, None, &state.rooms)
+        .await?
+        .accepted()?;
+
+    Ok(user_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let channel: DiscordChannel = "lobby=1234567890".parse().unwrap();
+        assert_eq!(channel.room, "lobby");
+        assert_eq!(channel.channel_id, "1234567890");
+        assert!("lobby".parse::<DiscordChannel>().is_err());
+        assert!("lobby=general".parse::<DiscordChannel>().is_err());
+    }
+
+    #[test]
+    fn test_attribution() {
+        let author: DiscordUser =
+            serde_json::from_str(r#"{"id": "7", "username": "alice_1", "global_name": "Alice"}"#)
+                .unwrap();
+        assert_eq!(author.nick(), "Alice@discord");
+        let author: DiscordUser =
+            serde_json::from_str(r#"{"id": "8", "username": "bob", "global_name": null}"#).unwrap();
+        assert_eq!(author.nick(), "bob@discord");
+
+        assert_eq!(discord_content("carol", "hi"), "**carol**: hi");
+        let long = "é".repeat(MAX_DISCORD_MESSAGE_LENGTH);
+        assert_eq!(
+            discord_content("carol", &long).chars().count(),
+            MAX_DISCORD_MESSAGE_LENGTH
+        );
+    }
+}
@@ -0,0 +1,455 @@
+use std::{fs, path::Path};
+
+use anyhow::anyhow;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::{auth::DELETED_USER_ID, compression::MessageText};
+
+// Number of messages read from the DB at a time while exporting a room.
+pub const PAGE_SIZE: usize = 500;
+
+// What the history of a room is exported as.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    // An array of messages
+    #[default]
+    Json,
+    // A header, then a line per message
+    Csv,
+}
+
+impl ExportFormat {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ExportFormat::Json => "application/json",
+            ExportFormat::Csv => "text/csv; charset=utf-8",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Json => "json",
+            ExportFormat::Csv => "csv",
+        }
+    }
+
+    // What the export starts with, before any message.
+    pub fn header(self) -> &'static str {
+        match self {
+            ExportFormat::Json => "[",
+            ExportFormat::Csv => "id,seq,user_id,username,nick,text,created_at,edited_at\r\n",
+        }
+    }
+
+    // What the export ends with, after every message.
+    pub fn footer(self) -> &'static str {
+        match self {
+            ExportFormat::Json => "]",
+            ExportFormat::Csv => "",
+        }
+    }
+
+    // `message` as exported, `first` being whether no message came before it.
+    pub fn format(self, message: &ExportedMessage, first: bool) -> String {
+        match self {
+            ExportFormat::Json => {
+                let json = serde_json::to_string(message).unwrap_or_default();
+                if first {
+                    json
+                } else {
+                    format!(",{}", json)
+                }
+            }
+            ExportFormat::Csv => {
+                let optional = |field: &Option<String>| csv_field(field.as_deref().unwrap_or(""));
+                format!(
+                    "{},{},{},{},{},{},{},{}\r\n",
+                    message.id,
+                    message.seq.map(|seq| seq.to_string()).unwrap_or_default(),
+                    message.user_id,
+                    optional(&message.username),
+                    optional(&message.nick),
+                    csv_field(&message.text),
+                    csv_field(&message.created_at),
+                    optional(&message.edited_at)
+                )
+            }
+        }
+    }
+}
+
+// Reads the fields of each line of `csv`, quoted or not.
+fn parse_csv(csv: &str) -> Result<Vec<Vec<String>>, anyhow::Error> {
+    let mut lines = Vec::new();
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = csv.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            '"' if quoted => quoted = false,
+            '"' if field.is_empty() => quoted = true,
+            ',' if !quoted => fields.push(std::mem::take(&mut field)),
+            '\r' if !quoted && chars.peek() == Some(&'\n') => {}
+            '\n' if !quoted => {
+                fields.push(std::mem::take(&mut field));
+                lines.push(std::mem::take(&mut fields));
+            }
+            c => field.push(c),
+        }
+    }
+    if quoted {
+        return Err(anyhow!("Unterminated quoted field"));
+    }
+    if !field.is_empty() || !fields.is_empty() {
+        fields.push(field);
+        lines.push(fields);
+    }
+
+    Ok(lines)
+}
+
+// Quotes `field` if it holds anything CSV gives a meaning to.
+fn csv_field(field: &str) -> String {
+    if field.contains(&[',', '"', '\r', '\n'][..]) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        String::from(field)
+    }
+}
+
+// A message of a room, as exported.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct ExportedMessage {
+    pub id: i64,
+    pub seq: Option<i64>,
+    pub user_id: usize,
+    // Unless sent as a guest
+    pub username: Option<String>,
+    pub nick: Option<String>,
+    pub text: String,
+    pub created_at: String,
+    pub edited_at: Option<String>,
+}
+
+impl ExportedMessage {
+    // Reads a line of a CSV export, in the order of its header.
+    fn from_csv(fields: &[String]) -> Result<Self, anyhow::Error> {
+        if fields.len() != 8 {
+            return Err(anyhow!("Expected 8 fields, found {}", fields.len()));
+        }
+        let optional = |field: &String| (!field.is_empty()).then(|| field.clone());
+
+        Ok(ExportedMessage {
+            id: fields[0].parse()?,
+            seq: optional(&fields[1]).map(|seq| seq.parse()).transpose()?,
+            user_id: fields[2].parse()?,
+            username: optional(&fields[3]),
+            nick: optional(&fields[4]),
+            text: fields[5].clone(),
+            created_at: fields[6].clone(),
+            edited_at: optional(&fields[7]),
+        })
+    }
+}
+
+// Reads the messages of an export of a room, as CSV if `path` ends in `.csv`,
+// and as JSON otherwise.
+pub fn read_export(path: &Path) -> Result<Vec<ExportedMessage>, anyhow::Error> {
+    let contents = fs::read_to_string(path)?;
+    if path.extension().is_some_and(|extension| extension == "csv") {
+        parse_csv(&contents)?
+            .iter()
+            // The header names the fields rather than give any
+            .skip(1)
+            .map(|fields| ExportedMessage::from_csv(fields))
+            .collect()
+    } else {
+        Ok(serde_json::from_str(&contents)?)
+    }
+}
+
+// What importing an export did.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Imported {
+    pub messages: usize,
+    // Messages given new IDs, their own being taken
+    pub renumbered: usize,
+    // Messages attributed to `DELETED_USER_ID`, their author being a guest or
+    // having no account of the same username here
+    pub anonymized: usize,
+}
+
+// Persists `messages`, as exported from a room, to `room_name`, keeping when
+// they were sent and edited. They keep their ID and `seq` unless already taken,
+// and are attributed to the user of the same username, if any. Messages of
+// guests are anonymized, as their user IDs are those of another instance.
+pub fn import(
+    conn: &Connection,
+    room_name: &str,
+    messages: &[ExportedMessage],
+) -> Result<Imported, rusqlite::Error> {
+    let mut imported = Imported::default();
+
+    // Messages keeping their ID are imported first, so that those given new
+    // ones do not take them
+    let mut renumbered = Vec::new();
+    for message in messages {
+        let id_taken = conn
+            .query_row(
+                "SELECT 1 FROM chat_messages WHERE message_id = ?1",
+                [message.id],
+                |_| Ok(()),
+            )
+            .optional()?
+            .is_some();
+        if id_taken {
+            renumbered.push(message);
+        } else if !import_message(conn, room_name, message, true)? {
+            imported.anonymized += 1;
+        }
+    }
+    for message in &renumbered {
+        if !import_message(conn, room_name, message, false)? {
+            imported.anonymized += 1;
+        }
+    }
+
+    imported.messages = messages.len();
+    imported.renumbered = renumbered.len();
+
+    Ok(imported)
+}
+
+// Persists `message` to `room_name`, with its own ID if `keep_id`, returning
+// whether its author was found.
+fn import_message(
+    conn: &Connection,
+    room_name: &str,
+    message: &ExportedMessage,
+    keep_id: bool,
+) -> Result<bool, rusqlite::Error> {
+    let seq_taken = conn
+        .query_row(
+            "SELECT 1 FROM chat_messages WHERE room_name = ?1 AND seq = ?2",
+            params![room_name, message.seq],
+            |_| Ok(()),
+        )
+        .optional()?
+        .is_some();
+    let user_id: Option<usize> = match &message.username {
+        Some(username) => conn
+            .query_row(
+                "SELECT user_id FROM users WHERE username = ?1",
+                [username],
+                |row| row.get(0),
+            )
+            .optional()?,
+        None => None,
+    };
+
+    conn.execute(
+        "INSERT INTO chat_messages
+            (message_id, seq, user_id, room_name, message, nickname, created_at, edited_at)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+        params![
+            keep_id.then_some(message.id),
+            message.seq.filter(|_| !seq_taken),
+            user_id.unwrap_or(DELETED_USER_ID),
+            room_name,
+            message.text,
+            message.nick,
+            message.created_at,
+            message.edited_at
+        ],
+    )?;
+
+    Ok(user_id.is_some())
+}
+
+// Where an export has got to: the time and ID of the last message exported.
+pub type Cursor = (String, i64);
+
+// Up to `limit` messages of `room_name` sent after `after`, or from the first
+// if unset, oldest first. Deleted messages are left out, as are the shadowed
+// messages of others than `viewer`.
+pub fn room_messages(
+    conn: &Connection,
+    room_name: &str,
+    viewer: usize,
+    after: Option<&Cursor>,
+    limit: usize,
+) -> Result<Vec<ExportedMessage>, rusqlite::Error> {
+    // Messages are ranged over by time, from where the last page ended, so that
+    // each page is as quick to read as the first
+    let (after_created, after_id) = match after {
+        Some((created_at, id)) => (created_at.as_str(), *id),
+        None => ("", 0),
+    };
+    let mut stmt = conn.prepare_cached(
+        "SELECT m.message_id, m.seq, m.user_id, u.username, m.nickname, m.message,
+                m.created_at, m.edited_at
+            FROM chat_messages m LEFT JOIN users u ON u.user_id = m.user_id
+            WHERE m.room_name = ?1 AND m.created_at >= ?2
+                AND (m.created_at > ?2 OR m.message_id > ?3)
+                AND m.deleted_at IS NULL AND (NOT m.shadowed OR m.user_id = ?4)
+            ORDER BY m.created_at, m.message_id
+            LIMIT ?5",
+    )?;
+    let messages = stmt
+        .query_map(
+            params![room_name, after_created, after_id, viewer, limit as i64],
+            |row| {
+                Ok(ExportedMessage {
+                    id: row.get(0)?,
+                    seq: row.get(1)?,
+                    user_id: row.get(2)?,
+                    username: row.get(3)?,
+                    nick: row.get(4)?,
+                    text: row.get::<_, MessageText>(5)?.into(),
+                    created_at: row.get(6)?,
+                    edited_at: row.get(7)?,
+                })
+            },
+        )?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(messages)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+
+    fn setup() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        db::init_schema(&conn).unwrap();
+        conn.execute_batch(
+            "INSERT INTO users (user_id, username) VALUES (1, 'alice');
+            INSERT INTO chat_messages (message_id, user_id, room_name, message, created_at)
+                VALUES (1, 1, 'room1', 'Morning', '2024-01-01 09:00:00'),
+                    (2, 2, 'room1', 'Same time', '2024-01-01 09:00:00'),
+                    (3, 1, 'room1', 'Evening', '2024-01-01 18:00:00'),
+                    (4, 1, 'room2', 'Elsewhere', '2024-01-01 12:00:00');
+            INSERT INTO chat_messages (message_id, user_id, room_name, message, deleted_at)
+                VALUES (5, 1, 'room1', '', '2024-01-02 00:00:00');",
+        )
+        .unwrap();
+
+        conn
+    }
+
+    #[test]
+    fn test_room_messages() {
+        let conn = setup();
+
+        let all = room_messages(&conn, "room1", 1, None, 10).unwrap();
+        let ids: Vec<_> = all.iter().map(|message| message.id).collect();
+        assert_eq!(ids, vec![1, 2, 3]);
+        assert_eq!(all[0].username.as_deref(), Some("alice"));
+        assert_eq!(all[1].username, None);
+
+        // Pages pick up after the last message, even those sent at its time
+        let first = room_messages(&conn, "room1", 1, None, 1).unwrap();
+        let cursor = (first[0].created_at.clone(), first[0].id);
+        let next = room_messages(&conn, "room1", 1, Some(&cursor), 10).unwrap();
+        assert_eq!(next, all[1..]);
+    }
+
+    #[test]
+    fn test_import() {
+        let conn = setup();
+        let exported = room_messages(&conn, "room1", 1, None, 10).unwrap();
+
+        let target = Connection::open_in_memory().unwrap();
+        db::init_schema(&target).unwrap();
+        target
+            .execute_batch(
+                "INSERT INTO users (user_id, username) VALUES (7, 'alice');
+                INSERT INTO chat_messages (message_id, user_id, room_name, message)
+                    VALUES (2, 7, 'lobby', 'Already here');",
+            )
+            .unwrap();
+
+        let imported = import(&target, "room1", &exported).unwrap();
+        assert_eq!(
+            imported,
+            Imported {
+                messages: 3,
+                renumbered: 1,
+                anonymized: 1,
+            }
+        );
+
+        // Authors are found by username, guests anonymized, and times are kept
+        let reimported = room_messages(&target, "room1", 1, None, 10).unwrap();
+        let ids: Vec<_> = reimported.iter().map(|message| message.id).collect();
+        assert_eq!(ids[0], 1);
+        assert!(ids[1] > 2);
+        assert_eq!(ids[2], 3);
+        assert_eq!(reimported[0].user_id, 7);
+        assert_eq!(reimported[1].user_id, DELETED_USER_ID);
+        assert_eq!(reimported[2].created_at, "2024-01-01 18:00:00");
+
+        // Authors without an account here are anonymized
+        let mut stranger = exported[0].clone();
+        stranger.username = Some(String::from("mallory"));
+        let imported = import(&target, "room2", &[stranger]).unwrap();
+        assert_eq!(imported.anonymized, 1);
+    }
+
+    #[test]
+    fn test_parse_csv() {
+        let message = ExportedMessage {
+            id: 1,
+            seq: None,
+            user_id: 1,
+            username: None,
+            nick: Some(String::from("al")),
+            text: String::from("Two\nlines, \"quoted\""),
+            created_at: String::from("2024-01-01 09:00:00"),
+            edited_at: None,
+        };
+        let csv = format!(
+            "{}{}",
+            ExportFormat::Csv.header(),
+            ExportFormat::Csv.format(&message, true)
+        );
+
+        let lines = parse_csv(&csv).unwrap();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(ExportedMessage::from_csv(&lines[1]).unwrap(), message);
+        assert!(parse_csv("1,\"open").is_err());
+    }
+
+    #[test]
+    fn test_format() {
+        let message = ExportedMessage {
+            id: 1,
+            seq: Some(1),
+            user_id: 1,
+            username: Some(String::from("alice")),
+            nick: None,
+            text: String::from("Hello, \"world\""),
+            created_at: String::from("2024-01-01 09:00:00"),
+            edited_at: None,
+        };
+
+        assert_eq!(
+            ExportFormat::Csv.format(&message, true),
+            "1,1,1,alice,,\"Hello, \"\"world\"\"\",2024-01-01 09:00:00,\r\n"
+        );
+        assert!(ExportFormat::Json
+            .format(&message, true)
+            .starts_with("{\"id\":1,"));
+        assert!(ExportFormat::Json.format(&message, false).starts_with(",{"));
+    }
+}
@@ -0,0 +1,408 @@
+use std::{collections::HashMap, str::FromStr, sync::Arc, time::Duration};
+
+use anyhow::anyhow;
+use futures::{SinkExt, StreamExt};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio_tungstenite::tungstenite::{
+    self,
+    http::{Request, Uri},
+};
+use warp::ws::{Message, WebSocket};
+
+use crate::{
+    auth::{self, oauth},
+    db,
+    server::ServerState,
+    shutdown::Shutdown,
+    user::{self, Rooms, User, UserRx},
+};
+
+// Provider the accounts of federated servers, and of their users, are
+// recorded under, as external identities.
+const FEDERATION_PROVIDER: &str = "federation";
+
+// How long to wait before linking a room again once its link is lost.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+// Characters of room names escaped in the URLs rooms are linked to: all but
+// those unreserved in URIs.
+const ROOM_SEGMENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+// A server allowed to link to the rooms of this one, given with
+// `--federation-peer <server>=<secret>`.
+#[derive(Clone, Debug)]
+pub struct FederationPeer {
+    pub name: String,
+    pub secret: String,
+}
+
+impl FromStr for FederationPeer {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, secret) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow!("Expected <server>=<secret>, got '{}'", s))?;
+        if name.is_empty() || secret.is_empty() {
+            return Err(anyhow!("Expected <server>=<secret>, got '{}'", s));
+        }
+
+        Ok(FederationPeer {
+            name: String::from(name),
+            secret: String::from(secret),
+        })
+    }
+}
+
+// A room of this server hosted by another, given with
+// `--federated-room <room>=<url>`, where `url` is the base WebSocket URL of
+// the hosting server, e.g. `wss://chat.example.org`.
+#[derive(Clone, Debug)]
+pub struct FederatedRoom {
+    pub room: String,
+    pub url: String,
+}
+
+impl FederatedRoom {
+    // Where the room is linked to on the hosting server, which decodes the
+    // room name with `decode_room`.
+    fn link_url(&self) -> String {
+        format!(
+            "{}/federation/rooms/{}",
+            self.url.trim_end_matches('/'),
+            utf8_percent_encode(&self.room, ROOM_SEGMENT)
+        )
+    }
+}
+
+// The name of the room linked to through `/federation/rooms/<segment>`.
+pub fn decode_room(segment: &str) -> Result<String, anyhow::Error> {
+    Ok(String::from(percent_decode_str(segment).decode_utf8()?))
+}
+
+impl FromStr for FederatedRoom {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (room, url) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow!("Expected <room>=<url>, got '{}'", s))?;
+        if !url.starts_with("ws://") && !url.starts_with("wss://") {
+            return Err(anyhow!("Expected a ws:// or wss:// URL, got '{}'", url));
+        }
+
+        let federated = FederatedRoom {
+            room: String::from(room),
+            url: String::from(url),
+        };
+        if let Err(e) = federated.link_url().parse::<Uri>() {
+            return Err(anyhow!("Invalid URL '{}': {}", url, e));
+        }
+
+        Ok(federated)
+    }
+}
+
+// A message sent by a user of a linked server to the room it is linked to.
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct RemoteMessage {
+    // ID of the user on the linked server
+    pub user_id: usize,
+    pub nick: Option<String>,
+    pub text: String,
+}
+
+// The peer `token` is the secret of, if any.
+pub fn authenticate<'a>(peers: &'a [FederationPeer], token: &str) -> Option<&'a FederationPeer> {
+    // Hashes are compared rather than secrets, so that comparisons do not leak
+    // how much of the secret was guessed right
+    let given = auth::hash_token(token);
+    peers
+        .iter()
+        .find(|peer| auth::hash_token(&peer.secret) == given)
+}
+
+// The local account of server `server`, created when it first links a room.
+// Access to rooms is granted to linked servers through it.
+pub fn server_user(conn: &Connection, server: &str) -> Result<usize, rusqlite::Error> {
+    oauth::identity_user(conn, FEDERATION_PROVIDER, server)
+}
+
+// The local account of user `user_id` of server `server`, created when they
+// first post to a linked room.
+pub fn remote_user(
+    conn: &Connection,
+    server: &str,
+    user_id: usize,
+) -> Result<usize, rusqlite::Error> {
+    oauth::identity_user(
+        conn,
+        FEDERATION_PROVIDER,
+        &format!("{}/{}", server, user_id),
+    )
+}
+
+// Relays what is sent to a room `link` joined to server `server`, which
+// linked it, and posts what the users of `server` send back, until either
+// side closes the link.
+pub async fn serve(link: User, server: String, ws: WebSocket, mut rx: UserRx, state: ServerState) {
+    let (mut ws_tx, mut ws_rx) = ws.split();
+    // Local accounts of the users of `server`, by their ID there
+    let mut accounts = HashMap::new();
+
+    loop {
+        tokio::select! {
+            msg = rx.recv() => match msg {
+                Some(msg) => {
+                    let is_close = msg.is_close();
+                    if let Err(e) = ws_tx.send(msg).await {
+                        eprintln!("Federation link send error ({}): {}", server, e);
+                        break;
+                    }
+                    if is_close {
+                        break;
+                    }
+                }
+                None => break,
+            },
+            frame = ws_rx.next() => match frame {
+                Some(Ok(frame)) => {
+                    if let Ok(text) = frame.to_str() {
+                        let posted = post(text, &server, &link.chat_room, &mut accounts, &state);
+                        if let Err(e) = posted.await {
+                            eprintln!("Failed to post message from {}: {}", server, e);
+                        }
+                    }
+                }
+                Some(Err(e)) => {
+                    eprintln!("Federation link error ({}): {}", server, e);
+                    break;
+                }
+                None => break,
+            },
+        }
+    }
+
+    user::user_disconnected(&link, &state.rooms).await;
+}
+
+// Posts `frame`, a message of a user of `server`, to `room_name` as their
+// local account, showing them as `<nick>@<server>`.
+async fn post(
+    frame: &str,
+    server: &str,
+    room_name: &str,
+    accounts: &mut HashMap<usize, usize>,
+    state: &ServerState,
+) -> Result<(), anyhow::Error> {
+    let remote: RemoteMessage = serde_json::from_str(frame)?;
+
+    let user_id = match accounts.get(&remote.user_id) {
+        Some(&user_id) => user_id,
+        None => {
+            let (name, remote_id) = (String::from(server), remote.user_id);
+            let user_id = db::query(&state.db_tx, move |conn| {
+                remote_user(conn, &name, remote_id)
+            })
+            .await?;
+            accounts.insert(remote.user_id, user_id);
+            user_id
+        }
+    };
+    if let Some(nick) = remote.nick {
+        let nick = format!("{}@{}", nick, server);
+        state.nicks.write().await.insert(user_id, nick);
+    }
+
+    let user = state.detached_user(room_name, user_id).await?;
+    user.send_message(&remote.text, None, None, None, None, &state.rooms)
+        .await?
+        .accepted()
+}
+
+// Posts the messages this server's users send to a room hosted by another
+// server to it.
+#[derive(Clone, Debug)]
+pub struct Link {
+    tx: UnboundedSender<RemoteMessage>,
+}
+
+impl Link {
+    // Links `federated` to its hosting server with `token` until shutdown,
+    // delivering what it relays to the connections in `rooms`. The link is
+    // made again whenever it is lost.
+    pub fn connect(
+        federated: FederatedRoom,
+        token: String,
+        rooms: Rooms,
+        shutdown: Shutdown,
+    ) -> Self {
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::task::spawn(follow(federated, token, rooms, rx, shutdown));
+
+        Link { tx }
+    }
+
+    pub fn send(&self, msg: RemoteMessage) {
+        // This will only fail once the link is gone, on shutdown
+        if let Err(_closed) = self.tx.send(msg) {}
+    }
+}
+
+// Links to the rooms of this server hosted by others, by room.
+#[derive(Clone, Debug, Default)]
+pub struct Federation {
+    links: Arc<HashMap<String, Link>>,
+}
+
+impl Federation {
+    pub fn new(links: HashMap<String, Link>) -> Self {
+        Federation {
+            links: Arc::new(links),
+        }
+    }
+
+    // The link of `room_name`, if hosted by another server.
+    pub fn link(&self, room_name: &str) -> Option<&Link> {
+        self.links.get(room_name)
+    }
+}
+
+async fn follow(
+    federated: FederatedRoom,
+    token: String,
+    rooms: Rooms,
+    mut rx: UnboundedReceiver<RemoteMessage>,
+    mut shutdown: Shutdown,
+) {
+    let url = federated.link_url();
+    while !shutdown.is_shutdown() {
+        let request = Request::builder()
+            .uri(&url)
+            .header("Authorization", format!("Bearer {}", token))
+            .body(());
+        // Retrying would not make the request any more valid
+        let request = match request {
+            Ok(request) => request,
+            Err(e) => {
+                return eprintln!("Invalid link of room {} to {}: {}", federated.room, url, e);
+            }
+        };
+        match tokio_tungstenite::connect_async(request).await {
+            Ok((ws, _)) => {
+                eprintln!("Linked room {} to {}", federated.room, federated.url);
+                relay(ws, &federated.room, &rooms, &mut rx, &mut shutdown).await;
+                eprintln!("Lost link of room {} to {}", federated.room, federated.url);
+            }
+            Err(e) => eprintln!("Failed to link room {} to {}: {}", federated.room, url, e),
+        }
+
+        tokio::select! {
+            _ = tokio::time::sleep(RECONNECT_DELAY) => {}
+            _ = shutdown.async_listen() => break,
+        }
+    }
+}
+
+// Relays the messages sent through `rx` to the hosting server, and what it
+// sends to the connections to `room_name`, until the link is lost or shutdown.
+async fn relay<S>(
+    mut ws: S,
+    room_name: &str,
+    rooms: &Rooms,
+    rx: &mut UnboundedReceiver<RemoteMessage>,
+    shutdown: &mut Shutdown,
+) where
+    S: futures::Stream<Item = Result<tungstenite::Message, tungstenite::Error>>
+        + futures::Sink<tungstenite::Message, Error = tungstenite::Error>
+        + Unpin,
+{
+    loop {
+        tokio::select! {
+            frame = ws.next() => match frame {
+                Some(Ok(tungstenite::Message::Text(text))) => {
+                    if let Some(room) = rooms.read().await.get(room_name) {
+                        room.lock().await.deliver(&Message::text(text), None);
+                    }
+                }
+                Some(Ok(tungstenite::Message::Close(_))) | Some(Err(_)) | None => return,
+                Some(Ok(_)) => {}
+            },
+            msg = rx.recv() => match msg {
+                Some(msg) => {
+                    // Serializing these types can not fail: all keys are strings
+                    let frame = serde_json::to_string(&msg).expect("Failed to serialize message");
+                    if let Err(e) = ws.send(tungstenite::Message::Text(frame)).await {
+                        eprintln!("Failed to relay message to {}: {}", room_name, e);
+                        return;
+                    }
+                }
+                None => return,
+            },
+            _ = shutdown.async_listen() => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::init_schema;
+
+    #[test]
+    fn test_parse() {
+        let peer: FederationPeer = "chat.example.org=s3cret".parse().unwrap();
+        assert_eq!(peer.name, "chat.example.org");
+        assert_eq!(peer.secret, "s3cret");
+        assert!("chat.example.org".parse::<FederationPeer>().is_err());
+        assert!("chat.example.org=".parse::<FederationPeer>().is_err());
+
+        let room: FederatedRoom = "lobby=ws://chat.example.org/".parse().unwrap();
+        assert_eq!(
+            room.link_url(),
+            "ws://chat.example.org/federation/rooms/lobby"
+        );
+        let room: FederatedRoom = "café #1=wss://chat.example.org".parse().unwrap();
+        assert_eq!(
+            room.link_url(),
+            "wss://chat.example.org/federation/rooms/caf%C3%A9%20%231"
+        );
+        assert_eq!(decode_room("caf%C3%A9%20%231").unwrap(), "café #1");
+        assert!("lobby=http://chat.example.org"
+            .parse::<FederatedRoom>()
+            .is_err());
+        assert!("lobby=ws://chat example.org"
+            .parse::<FederatedRoom>()
+            .is_err());
+    }
+
+    #[test]
+    fn test_accounts() {
+        let conn = Connection::open_in_memory().unwrap();
+        init_schema(&conn).unwrap();
+
+        let peers = vec![
+            "a.example.org=one".parse().unwrap(),
+            "b.example.org=two".parse().unwrap(),
+        ];
+        assert_eq!(authenticate(&peers, "two").unwrap().name, "b.example.org");
+        assert!(authenticate(&peers, "three").is_none());
+
+        let server = server_user(&conn, "a.example.org").unwrap();
+        assert_eq!(server_user(&conn, "a.example.org").unwrap(), server);
+
+        // Users of different servers get different accounts, even with the
+        // same ID
+        let alice = remote_user(&conn, "a.example.org", 1).unwrap();
+        assert_eq!(remote_user(&conn, "a.example.org", 1).unwrap(), alice);
+        assert_ne!(remote_user(&conn, "b.example.org", 1).unwrap(), alice);
+        assert_ne!(alice, server);
+    }
+}
@@ -0,0 +1,189 @@
+use std::{fmt, str::FromStr};
+
+use anyhow::anyhow;
+use regex::Regex;
+
+// What becomes of messages matching the word filter.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FilterAction {
+    // Messages are not filtered
+    Off,
+    // Messages are refused, with an error sent back to their author
+    Reject,
+    // Matches are replaced with asterisks
+    Mask,
+    // Messages are delivered unchanged, but flagged for moderators to review
+    Flag,
+}
+
+impl FromStr for FilterAction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(FilterAction::Off),
+            "reject" => Ok(FilterAction::Reject),
+            "mask" => Ok(FilterAction::Mask),
+            "flag" => Ok(FilterAction::Flag),
+            _ => Err(anyhow!(
+                "Unknown filter action '{}': expected off, reject, mask or flag",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for FilterAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let action = match self {
+            FilterAction::Off => "off",
+            FilterAction::Reject => "reject",
+            FilterAction::Mask => "mask",
+            FilterAction::Flag => "flag",
+        };
+        f.write_str(action)
+    }
+}
+
+// The filter action of a single room, given as `<room>=<action>`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RoomFilterAction {
+    pub room: String,
+    pub action: FilterAction,
+}
+
+impl FromStr for RoomFilterAction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (room, action) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow!("Expected <room>=<action>, got '{}'", s))?;
+
+        Ok(RoomFilterAction {
+            room: String::from(room),
+            action: action.parse()?,
+        })
+    }
+}
+
+// Words and patterns messages are checked against.
+#[derive(Debug, Default)]
+pub struct WordFilter {
+    patterns: Vec<Regex>,
+}
+
+// A message once through the word filter.
+#[derive(Debug, PartialEq)]
+pub struct Filtered {
+    pub text: String,
+    // Set if the message matched, and the filter flags messages
+    pub flagged: bool,
+}
+
+impl WordFilter {
+    // Filters whole `words`, regardless of case, and anything matching
+    // `patterns`.
+    pub fn new(words: &[String], patterns: &[Regex]) -> Self {
+        let words = words
+            .iter()
+            .filter(|word| !word.is_empty())
+            .map(|word| regex::escape(word))
+            .collect::<Vec<_>>();
+
+        let mut filter = WordFilter {
+            patterns: patterns.to_vec(),
+        };
+        if !words.is_empty() {
+            let words = format!(r"(?i)\b(?:{})\b", words.join("|"));
+            filter
+                .patterns
+                .push(Regex::new(&words).expect("Escaped words form a valid regex"));
+        }
+
+        filter
+    }
+
+    // Puts `text` through the filter, as `action` has it. Errs if the message
+    // is to be rejected.
+    pub fn apply(&self, action: FilterAction, text: &str) -> Result<Filtered, anyhow::Error> {
+        let matched = action != FilterAction::Off
+            && self.patterns.iter().any(|pattern| pattern.is_match(text));
+        if !matched {
+            return Ok(Filtered {
+                text: String::from(text),
+                flagged: false,
+            });
+        }
+
+        match action {
+            FilterAction::Reject => Err(anyhow!("Message contains filtered words")),
+            FilterAction::Mask => {
+                let masked = self
+                    .patterns
+                    .iter()
+                    .fold(String::from(text), |text, pattern| {
+                        pattern
+                            .replace_all(&text, |caps: &regex::Captures| {
+                                "*".repeat(caps[0].chars().count())
+                            })
+                            .into_owned()
+                    });
+                Ok(Filtered {
+                    text: masked,
+                    flagged: false,
+                })
+            }
+            FilterAction::Flag | FilterAction::Off => Ok(Filtered {
+                text: String::from(text),
+                flagged: action == FilterAction::Flag,
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_actions() {
+        assert_eq!("mask".parse::<FilterAction>().unwrap(), FilterAction::Mask);
+        assert!("censor".parse::<FilterAction>().is_err());
+
+        let room_action: RoomFilterAction = "kids=reject".parse().unwrap();
+        assert_eq!(room_action.room, "kids");
+        assert_eq!(room_action.action, FilterAction::Reject);
+        assert!("kids".parse::<RoomFilterAction>().is_err());
+    }
+
+    #[test]
+    fn test_word_filter() {
+        let filter = WordFilter::new(
+            &[String::from("darn"), String::from("h.ck")],
+            &[Regex::new(r"\d{4}-\d{4}").unwrap()],
+        );
+
+        // Only whole words match, in any case, and words are not patterns
+        let clean = filter
+            .apply(FilterAction::Reject, "darnation, heck")
+            .unwrap();
+        assert_eq!(clean.text, "darnation, heck");
+        assert!(filter.apply(FilterAction::Reject, "Darn it").is_err());
+
+        let masked = filter
+            .apply(FilterAction::Mask, "DARN, call 5555-1234 h.ck")
+            .unwrap();
+        assert_eq!(masked.text, "****, call ********* ****");
+        assert!(!masked.flagged);
+
+        let flagged = filter.apply(FilterAction::Flag, "darn").unwrap();
+        assert_eq!(flagged.text, "darn");
+        assert!(flagged.flagged);
+        assert!(!filter.apply(FilterAction::Flag, "fine").unwrap().flagged);
+
+        let off = filter.apply(FilterAction::Off, "darn").unwrap();
+        assert_eq!(off.text, "darn");
+        assert!(!off.flagged);
+    }
+}
@@ -0,0 +1,497 @@
+use std::net::IpAddr;
+
+use async_graphql::{
+    http::{WebSocket, WebSocketProtocols, WsMessage},
+    ComplexObject, Context, Data, EmptyMutation, Error, Object, Result, Schema, SimpleObject,
+    Subscription,
+};
+use futures::{future, stream, SinkExt, Stream, StreamExt};
+use serde::Deserialize;
+use tokio::sync::mpsc;
+use warp::ws::Message;
+
+use crate::{
+    auth::{self, scope::Scope, Principal},
+    authz,
+    cluster::RoomRead,
+    db,
+    history::{self, HistoryFilter, MessagePage},
+    profile::{self, Profile},
+    protocol::Availability,
+    room::{self, RoomInfo, Visibility},
+    server::{Follower, ServerState},
+    user::{self, Admission},
+};
+
+// Deepest a query may nest, e.g. the authors of the messages of a room.
+const MAX_DEPTH: usize = 10;
+
+pub type ChatSchema = Schema<Query, EmptyMutation, Subscription>;
+
+// The graph of rooms, their messages and users, read with queries and
+// followed live with subscriptions. The server state is part of its data.
+pub fn schema(state: ServerState) -> ChatSchema {
+    Schema::build(Query, EmptyMutation, Subscription)
+        .data(state)
+        .limit_depth(MAX_DEPTH)
+        .finish()
+}
+
+// Who a request is made by, if anyone logged in, given as request data.
+#[derive(Clone, Debug)]
+pub struct Viewer {
+    pub principal: Principal,
+    pub addr: Option<IpAddr>,
+}
+
+// The viewer of a request, provided they may do `scope`.
+fn viewer<'a>(ctx: &Context<'a>, scope: &Scope) -> Result<&'a Viewer> {
+    let viewer = ctx
+        .data_opt::<Viewer>()
+        .ok_or_else(|| Error::new("Log in to see this"))?;
+    if !viewer.principal.allows(scope) {
+        return Err(Error::new(format!("Token lacks the '{}' scope", scope)));
+    }
+
+    Ok(viewer)
+}
+
+fn internal_error(e: anyhow::Error) -> Error {
+    eprintln!("Failed to resolve GraphQL query: {}", e);
+    Error::new("Internal server error")
+}
+
+pub struct Query;
+
+#[Object]
+impl Query {
+    // Public rooms, by name
+    async fn rooms(&self, ctx: &Context<'_>) -> Result<Vec<RoomNode>> {
+        let state = ctx.data::<ServerState>()?;
+        let rooms = db::read(&state.db_tx, |conn| {
+            room::public_rooms(conn)?
+                .into_iter()
+                .filter_map(|listing| room::room_info(conn, &listing.name).transpose())
+                .collect::<Result<Vec<_>, _>>()
+        })
+        .await
+        .map_err(internal_error)?;
+
+        Ok(rooms.into_iter().map(RoomNode).collect())
+    }
+
+    // As a user who may join it
+    async fn room(&self, ctx: &Context<'_>, name: String) -> Result<Option<RoomNode>> {
+        may_join(ctx, &name).await?;
+        let state = ctx.data::<ServerState>()?;
+        let room = db::read(&state.db_tx, move |conn| room::room_info(conn, &name))
+            .await
+            .map_err(internal_error)?;
+
+        Ok(room.map(RoomNode))
+    }
+
+    async fn user(&self, ctx: &Context<'_>, id: usize) -> Result<Option<UserNode>> {
+        user_node(ctx, id).await
+    }
+
+    // The logged in user
+    async fn me(&self, ctx: &Context<'_>) -> Result<Option<UserNode>> {
+        let user_id = viewer(ctx, &Scope::Read(None))?.principal.user_id;
+        user_node(ctx, user_id).await
+    }
+}
+
+async fn user_node(ctx: &Context<'_>, user_id: usize) -> Result<Option<UserNode>> {
+    let state = ctx.data::<ServerState>()?;
+    let profile = db::read(&state.db_tx, move |conn| profile::profile(conn, user_id))
+        .await
+        .map_err(internal_error)?;
+
+    Ok(profile.map(UserNode::from))
+}
+
+// A user, as their profile tells of them.
+#[derive(SimpleObject)]
+#[graphql(name = "User")]
+pub struct UserNode {
+    pub id: usize,
+    pub nick: Option<String>,
+    pub avatar_url: Option<String>,
+    pub bio: Option<String>,
+}
+
+impl From<Profile> for UserNode {
+    fn from(profile: Profile) -> Self {
+        UserNode {
+            id: profile.user_id,
+            nick: profile.nick,
+            avatar_url: profile.avatar_url,
+            bio: profile.bio,
+        }
+    }
+}
+
+#[derive(SimpleObject)]
+#[graphql(name = "Message", complex)]
+pub struct MessageNode {
+    pub id: i64,
+    pub seq: Option<i64>,
+    pub room: String,
+    pub user_id: usize,
+    // Nickname of the author when the message was sent, if they had one
+    pub nick: Option<String>,
+    pub text: String,
+    // Unknown for messages followed live, which are not persisted yet
+    pub created_at: Option<String>,
+    pub edited_at: Option<String>,
+}
+
+#[ComplexObject]
+impl MessageNode {
+    async fn author(&self, ctx: &Context<'_>) -> Result<Option<UserNode>> {
+        user_node(ctx, self.user_id).await
+    }
+}
+
+#[derive(SimpleObject)]
+#[graphql(name = "MessagePage")]
+pub struct MessagePageNode {
+    pub messages: Vec<MessageNode>,
+    // Offset of the next page, if there are more messages
+    pub next_offset: Option<usize>,
+}
+
+#[derive(SimpleObject)]
+#[graphql(name = "OnlineUser")]
+pub struct OnlineUserNode {
+    pub user_id: usize,
+    pub nick: Option<String>,
+    pub away: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, async_graphql::Enum)]
+#[graphql(name = "Visibility")]
+pub enum VisibilityNode {
+    Public,
+    Private,
+}
+
+pub struct RoomNode(RoomInfo);
+
+#[Object(name = "Room")]
+impl RoomNode {
+    async fn name(&self) -> &str {
+        &self.0.name
+    }
+
+    async fn topic(&self) -> Option<&str> {
+        self.0.settings.topic.as_deref()
+    }
+
+    async fn description(&self) -> Option<&str> {
+        self.0.settings.description.as_deref()
+    }
+
+    async fn visibility(&self) -> VisibilityNode {
+        match self.0.settings.visibility {
+            Visibility::Public => VisibilityNode::Public,
+            Visibility::Private => VisibilityNode::Private,
+        }
+    }
+
+    async fn created_at(&self) -> &str {
+        &self.0.created_at
+    }
+
+    // Unknown for rooms created before their creators were recorded
+    async fn created_by(&self, ctx: &Context<'_>) -> Result<Option<UserNode>> {
+        match self.0.created_by {
+            Some(user_id) => user_node(ctx, user_id).await,
+            None => Ok(None),
+        }
+    }
+
+    // Users holding the room, oldest owner first
+    async fn owners(&self, ctx: &Context<'_>) -> Result<Vec<UserNode>> {
+        let mut owners = Vec::new();
+        for &user_id in &self.0.owners {
+            owners.extend(user_node(ctx, user_id).await?);
+        }
+
+        Ok(owners)
+    }
+
+    async fn password_protected(&self) -> bool {
+        self.0.settings.password_protected
+    }
+
+    async fn read_only(&self) -> bool {
+        self.0.settings.read_only
+    }
+
+    // Number of connections to the room
+    async fn occupancy(&self, ctx: &Context<'_>) -> Result<usize> {
+        let state = ctx.data::<ServerState>()?;
+        Ok(user::occupancy(&state.rooms)
+            .await
+            .get(&self.0.name)
+            .copied()
+            .unwrap_or(0))
+    }
+
+    // Users connected to the room, as a user who may join it
+    async fn online(&self, ctx: &Context<'_>) -> Result<Vec<OnlineUserNode>> {
+        self.may_join(ctx).await?;
+        let state = ctx.data::<ServerState>()?;
+        let online = user::online_users(&self.0.name, &state.rooms, &state.nicks).await;
+
+        Ok(online
+            .into_iter()
+            .map(|online| OnlineUserNode {
+                user_id: online.user_id,
+                nick: online.nick,
+                away: online.status == Availability::Away,
+            })
+            .collect())
+    }
+
+    // Messages of the room, oldest first, as a user who may join it, as
+    // `GET /rooms/:name/messages` lists them
+    async fn messages(
+        &self,
+        ctx: &Context<'_>,
+        since: Option<String>,
+        until: Option<String>,
+        user_id: Option<usize>,
+        limit: Option<usize>,
+        #[graphql(default)] offset: usize,
+    ) -> Result<MessagePageNode> {
+        let viewer = self.may_join(ctx).await?;
+        let state = ctx.data::<ServerState>()?;
+        let filter = HistoryFilter::new(since.as_deref(), until.as_deref(), user_id)
+            .map_err(|e| Error::new(e.to_string()))?;
+        let limit = limit
+            .unwrap_or(history::DEFAULT_LIMIT)
+            .clamp(1, history::MAX_LIMIT);
+
+        let read = RoomRead::Messages {
+            viewer,
+            filter,
+            limit,
+            offset,
+        };
+        let page: MessagePage = state
+            .read_room(&self.0.name, read)
+            .await
+            .map_err(internal_error)?;
+
+        Ok(MessagePageNode {
+            messages: page
+                .messages
+                .into_iter()
+                .map(|message| MessageNode {
+                    id: message.id,
+                    seq: message.seq,
+                    room: self.0.name.clone(),
+                    user_id: message.user_id,
+                    nick: message.nick,
+                    text: message.text,
+                    created_at: Some(message.created_at),
+                    edited_at: message.edited_at,
+                })
+                .collect(),
+            next_offset: page.next_offset,
+        })
+    }
+}
+
+impl RoomNode {
+    // The user ID of the viewer, provided they may read the room.
+    async fn may_join(&self, ctx: &Context<'_>) -> Result<usize> {
+        may_join(ctx, &self.0.name).await
+    }
+}
+
+// The user ID of the viewer, provided they may read room `room_name`.
+async fn may_join(ctx: &Context<'_>, room_name: &str) -> Result<usize> {
+    let user_id = viewer(ctx, &Scope::Read(Some(String::from(room_name))))?
+        .principal
+        .user_id;
+    let state = ctx.data::<ServerState>()?;
+    let room_name = String::from(room_name);
+    let may_join = db::read(&state.db_tx, move |conn| {
+        authz::may_join(conn, user_id, &room_name)
+    })
+    .await
+    .map_err(internal_error)?;
+
+    match may_join {
+        true => Ok(user_id),
+        false => Err(Error::new("Not allowed in this room")),
+    }
+}
+
+// A message event, as sent to the users in a room.
+#[derive(Deserialize)]
+struct MessageEvent {
+    id: i64,
+    seq: i64,
+    room: String,
+    user_id: usize,
+    #[serde(default)]
+    nick: Option<String>,
+    #[serde(default)]
+    text: String,
+    #[serde(default)]
+    deleted_at: Option<String>,
+}
+
+pub struct Subscription;
+
+#[Subscription]
+impl Subscription {
+    // Messages sent to `room` from now on. Subscribers join the room as over
+    // `/chat`, and leave it once they unsubscribe.
+    async fn messages(
+        &self,
+        ctx: &Context<'_>,
+        room: String,
+        password: Option<String>,
+    ) -> Result<impl Stream<Item = MessageNode>> {
+        let viewer = viewer(ctx, &Scope::Read(Some(room.clone())))?;
+        let state = ctx.data::<ServerState>()?.clone();
+
+        let user_id = viewer.principal.user_id;
+        let nick = db::query(&state.db_tx, move |conn| db::nickname(conn, user_id))
+            .await
+            .map_err(internal_error)?;
+        if let Some(nick) = nick {
+            state.nicks.write().await.insert(user_id, nick);
+        }
+
+        let (user_tx, user_rx) = mpsc::unbounded_channel();
+        let user = state.gateway_user(&room, viewer.principal.clone(), false, viewer.addr, user_tx);
+        let joined = user::add_user_to_room(
+            &user,
+            &state.rooms,
+            state.config.explicit_rooms,
+            state.config.room_capacity,
+            password,
+            None,
+        )
+        .await;
+        let refusal = match joined {
+            Ok(Ok(Admission::Joined)) => None,
+            // Subscriptions have no way to wait for approval
+            Ok(Ok(Admission::Pending(_))) => Some("Joining this room requires approval"),
+            Ok(Err(refusal)) => Some(refusal.reason()),
+            Err(e) => {
+                user::remove_user_from_room(&user, &state.rooms).await;
+                return Err(internal_error(e));
+            }
+        };
+        if let Some(reason) = refusal {
+            user::remove_user_from_room(&user, &state.rooms).await;
+            return Err(Error::new(reason));
+        }
+        if let Err(e) = user.announce_join(&state.rooms).await {
+            eprintln!("Failed to announce joining {}: {}", room, e);
+        }
+
+        let reader = Follower::new(user, false, state);
+        Ok(stream::unfold(
+            (user_rx, reader),
+            |(mut user_rx, reader)| async move {
+                loop {
+                    let msg = user_rx.recv().await?;
+                    // Kicked, banned, or the server shutting down
+                    if msg.is_close() {
+                        return None;
+                    }
+                    let event = msg.to_str().ok().and_then(|text| {
+                        let value: serde_json::Value = serde_json::from_str(text).ok()?;
+                        (value["type"] == "message")
+                            .then(|| serde_json::from_value::<MessageEvent>(value).ok())
+                            .flatten()
+                    });
+                    match event {
+                        Some(event) if event.deleted_at.is_none() => {
+                            let message = MessageNode {
+                                id: event.id,
+                                seq: Some(event.seq),
+                                room: event.room,
+                                user_id: event.user_id,
+                                nick: event.nick,
+                                text: event.text,
+                                created_at: None,
+                                edited_at: None,
+                            };
+                            return Some((message, (user_rx, reader)));
+                        }
+                        _ => continue,
+                    }
+                }
+            },
+        ))
+    }
+}
+
+// Who the `token` given in the payload of the `connection_init` message of a
+// subscription connection was issued to, if any, as connection data.
+async fn connection_init(
+    payload: serde_json::Value,
+    addr: Option<IpAddr>,
+    state: ServerState,
+) -> Result<Data> {
+    let mut data = Data::default();
+    if let Some(token) = payload["token"].as_str() {
+        let token = Some(String::from(token));
+        match auth::request_user(&state.db_tx, &state.jwt, token, None).await {
+            Ok(Some(principal)) => data.insert(Viewer { principal, addr }),
+            Ok(None) => return Err(Error::new("Invalid token")),
+            Err(e) => return Err(internal_error(e)),
+        }
+    }
+
+    Ok(data)
+}
+
+// Serves the subscriptions made over `socket` with `protocol`, as `viewer`
+// unless the connection is initialized with a token, until it closes.
+pub async fn serve(
+    socket: warp::ws::WebSocket,
+    protocol: WebSocketProtocols,
+    viewer: Option<Viewer>,
+    addr: Option<IpAddr>,
+    schema: ChatSchema,
+    state: ServerState,
+) {
+    let (mut socket_tx, socket_rx) = socket.split();
+    let socket_rx = socket_rx
+        .take_while(|msg| future::ready(matches!(msg, Ok(msg) if !msg.is_close())))
+        .filter_map(|msg| {
+            future::ready(
+                msg.ok()
+                    .filter(|msg| msg.is_text() || msg.is_binary())
+                    .map(Message::into_bytes),
+            )
+        });
+
+    let mut data = Data::default();
+    if let Some(viewer) = viewer {
+        data.insert(viewer);
+    }
+    let mut messages = WebSocket::new(schema, socket_rx, protocol)
+        .connection_data(data)
+        .on_connection_init(move |payload| connection_init(payload, addr, state));
+    while let Some(msg) = messages.next().await {
+        let msg = match msg {
+            WsMessage::Text(text) => Message::text(text),
+            WsMessage::Close(code, reason) => Message::close_with(code, reason),
+        };
+        if socket_tx.send(msg).await.is_err() {
+            break;
+        }
+    }
+}
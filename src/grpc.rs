@@ -0,0 +1,304 @@
+// tonic hands out and expects `Status` errors, however large they are
+#![allow(clippy::result_large_err)]
+
+use std::{net::IpAddr, pin::Pin};
+
+use futures::{channel::mpsc as frames, future, Stream, StreamExt};
+use tokio::sync::mpsc;
+use tonic::{metadata::MetadataMap, transport::Server, Request, Response, Status, Streaming};
+use warp::ws::Message;
+
+use crate::{
+    auth::{self, scope::Scope},
+    db,
+    guest::{self, GuestMode},
+    ip_ban,
+    proto::{
+        chat_server::{Chat, ChatServer},
+        server_frame::Frame,
+        ClientFrame, Close, ServerFrame,
+    },
+    server::ServerState,
+    shutdown::Shutdown,
+    user::{self, Admission, Refusal, User, UserRx},
+    wire,
+};
+
+// Serves the `Chat` gRPC service on `port`, bound to `127.0.0.1` like HTTP,
+// until shutdown.
+pub async fn listen(port: u16, state: ServerState, mut shutdown: Shutdown) {
+    let served = Server::builder()
+        .add_service(ChatServer::new(ChatService { state }))
+        .serve_with_shutdown(([127, 0, 0, 1], port).into(), async move {
+            shutdown.async_listen().await
+        })
+        .await;
+    if let Err(e) = served {
+        eprintln!("Failed to serve gRPC: {}", e);
+    }
+}
+
+struct ChatService {
+    state: ServerState,
+}
+
+// How a `Chat` call joins its room, as given in its metadata.
+struct ChatMetadata {
+    room: Option<String>,
+    token: Option<String>,
+    nick: Option<String>,
+    password: Option<String>,
+    since: Option<i64>,
+}
+
+impl ChatMetadata {
+    fn parse(metadata: &MetadataMap) -> Result<Self, Status> {
+        let get = |key: &str| -> Result<Option<String>, Status> {
+            metadata
+                .get(key)
+                .map(|value| {
+                    value
+                        .to_str()
+                        .map(String::from)
+                        .map_err(|_| Status::invalid_argument(format!("Invalid {}", key)))
+                })
+                .transpose()
+        };
+        let token = get("authorization")?.map(|authorization| {
+            let token = authorization
+                .strip_prefix("Bearer ")
+                .unwrap_or(&authorization);
+            String::from(token.trim())
+        });
+        let since = get("since")?
+            .map(|since| since.parse())
+            .transpose()
+            .map_err(|_| Status::invalid_argument("Invalid since"))?;
+
+        Ok(ChatMetadata {
+            room: get("room")?,
+            token,
+            nick: get("nick")?,
+            password: get("password")?,
+            since,
+        })
+    }
+}
+
+// The frame carrying `msg` to the client, if it is sent at all: text frames
+// not holding an event are not.
+fn server_frame(msg: Message) -> Option<ServerFrame> {
+    let frame = match msg.close_frame() {
+        Some((code, reason)) => Frame::Close(Close {
+            code: u32::from(code),
+            reason: String::from(reason),
+        }),
+        None => Frame::Event(wire::server_event(&msg)?),
+    };
+
+    Some(ServerFrame { frame: Some(frame) })
+}
+
+#[tonic::async_trait]
+impl Chat for ChatService {
+    type ChatStream = Pin<Box<dyn Stream<Item = Result<ServerFrame, Status>> + Send>>;
+
+    // Joins the room, as a connection to `/chat` does, then serves the call as
+    // one.
+    async fn chat(
+        &self,
+        request: Request<Streaming<ClientFrame>>,
+    ) -> Result<Response<Self::ChatStream>, Status> {
+        let addr = request.remote_addr().map(|addr| addr.ip());
+        let metadata = ChatMetadata::parse(request.metadata())?;
+        let (user, user_rx) = join(metadata, addr, &self.state).await?;
+
+        // Frames giving nothing are refused as binary frames failing to decode
+        // over `/chat` are
+        let client_frames = request.into_inner().map(|frame| {
+            frame.map(|frame| {
+                wire::client_message(frame).unwrap_or_else(|| Message::binary(Vec::new()))
+            })
+        });
+        let (frames_tx, frames_rx) = frames::unbounded();
+        let rooms = self.state.rooms.clone();
+        tokio::task::spawn(async move {
+            user.listen_over(frames_tx, client_frames, user_rx, rooms)
+                .await
+        });
+
+        let server_frames = frames_rx.filter_map(|msg| future::ready(server_frame(msg).map(Ok)));
+        Ok(Response::new(Box::pin(server_frames)))
+    }
+}
+
+fn internal_error(e: anyhow::Error) -> Status {
+    eprintln!("Failed to serve gRPC call: {}", e);
+    Status::internal("Internal server error")
+}
+
+// Identifies the user of a `Chat` call and adds them to its room, refusing the
+// call as `/chat` refuses connections.
+async fn join(
+    metadata: ChatMetadata,
+    addr: Option<IpAddr>,
+    state: &ServerState,
+) -> Result<(User, UserRx), Status> {
+    // Banned addresses are refused before anything is done on their behalf
+    if let Some(addr) = addr {
+        let banned = db::query(&state.db_tx, move |conn| ip_ban::is_banned(conn, addr))
+            .await
+            .map_err(internal_error)?;
+        if banned {
+            return Err(Status::permission_denied("Your address is banned"));
+        }
+    }
+
+    let nick = metadata
+        .nick
+        .as_deref()
+        .map(user::validate_nickname)
+        .transpose()
+        .map_err(|e| Status::invalid_argument(e.to_string()))?;
+
+    let room = metadata
+        .room
+        .unwrap_or_else(|| state.config.default_room.clone());
+    let is_guest = metadata.token.is_none();
+    if is_guest && state.config.guest_mode(&room) == GuestMode::Disabled {
+        return Err(Status::permission_denied("Guests may not join this room"));
+    }
+    let principal = auth::connection_user(&state.db_tx, &state.jwt, metadata.token, None, addr)
+        .await
+        .map_err(internal_error)?
+        .ok_or_else(|| Status::unauthenticated("Invalid token"))?;
+    let read_scope = Scope::Read(Some(room.clone()));
+    if !principal.allows(&read_scope) {
+        return Err(Status::permission_denied(format!(
+            "Token lacks the '{}' scope",
+            read_scope
+        )));
+    }
+
+    // Nicknames are reserved as over `/chat`, and guests given a temporary one
+    let user_id = principal.user_id;
+    let nick = match nick {
+        Some(nick) => {
+            let new_nick = nick.clone();
+            let reserved = db::query(&state.db_tx, move |conn| {
+                db::reserve_nickname(conn, user_id, &new_nick)
+            })
+            .await
+            .map_err(internal_error)?;
+            if !reserved {
+                return Err(Status::already_exists("Nickname is already taken"));
+            }
+            Some(nick)
+        }
+        None if is_guest => Some(
+            db::query(&state.db_tx, move |conn| {
+                Ok(guest::reserve_guest_nickname(conn, user_id))
+            })
+            .await
+            .and_then(|nick| nick)
+            .map_err(internal_error)?,
+        ),
+        None => db::query(&state.db_tx, move |conn| db::nickname(conn, user_id))
+            .await
+            .map_err(internal_error)?,
+    };
+    if let Some(nick) = nick {
+        state.nicks.write().await.insert(user_id, nick);
+    }
+
+    let (user_tx, user_rx) = mpsc::unbounded_channel();
+    let mut user = state.gateway_user(&room, principal, is_guest, addr, user_tx);
+    user.away_after = state.config.away_after();
+    user.offline_after = state.config.offline_after();
+    let history_seq = match user
+        .send_history(state.config.history_limit, metadata.since)
+        .await
+    {
+        Ok(history_seq) => Some(history_seq),
+        Err(e) => {
+            eprintln!("Failed to send room history: {}", e);
+            None
+        }
+    };
+
+    let joined = user::add_user_to_room(
+        &user,
+        &state.rooms,
+        state.config.explicit_rooms,
+        state.config.room_capacity,
+        metadata.password,
+        history_seq,
+    )
+    .await;
+    let refusal = match joined {
+        Ok(Ok(Admission::Joined)) => None,
+        // Calls have no way to wait for approval
+        Ok(Ok(Admission::Pending(_))) => Some(Status::failed_precondition(
+            "Joining this room requires approval",
+        )),
+        Ok(Err(refusal)) => Some(match refusal {
+            Refusal::WrongPassword => Status::unauthenticated(refusal.reason()),
+            Refusal::AccessDenied | Refusal::JoinRejected => {
+                Status::permission_denied(refusal.reason())
+            }
+            Refusal::RoomNotFound => Status::not_found(refusal.reason()),
+            Refusal::RoomFull { .. } => Status::resource_exhausted(refusal.reason()),
+        }),
+        Err(e) => Some(internal_error(e)),
+    };
+    if let Some(status) = refusal {
+        user::user_disconnected(&user, &state.rooms).await;
+        return Err(status);
+    }
+
+    if let Err(e) = user.send_join_requests(&state.rooms).await {
+        eprintln!("Failed to send join requests: {}", e);
+    }
+    if let Err(e) = user.announce_join(&state.rooms).await {
+        eprintln!("Failed to announce joining {}: {}", room, e);
+    }
+    if let Err(e) = user.send_queued().await {
+        eprintln!("Failed to send queued events: {}", e);
+    }
+
+    Ok((user, user_rx))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::{
+        server_event::{Ack, Event},
+        ServerEvent,
+    };
+
+    #[test]
+    fn test_server_frame() {
+        let frame = server_frame(Message::text(r#"{"type":"ack","id":1,"room":"lobby"}"#)).unwrap();
+        assert_eq!(
+            frame.frame,
+            Some(Frame::Event(ServerEvent {
+                event: Some(Event::Ack(Ack {
+                    id: 1,
+                    room: String::from("lobby"),
+                    client_id: None,
+                })),
+            }))
+        );
+
+        let frame = server_frame(Message::close_with(4003u16, "Kicked")).unwrap();
+        assert_eq!(
+            frame.frame,
+            Some(Frame::Close(Close {
+                code: 4003,
+                reason: String::from("Kicked"),
+            }))
+        );
+        assert!(server_frame(Message::ping(Vec::new())).is_none());
+    }
+}
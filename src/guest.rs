@@ -0,0 +1,198 @@
+use std::{
+    fmt,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use anyhow::anyhow;
+use rand::Rng;
+use rusqlite::Connection;
+use tokio::sync::Mutex;
+
+use crate::db;
+
+// Prefix of the nicknames given to guests, which no one else may use.
+pub const GUEST_PREFIX: &str = "guest-";
+
+// Window over which the messages of rate-limited guests are counted.
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+// What unauthenticated users may do in a room.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GuestMode {
+    // Guests may do anything authenticated users may
+    Full,
+    // Guests may only read messages
+    ReadOnly,
+    // Guests may only send so many messages per minute
+    RateLimited,
+    // Guests may not join
+    Disabled,
+}
+
+impl FromStr for GuestMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "full" => Ok(GuestMode::Full),
+            "read-only" => Ok(GuestMode::ReadOnly),
+            "rate-limited" => Ok(GuestMode::RateLimited),
+            "disabled" => Ok(GuestMode::Disabled),
+            _ => Err(anyhow!(
+                "Unknown guest mode '{}': expected full, read-only, rate-limited or disabled",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for GuestMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mode = match self {
+            GuestMode::Full => "full",
+            GuestMode::ReadOnly => "read-only",
+            GuestMode::RateLimited => "rate-limited",
+            GuestMode::Disabled => "disabled",
+        };
+        f.write_str(mode)
+    }
+}
+
+// The guest mode of a single room, given as `<room>=<mode>`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RoomGuestMode {
+    pub room: String,
+    pub mode: GuestMode,
+}
+
+impl FromStr for RoomGuestMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (room, mode) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow!("Expected <room>=<mode>, got '{}'", s))?;
+
+        Ok(RoomGuestMode {
+            room: String::from(room),
+            mode: mode.parse()?,
+        })
+    }
+}
+
+// Restrictions on what a guest connection may do in its room.
+#[derive(Debug)]
+pub struct Guest {
+    pub mode: GuestMode,
+
+    // Messages per `RATE_WINDOW` allowed when rate-limited
+    rate_limit: u32,
+
+    // Start of the current window, and messages sent within it
+    window: Mutex<(Instant, u32)>,
+}
+
+impl Guest {
+    pub fn new(mode: GuestMode, rate_limit: u32) -> Self {
+        Guest {
+            mode,
+            rate_limit,
+            window: Mutex::new((Instant::now(), 0)),
+        }
+    }
+
+    // Checks whether the guest may post (or edit) a message, counting it
+    // against its rate limit.
+    pub async fn check_post(&self) -> Result<(), anyhow::Error> {
+        match self.mode {
+            GuestMode::Full => Ok(()),
+            GuestMode::ReadOnly | GuestMode::Disabled => {
+                Err(anyhow!("Guests can not post in this room"))
+            }
+            GuestMode::RateLimited => {
+                let mut window = self.window.lock().await;
+                if window.0.elapsed() >= RATE_WINDOW {
+                    *window = (Instant::now(), 0);
+                }
+
+                if window.1 >= self.rate_limit {
+                    return Err(anyhow!(
+                        "Guests may only send {} messages per minute in this room",
+                        self.rate_limit
+                    ));
+                }
+
+                window.1 += 1;
+                Ok(())
+            }
+        }
+    }
+}
+
+// Whether `name` is reserved for guests.
+pub fn is_guest_name(name: &str) -> bool {
+    name.to_ascii_lowercase().starts_with(GUEST_PREFIX)
+}
+
+// Reserves a random `guest-xxxx` nickname for `user_id`, returning it.
+pub fn reserve_guest_nickname(conn: &Connection, user_id: usize) -> Result<String, anyhow::Error> {
+    let mut rng = rand::thread_rng();
+
+    for _ in 0..16 {
+        let nickname = format!("{}{:04x}", GUEST_PREFIX, rng.gen::<u16>());
+        if db::reserve_nickname(conn, user_id, &nickname)? {
+            return Ok(nickname);
+        }
+    }
+
+    Err(anyhow!("Unable to find a free guest nickname"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth;
+
+    #[test]
+    fn test_parse_modes() {
+        assert_eq!(
+            "read-only".parse::<GuestMode>().unwrap(),
+            GuestMode::ReadOnly
+        );
+        assert!("readonly".parse::<GuestMode>().is_err());
+
+        let room_mode: RoomGuestMode = "lobby=disabled".parse().unwrap();
+        assert_eq!(room_mode.room, "lobby");
+        assert_eq!(room_mode.mode, GuestMode::Disabled);
+        assert!("lobby".parse::<RoomGuestMode>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_check_post() {
+        assert!(Guest::new(GuestMode::Full, 0).check_post().await.is_ok());
+        assert!(Guest::new(GuestMode::ReadOnly, 10)
+            .check_post()
+            .await
+            .is_err());
+
+        let guest = Guest::new(GuestMode::RateLimited, 2);
+        assert!(guest.check_post().await.is_ok());
+        assert!(guest.check_post().await.is_ok());
+        assert!(guest.check_post().await.is_err());
+    }
+
+    #[test]
+    fn test_guest_nickname() {
+        let conn = Connection::open_in_memory().unwrap();
+        db::init_schema(&conn).unwrap();
+
+        let user_id = auth::create_guest(&conn, None).unwrap();
+        let nickname = reserve_guest_nickname(&conn, user_id).unwrap();
+
+        assert!(is_guest_name(&nickname));
+        assert_eq!(db::nickname(&conn, user_id).unwrap(), Some(nickname));
+        assert!(is_guest_name("Guest-1234"));
+        assert!(!is_guest_name("alice"));
+    }
+}
@@ -0,0 +1,5072 @@
+use std::{
+    convert::Infallible,
+    net::IpAddr,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use async_graphql::http::WebSocketProtocols;
+use futures::{stream, StreamExt, TryStreamExt};
+use serde::Serialize;
+use serde_json::json;
+use tokio::sync::mpsc;
+use utoipa::ToSchema;
+use warp::{
+    http::{
+        header::{
+            CONTENT_DISPOSITION, CONTENT_TYPE, SEC_WEBSOCKET_PROTOCOL, SET_COOKIE, WWW_AUTHENTICATE,
+        },
+        Response, StatusCode, Uri,
+    },
+    hyper::{body::Bytes, Body},
+    path::{FullPath, Tail},
+    reply::{self, Json, Reply, WithStatus},
+    ws::Ws,
+    Rejection,
+};
+
+use crate::{
+    alert::{self, KeywordUpdate},
+    announcement::{self, AnnouncementUpdate},
+    auth::{
+        self,
+        api_token::{self, NewApiToken},
+        oauth,
+        reset::{self, ForgotPassword, PasswordReset},
+        scope::Scope,
+        throttle, totp, Credentials, MessageRetention, Principal, Session,
+    },
+    authz::{self, AccessUpdate, Action, Role, RoleUpdate},
+    cluster::{Envelope, RemoteConn, RoomRead, RoomRequest, Sent},
+    conversation::{self, NewConversation},
+    db,
+    export::{self, Cursor},
+    federation,
+    graphql::{self, ChatSchema, Viewer},
+    guest::{self, Guest, GuestMode},
+    history::{self, HistoryFilter, NewMessage},
+    hook::{self, NewHook, SlackPayload},
+    invite::{self, NewInvite},
+    ip_ban::{self, NewIpBan},
+    maintenance,
+    moderation::{self, NewSanction, SanctionKind},
+    notification::{self, LevelUpdate},
+    openapi,
+    profile::{self, ProfileUpdate},
+    protocol::ServerEvent,
+    report::{self, NewReport, ReportAction, ReportResolution, ReportStatus},
+    revision,
+    room::{
+        self, MemberInvite, NewRoom, OwnerUpdate, ReadOnlyUpdate, RetentionUpdate, TopicUpdate,
+    },
+    routes::{
+        ChatQuery, CredentialsUnavailable, DeleteUserQuery, ExportQuery, HistoryQuery,
+        MissingScope, NotLoggedIn, OAuthCallback, SearchQuery, StreamQuery, Unauthorized,
+    },
+    schedule::{self, NewScheduledMessage},
+    search,
+    server::ServerState,
+    sse,
+    store::MessageChange,
+    user::{
+        self, announce_sanction, broadcast_to_room, disconnect_addresses, disconnect_session,
+        disconnect_user, enforce_access, join_room, kick, occupancy, session_connections,
+        validate_nickname, Admission, PostOutcome, Refusal, User, UserRx,
+    },
+    wire::{FramedSocket, WireFormat},
+};
+
+pub static NEXT_CONNECTION_ID: AtomicUsize = AtomicUsize::new(1);
+
+// Body of replies refusing requests.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ErrorReply {
+    // Why the request was refused
+    pub error: String,
+    // Number of seconds to wait before logging in again, once locked out
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry_after: Option<u64>,
+}
+
+// Challenge sent with replies refusing requests to admin routes.
+const ADMIN_CHALLENGE: &str = "Basic realm=\"bi_chat admin\", Bearer realm=\"bi_chat admin\"";
+
+// How long users have to authorize a login with an OAuth provider.
+const OAUTH_STATE_TTL: Duration = Duration::from_secs(10 * 60);
+
+// Upgrades a connection to `chat_room`, or to the default room if it names
+// none, to a WebSocket, once its user has been identified.
+pub async fn chat(
+    ws: Ws,
+    chat_room: Option<String>,
+    query: ChatQuery,
+    bearer_token: Option<String>,
+    addr: Option<IpAddr>,
+    session: Option<Session>,
+    state: ServerState,
+) -> Result<Box<dyn Reply>, Infallible> {
+    // Banned addresses are refused before anything is done on their behalf
+    if let Some(addr) = addr {
+        match db::query(&state.db_tx, move |conn| ip_ban::is_banned(conn, addr)).await {
+            Ok(false) => {}
+            Ok(true) => {
+                return Ok(Box::new(error_reply(
+                    StatusCode::FORBIDDEN,
+                    "Your address is banned",
+                )))
+            }
+            Err(e) => return Ok(Box::new(internal_error(e))),
+        }
+    }
+
+    let nick = match query.nick.as_deref().map(validate_nickname).transpose() {
+        Ok(nick) => nick,
+        Err(e) => {
+            return Ok(Box::new(error_reply(
+                StatusCode::BAD_REQUEST,
+                &e.to_string(),
+            )))
+        }
+    };
+
+    let chat_room = chat_room.unwrap_or_else(|| state.config.default_room.clone());
+    let token = query.token.or(bearer_token);
+    let guest_mode = state.config.guest_mode(&chat_room);
+    let is_guest = token.is_none() && session.is_none();
+    if is_guest && guest_mode == GuestMode::Disabled {
+        return Ok(Box::new(error_reply(
+            StatusCode::FORBIDDEN,
+            "Guests may not join this room",
+        )));
+    }
+
+    // Connections authenticated by their session are closed when it ends
+    let session_id = match (&token, &session) {
+        (None, Some(session)) => Some(session.id.clone()),
+        _ => None,
+    };
+
+    let user = auth::connection_user(&state.db_tx, &state.jwt, token, session.as_ref(), addr).await;
+    let principal = match user {
+        Ok(Some(principal)) => principal,
+        Ok(None) => {
+            return Ok(Box::new(error_reply(
+                StatusCode::UNAUTHORIZED,
+                "Invalid token",
+            )))
+        }
+        Err(e) => return Ok(Box::new(internal_error(e))),
+    };
+
+    // API tokens may be restricted to some rooms, or to reading them
+    let read_scope = Scope::Read(Some(chat_room.clone()));
+    if !principal.allows(&read_scope) {
+        return Ok(Box::new(missing_scope(&read_scope)));
+    }
+    let may_write = principal.allows(&Scope::Write(Some(chat_room.clone())));
+    let user_id = principal.user_id;
+
+    // Invites make registered users members of the room before they join it
+    if let Some(invite) = query.invite {
+        if is_guest {
+            return Ok(Box::new(error_reply(
+                StatusCode::UNAUTHORIZED,
+                "Log in to redeem invites",
+            )));
+        }
+
+        let room_name = chat_room.clone();
+        match db::query(&state.db_tx, move |conn| {
+            invite::redeem(conn, &invite, &room_name, user_id)
+        })
+        .await
+        {
+            Ok(true) => {}
+            Ok(false) => {
+                return Ok(Box::new(error_reply(
+                    StatusCode::FORBIDDEN,
+                    "Invite is invalid, expired or used up",
+                )))
+            }
+            Err(e) => return Ok(Box::new(internal_error(e))),
+        }
+    }
+
+    // Nicknames given when connecting are reserved, and remembered for later
+    // connections
+    let nick = match nick {
+        Some(nick) => {
+            let new_nick = nick.clone();
+            let reserved = db::query(&state.db_tx, move |conn| {
+                db::reserve_nickname(conn, user_id, &new_nick)
+            })
+            .await;
+
+            match reserved {
+                Ok(true) => Some(nick),
+                Ok(false) => {
+                    return Ok(Box::new(error_reply(
+                        StatusCode::CONFLICT,
+                        "Nickname is already taken",
+                    )))
+                }
+                Err(e) => return Ok(Box::new(internal_error(e))),
+            }
+        }
+        // Guests are given a temporary nickname
+        None if is_guest => {
+            let reserved = db::query(&state.db_tx, move |conn| {
+                Ok(guest::reserve_guest_nickname(conn, user_id))
+            })
+            .await;
+
+            match reserved.and_then(|nick| nick) {
+                Ok(nick) => Some(nick),
+                Err(e) => return Ok(Box::new(internal_error(e))),
+            }
+        }
+        None => match db::query(&state.db_tx, move |conn| db::nickname(conn, user_id)).await {
+            Ok(nick) => nick,
+            Err(e) => return Ok(Box::new(internal_error(e))),
+        },
+    };
+    if let Some(nick) = nick {
+        state.nicks.write().await.insert(user_id, nick);
+    }
+
+    let moderator_key = &state.config.moderator_key;
+    let granted_role = if moderator_key.is_some() && query.key == *moderator_key {
+        Role::Moderator
+    } else {
+        Role::Member
+    };
+
+    let filter_action = state.config.filter_action(&chat_room);
+    let password = query.password;
+    let since = query.since;
+    let format = query.subprotocol.or(query.format).unwrap_or_default();
+    let session_ttl = state.session_ttl();
+    let upgrade = ws.on_upgrade(move |socket| async move {
+        let mut socket = FramedSocket::new(socket, format);
+        let conn_id = NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed);
+
+        // Create unbounded channel to handle buffering and consuming of messages
+        let (user_tx, user_rx) = mpsc::unbounded_channel();
+
+        let new_user = User {
+            conn_id,
+            user_id,
+            nicks: state.nicks.clone(),
+            chat_room,
+            user_tx,
+            db_tx: state.db_tx.clone(),
+            message_ids: state.message_ids.clone(),
+            granted_role,
+            guest: is_guest.then(|| Guest::new(guest_mode, state.config.guest_rate_limit)),
+            session_id,
+            addr,
+            word_filter: state.word_filter.clone(),
+            filter_action,
+            duplicate_guard: state.duplicate_guard.clone(),
+            flood_guard: state.flood_guard.clone(),
+            content_hook: state.content_hook.clone(),
+            may_write,
+            away_after: state.config.away_after(),
+            offline_after: state.config.offline_after(),
+            principal,
+            config: state.config.clone(),
+            cluster: state.cluster.clone(),
+            event_sink: state.event_sink.clone(),
+            federation: state.federation.clone(),
+            remote_conn: None,
+        };
+
+        // Establish new connection
+        tokio::task::spawn(async move {
+            let history_seq = match new_user
+                .send_history(state.config.history_limit, since)
+                .await
+            {
+                Ok(history_seq) => Some(history_seq),
+                Err(e) => {
+                    eprintln!("Failed to send room history: {}", e);
+                    None
+                }
+            };
+
+            let explicit_rooms = state.config.explicit_rooms;
+            match join_room(
+                &new_user,
+                &mut socket,
+                &state.rooms,
+                explicit_rooms,
+                state.config.room_capacity,
+                password,
+                history_seq,
+            )
+            .await
+            {
+                Ok(Ok(())) => {}
+                Ok(Err(refusal)) => return new_user.refuse(socket, &state.rooms, refusal).await,
+                Err(e) => {
+                    eprintln!("Failed to join room {}: {}", new_user.chat_room, e);
+                    return;
+                }
+            }
+            if let Err(e) = new_user.announce_join(&state.rooms).await {
+                eprintln!("Failed to announce joining {}: {}", new_user.chat_room, e);
+            }
+            if let Err(e) = new_user.send_queued().await {
+                eprintln!("Failed to send queued events: {}", e);
+            }
+
+            new_user.listen(socket, user_rx, state.rooms).await
+        });
+    });
+
+    // Agree to the subprotocol asked for, if known
+    let upgrade: Box<dyn Reply> = match query.subprotocol.and_then(WireFormat::subprotocol) {
+        Some(subprotocol) => Box::new(reply::with_header(
+            upgrade,
+            SEC_WEBSOCKET_PROTOCOL,
+            subprotocol,
+        )),
+        None => Box::new(upgrade),
+    };
+
+    // Hand the renewed session back to the client
+    match session {
+        Some(session) => Ok(Box::new(reply::with_header(
+            upgrade,
+            SET_COOKIE,
+            auth::session_cookie(&session.token, session_ttl),
+        ))),
+        None => Ok(upgrade),
+    }
+}
+
+// Resolves the token of a session cookie to its session, renewing it.
+// Expired or unknown sessions are ignored, as if no cookie was sent.
+pub async fn session(
+    token: Option<String>,
+    state: ServerState,
+) -> Result<Option<Session>, Infallible> {
+    let token = match token {
+        Some(token) => token,
+        None => return Ok(None),
+    };
+
+    let ttl = state.session_ttl();
+    match db::query(&state.db_tx, move |conn| {
+        auth::renew_session(conn, &token, ttl)
+    })
+    .await
+    {
+        Ok(session) => Ok(session),
+        Err(e) => {
+            eprintln!("Failed to look up session: {}", e);
+            Ok(None)
+        }
+    }
+}
+
+// Resolves who makes a request, by its bearer token or `session`, for
+// `routes::require_scope`. Requests whose credentials can not be looked up
+// are rejected with `CredentialsUnavailable`.
+pub async fn principal(
+    bearer_token: Option<String>,
+    session: Option<Session>,
+    state: ServerState,
+) -> Result<Option<Principal>, Rejection> {
+    auth::request_user(&state.db_tx, &state.jwt, bearer_token, session.as_ref())
+        .await
+        .map_err(|e| {
+            eprintln!("Failed to look up credentials: {}", e);
+            warp::reject::custom(CredentialsUnavailable)
+        })
+}
+
+// Lists the messages pinned to `room`, as a user who may join it.
+#[utoipa::path(
+    get,
+    path = "/rooms/{name}/pins",
+    tag = "messages",
+    params(("name" = String, Path, description = "Name of the room")),
+    responses(
+        (status = 200, description = "Messages pinned to the room"),
+        (status = 401, description = "Not logged in", body = ErrorReply),
+        (status = 403, description = "Not allowed in this room", body = ErrorReply),
+    ),
+    security(("bearer" = []), ("session" = [])),
+)]
+pub async fn room_pins(
+    room: String,
+    principal: Principal,
+    state: ServerState,
+) -> Result<WithStatus<Json>, Infallible> {
+    if let Err(refusal) = require_may_join(&state, &room, &principal).await {
+        return Ok(refusal);
+    }
+
+    match state
+        .read_room::<serde_json::Value>(&room, RoomRead::Pins)
+        .await
+    {
+        Ok(pins) => Ok(reply::with_status(reply::json(&pins), StatusCode::OK)),
+        Err(e) => Ok(internal_error(e)),
+    }
+}
+
+// Fetches the profile of a user.
+#[utoipa::path(
+    get,
+    path = "/users/{id}/profile",
+    tag = "users",
+    params(("id" = usize, Path, description = "ID of the user")),
+    responses(
+        (status = 200, description = "Profile of the user"),
+        (status = 404, description = "User not found", body = ErrorReply),
+    ),
+)]
+pub async fn profile(user_id: usize, state: ServerState) -> Result<WithStatus<Json>, Infallible> {
+    match db::query(&state.db_tx, move |conn| profile::profile(conn, user_id)).await {
+        Ok(Some(profile)) => Ok(reply::with_status(reply::json(&profile), StatusCode::OK)),
+        Ok(None) => Ok(error_reply(StatusCode::NOT_FOUND, "User not found")),
+        Err(e) => Ok(internal_error(e)),
+    }
+}
+
+// Replaces the profile of a user. Users may only update their own profile.
+#[utoipa::path(
+    put,
+    path = "/users/{id}/profile",
+    tag = "users",
+    params(("id" = usize, Path, description = "ID of the user")),
+    request_body = ProfileUpdate,
+    responses(
+        (status = 200, description = "The updated profile"),
+        (status = 400, description = "Invalid profile", body = ErrorReply),
+        (status = 401, description = "Not logged in", body = ErrorReply),
+        (status = 403, description = "Not this user", body = ErrorReply),
+        (status = 404, description = "User not found", body = ErrorReply),
+    ),
+    security(("bearer" = []), ("session" = [])),
+)]
+pub async fn update_profile(
+    user_id: usize,
+    update: ProfileUpdate,
+    principal: Principal,
+    state: ServerState,
+) -> Result<WithStatus<Json>, Infallible> {
+    if principal.user_id != user_id {
+        return Ok(error_reply(
+            StatusCode::FORBIDDEN,
+            "Can only update your own profile",
+        ));
+    }
+
+    if let Err(e) = update.validate() {
+        return Ok(error_reply(StatusCode::BAD_REQUEST, &e.to_string()));
+    }
+
+    let updated = db::query(&state.db_tx, move |conn| {
+        profile::set_profile(conn, user_id, &update)?;
+        profile::profile(conn, user_id)
+    })
+    .await;
+
+    match updated {
+        Ok(Some(profile)) => Ok(reply::with_status(reply::json(&profile), StatusCode::OK)),
+        Ok(None) => Ok(error_reply(StatusCode::NOT_FOUND, "User not found")),
+        Err(e) => Ok(internal_error(e)),
+    }
+}
+
+// Deletes a user's account, as that user, closing their connections.
+#[utoipa::path(
+    delete,
+    path = "/users/{id}",
+    tag = "users",
+    params(
+        ("id" = usize, Path, description = "ID of the user"),
+        DeleteUserQuery,
+    ),
+    responses(
+        (status = 204, description = "Account deleted"),
+        (status = 401, description = "Not logged in", body = ErrorReply),
+        (status = 403, description = "Neither this user nor an admin", body = ErrorReply),
+        (status = 404, description = "User not found", body = ErrorReply),
+    ),
+    security(("bearer" = []), ("session" = [])),
+)]
+pub async fn delete_user(
+    user_id: usize,
+    query: DeleteUserQuery,
+    principal: Principal,
+    state: ServerState,
+) -> Result<Box<dyn Reply>, Infallible> {
+    let request_user = principal.user_id;
+
+    // Admins may delete the accounts of others
+    if request_user != user_id {
+        match permits(&state, request_user, None, Action::Administer).await {
+            Ok(true) => {}
+            Ok(false) => {
+                return Ok(Box::new(error_reply(
+                    StatusCode::FORBIDDEN,
+                    "Can only delete your own account",
+                )))
+            }
+            Err(e) => return Ok(Box::new(internal_error(e))),
+        }
+    }
+
+    let deleted = db::change(&state.db_tx, move |conn| {
+        let deleted = auth::delete_user(conn, user_id, query.messages)?;
+        let changes = match (deleted, query.messages) {
+            (false, _) => Vec::new(),
+            (true, MessageRetention::Anonymize) => vec![MessageChange::Anonymized(user_id)],
+            (true, MessageRetention::Delete) => vec![MessageChange::Erased(user_id)],
+        };
+        Ok((deleted, changes))
+    })
+    .await;
+
+    match deleted {
+        Ok(true) => {}
+        Ok(false) => {
+            return Ok(Box::new(error_reply(
+                StatusCode::NOT_FOUND,
+                "User not found",
+            )))
+        }
+        Err(e) => return Ok(Box::new(internal_error(e))),
+    }
+
+    disconnect_user(user_id, &state.rooms).await;
+    state.nicks.write().await.remove(&user_id);
+
+    if request_user != user_id {
+        return Ok(Box::new(StatusCode::NO_CONTENT));
+    }
+
+    Ok(Box::new(reply::with_header(
+        StatusCode::NO_CONTENT,
+        SET_COOKIE,
+        auth::session_cookie("", Duration::from_secs(0)),
+    )))
+}
+
+// Gives a user a server-wide role, as an admin.
+#[utoipa::path(
+    put,
+    path = "/users/{id}/role",
+    tag = "users",
+    params(("id" = usize, Path, description = "ID of the user")),
+    request_body = RoleUpdate,
+    responses(
+        (status = 200, description = "The role given"),
+        (status = 401, description = "Not logged in", body = ErrorReply),
+        (status = 403, description = "Not an admin", body = ErrorReply),
+        (status = 404, description = "User not found", body = ErrorReply),
+    ),
+    security(("bearer" = []), ("session" = [])),
+)]
+pub async fn set_user_role(
+    user_id: usize,
+    update: RoleUpdate,
+    principal: Principal,
+    state: ServerState,
+) -> Result<WithStatus<Json>, Infallible> {
+    let request_user = principal.user_id;
+
+    match permits(&state, request_user, None, Action::Administer).await {
+        Ok(true) => {}
+        Ok(false) => {
+            return Ok(error_reply(
+                StatusCode::FORBIDDEN,
+                "Only admins can give roles",
+            ))
+        }
+        Err(e) => return Ok(internal_error(e)),
+    }
+
+    let role = update.role;
+    match db::query(&state.db_tx, move |conn| {
+        authz::set_user_role(conn, user_id, role)
+    })
+    .await
+    {
+        Ok(true) => Ok(reply::with_status(
+            reply::json(&json!({ "user_id": user_id, "role": role })),
+            StatusCode::OK,
+        )),
+        Ok(false) => Ok(error_reply(StatusCode::NOT_FOUND, "User not found")),
+        Err(e) => Ok(internal_error(e)),
+    }
+}
+
+// Lists public rooms, along with how many users are connected to them.
+#[utoipa::path(
+    get,
+    path = "/rooms",
+    tag = "rooms",
+    responses(
+        (status = 200, description = "Public rooms, by name, with their occupancy and message count"),
+    ),
+)]
+pub async fn rooms(state: ServerState) -> Result<WithStatus<Json>, Infallible> {
+    let mut rooms = match db::query(&state.db_tx, room::public_rooms).await {
+        Ok(rooms) => rooms,
+        Err(e) => return Ok(internal_error(e)),
+    };
+
+    let occupancy = occupancy(&state.rooms).await;
+    for room in &mut rooms {
+        room.occupancy = occupancy.get(&room.name).copied().unwrap_or(0);
+    }
+
+    Ok(reply::with_status(reply::json(&rooms), StatusCode::OK))
+}
+
+// Creates a room with the given settings, owned by the logged in user.
+#[utoipa::path(
+    post,
+    path = "/rooms",
+    tag = "rooms",
+    request_body = NewRoom,
+    responses(
+        (status = 201, description = "The room created"),
+        (status = 400, description = "Invalid settings", body = ErrorReply),
+        (status = 401, description = "Not logged in", body = ErrorReply),
+        (status = 403, description = "Token lacks the `admin` scope", body = ErrorReply),
+        (status = 409, description = "Room already exists", body = ErrorReply),
+    ),
+    security(("bearer" = []), ("session" = [])),
+)]
+pub async fn create_room(
+    new_room: NewRoom,
+    principal: Principal,
+    state: ServerState,
+) -> Result<WithStatus<Json>, Infallible> {
+    let user_id = principal.user_id;
+
+    if let Err(e) = new_room.validate() {
+        return Ok(error_reply(StatusCode::BAD_REQUEST, &e.to_string()));
+    }
+
+    let password_hash = match new_room.password.clone() {
+        Some(password) => {
+            match tokio::task::spawn_blocking(move || auth::hash_password(&password)).await {
+                Ok(Ok(password_hash)) => Some(password_hash),
+                Ok(Err(e)) => return Ok(internal_error(e)),
+                Err(e) => return Ok(internal_error(e.into())),
+            }
+        }
+        None => None,
+    };
+
+    let created = db::query(&state.db_tx, move |conn| {
+        let (name, settings) = (&new_room.name, &new_room.settings);
+        if !room::create_room(conn, name, user_id, settings, password_hash.as_deref())? {
+            return Ok(None);
+        }
+        room::room_info(conn, &new_room.name)
+    })
+    .await;
+
+    match created {
+        Ok(Some(room)) => Ok(reply::with_status(reply::json(&room), StatusCode::CREATED)),
+        Ok(None) => Ok(error_reply(StatusCode::CONFLICT, "Room already exists")),
+        Err(e) => Ok(internal_error(e)),
+    }
+}
+
+// Fetches `room`, as a user who may join it.
+#[utoipa::path(
+    get,
+    path = "/rooms/{name}",
+    tag = "rooms",
+    params(("name" = String, Path, description = "Name of the room")),
+    responses(
+        (status = 200, description = "The room, with its creator, owners and settings"),
+        (status = 401, description = "Not logged in", body = ErrorReply),
+        (status = 403, description = "Not allowed in this room", body = ErrorReply),
+        (status = 404, description = "Room not found", body = ErrorReply),
+    ),
+    security(("bearer" = []), ("session" = [])),
+)]
+pub async fn room(
+    room: String,
+    principal: Principal,
+    state: ServerState,
+) -> Result<WithStatus<Json>, Infallible> {
+    if let Err(reply) = require_may_join(&state, &room, &principal).await {
+        return Ok(reply);
+    }
+
+    match db::query(&state.db_tx, move |conn| room::room_info(conn, &room)).await {
+        Ok(Some(room)) => Ok(reply::with_status(reply::json(&room), StatusCode::OK)),
+        Ok(None) => Ok(room_not_found()),
+        Err(e) => Ok(internal_error(e)),
+    }
+}
+
+// Replaces the topic and description of `room`, as a moderator of the server
+// or of the room, notifying everyone connected to it.
+#[utoipa::path(
+    put,
+    path = "/rooms/{name}/topic",
+    tag = "rooms",
+    params(("name" = String, Path, description = "Name of the room")),
+    request_body = TopicUpdate,
+    responses(
+        (status = 200, description = "The room, with its new topic"),
+        (status = 400, description = "Topic or description too long", body = ErrorReply),
+        (status = 401, description = "Not logged in", body = ErrorReply),
+        (status = 403, description = "Not a moderator of the server or room", body = ErrorReply),
+        (status = 404, description = "Room not found", body = ErrorReply),
+    ),
+    security(("bearer" = []), ("session" = [])),
+)]
+pub async fn set_room_topic(
+    room: String,
+    update: TopicUpdate,
+    principal: Principal,
+    state: ServerState,
+) -> Result<WithStatus<Json>, Infallible> {
+    let user_id = match require_room_permission(
+        &state,
+        &room,
+        &principal,
+        Action::SetTopic,
+        "Only moderators can set the topic of this room",
+    )
+    .await
+    {
+        Ok(user_id) => user_id,
+        Err(reply) => return Ok(reply),
+    };
+
+    if let Err(e) = update.validate() {
+        return Ok(error_reply(StatusCode::BAD_REQUEST, &e.to_string()));
+    }
+
+    let event = ServerEvent::Topic {
+        room: room.clone(),
+        topic: update.topic.clone(),
+        description: update.description.clone(),
+        set_by: user_id,
+    };
+    let room_name = room.clone();
+    let updated = db::query(&state.db_tx, move |conn| {
+        if !room::set_topic(conn, &room_name, &update)? {
+            return Ok(None);
+        }
+        room::room_info(conn, &room_name)
+    })
+    .await;
+
+    match updated {
+        Ok(Some(info)) => {
+            broadcast_to_room(&room, &state.rooms, &event).await;
+            Ok(reply::with_status(reply::json(&info), StatusCode::OK))
+        }
+        Ok(None) => Ok(room_not_found()),
+        Err(e) => Ok(internal_error(e)),
+    }
+}
+
+// Makes `room` read-only, or writable again, as a moderator of the room.
+#[utoipa::path(
+    put,
+    path = "/rooms/{name}/read_only",
+    tag = "rooms",
+    params(("name" = String, Path, description = "Name of the room")),
+    request_body = ReadOnlyUpdate,
+    responses(
+        (status = 200, description = "The room"),
+        (status = 401, description = "Not logged in", body = ErrorReply),
+        (status = 403, description = "Not a moderator of the server or room", body = ErrorReply),
+        (status = 404, description = "Room not found", body = ErrorReply),
+    ),
+    security(("bearer" = []), ("session" = [])),
+)]
+pub async fn set_room_read_only(
+    room: String,
+    update: ReadOnlyUpdate,
+    principal: Principal,
+    state: ServerState,
+) -> Result<WithStatus<Json>, Infallible> {
+    let user_id = match require_room_permission(
+        &state,
+        &room,
+        &principal,
+        Action::SetReadOnly,
+        "Only moderators can make this room read-only",
+    )
+    .await
+    {
+        Ok(user_id) => user_id,
+        Err(reply) => return Ok(reply),
+    };
+
+    let event = ServerEvent::ReadOnly {
+        room: room.clone(),
+        read_only: update.read_only,
+        set_by: user_id,
+    };
+    let room_name = room.clone();
+    let updated = db::query(&state.db_tx, move |conn| {
+        if !room::set_read_only(conn, &room_name, update.read_only)? {
+            return Ok(None);
+        }
+        room::room_info(conn, &room_name)
+    })
+    .await;
+
+    match updated {
+        Ok(Some(info)) => {
+            broadcast_to_room(&room, &state.rooms, &event).await;
+            Ok(reply::with_status(reply::json(&info), StatusCode::OK))
+        }
+        Ok(None) => Ok(room_not_found()),
+        Err(e) => Ok(internal_error(e)),
+    }
+}
+
+// Sets how long the messages of `room` are kept, as an admin of the server or
+// of the room. Messages past it are deleted once purged.
+#[utoipa::path(
+    put,
+    path = "/rooms/{name}/retention",
+    tag = "rooms",
+    params(("name" = String, Path, description = "Name of the room")),
+    request_body = RetentionUpdate,
+    responses(
+        (status = 200, description = "The room, with its new retention"),
+        (status = 400, description = "Invalid retention", body = ErrorReply),
+        (status = 401, description = "Not logged in", body = ErrorReply),
+        (status = 403, description = "Not an admin of the server or room", body = ErrorReply),
+        (status = 404, description = "Room not found", body = ErrorReply),
+    ),
+    security(("bearer" = []), ("session" = [])),
+)]
+pub async fn set_room_retention(
+    room: String,
+    update: RetentionUpdate,
+    principal: Principal,
+    state: ServerState,
+) -> Result<WithStatus<Json>, Infallible> {
+    if let Err(reply) = require_room_permission(
+        &state,
+        &room,
+        &principal,
+        Action::SetRetention,
+        "Only admins can set the retention of this room",
+    )
+    .await
+    {
+        return Ok(reply);
+    }
+
+    if let Err(e) = update.validate() {
+        return Ok(error_reply(StatusCode::BAD_REQUEST, &e.to_string()));
+    }
+
+    let updated = db::query(&state.db_tx, move |conn| {
+        if !room::set_retention(conn, &room, &update)? {
+            return Ok(None);
+        }
+        room::room_info(conn, &room)
+    })
+    .await;
+
+    match updated {
+        Ok(Some(info)) => Ok(reply::with_status(reply::json(&info), StatusCode::OK)),
+        Ok(None) => Ok(room_not_found()),
+        Err(e) => Ok(internal_error(e)),
+    }
+}
+
+// Makes `room` an announcement room, or a regular room again, as an admin of
+// the room.
+#[utoipa::path(
+    put,
+    path = "/rooms/{name}/announcement",
+    tag = "rooms",
+    params(("name" = String, Path, description = "Name of the room")),
+    request_body = AnnouncementUpdate,
+    responses(
+        (status = 200, description = "The room"),
+        (status = 401, description = "Not logged in", body = ErrorReply),
+        (status = 403, description = "Not an admin of the server or room", body = ErrorReply),
+        (status = 404, description = "Room not found", body = ErrorReply),
+    ),
+    security(("bearer" = []), ("session" = [])),
+)]
+pub async fn set_room_announcement(
+    room: String,
+    update: AnnouncementUpdate,
+    principal: Principal,
+    state: ServerState,
+) -> Result<WithStatus<Json>, Infallible> {
+    if let Err(reply) = require_announcement_manager(&state, &room, &principal).await {
+        return Ok(reply);
+    }
+
+    let updated = db::query(&state.db_tx, move |conn| {
+        if !announcement::set_announcement(conn, &room, update.announcement)? {
+            return Ok(None);
+        }
+        room::room_info(conn, &room)
+    })
+    .await;
+
+    match updated {
+        Ok(Some(info)) => Ok(reply::with_status(reply::json(&info), StatusCode::OK)),
+        Ok(None) => Ok(room_not_found()),
+        Err(e) => Ok(internal_error(e)),
+    }
+}
+
+// Lists the announcement rooms `room` follows, as an admin of the server or of
+// the room.
+#[utoipa::path(
+    get,
+    path = "/rooms/{name}/following",
+    tag = "rooms",
+    params(("name" = String, Path, description = "Name of the room")),
+    responses(
+        (status = 200, description = "Announcement rooms the room follows"),
+        (status = 401, description = "Not logged in", body = ErrorReply),
+        (status = 403, description = "Not an admin of the server or room", body = ErrorReply),
+    ),
+    security(("bearer" = []), ("session" = [])),
+)]
+pub async fn room_following(
+    room: String,
+    principal: Principal,
+    state: ServerState,
+) -> Result<WithStatus<Json>, Infallible> {
+    if let Err(reply) = require_announcement_manager(&state, &room, &principal).await {
+        return Ok(reply);
+    }
+
+    match db::query(&state.db_tx, move |conn| {
+        announcement::following(conn, &room)
+    })
+    .await
+    {
+        Ok(follows) => Ok(reply::with_status(reply::json(&follows), StatusCode::OK)),
+        Err(e) => Ok(internal_error(e)),
+    }
+}
+
+// Has `room` follow announcement room `source`, as an admin of the server or
+// of the room who may join `source`.
+#[utoipa::path(
+    put,
+    path = "/rooms/{name}/following/{source}",
+    tag = "rooms",
+    params(
+        ("name" = String, Path, description = "Name of the room"),
+        ("source" = String, Path, description = "Name of the announcement room"),
+    ),
+    responses(
+        (status = 200, description = "The follow"),
+        (status = 400, description = "Not an announcement room, or the room itself", body = ErrorReply),
+        (status = 401, description = "Not logged in", body = ErrorReply),
+        (status = 403, description = "Not an admin of the server or room, or not allowed into `source`", body = ErrorReply),
+        (status = 404, description = "Room not found", body = ErrorReply),
+    ),
+    security(("bearer" = []), ("session" = [])),
+)]
+pub async fn follow_room(
+    room: String,
+    source: String,
+    principal: Principal,
+    state: ServerState,
+) -> Result<WithStatus<Json>, Infallible> {
+    let user_id = match require_announcement_manager(&state, &room, &principal).await {
+        Ok(user_id) => user_id,
+        Err(reply) => return Ok(reply),
+    };
+
+    // Rooms the user may not join are not found, as if they did not exist
+    match db::query(&state.db_tx, move |conn| {
+        Ok(announcement::follow(conn, &room, &source, user_id))
+    })
+    .await
+    {
+        Ok(Ok(Some(follow))) => Ok(reply::with_status(reply::json(&follow), StatusCode::OK)),
+        Ok(Ok(None)) => Ok(room_not_found()),
+        Ok(Err(e)) => Ok(error_reply(StatusCode::BAD_REQUEST, &e.to_string())),
+        Err(e) => Ok(internal_error(e)),
+    }
+}
+
+// Stops `room` from following `source`, as an admin of the server or of the
+// room.
+#[utoipa::path(
+    delete,
+    path = "/rooms/{name}/following/{source}",
+    tag = "rooms",
+    params(
+        ("name" = String, Path, description = "Name of the room"),
+        ("source" = String, Path, description = "Name of the announcement room"),
+    ),
+    responses(
+        (status = 204, description = "Room no longer follows `source`"),
+        (status = 401, description = "Not logged in", body = ErrorReply),
+        (status = 403, description = "Not an admin of the server or room", body = ErrorReply),
+        (status = 404, description = "Room not followed", body = ErrorReply),
+    ),
+    security(("bearer" = []), ("session" = [])),
+)]
+pub async fn unfollow_room(
+    room: String,
+    source: String,
+    principal: Principal,
+    state: ServerState,
+) -> Result<Box<dyn Reply>, Infallible> {
+    if let Err(reply) = require_announcement_manager(&state, &room, &principal).await {
+        return Ok(Box::new(reply));
+    }
+
+    match db::query(&state.db_tx, move |conn| {
+        announcement::unfollow(conn, &room, &source)
+    })
+    .await
+    {
+        Ok(true) => Ok(Box::new(StatusCode::NO_CONTENT)),
+        Ok(false) => Ok(Box::new(error_reply(
+            StatusCode::NOT_FOUND,
+            "Room not followed",
+        ))),
+        Err(e) => Ok(Box::new(internal_error(e))),
+    }
+}
+
+// Makes a user a co-owner of `room`, as one of its owners.
+#[utoipa::path(
+    post,
+    path = "/rooms/{name}/owners",
+    tag = "rooms",
+    params(("name" = String, Path, description = "Name of the room")),
+    request_body = OwnerUpdate,
+    responses(
+        (status = 200, description = "The room, with its owners"),
+        (status = 401, description = "Not logged in", body = ErrorReply),
+        (status = 403, description = "Not an owner of the room", body = ErrorReply),
+        (status = 404, description = "User or room not found", body = ErrorReply),
+    ),
+    security(("bearer" = []), ("session" = [])),
+)]
+pub async fn add_room_owner(
+    room: String,
+    update: OwnerUpdate,
+    principal: Principal,
+    state: ServerState,
+) -> Result<WithStatus<Json>, Infallible> {
+    if let Err(reply) = require_owner(&state, &room, &principal).await {
+        return Ok(reply);
+    }
+
+    let user_id = update.user_id;
+    let added = db::query(&state.db_tx, move |conn| {
+        if !room::add_owner(conn, &room, user_id)? {
+            return Ok(None);
+        }
+        room::room_info(conn, &room)
+    })
+    .await;
+
+    match added {
+        Ok(Some(room)) => Ok(reply::with_status(reply::json(&room), StatusCode::OK)),
+        Ok(None) => Ok(error_reply(StatusCode::NOT_FOUND, "User or room not found")),
+        Err(e) => Ok(internal_error(e)),
+    }
+}
+
+// Takes ownership of `room` away from one of its owners, as one of its owners.
+// Rooms can not be left without an owner.
+#[utoipa::path(
+    delete,
+    path = "/rooms/{name}/owners/{user_id}",
+    tag = "rooms",
+    params(
+        ("name" = String, Path, description = "Name of the room"),
+        ("user_id" = usize, Path, description = "ID of the owner"),
+    ),
+    responses(
+        (status = 204, description = "User no longer owns the room"),
+        (status = 401, description = "Not logged in", body = ErrorReply),
+        (status = 403, description = "Not an owner of the room", body = ErrorReply),
+        (status = 404, description = "User does not own this room", body = ErrorReply),
+        (status = 409, description = "Last owner of the room", body = ErrorReply),
+    ),
+    security(("bearer" = []), ("session" = [])),
+)]
+pub async fn remove_room_owner(
+    room: String,
+    user_id: usize,
+    principal: Principal,
+    state: ServerState,
+) -> Result<Box<dyn Reply>, Infallible> {
+    if let Err(reply) = require_owner(&state, &room, &principal).await {
+        return Ok(Box::new(reply));
+    }
+
+    let removed = db::query(&state.db_tx, move |conn| {
+        if room::owners(conn, &room)? == [user_id] {
+            return Ok(None);
+        }
+        room::remove_owner(conn, &room, user_id).map(Some)
+    })
+    .await;
+
+    match removed {
+        Ok(Some(true)) => Ok(Box::new(StatusCode::NO_CONTENT)),
+        Ok(Some(false)) => Ok(Box::new(error_reply(
+            StatusCode::NOT_FOUND,
+            "User does not own this room",
+        ))),
+        Ok(None) => Ok(Box::new(error_reply(
+            StatusCode::CONFLICT,
+            "Can not remove the last owner of a room, transfer it instead",
+        ))),
+        Err(e) => Ok(Box::new(internal_error(e))),
+    }
+}
+
+// Transfers `room` to a user, who becomes its only owner, as one of its owners.
+#[utoipa::path(
+    put,
+    path = "/rooms/{name}/owner",
+    tag = "rooms",
+    params(("name" = String, Path, description = "Name of the room")),
+    request_body = OwnerUpdate,
+    responses(
+        (status = 200, description = "The room, owned by the user alone"),
+        (status = 401, description = "Not logged in", body = ErrorReply),
+        (status = 403, description = "Not an owner of the room", body = ErrorReply),
+        (status = 404, description = "User or room not found", body = ErrorReply),
+    ),
+    security(("bearer" = []), ("session" = [])),
+)]
+pub async fn transfer_room(
+    room: String,
+    update: OwnerUpdate,
+    principal: Principal,
+    state: ServerState,
+) -> Result<WithStatus<Json>, Infallible> {
+    if let Err(reply) = require_owner(&state, &room, &principal).await {
+        return Ok(reply);
+    }
+
+    let user_id = update.user_id;
+    let transferred = db::query(&state.db_tx, move |conn| {
+        if !room::transfer_ownership(conn, &room, user_id)? {
+            return Ok(None);
+        }
+        room::room_info(conn, &room)
+    })
+    .await;
+
+    match transferred {
+        Ok(Some(room)) => Ok(reply::with_status(reply::json(&room), StatusCode::OK)),
+        Ok(None) => Ok(error_reply(StatusCode::NOT_FOUND, "User or room not found")),
+        Err(e) => Ok(internal_error(e)),
+    }
+}
+
+fn room_not_found() -> WithStatus<Json> {
+    error_reply(StatusCode::NOT_FOUND, "Room not found")
+}
+
+// Lists who is allowed into and kept out of `room`, as an admin of the server
+// or of the room.
+#[utoipa::path(
+    get,
+    path = "/rooms/{name}/acl",
+    tag = "rooms",
+    params(("name" = String, Path, description = "Name of the room")),
+    responses(
+        (status = 200, description = "Access control list of the room"),
+        (status = 401, description = "Not logged in", body = ErrorReply),
+        (status = 403, description = "Not an admin of the server or room", body = ErrorReply),
+    ),
+    security(("bearer" = []), ("session" = [])),
+)]
+pub async fn room_acl(
+    room: String,
+    principal: Principal,
+    state: ServerState,
+) -> Result<WithStatus<Json>, Infallible> {
+    if let Err(reply) = require_access_manager(&state, &room, &principal).await {
+        return Ok(reply);
+    }
+
+    match db::query(&state.db_tx, move |conn| authz::room_acl(conn, &room)).await {
+        Ok(acl) => Ok(reply::with_status(reply::json(&acl), StatusCode::OK)),
+        Err(e) => Ok(internal_error(e)),
+    }
+}
+
+// Allows a user into `room`, or keeps them out of it, as an admin of the server
+// or of the room. Connections the room no longer lets in are closed.
+#[utoipa::path(
+    put,
+    path = "/rooms/{name}/acl/{user_id}",
+    tag = "rooms",
+    params(
+        ("name" = String, Path, description = "Name of the room"),
+        ("user_id" = usize, Path, description = "ID of the user"),
+    ),
+    request_body = AccessUpdate,
+    responses(
+        (status = 200, description = "The access given"),
+        (status = 401, description = "Not logged in", body = ErrorReply),
+        (status = 403, description = "Not an admin of the server or room", body = ErrorReply),
+        (status = 404, description = "User not found", body = ErrorReply),
+    ),
+    security(("bearer" = []), ("session" = [])),
+)]
+pub async fn set_room_access(
+    room: String,
+    user_id: usize,
+    update: AccessUpdate,
+    principal: Principal,
+    state: ServerState,
+) -> Result<WithStatus<Json>, Infallible> {
+    if let Err(reply) = require_access_manager(&state, &room, &principal).await {
+        return Ok(reply);
+    }
+
+    let (access, room_name) = (update.access, room.clone());
+    match db::query(&state.db_tx, move |conn| {
+        authz::set_access(conn, &room_name, user_id, access)
+    })
+    .await
+    {
+        Ok(true) => {}
+        Ok(false) => return Ok(error_reply(StatusCode::NOT_FOUND, "User not found")),
+        Err(e) => return Ok(internal_error(e)),
+    }
+
+    if let Err(e) = enforce_access(&room, &state.rooms, &state.db_tx).await {
+        return Ok(internal_error(e));
+    }
+
+    Ok(reply::with_status(
+        reply::json(&json!({ "user_id": user_id, "room": room, "access": access })),
+        StatusCode::OK,
+    ))
+}
+
+// Creates an invite into `room`, as an admin of the server or of the room.
+#[utoipa::path(
+    post,
+    path = "/rooms/{name}/invites",
+    tag = "rooms",
+    params(("name" = String, Path, description = "Name of the room")),
+    request_body = NewInvite,
+    responses(
+        (status = 201, description = "The invite, with its `token`"),
+        (status = 400, description = "Invalid limits", body = ErrorReply),
+        (status = 401, description = "Not logged in", body = ErrorReply),
+        (status = 403, description = "Not an admin of the server or room", body = ErrorReply),
+        (status = 404, description = "Room not found", body = ErrorReply),
+    ),
+    security(("bearer" = []), ("session" = [])),
+)]
+pub async fn create_invite(
+    room: String,
+    new_invite: NewInvite,
+    principal: Principal,
+    state: ServerState,
+) -> Result<WithStatus<Json>, Infallible> {
+    let user_id = match require_access_manager(&state, &room, &principal).await {
+        Ok(user_id) => user_id,
+        Err(reply) => return Ok(reply),
+    };
+
+    if let Err(e) = new_invite.validate() {
+        return Ok(error_reply(StatusCode::BAD_REQUEST, &e.to_string()));
+    }
+
+    let created = db::query(&state.db_tx, move |conn| {
+        invite::create_invite(conn, &room, user_id, &new_invite)
+    })
+    .await;
+
+    match created {
+        Ok(Some((invite, token))) => Ok(reply::with_status(
+            reply::json(&json!({
+                "id": invite.id,
+                "room": invite.room,
+                "created_by": invite.created_by,
+                "created_at": invite.created_at,
+                "expires_at": invite.expires_at,
+                "max_uses": invite.max_uses,
+                "uses": invite.uses,
+                "token": token,
+            })),
+            StatusCode::CREATED,
+        )),
+        Ok(None) => Ok(room_not_found()),
+        Err(e) => Ok(internal_error(e)),
+    }
+}
+
+// Lists the invites into `room`, as an admin of the server or of the room.
+#[utoipa::path(
+    get,
+    path = "/rooms/{name}/invites",
+    tag = "rooms",
+    params(("name" = String, Path, description = "Name of the room")),
+    responses(
+        (status = 200, description = "Invites into the room"),
+        (status = 401, description = "Not logged in", body = ErrorReply),
+        (status = 403, description = "Not an admin of the server or room", body = ErrorReply),
+    ),
+    security(("bearer" = []), ("session" = [])),
+)]
+pub async fn room_invites(
+    room: String,
+    principal: Principal,
+    state: ServerState,
+) -> Result<WithStatus<Json>, Infallible> {
+    if let Err(reply) = require_access_manager(&state, &room, &principal).await {
+        return Ok(reply);
+    }
+
+    match db::query(&state.db_tx, move |conn| invite::room_invites(conn, &room)).await {
+        Ok(invites) => Ok(reply::with_status(reply::json(&invites), StatusCode::OK)),
+        Err(e) => Ok(internal_error(e)),
+    }
+}
+
+// Revokes an invite into `room`, as an admin of the server or of the room.
+// Members who already redeemed it stay members.
+#[utoipa::path(
+    delete,
+    path = "/rooms/{name}/invites/{id}",
+    tag = "rooms",
+    params(
+        ("name" = String, Path, description = "Name of the room"),
+        ("id" = i64, Path, description = "ID of the invite"),
+    ),
+    responses(
+        (status = 204, description = "Invite revoked"),
+        (status = 401, description = "Not logged in", body = ErrorReply),
+        (status = 403, description = "Not an admin of the server or room", body = ErrorReply),
+        (status = 404, description = "Invite not found", body = ErrorReply),
+    ),
+    security(("bearer" = []), ("session" = [])),
+)]
+pub async fn revoke_invite(
+    room: String,
+    invite_id: i64,
+    principal: Principal,
+    state: ServerState,
+) -> Result<Box<dyn Reply>, Infallible> {
+    if let Err(reply) = require_access_manager(&state, &room, &principal).await {
+        return Ok(Box::new(reply));
+    }
+
+    match db::query(&state.db_tx, move |conn| {
+        invite::revoke_invite(conn, &room, invite_id)
+    })
+    .await
+    {
+        Ok(true) => Ok(Box::new(StatusCode::NO_CONTENT)),
+        Ok(false) => Ok(Box::new(error_reply(
+            StatusCode::NOT_FOUND,
+            "Invite not found",
+        ))),
+        Err(e) => Ok(Box::new(internal_error(e))),
+    }
+}
+
+// Creates an incoming webhook into `room`, as an admin of the server or of the
+// room.
+#[utoipa::path(
+    post,
+    path = "/rooms/{name}/hooks",
+    tag = "hooks",
+    params(("name" = String, Path, description = "Name of the room")),
+    request_body = NewHook,
+    responses(
+        (status = 201, description = "The hook, with its `token`"),
+        (status = 400, description = "Invalid name", body = ErrorReply),
+        (status = 401, description = "Not logged in", body = ErrorReply),
+        (status = 403, description = "Not an admin of the server or room", body = ErrorReply),
+        (status = 404, description = "Room not found", body = ErrorReply),
+    ),
+    security(("bearer" = []), ("session" = [])),
+)]
+pub async fn create_hook(
+    room: String,
+    new_hook: NewHook,
+    principal: Principal,
+    state: ServerState,
+) -> Result<WithStatus<Json>, Infallible> {
+    let user_id = match require_access_manager(&state, &room, &principal).await {
+        Ok(user_id) => user_id,
+        Err(reply) => return Ok(reply),
+    };
+
+    if let Err(e) = new_hook.validate() {
+        return Ok(error_reply(StatusCode::BAD_REQUEST, &e.to_string()));
+    }
+
+    let created = db::query(&state.db_tx, move |conn| {
+        hook::create_hook(conn, &room, user_id, &new_hook)
+    })
+    .await;
+
+    match created {
+        Ok(Some((hook, token))) => Ok(reply::with_status(
+            reply::json(&json!({
+                "id": hook.id,
+                "room": hook.room,
+                "name": hook.name,
+                "user_id": hook.user_id,
+                "created_by": hook.created_by,
+                "created_at": hook.created_at,
+                "token": token,
+            })),
+            StatusCode::CREATED,
+        )),
+        Ok(None) => Ok(room_not_found()),
+        Err(e) => Ok(internal_error(e)),
+    }
+}
+
+// Lists the incoming webhooks into `room`, as an admin of the server or of the
+// room.
+#[utoipa::path(
+    get,
+    path = "/rooms/{name}/hooks",
+    tag = "hooks",
+    params(("name" = String, Path, description = "Name of the room")),
+    responses(
+        (status = 200, description = "Incoming webhooks into the room"),
+        (status = 401, description = "Not logged in", body = ErrorReply),
+        (status = 403, description = "Not an admin of the server or room", body = ErrorReply),
+    ),
+    security(("bearer" = []), ("session" = [])),
+)]
+pub async fn room_hooks(
+    room: String,
+    principal: Principal,
+    state: ServerState,
+) -> Result<WithStatus<Json>, Infallible> {
+    if let Err(reply) = require_access_manager(&state, &room, &principal).await {
+        return Ok(reply);
+    }
+
+    match db::query(&state.db_tx, move |conn| hook::room_hooks(conn, &room)).await {
+        Ok(hooks) => Ok(reply::with_status(reply::json(&hooks), StatusCode::OK)),
+        Err(e) => Ok(internal_error(e)),
+    }
+}
+
+// Revokes an incoming webhook into `room`, as an admin of the server or of the
+// room.
+#[utoipa::path(
+    delete,
+    path = "/rooms/{name}/hooks/{id}",
+    tag = "hooks",
+    params(
+        ("name" = String, Path, description = "Name of the room"),
+        ("id" = i64, Path, description = "ID of the hook"),
+    ),
+    responses(
+        (status = 204, description = "Hook revoked"),
+        (status = 401, description = "Not logged in", body = ErrorReply),
+        (status = 403, description = "Not an admin of the server or room", body = ErrorReply),
+        (status = 404, description = "Hook not found", body = ErrorReply),
+    ),
+    security(("bearer" = []), ("session" = [])),
+)]
+pub async fn revoke_hook(
+    room: String,
+    hook_id: i64,
+    principal: Principal,
+    state: ServerState,
+) -> Result<Box<dyn Reply>, Infallible> {
+    if let Err(reply) = require_access_manager(&state, &room, &principal).await {
+        return Ok(Box::new(reply));
+    }
+
+    match db::query(&state.db_tx, move |conn| {
+        hook::revoke_hook(conn, &room, hook_id)
+    })
+    .await
+    {
+        Ok(true) => Ok(Box::new(StatusCode::NO_CONTENT)),
+        Ok(false) => Ok(Box::new(error_reply(
+            StatusCode::NOT_FOUND,
+            "Hook not found",
+        ))),
+        Err(e) => Ok(Box::new(internal_error(e))),
+    }
+}
+
+// Posts what a Slack incoming webhook was sent to the room of hook `token`,
+// as its bot. Replies are plain text, as Slack's are, so that integrations
+// checking for them keep working.
+#[utoipa::path(
+    post,
+    path = "/hooks/{token}",
+    tag = "hooks",
+    params(("token" = String, Path, description = "Token of the hook")),
+    request_body = SlackPayload,
+    responses(
+        (status = 200, description = "`ok`, as Slack replies", body = String),
+        (status = 400, description = "Invalid payload, one without text, or naming the bot after another user", body = String),
+        (status = 403, description = "`invalid_token`", body = String),
+    ),
+)]
+pub async fn post_hook(
+    token: String,
+    payload: Result<SlackPayload, serde_json::Error>,
+    state: ServerState,
+) -> Result<Box<dyn Reply>, Infallible> {
+    let text_reply = |text: &str, status| Box::new(reply::with_status(String::from(text), status));
+    let payload = match payload {
+        Ok(payload) => payload,
+        Err(_) => return Ok(text_reply("invalid_payload", StatusCode::BAD_REQUEST)),
+    };
+    let text = match payload.message() {
+        Some(text) => text,
+        None => return Ok(text_reply("no_text", StatusCode::BAD_REQUEST)),
+    };
+
+    let hook = match db::query(&state.db_tx, move |conn| hook::find_hook(conn, &token)).await {
+        Ok(Some(hook)) => hook,
+        Ok(None) => return Ok(text_reply("invalid_token", StatusCode::FORBIDDEN)),
+        Err(e) => return Ok(Box::new(internal_error(e))),
+    };
+
+    // Payloads may name the bot differently for each message, though not
+    // after anyone else. The name only goes with this message
+    let nick = payload
+        .username
+        .and_then(|username| validate_nickname(&username).ok())
+        .unwrap_or(hook.name);
+    let (user_id, taken_nick) = (hook.user_id, nick.clone());
+    match db::read(&state.db_tx, move |conn| {
+        db::nickname_taken(conn, user_id, &taken_nick)
+    })
+    .await
+    {
+        Ok(false) => {}
+        Ok(true) => return Ok(text_reply("username_taken", StatusCode::BAD_REQUEST)),
+        Err(e) => return Ok(Box::new(internal_error(e))),
+    }
+
+    let bot = match state.detached_user(&hook.room, hook.user_id).await {
+        Ok(bot) => bot,
+        Err(e) => return Ok(Box::new(internal_error(e))),
+    };
+    bot.nicks.write().await.insert(hook.user_id, nick);
+    let sent = bot
+        .send_message(&text, None, None, None, None, &state.rooms)
+        .await
+        .and_then(PostOutcome::accepted);
+    user::release_room(&hook.room, &state.rooms).await;
+
+    match sent {
+        Ok(()) => Ok(text_reply("ok", StatusCode::OK)),
+        Err(e) => Ok(text_reply(&e.to_string(), StatusCode::BAD_REQUEST)),
+    }
+}
+
+// Sends a message of the logged in user to `room`, as if over a connection to
+// it, for clients that cannot hold one, e.g. scripts and cron jobs. It is
+// persisted and relayed to everyone in the room like any other.
+#[utoipa::path(
+    post,
+    path = "/rooms/{name}/messages",
+    tag = "messages",
+    params(("name" = String, Path, description = "Name of the room")),
+    request_body = NewMessage,
+    responses(
+        (status = 201, description = "The message sent, with its `id` and `seq`"),
+        (status = 202, description = "Message handed over to the server hosting the room"),
+        (status = 400, description = "Invalid message", body = ErrorReply),
+        (status = 401, description = "Not logged in", body = ErrorReply),
+        (status = 403, description = "Muted, read-only room, or message refused by filters", body = ErrorReply),
+        (status = 404, description = "Room not found", body = ErrorReply),
+        (status = 429, description = "Too many messages", body = ErrorReply),
+    ),
+    security(("bearer" = []), ("session" = [])),
+)]
+pub async fn post_message(
+    room: String,
+    new_message: NewMessage,
+    principal: Principal,
+    state: ServerState,
+) -> Result<WithStatus<Json>, Infallible> {
+    let user_id = principal.user_id;
+
+    if let Err(e) = new_message.validate() {
+        return Ok(error_reply(StatusCode::BAD_REQUEST, &e.to_string()));
+    }
+
+    // Rooms the user may not join are not found, as if they did not exist
+    let room_name = room.clone();
+    match db::read(&state.db_tx, move |conn| {
+        Ok(room::settings(conn, &room_name)?.is_some()
+            && authz::may_join(conn, user_id, &room_name)?)
+    })
+    .await
+    {
+        Ok(true) => {}
+        Ok(false) => return Ok(room_not_found()),
+        Err(e) => return Ok(internal_error(e)),
+    }
+
+    let user = match state.detached_user(&room, user_id).await {
+        Ok(user) => user,
+        Err(e) => return Ok(internal_error(e)),
+    };
+    let sent = user
+        .send_message(
+            &new_message.text,
+            None,
+            new_message.ttl_secs,
+            None,
+            None,
+            &state.rooms,
+        )
+        .await;
+    user::release_room(&room, &state.rooms).await;
+
+    // Messages to rooms hosted by another server are handed over to it without
+    // an ID, and those of users flooding the room are dropped
+    match sent {
+        Ok(PostOutcome::Posted { id, seq }) => Ok(reply::with_status(
+            reply::json(&json!({ "id": id, "seq": seq, "room": room })),
+            StatusCode::CREATED,
+        )),
+        Ok(PostOutcome::Forwarded) => Ok(reply::with_status(
+            reply::json(&json!({ "room": room })),
+            StatusCode::ACCEPTED,
+        )),
+        Ok(PostOutcome::Flooded) => Ok(error_reply(
+            StatusCode::TOO_MANY_REQUESTS,
+            "Too many messages",
+        )),
+        Ok(PostOutcome::Refused(reason)) => Ok(error_reply(StatusCode::FORBIDDEN, &reason)),
+        Err(e) => Ok(internal_error(e)),
+    }
+}
+
+// Streams what is sent to `room` as server-sent events, for clients that only
+// read it and cannot hold a WebSocket, e.g. dashboards. They join the room as
+// over `/chat`, and leave it once they go away.
+#[utoipa::path(
+    get,
+    path = "/rooms/{name}/stream",
+    tag = "messages",
+    params(
+        ("name" = String, Path, description = "Name of the room"),
+        StreamQuery,
+        ("Last-Event-ID" = Option<i64>, Header, description = "ID of the last message received, when reconnecting"),
+    ),
+    responses(
+        (status = 200, description = "Events sent to the room", body = String, content_type = "text/event-stream"),
+        (status = 401, description = "Invalid token, or wrong password", body = ErrorReply),
+        (status = 403, description = "Not allowed in this room", body = ErrorReply),
+        (status = 404, description = "Room not found", body = ErrorReply),
+        (status = 503, description = "Room is full", body = ErrorReply),
+    ),
+    security((), ("bearer" = []), ("session" = [])),
+)]
+pub async fn room_stream(
+    room: String,
+    query: StreamQuery,
+    bearer_token: Option<String>,
+    last_event_id: Option<i64>,
+    addr: Option<IpAddr>,
+    session: Option<Session>,
+    state: ServerState,
+) -> Result<Box<dyn Reply>, Infallible> {
+    // Banned addresses are refused before anything is done on their behalf
+    if let Some(addr) = addr {
+        match db::query(&state.db_tx, move |conn| ip_ban::is_banned(conn, addr)).await {
+            Ok(false) => {}
+            Ok(true) => {
+                return Ok(Box::new(error_reply(
+                    StatusCode::FORBIDDEN,
+                    "Your address is banned",
+                )))
+            }
+            Err(e) => return Ok(Box::new(internal_error(e))),
+        }
+    }
+
+    let token = query.token.or(bearer_token);
+    let is_guest = token.is_none() && session.is_none();
+    if is_guest && state.config.guest_mode(&room) == GuestMode::Disabled {
+        return Ok(Box::new(error_reply(
+            StatusCode::FORBIDDEN,
+            "Guests may not join this room",
+        )));
+    }
+    let principal = match auth::connection_user(
+        &state.db_tx,
+        &state.jwt,
+        token,
+        session.as_ref(),
+        addr,
+    )
+    .await
+    {
+        Ok(Some(principal)) => principal,
+        Ok(None) => {
+            return Ok(Box::new(error_reply(
+                StatusCode::UNAUTHORIZED,
+                "Invalid token",
+            )))
+        }
+        Err(e) => return Ok(Box::new(internal_error(e))),
+    };
+    let read_scope = Scope::Read(Some(room.clone()));
+    if !principal.allows(&read_scope) {
+        return Ok(Box::new(missing_scope(&read_scope)));
+    }
+
+    // Guests are given a temporary nickname, as over `/chat`
+    let user_id = principal.user_id;
+    let nick = if is_guest {
+        db::query(&state.db_tx, move |conn| {
+            Ok(guest::reserve_guest_nickname(conn, user_id))
+        })
+        .await
+        .and_then(|nick| nick)
+        .map(Some)
+    } else {
+        db::query(&state.db_tx, move |conn| db::nickname(conn, user_id)).await
+    };
+    match nick {
+        Ok(Some(nick)) => {
+            state.nicks.write().await.insert(user_id, nick);
+        }
+        Ok(None) => {}
+        Err(e) => return Ok(Box::new(internal_error(e))),
+    }
+
+    let (user_tx, user_rx) = mpsc::unbounded_channel();
+    let user = state.gateway_user(&room, principal, is_guest, addr, user_tx);
+    let history_seq = match user
+        .send_history(state.config.history_limit, last_event_id.or(query.since))
+        .await
+    {
+        Ok(history_seq) => Some(history_seq),
+        Err(e) => {
+            eprintln!("Failed to send room history: {}", e);
+            None
+        }
+    };
+    let joined = user::add_user_to_room(
+        &user,
+        &state.rooms,
+        state.config.explicit_rooms,
+        state.config.room_capacity,
+        query.password,
+        history_seq,
+    )
+    .await;
+    let refusal = match joined {
+        Ok(Ok(Admission::Joined)) => None,
+        // Streams have no way to wait for approval
+        Ok(Ok(Admission::Pending(_))) => {
+            Some((StatusCode::FORBIDDEN, "Joining this room requires approval"))
+        }
+        Ok(Err(refusal)) => {
+            let status = match refusal {
+                Refusal::WrongPassword => StatusCode::UNAUTHORIZED,
+                Refusal::AccessDenied | Refusal::JoinRejected => StatusCode::FORBIDDEN,
+                Refusal::RoomNotFound => StatusCode::NOT_FOUND,
+                Refusal::RoomFull { .. } => StatusCode::SERVICE_UNAVAILABLE,
+            };
+            Some((status, refusal.reason()))
+        }
+        Err(e) => {
+            user::remove_user_from_room(&user, &state.rooms).await;
+            return Ok(Box::new(internal_error(e)));
+        }
+    };
+    if let Some((status, reason)) = refusal {
+        user::remove_user_from_room(&user, &state.rooms).await;
+        return Ok(Box::new(error_reply(status, reason)));
+    }
+    if let Err(e) = user.announce_join(&state.rooms).await {
+        eprintln!("Failed to announce joining {}: {}", room, e);
+    }
+
+    let events = sse::events(user, user_rx, is_guest, state);
+    Ok(Box::new(warp::sse::reply(
+        warp::sse::keep_alive().stream(events),
+    )))
+}
+
+// Runs a GraphQL query, as the logged in user if anyone is. What may only be
+// read when logged in resolves to errors otherwise.
+#[utoipa::path(
+    post,
+    path = "/graphql",
+    tag = "graphql",
+    request_body(content = Object, description = "A `query`, with optional `variables` and `operationName`"),
+    responses(
+        (status = 200, description = "The response, with any `errors`"),
+        (status = 401, description = "Invalid token", body = ErrorReply),
+        (status = 403, description = "Your address is banned", body = ErrorReply),
+    ),
+    security((), ("bearer" = []), ("session" = [])),
+)]
+pub async fn graphql(
+    bearer_token: Option<String>,
+    request: async_graphql::Request,
+    addr: Option<IpAddr>,
+    session: Option<Session>,
+    schema: ChatSchema,
+    state: ServerState,
+) -> Result<Box<dyn Reply>, Infallible> {
+    let viewer = match graphql_viewer(&state, bearer_token, addr, session.as_ref()).await {
+        Ok(viewer) => viewer,
+        Err(reply) => return Ok(Box::new(reply)),
+    };
+    let request = match viewer {
+        Some(viewer) => request.data(viewer),
+        None => request,
+    };
+
+    Ok(Box::new(reply::json(&schema.execute(request).await)))
+}
+
+// Upgrades a connection to a WebSocket serving GraphQL subscriptions, as the
+// logged in user if anyone is.
+pub async fn graphql_subscriptions(
+    ws: Ws,
+    protocols: Option<String>,
+    bearer_token: Option<String>,
+    addr: Option<IpAddr>,
+    session: Option<Session>,
+    schema: ChatSchema,
+    state: ServerState,
+) -> Result<Box<dyn Reply>, Infallible> {
+    // Clients list the protocols they speak, the first one known is spoken
+    let protocol = match protocols
+        .as_deref()
+        .unwrap_or_default()
+        .split(',')
+        .find_map(|protocol| protocol.trim().parse::<WebSocketProtocols>().ok())
+    {
+        Some(protocol) => protocol,
+        None => {
+            return Ok(Box::new(error_reply(
+                StatusCode::BAD_REQUEST,
+                "Expected the graphql-transport-ws or graphql-ws protocol",
+            )))
+        }
+    };
+
+    let viewer = match graphql_viewer(&state, bearer_token, addr, session.as_ref()).await {
+        Ok(viewer) => viewer,
+        Err(reply) => return Ok(Box::new(reply)),
+    };
+
+    let reply =
+        ws.on_upgrade(move |socket| graphql::serve(socket, protocol, viewer, addr, schema, state));
+    Ok(Box::new(reply::with_header(
+        reply,
+        "sec-websocket-protocol",
+        protocol.sec_websocket_protocol(),
+    )))
+}
+
+// Who a GraphQL request is made by, if anyone logged in. Banned addresses and
+// invalid tokens are refused.
+async fn graphql_viewer(
+    state: &ServerState,
+    token: Option<String>,
+    addr: Option<IpAddr>,
+    session: Option<&Session>,
+) -> Result<Option<Viewer>, WithStatus<Json>> {
+    if let Some(addr) = addr {
+        match db::query(&state.db_tx, move |conn| ip_ban::is_banned(conn, addr)).await {
+            Ok(false) => {}
+            Ok(true) => return Err(error_reply(StatusCode::FORBIDDEN, "Your address is banned")),
+            Err(e) => return Err(internal_error(e)),
+        }
+    }
+
+    let has_token = token.is_some();
+    match auth::request_user(&state.db_tx, &state.jwt, token, session).await {
+        Ok(Some(principal)) => Ok(Some(Viewer { principal, addr })),
+        Ok(None) if has_token => Err(error_reply(StatusCode::UNAUTHORIZED, "Invalid token")),
+        Ok(None) => Ok(None),
+        Err(e) => Err(internal_error(e)),
+    }
+}
+
+// Schedules a message of the logged in user to `room`, sent once its delay
+// has passed as if they sent it then, as a user who may join and post to it.
+#[utoipa::path(
+    post,
+    path = "/rooms/{name}/scheduled",
+    tag = "messages",
+    params(("name" = String, Path, description = "Name of the room")),
+    request_body = NewScheduledMessage,
+    responses(
+        (status = 201, description = "The scheduled message"),
+        (status = 400, description = "Invalid text or delay", body = ErrorReply),
+        (status = 401, description = "Not logged in", body = ErrorReply),
+        (status = 403, description = "Not allowed in this room", body = ErrorReply),
+        (status = 404, description = "Room not found", body = ErrorReply),
+        (status = 409, description = "Too many messages scheduled", body = ErrorReply),
+    ),
+    security(("bearer" = []), ("session" = [])),
+)]
+pub async fn schedule_message(
+    room: String,
+    new_message: NewScheduledMessage,
+    principal: Principal,
+    state: ServerState,
+) -> Result<WithStatus<Json>, Infallible> {
+    let user_id = principal.user_id;
+
+    if let Err(e) = new_message.validate() {
+        return Ok(error_reply(StatusCode::BAD_REQUEST, &e.to_string()));
+    }
+
+    // Rooms the user may not join are not found, as if they did not exist
+    match db::query(&state.db_tx, move |conn| {
+        if !authz::may_join(conn, user_id, &room)? {
+            return Ok(Ok(None));
+        }
+        Ok(schedule::schedule(conn, &room, user_id, &new_message))
+    })
+    .await
+    {
+        Ok(Ok(Some(scheduled))) => Ok(reply::with_status(
+            reply::json(&scheduled),
+            StatusCode::CREATED,
+        )),
+        Ok(Ok(None)) => Ok(room_not_found()),
+        Ok(Err(e)) => Ok(error_reply(StatusCode::CONFLICT, &e.to_string())),
+        Err(e) => Ok(internal_error(e)),
+    }
+}
+
+// Lists the messages the logged in user scheduled to `room` that are still
+// waiting to be sent, soonest first.
+#[utoipa::path(
+    get,
+    path = "/rooms/{name}/scheduled",
+    tag = "messages",
+    params(("name" = String, Path, description = "Name of the room")),
+    responses(
+        (status = 200, description = "Messages waiting to be sent, soonest first"),
+        (status = 401, description = "Not logged in", body = ErrorReply),
+        (status = 403, description = "Token lacks the `read` scope for the room", body = ErrorReply),
+    ),
+    security(("bearer" = []), ("session" = [])),
+)]
+pub async fn scheduled_messages(
+    room: String,
+    principal: Principal,
+    state: ServerState,
+) -> Result<WithStatus<Json>, Infallible> {
+    let user_id = principal.user_id;
+
+    match db::query(&state.db_tx, move |conn| {
+        schedule::scheduled_messages(conn, &room, user_id)
+    })
+    .await
+    {
+        Ok(scheduled) => Ok(reply::with_status(reply::json(&scheduled), StatusCode::OK)),
+        Err(e) => Ok(internal_error(e)),
+    }
+}
+
+// Cancels a message the logged in user scheduled to `room`, before it is sent.
+#[utoipa::path(
+    delete,
+    path = "/rooms/{name}/scheduled/{id}",
+    tag = "messages",
+    params(
+        ("name" = String, Path, description = "Name of the room"),
+        ("id" = i64, Path, description = "ID of the scheduled message"),
+    ),
+    responses(
+        (status = 204, description = "Scheduled message cancelled"),
+        (status = 401, description = "Not logged in", body = ErrorReply),
+        (status = 403, description = "Token lacks the `write` scope for the room", body = ErrorReply),
+        (status = 404, description = "Scheduled message not found", body = ErrorReply),
+    ),
+    security(("bearer" = []), ("session" = [])),
+)]
+pub async fn cancel_scheduled_message(
+    room: String,
+    scheduled_id: i64,
+    principal: Principal,
+    state: ServerState,
+) -> Result<Box<dyn Reply>, Infallible> {
+    let user_id = principal.user_id;
+
+    match db::query(&state.db_tx, move |conn| {
+        schedule::cancel(conn, &room, user_id, scheduled_id)
+    })
+    .await
+    {
+        Ok(true) => Ok(Box::new(StatusCode::NO_CONTENT)),
+        Ok(false) => Ok(Box::new(error_reply(
+            StatusCode::NOT_FOUND,
+            "Scheduled message not found",
+        ))),
+        Err(e) => Ok(Box::new(internal_error(e))),
+    }
+}
+
+// Kicks a user out of `room`, as a moderator of the server or of the room who
+// outranks them.
+#[utoipa::path(
+    post,
+    path = "/rooms/{name}/kick/{user_id}",
+    tag = "moderation",
+    params(
+        ("name" = String, Path, description = "Name of the room"),
+        ("user_id" = usize, Path, description = "ID of the user"),
+    ),
+    responses(
+        (status = 204, description = "User's connections to the room closed"),
+        (status = 401, description = "Not logged in", body = ErrorReply),
+        (status = 403, description = "Not a moderator of the server or room outranking the user", body = ErrorReply),
+    ),
+    security(("bearer" = []), ("session" = [])),
+)]
+pub async fn kick_user(
+    room: String,
+    user_id: usize,
+    principal: Principal,
+    state: ServerState,
+) -> Result<Box<dyn Reply>, Infallible> {
+    let kicked_by = match require_moderator_of(&state, &room, user_id, &principal).await {
+        Ok(kicked_by) => kicked_by,
+        Err(reply) => return Ok(Box::new(reply)),
+    };
+
+    kick(&room, user_id, kicked_by, &state.rooms).await;
+
+    Ok(Box::new(StatusCode::NO_CONTENT))
+}
+
+// Lists the users banned from `room`, as a moderator of the server or of the
+// room.
+#[utoipa::path(
+    get,
+    path = "/rooms/{name}/bans",
+    tag = "moderation",
+    params(("name" = String, Path, description = "Name of the room")),
+    responses(
+        (status = 200, description = "Users banned from the room"),
+        (status = 401, description = "Not logged in", body = ErrorReply),
+        (status = 403, description = "Not a moderator of the server or room", body = ErrorReply),
+    ),
+    security(("bearer" = []), ("session" = [])),
+)]
+pub async fn room_bans(
+    room: String,
+    principal: Principal,
+    state: ServerState,
+) -> Result<WithStatus<Json>, Infallible> {
+    list_sanctions(SanctionKind::Ban, room, principal, state).await
+}
+
+// Bans a user from `room`, closing their connections to it, as a moderator of
+// the server or of the room who outranks them.
+#[utoipa::path(
+    put,
+    path = "/rooms/{name}/bans/{user_id}",
+    tag = "moderation",
+    params(
+        ("name" = String, Path, description = "Name of the room"),
+        ("user_id" = usize, Path, description = "ID of the user"),
+    ),
+    request_body = NewSanction,
+    responses(
+        (status = 200, description = "The ban"),
+        (status = 400, description = "Invalid reason or duration", body = ErrorReply),
+        (status = 401, description = "Not logged in", body = ErrorReply),
+        (status = 403, description = "Not a moderator of the server or room outranking the user", body = ErrorReply),
+        (status = 404, description = "User not found", body = ErrorReply),
+    ),
+    security(("bearer" = []), ("session" = [])),
+)]
+pub async fn ban_user(
+    room: String,
+    user_id: usize,
+    new_sanction: NewSanction,
+    principal: Principal,
+    state: ServerState,
+) -> Result<WithStatus<Json>, Infallible> {
+    let kind = SanctionKind::Ban;
+    impose_sanction(kind, room, user_id, new_sanction, principal, state).await
+}
+
+// Lets a user back into `room`, as a moderator of the server or of the room.
+#[utoipa::path(
+    delete,
+    path = "/rooms/{name}/bans/{user_id}",
+    tag = "moderation",
+    params(
+        ("name" = String, Path, description = "Name of the room"),
+        ("user_id" = usize, Path, description = "ID of the user"),
+    ),
+    responses(
+        (status = 204, description = "User unbanned"),
+        (status = 401, description = "Not logged in", body = ErrorReply),
+        (status = 403, description = "Not a moderator of the server or room outranking the user", body = ErrorReply),
+        (status = 404, description = "User is not under a ban", body = ErrorReply),
+    ),
+    security(("bearer" = []), ("session" = [])),
+)]
+pub async fn unban_user(
+    room: String,
+    user_id: usize,
+    principal: Principal,
+    state: ServerState,
+) -> Result<Box<dyn Reply>, Infallible> {
+    lift_sanction(SanctionKind::Ban, room, user_id, principal, state).await
+}
+
+// Lists the users muted in `room`, as a moderator of the server or of the room.
+#[utoipa::path(
+    get,
+    path = "/rooms/{name}/mutes",
+    tag = "moderation",
+    params(("name" = String, Path, description = "Name of the room")),
+    responses(
+        (status = 200, description = "Users muted in the room"),
+        (status = 401, description = "Not logged in", body = ErrorReply),
+        (status = 403, description = "Not a moderator of the server or room", body = ErrorReply),
+    ),
+    security(("bearer" = []), ("session" = [])),
+)]
+pub async fn room_mutes(
+    room: String,
+    principal: Principal,
+    state: ServerState,
+) -> Result<WithStatus<Json>, Infallible> {
+    list_sanctions(SanctionKind::Mute, room, principal, state).await
+}
+
+// Keeps a user from posting in `room`, as a moderator of the server or of the
+// room who outranks them.
+#[utoipa::path(
+    put,
+    path = "/rooms/{name}/mutes/{user_id}",
+    tag = "moderation",
+    params(
+        ("name" = String, Path, description = "Name of the room"),
+        ("user_id" = usize, Path, description = "ID of the user"),
+    ),
+    request_body = NewSanction,
+    responses(
+        (status = 200, description = "The mute"),
+        (status = 400, description = "Invalid reason or duration", body = ErrorReply),
+        (status = 401, description = "Not logged in", body = ErrorReply),
+        (status = 403, description = "Not a moderator of the server or room outranking the user", body = ErrorReply),
+        (status = 404, description = "User not found", body = ErrorReply),
+    ),
+    security(("bearer" = []), ("session" = [])),
+)]
+pub async fn mute_user(
+    room: String,
+    user_id: usize,
+    new_sanction: NewSanction,
+    principal: Principal,
+    state: ServerState,
+) -> Result<WithStatus<Json>, Infallible> {
+    let kind = SanctionKind::Mute;
+    impose_sanction(kind, room, user_id, new_sanction, principal, state).await
+}
+
+// Lets a user post in `room` again, as a moderator of the server or of the room.
+#[utoipa::path(
+    delete,
+    path = "/rooms/{name}/mutes/{user_id}",
+    tag = "moderation",
+    params(
+        ("name" = String, Path, description = "Name of the room"),
+        ("user_id" = usize, Path, description = "ID of the user"),
+    ),
+    responses(
+        (status = 204, description = "User unmuted"),
+        (status = 401, description = "Not logged in", body = ErrorReply),
+        (status = 403, description = "Not a moderator of the server or room outranking the user", body = ErrorReply),
+        (status = 404, description = "User is not under a mute", body = ErrorReply),
+    ),
+    security(("bearer" = []), ("session" = [])),
+)]
+pub async fn unmute_user(
+    room: String,
+    user_id: usize,
+    principal: Principal,
+    state: ServerState,
+) -> Result<Box<dyn Reply>, Infallible> {
+    lift_sanction(SanctionKind::Mute, room, user_id, principal, state).await
+}
+
+// Lists the users shadow-banned in `room`, as a moderator of the server or of
+// the room.
+#[utoipa::path(
+    get,
+    path = "/rooms/{name}/shadow_bans",
+    tag = "moderation",
+    params(("name" = String, Path, description = "Name of the room")),
+    responses(
+        (status = 200, description = "Users shadow-banned in the room"),
+        (status = 401, description = "Not logged in", body = ErrorReply),
+        (status = 403, description = "Not a moderator of the server or room", body = ErrorReply),
+    ),
+    security(("bearer" = []), ("session" = [])),
+)]
+pub async fn room_shadow_bans(
+    room: String,
+    principal: Principal,
+    state: ServerState,
+) -> Result<WithStatus<Json>, Infallible> {
+    list_sanctions(SanctionKind::ShadowBan, room, principal, state).await
+}
+
+// Keeps the messages a user posts in `room` from reaching anyone else, without
+// telling them, as a moderator of the server or of the room who outranks them.
+#[utoipa::path(
+    put,
+    path = "/rooms/{name}/shadow_bans/{user_id}",
+    tag = "moderation",
+    params(
+        ("name" = String, Path, description = "Name of the room"),
+        ("user_id" = usize, Path, description = "ID of the user"),
+    ),
+    request_body = NewSanction,
+    responses(
+        (status = 200, description = "The shadow ban"),
+        (status = 400, description = "Invalid reason or duration", body = ErrorReply),
+        (status = 401, description = "Not logged in", body = ErrorReply),
+        (status = 403, description = "Not a moderator of the server or room outranking the user", body = ErrorReply),
+        (status = 404, description = "User not found", body = ErrorReply),
+    ),
+    security(("bearer" = []), ("session" = [])),
+)]
+pub async fn shadow_ban_user(
+    room: String,
+    user_id: usize,
+    new_sanction: NewSanction,
+    principal: Principal,
+    state: ServerState,
+) -> Result<WithStatus<Json>, Infallible> {
+    let kind = SanctionKind::ShadowBan;
+    impose_sanction(kind, room, user_id, new_sanction, principal, state).await
+}
+
+// Lets the messages of a user reach `room` again, as a moderator of the server
+// or of the room.
+#[utoipa::path(
+    delete,
+    path = "/rooms/{name}/shadow_bans/{user_id}",
+    tag = "moderation",
+    params(
+        ("name" = String, Path, description = "Name of the room"),
+        ("user_id" = usize, Path, description = "ID of the user"),
+    ),
+    responses(
+        (status = 204, description = "Shadow ban lifted"),
+        (status = 401, description = "Not logged in", body = ErrorReply),
+        (status = 403, description = "Not a moderator of the server or room outranking the user", body = ErrorReply),
+        (status = 404, description = "User is not under a shadow ban", body = ErrorReply),
+    ),
+    security(("bearer" = []), ("session" = [])),
+)]
+pub async fn unshadow_ban_user(
+    room: String,
+    user_id: usize,
+    principal: Principal,
+    state: ServerState,
+) -> Result<Box<dyn Reply>, Infallible> {
+    lift_sanction(SanctionKind::ShadowBan, room, user_id, principal, state).await
+}
+
+// Lists the messages of `room` flagged by the word filter, newest first, as a
+// moderator of the server or of the room.
+#[utoipa::path(
+    get,
+    path = "/rooms/{name}/flagged",
+    tag = "moderation",
+    params(("name" = String, Path, description = "Name of the room")),
+    responses(
+        (status = 200, description = "Flagged messages of the room, newest first"),
+        (status = 401, description = "Not logged in", body = ErrorReply),
+        (status = 403, description = "Not a moderator of the server or room", body = ErrorReply),
+    ),
+    security(("bearer" = []), ("session" = [])),
+)]
+pub async fn flagged_messages(
+    room: String,
+    principal: Principal,
+    state: ServerState,
+) -> Result<WithStatus<Json>, Infallible> {
+    if let Err(reply) = require_moderator(&state, &room, &principal).await {
+        return Ok(reply);
+    }
+
+    match db::query(&state.db_tx, move |conn| db::flagged_messages(conn, &room)).await {
+        Ok(messages) => Ok(reply::with_status(reply::json(&messages), StatusCode::OK)),
+        Err(e) => Ok(internal_error(e)),
+    }
+}
+
+// Lists the messages of `room` which were deleted, most recently deleted
+// first, as a moderator of the server or of the room.
+#[utoipa::path(
+    get,
+    path = "/rooms/{name}/deleted",
+    tag = "moderation",
+    params(("name" = String, Path, description = "Name of the room")),
+    responses(
+        (status = 200, description = "Deleted messages of the room, most recently deleted first"),
+        (status = 401, description = "Not logged in", body = ErrorReply),
+        (status = 403, description = "Not a moderator of the server or room", body = ErrorReply),
+    ),
+    security(("bearer" = []), ("session" = [])),
+)]
+pub async fn deleted_messages(
+    room: String,
+    principal: Principal,
+    state: ServerState,
+) -> Result<WithStatus<Json>, Infallible> {
+    if let Err(reply) = require_room_permission(
+        &state,
+        &room,
+        &principal,
+        Action::ViewDeletedMessages,
+        "Only moderators can read the deleted messages of this room",
+    )
+    .await
+    {
+        return Ok(reply);
+    }
+
+    match db::read(&state.db_tx, move |conn| db::deleted_messages(conn, &room)).await {
+        Ok(messages) => Ok(reply::with_status(reply::json(&messages), StatusCode::OK)),
+        Err(e) => Ok(internal_error(e)),
+    }
+}
+
+// Lists every version of a message, as a moderator of the server or of its
+// room, deleted messages included.
+#[utoipa::path(
+    get,
+    path = "/messages/{id}/history",
+    tag = "messages",
+    params(("id" = i64, Path, description = "ID of the message")),
+    responses(
+        (status = 200, description = "Every version of the message"),
+        (status = 401, description = "Not logged in", body = ErrorReply),
+        (status = 403, description = "Not a moderator of the server or room", body = ErrorReply),
+        (status = 404, description = "Message not found", body = ErrorReply),
+    ),
+    security(("bearer" = []), ("session" = [])),
+)]
+pub async fn message_history(
+    message_id: i64,
+    principal: Principal,
+    state: ServerState,
+) -> Result<WithStatus<Json>, Infallible> {
+    let history = match db::read(&state.db_tx, move |conn| {
+        revision::history(conn, message_id)
+    })
+    .await
+    {
+        Ok(Some(history)) => history,
+        Ok(None) => return Ok(error_reply(StatusCode::NOT_FOUND, "Message not found")),
+        Err(e) => return Ok(internal_error(e)),
+    };
+
+    if let Err(reply) = require_room_permission(
+        &state,
+        &history.room,
+        &principal,
+        Action::ViewEditHistory,
+        "Only moderators can read the edit history of messages in this room",
+    )
+    .await
+    {
+        return Ok(reply);
+    }
+
+    Ok(reply::with_status(reply::json(&history), StatusCode::OK))
+}
+
+// Lists the users connected to `room`, by user ID, as a user who may join it.
+#[utoipa::path(
+    get,
+    path = "/rooms/{name}/online",
+    tag = "rooms",
+    params(("name" = String, Path, description = "Name of the room")),
+    responses(
+        (status = 200, description = "Users connected to the room"),
+        (status = 401, description = "Not logged in", body = ErrorReply),
+        (status = 403, description = "Not allowed in this room", body = ErrorReply),
+    ),
+    security(("bearer" = []), ("session" = [])),
+)]
+pub async fn online_users(
+    room: String,
+    principal: Principal,
+    state: ServerState,
+) -> Result<WithStatus<Json>, Infallible> {
+    if let Err(refusal) = require_may_join(&state, &room, &principal).await {
+        return Ok(refusal);
+    }
+
+    let online = user::online_users(&room, &state.rooms, &state.nicks).await;
+    Ok(reply::with_status(reply::json(&online), StatusCode::OK))
+}
+
+// Lists how far each user has read the messages of `room`, as a user who may
+// join it.
+#[utoipa::path(
+    get,
+    path = "/rooms/{name}/read_markers",
+    tag = "messages",
+    params(("name" = String, Path, description = "Name of the room")),
+    responses(
+        (status = 200, description = "How far each user has read the room"),
+        (status = 401, description = "Not logged in", body = ErrorReply),
+        (status = 403, description = "Not allowed in this room", body = ErrorReply),
+    ),
+    security(("bearer" = []), ("session" = [])),
+)]
+pub async fn read_markers(
+    room: String,
+    principal: Principal,
+    state: ServerState,
+) -> Result<WithStatus<Json>, Infallible> {
+    if let Err(refusal) = require_may_join(&state, &room, &principal).await {
+        return Ok(refusal);
+    }
+
+    match state
+        .read_room::<serde_json::Value>(&room, RoomRead::ReadMarkers)
+        .await
+    {
+        Ok(markers) => Ok(reply::with_status(reply::json(&markers), StatusCode::OK)),
+        Err(e) => Ok(internal_error(e)),
+    }
+}
+
+// Lists the messages of `room` sent within a span of time, or by a user, oldest
+// first, as a user who may join it.
+#[utoipa::path(
+    get,
+    path = "/rooms/{name}/messages",
+    tag = "messages",
+    params(
+        ("name" = String, Path, description = "Name of the room"),
+        HistoryQuery,
+    ),
+    responses(
+        (status = 200, description = "Messages of the room, oldest first"),
+        (status = 400, description = "Invalid `since` or `until`", body = ErrorReply),
+        (status = 401, description = "Not logged in", body = ErrorReply),
+        (status = 403, description = "Not allowed in this room", body = ErrorReply),
+    ),
+    security(("bearer" = []), ("session" = [])),
+)]
+pub async fn room_messages(
+    room: String,
+    query: HistoryQuery,
+    principal: Principal,
+    state: ServerState,
+) -> Result<WithStatus<Json>, Infallible> {
+    let filter = match HistoryFilter::new(
+        query.since.as_deref(),
+        query.until.as_deref(),
+        query.user_id,
+    ) {
+        Ok(filter) => filter,
+        Err(e) => return Ok(error_reply(StatusCode::BAD_REQUEST, &e.to_string())),
+    };
+    let limit = query
+        .limit
+        .unwrap_or(history::DEFAULT_LIMIT)
+        .clamp(1, history::MAX_LIMIT);
+    let user_id = match require_may_join(&state, &room, &principal).await {
+        Ok(user_id) => user_id,
+        Err(refusal) => return Ok(refusal),
+    };
+
+    let read = RoomRead::Messages {
+        viewer: user_id,
+        filter,
+        limit,
+        offset: query.offset,
+    };
+    match state.read_room::<serde_json::Value>(&room, read).await {
+        Ok(page) => Ok(reply::with_status(reply::json(&page), StatusCode::OK)),
+        Err(e) => Ok(internal_error(e)),
+    }
+}
+
+// Streams the whole history of `room`, oldest first, as an admin of the room,
+// so that it can be archived. Messages are read a page at a time, as the
+// client takes them.
+#[utoipa::path(
+    get,
+    path = "/rooms/{name}/export",
+    tag = "messages",
+    params(
+        ("name" = String, Path, description = "Name of the room"),
+        ExportQuery,
+    ),
+    responses(
+        (status = 200, description = "The whole history of the room, oldest first", content(("application/json"), ("text/csv"))),
+        (status = 401, description = "Not logged in", body = ErrorReply),
+        (status = 403, description = "Not an admin of the server or room", body = ErrorReply),
+    ),
+    security(("bearer" = []), ("session" = [])),
+)]
+pub async fn export_room(
+    room: String,
+    query: ExportQuery,
+    principal: Principal,
+    state: ServerState,
+) -> Result<Box<dyn Reply>, Infallible> {
+    let user_id = match require_room_permission(
+        &state,
+        &room,
+        &principal,
+        Action::ExportHistory,
+        "Only admins can export this room",
+    )
+    .await
+    {
+        Ok(user_id) => user_id,
+        Err(reply) => return Ok(Box::new(reply)),
+    };
+
+    let format = query.format;
+    let file_name: String = room
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' => c,
+            _ => '_',
+        })
+        .collect();
+    let disposition = format!(
+        "attachment; filename=\"{}.{}\"",
+        file_name,
+        format.extension()
+    );
+
+    // Pages are read until one comes back short, each after the last message
+    // of the one before
+    let db_tx = state.db_tx.clone();
+    let pages = stream::try_unfold(Some(None), move |after: Option<Option<Cursor>>| {
+        let db_tx = db_tx.clone();
+        let room = room.clone();
+        async move {
+            let after = match after {
+                Some(after) => after,
+                None => return Ok(None),
+            };
+            let page = db::read(&db_tx, move |conn| {
+                export::room_messages(conn, &room, user_id, after.as_ref(), export::PAGE_SIZE)
+            })
+            .await?;
+            let next = match page.last() {
+                Some(last) if page.len() == export::PAGE_SIZE => {
+                    Some(Some((last.created_at.clone(), last.id)))
+                }
+                _ => None,
+            };
+            Ok::<_, anyhow::Error>(Some((page, next)))
+        }
+    });
+
+    let mut first = true;
+    let messages = pages.map_ok(move |page| {
+        let chunk: String = page
+            .iter()
+            .map(|message| {
+                let formatted = format.format(message, first);
+                first = false;
+                formatted
+            })
+            .collect();
+        Bytes::from(chunk)
+    });
+    let body = stream::once(async move { Ok(Bytes::from(format.header())) })
+        .chain(messages)
+        .chain(stream::once(
+            async move { Ok(Bytes::from(format.footer())) },
+        ))
+        .inspect_err(|e| eprintln!("Failed to export room: {}", e));
+
+    match Response::builder()
+        .header(CONTENT_TYPE, format.content_type())
+        .header(CONTENT_DISPOSITION, disposition)
+        .body(Body::wrap_stream(body))
+    {
+        Ok(response) => Ok(Box::new(response)),
+        Err(e) => Ok(Box::new(internal_error(e.into()))),
+    }
+}
+
+// Searches the messages of `room` for those containing every word of the
+// query, best matches first, as a user who may join it.
+#[utoipa::path(
+    get,
+    path = "/rooms/{name}/search",
+    tag = "messages",
+    params(
+        ("name" = String, Path, description = "Name of the room"),
+        SearchQuery,
+    ),
+    responses(
+        (status = 200, description = "Messages containing every word of `q`, best matches first"),
+        (status = 400, description = "Invalid query", body = ErrorReply),
+        (status = 401, description = "Not logged in", body = ErrorReply),
+        (status = 403, description = "Not allowed in this room", body = ErrorReply),
+    ),
+    security(("bearer" = []), ("session" = [])),
+)]
+pub async fn search_room(
+    room: String,
+    query: SearchQuery,
+    principal: Principal,
+    state: ServerState,
+) -> Result<WithStatus<Json>, Infallible> {
+    let match_query = match search::match_query(&query.q) {
+        Ok(match_query) => match_query,
+        Err(e) => return Ok(error_reply(StatusCode::BAD_REQUEST, &e.to_string())),
+    };
+    let limit = query
+        .limit
+        .unwrap_or(search::DEFAULT_LIMIT)
+        .clamp(1, search::MAX_LIMIT);
+    let user_id = match require_may_join(&state, &room, &principal).await {
+        Ok(user_id) => user_id,
+        Err(refusal) => return Ok(refusal),
+    };
+
+    let read = RoomRead::Search {
+        viewer: user_id,
+        match_query,
+        limit,
+        offset: query.offset,
+    };
+    match state.read_room::<serde_json::Value>(&room, read).await {
+        Ok(results) => Ok(reply::with_status(reply::json(&results), StatusCode::OK)),
+        Err(e) => Ok(internal_error(e)),
+    }
+}
+
+// Reports a message of `room` to its moderators, as a user who may join it.
+#[utoipa::path(
+    post,
+    path = "/rooms/{name}/reports",
+    tag = "moderation",
+    params(("name" = String, Path, description = "Name of the room")),
+    request_body = NewReport,
+    responses(
+        (status = 201, description = "The report"),
+        (status = 400, description = "Reason too long", body = ErrorReply),
+        (status = 401, description = "Not logged in", body = ErrorReply),
+        (status = 403, description = "Not allowed in this room", body = ErrorReply),
+        (status = 404, description = "Message not found", body = ErrorReply),
+    ),
+    security(("bearer" = []), ("session" = [])),
+)]
+pub async fn report_message(
+    room: String,
+    new_report: NewReport,
+    principal: Principal,
+    state: ServerState,
+) -> Result<WithStatus<Json>, Infallible> {
+    let user_id = principal.user_id;
+
+    if let Err(e) = new_report.validate() {
+        return Ok(error_reply(StatusCode::BAD_REQUEST, &e.to_string()));
+    }
+
+    // Messages of rooms the user may not join are not found, as if they did
+    // not exist
+    match db::query(&state.db_tx, move |conn| {
+        if !authz::may_join(conn, user_id, &room)? {
+            return Ok(None);
+        }
+        report::file_report(conn, &room, user_id, &new_report)
+    })
+    .await
+    {
+        Ok(Some(report)) => Ok(reply::with_status(
+            reply::json(&report),
+            StatusCode::CREATED,
+        )),
+        Ok(None) => Ok(error_reply(StatusCode::NOT_FOUND, "Message not found")),
+        Err(e) => Ok(internal_error(e)),
+    }
+}
+
+// Lists the open reports of `room`, oldest first, as a moderator of the server
+// or of the room.
+#[utoipa::path(
+    get,
+    path = "/rooms/{name}/reports",
+    tag = "moderation",
+    params(("name" = String, Path, description = "Name of the room")),
+    responses(
+        (status = 200, description = "Open reports of the room, oldest first"),
+        (status = 401, description = "Not logged in", body = ErrorReply),
+        (status = 403, description = "Not a moderator of the server or room", body = ErrorReply),
+    ),
+    security(("bearer" = []), ("session" = [])),
+)]
+pub async fn room_reports(
+    room: String,
+    principal: Principal,
+    state: ServerState,
+) -> Result<WithStatus<Json>, Infallible> {
+    if let Err(reply) = require_moderator(&state, &room, &principal).await {
+        return Ok(reply);
+    }
+
+    match db::query(&state.db_tx, move |conn| report::open_reports(conn, &room)).await {
+        Ok(reports) => Ok(reply::with_status(reply::json(&reports), StatusCode::OK)),
+        Err(e) => Ok(internal_error(e)),
+    }
+}
+
+// Resolves an open report of `room`, as a moderator of the server or of the
+// room, by dismissing it, deleting the message, or banning its author (which
+// requires outranking them). Every open report of the message is resolved.
+#[utoipa::path(
+    put,
+    path = "/rooms/{name}/reports/{id}",
+    tag = "moderation",
+    params(
+        ("name" = String, Path, description = "Name of the room"),
+        ("id" = i64, Path, description = "ID of the report"),
+    ),
+    request_body = ReportResolution,
+    responses(
+        (status = 200, description = "The resolved report"),
+        (status = 400, description = "Invalid reason or duration", body = ErrorReply),
+        (status = 401, description = "Not logged in", body = ErrorReply),
+        (status = 403, description = "Not a moderator of the server or room", body = ErrorReply),
+        (status = 404, description = "Report not found", body = ErrorReply),
+        (status = 409, description = "Report was already resolved", body = ErrorReply),
+    ),
+    security(("bearer" = []), ("session" = [])),
+)]
+pub async fn resolve_report(
+    room: String,
+    report_id: i64,
+    resolution: ReportResolution,
+    principal: Principal,
+    state: ServerState,
+) -> Result<WithStatus<Json>, Infallible> {
+    let moderator_id = match require_moderator(&state, &room, &principal).await {
+        Ok(moderator_id) => moderator_id,
+        Err(reply) => return Ok(reply),
+    };
+
+    if let Err(e) = resolution.ban.validate() {
+        return Ok(error_reply(StatusCode::BAD_REQUEST, &e.to_string()));
+    }
+
+    let room_name = room.clone();
+    let report = match db::query(&state.db_tx, move |conn| {
+        report::report(conn, &room_name, report_id)
+    })
+    .await
+    {
+        Ok(Some(report)) if report.status == ReportStatus::Open => report,
+        Ok(Some(_)) => {
+            return Ok(error_reply(
+                StatusCode::CONFLICT,
+                "Report was already resolved",
+            ))
+        }
+        Ok(None) => return Ok(error_reply(StatusCode::NOT_FOUND, "Report not found")),
+        Err(e) => return Ok(internal_error(e)),
+    };
+
+    let message_id = report.message_id;
+    let status = match resolution.action {
+        ReportAction::Dismiss => ReportStatus::Dismissed,
+        ReportAction::Delete => {
+            let room_name = room.clone();
+            match db::change(&state.db_tx, move |conn| {
+                let deleted = db::delete_message(conn, message_id, &room_name, moderator_id, None)?;
+                let changes = if deleted {
+                    vec![MessageChange::Deleted {
+                        id: message_id,
+                        deleted_by: moderator_id,
+                    }]
+                } else {
+                    Vec::new()
+                };
+                Ok((deleted, changes))
+            })
+            .await
+            {
+                // Already deleted otherwise
+                Ok(true) => {
+                    let event = ServerEvent::Delete {
+                        id: message_id,
+                        room: room.clone(),
+                        deleted_by: moderator_id,
+                    };
+                    broadcast_to_room(&room, &state.rooms, &event).await;
+                }
+                Ok(false) => {}
+                Err(e) => return Ok(internal_error(e)),
+            }
+            ReportStatus::Actioned
+        }
+        ReportAction::Ban => {
+            let author_id = report.author_id;
+            if let Err(reply) = require_moderator_of(&state, &room, author_id, &principal).await {
+                return Ok(reply);
+            }
+
+            let (room_name, ban) = (room.clone(), resolution.ban);
+            match db::query(&state.db_tx, move |conn| {
+                moderation::impose(
+                    conn,
+                    &room_name,
+                    author_id,
+                    SanctionKind::Ban,
+                    moderator_id,
+                    &ban,
+                )
+            })
+            .await
+            {
+                Ok(Some(sanction)) => announce_sanction(&sanction, &state.rooms).await,
+                Ok(None) => return Ok(error_reply(StatusCode::NOT_FOUND, "User not found")),
+                Err(e) => return Ok(internal_error(e)),
+            }
+            ReportStatus::Actioned
+        }
+    };
+
+    match db::query(&state.db_tx, move |conn| {
+        report::resolve(conn, message_id, status, moderator_id)?;
+        report::report(conn, &room, report_id)
+    })
+    .await
+    {
+        Ok(Some(report)) => Ok(reply::with_status(reply::json(&report), StatusCode::OK)),
+        Ok(None) => Ok(error_reply(StatusCode::NOT_FOUND, "Report not found")),
+        Err(e) => Ok(internal_error(e)),
+    }
+}
+
+async fn list_sanctions(
+    kind: SanctionKind,
+    room: String,
+    principal: Principal,
+    state: ServerState,
+) -> Result<WithStatus<Json>, Infallible> {
+    if let Err(reply) = require_moderator(&state, &room, &principal).await {
+        return Ok(reply);
+    }
+
+    match db::query(&state.db_tx, move |conn| {
+        moderation::sanctions(conn, &room, kind)
+    })
+    .await
+    {
+        Ok(sanctions) => Ok(reply::with_status(reply::json(&sanctions), StatusCode::OK)),
+        Err(e) => Ok(internal_error(e)),
+    }
+}
+
+async fn impose_sanction(
+    kind: SanctionKind,
+    room: String,
+    user_id: usize,
+    new_sanction: NewSanction,
+    principal: Principal,
+    state: ServerState,
+) -> Result<WithStatus<Json>, Infallible> {
+    let issued_by = match require_moderator_of(&state, &room, user_id, &principal).await {
+        Ok(issued_by) => issued_by,
+        Err(reply) => return Ok(reply),
+    };
+
+    if let Err(e) = new_sanction.validate() {
+        return Ok(error_reply(StatusCode::BAD_REQUEST, &e.to_string()));
+    }
+
+    match db::query(&state.db_tx, move |conn| {
+        moderation::impose(conn, &room, user_id, kind, issued_by, &new_sanction)
+    })
+    .await
+    {
+        Ok(Some(sanction)) => {
+            announce_sanction(&sanction, &state.rooms).await;
+            Ok(reply::with_status(reply::json(&sanction), StatusCode::OK))
+        }
+        Ok(None) => Ok(error_reply(StatusCode::NOT_FOUND, "User not found")),
+        Err(e) => Ok(internal_error(e)),
+    }
+}
+
+async fn lift_sanction(
+    kind: SanctionKind,
+    room: String,
+    user_id: usize,
+    principal: Principal,
+    state: ServerState,
+) -> Result<Box<dyn Reply>, Infallible> {
+    let lifted_by = match require_moderator(&state, &room, &principal).await {
+        Ok(lifted_by) => lifted_by,
+        Err(reply) => return Ok(Box::new(reply)),
+    };
+
+    let room_name = room.clone();
+    match db::query(&state.db_tx, move |conn| {
+        moderation::lift(conn, &room_name, user_id, kind)
+    })
+    .await
+    {
+        Ok(true) => {}
+        Ok(false) => {
+            return Ok(Box::new(error_reply(
+                StatusCode::NOT_FOUND,
+                &format!("User is not under a {} in this room", kind),
+            )))
+        }
+        Err(e) => return Ok(Box::new(internal_error(e))),
+    }
+
+    // Shadow bans were never announced, so neither is lifting them
+    if kind == SanctionKind::ShadowBan {
+        return Ok(Box::new(StatusCode::NO_CONTENT));
+    }
+
+    let event = ServerEvent::SanctionLifted {
+        room: room.clone(),
+        user_id,
+        kind,
+        lifted_by,
+    };
+    broadcast_to_room(&room, &state.rooms, &event).await;
+
+    Ok(Box::new(StatusCode::NO_CONTENT))
+}
+
+// Lists the banned ranges of addresses. Only reachable with the admin token.
+#[utoipa::path(
+    get,
+    path = "/admin/ip_bans",
+    tag = "admin",
+    responses(
+        (status = 200, description = "Banned ranges of addresses"),
+        (status = 401, description = "Missing or invalid admin credentials", body = ErrorReply),
+    ),
+    security(("admin_bearer" = []), ("admin_basic" = [])),
+)]
+pub async fn ip_bans(state: ServerState) -> Result<WithStatus<Json>, Infallible> {
+    match db::query(&state.db_tx, ip_ban::ip_bans).await {
+        Ok(bans) => Ok(reply::with_status(reply::json(&bans), StatusCode::OK)),
+        Err(e) => Ok(internal_error(e)),
+    }
+}
+
+// Bans a range of addresses, closing the connections opened from it. Only
+// reachable with the admin token.
+#[utoipa::path(
+    post,
+    path = "/admin/ip_bans",
+    tag = "admin",
+    request_body = NewIpBan,
+    responses(
+        (status = 201, description = "The ban"),
+        (status = 400, description = "Invalid reason", body = ErrorReply),
+        (status = 401, description = "Missing or invalid admin credentials", body = ErrorReply),
+        (status = 409, description = "Addresses are already banned", body = ErrorReply),
+    ),
+    security(("admin_bearer" = []), ("admin_basic" = [])),
+)]
+pub async fn ban_ip(new_ban: NewIpBan, state: ServerState) -> Result<WithStatus<Json>, Infallible> {
+    if let Err(e) = new_ban.validate() {
+        return Ok(error_reply(StatusCode::BAD_REQUEST, &e.to_string()));
+    }
+
+    match db::query(&state.db_tx, move |conn| ip_ban::ban(conn, &new_ban)).await {
+        Ok(Some(ban)) => {
+            disconnect_addresses(ban.cidr, &state.rooms).await;
+            Ok(reply::with_status(reply::json(&ban), StatusCode::CREATED))
+        }
+        Ok(None) => Ok(error_reply(
+            StatusCode::CONFLICT,
+            "Addresses are already banned",
+        )),
+        Err(e) => Ok(internal_error(e)),
+    }
+}
+
+// Lifts a ban of a range of addresses. Only reachable with the admin token.
+#[utoipa::path(
+    delete,
+    path = "/admin/ip_bans/{id}",
+    tag = "admin",
+    params(("id" = i64, Path, description = "ID of the ban")),
+    responses(
+        (status = 204, description = "Ban lifted"),
+        (status = 401, description = "Missing or invalid admin credentials", body = ErrorReply),
+        (status = 404, description = "Ban not found", body = ErrorReply),
+    ),
+    security(("admin_bearer" = []), ("admin_basic" = [])),
+)]
+pub async fn unban_ip(ban_id: i64, state: ServerState) -> Result<Box<dyn Reply>, Infallible> {
+    match db::query(&state.db_tx, move |conn| ip_ban::unban(conn, ban_id)).await {
+        Ok(true) => Ok(Box::new(StatusCode::NO_CONTENT)),
+        Ok(false) => Ok(Box::new(error_reply(
+            StatusCode::NOT_FOUND,
+            "Ban not found",
+        ))),
+        Err(e) => Ok(Box::new(internal_error(e))),
+    }
+}
+
+// Reports the size of the DB. Only reachable with the admin token.
+#[utoipa::path(
+    get,
+    path = "/admin/db",
+    tag = "admin",
+    responses(
+        (status = 200, description = "Size of the DB"),
+        (status = 401, description = "Missing or invalid admin credentials", body = ErrorReply),
+    ),
+    security(("admin_bearer" = []), ("admin_basic" = [])),
+)]
+pub async fn db_stats(state: ServerState) -> Result<WithStatus<Json>, Infallible> {
+    match db::query(&state.db_tx, maintenance::stats).await {
+        Ok(stats) => Ok(reply::with_status(reply::json(&stats), StatusCode::OK)),
+        Err(e) => Ok(internal_error(e)),
+    }
+}
+
+// Runs DB maintenance now rather than when next due, reporting what it did.
+// Only reachable with the admin token.
+#[utoipa::path(
+    post,
+    path = "/admin/db/maintenance",
+    tag = "admin",
+    responses(
+        (status = 200, description = "What maintaining the DB did"),
+        (status = 401, description = "Missing or invalid admin credentials", body = ErrorReply),
+    ),
+    security(("admin_bearer" = []), ("admin_basic" = [])),
+)]
+pub async fn maintain_db(state: ServerState) -> Result<WithStatus<Json>, Infallible> {
+    match db::maintain(&state.db_tx, state.config.vacuum_pages).await {
+        Ok(maintenance) => Ok(reply::with_status(
+            reply::json(&maintenance),
+            StatusCode::OK,
+        )),
+        Err(e) => Ok(internal_error(e)),
+    }
+}
+
+// Writes a backup of the DB, reporting where to. It is read from a snapshot of
+// the DB, while writes carry on. Only reachable with the admin token.
+#[utoipa::path(
+    post,
+    path = "/admin/db/backup",
+    tag = "admin",
+    responses(
+        (status = 201, description = "The backup taken"),
+        (status = 401, description = "Missing or invalid admin credentials", body = ErrorReply),
+    ),
+    security(("admin_bearer" = []), ("admin_basic" = [])),
+)]
+pub async fn backup_db(state: ServerState) -> Result<WithStatus<Json>, Infallible> {
+    let path = state.config.backup_path();
+    match db::read(&state.db_tx, move |conn| maintenance::backup(conn, &path)).await {
+        Ok(backup) => Ok(reply::with_status(
+            reply::json(&backup),
+            StatusCode::CREATED,
+        )),
+        Err(e) => Ok(internal_error(e)),
+    }
+}
+
+// Links `room` to the server authenticating with `bearer_token`, as one of
+// the `--federation-peer`s, relaying what is sent to the room to it and
+// posting what its users send back. The server joins the room as its own
+// account, so that access to rooms is granted to it as to any user.
+pub async fn federation_link(
+    room: String,
+    ws: Ws,
+    bearer_token: Option<String>,
+    state: ServerState,
+) -> Result<Box<dyn Reply>, Infallible> {
+    // Linking servers escape the room name
+    let room = match federation::decode_room(&room) {
+        Ok(room) => room,
+        Err(e) => {
+            return Ok(Box::new(error_reply(
+                StatusCode::BAD_REQUEST,
+                &e.to_string(),
+            )))
+        }
+    };
+    let peers = &state.config.federation_peers;
+    let server = match bearer_token
+        .as_deref()
+        .and_then(|token| federation::authenticate(peers, token))
+    {
+        Some(peer) => peer.name.clone(),
+        None => {
+            return Ok(Box::new(error_reply(
+                StatusCode::UNAUTHORIZED,
+                "Unknown federation peer",
+            )))
+        }
+    };
+
+    let name = server.clone();
+    let user_id = match db::query(&state.db_tx, move |conn| {
+        federation::server_user(conn, &name)
+    })
+    .await
+    {
+        Ok(user_id) => user_id,
+        Err(e) => return Ok(Box::new(internal_error(e))),
+    };
+    state.nicks.write().await.insert(user_id, server.clone());
+
+    Ok(Box::new(ws.on_upgrade(move |mut socket| async move {
+        // The link is a `User` of the room like any other, but for posting
+        // on behalf of the users of its server
+        let mut link = match state.detached_user(&room, user_id).await {
+            Ok(link) => link,
+            Err(e) => return eprintln!("Failed to link {} to {}: {}", room, server, e),
+        };
+        let (user_tx, user_rx) = mpsc::unbounded_channel();
+        link.conn_id = NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed);
+        link.user_tx = user_tx;
+
+        match join_room(&link, &mut socket, &state.rooms, true, None, None, None).await {
+            Ok(Ok(())) => {}
+            Ok(Err(refusal)) => return link.refuse(socket, &state.rooms, refusal).await,
+            Err(e) => return eprintln!("Failed to link {} to {}: {}", room, server, e),
+        }
+        if let Err(e) = link.announce_join(&state.rooms).await {
+            eprintln!("Failed to announce joining {}: {}", room, e);
+        }
+
+        federation::serve(link, server, socket, user_rx, state).await
+    })))
+}
+
+// Tells a peer who is connected to this instance, by room. Only reachable
+// with the cluster secret.
+pub async fn cluster_membership(state: ServerState) -> Result<WithStatus<Json>, Infallible> {
+    match &state.cluster {
+        Some(cluster) => {
+            let membership = cluster.membership(&state.rooms).await;
+            Ok(reply::with_status(reply::json(&membership), StatusCode::OK))
+        }
+        None => Ok(error_reply(StatusCode::NOT_FOUND, "Not clustered")),
+    }
+}
+
+// Delivers an event sent by a peer to the connections of its room or user.
+// Only reachable with the cluster secret.
+pub async fn cluster_deliver(
+    envelope: Envelope,
+    state: ServerState,
+) -> Result<Box<dyn Reply>, Infallible> {
+    match &state.cluster {
+        Some(cluster) => {
+            cluster.deliver(envelope, &state.rooms).await;
+            Ok(Box::new(StatusCode::NO_CONTENT))
+        }
+        None => Ok(Box::new(error_reply(
+            StatusCode::NOT_FOUND,
+            "Not clustered",
+        ))),
+    }
+}
+
+// Handles what a peer asks of this instance as the owner of a room, for a
+// user connected to the peer. Only reachable with the cluster secret.
+pub async fn cluster_room(
+    request: RoomRequest,
+    state: ServerState,
+) -> Result<WithStatus<Json>, Infallible> {
+    if state.cluster.is_none() {
+        return Ok(error_reply(StatusCode::NOT_FOUND, "Not clustered"));
+    }
+
+    let room_name = String::from(request.room());
+    let handled = handle_room_request(request, &state).await;
+    user::release_room(&room_name, &state.rooms).await;
+
+    match handled {
+        Ok(answer) => Ok(reply::with_status(reply::json(&answer), StatusCode::OK)),
+        Err(e) => Ok(error_reply(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            &e.to_string(),
+        )),
+    }
+}
+
+// Handles `request` as the owner of its room, returning the answer to it.
+async fn handle_room_request(
+    request: RoomRequest,
+    state: &ServerState,
+) -> Result<serde_json::Value, anyhow::Error> {
+    let answer = match request {
+        RoomRequest::Post {
+            room,
+            user_id,
+            sender,
+            post,
+        } => {
+            let nick = post.nick.clone();
+            let (user, _) = forwarded_user(state, &room, user_id, nick, sender).await?;
+            serde_json::to_value(user.publish(post, None, &state.rooms).await?)?
+        }
+        RoomRequest::Frame {
+            room,
+            user_id,
+            nick,
+            role,
+            principal,
+            sender,
+            frame,
+        } => {
+            let (mut user, user_rx) = forwarded_user(state, &room, user_id, nick, sender).await?;
+            user.granted_role = role;
+            user.principal = principal;
+            user.handle_forwarded(&frame, &state.rooms).await?;
+            serde_json::to_value(sent(user_rx, None))?
+        }
+        RoomRequest::History {
+            room,
+            user_id,
+            sender,
+            limit,
+            since,
+        } => {
+            let (user, user_rx) = forwarded_user(state, &room, user_id, None, sender).await?;
+            let last_seq = user.send_history(limit, since).await?;
+            serde_json::to_value(sent(user_rx, Some(last_seq)))?
+        }
+        RoomRequest::Missed {
+            room,
+            user_id,
+            sender,
+            after_seq,
+        } => {
+            let (user, user_rx) = forwarded_user(state, &room, user_id, None, sender).await?;
+            user.send_missed(after_seq).await?;
+            serde_json::to_value(sent(user_rx, None))?
+        }
+        RoomRequest::CrossPost {
+            room,
+            user_id,
+            original,
+            text,
+            ttl_secs,
+        } => {
+            let user = owned_room_user(state, &room, user_id).await?;
+            let posted = user
+                .cross_post_to(&room, original, &text, ttl_secs, &state.rooms)
+                .await?;
+            serde_json::to_value(posted)?
+        }
+        RoomRequest::Read { room, read } => {
+            db::read(&state.db_tx, move |conn| read.run(conn, &room)).await?
+        }
+    };
+
+    Ok(answer)
+}
+
+// A `User` acting as `user_id` in `room_name`, owned by this instance, without
+// a connection. Rooms are recorded by the instances they are joined through,
+// which their owner may not have been.
+async fn owned_room_user(
+    state: &ServerState,
+    room_name: &str,
+    user_id: usize,
+) -> Result<User, anyhow::Error> {
+    let name = String::from(room_name);
+    db::query(&state.db_tx, move |conn| {
+        room::record_room(conn, &name, user_id, false)
+    })
+    .await?;
+
+    state.detached_user(room_name, user_id).await
+}
+
+// A `User` acting as `user_id` in `room_name` for `sender`, a connection to a
+// peer, whose events are kept in the returned receiver to answer the peer.
+// Users are shown with the `nick` they have on the peer, if given.
+async fn forwarded_user(
+    state: &ServerState,
+    room_name: &str,
+    user_id: usize,
+    nick: Option<String>,
+    sender: RemoteConn,
+) -> Result<(User, UserRx), anyhow::Error> {
+    let mut user = owned_room_user(state, room_name, user_id).await?;
+    if let Some(nick) = nick {
+        user.nicks.write().await.insert(user_id, nick);
+    }
+    let (user_tx, user_rx) = mpsc::unbounded_channel();
+    user.user_tx = user_tx;
+    user.remote_conn = Some(sender);
+
+    Ok((user, user_rx))
+}
+
+// The events sent so far to a `User` acting for a peer's connection.
+fn sent(mut user_rx: UserRx, last_seq: Option<i64>) -> Sent {
+    let mut events = Vec::new();
+    while let Ok(msg) = user_rx.try_recv() {
+        if let Ok(event) = msg.to_str() {
+            events.push(String::from(event));
+        }
+    }
+
+    Sent { events, last_seq }
+}
+
+// Lists the members of `room`, as an admin of the server or of the room.
+#[utoipa::path(
+    get,
+    path = "/rooms/{name}/members",
+    tag = "rooms",
+    params(("name" = String, Path, description = "Name of the room")),
+    responses(
+        (status = 200, description = "Members of the room"),
+        (status = 401, description = "Not logged in", body = ErrorReply),
+        (status = 403, description = "Not an admin of the server or room", body = ErrorReply),
+    ),
+    security(("bearer" = []), ("session" = [])),
+)]
+pub async fn room_members(
+    room: String,
+    principal: Principal,
+    state: ServerState,
+) -> Result<WithStatus<Json>, Infallible> {
+    if let Err(reply) = require_access_manager(&state, &room, &principal).await {
+        return Ok(reply);
+    }
+
+    match db::query(&state.db_tx, move |conn| room::members(conn, &room)).await {
+        Ok(members) => Ok(reply::with_status(reply::json(&members), StatusCode::OK)),
+        Err(e) => Ok(internal_error(e)),
+    }
+}
+
+// Invites a user into `room`, as an admin of the server or of the room.
+#[utoipa::path(
+    post,
+    path = "/rooms/{name}/members",
+    tag = "rooms",
+    params(("name" = String, Path, description = "Name of the room")),
+    request_body = MemberInvite,
+    responses(
+        (status = 200, description = "The membership"),
+        (status = 401, description = "Not logged in", body = ErrorReply),
+        (status = 403, description = "Not an admin of the server or room", body = ErrorReply),
+        (status = 404, description = "User or room not found", body = ErrorReply),
+    ),
+    security(("bearer" = []), ("session" = [])),
+)]
+pub async fn invite_room_member(
+    room: String,
+    invite: MemberInvite,
+    principal: Principal,
+    state: ServerState,
+) -> Result<WithStatus<Json>, Infallible> {
+    let invited_by = match require_access_manager(&state, &room, &principal).await {
+        Ok(user_id) => user_id,
+        Err(reply) => return Ok(reply),
+    };
+
+    let user_id = invite.user_id;
+    match db::query(&state.db_tx, move |conn| {
+        room::add_member(conn, &room, user_id, Some(invited_by))
+    })
+    .await
+    {
+        Ok(Some(member)) => Ok(reply::with_status(reply::json(&member), StatusCode::OK)),
+        Ok(None) => Ok(error_reply(StatusCode::NOT_FOUND, "User or room not found")),
+        Err(e) => Ok(internal_error(e)),
+    }
+}
+
+// Takes a user out of the members of `room`, as an admin of the server or of
+// the room. Their connections are closed if the room no longer lets them in.
+#[utoipa::path(
+    delete,
+    path = "/rooms/{name}/members/{user_id}",
+    tag = "rooms",
+    params(
+        ("name" = String, Path, description = "Name of the room"),
+        ("user_id" = usize, Path, description = "ID of the member"),
+    ),
+    responses(
+        (status = 204, description = "User taken out of the members"),
+        (status = 401, description = "Not logged in", body = ErrorReply),
+        (status = 403, description = "Not an admin of the server or room", body = ErrorReply),
+        (status = 404, description = "User is not a member of this room", body = ErrorReply),
+    ),
+    security(("bearer" = []), ("session" = [])),
+)]
+pub async fn remove_room_member(
+    room: String,
+    user_id: usize,
+    principal: Principal,
+    state: ServerState,
+) -> Result<Box<dyn Reply>, Infallible> {
+    if let Err(reply) = require_access_manager(&state, &room, &principal).await {
+        return Ok(Box::new(reply));
+    }
+
+    let room_name = room.clone();
+    match db::query(&state.db_tx, move |conn| {
+        room::remove_member(conn, &room_name, user_id)
+    })
+    .await
+    {
+        Ok(true) => {}
+        Ok(false) => {
+            return Ok(Box::new(error_reply(
+                StatusCode::NOT_FOUND,
+                "User is not a member of this room",
+            )))
+        }
+        Err(e) => return Ok(Box::new(internal_error(e))),
+    }
+
+    if let Err(e) = enforce_access(&room, &state.rooms, &state.db_tx).await {
+        return Ok(Box::new(internal_error(e)));
+    }
+
+    Ok(Box::new(StatusCode::NO_CONTENT))
+}
+
+// Takes a user off the access control list of `room`, as an admin of the
+// server or of the room.
+#[utoipa::path(
+    delete,
+    path = "/rooms/{name}/acl/{user_id}",
+    tag = "rooms",
+    params(
+        ("name" = String, Path, description = "Name of the room"),
+        ("user_id" = usize, Path, description = "ID of the user"),
+    ),
+    responses(
+        (status = 204, description = "User taken off the access control list"),
+        (status = 401, description = "Not logged in", body = ErrorReply),
+        (status = 403, description = "Not an admin of the server or room", body = ErrorReply),
+        (status = 404, description = "User is not on the access control list", body = ErrorReply),
+    ),
+    security(("bearer" = []), ("session" = [])),
+)]
+pub async fn remove_room_access(
+    room: String,
+    user_id: usize,
+    principal: Principal,
+    state: ServerState,
+) -> Result<Box<dyn Reply>, Infallible> {
+    if let Err(reply) = require_access_manager(&state, &room, &principal).await {
+        return Ok(Box::new(reply));
+    }
+
+    let room_name = room.clone();
+    match db::query(&state.db_tx, move |conn| {
+        authz::remove_access(conn, &room_name, user_id)
+    })
+    .await
+    {
+        Ok(true) => {}
+        Ok(false) => {
+            return Ok(Box::new(error_reply(
+                StatusCode::NOT_FOUND,
+                "User is not on the access control list",
+            )))
+        }
+        Err(e) => return Ok(Box::new(internal_error(e))),
+    }
+
+    // Removing the last allowed user opens the room up, but removing one of
+    // several keeps them out
+    if let Err(e) = enforce_access(&room, &state.rooms, &state.db_tx).await {
+        return Ok(Box::new(internal_error(e)));
+    }
+
+    Ok(Box::new(StatusCode::NO_CONTENT))
+}
+
+// Lists the roles given in `room`, as a user who may join it.
+#[utoipa::path(
+    get,
+    path = "/rooms/{name}/roles",
+    tag = "rooms",
+    params(("name" = String, Path, description = "Name of the room")),
+    responses(
+        (status = 200, description = "Roles given in the room"),
+        (status = 401, description = "Not logged in", body = ErrorReply),
+        (status = 403, description = "Not allowed in this room", body = ErrorReply),
+    ),
+    security(("bearer" = []), ("session" = [])),
+)]
+pub async fn room_roles(
+    room: String,
+    principal: Principal,
+    state: ServerState,
+) -> Result<WithStatus<Json>, Infallible> {
+    if let Err(reply) = require_may_join(&state, &room, &principal).await {
+        return Ok(reply);
+    }
+
+    match db::query(&state.db_tx, move |conn| authz::room_roles(conn, &room)).await {
+        Ok(roles) => Ok(reply::with_status(reply::json(&roles), StatusCode::OK)),
+        Err(e) => Ok(internal_error(e)),
+    }
+}
+
+// Gives a user a role in `room`, as an admin of the server or of the room.
+#[utoipa::path(
+    put,
+    path = "/rooms/{name}/roles/{user_id}",
+    tag = "rooms",
+    params(
+        ("name" = String, Path, description = "Name of the room"),
+        ("user_id" = usize, Path, description = "ID of the user"),
+    ),
+    request_body = RoleUpdate,
+    responses(
+        (status = 200, description = "The role given"),
+        (status = 401, description = "Not logged in", body = ErrorReply),
+        (status = 403, description = "Not an admin of the server or room", body = ErrorReply),
+        (status = 404, description = "User not found", body = ErrorReply),
+    ),
+    security(("bearer" = []), ("session" = [])),
+)]
+pub async fn set_room_role(
+    room: String,
+    user_id: usize,
+    update: RoleUpdate,
+    principal: Principal,
+    state: ServerState,
+) -> Result<WithStatus<Json>, Infallible> {
+    let request_user = principal.user_id;
+
+    match permits(
+        &state,
+        request_user,
+        Some(room.clone()),
+        Action::AssignRoomRoles,
+    )
+    .await
+    {
+        Ok(true) => {}
+        Ok(false) => {
+            return Ok(error_reply(
+                StatusCode::FORBIDDEN,
+                "Only admins can give roles in this room",
+            ))
+        }
+        Err(e) => return Ok(internal_error(e)),
+    }
+
+    let (role, room_name) = (update.role, room.clone());
+    match db::query(&state.db_tx, move |conn| {
+        authz::set_room_role(conn, &room_name, user_id, role)
+    })
+    .await
+    {
+        Ok(true) => Ok(reply::with_status(
+            reply::json(&json!({ "user_id": user_id, "room": room, "role": role })),
+            StatusCode::OK,
+        )),
+        Ok(false) => Ok(error_reply(StatusCode::NOT_FOUND, "User not found")),
+        Err(e) => Ok(internal_error(e)),
+    }
+}
+
+// Lists the sessions of a user, as that user, along with the number of
+// connections opened with each.
+#[utoipa::path(
+    get,
+    path = "/users/{id}/sessions",
+    tag = "users",
+    params(("id" = usize, Path, description = "ID of the user")),
+    responses(
+        (status = 200, description = "Sessions of the user"),
+        (status = 401, description = "Not logged in", body = ErrorReply),
+        (status = 403, description = "Not this user", body = ErrorReply),
+    ),
+    security(("bearer" = []), ("session" = [])),
+)]
+pub async fn user_sessions(
+    user_id: usize,
+    principal: Principal,
+    state: ServerState,
+) -> Result<WithStatus<Json>, Infallible> {
+    if principal.user_id != user_id {
+        return Ok(error_reply(
+            StatusCode::FORBIDDEN,
+            "Can only list your own sessions",
+        ));
+    }
+
+    let mut sessions =
+        match db::query(&state.db_tx, move |conn| auth::user_sessions(conn, user_id)).await {
+            Ok(sessions) => sessions,
+            Err(e) => return Ok(internal_error(e)),
+        };
+
+    let connections = session_connections(user_id, &state.rooms).await;
+    for session in sessions.iter_mut() {
+        session.connections = connections.get(&session.id).copied().unwrap_or(0);
+    }
+
+    Ok(reply::with_status(reply::json(&sessions), StatusCode::OK))
+}
+
+// Ends one of the requesting user's sessions, closing the connections opened
+// with it.
+#[utoipa::path(
+    delete,
+    path = "/sessions/{id}",
+    tag = "users",
+    params(("id" = String, Path, description = "ID of the session")),
+    responses(
+        (status = 204, description = "Session ended"),
+        (status = 401, description = "Not logged in", body = ErrorReply),
+        (status = 403, description = "Token lacks the `admin` scope", body = ErrorReply),
+        (status = 404, description = "Session not found", body = ErrorReply),
+    ),
+    security(("bearer" = []), ("session" = [])),
+)]
+pub async fn revoke_session(
+    session_id: String,
+    principal: Principal,
+    state: ServerState,
+) -> Result<Box<dyn Reply>, Infallible> {
+    let user_id = principal.user_id;
+
+    let revoked_id = session_id.clone();
+    let revoked = db::query(&state.db_tx, move |conn| {
+        auth::revoke_session(conn, user_id, &revoked_id)
+    })
+    .await;
+
+    match revoked {
+        Ok(true) => {
+            disconnect_session(&session_id, &state.rooms).await;
+            Ok(Box::new(StatusCode::NO_CONTENT))
+        }
+        Ok(false) => Ok(Box::new(error_reply(
+            StatusCode::NOT_FOUND,
+            "Session not found",
+        ))),
+        Err(e) => Ok(Box::new(internal_error(e))),
+    }
+}
+
+// Issues a new API token to a user, as that user. The token is only ever
+// returned here.
+#[utoipa::path(
+    post,
+    path = "/users/{id}/tokens",
+    tag = "users",
+    params(("id" = usize, Path, description = "ID of the user")),
+    request_body = NewApiToken,
+    responses(
+        (status = 201, description = "The token, only ever shown once"),
+        (status = 400, description = "Invalid name or scopes", body = ErrorReply),
+        (status = 401, description = "Not logged in", body = ErrorReply),
+        (status = 403, description = "Not this user", body = ErrorReply),
+    ),
+    security(("bearer" = []), ("session" = [])),
+)]
+pub async fn create_api_token(
+    user_id: usize,
+    new_token: NewApiToken,
+    principal: Principal,
+    state: ServerState,
+) -> Result<WithStatus<Json>, Infallible> {
+    if principal.user_id != user_id {
+        return Ok(error_reply(
+            StatusCode::FORBIDDEN,
+            "Can only create your own API tokens",
+        ));
+    }
+
+    if let Err(e) = new_token.validate() {
+        return Ok(error_reply(StatusCode::BAD_REQUEST, &e.to_string()));
+    }
+
+    let created = db::query(&state.db_tx, move |conn| {
+        api_token::create_token(conn, user_id, &new_token.name, &new_token.scopes)
+    })
+    .await;
+
+    match created {
+        Ok((api_token, token)) => Ok(reply::with_status(
+            reply::json(&json!({
+                "id": api_token.id,
+                "name": api_token.name,
+                "scopes": api_token.scopes,
+                "created_at": api_token.created_at,
+                "token": token,
+            })),
+            StatusCode::CREATED,
+        )),
+        Err(e) => Ok(internal_error(e)),
+    }
+}
+
+// Lists the API tokens of a user, as that user.
+#[utoipa::path(
+    get,
+    path = "/users/{id}/tokens",
+    tag = "users",
+    params(("id" = usize, Path, description = "ID of the user")),
+    responses(
+        (status = 200, description = "API tokens of the user"),
+        (status = 401, description = "Not logged in", body = ErrorReply),
+        (status = 403, description = "Not this user", body = ErrorReply),
+    ),
+    security(("bearer" = []), ("session" = [])),
+)]
+pub async fn api_tokens(
+    user_id: usize,
+    principal: Principal,
+    state: ServerState,
+) -> Result<WithStatus<Json>, Infallible> {
+    if principal.user_id != user_id {
+        return Ok(error_reply(
+            StatusCode::FORBIDDEN,
+            "Can only list your own API tokens",
+        ));
+    }
+
+    match db::query(&state.db_tx, move |conn| {
+        api_token::user_tokens(conn, user_id)
+    })
+    .await
+    {
+        Ok(tokens) => Ok(reply::with_status(reply::json(&tokens), StatusCode::OK)),
+        Err(e) => Ok(internal_error(e)),
+    }
+}
+
+// Revokes one of a user's API tokens, as that user. Connections already
+// opened with it stay open.
+#[utoipa::path(
+    delete,
+    path = "/users/{id}/tokens/{token_id}",
+    tag = "users",
+    params(
+        ("id" = usize, Path, description = "ID of the user"),
+        ("token_id" = i64, Path, description = "ID of the token"),
+    ),
+    responses(
+        (status = 204, description = "Token revoked"),
+        (status = 401, description = "Not logged in", body = ErrorReply),
+        (status = 403, description = "Not this user", body = ErrorReply),
+        (status = 404, description = "API token not found", body = ErrorReply),
+    ),
+    security(("bearer" = []), ("session" = [])),
+)]
+pub async fn revoke_api_token(
+    user_id: usize,
+    token_id: i64,
+    principal: Principal,
+    state: ServerState,
+) -> Result<Box<dyn Reply>, Infallible> {
+    if principal.user_id != user_id {
+        return Ok(Box::new(error_reply(
+            StatusCode::FORBIDDEN,
+            "Can only revoke your own API tokens",
+        )));
+    }
+
+    let revoked = db::query(&state.db_tx, move |conn| {
+        api_token::revoke_token(conn, user_id, token_id)
+    })
+    .await;
+
+    match revoked {
+        Ok(true) => Ok(Box::new(StatusCode::NO_CONTENT)),
+        Ok(false) => Ok(Box::new(error_reply(
+            StatusCode::NOT_FOUND,
+            "API token not found",
+        ))),
+        Err(e) => Ok(Box::new(internal_error(e))),
+    }
+}
+
+// Lists the keywords a user watches, as that user.
+#[utoipa::path(
+    get,
+    path = "/users/{id}/keywords",
+    tag = "users",
+    params(("id" = usize, Path, description = "ID of the user")),
+    responses(
+        (status = 200, description = "Keywords the user watches"),
+        (status = 401, description = "Not logged in", body = ErrorReply),
+        (status = 403, description = "Not this user", body = ErrorReply),
+    ),
+    security(("bearer" = []), ("session" = [])),
+)]
+pub async fn alert_keywords(
+    user_id: usize,
+    principal: Principal,
+    state: ServerState,
+) -> Result<WithStatus<Json>, Infallible> {
+    if principal.user_id != user_id {
+        return Ok(error_reply(
+            StatusCode::FORBIDDEN,
+            "Can only list your own keywords",
+        ));
+    }
+
+    match db::query(&state.db_tx, move |conn| alert::keywords(conn, user_id)).await {
+        Ok(keywords) => Ok(reply::with_status(
+            reply::json(&json!({ "keywords": keywords })),
+            StatusCode::OK,
+        )),
+        Err(e) => Ok(internal_error(e)),
+    }
+}
+
+// Replaces the keywords a user watches, as that user.
+#[utoipa::path(
+    put,
+    path = "/users/{id}/keywords",
+    tag = "users",
+    params(("id" = usize, Path, description = "ID of the user")),
+    request_body = KeywordUpdate,
+    responses(
+        (status = 200, description = "Keywords the user watches"),
+        (status = 400, description = "Too many or too long keywords", body = ErrorReply),
+        (status = 401, description = "Not logged in", body = ErrorReply),
+        (status = 403, description = "Not this user", body = ErrorReply),
+    ),
+    security(("bearer" = []), ("session" = [])),
+)]
+pub async fn set_alert_keywords(
+    user_id: usize,
+    update: KeywordUpdate,
+    principal: Principal,
+    state: ServerState,
+) -> Result<WithStatus<Json>, Infallible> {
+    if principal.user_id != user_id {
+        return Ok(error_reply(
+            StatusCode::FORBIDDEN,
+            "Can only set your own keywords",
+        ));
+    }
+
+    if let Err(e) = update.validate() {
+        return Ok(error_reply(StatusCode::BAD_REQUEST, &e.to_string()));
+    }
+
+    let updated = db::query(&state.db_tx, move |conn| {
+        alert::set_keywords(conn, user_id, &update.keywords)?;
+        alert::keywords(conn, user_id)
+    })
+    .await;
+
+    match updated {
+        Ok(keywords) => Ok(reply::with_status(
+            reply::json(&json!({ "keywords": keywords })),
+            StatusCode::OK,
+        )),
+        Err(e) => Ok(internal_error(e)),
+    }
+}
+
+// Lists the latest alerts of a user, newest first, as that user.
+#[utoipa::path(
+    get,
+    path = "/users/{id}/alerts",
+    tag = "users",
+    params(("id" = usize, Path, description = "ID of the user")),
+    responses(
+        (status = 200, description = "Latest alerts of the user, newest first"),
+        (status = 401, description = "Not logged in", body = ErrorReply),
+        (status = 403, description = "Not this user", body = ErrorReply),
+    ),
+    security(("bearer" = []), ("session" = [])),
+)]
+pub async fn alerts(
+    user_id: usize,
+    principal: Principal,
+    state: ServerState,
+) -> Result<WithStatus<Json>, Infallible> {
+    if principal.user_id != user_id {
+        return Ok(error_reply(
+            StatusCode::FORBIDDEN,
+            "Can only list your own alerts",
+        ));
+    }
+
+    match db::query(&state.db_tx, move |conn| alert::alerts(conn, user_id)).await {
+        Ok(alerts) => Ok(reply::with_status(reply::json(&alerts), StatusCode::OK)),
+        Err(e) => Ok(internal_error(e)),
+    }
+}
+
+// Lists the rooms a user changed the notification level of, as that user.
+#[utoipa::path(
+    get,
+    path = "/users/{id}/notifications",
+    tag = "users",
+    params(("id" = usize, Path, description = "ID of the user")),
+    responses(
+        (status = 200, description = "Notification levels of the user, by room"),
+        (status = 401, description = "Not logged in", body = ErrorReply),
+        (status = 403, description = "Not this user", body = ErrorReply),
+    ),
+    security(("bearer" = []), ("session" = [])),
+)]
+pub async fn notification_levels(
+    user_id: usize,
+    principal: Principal,
+    state: ServerState,
+) -> Result<WithStatus<Json>, Infallible> {
+    if principal.user_id != user_id {
+        return Ok(error_reply(
+            StatusCode::FORBIDDEN,
+            "Can only list your own notification levels",
+        ));
+    }
+
+    match db::query(&state.db_tx, move |conn| {
+        notification::levels(conn, user_id)
+    })
+    .await
+    {
+        Ok(levels) => Ok(reply::with_status(reply::json(&levels), StatusCode::OK)),
+        Err(e) => Ok(internal_error(e)),
+    }
+}
+
+// Sets which notifications a user gets of messages sent to `room`, as that
+// user.
+#[utoipa::path(
+    put,
+    path = "/users/{id}/notifications/{room}",
+    tag = "users",
+    params(
+        ("id" = usize, Path, description = "ID of the user"),
+        ("room" = String, Path, description = "Name of the room"),
+    ),
+    request_body = LevelUpdate,
+    responses(
+        (status = 200, description = "The notification level"),
+        (status = 401, description = "Not logged in", body = ErrorReply),
+        (status = 403, description = "Not this user", body = ErrorReply),
+    ),
+    security(("bearer" = []), ("session" = [])),
+)]
+pub async fn set_notification_level(
+    user_id: usize,
+    room: String,
+    update: LevelUpdate,
+    principal: Principal,
+    state: ServerState,
+) -> Result<WithStatus<Json>, Infallible> {
+    if principal.user_id != user_id {
+        return Ok(error_reply(
+            StatusCode::FORBIDDEN,
+            "Can only set your own notification levels",
+        ));
+    }
+
+    let (set_room, level) = (room.clone(), update.level);
+    match db::query(&state.db_tx, move |conn| {
+        notification::set_level(conn, user_id, &set_room, level)
+    })
+    .await
+    {
+        Ok(()) => Ok(reply::with_status(
+            reply::json(&json!({ "room": room, "level": level })),
+            StatusCode::OK,
+        )),
+        Err(e) => Ok(internal_error(e)),
+    }
+}
+
+// Lists the number of unread messages of each room the logged in user has a
+// read marker in, and may still join, by room name.
+#[utoipa::path(
+    get,
+    path = "/users/me/unread",
+    tag = "users",
+    responses(
+        (status = 200, description = "Unread messages of the logged in user, by room"),
+        (status = 401, description = "Not logged in", body = ErrorReply),
+        (status = 403, description = "Token lacks the `read:*` scope", body = ErrorReply),
+    ),
+    security(("bearer" = []), ("session" = [])),
+)]
+pub async fn unread_counts(
+    principal: Principal,
+    state: ServerState,
+) -> Result<WithStatus<Json>, Infallible> {
+    let user_id = principal.user_id;
+
+    let unread = db::query(&state.db_tx, move |conn| {
+        let mut joinable = Vec::new();
+        for count in db::unread_counts(conn, user_id)? {
+            if authz::may_join(conn, user_id, &count.room)? {
+                joinable.push(count);
+            }
+        }
+
+        Ok(joinable)
+    })
+    .await;
+
+    match unread {
+        Ok(unread) => Ok(reply::with_status(reply::json(&unread), StatusCode::OK)),
+        Err(e) => Ok(internal_error(e)),
+    }
+}
+
+// Starts a private conversation among the logged in user and the given users.
+#[utoipa::path(
+    post,
+    path = "/conversations",
+    tag = "conversations",
+    request_body = NewConversation,
+    responses(
+        (status = 201, description = "The conversation, with the name of its room"),
+        (status = 400, description = "Too many users", body = ErrorReply),
+        (status = 401, description = "Not logged in", body = ErrorReply),
+        (status = 403, description = "Token lacks the `admin` scope", body = ErrorReply),
+        (status = 404, description = "User not found", body = ErrorReply),
+    ),
+    security(("bearer" = []), ("session" = [])),
+)]
+pub async fn create_conversation(
+    new_conversation: NewConversation,
+    principal: Principal,
+    state: ServerState,
+) -> Result<WithStatus<Json>, Infallible> {
+    let user_id = principal.user_id;
+
+    if let Err(e) = new_conversation.validate(user_id) {
+        return Ok(error_reply(StatusCode::BAD_REQUEST, &e.to_string()));
+    }
+
+    match db::query(&state.db_tx, move |conn| {
+        conversation::create_conversation(conn, user_id, &new_conversation.user_ids)
+    })
+    .await
+    {
+        Ok(Some(conversation)) => Ok(reply::with_status(
+            reply::json(&conversation),
+            StatusCode::CREATED,
+        )),
+        Ok(None) => Ok(error_reply(StatusCode::NOT_FOUND, "User not found")),
+        Err(e) => Ok(internal_error(e)),
+    }
+}
+
+// Lists the conversations the logged in user takes part in, newest first.
+#[utoipa::path(
+    get,
+    path = "/conversations",
+    tag = "conversations",
+    responses(
+        (status = 200, description = "Conversations of the logged in user, newest first"),
+        (status = 401, description = "Not logged in", body = ErrorReply),
+        (status = 403, description = "Token lacks the `read:*` scope", body = ErrorReply),
+    ),
+    security(("bearer" = []), ("session" = [])),
+)]
+pub async fn conversations(
+    principal: Principal,
+    state: ServerState,
+) -> Result<WithStatus<Json>, Infallible> {
+    let user_id = principal.user_id;
+
+    match db::query(&state.db_tx, move |conn| {
+        conversation::conversations(conn, user_id)
+    })
+    .await
+    {
+        Ok(conversations) => Ok(reply::with_status(
+            reply::json(&conversations),
+            StatusCode::OK,
+        )),
+        Err(e) => Ok(internal_error(e)),
+    }
+}
+
+// Registers a new user with a username and password.
+#[utoipa::path(
+    post,
+    path = "/users/register",
+    tag = "auth",
+    request_body = Credentials,
+    responses(
+        (status = 201, description = "The user registered"),
+        (status = 400, description = "Invalid username, password or email", body = ErrorReply),
+        (status = 409, description = "Username is already taken", body = ErrorReply),
+    ),
+)]
+pub async fn register(
+    credentials: Credentials,
+    state: ServerState,
+) -> Result<WithStatus<Json>, Infallible> {
+    let Credentials {
+        username,
+        password,
+        email,
+        ..
+    } = credentials;
+
+    if let Err(e) = auth::validate_username(&username) {
+        return Ok(error_reply(StatusCode::BAD_REQUEST, &e.to_string()));
+    }
+    if let Err(e) = auth::validate_password(&password) {
+        return Ok(error_reply(StatusCode::BAD_REQUEST, &e.to_string()));
+    }
+    if let Some(Err(e)) = email.as_deref().map(auth::validate_email) {
+        return Ok(error_reply(StatusCode::BAD_REQUEST, &e.to_string()));
+    }
+
+    let password_hash =
+        match tokio::task::spawn_blocking(move || auth::hash_password(&password)).await {
+            Ok(Ok(password_hash)) => password_hash,
+            Ok(Err(e)) => return Ok(internal_error(e)),
+            Err(e) => return Ok(internal_error(e.into())),
+        };
+
+    let new_username = username.clone();
+    let created = db::query(&state.db_tx, move |conn| {
+        let user_id = auth::create_user(conn, &new_username, &password_hash)?;
+        if let (Some(user_id), Some(email)) = (user_id, email) {
+            auth::set_email(conn, user_id, &email)?;
+        }
+
+        Ok(user_id)
+    })
+    .await;
+
+    match created {
+        Ok(Some(user_id)) => Ok(reply::with_status(
+            reply::json(&json!({ "user_id": user_id, "username": username })),
+            StatusCode::CREATED,
+        )),
+        Ok(None) => Ok(error_reply(
+            StatusCode::CONFLICT,
+            "Username is already taken",
+        )),
+        Err(e) => Ok(internal_error(e)),
+    }
+}
+
+// Logs a user in, returning a JWT to connect to chat rooms with, and starting a
+// session held in a cookie.
+#[utoipa::path(
+    post,
+    path = "/users/login",
+    tag = "auth",
+    request_body = Credentials,
+    responses(
+        (status = 200, description = "A JWT `token`, along with a `session` cookie"),
+        (status = 401, description = "Invalid username, password or second factor code", body = ErrorReply),
+        (status = 403, description = "Admin accounts must enroll a second factor, or the address is banned", body = ErrorReply),
+        (status = 429, description = "Too many failed logins", body = ErrorReply),
+    ),
+)]
+pub async fn login(
+    credentials: Credentials,
+    user_agent: Option<String>,
+    addr: Option<IpAddr>,
+    state: ServerState,
+) -> Result<Box<dyn Reply>, Infallible> {
+    let Credentials {
+        username,
+        password,
+        code,
+        ..
+    } = credentials;
+    // Admins given on the command line are made admins once logged in
+    let listed_admin = state.config.admin_users.contains(&username);
+
+    let user_id = match authenticate(&state, username.clone(), password, addr).await {
+        Ok(Authentication::User(user_id)) => user_id,
+        Ok(Authentication::Invalid) => return Ok(Box::new(invalid_credentials())),
+        Ok(Authentication::LockedOut(locked_for)) => return Ok(Box::new(locked_out(locked_for))),
+        Ok(Authentication::Banned) => return Ok(Box::new(address_banned())),
+        Err(e) => return Ok(Box::new(internal_error(e))),
+    };
+
+    let (totp, role) = match db::query(&state.db_tx, move |conn| {
+        Ok((
+            totp::totp_state(conn, user_id)?,
+            authz::user_role(conn, user_id)?,
+        ))
+    })
+    .await
+    {
+        Ok(found) => found,
+        Err(e) => return Ok(Box::new(internal_error(e))),
+    };
+    let is_admin = listed_admin || role == Role::Admin;
+
+    // Users that enrolled a second factor must provide a code, which admins
+    // must have enrolled
+    match totp.secret {
+        Some(secret) => match check_code(&state, user_id, &secret, code).await {
+            Ok(true) => {}
+            Ok(false) => {
+                let counters = throttle::counters(&state.config, &username, addr);
+                if let Err(e) = login_failed(&state, counters).await {
+                    return Ok(Box::new(internal_error(e)));
+                }
+                return Ok(Box::new(invalid_code()));
+            }
+            Err(e) => return Ok(Box::new(internal_error(e))),
+        },
+        None if is_admin => {
+            return Ok(Box::new(error_reply(
+                StatusCode::FORBIDDEN,
+                "Admin accounts must enroll a second factor",
+            )))
+        }
+        None => {}
+    }
+
+    if let Err(e) = login_succeeded(&state, username).await {
+        return Ok(Box::new(internal_error(e)));
+    }
+
+    if listed_admin && role != Role::Admin {
+        if let Err(e) = db::query(&state.db_tx, move |conn| {
+            authz::set_user_role(conn, user_id, Role::Admin)
+        })
+        .await
+        {
+            return Ok(Box::new(internal_error(e)));
+        }
+    }
+
+    let token = match state.jwt.issue(user_id) {
+        Ok(token) => token,
+        Err(e) => return Ok(Box::new(internal_error(e))),
+    };
+
+    let session_ttl = state.session_ttl();
+    let session_token = match db::query(&state.db_tx, move |conn| {
+        auth::create_session(conn, user_id, session_ttl, user_agent.as_deref())
+    })
+    .await
+    {
+        Ok(session_token) => session_token,
+        Err(e) => return Ok(Box::new(internal_error(e))),
+    };
+
+    let body = reply::json(&json!({
+        "user_id": user_id,
+        "token": token,
+        "expires_in": state.jwt.ttl().as_secs(),
+    }));
+
+    Ok(Box::new(reply::with_header(
+        body,
+        SET_COOKIE,
+        auth::session_cookie(&session_token, session_ttl),
+    )))
+}
+
+// Issues a password reset token to a registered user, delivering it out of
+// band. Whether the user exists is not revealed.
+#[utoipa::path(
+    post,
+    path = "/users/forgot",
+    tag = "auth",
+    request_body = ForgotPassword,
+    responses(
+        (status = 202, description = "Reset token sent, if the user is registered"),
+    ),
+)]
+pub async fn forgot_password(
+    request: ForgotPassword,
+    state: ServerState,
+) -> Result<WithStatus<Json>, Infallible> {
+    let ttl = Duration::from_secs(state.config.reset_token_ttl_secs);
+    let issued = db::query(&state.db_tx, move |conn| {
+        let recipient = match reset::find_recipient(conn, &request.username)? {
+            Some(recipient) => recipient,
+            None => return Ok(None),
+        };
+        let token = reset::create_reset_token(conn, recipient.user_id, ttl)?;
+
+        Ok(Some((recipient, token)))
+    })
+    .await;
+
+    match issued {
+        Ok(Some((recipient, token))) => {
+            // Delivery may be slow, and its outcome is not reported either
+            let delivery = state.reset_delivery.clone();
+            tokio::spawn(async move {
+                if let Err(e) = delivery.deliver(&recipient, &token).await {
+                    eprintln!(
+                        "Failed to deliver password reset token to user {}: {}",
+                        recipient.user_id, e
+                    );
+                }
+            });
+        }
+        Ok(None) => {}
+        Err(e) => return Ok(internal_error(e)),
+    }
+
+    Ok(reply::with_status(
+        reply::json(&json!({})),
+        StatusCode::ACCEPTED,
+    ))
+}
+
+// Sets a new password with a reset token, ending every session of the user.
+#[utoipa::path(
+    post,
+    path = "/users/reset",
+    tag = "auth",
+    request_body = PasswordReset,
+    responses(
+        (status = 200, description = "Password reset"),
+        (status = 400, description = "Invalid password, or invalid or expired reset token", body = ErrorReply),
+    ),
+)]
+pub async fn reset_password(
+    request: PasswordReset,
+    state: ServerState,
+) -> Result<WithStatus<Json>, Infallible> {
+    let PasswordReset { token, password } = request;
+
+    if let Err(e) = auth::validate_password(&password) {
+        return Ok(error_reply(StatusCode::BAD_REQUEST, &e.to_string()));
+    }
+
+    let password_hash =
+        match tokio::task::spawn_blocking(move || auth::hash_password(&password)).await {
+            Ok(Ok(password_hash)) => password_hash,
+            Ok(Err(e)) => return Ok(internal_error(e)),
+            Err(e) => return Ok(internal_error(e.into())),
+        };
+
+    let reset = db::query(&state.db_tx, move |conn| {
+        reset::reset_password(conn, &token, &password_hash)
+    })
+    .await;
+
+    match reset {
+        Ok(Some(user_id)) => Ok(reply::with_status(
+            reply::json(&json!({ "user_id": user_id })),
+            StatusCode::OK,
+        )),
+        Ok(None) => Ok(error_reply(
+            StatusCode::BAD_REQUEST,
+            "Invalid or expired reset token",
+        )),
+        Err(e) => Ok(internal_error(e)),
+    }
+}
+
+// Ends the session of the cookie sent, closing the connections opened with it,
+// and clears it.
+#[utoipa::path(
+    post,
+    path = "/users/logout",
+    tag = "auth",
+    responses(
+        (status = 204, description = "Session ended, and its cookie cleared"),
+    ),
+    security(("session" = [])),
+)]
+pub async fn logout(
+    session: Option<Session>,
+    state: ServerState,
+) -> Result<Box<dyn Reply>, Infallible> {
+    if let Some(Session { id, token, .. }) = session {
+        if let Err(e) =
+            db::query(&state.db_tx, move |conn| auth::delete_session(conn, &token)).await
+        {
+            return Ok(Box::new(internal_error(e)));
+        }
+        disconnect_session(&id, &state.rooms).await;
+    }
+
+    Ok(Box::new(reply::with_header(
+        StatusCode::NO_CONTENT,
+        SET_COOKIE,
+        auth::session_cookie("", Duration::from_secs(0)),
+    )))
+}
+
+// Starts logging in with an OAuth provider, redirecting the user to it.
+#[utoipa::path(
+    get,
+    path = "/auth/{provider}/login",
+    tag = "auth",
+    params(
+        ("provider" = String, Path, description = "`github` or `google`"),
+    ),
+    responses(
+        (status = 302, description = "Redirect to the provider"),
+        (status = 404, description = "Unknown OAuth provider", body = ErrorReply),
+    ),
+)]
+pub async fn oauth_login(
+    provider: String,
+    state: ServerState,
+) -> Result<Box<dyn Reply>, Infallible> {
+    let oauth_provider = match state.oauth_providers.get(&provider) {
+        Some(oauth_provider) => oauth_provider,
+        None => return Ok(Box::new(unknown_provider())),
+    };
+
+    // Ties the callback to this browser, so that logins can not be forged
+    let oauth_state = auth::new_token();
+    let redirect_uri = oauth_redirect_uri(&state, &provider);
+    let location = match oauth_provider
+        .authorization_url(&redirect_uri, &oauth_state)
+        .and_then(|url| Ok(url.as_str().parse::<Uri>()?))
+    {
+        Ok(location) => location,
+        Err(e) => return Ok(Box::new(internal_error(e))),
+    };
+
+    Ok(Box::new(reply::with_header(
+        warp::redirect::found(location),
+        SET_COOKIE,
+        format!(
+            "{}={}; Max-Age={}; Path=/auth/{}; HttpOnly; SameSite=Lax",
+            oauth::STATE_COOKIE,
+            oauth_state,
+            OAUTH_STATE_TTL.as_secs(),
+            provider
+        ),
+    )))
+}
+
+// Completes logging in with an OAuth provider: the user is identified with the
+// provider, mapped to a local user, and given a session.
+#[utoipa::path(
+    get,
+    path = "/auth/{provider}/callback",
+    tag = "auth",
+    params(
+        ("provider" = String, Path, description = "`github` or `google`"),
+        OAuthCallback,
+    ),
+    responses(
+        (status = 303, description = "Redirect to `/`, logged in with a `session` cookie"),
+        (status = 400, description = "Invalid OAuth callback", body = ErrorReply),
+        (status = 401, description = "Login was not authorized", body = ErrorReply),
+        (status = 404, description = "Unknown OAuth provider", body = ErrorReply),
+        (status = 502, description = "Unable to identify user with provider", body = ErrorReply),
+    ),
+)]
+pub async fn oauth_callback(
+    provider: String,
+    query: OAuthCallback,
+    state_cookie: Option<String>,
+    user_agent: Option<String>,
+    state: ServerState,
+) -> Result<Box<dyn Reply>, Infallible> {
+    let oauth_provider = match state.oauth_providers.get(&provider) {
+        Some(oauth_provider) => oauth_provider,
+        None => return Ok(Box::new(unknown_provider())),
+    };
+
+    if let Some(error) = query.error {
+        return Ok(Box::new(error_reply(
+            StatusCode::UNAUTHORIZED,
+            &format!("Login was not authorized: {}", error),
+        )));
+    }
+
+    let code = match (query.code, query.state) {
+        (Some(code), Some(oauth_state)) if state_cookie.as_ref() == Some(&oauth_state) => code,
+        _ => {
+            return Ok(Box::new(error_reply(
+                StatusCode::BAD_REQUEST,
+                "Invalid OAuth callback",
+            )))
+        }
+    };
+
+    let redirect_uri = oauth_redirect_uri(&state, &provider);
+    let external_id = match oauth_provider
+        .identify(&state.http_client, &code, &redirect_uri)
+        .await
+    {
+        Ok(external_id) => external_id,
+        Err(e) => {
+            eprintln!("Failed to identify user with {}: {}", provider, e);
+            return Ok(Box::new(error_reply(
+                StatusCode::BAD_GATEWAY,
+                "Unable to identify user with provider",
+            )));
+        }
+    };
+
+    let session_ttl = state.session_ttl();
+    let session_token = match db::query(&state.db_tx, move |conn| {
+        let user_id = oauth::identity_user(conn, &provider, &external_id)?;
+        auth::create_session(conn, user_id, session_ttl, user_agent.as_deref())
+    })
+    .await
+    {
+        Ok(session_token) => session_token,
+        Err(e) => return Ok(Box::new(internal_error(e))),
+    };
+
+    Ok(Box::new(reply::with_header(
+        warp::redirect::see_other(Uri::from_static("/")),
+        SET_COOKIE,
+        auth::session_cookie(&session_token, session_ttl),
+    )))
+}
+
+// Where `provider` redirects users back to once they authorized a login.
+fn oauth_redirect_uri(state: &ServerState, provider: &str) -> String {
+    format!("{}/auth/{}/callback", state.config.public_url(), provider)
+}
+
+fn unknown_provider() -> WithStatus<Json> {
+    error_reply(StatusCode::NOT_FOUND, "Unknown OAuth provider")
+}
+
+// Starts enrolling a second factor, returning the secret to set up an
+// authenticator app with. Users that already enrolled one must provide a code
+// to replace it.
+#[utoipa::path(
+    post,
+    path = "/users/totp/enroll",
+    tag = "auth",
+    request_body = Credentials,
+    responses(
+        (status = 200, description = "The new `secret`, with its `otpauth_uri`"),
+        (status = 401, description = "Invalid username, password or second factor code", body = ErrorReply),
+        (status = 403, description = "Your address is banned", body = ErrorReply),
+        (status = 429, description = "Too many failed logins", body = ErrorReply),
+    ),
+)]
+pub async fn totp_enroll(
+    credentials: Credentials,
+    addr: Option<IpAddr>,
+    state: ServerState,
+) -> Result<WithStatus<Json>, Infallible> {
+    let Credentials {
+        username,
+        password,
+        code,
+        ..
+    } = credentials;
+
+    let user_id = match authenticate(&state, username.clone(), password, addr).await {
+        Ok(Authentication::User(user_id)) => user_id,
+        Ok(Authentication::Invalid) => return Ok(invalid_credentials()),
+        Ok(Authentication::LockedOut(locked_for)) => return Ok(locked_out(locked_for)),
+        Ok(Authentication::Banned) => return Ok(address_banned()),
+        Err(e) => return Ok(internal_error(e)),
+    };
+
+    let totp = match db::query(&state.db_tx, move |conn| totp::totp_state(conn, user_id)).await {
+        Ok(totp) => totp,
+        Err(e) => return Ok(internal_error(e)),
+    };
+
+    if let Some(secret) = totp.secret {
+        match check_code(&state, user_id, &secret, code).await {
+            Ok(true) => {}
+            Ok(false) => {
+                let counters = throttle::counters(&state.config, &username, addr);
+                if let Err(e) = login_failed(&state, counters).await {
+                    return Ok(internal_error(e));
+                }
+                return Ok(invalid_code());
+            }
+            Err(e) => return Ok(internal_error(e)),
+        }
+    }
+
+    let secret = totp::generate_secret();
+    let pending_secret = secret.clone();
+    if let Err(e) = db::query(&state.db_tx, move |conn| {
+        totp::begin_enrollment(conn, user_id, &pending_secret)
+    })
+    .await
+    {
+        return Ok(internal_error(e));
+    }
+
+    Ok(reply::with_status(
+        reply::json(&json!({
+            "secret": secret,
+            "otpauth_uri": totp::otpauth_uri(&username, &secret),
+        })),
+        StatusCode::OK,
+    ))
+}
+
+// Completes enrolling a second factor, with a code generated from the secret
+// returned by `totp_enroll`.
+#[utoipa::path(
+    post,
+    path = "/users/totp/confirm",
+    tag = "auth",
+    request_body = Credentials,
+    responses(
+        (status = 200, description = "Second factor enrolled"),
+        (status = 400, description = "No second factor enrollment in progress", body = ErrorReply),
+        (status = 401, description = "Invalid username, password or second factor code", body = ErrorReply),
+        (status = 403, description = "Your address is banned", body = ErrorReply),
+        (status = 429, description = "Too many failed logins", body = ErrorReply),
+    ),
+)]
+pub async fn totp_confirm(
+    credentials: Credentials,
+    addr: Option<IpAddr>,
+    state: ServerState,
+) -> Result<WithStatus<Json>, Infallible> {
+    let Credentials {
+        username,
+        password,
+        code,
+        ..
+    } = credentials;
+
+    let user_id = match authenticate(&state, username, password, addr).await {
+        Ok(Authentication::User(user_id)) => user_id,
+        Ok(Authentication::Invalid) => return Ok(invalid_credentials()),
+        Ok(Authentication::LockedOut(locked_for)) => return Ok(locked_out(locked_for)),
+        Ok(Authentication::Banned) => return Ok(address_banned()),
+        Err(e) => return Ok(internal_error(e)),
+    };
+
+    let totp = match db::query(&state.db_tx, move |conn| totp::totp_state(conn, user_id)).await {
+        Ok(totp) => totp,
+        Err(e) => return Ok(internal_error(e)),
+    };
+
+    let pending_secret = match totp.pending_secret {
+        Some(pending_secret) => pending_secret,
+        None => {
+            return Ok(error_reply(
+                StatusCode::BAD_REQUEST,
+                "No second factor enrollment in progress",
+            ))
+        }
+    };
+
+    let step = match code.and_then(|code| totp::verify_code(&pending_secret, &code)) {
+        Some(step) => step,
+        None => return Ok(invalid_code()),
+    };
+
+    let confirmed = db::query(&state.db_tx, move |conn| {
+        let confirmed = totp::confirm_enrollment(conn, user_id)?;
+        totp::use_step(conn, user_id, step)?;
+        Ok(confirmed)
+    })
+    .await;
+
+    match confirmed {
+        Ok(true) => Ok(reply::with_status(
+            reply::json(&json!({ "totp_enabled": true })),
+            StatusCode::OK,
+        )),
+        Ok(false) => Ok(error_reply(
+            StatusCode::BAD_REQUEST,
+            "No second factor enrollment in progress",
+        )),
+        Err(e) => Ok(internal_error(e)),
+    }
+}
+
+// Checks that `principal`, who makes a request, may join `room`, returning
+// their user ID, or returns the reply refusing it.
+async fn require_may_join(
+    state: &ServerState,
+    room: &str,
+    principal: &Principal,
+) -> Result<usize, WithStatus<Json>> {
+    let user_id = principal.user_id;
+
+    let room_name = String::from(room);
+    match db::query(&state.db_tx, move |conn| {
+        authz::may_join(conn, user_id, &room_name)
+    })
+    .await
+    {
+        Ok(true) => Ok(user_id),
+        Ok(false) => Err(error_reply(
+            StatusCode::FORBIDDEN,
+            "Not allowed in this room",
+        )),
+        Err(e) => Err(internal_error(e)),
+    }
+}
+
+// Checks that `principal`, who makes a request, may take `action` in `room`,
+// returning their user ID, or returns the reply refusing it with `refusal`.
+async fn require_room_permission(
+    state: &ServerState,
+    room: &str,
+    principal: &Principal,
+    action: Action,
+    refusal: &str,
+) -> Result<usize, WithStatus<Json>> {
+    let user_id = principal.user_id;
+
+    match permits(state, user_id, Some(String::from(room)), action).await {
+        Ok(true) => Ok(user_id),
+        Ok(false) => Err(error_reply(StatusCode::FORBIDDEN, refusal)),
+        Err(e) => Err(internal_error(e)),
+    }
+}
+
+async fn require_moderator(
+    state: &ServerState,
+    room: &str,
+    principal: &Principal,
+) -> Result<usize, WithStatus<Json>> {
+    require_room_permission(
+        state,
+        room,
+        principal,
+        Action::ModerateUsers,
+        "Only moderators can moderate users in this room",
+    )
+    .await
+}
+
+// Requires a moderator of `room` who outranks `user_id` there.
+async fn require_moderator_of(
+    state: &ServerState,
+    room: &str,
+    user_id: usize,
+    principal: &Principal,
+) -> Result<usize, WithStatus<Json>> {
+    let moderator = require_moderator(state, room, principal).await?;
+
+    let room_name = String::from(room);
+    let outranks = db::query(&state.db_tx, move |conn| {
+        let role = authz::room_role(conn, moderator, &room_name)?;
+        authz::outranks(conn, &room_name, role, user_id)
+    })
+    .await;
+
+    match outranks {
+        Ok(true) => Ok(moderator),
+        Ok(false) => Err(error_reply(
+            StatusCode::FORBIDDEN,
+            "Can only moderate users of a lower role",
+        )),
+        Err(e) => Err(internal_error(e)),
+    }
+}
+
+async fn require_access_manager(
+    state: &ServerState,
+    room: &str,
+    principal: &Principal,
+) -> Result<usize, WithStatus<Json>> {
+    require_room_permission(
+        state,
+        room,
+        principal,
+        Action::ManageRoomAccess,
+        "Only admins can manage who may join this room",
+    )
+    .await
+}
+
+async fn require_announcement_manager(
+    state: &ServerState,
+    room: &str,
+    principal: &Principal,
+) -> Result<usize, WithStatus<Json>> {
+    require_room_permission(
+        state,
+        room,
+        principal,
+        Action::ManageAnnouncements,
+        "Only admins can manage the announcements of this room",
+    )
+    .await
+}
+
+async fn require_owner(
+    state: &ServerState,
+    room: &str,
+    principal: &Principal,
+) -> Result<usize, WithStatus<Json>> {
+    require_room_permission(
+        state,
+        room,
+        principal,
+        Action::ManageRoomOwners,
+        "Only owners can manage the owners of this room",
+    )
+    .await
+}
+
+// Whether `user_id` may take `action`, in `room` if given.
+async fn permits(
+    state: &ServerState,
+    user_id: usize,
+    room: Option<String>,
+    action: Action,
+) -> Result<bool, anyhow::Error> {
+    db::query(&state.db_tx, move |conn| {
+        authz::permits(conn, user_id, room.as_deref(), action)
+    })
+    .await
+}
+
+fn missing_scope(required: &Scope) -> WithStatus<Json> {
+    error_reply(
+        StatusCode::FORBIDDEN,
+        &format!("Token lacks the '{}' scope", required),
+    )
+}
+
+// Outcome of checking a username and password.
+enum Authentication {
+    User(usize),
+    Invalid,
+    // Too many failed logins: the user has to wait this long to try again
+    LockedOut(Duration),
+    // The address of the request is banned
+    Banned,
+}
+
+// Checks a username and password, returning the ID of the user they belong to.
+// Failures are counted against the account and the address of the request,
+// and checks are refused while either is locked out, or if the address is
+// banned.
+async fn authenticate(
+    state: &ServerState,
+    username: String,
+    password: String,
+    addr: Option<IpAddr>,
+) -> Result<Authentication, anyhow::Error> {
+    if let Some(addr) = addr {
+        if db::query(&state.db_tx, move |conn| ip_ban::is_banned(conn, addr)).await? {
+            return Ok(Authentication::Banned);
+        }
+    }
+
+    let counters = throttle::counters(&state.config, &username, addr);
+    let locked_out_counters = counters.clone();
+    let locked_for = db::query(&state.db_tx, move |conn| {
+        throttle::locked_for(conn, &locked_out_counters, throttle::now())
+    })
+    .await?;
+    if let Some(locked_for) = locked_for {
+        return Ok(Authentication::LockedOut(locked_for));
+    }
+
+    let user = db::query(&state.db_tx, move |conn| auth::find_user(conn, &username)).await?;
+
+    let verified = match user {
+        Some((user_id, password_hash)) => {
+            tokio::task::spawn_blocking(move || auth::verify_password(&password, &password_hash))
+                .await
+                .unwrap_or(false)
+                .then_some(user_id)
+        }
+        None => None,
+    };
+
+    match verified {
+        Some(user_id) => Ok(Authentication::User(user_id)),
+        None => {
+            login_failed(state, counters).await?;
+            Ok(Authentication::Invalid)
+        }
+    }
+}
+
+// Counts a failed login against `counters`, as given by `throttle::counters`.
+async fn login_failed(
+    state: &ServerState,
+    counters: Vec<(String, throttle::Policy)>,
+) -> Result<(), anyhow::Error> {
+    db::query(&state.db_tx, move |conn| {
+        throttle::record_failure(conn, &counters, throttle::now())
+    })
+    .await?;
+
+    Ok(())
+}
+
+// Forgets the failed logins to `username`, once fully authenticated.
+async fn login_succeeded(state: &ServerState, username: String) -> Result<(), anyhow::Error> {
+    db::query(&state.db_tx, move |conn| {
+        throttle::record_success(conn, &username)
+    })
+    .await
+}
+
+// Checks a second factor code against the enrolled `secret` of `user_id`.
+// Codes may only be used once.
+async fn check_code(
+    state: &ServerState,
+    user_id: usize,
+    secret: &str,
+    code: Option<String>,
+) -> Result<bool, anyhow::Error> {
+    let step = match code.and_then(|code| totp::verify_code(secret, &code)) {
+        Some(step) => step,
+        None => return Ok(false),
+    };
+
+    db::query(&state.db_tx, move |conn| {
+        totp::use_step(conn, user_id, step)
+    })
+    .await
+}
+
+fn invalid_code() -> WithStatus<Json> {
+    error_reply(
+        StatusCode::UNAUTHORIZED,
+        "Missing or invalid second factor code",
+    )
+}
+
+fn locked_out(locked_for: Duration) -> WithStatus<Json> {
+    let reply = ErrorReply {
+        error: String::from("Too many failed logins, try again later"),
+        retry_after: Some(locked_for.as_secs()),
+    };
+    reply::with_status(reply::json(&reply), StatusCode::TOO_MANY_REQUESTS)
+}
+
+// Serves the OpenAPI document of the REST API.
+pub async fn openapi() -> Result<Json, Infallible> {
+    Ok(reply::json(openapi::document()))
+}
+
+// Serves Swagger UI, browsing the OpenAPI document. `/swagger-ui` redirects to
+// `/swagger-ui/`, against which the UI resolves its files.
+pub async fn swagger_ui(full_path: FullPath, tail: Tail) -> Result<Box<dyn Reply>, Infallible> {
+    if full_path.as_str() == openapi::SWAGGER_UI_PATH {
+        let location = format!("{}/", openapi::SWAGGER_UI_PATH);
+        return Ok(Box::new(warp::redirect::found(
+            location
+                .parse::<Uri>()
+                .expect("Swagger UI path is a valid URI"),
+        )));
+    }
+
+    match openapi::swagger_ui_file(tail.as_str()) {
+        Ok(Some(file)) => Ok(Box::new(reply::with_header(
+            Response::new(Body::from(file.bytes.into_owned())),
+            CONTENT_TYPE,
+            file.content_type,
+        ))),
+        Ok(None) => Ok(Box::new(error_reply(StatusCode::NOT_FOUND, "Not found"))),
+        Err(e) => Ok(Box::new(internal_error(e))),
+    }
+}
+
+// Replies to requests `routes::admin_guard` rejected, challenging clients to
+// authenticate, and to those `routes::require_scope` rejected. Other
+// rejections are left to warp.
+pub async fn recover(rejection: Rejection) -> Result<Box<dyn Reply>, Rejection> {
+    if rejection.find::<Unauthorized>().is_some() {
+        return Ok(Box::new(reply::with_header(
+            error_reply(
+                StatusCode::UNAUTHORIZED,
+                "Missing or invalid admin credentials",
+            ),
+            WWW_AUTHENTICATE,
+            ADMIN_CHALLENGE,
+        )));
+    }
+
+    if rejection.find::<NotLoggedIn>().is_some() {
+        return Ok(Box::new(error_reply(
+            StatusCode::UNAUTHORIZED,
+            "Not logged in",
+        )));
+    }
+
+    if let Some(MissingScope(required)) = rejection.find::<MissingScope>() {
+        return Ok(Box::new(missing_scope(required)));
+    }
+
+    if rejection.find::<CredentialsUnavailable>().is_some() {
+        return Ok(Box::new(error_reply(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Internal server error",
+        )));
+    }
+
+    Err(rejection)
+}
+
+fn invalid_credentials() -> WithStatus<Json> {
+    error_reply(StatusCode::UNAUTHORIZED, "Invalid username or password")
+}
+
+fn address_banned() -> WithStatus<Json> {
+    error_reply(StatusCode::FORBIDDEN, "Your address is banned")
+}
+
+fn internal_error(e: anyhow::Error) -> WithStatus<Json> {
+    eprintln!("Failed to handle request: {}", e);
+    error_reply(StatusCode::INTERNAL_SERVER_ERROR, "Internal server error")
+}
+
+fn error_reply(status: StatusCode, reason: &str) -> WithStatus<Json> {
+    let reply = ErrorReply {
+        error: String::from(reason),
+        retry_after: None,
+    };
+    reply::with_status(reply::json(&reply), status)
+}
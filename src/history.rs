@@ -0,0 +1,272 @@
+use anyhow::anyhow;
+use regex::Regex;
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::compression::MessageText;
+
+// Number of messages returned per page, unless asked for fewer, and the most
+// that may be asked for.
+pub const DEFAULT_LIMIT: usize = 100;
+pub const MAX_LIMIT: usize = 1000;
+
+// Request body of the route sending a message to a room over HTTP.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct NewMessage {
+    pub text: String,
+    // Number of seconds the message is kept for, as with `ttl_secs` of
+    // `message` frames
+    #[serde(default)]
+    pub ttl_secs: Option<u64>,
+}
+
+impl NewMessage {
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        if self.text.trim().is_empty() {
+            return Err(anyhow!("Messages may not be empty"));
+        }
+
+        Ok(())
+    }
+}
+
+// A message of a room, as listed over HTTP.
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct RoomMessage {
+    pub id: i64,
+    pub seq: Option<i64>,
+    pub user_id: usize,
+    pub nick: Option<String>,
+    pub text: String,
+    pub created_at: String,
+    pub edited_at: Option<String>,
+}
+
+// A page of messages, oldest first.
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct MessagePage {
+    pub messages: Vec<RoomMessage>,
+    // Offset of the next page, if there are more messages
+    pub next_offset: Option<usize>,
+}
+
+// Which messages of a room are listed: those sent from `since` until before
+// `until`, by `user_id`, as far as each is set.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct HistoryFilter {
+    since: Option<String>,
+    until: Option<String>,
+    user_id: Option<usize>,
+}
+
+impl HistoryFilter {
+    // Timestamps are in UTC, as `YYYY-MM-DD`, `YYYY-MM-DD HH:MM[:SS]` or
+    // `YYYY-MM-DDTHH:MM[:SS][Z]`.
+    pub fn new(
+        since: Option<&str>,
+        until: Option<&str>,
+        user_id: Option<usize>,
+    ) -> Result<Self, anyhow::Error> {
+        Ok(HistoryFilter {
+            since: since.map(parse_timestamp).transpose()?,
+            until: until.map(parse_timestamp).transpose()?,
+            user_id,
+        })
+    }
+}
+
+// Reads `timestamp` as the DB writes them, `YYYY-MM-DD HH:MM:SS`, which
+// compare in order.
+fn parse_timestamp(timestamp: &str) -> Result<String, anyhow::Error> {
+    let format =
+        Regex::new(r"^(\d{4})-(\d{2})-(\d{2})(?:[ T](\d{2}):(\d{2})(?::(\d{2}))?Z?)?$").unwrap();
+    let invalid = || anyhow!("Invalid timestamp '{}'", timestamp);
+
+    let captures = format.captures(timestamp).ok_or_else(invalid)?;
+    let field = |i: usize, max: u32| -> Result<u32, anyhow::Error> {
+        match captures.get(i) {
+            Some(field) => match field.as_str().parse() {
+                Ok(value) if value <= max => Ok(value),
+                _ => Err(invalid()),
+            },
+            None => Ok(0),
+        }
+    };
+    let (month, day) = (field(2, 12)?, field(3, 31)?);
+    if month == 0 || day == 0 {
+        return Err(invalid());
+    }
+
+    Ok(format!(
+        "{}-{:02}-{:02} {:02}:{:02}:{:02}",
+        &captures[1],
+        month,
+        day,
+        field(4, 23)?,
+        field(5, 59)?,
+        field(6, 59)?
+    ))
+}
+
+// Messages of `room_name` that `viewer` may see and `filter` lets through,
+// oldest first, skipping the first `offset`. Deleted messages are left out.
+pub fn room_messages(
+    conn: &Connection,
+    room_name: &str,
+    viewer: usize,
+    filter: &HistoryFilter,
+    limit: usize,
+    offset: usize,
+) -> Result<MessagePage, rusqlite::Error> {
+    // Bounds left unset are open, so that the messages of the room are always
+    // ranged over by time rather than scanned. They must be text, which sorts
+    // after numbers. One more message than asked for tells whether there is
+    // another page.
+    let mut stmt = conn.prepare_cached(
+        "SELECT message_id, seq, user_id, nickname, message, created_at, edited_at
+            FROM chat_messages
+            WHERE room_name = ?1 AND created_at >= ?2 AND created_at < ?3
+                AND (?4 IS NULL OR user_id = ?4)
+                AND deleted_at IS NULL AND (NOT shadowed OR user_id = ?5)
+            ORDER BY created_at, message_id
+            LIMIT ?6 OFFSET ?7",
+    )?;
+    let mut messages = stmt
+        .query_map(
+            params![
+                room_name,
+                filter.since.as_deref().unwrap_or(""),
+                filter.until.as_deref().unwrap_or("9999-12-31 23:59:59"),
+                filter.user_id,
+                viewer,
+                limit as i64 + 1,
+                offset as i64
+            ],
+            |row| {
+                Ok(RoomMessage {
+                    id: row.get(0)?,
+                    seq: row.get(1)?,
+                    user_id: row.get(2)?,
+                    nick: row.get(3)?,
+                    text: row.get::<_, MessageText>(4)?.into(),
+                    created_at: row.get(5)?,
+                    edited_at: row.get(6)?,
+                })
+            },
+        )?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let next_offset = if messages.len() > limit {
+        messages.truncate(limit);
+        Some(offset + limit)
+    } else {
+        None
+    };
+
+    Ok(MessagePage {
+        messages,
+        next_offset,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+
+    fn setup() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        db::init_schema(&conn).unwrap();
+        conn.execute(
+            "INSERT INTO chat_messages (message_id, user_id, room_name, message, created_at)
+                VALUES (1, 1, 'room1', 'Morning', '2024-01-01 09:00:00'),
+                    (2, 2, 'room1', 'Noon', '2024-01-01 12:00:00'),
+                    (3, 1, 'room1', 'Evening', '2024-01-01 18:00:00'),
+                    (4, 1, 'room2', 'Elsewhere', '2024-01-01 12:00:00')",
+            [],
+        )
+        .unwrap();
+
+        conn
+    }
+
+    fn ids(page: &MessagePage) -> Vec<i64> {
+        page.messages.iter().map(|message| message.id).collect()
+    }
+
+    #[test]
+    fn test_parse_timestamp() {
+        assert_eq!(
+            parse_timestamp("2024-01-02").unwrap(),
+            "2024-01-02 00:00:00"
+        );
+        assert_eq!(
+            parse_timestamp("2024-01-02 03:04").unwrap(),
+            "2024-01-02 03:04:00"
+        );
+        assert_eq!(
+            parse_timestamp("2024-01-02T03:04:05Z").unwrap(),
+            "2024-01-02 03:04:05"
+        );
+        assert!(parse_timestamp("2024-13-02").is_err());
+        assert!(parse_timestamp("2024-01-02 24:00").is_err());
+        assert!(parse_timestamp("yesterday").is_err());
+        assert!(parse_timestamp("2024-01-02Z").is_err());
+    }
+
+    #[test]
+    fn test_room_messages() {
+        let conn = setup();
+        let messages = |since, until, user_id, limit, offset| {
+            let filter = HistoryFilter::new(since, until, user_id).unwrap();
+            room_messages(&conn, "room1", 1, &filter, limit, offset).unwrap()
+        };
+
+        assert_eq!(ids(&messages(None, None, None, 10, 0)), vec![1, 2, 3]);
+        // Since is inclusive, until is not
+        assert_eq!(
+            ids(&messages(Some("2024-01-01 12:00"), None, None, 10, 0)),
+            vec![2, 3]
+        );
+        assert_eq!(
+            ids(&messages(None, Some("2024-01-01T12:00:00Z"), None, 10, 0)),
+            vec![1]
+        );
+        assert_eq!(ids(&messages(None, None, Some(1), 10, 0)), vec![1, 3]);
+
+        let page = messages(None, None, None, 2, 0);
+        assert_eq!(ids(&page), vec![1, 2]);
+        assert_eq!(page.next_offset, Some(2));
+        let page = messages(None, None, None, 2, 2);
+        assert_eq!(ids(&page), vec![3]);
+        assert_eq!(page.next_offset, None);
+    }
+
+    #[test]
+    fn test_room_messages_plan() {
+        let conn = setup();
+        let mut stmt = conn
+            .prepare(
+                "EXPLAIN QUERY PLAN SELECT message_id FROM chat_messages
+                    WHERE room_name = ?1 AND created_at >= ?2 AND created_at < ?3
+                        AND (?4 IS NULL OR user_id = ?4)
+                        AND deleted_at IS NULL AND (NOT shadowed OR user_id = ?5)
+                    ORDER BY created_at, message_id",
+            )
+            .unwrap();
+        let plan = stmt
+            .query_map(
+                params!["room1", "", "9999-12-31 23:59:59", None::<usize>, 1],
+                |row| row.get::<_, String>(3),
+            )
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap()
+            .join("\n");
+
+        // Messages are ranged over by room and time, already in order
+        assert!(plan.contains("chat_messages_room_created"), "{}", plan);
+        assert!(!plan.contains("TEMP B-TREE"), "{}", plan);
+    }
+}
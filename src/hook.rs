@@ -0,0 +1,342 @@
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::{
+    auth::{self, oauth},
+    room, user,
+};
+
+// Provider the bot accounts of hooks are recorded under, as external
+// identities.
+const HOOK_PROVIDER: &str = "hook";
+
+// Request body of the route creating an incoming webhook into a room.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct NewHook {
+    // Nickname the bot posts as, unless a payload gives another
+    pub name: String,
+}
+
+impl NewHook {
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        user::validate_nickname(&self.name).map(|_| ())
+    }
+}
+
+// An incoming webhook into a room, as listed to its admins. The token posting
+// to it is only ever shown once, on creation.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Hook {
+    pub id: i64,
+    pub room: String,
+    pub name: String,
+    // Account of the bot it posts as
+    pub user_id: usize,
+    // Unknown if the user who created it has since been deleted
+    pub created_by: Option<usize>,
+    pub created_at: String,
+}
+
+// Creates an incoming webhook into `room_name` on behalf of `created_by`,
+// posting as a bot account of its own, returning it along with the token
+// posting to it, unless the room does not exist.
+pub fn create_hook(
+    conn: &Connection,
+    room_name: &str,
+    created_by: usize,
+    new_hook: &NewHook,
+) -> Result<Option<(Hook, String)>, rusqlite::Error> {
+    if room::settings(conn, room_name)?.is_none() {
+        return Ok(None);
+    }
+
+    let token = auth::new_token();
+    let token_hash = auth::hash_token(&token);
+    let user_id = oauth::identity_user(conn, HOOK_PROVIDER, &token_hash)?;
+    conn.execute(
+        "INSERT INTO room_hooks (room_name, token_hash, name, user_id, created_by)
+            VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![
+            room_name,
+            token_hash,
+            new_hook.name.trim(),
+            user_id,
+            created_by
+        ],
+    )?;
+
+    let hook = conn.query_row(
+        &format!("SELECT {} FROM room_hooks WHERE hook_id = ?1", HOOK_COLUMNS),
+        params![conn.last_insert_rowid()],
+        hook_from_row,
+    )?;
+
+    Ok(Some((hook, token)))
+}
+
+// Incoming webhooks into `room_name`, oldest first.
+pub fn room_hooks(conn: &Connection, room_name: &str) -> Result<Vec<Hook>, rusqlite::Error> {
+    let mut stmt = conn.prepare_cached(&format!(
+        "SELECT {} FROM room_hooks WHERE room_name = ?1 ORDER BY hook_id",
+        HOOK_COLUMNS
+    ))?;
+    let hooks = stmt.query_map(params![room_name], hook_from_row)?.collect();
+
+    hooks
+}
+
+// Revokes incoming webhook `hook_id` into `room_name`, returning whether it
+// existed. What its bot posted stays.
+pub fn revoke_hook(
+    conn: &Connection,
+    room_name: &str,
+    hook_id: i64,
+) -> Result<bool, rusqlite::Error> {
+    let deleted = conn.execute(
+        "DELETE FROM room_hooks WHERE hook_id = ?1 AND room_name = ?2",
+        params![hook_id, room_name],
+    )?;
+
+    Ok(deleted > 0)
+}
+
+// The incoming webhook `token` posts to, if any.
+pub fn find_hook(conn: &Connection, token: &str) -> Result<Option<Hook>, rusqlite::Error> {
+    conn.query_row(
+        &format!(
+            "SELECT {} FROM room_hooks WHERE token_hash = ?1",
+            HOOK_COLUMNS
+        ),
+        params![auth::hash_token(token)],
+        hook_from_row,
+    )
+    .optional()
+}
+
+const HOOK_COLUMNS: &str = "hook_id, room_name, name, user_id, created_by, created_at";
+
+fn hook_from_row(row: &rusqlite::Row) -> Result<Hook, rusqlite::Error> {
+    Ok(Hook {
+        id: row.get(0)?,
+        room: row.get(1)?,
+        name: row.get(2)?,
+        user_id: row.get(3)?,
+        created_by: row.get(4)?,
+        created_at: row.get(5)?,
+    })
+}
+
+// What a Slack incoming webhook is sent, as far as it makes up a message.
+// Anything else, e.g. `channel` or `icon_emoji`, is ignored: hooks always post
+// to their own room.
+#[derive(Debug, Default, Deserialize, ToSchema)]
+pub struct SlackPayload {
+    #[serde(default)]
+    pub text: Option<String>,
+    // Overrides the name of the hook for this message
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub blocks: Vec<SlackBlock>,
+    #[serde(default)]
+    pub attachments: Vec<SlackAttachment>,
+}
+
+// A layout block, e.g. a `section` or `header`, of which only text is kept.
+#[derive(Debug, Default, Deserialize, ToSchema)]
+pub struct SlackBlock {
+    #[serde(default)]
+    pub text: Option<SlackText>,
+    #[serde(default)]
+    pub fields: Vec<SlackText>,
+}
+
+#[derive(Debug, Default, Deserialize, ToSchema)]
+pub struct SlackText {
+    #[serde(default)]
+    pub text: String,
+}
+
+// A legacy attachment, as alerting and CI tools still commonly send.
+#[derive(Debug, Default, Deserialize, ToSchema)]
+pub struct SlackAttachment {
+    #[serde(default)]
+    pub fallback: Option<String>,
+    #[serde(default)]
+    pub pretext: Option<String>,
+    #[serde(default)]
+    pub title: Option<String>,
+    #[serde(default)]
+    pub title_link: Option<String>,
+    #[serde(default)]
+    pub text: Option<String>,
+    #[serde(default)]
+    pub fields: Vec<SlackField>,
+}
+
+#[derive(Debug, Default, Deserialize, ToSchema)]
+pub struct SlackField {
+    #[serde(default)]
+    pub title: String,
+    #[serde(default)]
+    pub value: String,
+}
+
+impl SlackPayload {
+    // The text of the message to post: `text`, or that of `blocks` without
+    // it, followed by that of `attachments`. None if it would be empty.
+    pub fn message(&self) -> Option<String> {
+        let mut lines = Vec::new();
+        match self.text.as_deref() {
+            Some(text) if !text.trim().is_empty() => lines.push(String::from(text)),
+            _ => {
+                for block in &self.blocks {
+                    lines.extend(block.text.iter().map(|text| text.text.clone()));
+                    lines.extend(block.fields.iter().map(|field| field.text.clone()));
+                }
+            }
+        }
+
+        for attachment in &self.attachments {
+            let start = lines.len();
+            lines.extend(attachment.pretext.clone());
+            match (&attachment.title, &attachment.title_link) {
+                (Some(title), Some(link)) => lines.push(format!("<{}|{}>", link, title)),
+                (Some(title), None) => lines.push(title.clone()),
+                _ => {}
+            }
+            lines.extend(attachment.text.clone());
+            lines.extend(
+                attachment
+                    .fields
+                    .iter()
+                    .map(|field| format!("{}: {}", field.title, field.value)),
+            );
+            // The fallback stands for the attachment where nothing else does
+            if lines.len() == start {
+                lines.extend(attachment.fallback.clone());
+            }
+        }
+
+        let text = lines
+            .iter()
+            .map(|line| plain_text(line))
+            .filter(|line| !line.trim().is_empty())
+            .collect::<Vec<_>>()
+            .join("\n");
+        Some(text).filter(|text| !text.is_empty())
+    }
+}
+
+// Slack's markup for links and mentions, e.g. `<https://ci.example.org|build
+// 42>` or `<!here>`, as plain text, with its escapes undone.
+fn plain_text(text: &str) -> String {
+    let mut plain = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('<') {
+        plain.push_str(&rest[..start]);
+        let end = match rest[start..].find('>') {
+            Some(end) => start + end,
+            None => break,
+        };
+
+        let (target, label) = match rest[start + 1..end].split_once('|') {
+            Some((target, label)) => (target, Some(label)),
+            None => (&rest[start + 1..end], None),
+        };
+        match (target.chars().next(), label) {
+            // Channels, e.g. `<#C024BE7LR|general>`
+            (Some('#'), Some(label)) => plain.push_str(&format!("#{}", label)),
+            // Special mentions, e.g. `<!here>`, and dates falling back to
+            // their label
+            (Some('!'), None) => plain.push_str(&format!("@{}", &target[1..])),
+            (Some('!' | '@'), Some(label)) => plain.push_str(label),
+            // Links
+            (_, Some(label)) => plain.push_str(&format!("{} ({})", label, target)),
+            (_, None) => plain.push_str(target),
+        }
+        rest = &rest[end + 1..];
+    }
+    plain.push_str(rest);
+
+    plain
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+
+    #[test]
+    fn test_hooks() {
+        let conn = Connection::open_in_memory().unwrap();
+        db::init_schema(&conn).unwrap();
+        let alice = auth::create_user(&conn, "alice", "hash").unwrap().unwrap();
+        room::record_room(&conn, "room1", alice, true).unwrap();
+
+        let new_hook = NewHook {
+            name: String::from("ci"),
+        };
+        let (hook, token) = create_hook(&conn, "room1", alice, &new_hook)
+            .unwrap()
+            .unwrap();
+        assert_eq!((hook.room.as_str(), hook.name.as_str()), ("room1", "ci"));
+        assert_ne!(hook.user_id, alice);
+        assert_eq!(create_hook(&conn, "room2", alice, &new_hook).unwrap(), None);
+
+        // Each hook posts as a bot of its own
+        let (other, _) = create_hook(&conn, "room1", alice, &new_hook)
+            .unwrap()
+            .unwrap();
+        assert_ne!(other.user_id, hook.user_id);
+
+        assert_eq!(find_hook(&conn, &token).unwrap(), Some(hook));
+        assert_eq!(find_hook(&conn, "unknown").unwrap(), None);
+        assert_eq!(room_hooks(&conn, "room1").unwrap().len(), 2);
+
+        let hook_id = room_hooks(&conn, "room1").unwrap()[0].id;
+        assert!(!revoke_hook(&conn, "room2", hook_id).unwrap());
+        assert!(revoke_hook(&conn, "room1", hook_id).unwrap());
+        assert_eq!(find_hook(&conn, &token).unwrap(), None);
+    }
+
+    #[test]
+    fn test_slack_payload() {
+        let payload: SlackPayload = serde_json::from_str(
+            r#"{"text": "Deploy &lt;prod&gt; done: <https://ci.example.org/42|build 42> <!here>"}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            payload.message().unwrap(),
+            "Deploy <prod> done: build 42 (https://ci.example.org/42) @here"
+        );
+
+        let payload: SlackPayload = serde_json::from_str(
+            r##"{
+                "channel": "#alerts",
+                "icon_emoji": ":fire:",
+                "attachments": [
+                    {"fallback": "Disk full on db1", "color": "danger"},
+                    {"title": "CPU", "fields": [{"title": "Host", "value": "web1", "short": true}]}
+                ]
+            }"##,
+        )
+        .unwrap();
+        assert_eq!(
+            payload.message().unwrap(),
+            "Disk full on db1\nCPU\nHost: web1"
+        );
+
+        let payload: SlackPayload = serde_json::from_str(
+            r#"{"blocks": [{"type": "section", "text": {"type": "mrkdwn", "text": "See <#C024BE7LR|general>"}}]}"#,
+        )
+        .unwrap();
+        assert_eq!(payload.message().unwrap(), "See #general");
+
+        assert_eq!(SlackPayload::default().message(), None);
+    }
+}
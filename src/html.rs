@@ -16,6 +16,10 @@ pub static INDEX_HTML: &str = r#"<!DOCTYPE html>
         const uri = 'ws://' + location.host + '/chat' + '/public';
         const ws = new WebSocket(uri);
 
+        function author(event) {
+            return '<' + (event.nick || 'User#' + event.user_id) + '>';
+        }
+
         function message(data) {
             const line = document.createElement('p');
             line.innerText = data;
@@ -27,7 +31,33 @@ pub static INDEX_HTML: &str = r#"<!DOCTYPE html>
         };
 
         ws.onmessage = function(msg) {
-            message(msg.data);
+            const event = JSON.parse(msg.data);
+            switch (event.type) {
+                case 'message':
+                    message(author(event) + ': ' + event.text);
+                    break;
+                case 'join':
+                    message('(' + author(event) + ' joined)');
+                    break;
+                case 'edit':
+                    message(author(event) + ' (edited #' + event.id + '): ' + event.text);
+                    break;
+                case 'delete':
+                    message('(message #' + event.id + ' deleted)');
+                    break;
+                case 'pin':
+                    message('(message #' + event.id + ' pinned)');
+                    break;
+                case 'unpin':
+                    message('(message #' + event.id + ' unpinned)');
+                    break;
+                case 'rename':
+                    message('(' + (event.old_nick || 'User#' + event.user_id) + ' is now known as ' + event.nick + ')');
+                    break;
+                case 'error':
+                    message('Error: ' + event.reason);
+                    break;
+            }
         };
 
         ws.onclose = function() {
@@ -36,7 +66,7 @@ pub static INDEX_HTML: &str = r#"<!DOCTYPE html>
 
         send.onclick = function() {
             const msg = text.value;
-            ws.send(msg);
+            ws.send(JSON.stringify({ type: 'message', text: msg }));
             text.value = '';
 
             message('<You>: ' + msg);
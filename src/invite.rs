@@ -0,0 +1,252 @@
+use std::time::Duration;
+
+use anyhow::anyhow;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::{auth, room};
+
+// Request body of the route creating an invite into a room.
+#[derive(Debug, Default, Deserialize, ToSchema)]
+pub struct NewInvite {
+    // Number of times the invite can be redeemed, if limited
+    #[serde(default)]
+    pub max_uses: Option<u32>,
+    // Number of seconds the invite can be redeemed for, if limited
+    #[serde(default)]
+    pub ttl_secs: Option<u64>,
+}
+
+impl NewInvite {
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        if self.max_uses == Some(0) {
+            return Err(anyhow!("Invites must allow at least 1 use"));
+        }
+
+        if self.ttl_secs == Some(0) {
+            return Err(anyhow!("Invites must last at least 1 second"));
+        }
+
+        Ok(())
+    }
+}
+
+// An invite into a room, as listed to its admins. The token redeeming it is
+// only ever shown once, on creation.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Invite {
+    pub id: i64,
+    pub room: String,
+    // Unknown if the user who created it has since been deleted
+    pub created_by: Option<usize>,
+    pub created_at: String,
+    pub expires_at: Option<String>,
+    pub max_uses: Option<u32>,
+    pub uses: u32,
+}
+
+// Creates an invite into `room_name` on behalf of `created_by`, returning it
+// along with the token redeeming it, unless the room does not exist.
+pub fn create_invite(
+    conn: &Connection,
+    room_name: &str,
+    created_by: usize,
+    new_invite: &NewInvite,
+) -> Result<Option<(Invite, String)>, rusqlite::Error> {
+    if room::settings(conn, room_name)?.is_none() {
+        return Ok(None);
+    }
+
+    let token = auth::new_token();
+    let expires_at = new_invite
+        .ttl_secs
+        .map(|ttl_secs| auth::ttl_modifier(Duration::from_secs(ttl_secs)));
+    conn.execute(
+        "INSERT INTO room_invites (room_name, token_hash, created_by, max_uses, expires_at)
+            VALUES (?1, ?2, ?3, ?4, datetime('now', ?5))",
+        params![
+            room_name,
+            auth::hash_token(&token),
+            created_by,
+            new_invite.max_uses,
+            expires_at
+        ],
+    )?;
+
+    let invite = conn.query_row(
+        &format!(
+            "SELECT {} FROM room_invites WHERE invite_id = ?1",
+            INVITE_COLUMNS
+        ),
+        params![conn.last_insert_rowid()],
+        invite_from_row,
+    )?;
+
+    Ok(Some((invite, token)))
+}
+
+// Invites into `room_name`, oldest first.
+pub fn room_invites(conn: &Connection, room_name: &str) -> Result<Vec<Invite>, rusqlite::Error> {
+    let mut stmt = conn.prepare_cached(&format!(
+        "SELECT {} FROM room_invites WHERE room_name = ?1 ORDER BY invite_id",
+        INVITE_COLUMNS
+    ))?;
+    let invites = stmt
+        .query_map(params![room_name], invite_from_row)?
+        .collect();
+
+    invites
+}
+
+// Revokes invite `invite_id` into `room_name`, returning whether it existed.
+pub fn revoke_invite(
+    conn: &Connection,
+    room_name: &str,
+    invite_id: i64,
+) -> Result<bool, rusqlite::Error> {
+    let deleted = conn.execute(
+        "DELETE FROM room_invites WHERE invite_id = ?1 AND room_name = ?2",
+        params![invite_id, room_name],
+    )?;
+
+    Ok(deleted > 0)
+}
+
+// Makes `user_id` a member of `room_name` with invite `token`. Returns whether
+// the invite was redeemed: it must be into that room, and neither expired nor
+// used up. Redeeming an invite into a room the user is already a member of
+// does not use it up.
+pub fn redeem(
+    conn: &Connection,
+    token: &str,
+    room_name: &str,
+    user_id: usize,
+) -> Result<bool, rusqlite::Error> {
+    let invite: Option<(i64, Option<usize>, bool)> = conn
+        .query_row(
+            "SELECT invite_id, created_by, max_uses IS NOT NULL AND uses >= max_uses
+                FROM room_invites
+                WHERE token_hash = ?1 AND room_name = ?2
+                    AND (expires_at IS NULL OR expires_at > datetime('now'))",
+            params![auth::hash_token(token), room_name],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )
+        .optional()?;
+
+    let (invite_id, created_by, used_up) = match invite {
+        Some(invite) => invite,
+        None => return Ok(false),
+    };
+    if room::is_member(conn, room_name, user_id)? {
+        return Ok(true);
+    }
+    if used_up {
+        return Ok(false);
+    }
+
+    conn.execute(
+        "UPDATE room_invites SET uses = uses + 1 WHERE invite_id = ?1",
+        params![invite_id],
+    )?;
+    room::add_member(conn, room_name, user_id, created_by)?;
+
+    Ok(true)
+}
+
+const INVITE_COLUMNS: &str =
+    "invite_id, room_name, created_by, created_at, expires_at, max_uses, uses";
+
+fn invite_from_row(row: &rusqlite::Row) -> Result<Invite, rusqlite::Error> {
+    Ok(Invite {
+        id: row.get(0)?,
+        room: row.get(1)?,
+        created_by: row.get(2)?,
+        created_at: row.get(3)?,
+        expires_at: row.get(4)?,
+        max_uses: row.get(5)?,
+        uses: row.get(6)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+
+    fn setup() -> (Connection, usize, usize) {
+        let conn = Connection::open_in_memory().unwrap();
+        db::init_schema(&conn).unwrap();
+        let alice = auth::create_user(&conn, "alice", "hash").unwrap().unwrap();
+        let bob = auth::create_user(&conn, "bob", "hash").unwrap().unwrap();
+        room::record_room(&conn, "room1", alice, true).unwrap();
+
+        (conn, alice, bob)
+    }
+
+    #[test]
+    fn test_redeem() {
+        let (conn, alice, bob) = setup();
+        let carol = auth::create_user(&conn, "carol", "hash").unwrap().unwrap();
+
+        let new_invite = NewInvite {
+            max_uses: Some(1),
+            ttl_secs: None,
+        };
+        let (invite, token) = create_invite(&conn, "room1", alice, &new_invite)
+            .unwrap()
+            .unwrap();
+        assert_eq!((invite.room.as_str(), invite.uses), ("room1", 0));
+        assert_eq!(
+            create_invite(&conn, "room2", alice, &new_invite).unwrap(),
+            None
+        );
+
+        // Invites only redeem into their room
+        assert!(!redeem(&conn, &token, "room2", bob).unwrap());
+        assert!(!redeem(&conn, "unknown", "room1", bob).unwrap());
+
+        assert!(redeem(&conn, &token, "room1", bob).unwrap());
+        assert!(room::is_member(&conn, "room1", bob).unwrap());
+        assert_eq!(
+            room::members(&conn, "room1").unwrap()[0].invited_by,
+            Some(alice)
+        );
+
+        // Members redeeming again do not use invites up, but others can not
+        // redeem used up invites
+        assert!(redeem(&conn, &token, "room1", bob).unwrap());
+        assert!(!redeem(&conn, &token, "room1", carol).unwrap());
+        assert_eq!(room_invites(&conn, "room1").unwrap()[0].uses, 1);
+    }
+
+    #[test]
+    fn test_expired_invite() {
+        let (conn, alice, bob) = setup();
+
+        let new_invite = NewInvite {
+            max_uses: None,
+            ttl_secs: Some(0),
+        };
+        let (_, token) = create_invite(&conn, "room1", alice, &new_invite)
+            .unwrap()
+            .unwrap();
+        assert!(!redeem(&conn, &token, "room1", bob).unwrap());
+    }
+
+    #[test]
+    fn test_revoke_invite() {
+        let (conn, alice, bob) = setup();
+
+        let (invite, token) = create_invite(&conn, "room1", alice, &NewInvite::default())
+            .unwrap()
+            .unwrap();
+        assert_eq!(invite.expires_at, None);
+        assert_eq!(room_invites(&conn, "room1").unwrap(), vec![invite]);
+
+        let invite_id = room_invites(&conn, "room1").unwrap()[0].id;
+        assert!(!revoke_invite(&conn, "room2", invite_id).unwrap());
+        assert!(revoke_invite(&conn, "room1", invite_id).unwrap());
+        assert!(!redeem(&conn, &token, "room1", bob).unwrap());
+    }
+}
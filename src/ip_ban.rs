@@ -0,0 +1,256 @@
+use std::{
+    convert::TryFrom,
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+};
+
+use anyhow::anyhow;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::moderation::MAX_REASON_LENGTH;
+
+// A range of addresses, given as `<address>/<prefix length>` in CIDR notation,
+// or as a single address.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct IpNet {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpNet {
+    // Whether `addr` is in this range. IPv4 addresses mapped to IPv6 are
+    // matched as IPv4 addresses.
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        let addr = canonical(addr);
+        addr.is_ipv4() == self.addr.is_ipv4() && network(addr, self.prefix_len) == self.addr
+    }
+}
+
+impl FromStr for IpNet {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+            None => (s, None),
+        };
+        let addr = canonical(
+            addr.parse::<IpAddr>()
+                .map_err(|_| anyhow!("Invalid address '{}'", addr))?,
+        );
+
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix_len| *prefix_len <= max_len)
+                .ok_or_else(|| {
+                    anyhow!(
+                        "Prefix length must be between 0 and {}, got '{}'",
+                        max_len,
+                        prefix_len
+                    )
+                })?,
+            None => max_len,
+        };
+
+        // Bits past the prefix are cleared, so that equal ranges compare equal
+        Ok(IpNet {
+            addr: network(addr, prefix_len),
+            prefix_len,
+        })
+    }
+}
+
+impl TryFrom<String> for IpNet {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<IpNet> for String {
+    fn from(net: IpNet) -> Self {
+        net.to_string()
+    }
+}
+
+impl fmt::Display for IpNet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+// `addr` with the bits past its first `prefix_len` cleared.
+fn network(addr: IpAddr, prefix_len: u8) -> IpAddr {
+    match addr {
+        IpAddr::V4(addr) => {
+            let mask = u32::MAX.checked_shl(32 - u32::from(prefix_len));
+            IpAddr::V4(Ipv4Addr::from(u32::from(addr) & mask.unwrap_or(0)))
+        }
+        IpAddr::V6(addr) => {
+            let mask = u128::MAX.checked_shl(128 - u32::from(prefix_len));
+            IpAddr::V6(Ipv6Addr::from(u128::from(addr) & mask.unwrap_or(0)))
+        }
+    }
+}
+
+// IPv4 addresses mapped to IPv6, as dual-stack sockets report them, as plain
+// IPv4 addresses.
+fn canonical(addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(addr, IpAddr::V4),
+        IpAddr::V4(_) => addr,
+    }
+}
+
+// Request body of the admin route banning a range of addresses.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct NewIpBan {
+    #[schema(value_type = String, example = "10.0.0.0/8")]
+    pub cidr: IpNet,
+    #[serde(default)]
+    pub reason: Option<String>,
+}
+
+impl NewIpBan {
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        if self
+            .reason
+            .as_ref()
+            .is_some_and(|reason| reason.chars().count() > MAX_REASON_LENGTH)
+        {
+            return Err(anyhow!(
+                "Reason must be at most {} characters long",
+                MAX_REASON_LENGTH
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+// A range of addresses connections are refused from.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct IpBan {
+    pub id: i64,
+    pub cidr: IpNet,
+    pub reason: Option<String>,
+    pub created_at: String,
+}
+
+// Bans the range of `new_ban`, unless it already is.
+pub fn ban(conn: &Connection, new_ban: &NewIpBan) -> Result<Option<IpBan>, rusqlite::Error> {
+    let inserted = conn.execute(
+        "INSERT OR IGNORE INTO ip_bans (cidr, reason) VALUES (?1, ?2)",
+        params![new_ban.cidr.to_string(), new_ban.reason],
+    )?;
+    if inserted == 0 {
+        return Ok(None);
+    }
+
+    conn.query_row(
+        "SELECT ban_id, cidr, reason, created_at FROM ip_bans WHERE ban_id = ?1",
+        params![conn.last_insert_rowid()],
+        ip_ban_from_row,
+    )
+    .optional()
+}
+
+// Banned ranges, oldest first. Ranges that no longer parse are left out.
+pub fn ip_bans(conn: &Connection) -> Result<Vec<IpBan>, rusqlite::Error> {
+    let mut stmt = conn
+        .prepare_cached("SELECT ban_id, cidr, reason, created_at FROM ip_bans ORDER BY ban_id")?;
+    let bans = stmt
+        .query_map([], |row| Ok(ip_ban_from_row(row).ok()))?
+        .filter_map(Result::transpose)
+        .collect();
+
+    bans
+}
+
+// Lifts ban `ban_id`, returning whether it existed.
+pub fn unban(conn: &Connection, ban_id: i64) -> Result<bool, rusqlite::Error> {
+    let deleted = conn.execute("DELETE FROM ip_bans WHERE ban_id = ?1", params![ban_id])?;
+
+    Ok(deleted > 0)
+}
+
+// Whether `addr` is in a banned range.
+pub fn is_banned(conn: &Connection, addr: IpAddr) -> Result<bool, rusqlite::Error> {
+    Ok(ip_bans(conn)?.iter().any(|ban| ban.cidr.contains(addr)))
+}
+
+fn ip_ban_from_row(row: &rusqlite::Row) -> Result<IpBan, rusqlite::Error> {
+    let cidr: String = row.get(1)?;
+
+    Ok(IpBan {
+        id: row.get(0)?,
+        cidr: cidr.parse().map_err(|_| {
+            rusqlite::Error::InvalidColumnType(1, cidr, rusqlite::types::Type::Text)
+        })?,
+        reason: row.get(2)?,
+        created_at: row.get(3)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+
+    #[test]
+    fn test_parse_ip_net() {
+        let net: IpNet = "10.1.2.3/8".parse().unwrap();
+        assert_eq!(net.to_string(), "10.0.0.0/8");
+        assert!(net.contains("10.255.0.1".parse().unwrap()));
+        assert!(!net.contains("11.0.0.1".parse().unwrap()));
+        assert!(net.contains("::ffff:10.0.0.1".parse().unwrap()));
+
+        let single: IpNet = "192.168.0.1".parse().unwrap();
+        assert_eq!(single.to_string(), "192.168.0.1/32");
+        assert!(single.contains("192.168.0.1".parse().unwrap()));
+        assert!(!single.contains("192.168.0.2".parse().unwrap()));
+
+        let v6: IpNet = "2001:db8::/32".parse().unwrap();
+        assert!(v6.contains("2001:db8:1::1".parse().unwrap()));
+        assert!(!v6.contains("2001:db9::1".parse().unwrap()));
+        assert!(!v6.contains("10.0.0.1".parse().unwrap()));
+
+        let everything: IpNet = "0.0.0.0/0".parse().unwrap();
+        assert!(everything.contains("1.2.3.4".parse().unwrap()));
+
+        assert!("10.0.0.0/33".parse::<IpNet>().is_err());
+        assert!("10.0.0/8".parse::<IpNet>().is_err());
+        assert!("localhost".parse::<IpNet>().is_err());
+    }
+
+    #[test]
+    fn test_ip_bans() {
+        let conn = Connection::open_in_memory().unwrap();
+        db::init_schema(&conn).unwrap();
+
+        let new_ban = NewIpBan {
+            cidr: "10.0.0.0/8".parse().unwrap(),
+            reason: Some(String::from("Abuse")),
+        };
+        let ban = ban(&conn, &new_ban).unwrap().unwrap();
+        assert_eq!(ban.cidr, new_ban.cidr);
+        assert_eq!(super::ban(&conn, &new_ban).unwrap(), None);
+        assert_eq!(ip_bans(&conn).unwrap(), vec![ban]);
+
+        assert!(is_banned(&conn, "10.1.2.3".parse().unwrap()).unwrap());
+        assert!(!is_banned(&conn, "127.0.0.1".parse().unwrap()).unwrap());
+
+        let ban_id = ip_bans(&conn).unwrap()[0].id;
+        assert!(unban(&conn, ban_id).unwrap());
+        assert!(!unban(&conn, ban_id).unwrap());
+        assert!(!is_banned(&conn, "10.1.2.3".parse().unwrap()).unwrap());
+    }
+}
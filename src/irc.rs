@@ -0,0 +1,707 @@
+use std::{collections::HashMap, net::SocketAddr, time::Duration};
+
+use serde_json::Value;
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    sync::mpsc::{self, UnboundedSender},
+};
+
+use crate::{
+    auth::{self, scope::Scope, Principal},
+    db,
+    guest::GuestMode,
+    ip_ban,
+    server::ServerState,
+    shutdown::Shutdown,
+    user::{self, Admission, PostOutcome, Refusal, User, UserRx},
+};
+
+// Name the gateway gives itself, as the prefix of the replies it sends.
+const SERVER_NAME: &str = "bi-chat";
+
+// Longest line, without its CRLF, IRC clients may send. Longer lines are
+// ignored, without ever being held whole.
+const MAX_LINE_LENGTH: usize = 510;
+
+// How long clients have to register once connected, before they are
+// disconnected.
+const REGISTRATION_TIMEOUT: Duration = Duration::from_secs(30);
+
+// A command sent by an IRC client, e.g. `PRIVMSG #rust :hello`.
+#[derive(Debug, PartialEq)]
+struct Command<'a> {
+    // Uppercased, e.g. `PRIVMSG`
+    name: String,
+    params: Vec<&'a str>,
+}
+
+impl<'a> Command<'a> {
+    // Parses `line`, ignoring its prefix if any. Returns None for blank lines.
+    fn parse(line: &'a str) -> Option<Self> {
+        let mut rest = line.trim_end_matches(['\r', '\n']).trim_start();
+        if rest.starts_with(':') {
+            rest = rest
+                .split_once(' ')
+                .map_or("", |(_, rest)| rest)
+                .trim_start();
+        }
+
+        let (name, mut rest) = rest.split_once(' ').unwrap_or((rest, ""));
+        if name.is_empty() {
+            return None;
+        }
+
+        let mut params = Vec::new();
+        loop {
+            rest = rest.trim_start_matches(' ');
+            if rest.is_empty() {
+                break;
+            }
+            if let Some(trailing) = rest.strip_prefix(':') {
+                params.push(trailing);
+                break;
+            }
+            let (param, after) = rest.split_once(' ').unwrap_or((rest, ""));
+            params.push(param);
+            rest = after;
+        }
+
+        Some(Command {
+            name: name.to_ascii_uppercase(),
+            params,
+        })
+    }
+}
+
+// Room a channel maps to, e.g. `rust` for `#rust`.
+fn channel_room(channel: &str) -> Option<&str> {
+    channel.strip_prefix('#').filter(|room| !room.is_empty())
+}
+
+// How a user shows up in the prefix of the lines relaying what they did.
+fn user_prefix(nick: Option<&str>, user_id: usize) -> String {
+    match nick {
+        Some(nick) => format!("{}!{}@{}", nick.replace(' ', "_"), user_id, SERVER_NAME),
+        None => format!("user{}!{}@{}", user_id, user_id, SERVER_NAME),
+    }
+}
+
+// The lines telling an IRC client of `event`, a server event as sent over
+// WebSockets, if IRC has a say for it.
+fn event_lines(event: &Value) -> Vec<String> {
+    let channel = format!("#{}", event["room"].as_str().unwrap_or_default());
+    let prefix = || {
+        user_prefix(
+            event["nick"].as_str(),
+            event["user_id"].as_u64().unwrap_or_default() as usize,
+        )
+    };
+
+    match event["type"].as_str().unwrap_or_default() {
+        "message" => event["text"]
+            .as_str()
+            .unwrap_or_default()
+            .lines()
+            .map(|line| format!(":{} PRIVMSG {} :{}", prefix(), channel, line))
+            .collect(),
+        "join" => vec![format!(":{} JOIN {}", prefix(), channel)],
+        "leave" => vec![format!(":{} PART {}", prefix(), channel)],
+        "rename" => {
+            let old_prefix = user_prefix(
+                event["old_nick"].as_str(),
+                event["user_id"].as_u64().unwrap_or_default() as usize,
+            );
+            vec![format!(
+                ":{} NICK :{}",
+                old_prefix,
+                event["nick"].as_str().unwrap_or_default()
+            )]
+        }
+        "topic" => vec![format!(
+            ":{} TOPIC {} :{}",
+            SERVER_NAME,
+            channel,
+            event["topic"].as_str().unwrap_or_default()
+        )],
+        "error" => vec![format!(
+            ":{} NOTICE {} :{}",
+            SERVER_NAME,
+            channel,
+            event["reason"].as_str().unwrap_or_default()
+        )],
+        _ => Vec::new(),
+    }
+}
+
+// Numeric reply `code` to the client known as `nick`.
+fn numeric(code: &str, nick: &str, params: &str) -> String {
+    format!(":{} {} {} {}", SERVER_NAME, code, nick, params)
+}
+
+// Accepts IRC clients on `port` until shutdown, letting them join rooms as
+// channels and chat in them.
+pub async fn listen(port: u16, state: ServerState, mut shutdown: Shutdown) {
+    let listener = TcpListener::bind(("127.0.0.1", port))
+        .await
+        .expect("Unable to bind IRC listener");
+
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, addr)) => {
+                    tokio::task::spawn(serve(stream, addr, state.clone()));
+                }
+                Err(e) => eprintln!("Failed to accept IRC client: {}", e),
+            },
+            _ = shutdown.async_listen() => break,
+        }
+    }
+}
+
+// An IRC client, once registered.
+struct Client {
+    principal: Principal,
+    // Set for clients registering without a token
+    is_guest: bool,
+    nick: String,
+    addr: SocketAddr,
+    // Lines waiting to be sent to the client
+    lines: UnboundedSender<String>,
+    // A `User` per channel joined, by room
+    channels: HashMap<String, User>,
+    state: ServerState,
+}
+
+async fn serve(stream: TcpStream, addr: SocketAddr, state: ServerState) {
+    let (reader, mut writer) = stream.into_split();
+    let (lines_tx, mut lines_rx) = mpsc::unbounded_channel::<String>();
+    let writing = tokio::task::spawn(async move {
+        while let Some(line) = lines_rx.recv().await {
+            if writer
+                .write_all(format!("{}\r\n", line).as_bytes())
+                .await
+                .is_err()
+            {
+                break;
+            }
+        }
+    });
+
+    let mut reader = BufReader::new(reader);
+    let registered = tokio::time::timeout(
+        REGISTRATION_TIMEOUT,
+        register(&mut reader, addr, lines_tx.clone(), &state),
+    )
+    .await;
+    match registered {
+        Ok(Some(mut client)) => {
+            while let Some(line) = next_line(&mut reader).await {
+                match Command::parse(&line) {
+                    Some(command) if command.name == "QUIT" => break,
+                    Some(command) => client.handle(command).await,
+                    None => {}
+                }
+            }
+
+            client.quit().await;
+        }
+        Ok(None) => {}
+        Err(_) => {
+            let _ = lines_tx.send(String::from("ERROR :Registration timed out"));
+        }
+    }
+    drop(lines_tx);
+
+    // Whatever is left to send is sent before the connection is closed
+    let _ = writing.await;
+}
+
+// Reads the next line of `reader`, without its CRLF, skipping lines longer
+// than allowed. None once the connection is lost.
+async fn next_line<R>(reader: &mut R) -> Option<String>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut line = Vec::new();
+    let mut too_long = false;
+    loop {
+        line.clear();
+        match reader
+            .take(MAX_LINE_LENGTH as u64 + 2)
+            .read_until(b'\n', &mut line)
+            .await
+        {
+            Ok(0) | Err(_) => return None,
+            Ok(_) => {}
+        }
+        // The rest of a line too long is read in chunks, until its end
+        if !line.ends_with(b"\n") {
+            too_long = true;
+            continue;
+        }
+
+        let text = String::from_utf8_lossy(&line);
+        let text = text.trim_end_matches(['\r', '\n']);
+        if std::mem::take(&mut too_long) || text.len() > MAX_LINE_LENGTH {
+            continue;
+        }
+
+        return Some(String::from(text));
+    }
+}
+
+// Registers a client from its `PASS`, `NICK` and `USER` commands. `PASS` is
+// a JWT or API token, without which the client is a guest.
+async fn register<R>(
+    reader: &mut R,
+    addr: SocketAddr,
+    lines_tx: UnboundedSender<String>,
+    state: &ServerState,
+) -> Option<Client>
+where
+    R: AsyncBufRead + Unpin,
+{
+    // Banned addresses are refused before anything is done on their behalf
+    let ip = addr.ip();
+    match db::query(&state.db_tx, move |conn| ip_ban::is_banned(conn, ip)).await {
+        Ok(false) => {}
+        Ok(true) => {
+            let _ = lines_tx.send(String::from("ERROR :Your address is banned"));
+            return None;
+        }
+        Err(e) => {
+            eprintln!("Failed to check IP bans: {}", e);
+            return None;
+        }
+    }
+
+    let (mut token, mut nick, mut user) = (None, None, false);
+    while nick.is_none() || !user {
+        let line = next_line(reader).await?;
+        let command = match Command::parse(&line) {
+            Some(command) => command,
+            None => continue,
+        };
+        match (command.name.as_str(), command.params.first()) {
+            ("PASS", Some(pass)) => token = Some(String::from(*pass)),
+            ("NICK", Some(given)) => match user::validate_nickname(given) {
+                Ok(given) => nick = Some(given),
+                Err(e) => {
+                    let _ = lines_tx.send(numeric("432", "*", &format!("{} :{}", given, e)));
+                }
+            },
+            ("USER", Some(_)) => user = true,
+            ("PASS" | "NICK" | "USER", None) => {
+                let reply = numeric(
+                    "461",
+                    "*",
+                    &format!("{} :Not enough parameters", command.name),
+                );
+                let _ = lines_tx.send(reply);
+            }
+            ("QUIT", _) => return None,
+            ("PING", token) => {
+                let _ = lines_tx.send(format!("PONG {} :{}", SERVER_NAME, token.unwrap_or(&"")));
+            }
+            ("CAP", _) => {}
+            _ => {
+                let _ = lines_tx.send(numeric("451", "*", ":You have not registered"));
+            }
+        }
+    }
+    let nick = nick?;
+
+    let is_guest = token.is_none();
+    let principal =
+        match auth::connection_user(&state.db_tx, &state.jwt, token, None, Some(ip)).await {
+            Ok(Some(principal)) => principal,
+            Ok(None) => {
+                let _ = lines_tx.send(numeric("464", &nick, ":Invalid token"));
+                return None;
+            }
+            Err(e) => {
+                eprintln!("Failed to authenticate IRC client: {}", e);
+                return None;
+            }
+        };
+
+    // Nicknames are reserved, and remembered for later connections, as
+    // when connecting over WebSockets
+    let (user_id, new_nick) = (principal.user_id, nick.clone());
+    let reserved = db::query(&state.db_tx, move |conn| {
+        db::reserve_nickname(conn, user_id, &new_nick)
+    })
+    .await;
+    match reserved {
+        Ok(true) => {}
+        Ok(false) => {
+            let _ = lines_tx.send(numeric(
+                "433",
+                "*",
+                &format!("{} :Nickname is already in use", nick),
+            ));
+            return None;
+        }
+        Err(e) => {
+            eprintln!("Failed to reserve nickname: {}", e);
+            return None;
+        }
+    }
+    state.nicks.write().await.insert(user_id, nick.clone());
+
+    let _ = lines_tx.send(numeric(
+        "001",
+        &nick,
+        &format!(":Welcome to {}, {}", SERVER_NAME, nick),
+    ));
+    let _ = lines_tx.send(numeric("422", &nick, ":MOTD File is missing"));
+
+    Some(Client {
+        principal,
+        is_guest,
+        nick,
+        addr,
+        lines: lines_tx,
+        channels: HashMap::new(),
+        state: state.clone(),
+    })
+}
+
+impl Client {
+    fn send(&self, line: String) {
+        // This will only fail once the client has disconnected
+        if let Err(_disconnected) = self.lines.send(line) {}
+    }
+
+    fn reply(&self, code: &str, params: &str) {
+        self.send(numeric(code, &self.nick, params));
+    }
+
+    async fn handle(&mut self, command: Command<'_>) {
+        match command.name.as_str() {
+            "PING" => self.send(format!(
+                "PONG {} :{}",
+                SERVER_NAME,
+                command.params.first().unwrap_or(&"")
+            )),
+            "PONG" | "CAP" | "USER" | "PASS" => {}
+            "NICK" => match command.params.first() {
+                Some(nick) => self.rename(nick).await,
+                None => self.reply("431", ":No nickname given"),
+            },
+            "JOIN" => match command.params.first() {
+                Some(channels) => {
+                    let mut keys = command
+                        .params
+                        .get(1)
+                        .map(|keys| keys.split(',').collect::<Vec<_>>())
+                        .unwrap_or_default()
+                        .into_iter();
+                    for channel in channels.split(',') {
+                        self.join(channel, keys.next()).await;
+                    }
+                }
+                None => self.reply("461", "JOIN :Not enough parameters"),
+            },
+            "PART" => match command.params.first() {
+                Some(channels) => {
+                    for channel in channels.split(',') {
+                        self.part(channel).await;
+                    }
+                }
+                None => self.reply("461", "PART :Not enough parameters"),
+            },
+            "PRIVMSG" | "NOTICE" => match command.params[..] {
+                [target, text, ..] => self.privmsg(target, text).await,
+                _ => self.reply("412", ":No text to send"),
+            },
+            name => self.reply("421", &format!("{} :Unknown command", name)),
+        }
+    }
+
+    // Joins the room `channel` maps to, with `key` as its password.
+    async fn join(&mut self, channel: &str, key: Option<&str>) {
+        let room_name = match channel_room(channel) {
+            Some(room_name) => String::from(room_name),
+            None => return self.reply("403", &format!("{} :No such channel", channel)),
+        };
+        if self.channels.contains_key(&room_name) {
+            return;
+        }
+
+        let cannot_join = |reason: &str| {
+            numeric(
+                "474",
+                &self.nick,
+                &format!("{} :Cannot join channel ({})", channel, reason),
+            )
+        };
+        let state = &self.state;
+        let is_guest = self.is_guest;
+        let guest_mode = state.config.guest_mode(&room_name);
+        if is_guest && guest_mode == GuestMode::Disabled {
+            return self.send(cannot_join("guests may not join"));
+        }
+        if !self.principal.allows(&Scope::Read(Some(room_name.clone()))) {
+            return self.send(cannot_join("token lacks the read scope"));
+        }
+
+        let (user_tx, user_rx) = mpsc::unbounded_channel();
+        let new_user = state.gateway_user(
+            &room_name,
+            self.principal.clone(),
+            is_guest,
+            Some(self.addr.ip()),
+            user_tx,
+        );
+
+        let history_seq = match new_user
+            .send_history(state.config.history_limit, None)
+            .await
+        {
+            Ok(history_seq) => Some(history_seq),
+            Err(e) => {
+                eprintln!("Failed to send room history: {}", e);
+                None
+            }
+        };
+        let joined = user::add_user_to_room(
+            &new_user,
+            &state.rooms,
+            state.config.explicit_rooms,
+            state.config.room_capacity,
+            key.map(String::from),
+            history_seq,
+        )
+        .await;
+        let refusal = match joined {
+            Ok(Ok(Admission::Joined)) => None,
+            // IRC has no way to wait for approval
+            Ok(Ok(Admission::Pending(_))) => Some(Refusal::JoinRejected),
+            Ok(Err(refusal)) => Some(refusal),
+            Err(e) => {
+                eprintln!("Failed to join room {}: {}", room_name, e);
+                return self.send(cannot_join("internal error"));
+            }
+        };
+        if let Some(refusal) = refusal {
+            let reply = match refusal {
+                Refusal::WrongPassword => numeric(
+                    "475",
+                    &self.nick,
+                    &format!("{} :Cannot join channel (+k)", channel),
+                ),
+                Refusal::RoomFull { .. } => numeric(
+                    "471",
+                    &self.nick,
+                    &format!("{} :Cannot join channel (+l)", channel),
+                ),
+                Refusal::RoomNotFound => {
+                    numeric("403", &self.nick, &format!("{} :No such channel", channel))
+                }
+                Refusal::AccessDenied => cannot_join("not allowed"),
+                Refusal::JoinRejected => cannot_join("approval required"),
+            };
+            self.send(reply);
+            return user::remove_user_from_room(&new_user, &state.rooms).await;
+        }
+        if let Err(e) = new_user.announce_join(&state.rooms).await {
+            eprintln!("Failed to announce joining {}: {}", room_name, e);
+        }
+
+        self.send(format!(
+            ":{} JOIN {}",
+            user_prefix(Some(&self.nick), self.principal.user_id),
+            channel
+        ));
+        let names: Vec<_> = user::online_users(&room_name, &state.rooms, &state.nicks)
+            .await
+            .into_iter()
+            .map(|online| match online.nick {
+                Some(nick) => nick.replace(' ', "_"),
+                None => format!("user{}", online.user_id),
+            })
+            .collect();
+        self.reply("353", &format!("= {} :{}", channel, names.join(" ")));
+        self.reply("366", &format!("{} :End of /NAMES list", channel));
+
+        tokio::task::spawn(forward(user_rx, self.lines.clone()));
+        self.channels.insert(room_name, new_user);
+    }
+
+    async fn part(&mut self, channel: &str) {
+        let user = match channel_room(channel).and_then(|room| self.channels.remove(room)) {
+            Some(user) => user,
+            None => return self.reply("442", &format!("{} :You're not on that channel", channel)),
+        };
+
+        user::remove_user_from_room(&user, &self.state.rooms).await;
+        self.send(format!(
+            ":{} PART {}",
+            user_prefix(Some(&self.nick), self.principal.user_id),
+            channel
+        ));
+    }
+
+    async fn privmsg(&self, target: &str, text: &str) {
+        let user = match channel_room(target).and_then(|room| self.channels.get(room)) {
+            Some(user) => user,
+            None => return self.reply("404", &format!("{} :Cannot send to channel", target)),
+        };
+
+        let sent = user
+            .send_message(text, None, None, None, None, &self.state.rooms)
+            .await
+            .and_then(PostOutcome::accepted);
+        if let Err(e) = sent {
+            self.send(format!(":{} NOTICE {} :{}", SERVER_NAME, target, e));
+        }
+    }
+
+    // Changes the nickname of the client, telling the rooms it is in.
+    async fn rename(&mut self, nick: &str) {
+        let renamed = match self.channels.values().next() {
+            Some(user) => user.set_nick(String::from(nick), &self.state.rooms).await,
+            None => self.reserve(nick).await,
+        };
+        match renamed {
+            Ok(()) => {
+                let old_prefix = user_prefix(Some(&self.nick), self.principal.user_id);
+                self.nick = user::validate_nickname(nick).unwrap_or_else(|_| String::from(nick));
+                // Clients in channels hear of it through their rooms
+                if self.channels.is_empty() {
+                    self.send(format!(":{} NICK :{}", old_prefix, self.nick));
+                }
+            }
+            Err(e) => self.reply("433", &format!("{} :{}", nick, e)),
+        }
+    }
+
+    // Reserves `nick` for the client, outside of any room.
+    async fn reserve(&self, nick: &str) -> Result<(), anyhow::Error> {
+        let nick = user::validate_nickname(nick)?;
+        let (user_id, new_nick) = (self.principal.user_id, nick.clone());
+        let reserved = db::query(&self.state.db_tx, move |conn| {
+            db::reserve_nickname(conn, user_id, &new_nick)
+        })
+        .await?;
+        if !reserved {
+            return Err(anyhow::anyhow!("Nickname {} is already taken", nick));
+        }
+        self.state.nicks.write().await.insert(user_id, nick);
+
+        Ok(())
+    }
+
+    // Leaves every channel, once the client disconnected. Guests give up
+    // their nickname, as when disconnecting from WebSockets.
+    async fn quit(self) {
+        for user in self.channels.values() {
+            user::remove_user_from_room(user, &self.state.rooms).await;
+        }
+
+        if self.is_guest {
+            let user_id = self.principal.user_id;
+            if let Err(e) = db::query(&self.state.db_tx, move |conn| {
+                db::release_nickname(conn, user_id)
+            })
+            .await
+            {
+                eprintln!("Failed to release nickname(uid={}): {}", user_id, e);
+            }
+            self.state.nicks.write().await.remove(&user_id);
+        }
+    }
+}
+
+// Sends what a room sends to the `User` of a channel to its client, as IRC
+// lines.
+async fn forward(mut user_rx: UserRx, lines: UnboundedSender<String>) {
+    while let Some(msg) = user_rx.recv().await {
+        if msg.is_close() {
+            break;
+        }
+
+        let event = match msg
+            .to_str()
+            .ok()
+            .and_then(|text| serde_json::from_str(text).ok())
+        {
+            Some(event) => event,
+            None => continue,
+        };
+        for line in event_lines(&event) {
+            if lines.send(line).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!(
+            Command::parse("privmsg #rust :hello there\r\n"),
+            Some(Command {
+                name: String::from("PRIVMSG"),
+                params: vec!["#rust", "hello there"],
+            })
+        );
+        assert_eq!(
+            Command::parse(":alice!a@host JOIN #rust,#go key"),
+            Some(Command {
+                name: String::from("JOIN"),
+                params: vec!["#rust,#go", "key"],
+            })
+        );
+        assert_eq!(Command::parse("QUIT").unwrap().params.len(), 0);
+        assert_eq!(Command::parse("   "), None);
+    }
+
+    #[tokio::test]
+    async fn test_next_line() {
+        let long = "x".repeat(2 * MAX_LINE_LENGTH);
+        let input = format!("NICK alice\r\n{}\r\nUSER a 0 * :A\nPING", long);
+        let mut reader = input.as_bytes();
+
+        assert_eq!(next_line(&mut reader).await.as_deref(), Some("NICK alice"));
+        // Lines too long are skipped whole
+        assert_eq!(
+            next_line(&mut reader).await.as_deref(),
+            Some("USER a 0 * :A")
+        );
+        // As are lines cut short by the connection closing
+        assert_eq!(next_line(&mut reader).await, None);
+    }
+
+    #[test]
+    fn test_event_lines() {
+        let message = json!({
+            "type": "message",
+            "room": "rust",
+            "user_id": 1,
+            "nick": "alice",
+            "text": "hello\nworld",
+        });
+        assert_eq!(
+            event_lines(&message),
+            vec![
+                ":alice!1@bi-chat PRIVMSG #rust :hello",
+                ":alice!1@bi-chat PRIVMSG #rust :world",
+            ]
+        );
+
+        let leave = json!({ "type": "leave", "room": "rust", "user_id": 2 });
+        assert_eq!(event_lines(&leave), vec![":user2!2@bi-chat PART #rust"]);
+
+        // Events IRC has no say for are not relayed
+        let ack = json!({ "type": "ack", "room": "rust", "id": 1 });
+        assert!(event_lines(&ack).is_empty());
+    }
+}
@@ -1,6 +1,54 @@
+pub mod alert;
+pub mod announcement;
+pub mod auth;
+pub mod authz;
+pub mod classifier;
+pub mod cluster;
+pub mod compression;
+pub mod config;
+pub mod conversation;
 pub mod db;
+pub mod dead_letter;
+pub mod discord;
+pub mod export;
+pub mod federation;
+pub mod filter;
+pub mod graphql;
+pub mod grpc;
+pub mod guest;
+pub mod handlers;
+pub mod history;
+pub mod hook;
 pub mod html;
+pub mod invite;
+pub mod ip_ban;
+pub mod irc;
+pub mod mail;
+pub mod maintenance;
+pub mod mention;
+pub mod migration;
+pub mod moderation;
+pub mod notification;
+pub mod offline;
+pub mod openapi;
+pub mod poll;
+pub mod profile;
+pub mod proto;
+pub mod protocol;
+pub mod read_pool;
+pub mod report;
+pub mod revision;
+pub mod room;
 pub mod routes;
+pub mod schedule;
+pub mod search;
 pub mod server;
 pub mod shutdown;
+pub mod sink;
+pub mod spam;
+pub mod sse;
+pub mod store;
+pub mod telemetry;
 pub mod user;
+pub mod wire;
+pub mod xmpp;
@@ -0,0 +1,946 @@
+use std::{str::FromStr, time::Duration};
+
+use anyhow::anyhow;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use lettre::{
+    message::{header::ContentType, Mailbox},
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+};
+
+use rusqlite::Connection;
+
+use crate::{
+    auth::oauth,
+    authz::{self, Role},
+    db::{self, DBMessage},
+    room,
+    server::ServerState,
+    shutdown::Shutdown,
+    user,
+};
+
+// Provider the accounts of email senders are recorded under, as external
+// identities, by their address.
+const EMAIL_PROVIDER: &str = "email";
+
+// Name the gateway gives itself in its greeting and replies.
+const SERVER_NAME: &str = "bi-chat";
+
+// Longest command line, with its CRLF, mail servers may send. The connection
+// is dropped on longer ones.
+const MAX_LINE_LENGTH: usize = 1000;
+
+// Largest mail accepted, in bytes, attachments included.
+const MAX_MAIL_SIZE: usize = 1 << 20;
+
+// Most rooms a single mail may be posted to.
+const MAX_RECIPIENTS: usize = 20;
+
+// A room whose new messages are mailed to an address every
+// `--mail-digest-secs`, given with `--mail-digest <room>=<address>`.
+#[derive(Clone, Debug)]
+pub struct MailDigest {
+    pub room: String,
+    pub address: String,
+}
+
+impl FromStr for MailDigest {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((room, address)) if !room.is_empty() && address.contains('@') => Ok(MailDigest {
+                room: String::from(room),
+                address: String::from(address),
+            }),
+            _ => Err(anyhow!("Expected <room>=<address>, got '{}'", s)),
+        }
+    }
+}
+
+// A sender or recipient of a mail, e.g. `"Alice" <alice@example.org>`.
+#[derive(Debug, PartialEq)]
+pub struct Address {
+    pub name: Option<String>,
+    // Lowercased
+    pub address: String,
+}
+
+impl Address {
+    fn parse(value: &str) -> Option<Self> {
+        let (name, address) = match (value.rfind('<'), value.rfind('>')) {
+            (Some(start), Some(end)) if start < end => (
+                decode_words(value[..start].trim().trim_matches('"')),
+                &value[start + 1..end],
+            ),
+            // e.g. `alice@example.org (Alice)`
+            _ => (
+                String::new(),
+                value.split_whitespace().find(|word| word.contains('@'))?,
+            ),
+        };
+        let address = address.trim();
+        if !address.contains('@') {
+            return None;
+        }
+
+        Some(Address {
+            name: Some(name).filter(|name| !name.is_empty()),
+            address: address.to_lowercase(),
+        })
+    }
+
+    // How the sender shows up in rooms: their name if it makes a valid
+    // nickname, else their address.
+    fn nick(&self) -> String {
+        self.name
+            .as_deref()
+            .and_then(|name| user::validate_nickname(name).ok())
+            .unwrap_or_else(|| self.address_nick())
+    }
+
+    // The address of the sender as a nickname, shortened to its local part if
+    // too long.
+    fn address_nick(&self) -> String {
+        user::validate_nickname(&self.address).unwrap_or_else(|_| {
+            let local = self.address.split('@').next().unwrap_or_default();
+            local.chars().take(user::MAX_NICKNAME_LENGTH).collect()
+        })
+    }
+}
+
+// A mail, as far as it makes up a message.
+#[derive(Debug, Default, PartialEq)]
+pub struct Mail {
+    pub from: Option<Address>,
+    pub subject: String,
+    // Plain text of the mail, without its signature nor the quoted mail it
+    // replies to
+    pub body: String,
+    // Set for mail sent by machines rather than people, e.g. out of office
+    // replies, which are never posted
+    pub auto_submitted: bool,
+}
+
+impl Mail {
+    pub fn parse(raw: &str) -> Self {
+        let (headers, body) = split_headers(raw);
+        let header = |name: &str| {
+            headers
+                .iter()
+                .find(|(header, _)| header == name)
+                .map(|(_, value)| value.as_str())
+        };
+
+        Mail {
+            from: header("from").and_then(Address::parse),
+            subject: header("subject").map(decode_words).unwrap_or_default(),
+            body: part_text(&headers, body)
+                .map(|text| strip_reply(&text))
+                .unwrap_or_default(),
+            auto_submitted: header("auto-submitted")
+                .is_some_and(|value| !value.trim().eq_ignore_ascii_case("no")),
+        }
+    }
+
+    // The text of the message to post: the subject, then the body. None if it
+    // would be empty.
+    pub fn message(&self) -> Option<String> {
+        let text = match (self.subject.trim(), self.body.trim()) {
+            (subject, "") => String::from(subject),
+            ("", body) => String::from(body),
+            (subject, body) => format!("{}\n\n{}", subject, body),
+        };
+        Some(text).filter(|text| !text.is_empty())
+    }
+}
+
+// Splits `raw` into its headers, unfolded, with lowercased names, and its
+// body.
+fn split_headers(raw: &str) -> (Vec<(String, String)>, &str) {
+    let (head, body) = match raw.find("\r\n\r\n") {
+        Some(end) => (&raw[..end], &raw[end + 4..]),
+        None => match raw.find("\n\n") {
+            Some(end) => (&raw[..end], &raw[end + 2..]),
+            None => (raw, ""),
+        },
+    };
+
+    let mut headers: Vec<(String, String)> = Vec::new();
+    for line in head.lines() {
+        if line.starts_with([' ', '\t']) {
+            if let Some((_, value)) = headers.last_mut() {
+                value.push(' ');
+                value.push_str(line.trim());
+            }
+        } else if let Some((name, value)) = line.split_once(':') {
+            headers.push((name.trim().to_ascii_lowercase(), String::from(value.trim())));
+        }
+    }
+
+    (headers, body)
+}
+
+// Parameter `name` of a header value, e.g. the boundary of
+// `multipart/mixed; boundary="abc"`.
+fn header_param(value: &str, name: &str) -> Option<String> {
+    value.split(';').skip(1).find_map(|param| {
+        let (key, value) = param.split_once('=')?;
+        key.trim()
+            .eq_ignore_ascii_case(name)
+            .then(|| String::from(value.trim().trim_matches('"')))
+    })
+}
+
+// The plain text of a part: its own if it is plain text, else that of the
+// first plain text part it contains. Attachments are skipped.
+fn part_text(headers: &[(String, String)], body: &str) -> Option<String> {
+    let header = |name: &str| {
+        headers
+            .iter()
+            .find(|(header, _)| header == name)
+            .map(|(_, value)| value.as_str())
+    };
+    if header("content-disposition").is_some_and(|value| {
+        value
+            .trim_start()
+            .to_ascii_lowercase()
+            .starts_with("attachment")
+    }) {
+        return None;
+    }
+
+    let content_type = header("content-type").unwrap_or("text/plain");
+    let mime = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    if mime.starts_with("multipart/") {
+        let boundary = format!("--{}", header_param(content_type, "boundary")?);
+        return body
+            .split(boundary.as_str())
+            // What comes before the first boundary is a preamble, and what
+            // comes after the last one, starting with `--`, an epilogue
+            .skip(1)
+            .take_while(|part| !part.starts_with("--"))
+            .find_map(|part| {
+                let part = part
+                    .strip_prefix("\r\n")
+                    .or_else(|| part.strip_prefix('\n'))?;
+                let (headers, body) = split_headers(part);
+                part_text(&headers, body)
+            });
+    }
+    if mime != "text/plain" {
+        return None;
+    }
+
+    let bytes = match header("content-transfer-encoding")
+        .map(|encoding| encoding.trim().to_ascii_lowercase())
+        .as_deref()
+    {
+        Some("base64") => {
+            let encoded: String = body.split_whitespace().collect();
+            BASE64.decode(encoded).ok()?
+        }
+        Some("quoted-printable") => decode_quoted_printable(body, false),
+        _ => body.as_bytes().to_vec(),
+    };
+    let charset = header_param(content_type, "charset").unwrap_or_default();
+
+    Some(decode_charset(&charset, &bytes).replace("\r\n", "\n"))
+}
+
+// Text in `charset`. Latin-1 is decoded as such, and anything else as UTF-8,
+// replacing what is not.
+fn decode_charset(charset: &str, bytes: &[u8]) -> String {
+    match charset.to_ascii_lowercase().as_str() {
+        "iso-8859-1" | "latin1" | "windows-1252" => bytes.iter().map(|&b| b as char).collect(),
+        _ => String::from_utf8_lossy(bytes).into_owned(),
+    }
+}
+
+// Decodes quoted-printable `text`, or the Q encoding of encoded-words, where
+// `_` stands for a space, if `words` is set.
+fn decode_quoted_printable(text: &str, words: bool) -> Vec<u8> {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'=' => {
+                let rest = &text[i + 1..];
+                if let Some(after) = rest
+                    .strip_prefix("\r\n")
+                    .or_else(|| rest.strip_prefix('\n'))
+                {
+                    // Soft line break
+                    i = bytes.len() - after.len();
+                    continue;
+                }
+                match rest
+                    .get(..2)
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                {
+                    Some(byte) => {
+                        decoded.push(byte);
+                        i += 3;
+                    }
+                    None => {
+                        decoded.push(b'=');
+                        i += 1;
+                    }
+                }
+            }
+            b'_' if words => {
+                decoded.push(b' ');
+                i += 1;
+            }
+            byte => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+
+    decoded
+}
+
+// Decodes the encoded-words of a header value, e.g. `=?UTF-8?Q?caf=C3=A9?=`.
+// Whitespace between encoded-words is dropped.
+fn decode_words(value: &str) -> String {
+    let mut decoded = String::with_capacity(value.len());
+    let mut rest = value;
+    let mut after_word = false;
+    while let Some(start) = rest.find("=?") {
+        let word = rest[start + 2..].splitn(3, '?').collect::<Vec<_>>();
+        let text = match word.as_slice() {
+            [charset, encoding, text] => text.split_once("?=").and_then(|(text, _)| {
+                let bytes = match encoding.to_ascii_uppercase().as_str() {
+                    "B" => BASE64.decode(text).ok()?,
+                    "Q" => decode_quoted_printable(text, true),
+                    _ => return None,
+                };
+                let len = charset.len() + encoding.len() + text.len() + 6;
+                Some((decode_charset(charset, &bytes), len))
+            }),
+            _ => None,
+        };
+
+        let before = &rest[..start];
+        if !(after_word && before.trim().is_empty()) {
+            decoded.push_str(before);
+        }
+        match text {
+            Some((text, len)) => {
+                decoded.push_str(&text);
+                rest = &rest[start + len..];
+                after_word = true;
+            }
+            None => {
+                decoded.push_str("=?");
+                rest = &rest[start + 2..];
+                after_word = false;
+            }
+        }
+    }
+    decoded.push_str(rest);
+
+    decoded
+}
+
+// `text` without its signature, following a `-- ` line, nor the quoted mail
+// it replies to, e.g. `On Monday, Alice wrote:` then `> ...` lines.
+fn strip_reply(text: &str) -> String {
+    let mut lines: Vec<&str> = text
+        .lines()
+        .take_while(|line| line.trim_end_matches('\r') != "-- ")
+        .collect();
+    while lines
+        .last()
+        .is_some_and(|line| line.trim().is_empty() || line.starts_with('>'))
+    {
+        lines.pop();
+    }
+    if lines
+        .last()
+        .is_some_and(|line| line.trim_end().ends_with("wrote:"))
+    {
+        lines.pop();
+    }
+
+    lines.join("\n").trim().to_string()
+}
+
+// Accepts mail on `port` until shutdown, posting mail sent to
+// `<room>@<domain>` to that room as an account of its sender.
+pub async fn listen(port: u16, domain: String, state: ServerState, mut shutdown: Shutdown) {
+    let listener = TcpListener::bind(("127.0.0.1", port))
+        .await
+        .expect("Unable to bind mail listener");
+
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => {
+                    tokio::task::spawn(serve(stream, domain.clone(), state.clone()));
+                }
+                Err(e) => eprintln!("Failed to accept mail: {}", e),
+            },
+            _ = shutdown.async_listen() => break,
+        }
+    }
+}
+
+// Who a mail is from and which rooms it is for, as told by `MAIL` and `RCPT`.
+#[derive(Default)]
+struct Envelope {
+    sender: Option<String>,
+    // Account of the sender
+    user_id: usize,
+    rooms: Vec<String>,
+}
+
+// Whether mail is accepted from `address`, given the addresses and
+// `@<domain>`s of `--mail-sender`. Bounces, from an empty address, never are.
+fn sender_allowed(allowed: &[String], address: &str) -> bool {
+    let domain = match address.rsplit_once('@') {
+        Some((_, domain)) => domain,
+        None => return false,
+    };
+    allowed.iter().any(|allowed| {
+        allowed.eq_ignore_ascii_case(address)
+            || allowed
+                .strip_prefix('@')
+                .is_some_and(|allowed| allowed.eq_ignore_ascii_case(domain))
+    })
+}
+
+// Whether `user_id` may post to `room_name` by mail: the room must exist, and
+// they must be let into it without its password or approval, as if they
+// joined it.
+fn may_post(conn: &Connection, user_id: usize, room_name: &str) -> Result<bool, rusqlite::Error> {
+    let settings = match room::settings(conn, room_name)? {
+        Some(settings) => settings,
+        None => return Ok(false),
+    };
+    if !authz::may_join(conn, user_id, room_name)? {
+        return Ok(false);
+    }
+    if authz::room_role(conn, user_id, room_name)? == Role::Admin {
+        return Ok(true);
+    }
+
+    if settings.password_protected {
+        return Ok(false);
+    }
+
+    Ok(!settings.approval_required || room::is_member(conn, room_name, user_id)?)
+}
+
+// Address of a `MAIL FROM:<...>` or `RCPT TO:<...>` command, ignoring its
+// parameters.
+fn path(arg: &str, prefix: &str) -> Option<String> {
+    let arg = arg.trim();
+    if !arg
+        .get(..prefix.len())
+        .is_some_and(|start| start.eq_ignore_ascii_case(prefix))
+    {
+        return None;
+    }
+    let path = arg[prefix.len()..].trim_start();
+    let path = path.strip_prefix('<')?;
+    let end = path.find('>')?;
+
+    Some(path[..end].to_lowercase())
+}
+
+async fn serve(stream: TcpStream, domain: String, state: ServerState) {
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut envelope = Envelope::default();
+    let mut line = Vec::new();
+
+    macro_rules! reply {
+        ($($arg:tt)*) => {
+            if writer
+                .write_all(format!("{}\r\n", format!($($arg)*)).as_bytes())
+                .await
+                .is_err()
+            {
+                return;
+            }
+        };
+    }
+
+    reply!("220 {} ESMTP", SERVER_NAME);
+    loop {
+        line.clear();
+        match (&mut reader)
+            .take(MAX_LINE_LENGTH as u64)
+            .read_until(b'\n', &mut line)
+            .await
+        {
+            Ok(0) | Err(_) => return,
+            Ok(_) if !line.ends_with(b"\n") => {
+                reply!("500 Line too long");
+                return;
+            }
+            Ok(_) => {}
+        }
+
+        let line = String::from_utf8_lossy(&line);
+        let line = line.trim_end_matches(['\r', '\n']);
+        let (verb, arg) = line.split_once(' ').unwrap_or((line, ""));
+        match verb.to_ascii_uppercase().as_str() {
+            "HELO" => {
+                envelope = Envelope::default();
+                reply!("250 {}", SERVER_NAME);
+            }
+            "EHLO" => {
+                envelope = Envelope::default();
+                reply!("250-{}", SERVER_NAME);
+                reply!("250-8BITMIME");
+                reply!("250 SIZE {}", MAX_MAIL_SIZE);
+            }
+            "MAIL" => match path(arg, "FROM:") {
+                _ if envelope.sender.is_some() => reply!("503 Sender already given"),
+                Some(sender) if !sender_allowed(&state.config.mail_senders, &sender) => {
+                    reply!("550 Sender not allowed")
+                }
+                Some(sender) => {
+                    // Senders post as an account of their own, created when
+                    // they first mail
+                    let address = sender.clone();
+                    match db::query(&state.db_tx, move |conn| {
+                        oauth::identity_user(conn, EMAIL_PROVIDER, &address)
+                    })
+                    .await
+                    {
+                        Ok(user_id) => {
+                            envelope.sender = Some(sender);
+                            envelope.user_id = user_id;
+                            reply!("250 OK");
+                        }
+                        Err(e) => {
+                            eprintln!("Failed to find account of {}: {}", sender, e);
+                            reply!("451 Try again later");
+                        }
+                    }
+                }
+                None => reply!("501 Expected MAIL FROM:<address>"),
+            },
+            "RCPT" => {
+                if envelope.sender.is_none() {
+                    reply!("503 Need MAIL first");
+                    continue;
+                }
+                let recipient = match path(arg, "TO:") {
+                    Some(recipient) => recipient,
+                    None => {
+                        reply!("501 Expected RCPT TO:<address>");
+                        continue;
+                    }
+                };
+                if envelope.rooms.len() >= MAX_RECIPIENTS {
+                    reply!("452 Too many recipients");
+                    continue;
+                }
+
+                let room_name = match recipient.rsplit_once('@') {
+                    Some((room, recipient_domain))
+                        if recipient_domain.eq_ignore_ascii_case(&domain) =>
+                    {
+                        String::from(room)
+                    }
+                    _ => {
+                        reply!("550 No such room");
+                        continue;
+                    }
+                };
+                // Rooms the sender may not post to are not found, as if they
+                // did not exist
+                let (user_id, lookup) = (envelope.user_id, room_name.clone());
+                match db::read(&state.db_tx, move |conn| may_post(conn, user_id, &lookup)).await {
+                    Ok(true) => {
+                        if !envelope.rooms.contains(&room_name) {
+                            envelope.rooms.push(room_name);
+                        }
+                        reply!("250 OK");
+                    }
+                    Ok(false) => reply!("550 No such room"),
+                    Err(e) => {
+                        eprintln!("Failed to look up room {}: {}", room_name, e);
+                        reply!("451 Try again later");
+                    }
+                }
+            }
+            "DATA" => {
+                if envelope.rooms.is_empty() {
+                    reply!("503 Need RCPT first");
+                    continue;
+                }
+                reply!("354 End data with <CR><LF>.<CR><LF>");
+
+                let raw = match read_data(&mut reader).await {
+                    Some(raw) => raw,
+                    None => return,
+                };
+                let envelope = std::mem::take(&mut envelope);
+                match raw {
+                    Ok(raw) => match post(&raw, envelope, &state).await {
+                        Ok(()) => reply!("250 OK"),
+                        Err(e) => reply!("554 {}", e.to_string().replace(['\r', '\n'], " ")),
+                    },
+                    Err(()) => reply!("552 Mail exceeds {} bytes", MAX_MAIL_SIZE),
+                }
+            }
+            "RSET" => {
+                envelope = Envelope::default();
+                reply!("250 OK");
+            }
+            "NOOP" => reply!("250 OK"),
+            "VRFY" => reply!("252 Cannot verify"),
+            "QUIT" => {
+                reply!("221 Bye");
+                return;
+            }
+            _ => reply!("502 Command not implemented"),
+        }
+    }
+}
+
+// Reads a mail up to its terminating `.` line, undoing dot-stuffing. Err if
+// it is larger than allowed, None if the connection was lost.
+async fn read_data<R>(reader: &mut R) -> Option<Result<String, ()>>
+where
+    R: AsyncBufReadExt + Unpin,
+{
+    let mut raw = Vec::new();
+    let mut line = Vec::new();
+    let mut too_large = false;
+    loop {
+        line.clear();
+        match reader
+            .take(MAX_MAIL_SIZE as u64 + 2)
+            .read_until(b'\n', &mut line)
+            .await
+        {
+            Ok(0) | Err(_) => return None,
+            Ok(_) => {}
+        }
+        if line == b".\r\n" || line == b".\n" {
+            break;
+        }
+
+        let unstuffed = line.strip_prefix(b".").unwrap_or(&line);
+        if raw.len() + unstuffed.len() > MAX_MAIL_SIZE {
+            too_large = true;
+            raw.clear();
+        }
+        if !too_large {
+            raw.extend_from_slice(unstuffed);
+        }
+    }
+
+    Some(if too_large {
+        Err(())
+    } else {
+        Ok(String::from_utf8_lossy(&raw).into_owned())
+    })
+}
+
+// Posts the mail `raw` to the rooms it is for, as the account of its sender.
+// The mail must be from the sender of the envelope, who is shown with the
+// name it gives unless another user holds it.
+async fn post(raw: &str, envelope: Envelope, state: &ServerState) -> Result<(), anyhow::Error> {
+    let mail = Mail::parse(raw);
+    if mail.auto_submitted {
+        return Ok(());
+    }
+
+    let text = mail.message().ok_or_else(|| anyhow!("Mail has no text"))?;
+    let envelope_sender = envelope.sender.unwrap_or_default();
+    let sender = match mail.from {
+        Some(from) if from.address == envelope_sender => from,
+        Some(_) => return Err(anyhow!("From does not match the sender")),
+        None => Address {
+            name: None,
+            address: envelope_sender,
+        },
+    };
+
+    let user_id = envelope.user_id;
+    let candidates = vec![sender.nick(), sender.address_nick()];
+    let nick = db::query(&state.db_tx, move |conn| {
+        for nick in candidates {
+            if db::reserve_nickname(conn, user_id, &nick)? {
+                return Ok(Some(nick));
+            }
+        }
+
+        Ok(None)
+    })
+    .await?;
+
+    for room_name in &envelope.rooms {
+        let user = state.detached_user(room_name, user_id).await?;
+        if let Some(nick) = &nick {
+            user.nicks.write().await.insert(user_id, nick.clone());
+        }
+        let sent = user
+            .send_message(&text, None, None, None, None, &state.rooms)
+            .await;
+        user::release_room(room_name, &state.rooms).await;
+        sent?.accepted()?;
+    }
+
+    Ok(())
+}
+
+// The body of a digest of `messages`, a line per message with the nickname of
+// its author. None if there is nothing to tell.
+fn digest_body(messages: &[DBMessage]) -> Option<String> {
+    let lines: Vec<String> = messages
+        .iter()
+        .filter(|message| message.deleted_at.is_none())
+        .map(|message| {
+            let nick = match &message.nickname {
+                Some(nick) => nick.clone(),
+                None => format!("user{}", message.user_id),
+            };
+            // Further lines of a message are indented under its first
+            format!("{}: {}", nick, message.message.replace('\n', "\n    "))
+        })
+        .collect();
+
+    Some(lines.join("\n")).filter(|body| !body.is_empty())
+}
+
+// Mails what was sent to the room of each of `digests` to its address every
+// `interval`, until shutdown. Only what is sent from now on is mailed.
+// Replies to digests are posted to the room if the gateway accepts mail for
+// `domain`.
+pub async fn send_digests(
+    digests: Vec<MailDigest>,
+    interval: Duration,
+    domain: Option<String>,
+    state: ServerState,
+    mut shutdown: Shutdown,
+) {
+    let (url, from) = match (&state.config.smtp_url, &state.config.smtp_from) {
+        (Some(url), Some(from)) => (url, from),
+        _ => return,
+    };
+    let transport = match AsyncSmtpTransport::<Tokio1Executor>::from_url(url) {
+        Ok(transport) => transport.build(),
+        Err(e) => {
+            eprintln!("Failed to mail digests: {}", e);
+            return;
+        }
+    };
+    let from: Mailbox = match from.parse() {
+        Ok(from) => from,
+        Err(e) => {
+            eprintln!("Failed to mail digests: {}", e);
+            return;
+        }
+    };
+
+    // Messages are taken in sequence, as IDs are only unique, not ordered,
+    // once rooms are shared between instances
+    let rooms: Vec<String> = digests.iter().map(|digest| digest.room.clone()).collect();
+    let mut last_seqs = match db::read(&state.db_tx, move |conn| {
+        rooms
+            .iter()
+            .map(|room_name| db::last_room_seq(conn, room_name))
+            .collect::<Result<Vec<_>, _>>()
+    })
+    .await
+    {
+        Ok(last_seqs) => last_seqs,
+        Err(e) => {
+            eprintln!("Failed to mail digests: {}", e);
+            return;
+        }
+    };
+
+    let mut ticks = tokio::time::interval(interval);
+    // The first tick completes immediately
+    ticks.tick().await;
+    loop {
+        tokio::select! {
+            _ = ticks.tick() => {}
+            _ = shutdown.async_listen() => break,
+        }
+
+        for (digest, last_seq) in digests.iter().zip(last_seqs.iter_mut()) {
+            let (room_name, after_seq) = (digest.room.clone(), *last_seq);
+            let messages = match db::read(&state.db_tx, move |conn| {
+                db::messages_after_seq(conn, &room_name, 0, after_seq)
+            })
+            .await
+            {
+                Ok(messages) => messages,
+                Err(e) => {
+                    eprintln!("Failed to read messages of {}: {}", digest.room, e);
+                    continue;
+                }
+            };
+            if let Some(seq) = messages.iter().filter_map(|message| message.seq).max() {
+                *last_seq = seq;
+            }
+
+            let body = match digest_body(&messages) {
+                Some(body) => body,
+                None => continue,
+            };
+            if let Err(e) = mail_digest(&transport, &from, digest, domain.as_deref(), body).await {
+                eprintln!("Failed to mail digest of {}: {}", digest.room, e);
+            }
+        }
+    }
+}
+
+async fn mail_digest(
+    transport: &AsyncSmtpTransport<Tokio1Executor>,
+    from: &Mailbox,
+    digest: &MailDigest,
+    domain: Option<&str>,
+    body: String,
+) -> Result<(), anyhow::Error> {
+    let mut builder = Message::builder()
+        .from(from.clone())
+        .to(digest.address.parse()?)
+        .subject(format!("New messages in {}", digest.room));
+    if let Some(domain) = domain {
+        builder = builder.reply_to(format!("{}@{}", digest.room, domain).parse()?);
+    }
+    let message = builder.header(ContentType::TEXT_PLAIN).body(body)?;
+    transport.send(message).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let digest: MailDigest = "lobby=team@example.org".parse().unwrap();
+        assert_eq!(
+            (digest.room.as_str(), digest.address.as_str()),
+            ("lobby", "team@example.org")
+        );
+        assert!("lobby".parse::<MailDigest>().is_err());
+        assert!("lobby=team".parse::<MailDigest>().is_err());
+
+        assert_eq!(
+            path("FROM:<Alice@Example.org> SIZE=100", "FROM:"),
+            Some(String::from("alice@example.org"))
+        );
+        assert_eq!(path("to: <>", "TO:"), Some(String::new()));
+        assert_eq!(path("TO:alice", "TO:"), None);
+    }
+
+    #[test]
+    fn test_sender_allowed() {
+        let allowed = vec![String::from("alice@example.org"), String::from("@Corp.org")];
+        assert!(sender_allowed(&allowed, "alice@example.org"));
+        assert!(sender_allowed(&allowed, "bob@corp.org"));
+        assert!(!sender_allowed(&allowed, "bob@example.org"));
+        assert!(!sender_allowed(&allowed, "bob@sub.corp.org"));
+        assert!(!sender_allowed(&allowed, ""));
+        assert!(!sender_allowed(&[], "alice@example.org"));
+    }
+
+    #[test]
+    fn test_mail() {
+        let mail = Mail::parse(
+            "From: =?UTF-8?Q?Ren=C3=A9e?= <Renee@Example.org>\r\n\
+            Subject: =?UTF-8?B?Q2Fmw6k=?= =?UTF-8?Q?_tonight?=\r\n\
+            Content-Type: text/plain; charset=utf-8\r\n\
+            Content-Transfer-Encoding: quoted-printable\r\n\
+            \r\n\
+            Who is in? It's a long line that gets wra=\r\n\
+            pped.\r\n\
+            \r\n\
+            On Monday, Bob wrote:\r\n\
+            > Anyone?\r\n",
+        );
+        assert_eq!(
+            mail.from,
+            Some(Address {
+                name: Some(String::from("Renée")),
+                address: String::from("renee@example.org"),
+            })
+        );
+        assert_eq!(mail.from.as_ref().unwrap().nick(), "Renée");
+        assert_eq!(
+            mail.message().unwrap(),
+            "Café tonight\n\nWho is in? It's a long line that gets wrapped."
+        );
+        assert!(!mail.auto_submitted);
+
+        let mail = Mail::parse(
+            "From: bob@example.org\n\
+            Content-Type: multipart/alternative; boundary=\"b1\"\n\
+            \n\
+            Preamble\n\
+            --b1\n\
+            Content-Type: text/html\n\
+            \n\
+            <p>Hello</p>\n\
+            --b1\n\
+            Content-Type: text/plain\n\
+            Content-Transfer-Encoding: base64\n\
+            \n\
+            SGVsbG8KLS0gCkJvYg==\n\
+            --b1--\n",
+        );
+        assert_eq!(mail.from.as_ref().unwrap().nick(), "bob@example.org");
+        assert_eq!(mail.message().unwrap(), "Hello");
+
+        let mail = Mail::parse("From: x@example.org\nAuto-Submitted: auto-replied\n\nAway");
+        assert!(mail.auto_submitted);
+        assert_eq!(Mail::parse("From: x@example.org\n\n").message(), None);
+    }
+
+    #[test]
+    fn test_digest_body() {
+        let message = |user_id, nickname: Option<&str>, text: &str| DBMessage {
+            message_id: None,
+            seq: None,
+            user_id,
+            nickname: nickname.map(String::from),
+            room_name: String::from("lobby"),
+            message: String::from(text),
+            edited_at: None,
+            deleted_at: None,
+            shadowed: false,
+            flagged: false,
+            ttl: None,
+            forwarded: None,
+        };
+        let mut deleted = message(3, None, "oops");
+        deleted.deleted_at = Some(String::from("2024-01-01 00:00:00"));
+
+        assert_eq!(
+            digest_body(&[
+                message(1, Some("alice"), "hi\nall"),
+                deleted,
+                message(2, None, "hello"),
+            ])
+            .unwrap(),
+            "alice: hi\n    all\nuser2: hello"
+        );
+        assert_eq!(digest_body(&[]), None);
+    }
+}
@@ -1,16 +1,50 @@
-use bi_chat::server;
-use std::path::PathBuf;
+use bi_chat::{config::Config, db, server};
 use structopt::StructOpt;
 
-#[derive(StructOpt)]
-#[structopt(name = "bi_chat", about = "A simple chat server backend.")]
-struct Opt {
-    #[structopt(default_value = "./main.db", parse(from_os_str))]
-    db_path: PathBuf,
-}
-
 #[tokio::main]
 async fn main() {
-    let opt = Opt::from_args();
-    server::run(3030, opt.db_path).await;
+    let config = Config::from_args();
+
+    if config.reingest_dead_letters {
+        let dead_letters = config.dead_letters();
+        match db::reingest_dead_letters(
+            &config.db_path,
+            config.pragmas(),
+            config.postgres_options().as_ref(),
+            &dead_letters,
+        ) {
+            Ok(reingested) => eprintln!(
+                "Re-ingested {} messages from {}",
+                reingested,
+                dead_letters.path().display()
+            ),
+            Err(e) => {
+                eprintln!("Unable to re-ingest dead-lettered messages: {}", e);
+                std::process::exit(1);
+            }
+        }
+
+        return;
+    }
+
+    if let (Some(export_path), Some(room)) = (&config.import_history, &config.import_room) {
+        match db::import_history(&config.db_path, config.pragmas(), room, export_path) {
+            Ok(imported) => eprintln!(
+                "Imported {} messages from {} into {} ({} given new IDs, {} anonymized)",
+                imported.messages,
+                export_path.display(),
+                room,
+                imported.renumbered,
+                imported.anonymized
+            ),
+            Err(e) => {
+                eprintln!("Unable to import history: {}", e);
+                std::process::exit(1);
+            }
+        }
+
+        return;
+    }
+
+    server::run_with_config(config).await;
 }
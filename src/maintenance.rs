@@ -0,0 +1,242 @@
+use std::{
+    fmt, fs,
+    path::{Path, PathBuf},
+};
+
+use rusqlite::Connection;
+use serde::Serialize;
+
+// Size of the DB and of its parts, in bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct DbStats {
+    pub page_size: u64,
+    // Including free pages
+    pub size: u64,
+    // Taken by pages no longer in use, which incremental vacuum returns to the
+    // filesystem
+    pub free: u64,
+    // Taken by the WAL, none once checkpointed
+    pub wal: u64,
+}
+
+impl fmt::Display for DbStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} bytes, {} free, WAL {} bytes",
+            self.size, self.free, self.wal
+        )
+    }
+}
+
+// What a maintenance run did, and the DB it left behind.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct Maintenance {
+    // Bytes of WAL copied back into the DB, then truncated
+    pub checkpointed: u64,
+    // Set if readers kept the WAL from being checkpointed in full
+    pub checkpoint_busy: bool,
+    // Free pages returned to the filesystem
+    pub vacuumed: u64,
+    pub stats: DbStats,
+}
+
+impl fmt::Display for Maintenance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} bytes checkpointed{}, {} pages vacuumed; {}",
+            self.checkpointed,
+            if self.checkpoint_busy { " (busy)" } else { "" },
+            self.vacuumed,
+            self.stats
+        )
+    }
+}
+
+// Snapshot of the DB written by a backup.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Backup {
+    pub path: PathBuf,
+    // In bytes
+    pub size: u64,
+}
+
+// Returns up to `vacuum_pages` free pages to the filesystem, all of them if 0,
+// then checkpoints the WAL, truncating it. Must be run outside of any
+// transaction. Free pages are only returned for DBs created with incremental
+// auto-vacuum, which those created since are.
+pub fn run(conn: &Connection, vacuum_pages: u32) -> Result<Maintenance, rusqlite::Error> {
+    // A page is vacuumed on every step, until done
+    let free_before = free_pages(conn)?;
+    let mut stmt = conn.prepare(&format!("PRAGMA incremental_vacuum({})", vacuum_pages))?;
+    let mut rows = stmt.query([])?;
+    while rows.next()?.is_some() {}
+    drop(rows);
+    let vacuumed = free_before - free_pages(conn)?;
+
+    // Pages checkpointed are only told of when the WAL is not truncated
+    let wal_before = wal_size(conn);
+    let busy: bool = conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |row| row.get(0))?;
+    let checkpointed = wal_before.saturating_sub(wal_size(conn));
+
+    Ok(Maintenance {
+        checkpointed,
+        checkpoint_busy: busy,
+        vacuumed,
+        stats: stats(conn)?,
+    })
+}
+
+// Writes a snapshot of the DB `conn` is connected to into the new file at
+// `path`, as of when it is read. The snapshot is consistent, and is written
+// without holding up writes to the DB in WAL mode, even from a read-only
+// connection. On a connection within a transaction, such as that of the DB
+// thread, what was written so far is committed first, and a new transaction
+// begun after, as when maintaining the DB.
+pub fn backup(conn: &Connection, path: &Path) -> Result<Backup, rusqlite::Error> {
+    let in_transaction = !conn.is_autocommit();
+    if in_transaction {
+        conn.execute_batch("COMMIT")?;
+    }
+    let written = conn.execute("VACUUM INTO ?1", [path.to_string_lossy()]);
+    if in_transaction {
+        conn.execute_batch("BEGIN")?;
+    }
+    written?;
+    let size = fs::metadata(path).map_or(0, |metadata| metadata.len());
+
+    Ok(Backup {
+        path: path.to_path_buf(),
+        size,
+    })
+}
+
+// Size of the DB `conn` is connected to.
+pub fn stats(conn: &Connection) -> Result<DbStats, rusqlite::Error> {
+    let page_size: u64 = conn.pragma_query_value(None, "page_size", |row| row.get(0))?;
+    let page_count: u64 = conn.pragma_query_value(None, "page_count", |row| row.get(0))?;
+
+    Ok(DbStats {
+        page_size,
+        size: page_size * page_count,
+        free: page_size * free_pages(conn)?,
+        wal: wal_size(conn),
+    })
+}
+
+// Size of the WAL of the DB `conn` is connected to, in bytes. In-memory DBs
+// have neither a file nor a WAL.
+fn wal_size(conn: &Connection) -> u64 {
+    match conn.path() {
+        Some(path) if !path.as_os_str().is_empty() => {
+            let mut wal_path = path.as_os_str().to_os_string();
+            wal_path.push("-wal");
+            fs::metadata(Path::new(&wal_path)).map_or(0, |metadata| metadata.len())
+        }
+        _ => 0,
+    }
+}
+
+fn free_pages(conn: &Connection) -> Result<u64, rusqlite::Error> {
+    conn.pragma_query_value(None, "freelist_count", |row| row.get(0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{self, Pragmas};
+
+    #[test]
+    fn test_run() {
+        let db_path = Path::new("./test_maintenance.db");
+        let conn = db::open(db_path, &Pragmas::default()).unwrap();
+        db::init_schema(&conn).unwrap();
+
+        // Deleted messages leave free pages behind
+        conn.execute_batch(
+            "WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 2000)
+                INSERT INTO chat_messages (user_id, room_name, message)
+                    SELECT 1, 'room1', printf('%.500c', 'x') FROM n;
+            DELETE FROM chat_messages;",
+        )
+        .unwrap();
+        let before = stats(&conn).unwrap();
+        assert!(before.wal > 0);
+
+        // Vacuuming may be bounded
+        let maintenance = run(&conn, 10).unwrap();
+        assert!(maintenance.checkpointed >= before.wal);
+        assert!(!maintenance.checkpoint_busy);
+        assert_eq!(maintenance.vacuumed, 10);
+        assert_eq!(maintenance.stats.wal, 0);
+
+        let maintenance = run(&conn, 0).unwrap();
+        assert!(maintenance.vacuumed > 0);
+        assert_eq!(maintenance.stats.free, 0);
+        assert!(maintenance.stats.size < before.size);
+
+        conn.close().unwrap();
+        std::fs::remove_file(db_path).unwrap();
+    }
+
+    #[test]
+    fn test_backup() {
+        let db_path = Path::new("./test_maintenance_backup.db");
+        let backup_path = Path::new("./test_maintenance_backup.db.backup");
+        let conn = db::open(db_path, &Pragmas::default()).unwrap();
+        db::init_schema(&conn).unwrap();
+        conn.execute(
+            "INSERT INTO chat_messages (user_id, room_name, message) VALUES (1, 'room1', 'Hello')",
+            [],
+        )
+        .unwrap();
+
+        let written = backup(&conn, backup_path).unwrap();
+        assert_eq!(written.path, backup_path);
+        assert!(written.size > 0);
+
+        // Backups are complete DBs of their own
+        let copy = Connection::open(backup_path).unwrap();
+        let message: String = copy
+            .query_row("SELECT message FROM chat_messages", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(message, "Hello");
+        copy.close().unwrap();
+
+        // Existing files are never overwritten
+        assert!(backup(&conn, backup_path).is_err());
+
+        // Within a transaction, what was written is committed first
+        std::fs::remove_file(backup_path).unwrap();
+        conn.execute_batch("BEGIN").unwrap();
+        conn.execute(
+            "INSERT INTO chat_messages (user_id, room_name, message) VALUES (1, 'room1', 'Again')",
+            [],
+        )
+        .unwrap();
+        backup(&conn, backup_path).unwrap();
+        assert!(!conn.is_autocommit());
+        conn.execute_batch("COMMIT").unwrap();
+        let copy = Connection::open(backup_path).unwrap();
+        let count: usize = copy
+            .query_row("SELECT COUNT(*) FROM chat_messages", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 2);
+        copy.close().unwrap();
+
+        conn.close().unwrap();
+        std::fs::remove_file(db_path).unwrap();
+        std::fs::remove_file(backup_path).unwrap();
+    }
+
+    #[test]
+    fn test_run_in_memory() {
+        let conn = Connection::open_in_memory().unwrap();
+        db::init_schema(&conn).unwrap();
+
+        let maintenance = run(&conn, 0).unwrap();
+        assert_eq!(maintenance.checkpointed, 0);
+        assert_eq!(maintenance.stats.wal, 0);
+    }
+}
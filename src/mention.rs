@@ -0,0 +1,113 @@
+use std::{collections::HashSet, sync::OnceLock};
+
+use regex::Regex;
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::authz;
+
+// Most users notified of a single message, so that messages can not ping
+// everyone in the room at once.
+pub const MAX_MENTIONS: usize = 10;
+
+// Matches `@username`, unless part of a word, e.g. of an email address.
+fn mention_regex() -> &'static Regex {
+    static MENTION: OnceLock<Regex> = OnceLock::new();
+    MENTION.get_or_init(|| {
+        Regex::new(r"(?:^|[^\w@])@([A-Za-z0-9_-]+)").expect("Mention regex is valid")
+    })
+}
+
+// Usernames mentioned in `text`, in order, each once regardless of case, and
+// at most `MAX_MENTIONS` of them.
+pub fn parse_mentions(text: &str) -> Vec<String> {
+    let mut seen = HashSet::new();
+
+    mention_regex()
+        .captures_iter(text)
+        .map(|caps| String::from(&caps[1]))
+        .filter(|username| seen.insert(username.to_lowercase()))
+        .take(MAX_MENTIONS)
+        .collect()
+}
+
+// Records that message `message_id`, sent to `room_name` by `author_id`,
+// mentions `usernames`. Only registered users who may join the room, other
+// than the author, are mentioned. Returns their user IDs.
+pub fn record_mentions(
+    conn: &Connection,
+    message_id: i64,
+    room_name: &str,
+    author_id: usize,
+    usernames: &[String],
+) -> Result<Vec<usize>, rusqlite::Error> {
+    let mut mentioned = Vec::new();
+    for username in usernames {
+        let user_id: Option<usize> = conn
+            .query_row(
+                "SELECT user_id FROM users WHERE username = ?1 COLLATE NOCASE",
+                params![username],
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        let user_id = match user_id {
+            Some(user_id) if user_id != author_id => user_id,
+            _ => continue,
+        };
+        if !authz::may_join(conn, user_id, room_name)? {
+            continue;
+        }
+
+        conn.execute(
+            "INSERT OR IGNORE INTO mentions (message_id, user_id, room_name)
+                VALUES (?1, ?2, ?3)",
+            params![message_id, user_id, room_name],
+        )?;
+        mentioned.push(user_id);
+    }
+
+    Ok(mentioned)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{auth, db};
+
+    #[test]
+    fn test_parse_mentions() {
+        assert_eq!(
+            parse_mentions("@alice hi, and @bob-2: @Alice"),
+            vec!["alice", "bob-2"]
+        );
+        assert!(parse_mentions("mail alice@example.com, or @ someone").is_empty());
+
+        let many = (0..20).map(|i| format!("@user{}", i)).collect::<Vec<_>>();
+        assert_eq!(parse_mentions(&many.join(" ")).len(), MAX_MENTIONS);
+    }
+
+    #[test]
+    fn test_record_mentions() {
+        let conn = Connection::open_in_memory().unwrap();
+        db::init_schema(&conn).unwrap();
+
+        let alice = auth::create_user(&conn, "alice", "hash").unwrap().unwrap();
+        let bob = auth::create_user(&conn, "bob", "hash").unwrap().unwrap();
+
+        // Unknown users, and the author, are left out
+        let usernames = parse_mentions("@BOB @alice @nobody");
+        assert_eq!(
+            record_mentions(&conn, 1, "room1", alice, &usernames).unwrap(),
+            vec![bob]
+        );
+
+        let recorded: usize = conn
+            .query_row(
+                "SELECT COUNT(*) FROM mentions WHERE user_id = ?1",
+                params![bob],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(recorded, 1);
+    }
+}
@@ -0,0 +1,714 @@
+use rusqlite::{ffi, params, Connection};
+
+// A change to the schema, applied once to every DB, in order of version.
+struct Migration {
+    version: i64,
+    // SQL script making the change
+    script: &'static str,
+}
+
+// Every migration, oldest first. Migrations are never changed once released:
+// changing the schema takes a new one, added to `migrations/`.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        script: include_str!("../migrations/0001_initial.sql"),
+    },
+    Migration {
+        version: 2,
+        script: include_str!("../migrations/0002_message_search.sql"),
+    },
+    Migration {
+        version: 3,
+        script: include_str!("../migrations/0003_history_indexes.sql"),
+    },
+    Migration {
+        version: 4,
+        script: include_str!("../migrations/0004_room_message_retention.sql"),
+    },
+    Migration {
+        version: 5,
+        script: include_str!("../migrations/0005_message_compression.sql"),
+    },
+    Migration {
+        version: 6,
+        script: include_str!("../migrations/0006_message_revisions.sql"),
+    },
+    Migration {
+        version: 7,
+        script: include_str!("../migrations/0007_room_hooks.sql"),
+    },
+    Migration {
+        version: 8,
+        script: include_str!("../migrations/0008_token_revocation.sql"),
+    },
+    Migration {
+        version: 9,
+        script: include_str!("../migrations/0009_guests.sql"),
+    },
+];
+
+// Version of the schema this server expects.
+pub fn latest_version() -> i64 {
+    MIGRATIONS.last().map_or(0, |migration| migration.version)
+}
+
+// Applies the migrations `conn` has not been through yet, each within its own
+// transaction, recording them in `schema_version`. Refuses DBs migrated by a
+// newer server.
+pub fn migrate(conn: &Connection) -> Result<(), rusqlite::Error> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS schema_version (
+                version INTEGER PRIMARY KEY NOT NULL,
+                applied_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL
+            )",
+        [],
+    )?;
+
+    let mut version = schema_version(conn)?;
+    if version > latest_version() {
+        return Err(rusqlite::Error::SqliteFailure(
+            ffi::Error::new(ffi::SQLITE_ERROR),
+            Some(format!(
+                "DB schema version {} is newer than the latest known, {}",
+                version,
+                latest_version()
+            )),
+        ));
+    }
+
+    // DBs created before migrations already have tables, possibly outdated
+    if version == 0 && table_exists(conn, "chat_messages")? {
+        let tx = conn.unchecked_transaction()?;
+        upgrade_legacy(&tx)?;
+        record_version(&tx, 1)?;
+        tx.commit()?;
+        version = 1;
+    }
+
+    for migration in MIGRATIONS.iter().filter(|m| m.version > version) {
+        let tx = conn.unchecked_transaction()?;
+        tx.execute_batch(migration.script)?;
+        record_version(&tx, migration.version)?;
+        tx.commit()?;
+    }
+
+    Ok(())
+}
+
+// Version of the last migration applied to `conn`, 0 if none were.
+pub fn schema_version(conn: &Connection) -> Result<i64, rusqlite::Error> {
+    conn.query_row(
+        "SELECT COALESCE(MAX(version), 0) FROM schema_version",
+        [],
+        |row| row.get(0),
+    )
+}
+
+fn record_version(conn: &Connection, version: i64) -> Result<(), rusqlite::Error> {
+    conn.execute(
+        "INSERT INTO schema_version (version) VALUES (?1)",
+        params![version],
+    )?;
+
+    Ok(())
+}
+
+// Brings DBs created before migrations, from whichever version of the server,
+// to the schema of the first migration. Not to be changed: later changes to
+// the schema are made by migrations.
+fn upgrade_legacy(conn: &Connection) -> Result<(), rusqlite::Error> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS chat_messages (
+                message_id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+                user_id INTEGER,
+                room_name TEXT NOT NULL,
+                message TEXT NOT NULL,
+                created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+                seq INTEGER,
+                edited_at TIMESTAMP,
+                deleted_at TIMESTAMP,
+                deleted_by INTEGER,
+                nickname TEXT,
+                shadowed BOOLEAN NOT NULL DEFAULT 0,
+                flagged BOOLEAN NOT NULL DEFAULT 0,
+                expires_at TIMESTAMP,
+                forwarded_id INTEGER,
+                forwarded_room TEXT,
+                forwarded_user_id INTEGER,
+                forwarded_nick TEXT
+            )",
+        [],
+    )?;
+
+    // DBs created before messages were sequenced: message IDs already order
+    // messages within each room, so they double as sequence numbers.
+    if add_column_if_missing(conn, "chat_messages", "seq", "INTEGER")? {
+        conn.execute("UPDATE chat_messages SET seq = message_id", [])?;
+    }
+    add_column_if_missing(conn, "chat_messages", "edited_at", "TIMESTAMP")?;
+    add_column_if_missing(conn, "chat_messages", "deleted_at", "TIMESTAMP")?;
+    add_column_if_missing(conn, "chat_messages", "deleted_by", "INTEGER")?;
+    add_column_if_missing(conn, "chat_messages", "nickname", "TEXT")?;
+    add_column_if_missing(
+        conn,
+        "chat_messages",
+        "shadowed",
+        "BOOLEAN NOT NULL DEFAULT 0",
+    )?;
+    add_column_if_missing(
+        conn,
+        "chat_messages",
+        "flagged",
+        "BOOLEAN NOT NULL DEFAULT 0",
+    )?;
+    add_column_if_missing(conn, "chat_messages", "expires_at", "TIMESTAMP")?;
+    add_column_if_missing(conn, "chat_messages", "forwarded_id", "INTEGER")?;
+    add_column_if_missing(conn, "chat_messages", "forwarded_room", "TEXT")?;
+    add_column_if_missing(conn, "chat_messages", "forwarded_user_id", "INTEGER")?;
+    add_column_if_missing(conn, "chat_messages", "forwarded_nick", "TEXT")?;
+
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS chat_messages_room_seq ON chat_messages (room_name, seq)",
+        [],
+    )?;
+
+    let had_users = table_exists(conn, "users")?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS users (
+                user_id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+                username TEXT UNIQUE,
+                password_hash TEXT,
+                created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+                totp_secret TEXT,
+                totp_pending_secret TEXT,
+                totp_last_step INTEGER,
+                email TEXT,
+                role TEXT NOT NULL DEFAULT 'member'
+            )",
+        [],
+    )?;
+    add_column_if_missing(conn, "users", "totp_secret", "TEXT")?;
+    add_column_if_missing(conn, "users", "totp_pending_secret", "TEXT")?;
+    add_column_if_missing(conn, "users", "totp_last_step", "INTEGER")?;
+    add_column_if_missing(conn, "users", "email", "TEXT")?;
+    add_column_if_missing(conn, "users", "role", "TEXT NOT NULL DEFAULT 'member'")?;
+
+    // DBs created before users were persisted: their messages were sent by
+    // users numbered from 1 on every boot. Recording those users keeps new
+    // users from being given the same IDs.
+    if !had_users {
+        conn.execute(
+            "INSERT OR IGNORE INTO users (user_id)
+                SELECT DISTINCT user_id FROM chat_messages WHERE user_id IS NOT NULL",
+            [],
+        )?;
+    }
+
+    // Nicknames reserved by users: a nickname can only be held by one user at
+    // a time, and never collides with the username of another account.
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS usernames (
+                name TEXT PRIMARY KEY COLLATE NOCASE NOT NULL,
+                user_id INTEGER UNIQUE NOT NULL,
+                reserved_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL
+            )",
+        [],
+    )?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS profiles (
+                user_id INTEGER PRIMARY KEY NOT NULL,
+                avatar_url TEXT,
+                bio TEXT,
+                updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL
+            )",
+        [],
+    )?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS sessions (
+                token_hash TEXT PRIMARY KEY NOT NULL,
+                user_id INTEGER NOT NULL,
+                created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+                expires_at TIMESTAMP NOT NULL,
+                session_id TEXT,
+                last_used_at TIMESTAMP,
+                user_agent TEXT
+            )",
+        [],
+    )?;
+    // Sessions started before they could be listed are given an ID
+    if add_column_if_missing(conn, "sessions", "session_id", "TEXT")? {
+        conn.execute(
+            "UPDATE sessions SET session_id = lower(hex(randomblob(8)))",
+            [],
+        )?;
+    }
+    add_column_if_missing(conn, "sessions", "last_used_at", "TIMESTAMP")?;
+    add_column_if_missing(conn, "sessions", "user_agent", "TEXT")?;
+    conn.execute(
+        "CREATE UNIQUE INDEX IF NOT EXISTS sessions_session_id ON sessions (session_id)",
+        [],
+    )?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS api_tokens (
+                token_id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+                user_id INTEGER NOT NULL,
+                name TEXT NOT NULL,
+                token_hash TEXT UNIQUE NOT NULL,
+                created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+                last_used_at TIMESTAMP,
+                scopes TEXT NOT NULL DEFAULT 'admin'
+            )",
+        [],
+    )?;
+    // Tokens issued before they were scoped could be used for anything
+    add_column_if_missing(
+        conn,
+        "api_tokens",
+        "scopes",
+        "TEXT NOT NULL DEFAULT 'admin'",
+    )?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS login_failures (
+                counter TEXT PRIMARY KEY NOT NULL,
+                failures INTEGER NOT NULL,
+                last_failure_at INTEGER NOT NULL,
+                locked_until INTEGER
+            )",
+        [],
+    )?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS password_resets (
+                token_hash TEXT PRIMARY KEY NOT NULL,
+                user_id INTEGER NOT NULL,
+                created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+                expires_at TIMESTAMP NOT NULL
+            )",
+        [],
+    )?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS oauth_identities (
+                provider TEXT NOT NULL,
+                external_id TEXT NOT NULL,
+                user_id INTEGER NOT NULL,
+                PRIMARY KEY (provider, external_id)
+            )",
+        [],
+    )?;
+
+    // Rooms are recorded once created or first joined. Those that had messages
+    // before are recorded without a creator or owner.
+    let had_rooms = table_exists(conn, "rooms")?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS rooms (
+                room_name TEXT PRIMARY KEY NOT NULL,
+                created_by INTEGER,
+                created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+                topic TEXT,
+                description TEXT,
+                visibility TEXT NOT NULL DEFAULT 'public',
+                capacity INTEGER,
+                retention_secs INTEGER,
+                password_hash TEXT,
+                read_only INTEGER NOT NULL DEFAULT 0
+            )",
+        [],
+    )?;
+    add_column_if_missing(conn, "rooms", "topic", "TEXT")?;
+    add_column_if_missing(conn, "rooms", "description", "TEXT")?;
+    add_column_if_missing(
+        conn,
+        "rooms",
+        "visibility",
+        "TEXT NOT NULL DEFAULT 'public'",
+    )?;
+    add_column_if_missing(conn, "rooms", "capacity", "INTEGER")?;
+    add_column_if_missing(conn, "rooms", "retention_secs", "INTEGER")?;
+    add_column_if_missing(conn, "rooms", "password_hash", "TEXT")?;
+    add_column_if_missing(conn, "rooms", "read_only", "INTEGER NOT NULL DEFAULT 0")?;
+    add_column_if_missing(
+        conn,
+        "rooms",
+        "approval_required",
+        "INTEGER NOT NULL DEFAULT 0",
+    )?;
+    add_column_if_missing(conn, "rooms", "announcement", "INTEGER NOT NULL DEFAULT 0")?;
+    if !had_rooms {
+        conn.execute(
+            "INSERT OR IGNORE INTO rooms (room_name, created_at)
+                SELECT room_name, MIN(created_at) FROM chat_messages GROUP BY room_name",
+            [],
+        )?;
+    }
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS room_owners (
+                room_name TEXT NOT NULL,
+                user_id INTEGER NOT NULL,
+                added_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+                PRIMARY KEY (room_name, user_id)
+            )",
+        [],
+    )?;
+
+    // Users invited into a room. Private rooms only let in their members
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS room_members (
+                room_name TEXT NOT NULL,
+                user_id INTEGER NOT NULL,
+                invited_by INTEGER,
+                added_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+                PRIMARY KEY (room_name, user_id)
+            )",
+        [],
+    )?;
+
+    // Invites making users members of a room, by the hash of their token
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS room_invites (
+                invite_id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+                room_name TEXT NOT NULL,
+                token_hash TEXT UNIQUE NOT NULL,
+                created_by INTEGER,
+                created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+                expires_at TIMESTAMP,
+                max_uses INTEGER,
+                uses INTEGER NOT NULL DEFAULT 0
+            )",
+        [],
+    )?;
+
+    // Roles given to users in a single room, on top of their server-wide role
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS room_roles (
+                room_name TEXT NOT NULL,
+                user_id INTEGER NOT NULL,
+                role TEXT NOT NULL,
+                PRIMARY KEY (room_name, user_id)
+            )",
+        [],
+    )?;
+
+    // Users allowed into, or kept out of, a room
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS room_acl (
+                room_name TEXT NOT NULL,
+                user_id INTEGER NOT NULL,
+                allowed BOOLEAN NOT NULL,
+                PRIMARY KEY (room_name, user_id)
+            )",
+        [],
+    )?;
+
+    // Bans and mutes of users in a room, by kind
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS room_sanctions (
+                room_name TEXT NOT NULL,
+                user_id INTEGER NOT NULL,
+                kind TEXT NOT NULL,
+                issued_by INTEGER,
+                reason TEXT,
+                created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+                expires_at TIMESTAMP,
+                PRIMARY KEY (room_name, user_id, kind)
+            )",
+        [],
+    )?;
+
+    // Reports of messages by users, queued for moderators to review
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS message_reports (
+                report_id INTEGER PRIMARY KEY,
+                message_id INTEGER NOT NULL,
+                room_name TEXT NOT NULL,
+                reported_by INTEGER NOT NULL,
+                reason TEXT,
+                created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+                status TEXT NOT NULL DEFAULT 'open',
+                resolved_by INTEGER,
+                resolved_at TIMESTAMP,
+                UNIQUE (message_id, reported_by)
+            )",
+        [],
+    )?;
+
+    // Ranges of addresses connections are refused from, in CIDR notation
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS ip_bans (
+                ban_id INTEGER PRIMARY KEY,
+                cidr TEXT NOT NULL UNIQUE,
+                reason TEXT,
+                created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL
+            )",
+        [],
+    )?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS room_pins (
+                room_name TEXT NOT NULL,
+                message_id INTEGER NOT NULL,
+                pinned_by INTEGER NOT NULL,
+                pinned_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+                PRIMARY KEY (room_name, message_id)
+            )",
+        [],
+    )?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS mentions (
+                message_id INTEGER NOT NULL,
+                user_id INTEGER NOT NULL,
+                room_name TEXT NOT NULL,
+                created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+                PRIMARY KEY (message_id, user_id)
+            )",
+        [],
+    )?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS alert_keywords (
+                user_id INTEGER NOT NULL,
+                keyword TEXT COLLATE NOCASE NOT NULL,
+                PRIMARY KEY (user_id, keyword)
+            )",
+        [],
+    )?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS alerts (
+                alert_id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+                user_id INTEGER NOT NULL,
+                message_id INTEGER NOT NULL,
+                room_name TEXT NOT NULL,
+                keyword TEXT NOT NULL,
+                created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL
+            )",
+        [],
+    )?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS queued_events (
+                event_id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+                user_id INTEGER NOT NULL,
+                room_name TEXT NOT NULL,
+                message_id INTEGER NOT NULL,
+                event TEXT NOT NULL,
+                created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL
+            )",
+        [],
+    )?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS scheduled_messages (
+                scheduled_id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+                room_name TEXT NOT NULL,
+                user_id INTEGER NOT NULL,
+                message TEXT NOT NULL,
+                deliver_at TIMESTAMP NOT NULL,
+                created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL
+            )",
+        [],
+    )?;
+
+    // Announcement rooms followed by rooms, which their messages are
+    // cross-posted to
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS announcement_follows (
+                room_name TEXT NOT NULL,
+                source_room TEXT NOT NULL,
+                followed_by INTEGER,
+                created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+                PRIMARY KEY (room_name, source_room)
+            )",
+        [],
+    )?;
+
+    // Options of polls, sent as messages asking their question
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS poll_options (
+                message_id INTEGER NOT NULL,
+                room_name TEXT NOT NULL,
+                option_index INTEGER NOT NULL,
+                text TEXT NOT NULL,
+                PRIMARY KEY (message_id, option_index)
+            )",
+        [],
+    )?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS poll_votes (
+                message_id INTEGER NOT NULL,
+                room_name TEXT NOT NULL,
+                user_id INTEGER NOT NULL,
+                option_index INTEGER NOT NULL,
+                voted_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+                PRIMARY KEY (message_id, user_id)
+            )",
+        [],
+    )?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS notification_levels (
+                user_id INTEGER NOT NULL,
+                room_name TEXT NOT NULL,
+                level TEXT NOT NULL,
+                PRIMARY KEY (user_id, room_name)
+            )",
+        [],
+    )?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS read_markers (
+                room_name TEXT NOT NULL,
+                user_id INTEGER NOT NULL,
+                message_id INTEGER NOT NULL,
+                seq INTEGER,
+                read_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+                PRIMARY KEY (room_name, user_id)
+            )",
+        [],
+    )?;
+
+    // DBs created before unread counts: markers are sequenced as the messages
+    // they point to
+    if add_column_if_missing(conn, "read_markers", "seq", "INTEGER")? {
+        conn.execute(
+            "UPDATE read_markers SET seq =
+                (SELECT m.seq FROM chat_messages m WHERE m.message_id = read_markers.message_id)",
+            [],
+        )?;
+    }
+
+    Ok(())
+}
+
+// Whether `table` has been created.
+fn table_exists(conn: &Connection, table: &str) -> Result<bool, rusqlite::Error> {
+    conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?1)",
+        params![table],
+        |row| row.get(0),
+    )
+}
+
+// Adds `column` to `table` if it does not exist yet, returning whether it was added.
+fn add_column_if_missing(
+    conn: &Connection,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> Result<bool, rusqlite::Error> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+    let exists = stmt
+        .query_map([], |row| row.get::<_, String>(1))?
+        .collect::<Result<Vec<_>, _>>()?
+        .iter()
+        .any(|name| name == column);
+
+    if !exists {
+        conn.execute(
+            &format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition),
+            [],
+        )?;
+    }
+
+    Ok(!exists)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Columns of every table, and the indexes, making up the schema of `conn`.
+    fn schema(conn: &Connection) -> Vec<String> {
+        let mut stmt = conn
+            .prepare(
+                "SELECT type, name, tbl_name FROM sqlite_master
+                    WHERE name NOT IN ('sqlite_sequence', 'schema_version')
+                    ORDER BY type, name",
+            )
+            .unwrap();
+        let objects = stmt
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                ))
+            })
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+
+        let mut schema = Vec::new();
+        for (kind, name, table) in objects {
+            schema.push(format!("{} {} on {}", kind, name, table));
+            if kind != "table" {
+                continue;
+            }
+
+            let mut stmt = conn
+                .prepare(&format!("PRAGMA table_info({})", name))
+                .unwrap();
+            let columns = stmt
+                .query_map([], |row| {
+                    Ok(format!(
+                        "{}.{} {} notnull={} default={:?} pk={}",
+                        name,
+                        row.get::<_, String>(1)?,
+                        row.get::<_, String>(2)?,
+                        row.get::<_, bool>(3)?,
+                        row.get::<_, Option<String>>(4)?,
+                        row.get::<_, i64>(5)?
+                    ))
+                })
+                .unwrap()
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap();
+            schema.extend(columns);
+        }
+
+        schema
+    }
+
+    #[test]
+    fn test_migrate() {
+        let conn = Connection::open_in_memory().unwrap();
+        migrate(&conn).unwrap();
+        assert_eq!(schema_version(&conn).unwrap(), latest_version());
+
+        // Running again is a no-op
+        migrate(&conn).unwrap();
+        assert_eq!(schema_version(&conn).unwrap(), latest_version());
+    }
+
+    #[test]
+    fn test_legacy_schema() {
+        // DBs upgraded from before migrations end up with the same schema as
+        // those created by them
+        let legacy = Connection::open_in_memory().unwrap();
+        upgrade_legacy(&legacy).unwrap();
+        let migrated = Connection::open_in_memory().unwrap();
+        migrated.execute_batch(MIGRATIONS[0].script).unwrap();
+        assert_eq!(schema(&legacy), schema(&migrated));
+
+        // And are then recorded as such
+        migrate(&legacy).unwrap();
+        assert_eq!(schema_version(&legacy).unwrap(), latest_version());
+    }
+
+    #[test]
+    fn test_newer_schema() {
+        let conn = Connection::open_in_memory().unwrap();
+        migrate(&conn).unwrap();
+        record_version(&conn, latest_version() + 1).unwrap();
+
+        assert!(migrate(&conn).is_err());
+    }
+}
@@ -0,0 +1,336 @@
+use std::{fmt, str::FromStr, time::Duration};
+
+use anyhow::anyhow;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::auth;
+
+pub const MAX_REASON_LENGTH: usize = 256;
+
+// Lasting measures moderators take against a user in a room.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SanctionKind {
+    // The user may not join the room
+    Ban,
+    // The user may join the room, but not post in it
+    Mute,
+    // The user may post in the room, but their messages are only shown to
+    // themselves. Unlike other sanctions, it is not announced to the room
+    ShadowBan,
+}
+
+impl FromStr for SanctionKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ban" => Ok(SanctionKind::Ban),
+            "mute" => Ok(SanctionKind::Mute),
+            "shadow_ban" => Ok(SanctionKind::ShadowBan),
+            _ => Err(anyhow!(
+                "Unknown sanction '{}': expected ban, mute or shadow_ban",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for SanctionKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self {
+            SanctionKind::Ban => "ban",
+            SanctionKind::Mute => "mute",
+            SanctionKind::ShadowBan => "shadow_ban",
+        };
+        f.write_str(kind)
+    }
+}
+
+// Request body of the routes banning or muting a user, also sent in `ban` and
+// `mute` frames.
+#[derive(Debug, Default, Deserialize, ToSchema)]
+pub struct NewSanction {
+    // Shown to the room along with the sanction
+    #[serde(default)]
+    pub reason: Option<String>,
+    // Number of seconds the sanction lasts for, if not until lifted
+    #[serde(default)]
+    pub duration_secs: Option<u64>,
+}
+
+impl NewSanction {
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        if self
+            .reason
+            .as_ref()
+            .is_some_and(|reason| reason.chars().count() > MAX_REASON_LENGTH)
+        {
+            return Err(anyhow!(
+                "Reason must be at most {} characters long",
+                MAX_REASON_LENGTH
+            ));
+        }
+
+        if self.duration_secs == Some(0) {
+            return Err(anyhow!("Sanctions must last at least 1 second"));
+        }
+
+        Ok(())
+    }
+}
+
+// A ban or mute of a user in a room.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Sanction {
+    pub room: String,
+    pub user_id: usize,
+    pub kind: SanctionKind,
+    // Unknown if the moderator who issued it has since been deleted
+    pub issued_by: Option<usize>,
+    pub reason: Option<String>,
+    pub created_at: String,
+    pub expires_at: Option<String>,
+}
+
+// Bans or mutes `user_id` in `room_name` on behalf of `issued_by`, replacing
+// any sanction of the same kind. Returns the sanction, unless the user does not
+// exist. Sanctions of guests also apply to every guest connecting from the
+// same address, as guests are given a new ID on every connection.
+pub fn impose(
+    conn: &Connection,
+    room_name: &str,
+    user_id: usize,
+    kind: SanctionKind,
+    issued_by: usize,
+    new_sanction: &NewSanction,
+) -> Result<Option<Sanction>, rusqlite::Error> {
+    if !auth::user_exists(conn, user_id)? {
+        return Ok(None);
+    }
+
+    let expires_at = new_sanction
+        .duration_secs
+        .map(|secs| auth::ttl_modifier(Duration::from_secs(secs)));
+    conn.execute(
+        "INSERT OR REPLACE INTO room_sanctions
+                (room_name, user_id, kind, issued_by, reason, expires_at, addr)
+            VALUES (?1, ?2, ?3, ?4, ?5, datetime('now', ?6),
+                (SELECT addr FROM guests WHERE user_id = ?2))",
+        params![
+            room_name,
+            user_id,
+            kind.to_string(),
+            issued_by,
+            new_sanction.reason,
+            expires_at
+        ],
+    )?;
+
+    conn.query_row(
+        &format!(
+            "SELECT {} FROM room_sanctions
+                WHERE room_name = ?1 AND user_id = ?2 AND kind = ?3",
+            SANCTION_COLUMNS
+        ),
+        params![room_name, user_id, kind.to_string()],
+        sanction_from_row,
+    )
+    .optional()
+}
+
+// Lifts the ban or mute of `user_id` in `room_name`, returning whether one was
+// in force.
+pub fn lift(
+    conn: &Connection,
+    room_name: &str,
+    user_id: usize,
+    kind: SanctionKind,
+) -> Result<bool, rusqlite::Error> {
+    let deleted = conn.execute(
+        &format!(
+            "DELETE FROM room_sanctions
+                WHERE room_name = ?1 AND user_id = ?2 AND kind = ?3 AND {}",
+            IN_FORCE
+        ),
+        params![room_name, user_id, kind.to_string()],
+    )?;
+
+    Ok(deleted > 0)
+}
+
+// Bans or mutes in force in `room_name`, oldest first.
+pub fn sanctions(
+    conn: &Connection,
+    room_name: &str,
+    kind: SanctionKind,
+) -> Result<Vec<Sanction>, rusqlite::Error> {
+    let mut stmt = conn.prepare_cached(&format!(
+        "SELECT {} FROM room_sanctions
+            WHERE room_name = ?1 AND kind = ?2 AND {}
+            ORDER BY created_at, rowid",
+        SANCTION_COLUMNS, IN_FORCE
+    ))?;
+    let sanctions = stmt
+        .query_map(params![room_name, kind.to_string()], sanction_from_row)?
+        .collect();
+
+    sanctions
+}
+
+// Whether `user_id` is banned or muted in `room_name`, themselves or, if a
+// guest, through their address.
+pub fn is_sanctioned(
+    conn: &Connection,
+    room_name: &str,
+    user_id: usize,
+    kind: SanctionKind,
+) -> Result<bool, rusqlite::Error> {
+    conn.query_row(
+        &format!(
+            "SELECT EXISTS (SELECT 1 FROM room_sanctions
+                WHERE room_name = ?1 AND kind = ?3 AND {}
+                    AND (user_id = ?2
+                        OR addr = (SELECT addr FROM guests WHERE user_id = ?2)))",
+            IN_FORCE
+        ),
+        params![room_name, user_id, kind.to_string()],
+        |row| row.get(0),
+    )
+}
+
+// Sanctions past their expiry are kept until replaced, but no longer apply.
+const IN_FORCE: &str = "(expires_at IS NULL OR expires_at > datetime('now'))";
+
+const SANCTION_COLUMNS: &str =
+    "room_name, user_id, kind, issued_by, reason, created_at, expires_at";
+
+fn sanction_from_row(row: &rusqlite::Row) -> Result<Sanction, rusqlite::Error> {
+    Ok(Sanction {
+        room: row.get(0)?,
+        user_id: row.get(1)?,
+        // Unknown kinds are taken as bans, which is safer
+        kind: row
+            .get::<_, String>(2)?
+            .parse()
+            .unwrap_or(SanctionKind::Ban),
+        issued_by: row.get(3)?,
+        reason: row.get(4)?,
+        created_at: row.get(5)?,
+        expires_at: row.get(6)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+
+    #[test]
+    fn test_sanctions() {
+        let conn = Connection::open_in_memory().unwrap();
+        db::init_schema(&conn).unwrap();
+
+        let alice = auth::create_user(&conn, "alice", "hash").unwrap().unwrap();
+        let bob = auth::create_user(&conn, "bob", "hash").unwrap().unwrap();
+
+        let ban = NewSanction {
+            reason: Some(String::from("Spam")),
+            duration_secs: None,
+        };
+        let sanction = impose(&conn, "room1", bob, SanctionKind::Ban, alice, &ban)
+            .unwrap()
+            .unwrap();
+        assert_eq!(sanction.issued_by, Some(alice));
+        assert_eq!(sanction.reason.as_deref(), Some("Spam"));
+        assert_eq!(
+            impose(&conn, "room1", 999, SanctionKind::Ban, alice, &ban).unwrap(),
+            None
+        );
+
+        // Sanctions only apply in their room, and are of a single kind
+        assert!(is_sanctioned(&conn, "room1", bob, SanctionKind::Ban).unwrap());
+        assert!(!is_sanctioned(&conn, "room2", bob, SanctionKind::Ban).unwrap());
+        assert!(!is_sanctioned(&conn, "room1", bob, SanctionKind::Mute).unwrap());
+        assert_eq!(
+            sanctions(&conn, "room1", SanctionKind::Ban).unwrap(),
+            vec![sanction]
+        );
+        assert!(sanctions(&conn, "room1", SanctionKind::Mute)
+            .unwrap()
+            .is_empty());
+
+        assert!(lift(&conn, "room1", bob, SanctionKind::Ban).unwrap());
+        assert!(!lift(&conn, "room1", bob, SanctionKind::Ban).unwrap());
+        assert!(!is_sanctioned(&conn, "room1", bob, SanctionKind::Ban).unwrap());
+
+        // Kinds round-trip through the DB
+        impose(&conn, "room1", bob, SanctionKind::ShadowBan, alice, &ban).unwrap();
+        assert_eq!(
+            sanctions(&conn, "room1", SanctionKind::ShadowBan).unwrap()[0].kind,
+            SanctionKind::ShadowBan
+        );
+    }
+
+    #[test]
+    fn test_expired_sanction() {
+        let conn = Connection::open_in_memory().unwrap();
+        db::init_schema(&conn).unwrap();
+
+        let alice = auth::create_user(&conn, "alice", "hash").unwrap().unwrap();
+        let bob = auth::create_user(&conn, "bob", "hash").unwrap().unwrap();
+
+        let mute = NewSanction {
+            reason: None,
+            duration_secs: Some(60),
+        };
+        impose(&conn, "room1", bob, SanctionKind::Mute, alice, &mute).unwrap();
+        assert!(is_sanctioned(&conn, "room1", bob, SanctionKind::Mute).unwrap());
+
+        conn.execute(
+            "UPDATE room_sanctions SET expires_at = datetime('now', '-1 seconds')",
+            [],
+        )
+        .unwrap();
+        assert!(!is_sanctioned(&conn, "room1", bob, SanctionKind::Mute).unwrap());
+        assert!(sanctions(&conn, "room1", SanctionKind::Mute)
+            .unwrap()
+            .is_empty());
+        assert!(!lift(&conn, "room1", bob, SanctionKind::Mute).unwrap());
+
+        assert!(NewSanction {
+            reason: Some("a".repeat(MAX_REASON_LENGTH + 1)),
+            duration_secs: None,
+        }
+        .validate()
+        .is_err());
+        assert!(mute.validate().is_ok());
+    }
+
+    #[test]
+    fn test_guest_sanctions() {
+        let conn = Connection::open_in_memory().unwrap();
+        db::init_schema(&conn).unwrap();
+
+        let alice = auth::create_user(&conn, "alice", "hash").unwrap().unwrap();
+        let addr = "10.0.0.1".parse().ok();
+        let guest = auth::create_guest(&conn, addr).unwrap();
+        let ban = NewSanction::default();
+        impose(&conn, "room1", guest, SanctionKind::Ban, alice, &ban).unwrap();
+
+        // Guests reconnecting from the same address are banned too, unlike
+        // others
+        let reconnected = auth::create_guest(&conn, addr).unwrap();
+        let other = auth::create_guest(&conn, "10.0.0.2".parse().ok()).unwrap();
+        let unknown = auth::create_guest(&conn, None).unwrap();
+        assert!(is_sanctioned(&conn, "room1", reconnected, SanctionKind::Ban).unwrap());
+        assert!(!is_sanctioned(&conn, "room2", reconnected, SanctionKind::Ban).unwrap());
+        assert!(!is_sanctioned(&conn, "room1", other, SanctionKind::Ban).unwrap());
+        assert!(!is_sanctioned(&conn, "room1", unknown, SanctionKind::Ban).unwrap());
+
+        assert!(lift(&conn, "room1", guest, SanctionKind::Ban).unwrap());
+        assert!(!is_sanctioned(&conn, "room1", reconnected, SanctionKind::Ban).unwrap());
+    }
+}
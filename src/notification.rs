@@ -0,0 +1,206 @@
+use std::{fmt, str::FromStr};
+
+use anyhow::anyhow;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+// Events notifying users of messages outside of the room they were sent to.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Notification {
+    // The message mentions the user
+    Mention,
+    // The message matches a keyword the user watches
+    Alert,
+    // The message was sent to a conversation the user takes part in
+    Direct,
+}
+
+// Which notifications a user gets of messages sent to a room. Chat messages
+// are delivered to their connections to the room all the same.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum NotificationLevel {
+    // Every notification (the default)
+    All,
+    // Only mentions, and messages of conversations
+    Mentions,
+    // None at all
+    Muted,
+}
+
+impl NotificationLevel {
+    pub fn allows(self, notification: Notification) -> bool {
+        match self {
+            NotificationLevel::All => true,
+            NotificationLevel::Mentions => notification != Notification::Alert,
+            NotificationLevel::Muted => false,
+        }
+    }
+}
+
+impl FromStr for NotificationLevel {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "all" => Ok(NotificationLevel::All),
+            "mentions" => Ok(NotificationLevel::Mentions),
+            "muted" => Ok(NotificationLevel::Muted),
+            _ => Err(anyhow!(
+                "Unknown notification level '{}': expected all, mentions or muted",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for NotificationLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let level = match self {
+            NotificationLevel::All => "all",
+            NotificationLevel::Mentions => "mentions",
+            NotificationLevel::Muted => "muted",
+        };
+        f.write_str(level)
+    }
+}
+
+// Request body of the route setting the notification level of a room.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct LevelUpdate {
+    pub level: NotificationLevel,
+}
+
+// The notification level a user set for a room.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct RoomLevel {
+    pub room: String,
+    pub level: NotificationLevel,
+}
+
+// Sets the notification level of `user_id` for `room_name`. Rooms are back to
+// the default once set to `all`.
+pub fn set_level(
+    conn: &Connection,
+    user_id: usize,
+    room_name: &str,
+    level: NotificationLevel,
+) -> Result<(), rusqlite::Error> {
+    if level == NotificationLevel::All {
+        conn.execute(
+            "DELETE FROM notification_levels WHERE user_id = ?1 AND room_name = ?2",
+            params![user_id, room_name],
+        )?;
+    } else {
+        conn.execute(
+            "INSERT INTO notification_levels (user_id, room_name, level) VALUES (?1, ?2, ?3)
+                ON CONFLICT (user_id, room_name) DO UPDATE SET level = excluded.level",
+            params![user_id, room_name, level.to_string()],
+        )?;
+    }
+
+    Ok(())
+}
+
+// The notification level of `user_id` for `room_name`.
+pub fn level(
+    conn: &Connection,
+    user_id: usize,
+    room_name: &str,
+) -> Result<NotificationLevel, rusqlite::Error> {
+    let level: Option<String> = conn
+        .query_row(
+            "SELECT level FROM notification_levels WHERE user_id = ?1 AND room_name = ?2",
+            params![user_id, room_name],
+            |row| row.get(0),
+        )
+        .optional()?;
+
+    // Unknown levels are taken as the default, so that nothing is missed
+    Ok(level
+        .and_then(|level| level.parse().ok())
+        .unwrap_or(NotificationLevel::All))
+}
+
+// The rooms `user_id` changed the notification level of, by room name.
+pub fn levels(conn: &Connection, user_id: usize) -> Result<Vec<RoomLevel>, rusqlite::Error> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT room_name, level FROM notification_levels WHERE user_id = ?1
+            ORDER BY room_name",
+    )?;
+    let levels = stmt
+        .query_map(params![user_id], |row| {
+            Ok(RoomLevel {
+                room: row.get(0)?,
+                level: row
+                    .get::<_, String>(1)?
+                    .parse()
+                    .unwrap_or(NotificationLevel::All),
+            })
+        })?
+        .collect();
+
+    levels
+}
+
+// Those of `user_ids` who get `notification` of messages sent to `room_name`.
+pub fn recipients(
+    conn: &Connection,
+    room_name: &str,
+    user_ids: Vec<usize>,
+    notification: Notification,
+) -> Result<Vec<usize>, rusqlite::Error> {
+    let mut recipients = Vec::new();
+    for user_id in user_ids {
+        if level(conn, user_id, room_name)?.allows(notification) {
+            recipients.push(user_id);
+        }
+    }
+
+    Ok(recipients)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+
+    #[test]
+    fn test_levels() {
+        let conn = Connection::open_in_memory().unwrap();
+        db::init_schema(&conn).unwrap();
+
+        assert_eq!(level(&conn, 1, "room1").unwrap(), NotificationLevel::All);
+        set_level(&conn, 1, "room1", NotificationLevel::Muted).unwrap();
+        set_level(&conn, 1, "room2", NotificationLevel::Mentions).unwrap();
+        set_level(&conn, 2, "room1", NotificationLevel::All).unwrap();
+
+        assert_eq!(
+            recipients(&conn, "room1", vec![1, 2], Notification::Mention).unwrap(),
+            vec![2]
+        );
+        assert_eq!(
+            recipients(&conn, "room2", vec![1, 2], Notification::Mention).unwrap(),
+            vec![1, 2]
+        );
+        assert_eq!(
+            recipients(&conn, "room2", vec![1, 2], Notification::Alert).unwrap(),
+            vec![2]
+        );
+        assert_eq!(
+            recipients(&conn, "room2", vec![1, 2], Notification::Direct).unwrap(),
+            vec![1, 2]
+        );
+
+        // Rooms set back to `all` are no longer listed
+        set_level(&conn, 1, "room1", NotificationLevel::All).unwrap();
+        assert_eq!(
+            levels(&conn, 1).unwrap(),
+            vec![RoomLevel {
+                room: String::from("room2"),
+                level: NotificationLevel::Mentions
+            }]
+        );
+    }
+}
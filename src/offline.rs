@@ -0,0 +1,85 @@
+use rusqlite::{params, Connection};
+
+// Most events queued for a user while they are offline. Older events are
+// dropped first.
+pub const MAX_QUEUED_EVENTS: usize = 100;
+
+// Queues `event`, about message `message_id` sent to `room_name`, for
+// `user_id` to be sent once they connect again. `event` is kept as serialized.
+pub fn enqueue(
+    conn: &Connection,
+    user_id: usize,
+    room_name: &str,
+    message_id: i64,
+    event: &str,
+) -> Result<(), rusqlite::Error> {
+    conn.execute(
+        "INSERT INTO queued_events (user_id, room_name, message_id, event)
+            VALUES (?1, ?2, ?3, ?4)",
+        params![user_id, room_name, message_id, event],
+    )?;
+    conn.execute(
+        "DELETE FROM queued_events WHERE user_id = ?1 AND event_id NOT IN
+            (SELECT event_id FROM queued_events WHERE user_id = ?1
+                ORDER BY event_id DESC LIMIT ?2)",
+        params![user_id, MAX_QUEUED_EVENTS],
+    )?;
+
+    Ok(())
+}
+
+// Takes the events queued for `user_id`, oldest first, emptying their queue.
+// Events of messages deleted since are dropped.
+pub fn take_queued(conn: &Connection, user_id: usize) -> Result<Vec<String>, rusqlite::Error> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT q.event FROM queued_events q
+            JOIN chat_messages m ON m.message_id = q.message_id
+            WHERE q.user_id = ?1 AND m.deleted_at IS NULL
+            ORDER BY q.event_id",
+    )?;
+    let events = stmt
+        .query_map(params![user_id], |row| row.get(0))?
+        .collect::<Result<Vec<String>, _>>()?;
+
+    conn.execute(
+        "DELETE FROM queued_events WHERE user_id = ?1",
+        params![user_id],
+    )?;
+
+    Ok(events)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+
+    #[test]
+    fn test_queued_events() {
+        let conn = Connection::open_in_memory().unwrap();
+        db::init_schema(&conn).unwrap();
+
+        conn.execute(
+            "INSERT INTO chat_messages (message_id, user_id, room_name, message) VALUES
+                (1, 1, 'room1', 'Hi'), (2, 1, 'room1', 'Bye')",
+            [],
+        )
+        .unwrap();
+        enqueue(&conn, 2, "room1", 1, "first").unwrap();
+        enqueue(&conn, 2, "room1", 2, "second").unwrap();
+        enqueue(&conn, 3, "room1", 1, "other").unwrap();
+
+        // Events of deleted messages are dropped
+        db::delete_message(&conn, 2, "room1", 1, None).unwrap();
+        assert_eq!(take_queued(&conn, 2).unwrap(), vec!["first"]);
+        assert!(take_queued(&conn, 2).unwrap().is_empty());
+
+        // Queues are bounded, keeping the newest events
+        for i in 0..MAX_QUEUED_EVENTS + 5 {
+            enqueue(&conn, 3, "room1", 1, &i.to_string()).unwrap();
+        }
+        let queued = take_queued(&conn, 3).unwrap();
+        assert_eq!(queued.len(), MAX_QUEUED_EVENTS);
+        assert_eq!(queued[0], "5");
+    }
+}
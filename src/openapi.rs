@@ -0,0 +1,230 @@
+use std::sync::{Arc, OnceLock};
+
+use utoipa::{
+    openapi::{
+        security::{ApiKey, ApiKeyValue, HttpAuthScheme, HttpBuilder, SecurityScheme},
+        OpenApi as Document,
+    },
+    Modify, OpenApi,
+};
+use utoipa_swagger_ui::{Config, SwaggerFile};
+
+use crate::{auth, handlers};
+
+// Where the OpenAPI document is served, and Swagger UI browsing it.
+pub const OPENAPI_PATH: &str = "/openapi.json";
+pub const SWAGGER_UI_PATH: &str = "/swagger-ui";
+
+// The REST API, as described by the annotations of its handlers. Routes
+// upgrading to WebSockets (`/chat`, GraphQL subscriptions and federation
+// links) and those peers of a cluster call each other with are left out.
+#[derive(OpenApi)]
+#[openapi(
+    paths(
+        handlers::rooms,
+        handlers::create_room,
+        handlers::room,
+        handlers::set_room_topic,
+        handlers::set_room_read_only,
+        handlers::set_room_retention,
+        handlers::set_room_announcement,
+        handlers::room_following,
+        handlers::follow_room,
+        handlers::unfollow_room,
+        handlers::add_room_owner,
+        handlers::remove_room_owner,
+        handlers::transfer_room,
+        handlers::room_roles,
+        handlers::set_room_role,
+        handlers::room_acl,
+        handlers::set_room_access,
+        handlers::remove_room_access,
+        handlers::room_members,
+        handlers::invite_room_member,
+        handlers::remove_room_member,
+        handlers::create_invite,
+        handlers::room_invites,
+        handlers::revoke_invite,
+        handlers::create_hook,
+        handlers::room_hooks,
+        handlers::revoke_hook,
+        handlers::post_hook,
+        handlers::schedule_message,
+        handlers::scheduled_messages,
+        handlers::cancel_scheduled_message,
+        handlers::room_pins,
+        handlers::online_users,
+        handlers::read_markers,
+        handlers::room_messages,
+        handlers::post_message,
+        handlers::room_stream,
+        handlers::export_room,
+        handlers::search_room,
+        handlers::message_history,
+        handlers::kick_user,
+        handlers::room_bans,
+        handlers::ban_user,
+        handlers::unban_user,
+        handlers::room_mutes,
+        handlers::mute_user,
+        handlers::unmute_user,
+        handlers::room_shadow_bans,
+        handlers::shadow_ban_user,
+        handlers::unshadow_ban_user,
+        handlers::flagged_messages,
+        handlers::deleted_messages,
+        handlers::report_message,
+        handlers::room_reports,
+        handlers::resolve_report,
+        handlers::create_conversation,
+        handlers::conversations,
+        handlers::profile,
+        handlers::update_profile,
+        handlers::delete_user,
+        handlers::set_user_role,
+        handlers::user_sessions,
+        handlers::revoke_session,
+        handlers::create_api_token,
+        handlers::api_tokens,
+        handlers::revoke_api_token,
+        handlers::alert_keywords,
+        handlers::set_alert_keywords,
+        handlers::alerts,
+        handlers::notification_levels,
+        handlers::set_notification_level,
+        handlers::unread_counts,
+        handlers::register,
+        handlers::login,
+        handlers::forgot_password,
+        handlers::reset_password,
+        handlers::totp_enroll,
+        handlers::totp_confirm,
+        handlers::logout,
+        handlers::oauth_login,
+        handlers::oauth_callback,
+        handlers::ip_bans,
+        handlers::ban_ip,
+        handlers::unban_ip,
+        handlers::db_stats,
+        handlers::maintain_db,
+        handlers::backup_db,
+        handlers::graphql
+
+    ),
+    modifiers(&SecuritySchemes),
+    tags(
+        (name = "rooms", description = "Rooms, their settings, and who may join them"),
+        (name = "messages", description = "Messages of rooms, and sending them"),
+        (name = "moderation", description = "Sanctions, reports and flagged messages"),
+        (name = "hooks", description = "Incoming webhooks"),
+        (name = "conversations", description = "Private conversations"),
+        (name = "users", description = "Accounts, their tokens and settings"),
+        (name = "auth", description = "Registering and logging in"),
+        (name = "admin", description = "Server administration, with the admin token"),
+        (name = "graphql", description = "Queries of the GraphQL API"),
+    )
+)]
+pub struct ApiDoc;
+
+// Ways requests are authenticated, as the `security` of each operation names
+// them.
+struct SecuritySchemes;
+
+impl Modify for SecuritySchemes {
+    fn modify(&self, openapi: &mut Document) {
+        let bearer = |description: &str| {
+            SecurityScheme::Http(
+                HttpBuilder::new()
+                    .scheme(HttpAuthScheme::Bearer)
+                    .description(Some(description))
+                    .build(),
+            )
+        };
+        let components = openapi.components.get_or_insert_with(Default::default);
+
+        components.add_security_scheme(
+            "bearer",
+            bearer("JWT obtained by logging in, or an API token"),
+        );
+        components.add_security_scheme(
+            "session",
+            SecurityScheme::ApiKey(ApiKey::Cookie(ApiKeyValue::with_description(
+                auth::SESSION_COOKIE,
+                "Session cookie set by logging in",
+            ))),
+        );
+        components.add_security_scheme("admin_bearer", bearer("Admin token"));
+        components.add_security_scheme(
+            "admin_basic",
+            SecurityScheme::Http(
+                HttpBuilder::new()
+                    .scheme(HttpAuthScheme::Basic)
+                    .description(Some("Admin token as the password, with any username"))
+                    .build(),
+            ),
+        );
+    }
+}
+
+// The OpenAPI document, built once.
+pub fn document() -> &'static Document {
+    static DOCUMENT: OnceLock<Document> = OnceLock::new();
+    DOCUMENT.get_or_init(ApiDoc::openapi)
+}
+
+// The file of Swagger UI at `path`, relative to `SWAGGER_UI_PATH`, if any.
+pub fn swagger_ui_file(path: &str) -> Result<Option<SwaggerFile<'static>>, anyhow::Error> {
+    static CONFIG: OnceLock<Arc<Config<'static>>> = OnceLock::new();
+    let config = CONFIG.get_or_init(|| Arc::new(Config::from(OPENAPI_PATH)));
+
+    utoipa_swagger_ui::serve(path, config.clone()).map_err(|e| anyhow::anyhow!("{}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use utoipa::openapi::path::{Operation, ParameterIn, PathItem};
+
+    fn operations(item: &PathItem) -> impl Iterator<Item = &Operation> {
+        vec![&item.get, &item.put, &item.post, &item.delete]
+            .into_iter()
+            .flatten()
+    }
+
+    #[test]
+    fn test_path_parameters() {
+        // Every parameter in a path is documented, and nothing else as one
+        for (path, item) in &document().paths.paths {
+            let mut in_path: Vec<&str> = path
+                .split('/')
+                .filter_map(|segment| segment.strip_prefix('{')?.strip_suffix('}'))
+                .collect();
+            in_path.sort_unstable();
+            for operation in operations(item) {
+                let mut documented: Vec<&str> = operation
+                    .parameters
+                    .iter()
+                    .flatten()
+                    .filter(|parameter| parameter.parameter_in == ParameterIn::Path)
+                    .map(|parameter| parameter.name.as_str())
+                    .collect();
+                documented.sort_unstable();
+                assert_eq!(documented, in_path, "{}", path);
+            }
+        }
+    }
+
+    #[test]
+    fn test_security_schemes() {
+        // Every scheme operations are secured with is defined
+        let schemes = &document().components.as_ref().unwrap().security_schemes;
+        for operation in document().paths.paths.values().flat_map(operations) {
+            let requirements = serde_json::to_value(&operation.security).unwrap();
+            for requirement in requirements.as_array().into_iter().flatten() {
+                for scheme in requirement.as_object().unwrap().keys() {
+                    assert!(schemes.contains_key(scheme), "{}", scheme);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,216 @@
+use std::collections::HashMap;
+
+use anyhow::anyhow;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+
+// Fewest and most options a poll may have.
+pub const MIN_OPTIONS: usize = 2;
+pub const MAX_OPTIONS: usize = 10;
+
+// Longest an option may be, in characters.
+pub const MAX_OPTION_LENGTH: usize = 100;
+
+// An option of a poll, with the number of users who voted for it.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct PollOption {
+    pub text: String,
+    pub votes: u64,
+}
+
+// Checks that a poll asking `question` may offer `options`.
+pub fn validate(question: &str, options: &[String]) -> Result<(), anyhow::Error> {
+    if question.trim().is_empty() {
+        return Err(anyhow!("Polls must ask a question"));
+    }
+
+    if options.len() < MIN_OPTIONS || options.len() > MAX_OPTIONS {
+        return Err(anyhow!(
+            "Polls must have between {} and {} options",
+            MIN_OPTIONS,
+            MAX_OPTIONS
+        ));
+    }
+
+    if options
+        .iter()
+        .any(|option| option.trim().is_empty() || option.chars().count() > MAX_OPTION_LENGTH)
+    {
+        return Err(anyhow!(
+            "Options must be between 1 and {} characters long",
+            MAX_OPTION_LENGTH
+        ));
+    }
+
+    Ok(())
+}
+
+// Records the options of the poll sent as message `message_id` to `room_name`.
+pub fn create_poll(
+    conn: &Connection,
+    message_id: i64,
+    room_name: &str,
+    options: &[String],
+) -> Result<(), rusqlite::Error> {
+    let mut stmt = conn.prepare_cached(
+        "INSERT INTO poll_options (message_id, room_name, option_index, text)
+            VALUES (?1, ?2, ?3, ?4)",
+    )?;
+    for (index, option) in options.iter().enumerate() {
+        stmt.execute(params![message_id, room_name, index, option])?;
+    }
+
+    Ok(())
+}
+
+// Records the vote of `user_id` for option `option` of poll `message_id` of
+// `room_name`, replacing any vote they cast before, and returns the tally of
+// the poll. Returns None if there is no such poll, and an error if it has no
+// such option.
+pub fn vote(
+    conn: &Connection,
+    room_name: &str,
+    message_id: i64,
+    user_id: usize,
+    option: usize,
+) -> Result<Option<Vec<PollOption>>, anyhow::Error> {
+    let options: Option<usize> = conn
+        .query_row(
+            "SELECT COUNT(*) FROM poll_options p
+                JOIN chat_messages m ON m.message_id = p.message_id
+                WHERE p.message_id = ?1 AND p.room_name = ?2 AND m.deleted_at IS NULL
+                GROUP BY p.message_id",
+            params![message_id, room_name],
+            |row| row.get(0),
+        )
+        .optional()?;
+    match options {
+        Some(options) if option >= options => {
+            return Err(anyhow!("Poll {} has no option {}", message_id, option))
+        }
+        Some(_) => {}
+        None => return Ok(None),
+    }
+
+    conn.execute(
+        "INSERT INTO poll_votes (message_id, room_name, user_id, option_index)
+            VALUES (?1, ?2, ?3, ?4)
+            ON CONFLICT (message_id, user_id) DO UPDATE
+                SET option_index = excluded.option_index, voted_at = CURRENT_TIMESTAMP",
+        params![message_id, room_name, user_id, option],
+    )?;
+
+    Ok(tally(conn, message_id)?)
+}
+
+// Options of poll `message_id` with their votes, in order, if it is a poll.
+pub fn tally(
+    conn: &Connection,
+    message_id: i64,
+) -> Result<Option<Vec<PollOption>>, rusqlite::Error> {
+    Ok(tallies(conn, &[message_id])?.remove(&message_id))
+}
+
+// Options of the polls among `message_ids`, with their votes, by message ID.
+// Messages that are not polls are left out.
+pub fn tallies(
+    conn: &Connection,
+    message_ids: &[i64],
+) -> Result<HashMap<i64, Vec<PollOption>>, rusqlite::Error> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT p.text, COUNT(v.user_id) FROM poll_options p
+            LEFT JOIN poll_votes v
+                ON v.message_id = p.message_id AND v.option_index = p.option_index
+            WHERE p.message_id = ?1
+            GROUP BY p.option_index
+            ORDER BY p.option_index",
+    )?;
+
+    let mut tallies = HashMap::new();
+    for message_id in message_ids {
+        let options = stmt
+            .query_map(params![message_id], |row| {
+                Ok(PollOption {
+                    text: row.get(0)?,
+                    votes: row.get(1)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        if !options.is_empty() {
+            tallies.insert(*message_id, options);
+        }
+    }
+
+    Ok(tallies)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+
+    fn options(texts: &[&str]) -> Vec<String> {
+        texts.iter().map(|text| String::from(*text)).collect()
+    }
+
+    fn setup() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        db::init_schema(&conn).unwrap();
+        conn.execute(
+            "INSERT INTO chat_messages (message_id, user_id, room_name, message)
+                VALUES (1, 1, 'room1', 'Lunch?'), (2, 1, 'room1', 'Hi')",
+            [],
+        )
+        .unwrap();
+        create_poll(&conn, 1, "room1", &options(&["Pizza", "Sushi"])).unwrap();
+
+        conn
+    }
+
+    fn votes(tally: Vec<PollOption>) -> Vec<u64> {
+        tally.into_iter().map(|option| option.votes).collect()
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(validate("Lunch?", &options(&["Pizza", "Sushi"])).is_ok());
+        assert!(validate(" ", &options(&["Pizza", "Sushi"])).is_err());
+        assert!(validate("Lunch?", &options(&["Pizza"])).is_err());
+        assert!(validate("Lunch?", &options(&["Pizza", ""])).is_err());
+        assert!(validate("Lunch?", &vec![String::from("Pizza"); MAX_OPTIONS + 1]).is_err());
+    }
+
+    #[test]
+    fn test_vote() {
+        let conn = setup();
+
+        assert_eq!(votes(tally(&conn, 1).unwrap().unwrap()), vec![0, 0]);
+        assert!(tally(&conn, 2).unwrap().is_none());
+
+        // Users have a single vote, which they may change
+        assert_eq!(
+            votes(vote(&conn, "room1", 1, 1, 0).unwrap().unwrap()),
+            vec![1, 0]
+        );
+        assert_eq!(
+            votes(vote(&conn, "room1", 1, 2, 0).unwrap().unwrap()),
+            vec![2, 0]
+        );
+        assert_eq!(
+            votes(vote(&conn, "room1", 1, 1, 1).unwrap().unwrap()),
+            vec![1, 1]
+        );
+
+        assert!(vote(&conn, "room1", 1, 1, 2).is_err());
+        assert!(vote(&conn, "room1", 2, 1, 0).unwrap().is_none());
+        assert!(vote(&conn, "room2", 1, 1, 0).unwrap().is_none());
+
+        // Deleted polls are closed
+        conn.execute(
+            "UPDATE chat_messages SET deleted_at = CURRENT_TIMESTAMP WHERE message_id = 1",
+            [],
+        )
+        .unwrap();
+        assert!(vote(&conn, "room1", 1, 1, 0).unwrap().is_none());
+    }
+}
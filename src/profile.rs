@@ -0,0 +1,151 @@
+use anyhow::anyhow;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+pub const MAX_AVATAR_URL_LENGTH: usize = 2048;
+pub const MAX_BIO_LENGTH: usize = 500;
+
+// What clients show next to the messages of a user.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Profile {
+    pub user_id: usize,
+    pub nick: Option<String>,
+    pub avatar_url: Option<String>,
+    pub bio: Option<String>,
+}
+
+// Request body of the profile update route: replaces the whole profile.
+#[derive(Debug, Default, Deserialize, ToSchema)]
+pub struct ProfileUpdate {
+    #[serde(default)]
+    pub avatar_url: Option<String>,
+    #[serde(default)]
+    pub bio: Option<String>,
+}
+
+impl ProfileUpdate {
+    // Checks that the profile is acceptable.
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        if let Some(avatar_url) = &self.avatar_url {
+            if avatar_url.len() > MAX_AVATAR_URL_LENGTH {
+                return Err(anyhow!(
+                    "Avatar URL may be at most {} characters long",
+                    MAX_AVATAR_URL_LENGTH
+                ));
+            }
+
+            // Clients render avatars as-is, so only web URLs are allowed
+            if !avatar_url.starts_with("https://") && !avatar_url.starts_with("http://") {
+                return Err(anyhow!("Avatar URL must be an http(s) URL"));
+            }
+        }
+
+        if let Some(bio) = &self.bio {
+            if bio.chars().count() > MAX_BIO_LENGTH {
+                return Err(anyhow!(
+                    "Bio may be at most {} characters long",
+                    MAX_BIO_LENGTH
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// The profile of `user_id`, or `None` if there is no such user.
+// Users that never set up their profile have an empty one.
+pub fn profile(conn: &Connection, user_id: usize) -> Result<Option<Profile>, rusqlite::Error> {
+    conn.query_row(
+        "SELECT u.user_id, n.name, p.avatar_url, p.bio
+            FROM users u
+            LEFT JOIN usernames n ON n.user_id = u.user_id
+            LEFT JOIN profiles p ON p.user_id = u.user_id
+            WHERE u.user_id = ?1",
+        params![user_id],
+        |row| {
+            Ok(Profile {
+                user_id: row.get(0)?,
+                nick: row.get(1)?,
+                avatar_url: row.get(2)?,
+                bio: row.get(3)?,
+            })
+        },
+    )
+    .optional()
+}
+
+// Replaces the profile of `user_id`.
+pub fn set_profile(
+    conn: &Connection,
+    user_id: usize,
+    update: &ProfileUpdate,
+) -> Result<(), rusqlite::Error> {
+    conn.execute(
+        "INSERT INTO profiles (user_id, avatar_url, bio) VALUES (?1, ?2, ?3)
+            ON CONFLICT (user_id) DO UPDATE SET
+                avatar_url = excluded.avatar_url,
+                bio = excluded.bio,
+                updated_at = CURRENT_TIMESTAMP",
+        params![user_id, update.avatar_url, update.bio],
+    )?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{auth, db};
+
+    #[test]
+    fn test_validate() {
+        let update = ProfileUpdate {
+            avatar_url: Some(String::from("https://example.com/a.png")),
+            bio: Some(String::from("Hi there")),
+        };
+        assert!(update.validate().is_ok());
+        assert!(ProfileUpdate::default().validate().is_ok());
+
+        let update = ProfileUpdate {
+            avatar_url: Some(String::from("javascript:alert(1)")),
+            bio: None,
+        };
+        assert!(update.validate().is_err());
+
+        let update = ProfileUpdate {
+            avatar_url: None,
+            bio: Some("a".repeat(MAX_BIO_LENGTH + 1)),
+        };
+        assert!(update.validate().is_err());
+    }
+
+    #[test]
+    fn test_profiles() {
+        let conn = Connection::open_in_memory().unwrap();
+        db::init_schema(&conn).unwrap();
+
+        let user_id = auth::create_guest(&conn, None).unwrap();
+        assert_eq!(profile(&conn, user_id + 1).unwrap(), None);
+
+        let empty = profile(&conn, user_id).unwrap().unwrap();
+        assert_eq!(empty.avatar_url, None);
+        assert_eq!(empty.bio, None);
+
+        db::reserve_nickname(&conn, user_id, "alice").unwrap();
+        let update = ProfileUpdate {
+            avatar_url: Some(String::from("https://example.com/a.png")),
+            bio: Some(String::from("Hi there")),
+        };
+        set_profile(&conn, user_id, &update).unwrap();
+
+        let profile_after = profile(&conn, user_id).unwrap().unwrap();
+        assert_eq!(profile_after.nick.as_deref(), Some("alice"));
+        assert_eq!(profile_after.bio.as_deref(), Some("Hi there"));
+
+        // Updates replace the whole profile
+        set_profile(&conn, user_id, &ProfileUpdate::default()).unwrap();
+        assert_eq!(profile(&conn, user_id).unwrap().unwrap().avatar_url, None);
+    }
+}
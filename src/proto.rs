@@ -0,0 +1,7 @@
+// Types generated from `proto/`, shared by the gRPC service and protobuf
+// WebSocket connections.
+
+// Events are as large as their largest message, as over JSON
+#![allow(clippy::large_enum_variant)]
+
+tonic::include_proto!("bi_chat");
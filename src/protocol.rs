@@ -0,0 +1,1135 @@
+use serde::{Deserialize, Serialize};
+use warp::ws::Message;
+
+use crate::{
+    db::{DBMessage, Forwarded},
+    moderation::SanctionKind,
+    poll::PollOption,
+    proto,
+};
+
+// Frames sent by clients over the WebSocket connection.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientFrame {
+    // A chat message to be broadcast to the room.
+    // `client_id` is an optional client-chosen identifier, echoed back in the
+    // `Ack` so that clients can match acknowledgments to pending messages.
+    // Messages with `ttl_secs` set are deleted that many seconds after being
+    // sent, or never persisted at all if 0.
+    Message {
+        text: String,
+        #[serde(default)]
+        client_id: Option<String>,
+        #[serde(default)]
+        ttl_secs: Option<u64>,
+    },
+
+    // A poll asking `question` to the room, sent as a message. Everyone in
+    // the room may vote for one of its `options`.
+    Poll {
+        question: String,
+        options: Vec<String>,
+        #[serde(default)]
+        client_id: Option<String>,
+    },
+
+    // Votes for option `option` of poll `id`, replacing any earlier vote of
+    // this client's user.
+    Vote {
+        id: i64,
+        option: usize,
+    },
+
+    // Replaces the content of a message previously sent by this client.
+    Edit {
+        id: i64,
+        text: String,
+    },
+
+    // Forwards a message of the room to room `to`, which this client's user
+    // may join, crediting its original author.
+    Forward {
+        id: i64,
+        to: String,
+    },
+
+    // Deletes a message previously sent by this client.
+    // Moderators may delete any message in the room.
+    Delete {
+        id: i64,
+    },
+
+    // Pins a message to the room. Moderators only.
+    Pin {
+        id: i64,
+    },
+
+    // Unpins a message from the room. Moderators only.
+    Unpin {
+        id: i64,
+    },
+
+    // Marks the messages of the room up to `id` as seen by this client's user.
+    Read {
+        id: i64,
+    },
+
+    // Tells the server this client is still active, keeping its user online.
+    // Any other frame does as well.
+    Heartbeat,
+
+    // Sets the nickname this client's user is displayed with.
+    SetNick {
+        nick: String,
+    },
+
+    // Gives the password of a password-protected room, if not given when
+    // connecting. Only accepted as the first frame of a connection.
+    Auth {
+        password: String,
+    },
+
+    // Replaces the topic and description of the room. Moderators only.
+    SetTopic {
+        #[serde(default)]
+        topic: Option<String>,
+        #[serde(default)]
+        description: Option<String>,
+    },
+
+    // Closes the connections of a user to the room. Moderators only.
+    Kick {
+        user_id: usize,
+    },
+
+    // Kicks a user out of the room, keeping them out for `duration_secs` if
+    // set, or until unbanned. Moderators only.
+    Ban {
+        user_id: usize,
+        #[serde(default)]
+        reason: Option<String>,
+        #[serde(default)]
+        duration_secs: Option<u64>,
+    },
+
+    // Keeps a user from posting in the room for `duration_secs` if set, or
+    // until unmuted. Moderators only.
+    Mute {
+        user_id: usize,
+        #[serde(default)]
+        reason: Option<String>,
+        #[serde(default)]
+        duration_secs: Option<u64>,
+    },
+
+    // Lets a user waiting for approval into the room, making them a member of
+    // it. Moderators only.
+    ApproveJoin {
+        user_id: usize,
+    },
+
+    // Turns away a user waiting for approval, closing their connections.
+    // Moderators only.
+    RejectJoin {
+        user_id: usize,
+    },
+
+    // Joins another room over this connection, with its password if it has
+    // one. Frames are then meant for it when naming it as their `room`.
+    // `since` is the ID of the last message received from the room, if any:
+    // every later message is replayed instead of its recent history.
+    Join {
+        room: String,
+        #[serde(default)]
+        password: Option<String>,
+        #[serde(default)]
+        since: Option<i64>,
+    },
+
+    // Leaves a room joined with a `join` frame.
+    Leave {
+        room: String,
+    },
+}
+
+impl ClientFrame {
+    // Whether the frame acts on messages of its room, which the owner of the
+    // room handles when clustered: see `Cluster::owner`. Messages and polls
+    // are checked before being handed to it.
+    pub fn acts_on_messages(&self) -> bool {
+        matches!(
+            self,
+            ClientFrame::Vote { .. }
+                | ClientFrame::Forward { .. }
+                | ClientFrame::Edit { .. }
+                | ClientFrame::Delete { .. }
+                | ClientFrame::Pin { .. }
+                | ClientFrame::Unpin { .. }
+                | ClientFrame::Read { .. }
+        )
+    }
+
+    // Parses a text frame sent by a client.
+    // Anything that is not a JSON object is treated as a plain chat message,
+    // so that simple clients (e.g. websocat) can keep sending raw text.
+    pub fn parse(text: &str) -> Result<Self, serde_json::Error> {
+        match serde_json::from_str::<serde_json::Value>(text) {
+            Ok(value) if value.is_object() => serde_json::from_value(value),
+            _ => Ok(ClientFrame::Message {
+                text: String::from(text),
+                client_id: None,
+                ttl_secs: None,
+            }),
+        }
+    }
+
+    // Room a text frame is meant for, if it names one as its `room`. Frames
+    // naming none are meant for the room the connection was opened to.
+    // `join` and `leave` frames name the room they join or leave instead.
+    pub fn target_room(text: &str) -> Option<String> {
+        #[derive(Deserialize)]
+        struct Target {
+            room: Option<String>,
+        }
+
+        serde_json::from_str::<Target>(text).ok()?.room
+    }
+}
+
+// Whether a user connected to a room is active.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Availability {
+    // At least one of their connections sent a frame lately
+    Online,
+    // None of their connections sent a frame for a while
+    Away,
+}
+
+impl Availability {
+    pub fn of(away: bool) -> Self {
+        if away {
+            Availability::Away
+        } else {
+            Availability::Online
+        }
+    }
+}
+
+// A user connected to a room, once however many connections they have to it.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct OnlineUser {
+    pub user_id: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nick: Option<String>,
+    pub status: Availability,
+}
+
+// Events sent by the server over the WebSocket connection.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerEvent {
+    // A chat message sent to the room.
+    // `seq` orders messages within a room: every user in the room receives
+    // messages in increasing `seq` order.
+    Message {
+        id: i64,
+        seq: i64,
+        room: String,
+        user_id: usize,
+        // Nickname of the author when the message was sent, if they had one
+        #[serde(skip_serializing_if = "Option::is_none")]
+        nick: Option<String>,
+        text: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        edited_at: Option<String>,
+        // Deleted messages are sent as tombstones, without their text
+        #[serde(skip_serializing_if = "Option::is_none")]
+        deleted_at: Option<String>,
+        // Seconds the message is kept for, if it expires
+        #[serde(skip_serializing_if = "Option::is_none")]
+        ttl_secs: Option<u64>,
+        // Where the message was first sent, and by whom, if it was forwarded
+        #[serde(skip_serializing_if = "Option::is_none")]
+        forwarded: Option<Forwarded>,
+        // Options of the poll, with their votes, if the message is one. Its
+        // `text` is the question it asks
+        #[serde(skip_serializing_if = "Option::is_none")]
+        poll: Option<Vec<PollOption>>,
+    },
+
+    // Someone voted in poll `id` of the room, which now stands at `options`.
+    PollTally {
+        id: i64,
+        room: String,
+        options: Vec<PollOption>,
+    },
+
+    // A message in the room has been edited by its author.
+    Edit {
+        id: i64,
+        room: String,
+        user_id: usize,
+        text: String,
+    },
+
+    // A message in the room has been deleted.
+    Delete {
+        id: i64,
+        room: String,
+        deleted_by: usize,
+    },
+
+    // A message has been pinned to the room.
+    Pin {
+        id: i64,
+        room: String,
+        pinned_by: usize,
+    },
+
+    // A message has been unpinned from the room.
+    Unpin {
+        id: i64,
+        room: String,
+        unpinned_by: usize,
+    },
+
+    // A message mentioning this client's user has been sent to `room`, which
+    // may be another room than the one this client is connected to.
+    Mention {
+        id: i64,
+        room: String,
+        user_id: usize,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        nick: Option<String>,
+        text: String,
+    },
+
+    // A message matching a keyword this client's user watches has been sent to
+    // `room`, which may be another room than the one this client is connected
+    // to. `alert_id` identifies it among the alerts listed by the HTTP API.
+    Alert {
+        alert_id: i64,
+        id: i64,
+        room: String,
+        user_id: usize,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        nick: Option<String>,
+        text: String,
+        keyword: String,
+    },
+
+    // A user has seen the messages of the room up to `id`.
+    Read {
+        id: i64,
+        room: String,
+        user_id: usize,
+    },
+
+    // The topic and description of the room have been changed. Either is null
+    // once cleared.
+    Topic {
+        room: String,
+        topic: Option<String>,
+        description: Option<String>,
+        set_by: usize,
+    },
+
+    // The room has been made read-only, or writable again.
+    ReadOnly {
+        room: String,
+        read_only: bool,
+        set_by: usize,
+    },
+
+    // A user has been kicked out of the room by a moderator. Their connections
+    // are closed right after.
+    Kick {
+        room: String,
+        user_id: usize,
+        kicked_by: usize,
+    },
+
+    // A user has been kicked out of the room automatically, for sending more
+    // than `max_messages` within `window_secs`. Their connections are closed
+    // right after.
+    FloodKick {
+        room: String,
+        user_id: usize,
+        max_messages: usize,
+        window_secs: u64,
+    },
+
+    // A user has been banned from, or muted in, the room by a moderator, until
+    // `expires_at` if set. Banned users have their connections closed right
+    // after.
+    Sanction {
+        room: String,
+        user_id: usize,
+        kind: SanctionKind,
+        issued_by: usize,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        reason: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        expires_at: Option<String>,
+    },
+
+    // The ban or mute of a user in the room has been lifted.
+    SanctionLifted {
+        room: String,
+        user_id: usize,
+        kind: SanctionKind,
+        lifted_by: usize,
+    },
+
+    // A user has joined the room, along with their profile.
+    Join {
+        room: String,
+        user_id: usize,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        nick: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        avatar_url: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        bio: Option<String>,
+    },
+
+    // The last connection of a user to the room has closed. Their nickname is
+    // given as when they joined, for clients to tell who left.
+    Leave {
+        room: String,
+        user_id: usize,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        nick: Option<String>,
+    },
+
+    // Who is connected to the room, sent to a client once it joined. `join`,
+    // `leave`, `status` and `rename` events keep it up to date from then on.
+    Presence {
+        room: String,
+        users: Vec<OnlineUser>,
+    },
+
+    // A user in the room has gone away, or come back online. Users going
+    // offline leave the room instead.
+    Status {
+        room: String,
+        user_id: usize,
+        status: Availability,
+    },
+
+    // A user in the room has changed nickname.
+    Rename {
+        room: String,
+        user_id: usize,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        old_nick: Option<String>,
+        nick: String,
+    },
+
+    // Sent back to the author of a message once it has been accepted.
+    Ack {
+        id: i64,
+        room: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        client_id: Option<String>,
+    },
+
+    // Sent to a client joining a room that requires approval, instead of
+    // anything else until a moderator approves it. Its connection is closed if
+    // rejected.
+    JoinPending {
+        room: String,
+    },
+
+    // Sent to the moderators of a room requiring approval when a user is
+    // waiting to join it, and to moderators joining while they wait.
+    JoinRequest {
+        room: String,
+        user_id: usize,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        nick: Option<String>,
+    },
+
+    // Sent to a client refused by its room for already holding `capacity`
+    // connections, right before its connection is closed.
+    RoomFull {
+        room: String,
+        capacity: usize,
+    },
+
+    // Sent to a client no longer in a room it joined with a `join` frame:
+    // having left it, been refused by it, or been kicked or banned from it.
+    // `code` and `reason` are those its connection would have been closed
+    // with, had it been opened to the room.
+    Left {
+        room: String,
+        code: u16,
+        reason: String,
+    },
+
+    // Sent back to a client when one of its frames, meant for `room`, could
+    // not be handled.
+    Error {
+        room: String,
+        reason: String,
+    },
+}
+
+impl From<DBMessage> for ServerEvent {
+    fn from(msg: DBMessage) -> Self {
+        let id = msg.message_id.unwrap_or_default();
+        let text = if msg.deleted_at.is_some() {
+            String::new()
+        } else {
+            msg.message
+        };
+
+        ServerEvent::Message {
+            id,
+            // Messages persisted before sequencing was introduced
+            seq: msg.seq.unwrap_or(id),
+            room: msg.room_name,
+            user_id: msg.user_id,
+            nick: msg.nickname,
+            text,
+            edited_at: msg.edited_at,
+            deleted_at: msg.deleted_at,
+            ttl_secs: None,
+            forwarded: msg.forwarded,
+            poll: None,
+        }
+    }
+}
+
+impl ServerEvent {
+    pub fn to_message(&self) -> Message {
+        Message::text(self.to_json())
+    }
+
+    pub fn to_json(&self) -> String {
+        // Serializing these types can not fail: all keys are strings
+        serde_json::to_string(self).expect("Failed to serialize server event")
+    }
+}
+
+// Frames sent by clients speaking protobuf. Plain messages are messages like
+// any other.
+impl From<proto::client_frame::Frame> for ClientFrame {
+    fn from(frame: proto::client_frame::Frame) -> Self {
+        use proto::client_frame::{self as pb, Frame};
+
+        match frame {
+            Frame::Text(text) => ClientFrame::Message {
+                text,
+                client_id: None,
+                ttl_secs: None,
+            },
+            Frame::Message(pb::Message {
+                text,
+                client_id,
+                ttl_secs,
+            }) => ClientFrame::Message {
+                text,
+                client_id,
+                ttl_secs,
+            },
+            Frame::Poll(pb::Poll {
+                question,
+                options,
+                client_id,
+            }) => ClientFrame::Poll {
+                question,
+                options,
+                client_id,
+            },
+            Frame::Vote(pb::Vote { id, option }) => ClientFrame::Vote {
+                id,
+                option: option as usize,
+            },
+            Frame::Edit(pb::Edit { id, text }) => ClientFrame::Edit { id, text },
+            Frame::Forward(pb::Forward { id, to }) => ClientFrame::Forward { id, to },
+            Frame::Delete(pb::Delete { id }) => ClientFrame::Delete { id },
+            Frame::Pin(pb::Pin { id }) => ClientFrame::Pin { id },
+            Frame::Unpin(pb::Unpin { id }) => ClientFrame::Unpin { id },
+            Frame::Read(pb::Read { id }) => ClientFrame::Read { id },
+            Frame::Heartbeat(pb::Heartbeat {}) => ClientFrame::Heartbeat,
+            Frame::SetNick(pb::SetNick { nick }) => ClientFrame::SetNick { nick },
+            Frame::Auth(pb::Auth { password }) => ClientFrame::Auth { password },
+            Frame::SetTopic(pb::SetTopic { topic, description }) => {
+                ClientFrame::SetTopic { topic, description }
+            }
+            Frame::Kick(pb::Kick { user_id }) => ClientFrame::Kick {
+                user_id: user_id as usize,
+            },
+            Frame::Ban(pb::Ban {
+                user_id,
+                reason,
+                duration_secs,
+            }) => ClientFrame::Ban {
+                user_id: user_id as usize,
+                reason,
+                duration_secs,
+            },
+            Frame::Mute(pb::Mute {
+                user_id,
+                reason,
+                duration_secs,
+            }) => ClientFrame::Mute {
+                user_id: user_id as usize,
+                reason,
+                duration_secs,
+            },
+            Frame::ApproveJoin(pb::ApproveJoin { user_id }) => ClientFrame::ApproveJoin {
+                user_id: user_id as usize,
+            },
+            Frame::RejectJoin(pb::RejectJoin { user_id }) => ClientFrame::RejectJoin {
+                user_id: user_id as usize,
+            },
+            Frame::Join(pb::Join {
+                room,
+                password,
+                since,
+            }) => ClientFrame::Join {
+                room,
+                password,
+                since,
+            },
+            Frame::Leave(pb::Leave { room }) => ClientFrame::Leave { room },
+        }
+    }
+}
+
+impl From<Availability> for proto::Availability {
+    fn from(status: Availability) -> Self {
+        match status {
+            Availability::Online => proto::Availability::Online,
+            Availability::Away => proto::Availability::Away,
+        }
+    }
+}
+
+impl From<SanctionKind> for proto::SanctionKind {
+    fn from(kind: SanctionKind) -> Self {
+        match kind {
+            SanctionKind::Ban => proto::SanctionKind::Ban,
+            SanctionKind::Mute => proto::SanctionKind::Mute,
+            SanctionKind::ShadowBan => proto::SanctionKind::ShadowBan,
+        }
+    }
+}
+
+impl From<PollOption> for proto::PollOption {
+    fn from(option: PollOption) -> Self {
+        proto::PollOption {
+            text: option.text,
+            votes: option.votes,
+        }
+    }
+}
+
+// Events sent to clients speaking protobuf.
+impl From<ServerEvent> for proto::ServerEvent {
+    fn from(event: ServerEvent) -> Self {
+        use proto::server_event::{self as pb, Event};
+
+        let options = |options: Vec<PollOption>| options.into_iter().map(Into::into).collect();
+        let event = match event {
+            ServerEvent::Message {
+                id,
+                seq,
+                room,
+                user_id,
+                nick,
+                text,
+                edited_at,
+                deleted_at,
+                ttl_secs,
+                forwarded,
+                poll,
+            } => Event::Message(pb::Message {
+                id,
+                seq,
+                room,
+                user_id: user_id as u64,
+                nick,
+                text,
+                edited_at,
+                deleted_at,
+                ttl_secs,
+                forwarded: forwarded.map(|forwarded| proto::Forwarded {
+                    id: forwarded.id,
+                    room: forwarded.room,
+                    user_id: forwarded.user_id as u64,
+                    nick: forwarded.nick,
+                }),
+                poll: poll.map(options).unwrap_or_default(),
+            }),
+            ServerEvent::PollTally {
+                id,
+                room,
+                options: tally,
+            } => Event::PollTally(pb::PollTally {
+                id,
+                room,
+                options: options(tally),
+            }),
+            ServerEvent::Edit {
+                id,
+                room,
+                user_id,
+                text,
+            } => Event::Edit(pb::Edit {
+                id,
+                room,
+                user_id: user_id as u64,
+                text,
+            }),
+            ServerEvent::Delete {
+                id,
+                room,
+                deleted_by,
+            } => Event::Delete(pb::Delete {
+                id,
+                room,
+                deleted_by: deleted_by as u64,
+            }),
+            ServerEvent::Pin {
+                id,
+                room,
+                pinned_by,
+            } => Event::Pin(pb::Pin {
+                id,
+                room,
+                pinned_by: pinned_by as u64,
+            }),
+            ServerEvent::Unpin {
+                id,
+                room,
+                unpinned_by,
+            } => Event::Unpin(pb::Unpin {
+                id,
+                room,
+                unpinned_by: unpinned_by as u64,
+            }),
+            ServerEvent::Mention {
+                id,
+                room,
+                user_id,
+                nick,
+                text,
+            } => Event::Mention(pb::Mention {
+                id,
+                room,
+                user_id: user_id as u64,
+                nick,
+                text,
+            }),
+            ServerEvent::Alert {
+                alert_id,
+                id,
+                room,
+                user_id,
+                nick,
+                text,
+                keyword,
+            } => Event::Alert(pb::Alert {
+                alert_id,
+                id,
+                room,
+                user_id: user_id as u64,
+                nick,
+                text,
+                keyword,
+            }),
+            ServerEvent::Read { id, room, user_id } => Event::Read(pb::Read {
+                id,
+                room,
+                user_id: user_id as u64,
+            }),
+            ServerEvent::Topic {
+                room,
+                topic,
+                description,
+                set_by,
+            } => Event::Topic(pb::Topic {
+                room,
+                topic,
+                description,
+                set_by: set_by as u64,
+            }),
+            ServerEvent::ReadOnly {
+                room,
+                read_only,
+                set_by,
+            } => Event::ReadOnly(pb::ReadOnly {
+                room,
+                read_only,
+                set_by: set_by as u64,
+            }),
+            ServerEvent::Kick {
+                room,
+                user_id,
+                kicked_by,
+            } => Event::Kick(pb::Kick {
+                room,
+                user_id: user_id as u64,
+                kicked_by: kicked_by as u64,
+            }),
+            ServerEvent::FloodKick {
+                room,
+                user_id,
+                max_messages,
+                window_secs,
+            } => Event::FloodKick(pb::FloodKick {
+                room,
+                user_id: user_id as u64,
+                max_messages: max_messages as u64,
+                window_secs,
+            }),
+            ServerEvent::Sanction {
+                room,
+                user_id,
+                kind,
+                issued_by,
+                reason,
+                expires_at,
+            } => Event::Sanction(pb::Sanction {
+                room,
+                user_id: user_id as u64,
+                kind: proto::SanctionKind::from(kind).into(),
+                issued_by: issued_by as u64,
+                reason,
+                expires_at,
+            }),
+            ServerEvent::SanctionLifted {
+                room,
+                user_id,
+                kind,
+                lifted_by,
+            } => Event::SanctionLifted(pb::SanctionLifted {
+                room,
+                user_id: user_id as u64,
+                kind: proto::SanctionKind::from(kind).into(),
+                lifted_by: lifted_by as u64,
+            }),
+            ServerEvent::Join {
+                room,
+                user_id,
+                nick,
+                avatar_url,
+                bio,
+            } => Event::Join(pb::Join {
+                room,
+                user_id: user_id as u64,
+                nick,
+                avatar_url,
+                bio,
+            }),
+            ServerEvent::Leave {
+                room,
+                user_id,
+                nick,
+            } => Event::Leave(pb::Leave {
+                room,
+                user_id: user_id as u64,
+                nick,
+            }),
+            ServerEvent::Presence { room, users } => Event::Presence(pb::Presence {
+                room,
+                users: users
+                    .into_iter()
+                    .map(|user| proto::OnlineUser {
+                        user_id: user.user_id as u64,
+                        nick: user.nick,
+                        status: proto::Availability::from(user.status).into(),
+                    })
+                    .collect(),
+            }),
+            ServerEvent::Status {
+                room,
+                user_id,
+                status,
+            } => Event::Status(pb::Status {
+                room,
+                user_id: user_id as u64,
+                status: proto::Availability::from(status).into(),
+            }),
+            ServerEvent::Rename {
+                room,
+                user_id,
+                old_nick,
+                nick,
+            } => Event::Rename(pb::Rename {
+                room,
+                user_id: user_id as u64,
+                old_nick,
+                nick,
+            }),
+            ServerEvent::Ack {
+                id,
+                room,
+                client_id,
+            } => Event::Ack(pb::Ack {
+                id,
+                room,
+                client_id,
+            }),
+            ServerEvent::JoinPending { room } => Event::JoinPending(pb::JoinPending { room }),
+            ServerEvent::JoinRequest {
+                room,
+                user_id,
+                nick,
+            } => Event::JoinRequest(pb::JoinRequest {
+                room,
+                user_id: user_id as u64,
+                nick,
+            }),
+            ServerEvent::RoomFull { room, capacity } => Event::RoomFull(pb::RoomFull {
+                room,
+                capacity: capacity as u64,
+            }),
+            ServerEvent::Left { room, code, reason } => Event::Left(pb::Left {
+                room,
+                code: u32::from(code),
+                reason,
+            }),
+            ServerEvent::Error { room, reason } => Event::Error(pb::Error { room, reason }),
+        };
+
+        proto::ServerEvent { event: Some(event) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_plain_text() {
+        let frame = ClientFrame::parse("Hello there").unwrap();
+
+        assert_eq!(
+            frame,
+            ClientFrame::Message {
+                text: String::from("Hello there"),
+                client_id: None,
+                ttl_secs: None
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_json_frame() {
+        let frame =
+            ClientFrame::parse(r#"{"type":"message","text":"Hi","client_id":"a1"}"#).unwrap();
+
+        assert_eq!(
+            frame,
+            ClientFrame::Message {
+                text: String::from("Hi"),
+                client_id: Some(String::from("a1")),
+                ttl_secs: None
+            }
+        );
+
+        let frame =
+            ClientFrame::parse(r#"{"type":"message","text":"Psst","ttl_secs":30}"#).unwrap();
+
+        assert_eq!(
+            frame,
+            ClientFrame::Message {
+                text: String::from("Psst"),
+                client_id: None,
+                ttl_secs: Some(30)
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_forward_frame() {
+        let frame = ClientFrame::parse(r#"{"type":"forward","id":3,"to":"rust"}"#).unwrap();
+
+        assert_eq!(
+            frame,
+            ClientFrame::Forward {
+                id: 3,
+                to: String::from("rust")
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_poll_frames() {
+        let frame = ClientFrame::parse(
+            r#"{"type":"poll","question":"Lunch?","options":["Pizza","Sushi"]}"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            frame,
+            ClientFrame::Poll {
+                question: String::from("Lunch?"),
+                options: vec![String::from("Pizza"), String::from("Sushi")],
+                client_id: None
+            }
+        );
+
+        let frame = ClientFrame::parse(r#"{"type":"vote","id":3,"option":1}"#).unwrap();
+
+        assert_eq!(frame, ClientFrame::Vote { id: 3, option: 1 });
+    }
+
+    #[test]
+    fn test_parse_edit_frame() {
+        let frame = ClientFrame::parse(r#"{"type":"edit","id":3,"text":"Fixed"}"#).unwrap();
+
+        assert_eq!(
+            frame,
+            ClientFrame::Edit {
+                id: 3,
+                text: String::from("Fixed")
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_heartbeat_frame() {
+        let frame = ClientFrame::parse(r#"{"type":"heartbeat"}"#).unwrap();
+
+        assert_eq!(frame, ClientFrame::Heartbeat);
+    }
+
+    #[test]
+    fn test_parse_set_nick_frame() {
+        let frame = ClientFrame::parse(r#"{"type":"set_nick","nick":"alice"}"#).unwrap();
+
+        assert_eq!(
+            frame,
+            ClientFrame::SetNick {
+                nick: String::from("alice")
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_set_topic_frame() {
+        let frame = ClientFrame::parse(r#"{"type":"set_topic","topic":"Rust"}"#).unwrap();
+
+        assert_eq!(
+            frame,
+            ClientFrame::SetTopic {
+                topic: Some(String::from("Rust")),
+                description: None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_join_frame() {
+        let frame = ClientFrame::parse(r#"{"type":"join","room":"rust"}"#).unwrap();
+
+        assert_eq!(
+            frame,
+            ClientFrame::Join {
+                room: String::from("rust"),
+                password: None,
+                since: None,
+            }
+        );
+
+        let frame = ClientFrame::parse(r#"{"type":"join","room":"rust","since":42}"#).unwrap();
+
+        assert_eq!(
+            frame,
+            ClientFrame::Join {
+                room: String::from("rust"),
+                password: None,
+                since: Some(42),
+            }
+        );
+    }
+
+    #[test]
+    fn test_target_room() {
+        assert_eq!(
+            ClientFrame::target_room(r#"{"type":"message","text":"Hi","room":"rust"}"#),
+            Some(String::from("rust"))
+        );
+        assert_eq!(
+            ClientFrame::target_room(r#"{"type":"message","text":"Hi"}"#),
+            None
+        );
+        assert_eq!(ClientFrame::target_room("Hello there"), None);
+    }
+
+    #[test]
+    fn test_events_read_back() {
+        use proto::server_event::{self as pb, Event};
+
+        // Events are read back from the JSON they are sent as, to be sent over
+        // protobuf
+        let read_back = |event: ServerEvent| -> proto::ServerEvent {
+            serde_json::from_str::<ServerEvent>(&event.to_json())
+                .unwrap()
+                .into()
+        };
+
+        let event = read_back(ServerEvent::Presence {
+            room: String::from("lobby"),
+            users: vec![OnlineUser {
+                user_id: 2,
+                nick: None,
+                status: Availability::Away,
+            }],
+        });
+        assert_eq!(
+            event.event,
+            Some(Event::Presence(pb::Presence {
+                room: String::from("lobby"),
+                users: vec![proto::OnlineUser {
+                    user_id: 2,
+                    nick: None,
+                    status: proto::Availability::Away.into(),
+                }],
+            }))
+        );
+
+        let event = read_back(ServerEvent::Topic {
+            room: String::from("lobby"),
+            topic: None,
+            description: Some(String::from("Chatting")),
+            set_by: 1,
+        });
+        assert_eq!(
+            event.event,
+            Some(Event::Topic(pb::Topic {
+                room: String::from("lobby"),
+                topic: None,
+                description: Some(String::from("Chatting")),
+                set_by: 1,
+            }))
+        );
+
+        let event = read_back(ServerEvent::Sanction {
+            room: String::from("lobby"),
+            user_id: 3,
+            kind: SanctionKind::Mute,
+            issued_by: 1,
+            reason: None,
+            expires_at: None,
+        });
+        match event.event {
+            Some(Event::Sanction(sanction)) => {
+                assert_eq!(sanction.kind(), proto::SanctionKind::Mute);
+            }
+            event => panic!("Unexpected event: {:?}", event),
+        }
+    }
+
+    #[test]
+    fn test_parse_invalid_json_frame() {
+        assert!(ClientFrame::parse(r#"{"type":"unknown"}"#).is_err());
+    }
+}
@@ -0,0 +1,119 @@
+use std::{
+    path::Path,
+    sync::{mpsc, Arc, Mutex},
+    thread,
+};
+
+use rusqlite::{Connection, OpenFlags};
+
+use crate::db::{self, DbQuery, Pragmas};
+
+// Read-only connections to the DB, each on a thread of its own, which reads
+// such as history are run on without holding up the DB thread. They see what
+// the DB thread has committed.
+pub struct ReadPool {
+    // Taken on drop, stopping the threads
+    queries_tx: Option<mpsc::Sender<DbQuery>>,
+    threads: Vec<thread::JoinHandle<()>>,
+}
+
+impl ReadPool {
+    // Opens `size` read-only connections to the existing DB at `db_path`.
+    pub fn open(db_path: &Path, pragmas: &Pragmas, size: usize) -> Result<Self, rusqlite::Error> {
+        let (queries_tx, queries_rx) = mpsc::channel::<DbQuery>();
+        let queries_rx = Arc::new(Mutex::new(queries_rx));
+
+        let mut threads = Vec::with_capacity(size);
+        for _ in 0..size {
+            let conn = Connection::open_with_flags(
+                db_path,
+                OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+            )?;
+            if let Some(key) = &pragmas.key {
+                db::unlock(&conn, key)?;
+            }
+            conn.busy_timeout(pragmas.busy_timeout)?;
+            conn.pragma_update(None, "cache_size", -(pragmas.cache_size_kib as i64))?;
+
+            let queries_rx = queries_rx.clone();
+            threads.push(thread::spawn(move || loop {
+                // The lock is only held while waiting for a query
+                let query = queries_rx.lock().unwrap().recv();
+                match query {
+                    Ok(query) => query(&conn),
+                    Err(_) => break,
+                }
+            }));
+        }
+
+        Ok(ReadPool {
+            queries_tx: Some(queries_tx),
+            threads,
+        })
+    }
+
+    // Runs `query` on the first connection free.
+    pub fn run(&self, query: DbQuery) {
+        if let Some(queries_tx) = &self.queries_tx {
+            // Connections only go away along with the pool
+            let _ = queries_tx.send(query);
+        }
+    }
+}
+
+impl Drop for ReadPool {
+    // Closes the connections, once done with the queries they were given.
+    fn drop(&mut self) {
+        self.queries_tx.take();
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        db::DBMessage,
+        store::{MessageStore, SqliteStore},
+    };
+
+    #[test]
+    fn test_read_pool() {
+        let db_path = Path::new("./test_read_pool.db");
+        let mut store = SqliteStore::open(db_path, &Pragmas::default()).unwrap();
+        store
+            .insert(&DBMessage::new(1, "room1", "Hello there"))
+            .unwrap();
+
+        let pool = ReadPool::open(db_path, &Pragmas::default(), 2).unwrap();
+        let count = || {
+            let (count_tx, count_rx) = mpsc::channel();
+            pool.run(Box::new(move |conn: &Connection| {
+                let count: i64 = conn
+                    .query_row("SELECT COUNT(*) FROM chat_messages", [], |row| row.get(0))
+                    .unwrap();
+                count_tx.send(count).unwrap();
+            }));
+            count_rx.recv().unwrap()
+        };
+
+        // Only committed writes are seen
+        assert_eq!(count(), 0);
+        store.commit().unwrap();
+        assert_eq!(count(), 1);
+
+        // Nothing may be written through the pool
+        let (result_tx, result_rx) = mpsc::channel();
+        pool.run(Box::new(move |conn: &Connection| {
+            let result = conn.execute("DELETE FROM chat_messages", []);
+            result_tx.send(result.is_err()).unwrap();
+        }));
+        assert!(result_rx.recv().unwrap());
+
+        drop(pool);
+        store.close().unwrap();
+        std::fs::remove_file(db_path).unwrap();
+    }
+}
@@ -0,0 +1,281 @@
+use std::{fmt, str::FromStr};
+
+use anyhow::anyhow;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::{
+    compression::MessageText,
+    moderation::{NewSanction, MAX_REASON_LENGTH},
+};
+
+// Where a report stands in the moderation queue.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReportStatus {
+    // Waiting for a moderator to review it
+    Open,
+    // Reviewed, without anything being done
+    Dismissed,
+    // Reviewed, and the message deleted or its author banned
+    Actioned,
+}
+
+impl FromStr for ReportStatus {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "open" => Ok(ReportStatus::Open),
+            "dismissed" => Ok(ReportStatus::Dismissed),
+            "actioned" => Ok(ReportStatus::Actioned),
+            _ => Err(anyhow!(
+                "Unknown report status '{}': expected open, dismissed or actioned",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ReportStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let status = match self {
+            ReportStatus::Open => "open",
+            ReportStatus::Dismissed => "dismissed",
+            ReportStatus::Actioned => "actioned",
+        };
+        f.write_str(status)
+    }
+}
+
+// Request body of the route reporting a message.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct NewReport {
+    pub message_id: i64,
+    #[serde(default)]
+    pub reason: Option<String>,
+}
+
+impl NewReport {
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        if self
+            .reason
+            .as_ref()
+            .is_some_and(|reason| reason.chars().count() > MAX_REASON_LENGTH)
+        {
+            return Err(anyhow!(
+                "Reason must be at most {} characters long",
+                MAX_REASON_LENGTH
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+// What a moderator does about a reported message.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ReportAction {
+    // Leaves the message be
+    Dismiss,
+    // Deletes the message
+    Delete,
+    // Bans the author of the message from the room
+    Ban,
+}
+
+// Request body of the route resolving a report. Bans may be given a `reason`
+// and `duration_secs`, as when banning users directly.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct ReportResolution {
+    pub action: ReportAction,
+    #[serde(flatten)]
+    pub ban: NewSanction,
+}
+
+// A report of a message, along with the message.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Report {
+    pub id: i64,
+    pub room: String,
+    pub message_id: i64,
+    // Author of the message, and what it said when reported
+    pub author_id: usize,
+    pub text: String,
+    pub reported_by: usize,
+    pub reason: Option<String>,
+    pub created_at: String,
+    pub status: ReportStatus,
+    pub resolved_by: Option<usize>,
+    pub resolved_at: Option<String>,
+}
+
+// Reports message `message_id` of `room_name` on behalf of `reported_by`.
+// Reporting a message again returns the first report. Returns the report,
+// unless the message does not exist or was deleted.
+pub fn file_report(
+    conn: &Connection,
+    room_name: &str,
+    reported_by: usize,
+    new_report: &NewReport,
+) -> Result<Option<Report>, rusqlite::Error> {
+    conn.execute(
+        "INSERT OR IGNORE INTO message_reports (message_id, room_name, reported_by, reason)
+            SELECT message_id, room_name, ?3, ?4 FROM chat_messages
+            WHERE message_id = ?1 AND room_name = ?2 AND deleted_at IS NULL",
+        params![
+            new_report.message_id,
+            room_name,
+            reported_by,
+            new_report.reason
+        ],
+    )?;
+
+    conn.query_row(
+        &format!(
+            "SELECT {} FROM message_reports r
+                JOIN chat_messages m ON m.message_id = r.message_id
+                WHERE r.message_id = ?1 AND r.room_name = ?2 AND r.reported_by = ?3",
+            REPORT_COLUMNS
+        ),
+        params![new_report.message_id, room_name, reported_by],
+        report_from_row,
+    )
+    .optional()
+}
+
+// Report `report_id` of `room_name`, if any.
+pub fn report(
+    conn: &Connection,
+    room_name: &str,
+    report_id: i64,
+) -> Result<Option<Report>, rusqlite::Error> {
+    conn.query_row(
+        &format!(
+            "SELECT {} FROM message_reports r
+                JOIN chat_messages m ON m.message_id = r.message_id
+                WHERE r.report_id = ?1 AND r.room_name = ?2",
+            REPORT_COLUMNS
+        ),
+        params![report_id, room_name],
+        report_from_row,
+    )
+    .optional()
+}
+
+// Open reports of `room_name`, oldest first: the moderation queue.
+pub fn open_reports(conn: &Connection, room_name: &str) -> Result<Vec<Report>, rusqlite::Error> {
+    let mut stmt = conn.prepare_cached(&format!(
+        "SELECT {} FROM message_reports r
+            JOIN chat_messages m ON m.message_id = r.message_id
+            WHERE r.room_name = ?1 AND r.status = 'open'
+            ORDER BY r.report_id",
+        REPORT_COLUMNS
+    ))?;
+    let reports = stmt
+        .query_map(params![room_name], report_from_row)?
+        .collect();
+
+    reports
+}
+
+// Closes every open report of message `message_id` with `status`, on behalf of
+// `resolved_by`. Returns how many were closed.
+pub fn resolve(
+    conn: &Connection,
+    message_id: i64,
+    status: ReportStatus,
+    resolved_by: usize,
+) -> Result<usize, rusqlite::Error> {
+    conn.execute(
+        "UPDATE message_reports
+            SET status = ?1, resolved_by = ?2, resolved_at = CURRENT_TIMESTAMP
+            WHERE message_id = ?3 AND status = 'open'",
+        params![status.to_string(), resolved_by, message_id],
+    )
+}
+
+const REPORT_COLUMNS: &str = "r.report_id, r.room_name, r.message_id, m.user_id, m.message,
+    r.reported_by, r.reason, r.created_at, r.status, r.resolved_by, r.resolved_at";
+
+fn report_from_row(row: &rusqlite::Row) -> Result<Report, rusqlite::Error> {
+    Ok(Report {
+        id: row.get(0)?,
+        room: row.get(1)?,
+        message_id: row.get(2)?,
+        author_id: row.get(3)?,
+        text: row.get::<_, MessageText>(4)?.into(),
+        reported_by: row.get(5)?,
+        reason: row.get(6)?,
+        created_at: row.get(7)?,
+        // Unknown statuses are taken as open, so that they are reviewed again
+        status: row
+            .get::<_, String>(8)?
+            .parse()
+            .unwrap_or(ReportStatus::Open),
+        resolved_by: row.get(9)?,
+        resolved_at: row.get(10)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+
+    #[test]
+    fn test_reports() {
+        let conn = Connection::open_in_memory().unwrap();
+        db::init_schema(&conn).unwrap();
+
+        conn.execute(
+            "INSERT INTO chat_messages (message_id, user_id, room_name, message) VALUES
+                (1, 1, 'room1', 'spam'), (2, 1, 'room1', 'fine'), (3, 1, 'room2', 'other')",
+            [],
+        )
+        .unwrap();
+
+        let new_report = |message_id| NewReport {
+            message_id,
+            reason: Some(String::from("Spam")),
+        };
+        let filed = file_report(&conn, "room1", 2, &new_report(1))
+            .unwrap()
+            .unwrap();
+        assert_eq!(filed.author_id, 1);
+        assert_eq!(filed.text, "spam");
+        assert_eq!(filed.status, ReportStatus::Open);
+        let report_id = filed.id;
+
+        // Reporting again changes nothing, and only messages of the room can be
+        assert_eq!(
+            file_report(&conn, "room1", 2, &new_report(1)).unwrap(),
+            Some(filed)
+        );
+        assert_eq!(
+            file_report(&conn, "room1", 2, &new_report(3)).unwrap(),
+            None
+        );
+        assert_eq!(
+            file_report(&conn, "room1", 2, &new_report(9)).unwrap(),
+            None
+        );
+
+        file_report(&conn, "room1", 3, &new_report(1)).unwrap();
+        file_report(&conn, "room1", 3, &new_report(2)).unwrap();
+        assert_eq!(open_reports(&conn, "room1").unwrap().len(), 3);
+
+        // Resolving a report closes every other report of the same message
+        assert_eq!(resolve(&conn, 1, ReportStatus::Actioned, 4).unwrap(), 2);
+        let open = open_reports(&conn, "room1").unwrap();
+        assert_eq!(open.len(), 1);
+        assert_eq!(open[0].message_id, 2);
+
+        let resolved = report(&conn, "room1", report_id).unwrap().unwrap();
+        assert_eq!(resolved.status, ReportStatus::Actioned);
+        assert_eq!(resolved.resolved_by, Some(4));
+        assert_eq!(report(&conn, "room2", report_id).unwrap(), None);
+    }
+}
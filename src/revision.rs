@@ -0,0 +1,145 @@
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::Serialize;
+
+use crate::compression::MessageText;
+
+// A version of a message, as listed in its edit history.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Revision {
+    pub text: String,
+    // When this version was written, by sending or editing the message
+    pub created_at: String,
+    // When an edit replaced it, unless it is the current version
+    pub replaced_at: Option<String>,
+}
+
+// Every version of a message, oldest first, ending with the current one.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct MessageHistory {
+    pub id: i64,
+    pub room: String,
+    pub user_id: usize,
+    pub deleted_at: Option<String>,
+    pub revisions: Vec<Revision>,
+}
+
+// Keeps the current version of message `message_id`, provided it was sent to
+// `room_name` by `user_id` and has not been deleted, before an edit replaces
+// it.
+// Returns whether a revision was kept.
+pub fn record(
+    conn: &Connection,
+    message_id: i64,
+    user_id: usize,
+    room_name: &str,
+) -> Result<bool, rusqlite::Error> {
+    let inserted = conn.execute(
+        "INSERT INTO message_revisions (message_id, message, created_at)
+            SELECT message_id, message, COALESCE(edited_at, created_at) FROM chat_messages
+            WHERE message_id = ?1 AND user_id = ?2 AND room_name = ?3 AND deleted_at IS NULL",
+        params![message_id, user_id, room_name],
+    )?;
+
+    Ok(inserted > 0)
+}
+
+// The edit history of message `message_id`, deleted or not, if there is such
+// a message.
+pub fn history(
+    conn: &Connection,
+    message_id: i64,
+) -> Result<Option<MessageHistory>, rusqlite::Error> {
+    let message = conn
+        .query_row(
+            "SELECT room_name, user_id, message, COALESCE(edited_at, created_at), deleted_at
+                FROM chat_messages WHERE message_id = ?1",
+            params![message_id],
+            |row| {
+                let current = Revision {
+                    text: row.get::<_, MessageText>(2)?.into(),
+                    created_at: row.get(3)?,
+                    replaced_at: None,
+                };
+                Ok((row.get(0)?, row.get(1)?, current, row.get(4)?))
+            },
+        )
+        .optional()?;
+    let (room, user_id, current, deleted_at) = match message {
+        Some(message) => message,
+        None => return Ok(None),
+    };
+
+    let mut stmt = conn.prepare(
+        "SELECT message, created_at, replaced_at FROM message_revisions
+            WHERE message_id = ?1 ORDER BY revision_id",
+    )?;
+    let mut revisions = stmt
+        .query_map(params![message_id], |row| {
+            Ok(Revision {
+                text: row.get::<_, MessageText>(0)?.into(),
+                created_at: row.get(1)?,
+                replaced_at: row.get(2)?,
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    revisions.push(current);
+
+    Ok(Some(MessageHistory {
+        id: message_id,
+        room,
+        user_id,
+        deleted_at,
+        revisions,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{self, init_schema};
+
+    #[test]
+    fn test_history() {
+        let conn = Connection::open_in_memory().unwrap();
+        init_schema(&conn).unwrap();
+
+        conn.execute(
+            "INSERT INTO chat_messages (message_id, user_id, room_name, message) VALUES (1, 1, 'room1', 'helo')",
+            [],
+        )
+        .unwrap();
+        assert_eq!(history(&conn, 2).unwrap(), None);
+
+        let unedited = history(&conn, 1).unwrap().unwrap();
+        assert_eq!(unedited.room, "room1");
+        assert_eq!(unedited.revisions.len(), 1);
+        assert_eq!(unedited.revisions[0].text, "helo");
+
+        // Edits refused leave no revision behind
+        assert!(!db::edit_message(&conn, 1, 2, "room1", "hijacked", false).unwrap());
+        assert!(db::edit_message(&conn, 1, 1, "room1", "hello", false).unwrap());
+        assert!(db::edit_message(&conn, 1, 1, "room1", "hello!", false).unwrap());
+
+        let edited = history(&conn, 1).unwrap().unwrap();
+        let texts: Vec<_> = edited.revisions.iter().map(|r| r.text.as_str()).collect();
+        assert_eq!(texts, vec!["helo", "hello", "hello!"]);
+        assert!(edited.revisions[..2]
+            .iter()
+            .all(|r| r.replaced_at.is_some()));
+        assert_eq!(edited.revisions[2].replaced_at, None);
+
+        // Deleted messages keep their history, until they are gone for good
+        assert!(db::delete_message(&conn, 1, "room1", 2, None).unwrap());
+        let deleted = history(&conn, 1).unwrap().unwrap();
+        assert!(deleted.deleted_at.is_some());
+        assert_eq!(deleted.revisions.len(), 3);
+
+        conn.execute("DELETE FROM chat_messages", []).unwrap();
+        let revisions: i64 = conn
+            .query_row("SELECT COUNT(*) FROM message_revisions", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(revisions, 0);
+    }
+}
@@ -0,0 +1,1325 @@
+use std::{fmt, str::FromStr};
+
+use anyhow::anyhow;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::{auth, conversation};
+
+pub const MAX_ROOM_NAME_LENGTH: usize = 64;
+pub const MAX_TOPIC_LENGTH: usize = 256;
+pub const MAX_DESCRIPTION_LENGTH: usize = 2048;
+
+// Who may join a room.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Visibility {
+    // Anyone not kept out by its access control list
+    #[default]
+    Public,
+    // Only its admins, and users on its allow list
+    Private,
+}
+
+impl FromStr for Visibility {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "public" => Ok(Visibility::Public),
+            "private" => Ok(Visibility::Private),
+            _ => Err(anyhow!(
+                "Unknown visibility '{}': expected public or private",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Visibility {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let visibility = match self {
+            Visibility::Public => "public",
+            Visibility::Private => "private",
+        };
+        f.write_str(visibility)
+    }
+}
+
+// What becomes of rooms left idle for too long.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IdleRoomAction {
+    // The room is made read-only, keeping it and its history
+    Archive,
+    // The room is deleted, keeping its history
+    Delete,
+    // The room is deleted along with its history
+    Purge,
+}
+
+impl FromStr for IdleRoomAction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "archive" => Ok(IdleRoomAction::Archive),
+            "delete" => Ok(IdleRoomAction::Delete),
+            "purge" => Ok(IdleRoomAction::Purge),
+            _ => Err(anyhow!(
+                "Unknown idle room action '{}': expected archive, delete or purge",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for IdleRoomAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let action = match self {
+            IdleRoomAction::Archive => "archive",
+            IdleRoomAction::Delete => "delete",
+            IdleRoomAction::Purge => "purge",
+        };
+        f.write_str(action)
+    }
+}
+
+// How a room behaves, as set when created. Rooms created by joining them have
+// the default settings.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize, ToSchema)]
+pub struct RoomSettings {
+    // What the room is about, shown when listing rooms
+    #[serde(default)]
+    pub topic: Option<String>,
+    // Longer explanation of what the room is for
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub visibility: Visibility,
+    // Most connections the room holds at once, if limited
+    #[serde(default)]
+    pub capacity: Option<usize>,
+    // Number of seconds messages are kept for, if not forever
+    #[serde(default)]
+    pub retention_secs: Option<u64>,
+    // Number of most recent messages kept, if not all of them
+    #[serde(default)]
+    pub retention_messages: Option<u64>,
+    // Whether joining requires the password of the room. Set by giving one
+    // when creating the room
+    #[serde(default, skip_deserializing)]
+    pub password_protected: bool,
+    // Whether only moderators may post in the room, e.g. for announcements or
+    // archived discussions. Everyone else may still join and read it
+    #[serde(default)]
+    pub read_only: bool,
+    // Whether users joining the room wait for a moderator to approve them,
+    // unless they are members of it or moderators themselves
+    #[serde(default)]
+    pub approval_required: bool,
+    // Whether messages posted in the room are cross-posted to the rooms
+    // following it
+    #[serde(default)]
+    pub announcement: bool,
+}
+
+// Request body of the route creating a room.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct NewRoom {
+    pub name: String,
+    #[serde(flatten)]
+    pub settings: RoomSettings,
+    // Password users must give to join the room, if any
+    #[serde(default)]
+    pub password: Option<String>,
+}
+
+impl NewRoom {
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        validate_name(&self.name)?;
+
+        if conversation::is_conversation(&self.name) {
+            return Err(anyhow!(
+                "Room names starting with '{}' are kept for conversations",
+                conversation::CONVERSATION_PREFIX
+            ));
+        }
+
+        validate_topic(&self.settings.topic, &self.settings.description)?;
+
+        if self.settings.capacity == Some(0) {
+            return Err(anyhow!("Room capacity must be at least 1"));
+        }
+
+        validate_retention(
+            self.settings.retention_secs,
+            self.settings.retention_messages,
+        )?;
+
+        if self.password.as_deref() == Some("") {
+            return Err(anyhow!("Room password must not be empty"));
+        }
+
+        Ok(())
+    }
+}
+
+// Checks that `room_name` may name a room: room names are part of the paths of
+// room routes.
+pub fn validate_name(room_name: &str) -> Result<(), anyhow::Error> {
+    if room_name.is_empty()
+        || room_name.len() > MAX_ROOM_NAME_LENGTH
+        || room_name.contains(|c: char| c == '/' || c.is_whitespace() || c.is_control())
+    {
+        return Err(anyhow!(
+            "Room name must be between 1 and {} characters long, without slashes or whitespace",
+            MAX_ROOM_NAME_LENGTH
+        ));
+    }
+
+    Ok(())
+}
+
+// Request body of the route setting the topic of a room, replacing both its
+// topic and description. Either is cleared if left out.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct TopicUpdate {
+    #[serde(default)]
+    pub topic: Option<String>,
+    #[serde(default)]
+    pub description: Option<String>,
+}
+
+impl TopicUpdate {
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        validate_topic(&self.topic, &self.description)
+    }
+}
+
+fn validate_retention(
+    retention_secs: Option<u64>,
+    retention_messages: Option<u64>,
+) -> Result<(), anyhow::Error> {
+    if retention_secs == Some(0) {
+        return Err(anyhow!("Retention must be at least 1 second"));
+    }
+
+    if retention_messages == Some(0) {
+        return Err(anyhow!("Retention must be at least 1 message"));
+    }
+
+    Ok(())
+}
+
+// Request body of the route setting how long the messages of a room are kept,
+// replacing both limits. Either is lifted if left out.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct RetentionUpdate {
+    #[serde(default)]
+    pub retention_secs: Option<u64>,
+    #[serde(default)]
+    pub retention_messages: Option<u64>,
+}
+
+impl RetentionUpdate {
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        validate_retention(self.retention_secs, self.retention_messages)
+    }
+}
+
+// Request body of the route making a room read-only, or writable again.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct ReadOnlyUpdate {
+    pub read_only: bool,
+}
+
+fn validate_topic(
+    topic: &Option<String>,
+    description: &Option<String>,
+) -> Result<(), anyhow::Error> {
+    if topic.as_ref().map_or(0, |topic| topic.chars().count()) > MAX_TOPIC_LENGTH {
+        return Err(anyhow!(
+            "Topic must be at most {} characters long",
+            MAX_TOPIC_LENGTH
+        ));
+    }
+
+    if description
+        .as_ref()
+        .map_or(0, |description| description.chars().count())
+        > MAX_DESCRIPTION_LENGTH
+    {
+        return Err(anyhow!(
+            "Description must be at most {} characters long",
+            MAX_DESCRIPTION_LENGTH
+        ));
+    }
+
+    Ok(())
+}
+
+// A room, as persisted once created or first joined.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct RoomInfo {
+    pub name: String,
+    // Unknown for rooms created before their creators were recorded
+    pub created_by: Option<usize>,
+    pub created_at: String,
+    // Users holding the room, oldest owner first
+    pub owners: Vec<usize>,
+    #[serde(flatten)]
+    pub settings: RoomSettings,
+}
+
+// A room anyone may join, as listed to find rooms.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct RoomListing {
+    pub name: String,
+    pub topic: Option<String>,
+    // Number of connections to the room
+    pub occupancy: usize,
+    // Number of messages in the room, not counting deleted ones
+    pub message_count: usize,
+    pub password_protected: bool,
+}
+
+// Creates room `room_name` with `settings`, owned by `user_id`, and protected
+// by the password hashed as `password_hash` if given. Returns whether it was
+// created, i.e. did not exist yet.
+pub fn create_room(
+    conn: &Connection,
+    room_name: &str,
+    user_id: usize,
+    settings: &RoomSettings,
+    password_hash: Option<&str>,
+) -> Result<bool, rusqlite::Error> {
+    let created = conn.execute(
+        "INSERT OR IGNORE INTO rooms (room_name, created_by, topic, description, visibility,
+                capacity, retention_secs, retention_messages, password_hash, read_only,
+                approval_required, announcement)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+        params![
+            room_name,
+            user_id,
+            settings.topic,
+            settings.description,
+            settings.visibility.to_string(),
+            settings.capacity,
+            settings.retention_secs,
+            settings.retention_messages,
+            password_hash,
+            settings.read_only,
+            settings.approval_required,
+            settings.announcement
+        ],
+    )?;
+
+    if created > 0 {
+        add_owner(conn, room_name, user_id)?;
+    }
+
+    Ok(created > 0)
+}
+
+// Request body of the routes adding an owner to a room, and transferring it.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct OwnerUpdate {
+    pub user_id: usize,
+}
+
+// A user invited into a room.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct RoomMember {
+    pub user_id: usize,
+    // Unknown if the user who invited them has since been deleted
+    pub invited_by: Option<usize>,
+    pub added_at: String,
+}
+
+// Request body of the route inviting a user into a room.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct MemberInvite {
+    pub user_id: usize,
+}
+
+// Records `room_name` as created by `user_id`, unless it already exists.
+// Unless `owned` is unset (i.e. for guests), its creator is made its owner.
+pub fn record_room(
+    conn: &Connection,
+    room_name: &str,
+    user_id: usize,
+    owned: bool,
+) -> Result<(), rusqlite::Error> {
+    let created = conn.execute(
+        "INSERT OR IGNORE INTO rooms (room_name, created_by) VALUES (?1, ?2)",
+        params![room_name, user_id],
+    )?;
+
+    if created > 0 && owned {
+        add_owner(conn, room_name, user_id)?;
+    }
+
+    Ok(())
+}
+
+// The room `room_name`, if it was ever created or joined.
+pub fn room_info(conn: &Connection, room_name: &str) -> Result<Option<RoomInfo>, rusqlite::Error> {
+    let room = conn
+        .query_row(
+            "SELECT room_name, created_by, created_at FROM rooms WHERE room_name = ?1",
+            params![room_name],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )
+        .optional()?;
+
+    let (name, created_by, created_at) = match room {
+        Some(room) => room,
+        None => return Ok(None),
+    };
+
+    Ok(Some(RoomInfo {
+        name,
+        created_by,
+        created_at,
+        owners: owners(conn, room_name)?,
+        settings: settings(conn, room_name)?.unwrap_or_default(),
+    }))
+}
+
+// Settings of room `room_name`, if it exists.
+pub fn settings(
+    conn: &Connection,
+    room_name: &str,
+) -> Result<Option<RoomSettings>, rusqlite::Error> {
+    conn.query_row(
+        "SELECT topic, description, visibility, capacity, retention_secs, retention_messages,
+                password_hash IS NOT NULL, read_only, approval_required, announcement
+            FROM rooms WHERE room_name = ?1",
+        params![room_name],
+        |row| {
+            Ok(RoomSettings {
+                topic: row.get(0)?,
+                description: row.get(1)?,
+                // Unknown visibilities are taken as private, which is safer
+                visibility: row
+                    .get::<_, String>(2)?
+                    .parse()
+                    .unwrap_or(Visibility::Private),
+                capacity: row.get(3)?,
+                retention_secs: row.get(4)?,
+                retention_messages: row.get(5)?,
+                password_protected: row.get(6)?,
+                read_only: row.get(7)?,
+                approval_required: row.get(8)?,
+                announcement: row.get(9)?,
+            })
+        },
+    )
+    .optional()
+}
+
+// Hash of the password protecting `room_name`, if it exists and has one.
+pub fn password_hash(
+    conn: &Connection,
+    room_name: &str,
+) -> Result<Option<String>, rusqlite::Error> {
+    let hash = conn
+        .query_row(
+            "SELECT password_hash FROM rooms WHERE room_name = ?1",
+            params![room_name],
+            |row| row.get(0),
+        )
+        .optional()?;
+
+    Ok(hash.flatten())
+}
+
+// Replaces the topic and description of `room_name`. Returns whether the room
+// exists.
+pub fn set_topic(
+    conn: &Connection,
+    room_name: &str,
+    update: &TopicUpdate,
+) -> Result<bool, rusqlite::Error> {
+    let updated = conn.execute(
+        "UPDATE rooms SET topic = ?2, description = ?3 WHERE room_name = ?1",
+        params![room_name, update.topic, update.description],
+    )?;
+
+    Ok(updated > 0)
+}
+
+// Sets how long the messages of `room_name` are kept. Returns whether the room
+// exists.
+pub fn set_retention(
+    conn: &Connection,
+    room_name: &str,
+    update: &RetentionUpdate,
+) -> Result<bool, rusqlite::Error> {
+    let updated = conn.execute(
+        "UPDATE rooms SET retention_secs = ?2, retention_messages = ?3 WHERE room_name = ?1",
+        params![room_name, update.retention_secs, update.retention_messages],
+    )?;
+
+    Ok(updated > 0)
+}
+
+// Makes `room_name` read-only, or lets everyone post in it again. Returns
+// whether the room exists.
+pub fn set_read_only(
+    conn: &Connection,
+    room_name: &str,
+    read_only: bool,
+) -> Result<bool, rusqlite::Error> {
+    let updated = conn.execute(
+        "UPDATE rooms SET read_only = ?2 WHERE room_name = ?1",
+        params![room_name, read_only],
+    )?;
+
+    Ok(updated > 0)
+}
+
+// Whether only moderators may post in `room_name`.
+pub fn is_read_only(conn: &Connection, room_name: &str) -> Result<bool, rusqlite::Error> {
+    let read_only = conn
+        .query_row(
+            "SELECT read_only FROM rooms WHERE room_name = ?1",
+            params![room_name],
+            |row| row.get(0),
+        )
+        .optional()?;
+
+    Ok(read_only.unwrap_or(false))
+}
+
+// Public rooms, by name. Their occupancy is left to be filled in from the
+// connected users.
+pub fn public_rooms(conn: &Connection) -> Result<Vec<RoomListing>, rusqlite::Error> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT r.room_name, r.topic, COUNT(m.message_id), r.password_hash IS NOT NULL
+            FROM rooms r
+            LEFT JOIN chat_messages m ON m.room_name = r.room_name AND m.deleted_at IS NULL
+            WHERE r.visibility = 'public'
+            GROUP BY r.room_name
+            ORDER BY r.room_name",
+    )?;
+    let rooms = stmt
+        .query_map([], |row| {
+            Ok(RoomListing {
+                name: row.get(0)?,
+                topic: row.get(1)?,
+                occupancy: 0,
+                message_count: row.get(2)?,
+                password_protected: row.get(3)?,
+            })
+        })?
+        .collect();
+
+    rooms
+}
+
+// Number of messages deleted for being past the retention of their room, or
+// deleted by users for longer than they are kept.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PurgeReport {
+    // Kept for longer than the room keeps them
+    pub by_age: usize,
+    // Older than the most recent ones the room keeps, and not too old
+    pub by_count: usize,
+    // Deleted for longer than deleted messages are kept, and otherwise kept
+    pub deleted: usize,
+}
+
+impl PurgeReport {
+    pub fn total(&self) -> usize {
+        self.by_age + self.by_count + self.deleted
+    }
+}
+
+impl fmt::Display for PurgeReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} messages ({} by age, {} by count, {} deleted)",
+            self.total(),
+            self.by_age,
+            self.by_count,
+            self.deleted
+        )
+    }
+}
+
+// Deletes the messages past the retention of their room: those kept for
+// longer than its retention period, and those older than the most recent
+// messages it keeps. Messages deleted by users, which moderators may still
+// read, are purged once deleted for `deleted_retention_secs` if set. Their
+// pins, reports, mentions, alerts and the events queued about them go along
+// with them. Returns the IDs of the messages purged, along with the report.
+pub fn purge_expired(
+    conn: &Connection,
+    deleted_retention_secs: Option<u64>,
+) -> Result<(PurgeReport, Vec<i64>), rusqlite::Error> {
+    const EXPIRED: &str = "SELECT m.message_id FROM chat_messages m
+        JOIN rooms r ON r.room_name = m.room_name
+        WHERE r.retention_secs IS NOT NULL
+            AND m.created_at <= datetime('now', '-' || r.retention_secs || ' seconds')";
+    const OVERFLOWING: &str = "SELECT message_id FROM (
+            SELECT m.message_id, r.retention_messages AS kept,
+                ROW_NUMBER() OVER (
+                    PARTITION BY m.room_name ORDER BY m.seq DESC, m.message_id DESC
+                ) AS newer
+            FROM chat_messages m
+            JOIN rooms r ON r.room_name = m.room_name
+            WHERE r.retention_messages IS NOT NULL
+        ) WHERE newer > kept";
+    const DELETED: &str = "SELECT message_id FROM chat_messages
+        WHERE deleted_at <= datetime('now', '-' || ?1 || ' seconds')";
+
+    // Messages are picked once, however many tables they are deleted from
+    conn.execute(
+        "CREATE TEMP TABLE IF NOT EXISTS purged_messages (message_id INTEGER PRIMARY KEY)",
+        [],
+    )?;
+    conn.execute("DELETE FROM temp.purged_messages", [])?;
+    let by_age = conn.execute(&format!("INSERT INTO temp.purged_messages {}", EXPIRED), [])?;
+    let by_count = conn.execute(
+        &format!("INSERT OR IGNORE INTO temp.purged_messages {}", OVERFLOWING),
+        [],
+    )?;
+    let deleted = match deleted_retention_secs {
+        Some(secs) => conn.execute(
+            &format!("INSERT OR IGNORE INTO temp.purged_messages {}", DELETED),
+            params![secs],
+        )?,
+        None => 0,
+    };
+    let purged = conn
+        .prepare("SELECT message_id FROM temp.purged_messages ORDER BY message_id")?
+        .query_map([], |row| row.get(0))?
+        .collect::<Result<Vec<i64>, _>>()?;
+
+    for table in &[
+        "room_pins",
+        "message_reports",
+        "mentions",
+        "alerts",
+        "queued_events",
+        "chat_messages",
+    ] {
+        conn.execute(
+            &format!(
+                "DELETE FROM {} WHERE message_id IN (SELECT message_id FROM temp.purged_messages)",
+                table
+            ),
+            [],
+        )?;
+    }
+    conn.execute("DELETE FROM temp.purged_messages", [])?;
+
+    let report = PurgeReport {
+        by_age,
+        by_count,
+        deleted,
+    };
+
+    Ok((report, purged))
+}
+
+// Archives or deletes, as told by `action`, the rooms where nothing was posted
+// for `idle_secs`, other than those in `occupied`. Rooms without messages are
+// idle once created for that long. Returns the names of the rooms cleaned up.
+pub fn clean_up_idle_rooms(
+    conn: &Connection,
+    idle_secs: u64,
+    action: IdleRoomAction,
+    occupied: &[String],
+) -> Result<Vec<String>, rusqlite::Error> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT r.room_name FROM rooms r
+            LEFT JOIN chat_messages m ON m.room_name = r.room_name
+            WHERE NOT (?2 AND r.read_only)
+            GROUP BY r.room_name
+            HAVING MAX(r.created_at, COALESCE(MAX(m.created_at), r.created_at))
+                <= datetime('now', '-' || ?1 || ' seconds')
+            ORDER BY r.room_name",
+    )?;
+    // Archived rooms are not archived again
+    let archiving = action == IdleRoomAction::Archive;
+    let idle = stmt
+        .query_map(params![idle_secs, archiving], |row| row.get::<_, String>(0))?
+        .filter(|room_name| {
+            room_name
+                .as_ref()
+                .map_or(true, |room_name| !occupied.contains(room_name))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    for room_name in &idle {
+        match action {
+            IdleRoomAction::Archive => {
+                set_read_only(conn, room_name, true)?;
+            }
+            IdleRoomAction::Delete => delete_room(conn, room_name, false)?,
+            IdleRoomAction::Purge => delete_room(conn, room_name, true)?,
+        }
+    }
+
+    Ok(idle)
+}
+
+// Deletes `room_name` along with its owners, roles, access control list,
+// members, invites, sanctions, pins, reports, follows, polls and webhooks, and
+// its messages if `purge_history` is set.
+pub fn delete_room(
+    conn: &Connection,
+    room_name: &str,
+    purge_history: bool,
+) -> Result<(), rusqlite::Error> {
+    for table in &[
+        "room_owners",
+        "room_roles",
+        "room_acl",
+        "room_members",
+        "room_invites",
+        "room_sanctions",
+        "room_pins",
+        "message_reports",
+        "read_markers",
+        "mentions",
+        "alerts",
+        "notification_levels",
+        "queued_events",
+        "scheduled_messages",
+        "announcement_follows",
+        "poll_options",
+        "poll_votes",
+        "room_hooks",
+        "rooms",
+    ] {
+        conn.execute(
+            &format!("DELETE FROM {} WHERE room_name = ?1", table),
+            params![room_name],
+        )?;
+    }
+    conn.execute(
+        "DELETE FROM announcement_follows WHERE source_room = ?1",
+        params![room_name],
+    )?;
+
+    if purge_history {
+        conn.execute(
+            "DELETE FROM chat_messages WHERE room_name = ?1",
+            params![room_name],
+        )?;
+    }
+
+    Ok(())
+}
+
+// Owners of `room_name`, oldest first.
+pub fn owners(conn: &Connection, room_name: &str) -> Result<Vec<usize>, rusqlite::Error> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT user_id FROM room_owners WHERE room_name = ?1 ORDER BY added_at, rowid",
+    )?;
+    let owners = stmt
+        .query_map(params![room_name], |row| row.get(0))?
+        .collect();
+
+    owners
+}
+
+// Whether `user_id` owns `room_name`.
+pub fn is_owner(
+    conn: &Connection,
+    room_name: &str,
+    user_id: usize,
+) -> Result<bool, rusqlite::Error> {
+    conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM room_owners WHERE room_name = ?1 AND user_id = ?2)",
+        params![room_name, user_id],
+        |row| row.get(0),
+    )
+}
+
+// Makes `user_id` a co-owner of `room_name`, returning whether both exist.
+pub fn add_owner(
+    conn: &Connection,
+    room_name: &str,
+    user_id: usize,
+) -> Result<bool, rusqlite::Error> {
+    if !auth::user_exists(conn, user_id)? || !room_exists(conn, room_name)? {
+        return Ok(false);
+    }
+
+    conn.execute(
+        "INSERT OR IGNORE INTO room_owners (room_name, user_id) VALUES (?1, ?2)",
+        params![room_name, user_id],
+    )?;
+
+    Ok(true)
+}
+
+// Takes ownership of `room_name` away from `user_id`, returning whether they
+// owned it.
+pub fn remove_owner(
+    conn: &Connection,
+    room_name: &str,
+    user_id: usize,
+) -> Result<bool, rusqlite::Error> {
+    let deleted = conn.execute(
+        "DELETE FROM room_owners WHERE room_name = ?1 AND user_id = ?2",
+        params![room_name, user_id],
+    )?;
+
+    Ok(deleted > 0)
+}
+
+// Makes `user_id` the only owner of `room_name`, returning whether both exist.
+pub fn transfer_ownership(
+    conn: &Connection,
+    room_name: &str,
+    user_id: usize,
+) -> Result<bool, rusqlite::Error> {
+    if !auth::user_exists(conn, user_id)? || !room_exists(conn, room_name)? {
+        return Ok(false);
+    }
+
+    conn.execute(
+        "DELETE FROM room_owners WHERE room_name = ?1",
+        params![room_name],
+    )?;
+    add_owner(conn, room_name, user_id)
+}
+
+// Members of `room_name`, in the order they were invited.
+pub fn members(conn: &Connection, room_name: &str) -> Result<Vec<RoomMember>, rusqlite::Error> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT user_id, invited_by, added_at FROM room_members
+            WHERE room_name = ?1 ORDER BY added_at, rowid",
+    )?;
+    let members = stmt
+        .query_map(params![room_name], |row| {
+            Ok(RoomMember {
+                user_id: row.get(0)?,
+                invited_by: row.get(1)?,
+                added_at: row.get(2)?,
+            })
+        })?
+        .collect();
+
+    members
+}
+
+// Whether `user_id` is a member of `room_name`.
+pub fn is_member(
+    conn: &Connection,
+    room_name: &str,
+    user_id: usize,
+) -> Result<bool, rusqlite::Error> {
+    conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM room_members WHERE room_name = ?1 AND user_id = ?2)",
+        params![room_name, user_id],
+        |row| row.get(0),
+    )
+}
+
+// Makes `user_id` a member of `room_name`, as invited by `invited_by` if known.
+// Returns the membership, unless the user or room does not exist. Inviting
+// members again keeps their first invitation.
+pub fn add_member(
+    conn: &Connection,
+    room_name: &str,
+    user_id: usize,
+    invited_by: Option<usize>,
+) -> Result<Option<RoomMember>, rusqlite::Error> {
+    if !auth::user_exists(conn, user_id)? || !room_exists(conn, room_name)? {
+        return Ok(None);
+    }
+
+    conn.execute(
+        "INSERT OR IGNORE INTO room_members (room_name, user_id, invited_by) VALUES (?1, ?2, ?3)",
+        params![room_name, user_id, invited_by],
+    )?;
+
+    conn.query_row(
+        "SELECT user_id, invited_by, added_at FROM room_members
+            WHERE room_name = ?1 AND user_id = ?2",
+        params![room_name, user_id],
+        |row| {
+            Ok(RoomMember {
+                user_id: row.get(0)?,
+                invited_by: row.get(1)?,
+                added_at: row.get(2)?,
+            })
+        },
+    )
+    .optional()
+}
+
+// Takes `user_id` out of the members of `room_name`, returning whether they
+// were one.
+pub fn remove_member(
+    conn: &Connection,
+    room_name: &str,
+    user_id: usize,
+) -> Result<bool, rusqlite::Error> {
+    let deleted = conn.execute(
+        "DELETE FROM room_members WHERE room_name = ?1 AND user_id = ?2",
+        params![room_name, user_id],
+    )?;
+
+    Ok(deleted > 0)
+}
+
+fn room_exists(conn: &Connection, room_name: &str) -> Result<bool, rusqlite::Error> {
+    conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM rooms WHERE room_name = ?1)",
+        params![room_name],
+        |row| row.get(0),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{db, hook};
+
+    #[test]
+    fn test_ownership() {
+        let conn = Connection::open_in_memory().unwrap();
+        db::init_schema(&conn).unwrap();
+
+        let alice = auth::create_user(&conn, "alice", "hash").unwrap().unwrap();
+        let bob = auth::create_user(&conn, "bob", "hash").unwrap().unwrap();
+        assert_eq!(room_info(&conn, "room1").unwrap(), None);
+
+        // Rooms are only created once, by whoever joins first
+        record_room(&conn, "room1", alice, true).unwrap();
+        record_room(&conn, "room1", bob, true).unwrap();
+        let room = room_info(&conn, "room1").unwrap().unwrap();
+        assert_eq!(room.created_by, Some(alice));
+        assert_eq!(room.owners, vec![alice]);
+        assert!(is_owner(&conn, "room1", alice).unwrap());
+        assert!(!is_owner(&conn, "room1", bob).unwrap());
+
+        assert!(add_owner(&conn, "room1", bob).unwrap());
+        assert_eq!(owners(&conn, "room1").unwrap(), vec![alice, bob]);
+        assert!(remove_owner(&conn, "room1", alice).unwrap());
+        assert!(!remove_owner(&conn, "room1", alice).unwrap());
+        assert_eq!(owners(&conn, "room1").unwrap(), vec![bob]);
+
+        assert!(transfer_ownership(&conn, "room1", alice).unwrap());
+        assert_eq!(owners(&conn, "room1").unwrap(), vec![alice]);
+        assert!(!transfer_ownership(&conn, "room2", alice).unwrap());
+        assert!(!add_owner(&conn, "room1", 100).unwrap());
+
+        // Rooms created by guests have no owner
+        let guest = auth::create_guest(&conn, None).unwrap();
+        record_room(&conn, "room2", guest, false).unwrap();
+        assert!(owners(&conn, "room2").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_create_room() {
+        let conn = Connection::open_in_memory().unwrap();
+        db::init_schema(&conn).unwrap();
+
+        let alice = auth::create_user(&conn, "alice", "hash").unwrap().unwrap();
+        let private = RoomSettings {
+            topic: Some(String::from("Secrets")),
+            description: Some(String::from("Keep them")),
+            visibility: Visibility::Private,
+            capacity: Some(10),
+            retention_secs: Some(3600),
+            retention_messages: Some(1000),
+            password_protected: false,
+            read_only: true,
+            approval_required: true,
+            announcement: true,
+        };
+        assert!(create_room(&conn, "room1", alice, &private, None).unwrap());
+        assert!(!create_room(&conn, "room1", alice, &RoomSettings::default(), None).unwrap());
+
+        let room = room_info(&conn, "room1").unwrap().unwrap();
+        assert_eq!(room.settings, private);
+        assert_eq!(room.owners, vec![alice]);
+
+        // Rooms created by joining them have the default settings
+        record_room(&conn, "room2", alice, true).unwrap();
+        assert_eq!(
+            settings(&conn, "room2").unwrap(),
+            Some(RoomSettings::default())
+        );
+        assert_eq!(settings(&conn, "room3").unwrap(), None);
+
+        // Rooms given a password are protected by it
+        assert_eq!(password_hash(&conn, "room1").unwrap(), None);
+        create_room(
+            &conn,
+            "room4",
+            alice,
+            &RoomSettings::default(),
+            Some("hash"),
+        )
+        .unwrap();
+        assert!(
+            settings(&conn, "room4")
+                .unwrap()
+                .unwrap()
+                .password_protected
+        );
+        assert_eq!(
+            password_hash(&conn, "room4").unwrap().as_deref(),
+            Some("hash")
+        );
+
+        let named = |name: &str| NewRoom {
+            name: String::from(name),
+            settings: RoomSettings::default(),
+            password: None,
+        };
+        assert!(named("general").validate().is_ok());
+        assert!(named("").validate().is_err());
+        assert!(named("a/b").validate().is_err());
+        let full = NewRoom {
+            settings: RoomSettings {
+                capacity: Some(0),
+                ..RoomSettings::default()
+            },
+            ..named("general")
+        };
+        assert!(full.validate().is_err());
+    }
+
+    #[test]
+    fn test_delete_room() {
+        let conn = Connection::open_in_memory().unwrap();
+        db::init_schema(&conn).unwrap();
+
+        let alice = auth::create_user(&conn, "alice", "hash").unwrap().unwrap();
+        create_room(&conn, "room1", alice, &RoomSettings::default(), None).unwrap();
+        let new_hook = hook::NewHook {
+            name: String::from("deploys"),
+        };
+        let (_, token) = hook::create_hook(&conn, "room1", alice, &new_hook)
+            .unwrap()
+            .unwrap();
+
+        // Webhooks go with their room, rather than posting to whichever room
+        // is created under its name next
+        delete_room(&conn, "room1", false).unwrap();
+        assert!(hook::room_hooks(&conn, "room1").unwrap().is_empty());
+        assert!(hook::find_hook(&conn, &token).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_purge_expired() {
+        let conn = Connection::open_in_memory().unwrap();
+        db::init_schema(&conn).unwrap();
+
+        let alice = auth::create_user(&conn, "alice", "hash").unwrap().unwrap();
+        let ephemeral = RoomSettings {
+            retention_secs: Some(60),
+            ..RoomSettings::default()
+        };
+        create_room(&conn, "ephemeral", alice, &ephemeral, None).unwrap();
+        create_room(&conn, "archive", alice, &RoomSettings::default(), None).unwrap();
+
+        for (room_name, age) in &[("ephemeral", 120), ("ephemeral", 0), ("archive", 120)] {
+            conn.execute(
+                "INSERT INTO chat_messages (user_id, room_name, message, created_at)
+                    VALUES (?1, ?2, 'Hello', datetime('now', ?3))",
+                params![alice, room_name, format!("-{} seconds", age)],
+            )
+            .unwrap();
+        }
+
+        assert_eq!(
+            purge_expired(&conn, None).unwrap().0,
+            PurgeReport {
+                by_age: 1,
+                by_count: 0,
+                deleted: 0
+            }
+        );
+        assert_eq!(
+            db::recent_messages(&conn, "ephemeral", 1, 10)
+                .unwrap()
+                .len(),
+            1
+        );
+        assert_eq!(
+            db::recent_messages(&conn, "archive", 1, 10).unwrap().len(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_purge_overflowing() {
+        let conn = Connection::open_in_memory().unwrap();
+        db::init_schema(&conn).unwrap();
+
+        let alice = auth::create_user(&conn, "alice", "hash").unwrap().unwrap();
+        let recent = RoomSettings {
+            retention_secs: Some(60),
+            retention_messages: Some(2),
+            ..RoomSettings::default()
+        };
+        create_room(&conn, "recent", alice, &recent, None).unwrap();
+        create_room(&conn, "archive", alice, &RoomSettings::default(), None).unwrap();
+
+        for (seq, room_name, age) in &[
+            (1, "recent", 120),
+            (2, "recent", 0),
+            (3, "recent", 0),
+            (4, "recent", 0),
+            (1, "archive", 0),
+            (2, "archive", 0),
+            (3, "archive", 0),
+        ] {
+            conn.execute(
+                "INSERT INTO chat_messages (seq, user_id, room_name, message, created_at)
+                    VALUES (?1, ?2, ?3, 'Hello', datetime('now', ?4))",
+                params![seq, alice, room_name, format!("-{} seconds", age)],
+            )
+            .unwrap();
+        }
+        conn.execute(
+            "INSERT INTO room_pins (room_name, message_id, pinned_by) VALUES ('recent', 2, ?1)",
+            params![alice],
+        )
+        .unwrap();
+
+        // Messages too old are only counted once
+        assert_eq!(
+            purge_expired(&conn, None).unwrap().0,
+            PurgeReport {
+                by_age: 1,
+                by_count: 1,
+                deleted: 0
+            }
+        );
+        let kept = db::recent_messages(&conn, "recent", 1, 10).unwrap();
+        assert_eq!(
+            kept.iter().map(|msg| msg.seq).collect::<Vec<_>>(),
+            vec![Some(3), Some(4)]
+        );
+        assert!(db::room_pins(&conn, "recent").unwrap().is_empty());
+        assert_eq!(
+            db::recent_messages(&conn, "archive", 1, 10).unwrap().len(),
+            3
+        );
+
+        assert_eq!(purge_expired(&conn, None).unwrap().0.total(), 0);
+    }
+
+    #[test]
+    fn test_purge_deleted() {
+        let conn = Connection::open_in_memory().unwrap();
+        db::init_schema(&conn).unwrap();
+
+        for (message_id, deleted) in &[(1, Some(120)), (2, Some(0)), (3, None)] {
+            conn.execute(
+                "INSERT INTO chat_messages (message_id, user_id, room_name, message, deleted_at)
+                    VALUES (?1, 1, 'room1', 'Hello', datetime('now', ?2))",
+                params![message_id, deleted.map(|age| format!("-{} seconds", age))],
+            )
+            .unwrap();
+        }
+
+        // Deleted messages are kept unless told otherwise
+        assert_eq!(purge_expired(&conn, None).unwrap().0.total(), 0);
+        assert_eq!(
+            purge_expired(&conn, Some(60)).unwrap(),
+            (
+                PurgeReport {
+                    by_age: 0,
+                    by_count: 0,
+                    deleted: 1
+                },
+                vec![1]
+            )
+        );
+        assert_eq!(db::deleted_messages(&conn, "room1").unwrap().len(), 1);
+
+        // Tombstones of messages still kept remain in history
+        assert_eq!(db::recent_messages(&conn, "room1", 1, 10).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_clean_up_idle_rooms() {
+        let conn = Connection::open_in_memory().unwrap();
+        db::init_schema(&conn).unwrap();
+
+        let alice = auth::create_user(&conn, "alice", "hash").unwrap().unwrap();
+        for room_name in &["idle", "active", "occupied", "empty"] {
+            create_room(&conn, room_name, alice, &RoomSettings::default(), None).unwrap();
+        }
+        conn.execute(
+            "UPDATE rooms SET created_at = datetime('now', '-2 hours')",
+            [],
+        )
+        .unwrap();
+        for (room_name, age) in &[("idle", 3600), ("active", 3600), ("active", 0)] {
+            conn.execute(
+                "INSERT INTO chat_messages (user_id, room_name, message, created_at)
+                    VALUES (?1, ?2, 'Hello', datetime('now', ?3))",
+                params![alice, room_name, format!("-{} seconds", age)],
+            )
+            .unwrap();
+        }
+        let occupied = vec![String::from("occupied")];
+
+        // Archived rooms are not archived again
+        let archived = clean_up_idle_rooms(&conn, 60, IdleRoomAction::Archive, &occupied).unwrap();
+        assert_eq!(archived, vec!["empty", "idle"]);
+        assert!(is_read_only(&conn, "idle").unwrap());
+        assert!(!is_read_only(&conn, "active").unwrap());
+        assert!(
+            clean_up_idle_rooms(&conn, 60, IdleRoomAction::Archive, &occupied)
+                .unwrap()
+                .is_empty()
+        );
+
+        let deleted = clean_up_idle_rooms(&conn, 60, IdleRoomAction::Delete, &occupied).unwrap();
+        assert_eq!(deleted, vec!["empty", "idle"]);
+        assert_eq!(settings(&conn, "idle").unwrap(), None);
+        assert_eq!(owners(&conn, "idle").unwrap(), Vec::<usize>::new());
+        assert_eq!(db::recent_messages(&conn, "idle", 1, 10).unwrap().len(), 1);
+
+        let purged = clean_up_idle_rooms(&conn, 0, IdleRoomAction::Purge, &occupied).unwrap();
+        assert_eq!(purged, vec!["active"]);
+        assert!(db::recent_messages(&conn, "active", 1, 10)
+            .unwrap()
+            .is_empty());
+        assert!(settings(&conn, "occupied").unwrap().is_some());
+    }
+
+    #[test]
+    fn test_public_rooms() {
+        let conn = Connection::open_in_memory().unwrap();
+        db::init_schema(&conn).unwrap();
+
+        let alice = auth::create_user(&conn, "alice", "hash").unwrap().unwrap();
+        let lobby = RoomSettings {
+            topic: Some(String::from("Say hi")),
+            ..RoomSettings::default()
+        };
+        create_room(&conn, "lobby", alice, &lobby, None).unwrap();
+        let private = RoomSettings {
+            visibility: Visibility::Private,
+            ..RoomSettings::default()
+        };
+        create_room(&conn, "vault", alice, &private, None).unwrap();
+        record_room(&conn, "general", alice, true).unwrap();
+
+        for (room_name, deleted) in &[("lobby", false), ("lobby", true), ("vault", false)] {
+            conn.execute(
+                "INSERT INTO chat_messages (user_id, room_name, message, deleted_at)
+                    VALUES (?1, ?2, 'Hello', CASE WHEN ?3 THEN CURRENT_TIMESTAMP END)",
+                params![alice, room_name, deleted],
+            )
+            .unwrap();
+        }
+
+        assert_eq!(
+            public_rooms(&conn).unwrap(),
+            vec![
+                RoomListing {
+                    name: String::from("general"),
+                    topic: None,
+                    occupancy: 0,
+                    message_count: 0,
+                    password_protected: false,
+                },
+                RoomListing {
+                    name: String::from("lobby"),
+                    topic: Some(String::from("Say hi")),
+                    occupancy: 0,
+                    message_count: 1,
+                    password_protected: false,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_set_topic() {
+        let conn = Connection::open_in_memory().unwrap();
+        db::init_schema(&conn).unwrap();
+
+        let alice = auth::create_user(&conn, "alice", "hash").unwrap().unwrap();
+        record_room(&conn, "room1", alice, true).unwrap();
+
+        let update = TopicUpdate {
+            topic: Some(String::from("Rust")),
+            description: Some(String::from("Talking about Rust")),
+        };
+        assert!(set_topic(&conn, "room1", &update).unwrap());
+        assert!(!set_topic(&conn, "room2", &update).unwrap());
+        let room1 = settings(&conn, "room1").unwrap().unwrap();
+        assert_eq!(room1.topic.as_deref(), Some("Rust"));
+        assert_eq!(room1.description.as_deref(), Some("Talking about Rust"));
+
+        // Leaving either out clears it
+        let cleared = TopicUpdate {
+            topic: Some(String::from("Rust")),
+            description: None,
+        };
+        assert!(set_topic(&conn, "room1", &cleared).unwrap());
+        assert_eq!(settings(&conn, "room1").unwrap().unwrap().description, None);
+
+        let long = TopicUpdate {
+            topic: Some("a".repeat(MAX_TOPIC_LENGTH + 1)),
+            description: None,
+        };
+        assert!(long.validate().is_err());
+        assert!(update.validate().is_ok());
+    }
+
+    #[test]
+    fn test_set_read_only() {
+        let conn = Connection::open_in_memory().unwrap();
+        db::init_schema(&conn).unwrap();
+
+        let alice = auth::create_user(&conn, "alice", "hash").unwrap().unwrap();
+        record_room(&conn, "room1", alice, true).unwrap();
+        assert!(!is_read_only(&conn, "room1").unwrap());
+
+        assert!(set_read_only(&conn, "room1", true).unwrap());
+        assert!(!set_read_only(&conn, "room2", true).unwrap());
+        assert!(is_read_only(&conn, "room1").unwrap());
+        assert!(settings(&conn, "room1").unwrap().unwrap().read_only);
+        assert!(!is_read_only(&conn, "room2").unwrap());
+
+        assert!(set_read_only(&conn, "room1", false).unwrap());
+        assert!(!is_read_only(&conn, "room1").unwrap());
+    }
+
+    #[test]
+    fn test_members() {
+        let conn = Connection::open_in_memory().unwrap();
+        db::init_schema(&conn).unwrap();
+
+        let alice = auth::create_user(&conn, "alice", "hash").unwrap().unwrap();
+        let bob = auth::create_user(&conn, "bob", "hash").unwrap().unwrap();
+        record_room(&conn, "room1", alice, true).unwrap();
+        assert!(!is_member(&conn, "room1", bob).unwrap());
+
+        let member = add_member(&conn, "room1", bob, Some(alice))
+            .unwrap()
+            .unwrap();
+        assert_eq!((member.user_id, member.invited_by), (bob, Some(alice)));
+        assert!(is_member(&conn, "room1", bob).unwrap());
+        assert_eq!(members(&conn, "room1").unwrap(), vec![member]);
+
+        // Only existing users can be invited into existing rooms
+        assert_eq!(add_member(&conn, "room2", bob, Some(alice)).unwrap(), None);
+        assert_eq!(add_member(&conn, "room1", 100, Some(alice)).unwrap(), None);
+
+        assert!(remove_member(&conn, "room1", bob).unwrap());
+        assert!(!remove_member(&conn, "room1", bob).unwrap());
+        assert!(!is_member(&conn, "room1", bob).unwrap());
+    }
+}
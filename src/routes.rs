@@ -1,11 +1,946 @@
-use warp::{ws::Ws, Filter};
+use std::{
+    convert::Infallible,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
 
-use crate::html::INDEX_HTML;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use serde::Deserialize;
+use utoipa::IntoParams;
+use warp::{
+    http::HeaderMap,
+    path::{FullPath, Tail},
+    reject::Reject,
+    ws::Ws,
+    Filter, Rejection,
+};
+
+use crate::{
+    alert::KeywordUpdate,
+    announcement::AnnouncementUpdate,
+    auth::{
+        self,
+        api_token::NewApiToken,
+        oauth,
+        reset::{ForgotPassword, PasswordReset},
+        scope::Scope,
+        Credentials, MessageRetention, Principal,
+    },
+    authz::{AccessUpdate, RoleUpdate},
+    cluster,
+    conversation::NewConversation,
+    export::ExportFormat,
+    history::NewMessage,
+    hook::{NewHook, SlackPayload},
+    html::INDEX_HTML,
+    invite::NewInvite,
+    ip_ban::NewIpBan,
+    moderation::NewSanction,
+    notification::LevelUpdate,
+    profile::ProfileUpdate,
+    report::{NewReport, ReportResolution},
+    room::{MemberInvite, NewRoom, OwnerUpdate, ReadOnlyUpdate, RetentionUpdate, TopicUpdate},
+    schedule::NewScheduledMessage,
+    wire::WireFormat,
+};
+
+// Largest request body accepted by JSON routes.
+const MAX_BODY_SIZE: u64 = 16 * 1024;
+
+// Maximum size of the events peers send each other, which may carry whole
+// messages along with their metadata.
+const MAX_EVENT_SIZE: u64 = 256 * 1024;
+
+// Rejection of requests to admin routes without the admin credential.
+#[derive(Debug)]
+pub struct Unauthorized;
+
+impl Reject for Unauthorized {}
+
+// Rejection of requests to routes that need a logged in user, made without a
+// valid token or session.
+#[derive(Debug)]
+pub struct NotLoggedIn;
+
+impl Reject for NotLoggedIn {}
+
+// Rejection of requests made with an API token that lacks the scope a route
+// needs.
+#[derive(Debug)]
+pub struct MissingScope(pub Scope);
+
+impl Reject for MissingScope {}
+
+// Rejection of requests whose token or session could not be looked up.
+#[derive(Debug)]
+pub struct CredentialsUnavailable;
+
+impl Reject for CredentialsUnavailable {}
+
+// Optional query parameters of the chat route.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct ChatQuery {
+    // Grants moderator permissions if it matches the configured moderator key
+    pub key: Option<String>,
+
+    // JWT obtained by logging in -- guests connect without one.
+    // May also be given as an `Authorization: Bearer` header.
+    pub token: Option<String>,
+
+    // Nickname to be displayed with, instead of the user's ID
+    pub nick: Option<String>,
+
+    // Password of password-protected rooms -- may instead be sent in an `auth`
+    // frame once connected.
+    pub password: Option<String>,
+
+    // Token of an invite into the room, making the user a member of it
+    pub invite: Option<String>,
+
+    // ID of the last message received from the room, when reconnecting: every
+    // later message is replayed instead of the room's recent history.
+    pub since: Option<i64>,
+
+    // How frames are encoded, JSON unless `msgpack` or `protobuf` is asked for
+    pub format: Option<WireFormat>,
+
+    // Format asked for as a WebSocket subprotocol instead, which is agreed to
+    // in the handshake
+    #[serde(skip)]
+    pub subprotocol: Option<WireFormat>,
+}
+
+// Query parameters of the message search route.
+#[derive(Clone, Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct SearchQuery {
+    // Words messages must all contain
+    pub q: String,
+    // Number of matches to return, and how many to skip, paging through them
+    pub limit: Option<usize>,
+    #[serde(default)]
+    pub offset: usize,
+}
+
+// Optional query parameters of the room history route.
+#[derive(Clone, Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct HistoryQuery {
+    // Messages sent from then on, until before `until`
+    pub since: Option<String>,
+    pub until: Option<String>,
+    // Only messages sent by this user
+    pub user_id: Option<usize>,
+    // Number of messages to return, and how many to skip, paging through them
+    pub limit: Option<usize>,
+    #[serde(default)]
+    pub offset: usize,
+}
+
+// Optional query parameters of the room event stream route, which browsers'
+// `EventSource` cannot send headers to.
+#[derive(Clone, Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct StreamQuery {
+    // Taken over the `Authorization` header
+    pub token: Option<String>,
+    // Password of password-protected rooms
+    pub password: Option<String>,
+    // ID of the last message received from the room, as with `/chat`, unless
+    // a `Last-Event-ID` header gives it
+    pub since: Option<i64>,
+}
+
+// Optional query parameters of the room export route.
+#[derive(Clone, Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ExportQuery {
+    // JSON by default
+    #[serde(default)]
+    pub format: ExportFormat,
+}
+
+// Optional query parameters of the account deletion route.
+#[derive(Clone, Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct DeleteUserQuery {
+    // What becomes of the user's messages, anonymized by default
+    #[serde(default)]
+    pub messages: MessageRetention,
+}
+
+// Query parameters providers redirect back to the OAuth callback route with.
+#[derive(Clone, Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct OAuthCallback {
+    pub code: Option<String>,
+    pub state: Option<String>,
+    // Set instead of `code` if the user did not authorize the login
+    pub error: Option<String>,
+}
+
+// Connections to `/chat` without a room are left to join the default room.
+pub fn chat(
+) -> impl Filter<Extract = (Ws, Option<String>, ChatQuery, Option<String>), Error = warp::Rejection> + Copy
+{
+    let room = warp::path::param::<String>()
+        .map(Some)
+        .or(warp::path::end().map(|| None))
+        .unify();
 
-pub fn chat() -> impl Filter<Extract = (Ws, String), Error = warp::Rejection> + Copy {
     warp::path("chat")
         .and(warp::ws())
-        .and(warp::path::param::<String>())
+        .and(room)
+        .and(chat_query())
+        .and(bearer_token())
+}
+
+// Query parameters of the chat route, along with the wire format asked for in
+// the `Sec-WebSocket-Protocol` header, if any.
+fn chat_query() -> impl Filter<Extract = (ChatQuery,), Error = warp::Rejection> + Copy {
+    warp::query::<ChatQuery>()
+        .and(warp::header::optional::<String>("sec-websocket-protocol"))
+        .map(|mut query: ChatQuery, protocols: Option<String>| {
+            query.subprotocol = protocols.as_deref().and_then(WireFormat::negotiate);
+            query
+        })
+}
+
+// Token given in an `Authorization: Bearer <token>` header, if any.
+pub fn bearer_token() -> impl Filter<Extract = (Option<String>,), Error = Infallible> + Copy {
+    warp::header::optional::<String>("authorization")
+        .map(|header: Option<String>| {
+            header.and_then(|header| {
+                header
+                    .strip_prefix("Bearer ")
+                    .map(|token| String::from(token.trim()))
+            })
+        })
+        .or(warp::any().map(|| None))
+        .unify()
+}
+
+// Guards admin routes: requests must carry `admin_token`, as configured with
+// `--admin-token`, either as a bearer token or as the password of HTTP Basic
+// auth (with any username). Requests without it are rejected with
+// `Unauthorized`, and admin routes are not found at all without a token.
+pub fn admin_guard(
+    admin_token: Option<String>,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    // Hashes are compared rather than tokens, so that comparisons do not leak
+    // how much of the token was guessed right
+    let expected = admin_token.map(|token| Arc::new(auth::hash_token(&token)));
+
+    warp::header::optional::<String>("authorization")
+        .and_then(move |header: Option<String>| {
+            let expected = expected.clone();
+            async move {
+                let expected = expected.ok_or_else(warp::reject::not_found)?;
+                match header.as_deref().and_then(admin_credential) {
+                    Some(given) if auth::hash_token(&given) == *expected => Ok(()),
+                    _ => Err(warp::reject::custom(Unauthorized)),
+                }
+            }
+        })
+        .untuple_one()
+}
+
+// Guards routes that need a logged in user. `principal` resolves who makes a
+// request, as `handlers::principal` does: requests without one are rejected
+// with `NotLoggedIn`, and those whose API token lacks `scope` with
+// `MissingScope`. Handlers are given the principal of the request.
+pub fn require_scope<F>(
+    principal: F,
+    scope: Scope,
+) -> impl Filter<Extract = (Principal,), Error = Rejection> + Clone
+where
+    F: Filter<Extract = (Option<Principal>,), Error = Rejection> + Clone + Send + Sync + 'static,
+{
+    principal.and_then(move |principal: Option<Principal>| {
+        let scope = scope.clone();
+        async move { authorize(principal, scope) }
+    })
+}
+
+// Like `require_scope`, for scopes limited to the room named in the path of
+// requests, `/rooms/{name}/...`, e.g. `Scope::Read` for reading its messages.
+pub fn require_room_scope<F>(
+    principal: F,
+    scope: fn(Option<String>) -> Scope,
+) -> impl Filter<Extract = (Principal,), Error = Rejection> + Clone
+where
+    F: Filter<Extract = (Option<Principal>,), Error = Rejection> + Clone + Send + Sync + 'static,
+{
+    warp::path::full().and(principal).and_then(
+        move |path: FullPath, principal: Option<Principal>| async move {
+            // Taken as is, as `warp::path!` passes it to handlers
+            let room = path
+                .as_str()
+                .strip_prefix("/rooms/")
+                .and_then(|rest| rest.split('/').next())
+                .map(String::from);
+            authorize(principal, scope(room))
+        },
+    )
+}
+
+fn authorize(principal: Option<Principal>, scope: Scope) -> Result<Principal, Rejection> {
+    match principal {
+        Some(principal) if principal.allows(&scope) => Ok(principal),
+        Some(_) => Err(warp::reject::custom(MissingScope(scope))),
+        None => Err(warp::reject::custom(NotLoggedIn)),
+    }
+}
+
+// Credential given in an `Authorization` header, as a bearer token or the
+// password of HTTP Basic auth.
+fn admin_credential(header: &str) -> Option<String> {
+    let (scheme, value) = header.split_once(' ')?;
+
+    if scheme.eq_ignore_ascii_case("bearer") {
+        return Some(String::from(value.trim()));
+    }
+
+    if scheme.eq_ignore_ascii_case("basic") {
+        let decoded = String::from_utf8(BASE64.decode(value.trim()).ok()?).ok()?;
+        let (_username, password) = decoded.split_once(':')?;
+        return Some(String::from(password));
+    }
+
+    None
+}
+
+// Address of the client, if known. Unless `trust_forwarded_for` is set, it is
+// the peer address of the connection. Otherwise, it is taken from the
+// `Forwarded` header, or failing that from `X-Forwarded-For`, as set by the
+// reverse proxy in front of the server: only the last address of either is
+// used, since any before it may have been forged by the client.
+pub fn client_ip(
+    trust_forwarded_for: bool,
+) -> impl Filter<Extract = (Option<IpAddr>,), Error = Infallible> + Copy {
+    warp::addr::remote()
+        .and(warp::header::headers_cloned())
+        .map(move |remote: Option<SocketAddr>, headers: HeaderMap| {
+            let forwarded = if trust_forwarded_for {
+                forwarded_for(&headers)
+            } else {
+                None
+            };
+            forwarded.or_else(|| remote.map(|addr| addr.ip()))
+        })
+}
+
+// Last client address forwarded by a proxy, if any.
+fn forwarded_for(headers: &HeaderMap) -> Option<IpAddr> {
+    let last_value = |name: &str| {
+        headers
+            .get_all(name)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .last()
+            .map(str::trim)
+    };
+
+    let forwarded = last_value("forwarded").and_then(|element| {
+        element.split(';').find_map(|pair| {
+            let (key, value) = pair.trim().split_once('=')?;
+            key.eq_ignore_ascii_case("for")
+                .then(|| value.trim_matches('"'))
+        })
+    });
+
+    match forwarded {
+        Some(node) => parse_node(node),
+        None => last_value("x-forwarded-for").and_then(parse_node),
+    }
+}
+
+// Address of a forwarded node, given as `<IPv4>`, `<IPv4>:<port>`, `<IPv6>` or
+// `[<IPv6>]:<port>`.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    if let Ok(addr) = node.parse::<IpAddr>() {
+        return Some(addr);
+    }
+    if let Ok(addr) = node.parse::<SocketAddr>() {
+        return Some(addr.ip());
+    }
+
+    node.strip_prefix('[')?.strip_suffix(']')?.parse().ok()
+}
+
+// User agent of the client, if given. Recorded with the sessions it logs in to.
+pub fn user_agent() -> impl Filter<Extract = (Option<String>,), Error = Infallible> + Copy {
+    warp::header::optional::<String>("user-agent")
+        .or(warp::any().map(|| None))
+        .unify()
+}
+
+// Token of the session cookie issued on login, if any.
+// `handlers::session` resolves it to a session.
+pub fn session_cookie() -> impl Filter<Extract = (Option<String>,), Error = Infallible> + Copy {
+    warp::cookie::optional::<String>(auth::SESSION_COOKIE)
+}
+
+pub fn ip_bans() -> impl Filter<Extract = (), Error = warp::Rejection> + Copy {
+    warp::path!("admin" / "ip_bans").and(warp::get())
+}
+
+pub fn ban_ip() -> impl Filter<Extract = (NewIpBan,), Error = warp::Rejection> + Copy {
+    warp::path!("admin" / "ip_bans")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(MAX_BODY_SIZE))
+        .and(warp::body::json())
+}
+
+pub fn unban_ip() -> impl Filter<Extract = (i64,), Error = warp::Rejection> + Copy {
+    warp::path!("admin" / "ip_bans" / i64).and(warp::delete())
+}
+
+pub fn db_stats() -> impl Filter<Extract = (), Error = warp::Rejection> + Copy {
+    warp::path!("admin" / "db").and(warp::get())
+}
+
+pub fn maintain_db() -> impl Filter<Extract = (), Error = warp::Rejection> + Copy {
+    warp::path!("admin" / "db" / "maintenance").and(warp::post())
+}
+
+pub fn backup_db() -> impl Filter<Extract = (), Error = warp::Rejection> + Copy {
+    warp::path!("admin" / "db" / "backup").and(warp::post())
+}
+
+pub fn federation_link() -> impl Filter<Extract = (String, Ws), Error = warp::Rejection> + Copy {
+    warp::path!("federation" / "rooms" / String).and(warp::ws())
+}
+
+pub fn cluster_membership() -> impl Filter<Extract = (), Error = warp::Rejection> + Copy {
+    warp::path!("cluster" / "membership").and(warp::get())
+}
+
+pub fn cluster_deliver(
+) -> impl Filter<Extract = (cluster::Envelope,), Error = warp::Rejection> + Copy {
+    warp::path!("cluster" / "deliver")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(MAX_EVENT_SIZE))
+        .and(warp::body::json())
+}
+
+pub fn cluster_room(
+) -> impl Filter<Extract = (cluster::RoomRequest,), Error = warp::Rejection> + Copy {
+    warp::path!("cluster" / "rooms")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(MAX_EVENT_SIZE))
+        .and(warp::body::json())
+}
+
+pub fn room_pins() -> impl Filter<Extract = (String,), Error = warp::Rejection> + Copy {
+    warp::path!("rooms" / String / "pins").and(warp::get())
+}
+
+pub fn room_roles() -> impl Filter<Extract = (String,), Error = warp::Rejection> + Copy {
+    warp::path!("rooms" / String / "roles").and(warp::get())
+}
+
+pub fn set_room_role(
+) -> impl Filter<Extract = (String, usize, RoleUpdate), Error = warp::Rejection> + Copy {
+    warp::path!("rooms" / String / "roles" / usize)
+        .and(warp::put())
+        .and(warp::body::content_length_limit(MAX_BODY_SIZE))
+        .and(warp::body::json())
+}
+
+pub fn rooms() -> impl Filter<Extract = (), Error = warp::Rejection> + Copy {
+    warp::path!("rooms").and(warp::get())
+}
+
+pub fn create_room() -> impl Filter<Extract = (NewRoom,), Error = warp::Rejection> + Copy {
+    warp::path!("rooms")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(MAX_BODY_SIZE))
+        .and(warp::body::json())
+}
+
+pub fn room() -> impl Filter<Extract = (String,), Error = warp::Rejection> + Copy {
+    warp::path!("rooms" / String).and(warp::get())
+}
+
+pub fn set_room_topic(
+) -> impl Filter<Extract = (String, TopicUpdate), Error = warp::Rejection> + Copy {
+    warp::path!("rooms" / String / "topic")
+        .and(warp::put())
+        .and(warp::body::content_length_limit(MAX_BODY_SIZE))
+        .and(warp::body::json())
+}
+
+pub fn set_room_read_only(
+) -> impl Filter<Extract = (String, ReadOnlyUpdate), Error = warp::Rejection> + Copy {
+    warp::path!("rooms" / String / "read_only")
+        .and(warp::put())
+        .and(warp::body::content_length_limit(MAX_BODY_SIZE))
+        .and(warp::body::json())
+}
+
+pub fn set_room_retention(
+) -> impl Filter<Extract = (String, RetentionUpdate), Error = warp::Rejection> + Copy {
+    warp::path!("rooms" / String / "retention")
+        .and(warp::put())
+        .and(warp::body::content_length_limit(MAX_BODY_SIZE))
+        .and(warp::body::json())
+}
+
+pub fn set_room_announcement(
+) -> impl Filter<Extract = (String, AnnouncementUpdate), Error = warp::Rejection> + Copy {
+    warp::path!("rooms" / String / "announcement")
+        .and(warp::put())
+        .and(warp::body::content_length_limit(MAX_BODY_SIZE))
+        .and(warp::body::json())
+}
+
+pub fn room_following() -> impl Filter<Extract = (String,), Error = warp::Rejection> + Copy {
+    warp::path!("rooms" / String / "following").and(warp::get())
+}
+
+pub fn follow_room() -> impl Filter<Extract = (String, String), Error = warp::Rejection> + Copy {
+    warp::path!("rooms" / String / "following" / String).and(warp::put())
+}
+
+pub fn unfollow_room() -> impl Filter<Extract = (String, String), Error = warp::Rejection> + Copy {
+    warp::path!("rooms" / String / "following" / String).and(warp::delete())
+}
+
+pub fn add_room_owner(
+) -> impl Filter<Extract = (String, OwnerUpdate), Error = warp::Rejection> + Copy {
+    warp::path!("rooms" / String / "owners")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(MAX_BODY_SIZE))
+        .and(warp::body::json())
+}
+
+pub fn remove_room_owner() -> impl Filter<Extract = (String, usize), Error = warp::Rejection> + Copy
+{
+    warp::path!("rooms" / String / "owners" / usize).and(warp::delete())
+}
+
+pub fn transfer_room(
+) -> impl Filter<Extract = (String, OwnerUpdate), Error = warp::Rejection> + Copy {
+    warp::path!("rooms" / String / "owner")
+        .and(warp::put())
+        .and(warp::body::content_length_limit(MAX_BODY_SIZE))
+        .and(warp::body::json())
+}
+
+pub fn room_acl() -> impl Filter<Extract = (String,), Error = warp::Rejection> + Copy {
+    warp::path!("rooms" / String / "acl").and(warp::get())
+}
+
+pub fn set_room_access(
+) -> impl Filter<Extract = (String, usize, AccessUpdate), Error = warp::Rejection> + Copy {
+    warp::path!("rooms" / String / "acl" / usize)
+        .and(warp::put())
+        .and(warp::body::content_length_limit(MAX_BODY_SIZE))
+        .and(warp::body::json())
+}
+
+pub fn remove_room_access() -> impl Filter<Extract = (String, usize), Error = warp::Rejection> + Copy
+{
+    warp::path!("rooms" / String / "acl" / usize).and(warp::delete())
+}
+
+pub fn room_members() -> impl Filter<Extract = (String,), Error = warp::Rejection> + Copy {
+    warp::path!("rooms" / String / "members").and(warp::get())
+}
+
+pub fn invite_room_member(
+) -> impl Filter<Extract = (String, MemberInvite), Error = warp::Rejection> + Copy {
+    warp::path!("rooms" / String / "members")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(MAX_BODY_SIZE))
+        .and(warp::body::json())
+}
+
+pub fn remove_room_member() -> impl Filter<Extract = (String, usize), Error = warp::Rejection> + Copy
+{
+    warp::path!("rooms" / String / "members" / usize).and(warp::delete())
+}
+
+pub fn kick_user() -> impl Filter<Extract = (String, usize), Error = warp::Rejection> + Copy {
+    warp::path!("rooms" / String / "kick" / usize).and(warp::post())
+}
+
+pub fn room_bans() -> impl Filter<Extract = (String,), Error = warp::Rejection> + Copy {
+    warp::path!("rooms" / String / "bans").and(warp::get())
+}
+
+pub fn ban_user(
+) -> impl Filter<Extract = (String, usize, NewSanction), Error = warp::Rejection> + Copy {
+    warp::path!("rooms" / String / "bans" / usize)
+        .and(warp::put())
+        .and(warp::body::content_length_limit(MAX_BODY_SIZE))
+        .and(warp::body::json())
+}
+
+pub fn unban_user() -> impl Filter<Extract = (String, usize), Error = warp::Rejection> + Copy {
+    warp::path!("rooms" / String / "bans" / usize).and(warp::delete())
+}
+
+pub fn room_mutes() -> impl Filter<Extract = (String,), Error = warp::Rejection> + Copy {
+    warp::path!("rooms" / String / "mutes").and(warp::get())
+}
+
+pub fn mute_user(
+) -> impl Filter<Extract = (String, usize, NewSanction), Error = warp::Rejection> + Copy {
+    warp::path!("rooms" / String / "mutes" / usize)
+        .and(warp::put())
+        .and(warp::body::content_length_limit(MAX_BODY_SIZE))
+        .and(warp::body::json())
+}
+
+pub fn unmute_user() -> impl Filter<Extract = (String, usize), Error = warp::Rejection> + Copy {
+    warp::path!("rooms" / String / "mutes" / usize).and(warp::delete())
+}
+
+pub fn room_shadow_bans() -> impl Filter<Extract = (String,), Error = warp::Rejection> + Copy {
+    warp::path!("rooms" / String / "shadow_bans").and(warp::get())
+}
+
+pub fn shadow_ban_user(
+) -> impl Filter<Extract = (String, usize, NewSanction), Error = warp::Rejection> + Copy {
+    warp::path!("rooms" / String / "shadow_bans" / usize)
+        .and(warp::put())
+        .and(warp::body::content_length_limit(MAX_BODY_SIZE))
+        .and(warp::body::json())
+}
+
+pub fn unshadow_ban_user() -> impl Filter<Extract = (String, usize), Error = warp::Rejection> + Copy
+{
+    warp::path!("rooms" / String / "shadow_bans" / usize).and(warp::delete())
+}
+
+pub fn flagged_messages() -> impl Filter<Extract = (String,), Error = warp::Rejection> + Copy {
+    warp::path!("rooms" / String / "flagged").and(warp::get())
+}
+
+pub fn message_history() -> impl Filter<Extract = (i64,), Error = warp::Rejection> + Copy {
+    warp::path!("messages" / i64 / "history").and(warp::get())
+}
+
+pub fn deleted_messages() -> impl Filter<Extract = (String,), Error = warp::Rejection> + Copy {
+    warp::path!("rooms" / String / "deleted").and(warp::get())
+}
+
+pub fn report_message() -> impl Filter<Extract = (String, NewReport), Error = warp::Rejection> + Copy
+{
+    warp::path!("rooms" / String / "reports")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(MAX_BODY_SIZE))
+        .and(warp::body::json())
+}
+
+pub fn online_users() -> impl Filter<Extract = (String,), Error = warp::Rejection> + Copy {
+    warp::path!("rooms" / String / "online").and(warp::get())
+}
+
+pub fn read_markers() -> impl Filter<Extract = (String,), Error = warp::Rejection> + Copy {
+    warp::path!("rooms" / String / "read_markers").and(warp::get())
+}
+
+pub fn room_messages(
+) -> impl Filter<Extract = (String, HistoryQuery), Error = warp::Rejection> + Copy {
+    warp::path!("rooms" / String / "messages")
+        .and(warp::get())
+        .and(warp::query::<HistoryQuery>())
+}
+
+pub fn post_message() -> impl Filter<Extract = (String, NewMessage), Error = warp::Rejection> + Copy
+{
+    warp::path!("rooms" / String / "messages")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(MAX_BODY_SIZE))
+        .and(warp::body::json())
+}
+
+pub fn room_stream(
+) -> impl Filter<Extract = (String, StreamQuery, Option<String>, Option<i64>), Error = warp::Rejection>
+       + Copy {
+    warp::path!("rooms" / String / "stream")
+        .and(warp::get())
+        .and(warp::query::<StreamQuery>())
+        .and(bearer_token())
+        .and(warp::header::optional::<i64>("last-event-id"))
+}
+
+pub fn graphql(
+) -> impl Filter<Extract = (Option<String>, async_graphql::Request), Error = warp::Rejection> + Copy
+{
+    warp::path!("graphql")
+        .and(warp::post())
+        .and(bearer_token())
+        .and(warp::body::content_length_limit(MAX_BODY_SIZE))
+        .and(warp::body::json())
+}
+
+// Subscriptions to the GraphQL API are made over a WebSocket, speaking the
+// `graphql-transport-ws` protocol or the older `graphql-ws` one. Browsers,
+// which cannot send headers to WebSockets, give their token in the payload of
+// the `connection_init` message instead.
+pub fn graphql_subscriptions(
+) -> impl Filter<Extract = (Ws, Option<String>, Option<String>), Error = warp::Rejection> + Copy {
+    warp::path!("graphql")
+        .and(warp::ws())
+        .and(warp::header::optional::<String>("sec-websocket-protocol"))
+        .and(bearer_token())
+}
+
+pub fn export_room() -> impl Filter<Extract = (String, ExportQuery), Error = warp::Rejection> + Copy
+{
+    warp::path!("rooms" / String / "export")
+        .and(warp::get())
+        .and(warp::query::<ExportQuery>())
+}
+
+pub fn search_room() -> impl Filter<Extract = (String, SearchQuery), Error = warp::Rejection> + Copy
+{
+    warp::path!("rooms" / String / "search")
+        .and(warp::get())
+        .and(warp::query::<SearchQuery>())
+}
+
+pub fn room_reports() -> impl Filter<Extract = (String,), Error = warp::Rejection> + Copy {
+    warp::path!("rooms" / String / "reports").and(warp::get())
+}
+
+pub fn resolve_report(
+) -> impl Filter<Extract = (String, i64, ReportResolution), Error = warp::Rejection> + Copy {
+    warp::path!("rooms" / String / "reports" / i64)
+        .and(warp::put())
+        .and(warp::body::content_length_limit(MAX_BODY_SIZE))
+        .and(warp::body::json())
+}
+
+pub fn create_invite() -> impl Filter<Extract = (String, NewInvite), Error = warp::Rejection> + Copy
+{
+    warp::path!("rooms" / String / "invites")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(MAX_BODY_SIZE))
+        .and(warp::body::json())
+}
+
+pub fn room_invites() -> impl Filter<Extract = (String,), Error = warp::Rejection> + Copy {
+    warp::path!("rooms" / String / "invites").and(warp::get())
+}
+
+pub fn revoke_invite() -> impl Filter<Extract = (String, i64), Error = warp::Rejection> + Copy {
+    warp::path!("rooms" / String / "invites" / i64).and(warp::delete())
+}
+
+pub fn create_hook() -> impl Filter<Extract = (String, NewHook), Error = warp::Rejection> + Copy {
+    warp::path!("rooms" / String / "hooks")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(MAX_BODY_SIZE))
+        .and(warp::body::json())
+}
+
+pub fn room_hooks() -> impl Filter<Extract = (String,), Error = warp::Rejection> + Copy {
+    warp::path!("rooms" / String / "hooks").and(warp::get())
+}
+
+pub fn revoke_hook() -> impl Filter<Extract = (String, i64), Error = warp::Rejection> + Copy {
+    warp::path!("rooms" / String / "hooks" / i64).and(warp::delete())
+}
+
+// Slack webhooks are sent either as JSON or as a form with the JSON in its
+// `payload` field.
+#[derive(Deserialize)]
+struct SlackForm {
+    payload: String,
+}
+
+pub fn post_hook(
+) -> impl Filter<Extract = (String, Result<SlackPayload, serde_json::Error>), Error = warp::Rejection>
+       + Copy {
+    let json = warp::body::json().map(Ok);
+    let form = warp::body::form().map(|form: SlackForm| serde_json::from_str(&form.payload));
+
+    warp::path!("hooks" / String)
+        .and(warp::post())
+        .and(warp::body::content_length_limit(MAX_BODY_SIZE))
+        .and(json.or(form).unify())
+}
+
+pub fn schedule_message(
+) -> impl Filter<Extract = (String, NewScheduledMessage), Error = warp::Rejection> + Copy {
+    warp::path!("rooms" / String / "scheduled")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(MAX_BODY_SIZE))
+        .and(warp::body::json())
+}
+
+pub fn scheduled_messages() -> impl Filter<Extract = (String,), Error = warp::Rejection> + Copy {
+    warp::path!("rooms" / String / "scheduled").and(warp::get())
+}
+
+pub fn cancel_scheduled_message(
+) -> impl Filter<Extract = (String, i64), Error = warp::Rejection> + Copy {
+    warp::path!("rooms" / String / "scheduled" / i64).and(warp::delete())
+}
+
+pub fn profile() -> impl Filter<Extract = (usize,), Error = warp::Rejection> + Copy {
+    warp::path!("users" / usize / "profile").and(warp::get())
+}
+
+pub fn update_profile(
+) -> impl Filter<Extract = (usize, ProfileUpdate), Error = warp::Rejection> + Copy {
+    warp::path!("users" / usize / "profile")
+        .and(warp::put())
+        .and(warp::body::content_length_limit(MAX_BODY_SIZE))
+        .and(warp::body::json())
+}
+
+pub fn delete_user(
+) -> impl Filter<Extract = (usize, DeleteUserQuery), Error = warp::Rejection> + Copy {
+    warp::path!("users" / usize)
+        .and(warp::delete())
+        .and(warp::query::<DeleteUserQuery>())
+}
+
+pub fn set_user_role() -> impl Filter<Extract = (usize, RoleUpdate), Error = warp::Rejection> + Copy
+{
+    warp::path!("users" / usize / "role")
+        .and(warp::put())
+        .and(warp::body::content_length_limit(MAX_BODY_SIZE))
+        .and(warp::body::json())
+}
+
+pub fn user_sessions() -> impl Filter<Extract = (usize,), Error = warp::Rejection> + Copy {
+    warp::path!("users" / usize / "sessions").and(warp::get())
+}
+
+pub fn revoke_session() -> impl Filter<Extract = (String,), Error = warp::Rejection> + Copy {
+    warp::path!("sessions" / String).and(warp::delete())
+}
+
+pub fn create_api_token(
+) -> impl Filter<Extract = (usize, NewApiToken), Error = warp::Rejection> + Copy {
+    warp::path!("users" / usize / "tokens")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(MAX_BODY_SIZE))
+        .and(warp::body::json())
+}
+
+pub fn api_tokens() -> impl Filter<Extract = (usize,), Error = warp::Rejection> + Copy {
+    warp::path!("users" / usize / "tokens").and(warp::get())
+}
+
+pub fn revoke_api_token() -> impl Filter<Extract = (usize, i64), Error = warp::Rejection> + Copy {
+    warp::path!("users" / usize / "tokens" / i64).and(warp::delete())
+}
+
+pub fn alert_keywords() -> impl Filter<Extract = (usize,), Error = warp::Rejection> + Copy {
+    warp::path!("users" / usize / "keywords").and(warp::get())
+}
+
+pub fn set_alert_keywords(
+) -> impl Filter<Extract = (usize, KeywordUpdate), Error = warp::Rejection> + Copy {
+    warp::path!("users" / usize / "keywords")
+        .and(warp::put())
+        .and(warp::body::content_length_limit(MAX_BODY_SIZE))
+        .and(warp::body::json())
+}
+
+pub fn alerts() -> impl Filter<Extract = (usize,), Error = warp::Rejection> + Copy {
+    warp::path!("users" / usize / "alerts").and(warp::get())
+}
+
+pub fn notification_levels() -> impl Filter<Extract = (usize,), Error = warp::Rejection> + Copy {
+    warp::path!("users" / usize / "notifications").and(warp::get())
+}
+
+pub fn set_notification_level(
+) -> impl Filter<Extract = (usize, String, LevelUpdate), Error = warp::Rejection> + Copy {
+    warp::path!("users" / usize / "notifications" / String)
+        .and(warp::put())
+        .and(warp::body::content_length_limit(MAX_BODY_SIZE))
+        .and(warp::body::json())
+}
+
+pub fn unread_counts() -> impl Filter<Extract = (), Error = warp::Rejection> + Copy {
+    warp::path!("users" / "me" / "unread").and(warp::get())
+}
+
+pub fn create_conversation(
+) -> impl Filter<Extract = (NewConversation,), Error = warp::Rejection> + Copy {
+    warp::path!("conversations")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(MAX_BODY_SIZE))
+        .and(warp::body::json())
+}
+
+pub fn conversations() -> impl Filter<Extract = (), Error = warp::Rejection> + Copy {
+    warp::path!("conversations").and(warp::get())
+}
+
+pub fn register() -> impl Filter<Extract = (Credentials,), Error = warp::Rejection> + Copy {
+    warp::path!("users" / "register")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(MAX_BODY_SIZE))
+        .and(warp::body::json())
+}
+
+pub fn login(
+) -> impl Filter<Extract = (Credentials, Option<String>), Error = warp::Rejection> + Copy {
+    warp::path!("users" / "login")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(MAX_BODY_SIZE))
+        .and(warp::body::json())
+        .and(user_agent())
+}
+
+pub fn forgot_password() -> impl Filter<Extract = (ForgotPassword,), Error = warp::Rejection> + Copy
+{
+    warp::path!("users" / "forgot")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(MAX_BODY_SIZE))
+        .and(warp::body::json())
+}
+
+pub fn reset_password() -> impl Filter<Extract = (PasswordReset,), Error = warp::Rejection> + Copy {
+    warp::path!("users" / "reset")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(MAX_BODY_SIZE))
+        .and(warp::body::json())
+}
+
+pub fn totp_enroll() -> impl Filter<Extract = (Credentials,), Error = warp::Rejection> + Copy {
+    warp::path!("users" / "totp" / "enroll")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(MAX_BODY_SIZE))
+        .and(warp::body::json())
+}
+
+pub fn totp_confirm() -> impl Filter<Extract = (Credentials,), Error = warp::Rejection> + Copy {
+    warp::path!("users" / "totp" / "confirm")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(MAX_BODY_SIZE))
+        .and(warp::body::json())
+}
+
+pub fn logout() -> impl Filter<Extract = (), Error = warp::Rejection> + Copy {
+    warp::path!("users" / "logout").and(warp::post())
+}
+
+pub fn oauth_login() -> impl Filter<Extract = (String,), Error = warp::Rejection> + Copy {
+    warp::path!("auth" / String / "login").and(warp::get())
+}
+
+pub fn oauth_callback() -> impl Filter<
+    Extract = (String, OAuthCallback, Option<String>, Option<String>),
+    Error = warp::Rejection,
+> + Copy {
+    warp::path!("auth" / String / "callback")
+        .and(warp::get())
+        .and(warp::query::<OAuthCallback>())
+        .and(warp::cookie::optional::<String>(oauth::STATE_COOKIE))
+        .and(user_agent())
 }
 
 pub fn index(
@@ -13,10 +948,23 @@ pub fn index(
     warp::path::end().map(|| warp::reply::html(INDEX_HTML))
 }
 
+pub fn openapi() -> impl Filter<Extract = (), Error = warp::Rejection> + Copy {
+    warp::path!("openapi.json").and(warp::get())
+}
+
+// Swagger UI is served from the files under `/swagger-ui/`, whose own links
+// are relative to it.
+pub fn swagger_ui() -> impl Filter<Extract = (FullPath, Tail), Error = warp::Rejection> + Copy {
+    warp::path("swagger-ui")
+        .and(warp::get())
+        .and(warp::path::full())
+        .and(warp::path::tail())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::{html::INDEX_HTML, routes};
+    use crate::{handlers, html::INDEX_HTML, routes};
     use futures::future;
     use warp::test;
 
@@ -32,7 +980,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_ws_connection() {
-        let chat = routes::chat().map(|ws: Ws, _| ws.on_upgrade(|_| future::ready(())));
+        let chat = routes::chat().map(|ws: Ws, _, _, _| ws.on_upgrade(|_| future::ready(())));
 
         test::ws()
             .path("/chat/room1")
@@ -48,11 +996,132 @@ mod tests {
     }
 
     #[tokio::test]
-    #[should_panic]
-    async fn test_ws_connection_panics() {
-        let chat = routes::chat().map(|ws: Ws, _| ws.on_upgrade(|_| future::ready(())));
+    async fn test_admin_guard() {
+        let admin = routes::admin_guard(Some(String::from("secret")))
+            .map(|| "ok")
+            .recover(handlers::recover);
+
+        let response = test::request().reply(&admin).await;
+        assert_eq!(response.status(), 401);
+        assert!(response.headers()["www-authenticate"]
+            .to_str()
+            .unwrap()
+            .starts_with("Basic realm="));
+
+        let response = test::request()
+            .header("authorization", "Bearer wrong")
+            .reply(&admin)
+            .await;
+        assert_eq!(response.status(), 401);
+
+        let response = test::request()
+            .header("authorization", "Bearer secret")
+            .reply(&admin)
+            .await;
+        assert_eq!(response.status(), 200);
+
+        let basic = format!("Basic {}", BASE64.encode("admin:secret"));
+        let response = test::request()
+            .header("authorization", basic)
+            .reply(&admin)
+            .await;
+        assert_eq!(response.status(), 200);
+
+        // Admin routes do not exist without an admin token
+        let disabled = routes::admin_guard(None).map(|| "ok");
+        let response = test::request()
+            .header("authorization", "Bearer secret")
+            .reply(&disabled)
+            .await;
+        assert_eq!(response.status(), 404);
+    }
+
+    #[tokio::test]
+    async fn test_client_ip() {
+        let direct = routes::client_ip(false);
+        let ip = test::request()
+            .remote_addr("10.0.0.1:4000".parse().unwrap())
+            .header("x-forwarded-for", "192.0.2.1")
+            .filter(&direct)
+            .await
+            .unwrap();
+        assert_eq!(ip, Some("10.0.0.1".parse().unwrap()));
+
+        // Only the last forwarded address is trusted
+        let proxied = routes::client_ip(true);
+        let ip = test::request()
+            .remote_addr("10.0.0.1:4000".parse().unwrap())
+            .header("x-forwarded-for", "198.51.100.1, 192.0.2.1")
+            .filter(&proxied)
+            .await
+            .unwrap();
+        assert_eq!(ip, Some("192.0.2.1".parse().unwrap()));
+
+        let ip = test::request()
+            .remote_addr("10.0.0.1:4000".parse().unwrap())
+            .header(
+                "forwarded",
+                r#"for=198.51.100.1, for="[2001:db8::1]:4711";proto=https"#,
+            )
+            .header("x-forwarded-for", "192.0.2.1")
+            .filter(&proxied)
+            .await
+            .unwrap();
+        assert_eq!(ip, Some("2001:db8::1".parse().unwrap()));
+
+        let ip = test::request()
+            .remote_addr("10.0.0.1:4000".parse().unwrap())
+            .filter(&proxied)
+            .await
+            .unwrap();
+        assert_eq!(ip, Some("10.0.0.1".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_post_hook() {
+        let hook = routes::post_hook();
+
+        let (token, payload) = test::request()
+            .method("POST")
+            .path("/hooks/abc")
+            .header("content-type", "application/json")
+            .body(r#"{"text":"Build passed"}"#)
+            .filter(&hook)
+            .await
+            .unwrap();
+        assert_eq!(token, "abc");
+        assert_eq!(payload.unwrap().message().unwrap(), "Build passed");
+
+        // As some integrations send it
+        let (_, payload) = test::request()
+            .method("POST")
+            .path("/hooks/abc")
+            .header("content-type", "application/x-www-form-urlencoded")
+            .body("payload=%7B%22text%22%3A%22Build+failed%22%7D")
+            .filter(&hook)
+            .await
+            .unwrap();
+        assert_eq!(payload.unwrap().message().unwrap(), "Build failed");
+
+        let (_, payload) = test::request()
+            .method("POST")
+            .path("/hooks/abc")
+            .header("content-type", "application/x-www-form-urlencoded")
+            .body("payload=not+json")
+            .filter(&hook)
+            .await
+            .unwrap();
+        assert!(payload.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_ws_connection_without_room() {
+        let chat = routes::chat().map(|ws: Ws, room: Option<String>, _, _| {
+            assert_eq!(room, None);
+            ws.on_upgrade(|_| future::ready(()))
+        });
 
-        // Should panic, since no room specified -- default should be 'public'
+        // Left to join the default room
         test::ws()
             .path("/chat")
             .handshake(chat)
@@ -0,0 +1,249 @@
+use std::time::Duration;
+
+use anyhow::anyhow;
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::{auth, room};
+
+// Furthest into the future messages may be scheduled, a year.
+pub const MAX_DELAY_SECS: u64 = 365 * 24 * 60 * 60;
+
+// Most messages a user may have waiting to be sent to a room.
+pub const MAX_SCHEDULED_PER_ROOM: usize = 50;
+
+// Request body of the route scheduling a message to a room.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct NewScheduledMessage {
+    pub text: String,
+    // Number of seconds from now the message is sent in
+    pub delay_secs: u64,
+}
+
+impl NewScheduledMessage {
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        if self.text.trim().is_empty() {
+            return Err(anyhow!("Messages may not be empty"));
+        }
+
+        if self.delay_secs == 0 || self.delay_secs > MAX_DELAY_SECS {
+            return Err(anyhow!(
+                "Messages must be scheduled between 1 and {} seconds ahead",
+                MAX_DELAY_SECS
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+// A message waiting to be sent to a room.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct ScheduledMessage {
+    pub id: i64,
+    pub room: String,
+    pub user_id: usize,
+    pub text: String,
+    pub deliver_at: String,
+    pub created_at: String,
+}
+
+// Schedules a message of `user_id` to `room_name`, returning it. Returns None
+// if the room does not exist, and an error if the user already has too many
+// messages waiting to be sent to it.
+pub fn schedule(
+    conn: &Connection,
+    room_name: &str,
+    user_id: usize,
+    new_message: &NewScheduledMessage,
+) -> Result<Option<ScheduledMessage>, anyhow::Error> {
+    if room::settings(conn, room_name)?.is_none() {
+        return Ok(None);
+    }
+    if scheduled_messages(conn, room_name, user_id)?.len() >= MAX_SCHEDULED_PER_ROOM {
+        return Err(anyhow!(
+            "Can not schedule more than {} messages to a room",
+            MAX_SCHEDULED_PER_ROOM
+        ));
+    }
+
+    let delay = auth::ttl_modifier(Duration::from_secs(new_message.delay_secs));
+    conn.execute(
+        "INSERT INTO scheduled_messages (room_name, user_id, message, deliver_at)
+            VALUES (?1, ?2, ?3, datetime('now', ?4))",
+        params![room_name, user_id, new_message.text, delay],
+    )?;
+
+    let scheduled = conn.query_row(
+        &format!(
+            "SELECT {} FROM scheduled_messages WHERE scheduled_id = ?1",
+            SCHEDULED_COLUMNS
+        ),
+        params![conn.last_insert_rowid()],
+        scheduled_from_row,
+    )?;
+
+    Ok(Some(scheduled))
+}
+
+// Messages `user_id` scheduled to `room_name` that are still waiting to be
+// sent, soonest first.
+pub fn scheduled_messages(
+    conn: &Connection,
+    room_name: &str,
+    user_id: usize,
+) -> Result<Vec<ScheduledMessage>, rusqlite::Error> {
+    let mut stmt = conn.prepare_cached(&format!(
+        "SELECT {} FROM scheduled_messages
+            WHERE room_name = ?1 AND user_id = ?2
+            ORDER BY deliver_at, scheduled_id",
+        SCHEDULED_COLUMNS
+    ))?;
+    let scheduled = stmt
+        .query_map(params![room_name, user_id], scheduled_from_row)?
+        .collect();
+
+    scheduled
+}
+
+// Cancels message `scheduled_id` that `user_id` scheduled to `room_name`,
+// returning whether it was still waiting to be sent.
+pub fn cancel(
+    conn: &Connection,
+    room_name: &str,
+    user_id: usize,
+    scheduled_id: i64,
+) -> Result<bool, rusqlite::Error> {
+    let deleted = conn.execute(
+        "DELETE FROM scheduled_messages
+            WHERE scheduled_id = ?1 AND room_name = ?2 AND user_id = ?3",
+        params![scheduled_id, room_name, user_id],
+    )?;
+
+    Ok(deleted > 0)
+}
+
+// Takes the messages due to be sent, in the order they were due, so that each
+// is only ever sent once.
+pub fn take_due(conn: &Connection) -> Result<Vec<ScheduledMessage>, rusqlite::Error> {
+    let mut stmt = conn.prepare_cached(&format!(
+        "SELECT {} FROM scheduled_messages
+            WHERE deliver_at <= datetime('now')
+            ORDER BY deliver_at, scheduled_id",
+        SCHEDULED_COLUMNS
+    ))?;
+    let due = stmt
+        .query_map([], scheduled_from_row)?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    for scheduled in &due {
+        conn.execute(
+            "DELETE FROM scheduled_messages WHERE scheduled_id = ?1",
+            params![scheduled.id],
+        )?;
+    }
+
+    Ok(due)
+}
+
+const SCHEDULED_COLUMNS: &str = "scheduled_id, room_name, user_id, message, deliver_at, created_at";
+
+fn scheduled_from_row(row: &rusqlite::Row) -> Result<ScheduledMessage, rusqlite::Error> {
+    Ok(ScheduledMessage {
+        id: row.get(0)?,
+        room: row.get(1)?,
+        user_id: row.get(2)?,
+        text: row.get(3)?,
+        deliver_at: row.get(4)?,
+        created_at: row.get(5)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+
+    fn setup() -> (Connection, usize, usize) {
+        let conn = Connection::open_in_memory().unwrap();
+        db::init_schema(&conn).unwrap();
+        let alice = auth::create_user(&conn, "alice", "hash").unwrap().unwrap();
+        let bob = auth::create_user(&conn, "bob", "hash").unwrap().unwrap();
+        room::record_room(&conn, "room1", alice, true).unwrap();
+
+        (conn, alice, bob)
+    }
+
+    fn new_message(text: &str) -> NewScheduledMessage {
+        NewScheduledMessage {
+            text: String::from(text),
+            delay_secs: 60,
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(new_message("Hi").validate().is_ok());
+        assert!(new_message("  ").validate().is_err());
+
+        let mut scheduled = new_message("Hi");
+        scheduled.delay_secs = 0;
+        assert!(scheduled.validate().is_err());
+        scheduled.delay_secs = MAX_DELAY_SECS + 1;
+        assert!(scheduled.validate().is_err());
+    }
+
+    #[test]
+    fn test_schedule() {
+        let (conn, alice, bob) = setup();
+
+        let scheduled = schedule(&conn, "room1", alice, &new_message("Later"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(scheduled.text, "Later");
+        assert!(schedule(&conn, "room2", alice, &new_message("Later"))
+            .unwrap()
+            .is_none());
+
+        // Users only see and cancel their own messages
+        assert_eq!(
+            scheduled_messages(&conn, "room1", alice).unwrap(),
+            vec![scheduled]
+        );
+        assert!(scheduled_messages(&conn, "room1", bob).unwrap().is_empty());
+        let id = scheduled_messages(&conn, "room1", alice).unwrap()[0].id;
+        assert!(!cancel(&conn, "room1", bob, id).unwrap());
+        assert!(cancel(&conn, "room1", alice, id).unwrap());
+        assert!(!cancel(&conn, "room1", alice, id).unwrap());
+
+        for _ in 0..MAX_SCHEDULED_PER_ROOM {
+            schedule(&conn, "room1", alice, &new_message("Spam")).unwrap();
+        }
+        assert!(schedule(&conn, "room1", alice, &new_message("Spam")).is_err());
+        assert!(schedule(&conn, "room1", bob, &new_message("Hi")).is_ok());
+    }
+
+    #[test]
+    fn test_take_due() {
+        let (conn, alice, _) = setup();
+
+        schedule(&conn, "room1", alice, &new_message("Later")).unwrap();
+        conn.execute(
+            "INSERT INTO scheduled_messages (room_name, user_id, message, deliver_at) VALUES
+                ('room1', ?1, 'second', datetime('now', '-1 seconds')),
+                ('room1', ?1, 'first', datetime('now', '-2 seconds'))",
+            params![alice],
+        )
+        .unwrap();
+
+        // Due messages are taken once, in the order they were due
+        let due = take_due(&conn).unwrap();
+        assert_eq!(
+            due.iter().map(|s| s.text.as_str()).collect::<Vec<_>>(),
+            vec!["first", "second"]
+        );
+        assert!(take_due(&conn).unwrap().is_empty());
+        assert_eq!(scheduled_messages(&conn, "room1", alice).unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,208 @@
+use anyhow::anyhow;
+use rusqlite::{params, Connection};
+use serde::Serialize;
+
+use crate::compression::MessageText;
+
+// Number of matches returned per page, unless asked for fewer, and the most
+// that may be asked for.
+pub const DEFAULT_LIMIT: usize = 20;
+pub const MAX_LIMIT: usize = 100;
+
+// Longest a search may be, in characters.
+pub const MAX_QUERY_LENGTH: usize = 200;
+
+// A message matching a search.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct SearchHit {
+    pub id: i64,
+    pub seq: Option<i64>,
+    pub user_id: usize,
+    pub nick: Option<String>,
+    pub text: String,
+    pub created_at: String,
+    pub edited_at: Option<String>,
+}
+
+// A page of matches, best first.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct SearchResults {
+    pub hits: Vec<SearchHit>,
+    // Offset of the next page, if there are more matches
+    pub next_offset: Option<usize>,
+}
+
+// Turns what users search for into an FTS5 query matching messages that
+// contain every word of it. Words are quoted, so that FTS5 syntax is searched
+// for as is rather than failing to parse.
+pub fn match_query(q: &str) -> Result<String, anyhow::Error> {
+    if q.chars().count() > MAX_QUERY_LENGTH {
+        return Err(anyhow!(
+            "Searches must be at most {} characters long",
+            MAX_QUERY_LENGTH
+        ));
+    }
+
+    let words = q
+        .split_whitespace()
+        .map(|word| format!("\"{}\"", word.replace('"', "\"\"")))
+        .collect::<Vec<_>>();
+    if words.is_empty() {
+        return Err(anyhow!("Searches must contain a word"));
+    }
+
+    Ok(words.join(" "))
+}
+
+// Messages of `room_name` that `viewer` may see matching `match_query`, as
+// made by `match_query`, best first, skipping the first `offset`. Deleted
+// messages are left out.
+pub fn search(
+    conn: &Connection,
+    room_name: &str,
+    viewer: usize,
+    match_query: &str,
+    limit: usize,
+    offset: usize,
+) -> Result<SearchResults, rusqlite::Error> {
+    // One more match than asked for tells whether there is another page
+    let mut stmt = conn.prepare_cached(
+        "SELECT m.message_id, m.seq, m.user_id, m.nickname, m.message, m.created_at,
+                m.edited_at
+            FROM message_search s JOIN chat_messages m ON m.message_id = s.rowid
+            WHERE message_search MATCH ?1 AND m.room_name = ?2 AND m.deleted_at IS NULL
+                AND (NOT m.shadowed OR m.user_id = ?3)
+            ORDER BY s.rank, m.message_id DESC
+            LIMIT ?4 OFFSET ?5",
+    )?;
+    let mut hits = stmt
+        .query_map(
+            params![
+                match_query,
+                room_name,
+                viewer,
+                limit as i64 + 1,
+                offset as i64
+            ],
+            |row| {
+                Ok(SearchHit {
+                    id: row.get(0)?,
+                    seq: row.get(1)?,
+                    user_id: row.get(2)?,
+                    nick: row.get(3)?,
+                    text: row.get::<_, MessageText>(4)?.into(),
+                    created_at: row.get(5)?,
+                    edited_at: row.get(6)?,
+                })
+            },
+        )?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let next_offset = if hits.len() > limit {
+        hits.truncate(limit);
+        Some(offset + limit)
+    } else {
+        None
+    };
+
+    Ok(SearchResults { hits, next_offset })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+
+    fn setup() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        db::init_schema(&conn).unwrap();
+        conn.execute(
+            "INSERT INTO chat_messages (message_id, user_id, room_name, message, shadowed)
+                VALUES (1, 1, 'room1', 'Lunch at noon?', 0),
+                    (2, 2, 'room1', 'Lunch! Lunch is the best', 0),
+                    (3, 1, 'room2', 'Lunch elsewhere', 0),
+                    (4, 3, 'room1', 'Spam lunch', 1)",
+            [],
+        )
+        .unwrap();
+
+        conn
+    }
+
+    fn find(
+        conn: &Connection,
+        viewer: usize,
+        q: &str,
+        limit: usize,
+        offset: usize,
+    ) -> SearchResults {
+        search(
+            conn,
+            "room1",
+            viewer,
+            &match_query(q).unwrap(),
+            limit,
+            offset,
+        )
+        .unwrap()
+    }
+
+    fn ids(results: &SearchResults) -> Vec<i64> {
+        results.hits.iter().map(|hit| hit.id).collect()
+    }
+
+    #[test]
+    fn test_match_query() {
+        assert_eq!(match_query(" lunch  noon ").unwrap(), "\"lunch\" \"noon\"");
+        assert_eq!(match_query("NOT \"a").unwrap(), "\"NOT\" \"\"\"a\"");
+        assert!(match_query("  ").is_err());
+        assert!(match_query(&"a".repeat(MAX_QUERY_LENGTH + 1)).is_err());
+    }
+
+    #[test]
+    fn test_search() {
+        let conn = setup();
+
+        // Better matches come first. Shadowed messages are only found by their
+        // author
+        let results = find(&conn, 1, "lunch", 10, 0);
+        assert_eq!(ids(&results), vec![2, 1]);
+        assert_eq!(results.next_offset, None);
+        assert_eq!(ids(&find(&conn, 3, "lunch", 10, 0)).len(), 3);
+        assert_eq!(ids(&find(&conn, 1, "lunch noon", 10, 0)), vec![1]);
+
+        // Syntax is searched for as is
+        assert!(find(&conn, 1, "NOT (", 10, 0).hits.is_empty());
+
+        // Matches are paged through
+        let results = find(&conn, 1, "lunch", 1, 0);
+        assert_eq!(ids(&results), vec![2]);
+        assert_eq!(results.next_offset, Some(1));
+        let results = find(&conn, 1, "lunch", 1, 1);
+        assert_eq!(ids(&results), vec![1]);
+        assert_eq!(results.next_offset, None);
+    }
+
+    #[test]
+    fn test_search_index() {
+        let conn = setup();
+
+        // Edits are searched, deleted messages are not
+        db::edit_message(&conn, 1, 1, "room1", "Dinner at eight?", false).unwrap();
+        assert_eq!(ids(&find(&conn, 1, "lunch", 10, 0)), vec![2]);
+        assert_eq!(ids(&find(&conn, 1, "dinner", 10, 0)), vec![1]);
+
+        db::delete_message(&conn, 2, "room1", 2, None).unwrap();
+        assert!(find(&conn, 1, "lunch", 10, 0).hits.is_empty());
+
+        conn.execute("DELETE FROM chat_messages", []).unwrap();
+        let indexed: i64 = conn
+            .query_row(
+                "SELECT COUNT(*) FROM message_search WHERE message_search MATCH 'dinner'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(indexed, 0);
+    }
+}
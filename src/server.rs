@@ -1,24 +1,291 @@
 use std::{
+    collections::HashMap,
+    net::IpAddr,
     path::PathBuf,
-    sync::atomic::{AtomicUsize, Ordering},
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
 };
 
+use anyhow::anyhow;
+use serde::de::DeserializeOwned;
 use tokio::sync::{
     broadcast,
     mpsc::{self},
+    oneshot, RwLock,
 };
-use warp::{ws::Ws, Filter};
+use warp::Filter;
 
 use crate::{
-    db::spawn_db,
+    auth::{
+        self,
+        oauth::{self, OAuthProvider},
+        reset::{self, ResetDelivery},
+        scope::Scope,
+        JwtKeys, Principal,
+    },
+    authz::{self, Role},
+    classifier::{self, ContentHook},
+    cluster::{Cluster, RoomRead, RoomRequest},
+    config::Config,
+    db::{self, spawn_db, spawn_memory_db, DbFailureAction, DbTx, MessageIds},
+    discord::{self, DiscordApi},
+    federation::{Federation, Link},
+    filter::WordFilter,
+    graphql, grpc,
+    guest::Guest,
+    handlers::{self, NEXT_CONNECTION_ID},
+    irc, mail,
+    room::{self, IdleRoomAction},
     routes,
+    schedule::{self, ScheduledMessage},
     shutdown::Shutdown,
-    user::{add_user_to_room, Rooms, User},
+    sink::{self, EventSink},
+    spam::{DuplicateGuard, FloodGuard},
+    store::{MessageChange, StoreKind},
+    telemetry::Telemetry,
+    user::{self, Nicks, Rooms, User, UserTx, DETACHED_CONN_ID},
+    xmpp,
 };
 
-static NEXT_USER_ID: AtomicUsize = AtomicUsize::new(1);
+// State shared by every connection and request handler.
+#[derive(Clone)]
+pub struct ServerState {
+    pub config: Arc<Config>,
+
+    pub db_tx: DbTx,
+
+    pub rooms: Rooms,
+
+    pub nicks: Nicks,
+
+    pub message_ids: MessageIds,
+
+    pub jwt: Arc<JwtKeys>,
+
+    // OAuth providers users can log in with, by name
+    pub oauth_providers: Arc<HashMap<String, OAuthProvider>>,
+
+    pub http_client: reqwest::Client,
+
+    // How password reset tokens reach users
+    pub reset_delivery: Arc<dyn ResetDelivery>,
+
+    pub word_filter: Arc<WordFilter>,
+
+    // Refuses messages users already sent too many times lately
+    pub duplicate_guard: Arc<DuplicateGuard>,
+
+    // Kicks users sending too many messages at once
+    pub flood_guard: Arc<FloodGuard>,
+
+    // Lets an external classifier block or flag messages
+    pub content_hook: Arc<ContentHook>,
+
+    // Shares rooms with other instances of the server, if clustered
+    pub cluster: Option<Cluster>,
+
+    // Where messages, joins and leaves are emitted to, if anywhere
+    pub event_sink: Option<EventSink>,
+
+    // Links to the rooms hosted by other servers
+    pub federation: Federation,
+}
+
+impl ServerState {
+    // How long login sessions last without being used.
+    pub fn session_ttl(&self) -> Duration {
+        Duration::from_secs(self.config.session_ttl_secs)
+    }
+
+    // Answers `read` from the messages of `room_name`, as kept by the peer
+    // owning the room if clustered.
+    pub async fn read_room<T>(&self, room_name: &str, read: RoomRead) -> Result<T, anyhow::Error>
+    where
+        T: DeserializeOwned,
+    {
+        let owner = match &self.cluster {
+            Some(cluster) => cluster.owner(room_name).await.map(|owner| (cluster, owner)),
+            None => None,
+        };
+        let answer = match owner {
+            Some((cluster, owner)) => {
+                let request = RoomRequest::Read {
+                    room: String::from(room_name),
+                    read,
+                };
+                cluster.forward(&owner, &request).await?
+            }
+            None => {
+                let room_name = String::from(room_name);
+                db::read(&self.db_tx, move |conn| read.run(conn, &room_name)).await?
+            }
+        };
+
+        Ok(serde_json::from_value(answer)?)
+    }
+
+    // A `User` acting as `user_id` in `room_name` without a connection, e.g. to
+    // send the messages they scheduled. What it is sent goes nowhere.
+    pub async fn detached_user(
+        &self,
+        room_name: &str,
+        user_id: usize,
+    ) -> Result<User, anyhow::Error> {
+        // Users who are not connected are shown with the nickname they last had
+        let nick = match self.nicks.read().await.get(&user_id).cloned() {
+            Some(nick) => Some(nick),
+            None => db::query(&self.db_tx, move |conn| db::nickname(conn, user_id)).await?,
+        };
+        let nicks = nick.map(|nick| (user_id, nick)).into_iter().collect();
+        let (user_tx, _) = mpsc::unbounded_channel();
+
+        Ok(User {
+            conn_id: DETACHED_CONN_ID,
+            user_id,
+            nicks: Arc::new(RwLock::new(nicks)),
+            chat_room: String::from(room_name),
+            user_tx,
+            db_tx: self.db_tx.clone(),
+            message_ids: self.message_ids.clone(),
+            granted_role: Role::Member,
+            guest: None,
+            session_id: None,
+            addr: None,
+            word_filter: self.word_filter.clone(),
+            filter_action: self.config.filter_action(room_name),
+            duplicate_guard: self.duplicate_guard.clone(),
+            flood_guard: self.flood_guard.clone(),
+            content_hook: self.content_hook.clone(),
+            may_write: true,
+            away_after: None,
+            offline_after: None,
+            principal: Principal::user(user_id),
+            config: self.config.clone(),
+            cluster: self.cluster.clone(),
+            event_sink: self.event_sink.clone(),
+            federation: self.federation.clone(),
+            remote_conn: None,
+        })
+    }
+
+    // A `User` for a connection to `room_name` made through a gateway to
+    // another chat protocol, e.g. IRC, acting as `principal`. It is a guest
+    // if `is_guest` is set, and what it is sent goes to `user_tx`.
+    pub fn gateway_user(
+        &self,
+        room_name: &str,
+        principal: Principal,
+        is_guest: bool,
+        addr: Option<IpAddr>,
+        user_tx: UserTx,
+    ) -> User {
+        let guest_mode = self.config.guest_mode(room_name);
+        User {
+            conn_id: NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed),
+            user_id: principal.user_id,
+            nicks: self.nicks.clone(),
+            chat_room: String::from(room_name),
+            user_tx,
+            db_tx: self.db_tx.clone(),
+            message_ids: self.message_ids.clone(),
+            granted_role: Role::Member,
+            guest: is_guest.then(|| Guest::new(guest_mode, self.config.guest_rate_limit)),
+            session_id: None,
+            addr,
+            word_filter: self.word_filter.clone(),
+            filter_action: self.config.filter_action(room_name),
+            duplicate_guard: self.duplicate_guard.clone(),
+            flood_guard: self.flood_guard.clone(),
+            content_hook: self.content_hook.clone(),
+            may_write: principal.allows(&Scope::Write(Some(String::from(room_name)))),
+            away_after: None,
+            offline_after: None,
+            principal,
+            config: self.config.clone(),
+            cluster: self.cluster.clone(),
+            event_sink: self.event_sink.clone(),
+            federation: self.federation.clone(),
+            remote_conn: None,
+        }
+    }
+}
+
+// A client following a room through a gateway without a connection of its
+// own, e.g. over server-sent events, which leaves the room once it goes away,
+// i.e. once this is dropped.
+pub struct Follower {
+    // Only taken on drop
+    user: Option<User>,
+    is_guest: bool,
+    state: ServerState,
+}
+
+impl Follower {
+    pub fn new(user: User, is_guest: bool, state: ServerState) -> Self {
+        Follower {
+            user: Some(user),
+            is_guest,
+            state,
+        }
+    }
+}
+
+impl Drop for Follower {
+    fn drop(&mut self) {
+        let (user, is_guest, state) = match self.user.take() {
+            Some(user) => (user, self.is_guest, self.state.clone()),
+            None => return,
+        };
+
+        tokio::task::spawn(async move {
+            user::remove_user_from_room(&user, &state.rooms).await;
+
+            // Guests only hold their nickname while connected
+            if is_guest {
+                let user_id = user.user_id;
+                if let Err(e) = db::query(&state.db_tx, move |conn| {
+                    db::release_nickname(conn, user_id)
+                })
+                .await
+                {
+                    eprintln!("Failed to release nickname(uid={}): {}", user_id, e);
+                }
+                state.nicks.write().await.remove(&user_id);
+            }
+        });
+    }
+}
+
+// How often messages past the retention period of their room are deleted, and
+// idle rooms and guests are cleaned up.
+const PURGE_INTERVAL: Duration = Duration::from_secs(60);
+
+// How long guests are kept once they left, so that they can still be
+// sanctioned, which applies to their address.
+const GUEST_RETENTION: Duration = Duration::from_secs(24 * 60 * 60);
+
+// How often scheduled messages are checked for being due.
+const SCHEDULE_INTERVAL: Duration = Duration::from_secs(1);
+
+// How long outgoing HTTP requests, such as OAuth token exchange and the Discord
+// relay, may take, so a hung provider can't stall them forever.
+const HTTP_TIMEOUT: Duration = Duration::from_secs(30);
+const HTTP_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
 
 pub async fn run(port: u16, db_path: PathBuf) {
+    run_with_config(Config::new(port, db_path)).await
+}
+
+pub async fn run_with_config(config: Config) {
+    let port = config.port;
+
+    // Exports spans, from the connections messages are received on to their
+    // commit to the DB, to an OTLP collector
+    let telemetry = config.otlp_endpoint.as_ref().map(|endpoint| {
+        Telemetry::export(endpoint, &config.otel_service_name)
+            .expect("Unable to set up span export")
+    });
+
     // Broadcast channel for sending a shutdown message to all active connections
     let (notify_shutdown, _) = broadcast::channel(1);
     let (shutdown_complete_tx, mut shutdown_complete_rx) = mpsc::channel(1);
@@ -27,76 +294,1106 @@ pub async fn run(port: u16, db_path: PathBuf) {
 
     // Spawning of a dedicated thread to handle DB writes
     let (db_tx, db_rx) = mpsc::unbounded_channel();
+    let db_path = config.db_path.clone();
+    let pragmas = config.pragmas();
+    let store_options = config.store_options();
+    let commit_policy = config.commit_policy();
+    let dead_letters = config.dead_letters();
+    let store = config.store;
+    let on_db_failure = config.on_db_failure;
+    if store == StoreKind::Memory && store_options.postgres.is_some() {
+        eprintln!("Messages kept in memory are not mirrored to Postgres");
+    }
+    // Told should the DB thread fail
+    let (db_failed_tx, db_failed_rx) = oneshot::channel();
     std::thread::spawn(move || {
-        let db_path = db_path.clone();
-        spawn_db(
-            &db_path,
-            db_rx,
-            Shutdown::new(shutdown_listener, db_shutdown_complete_tx),
-        )
+        let shutdown = Shutdown::new(shutdown_listener, db_shutdown_complete_tx);
+        // Held for as long as the DB is written to, so that imports are refused
+        let _lock = (store == StoreKind::Sqlite)
+            .then(|| db::lock(&db_path))
+            .transpose()
+            .unwrap_or_else(|e| {
+                eprintln!("Unable to lock DB, imports will not be refused: {}", e);
+                None
+            });
+        let result = match store {
+            StoreKind::Sqlite => spawn_db(
+                &db_path,
+                pragmas,
+                store_options,
+                commit_policy,
+                dead_letters,
+                db_rx,
+                shutdown,
+            ),
+            StoreKind::Memory => spawn_memory_db(commit_policy, dead_letters, db_rx, shutdown),
+        };
+        if let Err(e) = result {
+            eprintln!("DB can no longer be written to: {}", e);
+            let _ = db_failed_tx.send(());
+        }
     });
 
-    // Defining stateful data + DB channel
+    // Deletes messages once past the retention period of their room
+    tokio::task::spawn(purge_expired_messages(
+        db_tx.clone(),
+        config.deleted_retention_secs,
+        Shutdown::new(notify_shutdown.subscribe(), shutdown_complete_tx.clone()),
+    ));
+
+    // Checkpoints and vacuums the DB, logging its size
+    if config.maintenance_interval_secs > 0 {
+        tokio::task::spawn(maintain_db(
+            db_tx.clone(),
+            Duration::from_secs(config.maintenance_interval_secs),
+            config.vacuum_pages,
+            Shutdown::new(notify_shutdown.subscribe(), shutdown_complete_tx.clone()),
+        ));
+    }
+
     let rooms = Rooms::default();
-    let rooms = warp::any().map(move || rooms.clone());
-    // A DB channel transmission handle/sender should be passed to each connection
-    let db_tx = warp::any().map(move || db_tx.clone());
+    // Shares rooms with the other instances connected to the same Redis server,
+    // or with the peers given
+    let node_id = config.node_id.unwrap_or_default();
+    let cluster = match (&config.redis_url, &config.cluster_secret) {
+        (Some(url), _) => Some(
+            Cluster::connect(
+                url,
+                node_id,
+                rooms.clone(),
+                Shutdown::new(notify_shutdown.subscribe(), shutdown_complete_tx.clone()),
+            )
+            .await
+            .expect("Unable to connect to Redis"),
+        ),
+        (None, Some(secret)) if !config.peers.is_empty() || config.peer_dns.is_some() => {
+            Some(Cluster::discover(
+                config.peers.clone(),
+                config.peer_dns.clone(),
+                secret,
+                node_id,
+                Shutdown::new(notify_shutdown.subscribe(), shutdown_complete_tx.clone()),
+            ))
+        }
+        _ => None,
+    };
+    // Emits messages, joins and leaves for systems downstream to consume
+    let event_sink = match &config.event_sink_url {
+        Some(url) => {
+            let publisher = sink::connect(url, &config.event_topic)
+                .await
+                .expect("Unable to connect to event sink");
+            Some(EventSink::new(
+                publisher,
+                Shutdown::new(notify_shutdown.subscribe(), shutdown_complete_tx.clone()),
+            ))
+        }
+        None => None,
+    };
+    // Links the rooms hosted by other servers to them
+    let federation = match &config.federation_token {
+        Some(token) => Federation::new(
+            config
+                .federated_rooms
+                .iter()
+                .map(|federated| {
+                    let link = Link::connect(
+                        federated.clone(),
+                        token.clone(),
+                        rooms.clone(),
+                        Shutdown::new(notify_shutdown.subscribe(), shutdown_complete_tx.clone()),
+                    );
+                    (federated.room.clone(), link)
+                })
+                .collect(),
+        ),
+        None => Federation::default(),
+    };
+    // Deletes the guests who left a while ago, as every connection without
+    // credentials is given one
+    tokio::task::spawn(expire_guests(
+        db_tx.clone(),
+        rooms.clone(),
+        Shutdown::new(notify_shutdown.subscribe(), shutdown_complete_tx.clone()),
+    ));
+    if let Some(idle_secs) = config.idle_room_secs {
+        tokio::task::spawn(clean_up_idle_rooms(
+            db_tx.clone(),
+            rooms.clone(),
+            idle_secs,
+            config.idle_room_action,
+            Shutdown::new(notify_shutdown.subscribe(), shutdown_complete_tx.clone()),
+        ));
+    }
 
-    let chat = routes::chat()
-        .and(db_tx)
-        .and(rooms)
-        .map(|ws: Ws, chat_room, db_tx, rooms| {
-            // let shutdown_listener = notify_shutdown.subscribe();
-            // let shutdown_complete_tx = shutdown_complete_tx.clone();
-            ws.on_upgrade(move |socket| async {
-                let user_id = NEXT_USER_ID.fetch_add(1, Ordering::Relaxed);
-
-                // Create unbounded channel to handle buffering and consuming of messages
-                let (user_tx, user_rx) = mpsc::unbounded_channel();
-
-                let new_user = User {
-                    user_id,
-                    chat_room,
-                    user_tx,
-                    db_tx,
-                };
+    // Continue numbering messages from where the last run left off, apart
+    // from the other instances rooms are shared with
+    let last_message_id = db::query(&db_tx, db::last_message_id)
+        .await
+        .expect("Unable to read last message ID from DB");
+    let message_ids = MessageIds::new(last_message_id, config.node_id);
 
-                // Establish new connection
-                tokio::task::spawn(async move {
-                    add_user_to_room(&new_user, &rooms).await;
-                    new_user.listen(socket, user_rx, rooms).await
-                });
-            })
-        });
+    let token_ttl = Duration::from_secs(config.token_ttl_secs);
+    let jwt = match &config.jwt_secret {
+        Some(secret) => JwtKeys::new(secret.as_bytes(), token_ttl),
+        None => JwtKeys::random(token_ttl),
+    };
+
+    let oauth_providers = oauth::providers(&config);
+    let reset_delivery =
+        reset::delivery(&config).expect("Unable to set up password reset delivery");
+
+    let word_filter = WordFilter::new(&config.filter_words, &config.filter_patterns);
+    let duplicate_guard = DuplicateGuard::new(
+        Duration::from_secs(config.duplicate_window_secs),
+        config.max_duplicate_messages,
+    );
+    let flood_guard = FloodGuard::new(
+        Duration::from_secs(config.flood_window_secs),
+        config.flood_max_messages,
+    );
+
+    let http_client = reqwest::Client::builder()
+        .timeout(HTTP_TIMEOUT)
+        .connect_timeout(HTTP_CONNECT_TIMEOUT)
+        .build()
+        .expect("Unable to set up HTTP client");
+    let content_hook = classifier::hook(&config, &http_client);
+
+    let trust_forwarded_for = config.trust_forwarded_for;
+    let admin_token = config.admin_token.clone();
+    let cluster_secret = config.cluster_secret.clone();
+
+    // Defining stateful data + DB channel
+    // A handle to the state (including the DB channel) is passed to each connection
+    let state = ServerState {
+        config: Arc::new(config),
+        db_tx,
+        rooms,
+        nicks: Nicks::default(),
+        message_ids,
+        jwt: Arc::new(jwt),
+        oauth_providers: Arc::new(oauth_providers),
+        http_client,
+        reset_delivery,
+        word_filter: Arc::new(word_filter),
+        duplicate_guard: Arc::new(duplicate_guard),
+        flood_guard: Arc::new(flood_guard),
+        content_hook: Arc::new(content_hook),
+        cluster,
+        event_sink,
+        federation,
+    };
+
+    // Sends scheduled messages once due, including those that fell due while
+    // the server was down
+    tokio::task::spawn(send_scheduled_messages(
+        state.clone(),
+        Shutdown::new(notify_shutdown.subscribe(), shutdown_complete_tx.clone()),
+    ));
+
+    // Lets IRC clients chat in rooms
+    if let Some(irc_port) = state.config.irc_port {
+        tokio::task::spawn(irc::listen(
+            irc_port,
+            state.clone(),
+            Shutdown::new(notify_shutdown.subscribe(), shutdown_complete_tx.clone()),
+        ));
+    }
+
+    // Lets services chat in rooms over gRPC
+    if let Some(grpc_port) = state.config.grpc_port {
+        tokio::task::spawn(grpc::listen(
+            grpc_port,
+            state.clone(),
+            Shutdown::new(notify_shutdown.subscribe(), shutdown_complete_tx.clone()),
+        ));
+    }
+
+    // Posts mail sent to rooms, and mails digests of rooms
+    if let (Some(mail_port), Some(domain)) = (state.config.mail_port, &state.config.mail_domain) {
+        tokio::task::spawn(mail::listen(
+            mail_port,
+            domain.clone(),
+            state.clone(),
+            Shutdown::new(notify_shutdown.subscribe(), shutdown_complete_tx.clone()),
+        ));
+    }
+    if !state.config.mail_digests.is_empty() {
+        tokio::task::spawn(mail::send_digests(
+            state.config.mail_digests.clone(),
+            Duration::from_secs(state.config.mail_digest_secs),
+            state.config.mail_port.and(state.config.mail_domain.clone()),
+            state.clone(),
+            Shutdown::new(notify_shutdown.subscribe(), shutdown_complete_tx.clone()),
+        ));
+    }
+
+    // Mirrors Discord channels to rooms
+    if let Some(token) = &state.config.discord_token {
+        let api = DiscordApi::new(
+            state.http_client.clone(),
+            &state.config.discord_api_url,
+            token,
+        );
+        for channel in &state.config.discord_channels {
+            tokio::task::spawn(discord::relay(
+                channel.clone(),
+                api.clone(),
+                Duration::from_millis(state.config.discord_poll_ms),
+                state.clone(),
+                Shutdown::new(notify_shutdown.subscribe(), shutdown_complete_tx.clone()),
+            ));
+        }
+    }
+
+    // Bridges rooms to an XMPP server, as multi-user chat rooms
+    if let (Some(addr), Some(domain), Some(secret)) = (
+        state.config.xmpp_component.clone(),
+        state.config.xmpp_domain.clone(),
+        state.config.xmpp_secret.clone(),
+    ) {
+        tokio::task::spawn(xmpp::connect(
+            addr,
+            domain,
+            secret,
+            state.clone(),
+            Shutdown::new(notify_shutdown.subscribe(), shutdown_complete_tx.clone()),
+        ));
+    }
+
+    let schema = graphql::schema(state.clone());
+    let schema = warp::any().map(move || schema.clone());
+
+    let state = warp::any().map(move || state.clone());
+
+    // Validates (and renews) the session cookie of requests, for routes that
+    // accept it
+    let session = routes::session_cookie()
+        .and(state.clone())
+        .and_then(handlers::session);
+
+    // Who makes requests, by their bearer token or session, and the scopes
+    // routes that need a logged in user require of them. Boxed, like route
+    // groups, to keep the types of routes shallow
+    let principal = routes::bearer_token()
+        .and(session.clone())
+        .and(state.clone())
+        .and_then(handlers::principal);
+    let admin_scope = routes::require_scope(principal.clone(), Scope::Admin).boxed();
+    let read_scope = routes::require_scope(principal.clone(), Scope::Read(None)).boxed();
+    let room_read_scope = routes::require_room_scope(principal.clone(), Scope::Read).boxed();
+    let room_write_scope = routes::require_room_scope(principal, Scope::Write).boxed();
+
+    let chat = routes::chat()
+        .and(routes::client_ip(trust_forwarded_for))
+        .and(session.clone())
+        .and(state.clone())
+        .and_then(handlers::chat);
 
     let index = routes::index();
 
-    let routes = index.or(chat);
+    // Admin routes, only reachable with the admin token
+    let admin_guard = routes::admin_guard(admin_token);
+
+    let ip_bans = routes::ip_bans()
+        .and(admin_guard.clone())
+        .and(state.clone())
+        .and_then(handlers::ip_bans);
+
+    let ban_ip = routes::ban_ip()
+        .and(admin_guard.clone())
+        .and(state.clone())
+        .and_then(handlers::ban_ip);
+
+    let unban_ip = routes::unban_ip()
+        .and(admin_guard.clone())
+        .and(state.clone())
+        .and_then(handlers::unban_ip);
+
+    let db_stats = routes::db_stats()
+        .and(admin_guard.clone())
+        .and(state.clone())
+        .and_then(handlers::db_stats);
+
+    let maintain_db = routes::maintain_db()
+        .and(admin_guard.clone())
+        .and(state.clone())
+        .and_then(handlers::maintain_db);
+
+    let backup_db = routes::backup_db()
+        .and(admin_guard)
+        .and(state.clone())
+        .and_then(handlers::backup_db);
+
+    // Links rooms to the servers federated with this one
+    let federation_link = routes::federation_link()
+        .and(routes::bearer_token())
+        .and(state.clone())
+        .and_then(handlers::federation_link);
+
+    // Cluster routes, only reachable by peers with the cluster secret
+    let cluster_guard = routes::admin_guard(cluster_secret);
+
+    let cluster_membership = routes::cluster_membership()
+        .and(cluster_guard.clone())
+        .and(state.clone())
+        .and_then(handlers::cluster_membership);
+
+    let cluster_deliver = routes::cluster_deliver()
+        .and(cluster_guard.clone())
+        .and(state.clone())
+        .and_then(handlers::cluster_deliver);
+
+    let cluster_room = routes::cluster_room()
+        .and(cluster_guard)
+        .and(state.clone())
+        .and_then(handlers::cluster_room);
+
+    let room_pins = routes::room_pins()
+        .and(room_read_scope.clone())
+        .and(state.clone())
+        .and_then(handlers::room_pins);
+
+    let room_roles = routes::room_roles()
+        .and(room_read_scope.clone())
+        .and(state.clone())
+        .and_then(handlers::room_roles);
+
+    let set_room_role = routes::set_room_role()
+        .and(admin_scope.clone())
+        .and(state.clone())
+        .and_then(handlers::set_room_role);
+
+    let rooms = routes::rooms().and(state.clone()).and_then(handlers::rooms);
+
+    let create_room = routes::create_room()
+        .and(admin_scope.clone())
+        .and(state.clone())
+        .and_then(handlers::create_room);
+
+    let room = routes::room()
+        .and(room_read_scope.clone())
+        .and(state.clone())
+        .and_then(handlers::room);
+
+    let set_room_topic = routes::set_room_topic()
+        .and(admin_scope.clone())
+        .and(state.clone())
+        .and_then(handlers::set_room_topic);
+
+    let kick_user = routes::kick_user()
+        .and(admin_scope.clone())
+        .and(state.clone())
+        .and_then(handlers::kick_user);
+
+    let room_bans = routes::room_bans()
+        .and(admin_scope.clone())
+        .and(state.clone())
+        .and_then(handlers::room_bans);
+
+    let ban_user = routes::ban_user()
+        .and(admin_scope.clone())
+        .and(state.clone())
+        .and_then(handlers::ban_user);
+
+    let unban_user = routes::unban_user()
+        .and(admin_scope.clone())
+        .and(state.clone())
+        .and_then(handlers::unban_user);
+
+    let room_mutes = routes::room_mutes()
+        .and(admin_scope.clone())
+        .and(state.clone())
+        .and_then(handlers::room_mutes);
+
+    let mute_user = routes::mute_user()
+        .and(admin_scope.clone())
+        .and(state.clone())
+        .and_then(handlers::mute_user);
+
+    let unmute_user = routes::unmute_user()
+        .and(admin_scope.clone())
+        .and(state.clone())
+        .and_then(handlers::unmute_user);
+
+    let room_shadow_bans = routes::room_shadow_bans()
+        .and(admin_scope.clone())
+        .and(state.clone())
+        .and_then(handlers::room_shadow_bans);
+
+    let shadow_ban_user = routes::shadow_ban_user()
+        .and(admin_scope.clone())
+        .and(state.clone())
+        .and_then(handlers::shadow_ban_user);
+
+    let unshadow_ban_user = routes::unshadow_ban_user()
+        .and(admin_scope.clone())
+        .and(state.clone())
+        .and_then(handlers::unshadow_ban_user);
+
+    let flagged_messages = routes::flagged_messages()
+        .and(admin_scope.clone())
+        .and(state.clone())
+        .and_then(handlers::flagged_messages);
+
+    let online_users = routes::online_users()
+        .and(room_read_scope.clone())
+        .and(state.clone())
+        .and_then(handlers::online_users);
+
+    let read_markers = routes::read_markers()
+        .and(room_read_scope.clone())
+        .and(state.clone())
+        .and_then(handlers::read_markers);
+
+    let room_messages = routes::room_messages()
+        .and(room_read_scope.clone())
+        .and(state.clone())
+        .and_then(handlers::room_messages);
+
+    let room_stream = routes::room_stream()
+        .and(routes::client_ip(trust_forwarded_for))
+        .and(session.clone())
+        .and(state.clone())
+        .and_then(handlers::room_stream);
+
+    let post_message = routes::post_message()
+        .and(room_write_scope.clone())
+        .and(state.clone())
+        .and_then(handlers::post_message);
+
+    let export_room = routes::export_room()
+        .and(admin_scope.clone())
+        .and(state.clone())
+        .and_then(handlers::export_room);
+
+    let search_room = routes::search_room()
+        .and(room_read_scope.clone())
+        .and(state.clone())
+        .and_then(handlers::search_room);
+
+    let deleted_messages = routes::deleted_messages()
+        .and(admin_scope.clone())
+        .and(state.clone())
+        .and_then(handlers::deleted_messages);
+
+    let message_history = routes::message_history()
+        .and(admin_scope.clone())
+        .and(state.clone())
+        .and_then(handlers::message_history);
+
+    let report_message = routes::report_message()
+        .and(room_read_scope.clone())
+        .and(state.clone())
+        .and_then(handlers::report_message);
+
+    let room_reports = routes::room_reports()
+        .and(admin_scope.clone())
+        .and(state.clone())
+        .and_then(handlers::room_reports);
+
+    let resolve_report = routes::resolve_report()
+        .and(admin_scope.clone())
+        .and(state.clone())
+        .and_then(handlers::resolve_report);
+
+    let set_room_retention = routes::set_room_retention()
+        .and(admin_scope.clone())
+        .and(state.clone())
+        .and_then(handlers::set_room_retention);
+
+    let set_room_read_only = routes::set_room_read_only()
+        .and(admin_scope.clone())
+        .and(state.clone())
+        .and_then(handlers::set_room_read_only);
+
+    let set_room_announcement = routes::set_room_announcement()
+        .and(admin_scope.clone())
+        .and(state.clone())
+        .and_then(handlers::set_room_announcement);
+
+    let room_following = routes::room_following()
+        .and(admin_scope.clone())
+        .and(state.clone())
+        .and_then(handlers::room_following);
+
+    let follow_room = routes::follow_room()
+        .and(admin_scope.clone())
+        .and(state.clone())
+        .and_then(handlers::follow_room);
+
+    let unfollow_room = routes::unfollow_room()
+        .and(admin_scope.clone())
+        .and(state.clone())
+        .and_then(handlers::unfollow_room);
+
+    let add_room_owner = routes::add_room_owner()
+        .and(admin_scope.clone())
+        .and(state.clone())
+        .and_then(handlers::add_room_owner);
+
+    let remove_room_owner = routes::remove_room_owner()
+        .and(admin_scope.clone())
+        .and(state.clone())
+        .and_then(handlers::remove_room_owner);
+
+    let transfer_room = routes::transfer_room()
+        .and(admin_scope.clone())
+        .and(state.clone())
+        .and_then(handlers::transfer_room);
+
+    let room_acl = routes::room_acl()
+        .and(admin_scope.clone())
+        .and(state.clone())
+        .and_then(handlers::room_acl);
+
+    let set_room_access = routes::set_room_access()
+        .and(admin_scope.clone())
+        .and(state.clone())
+        .and_then(handlers::set_room_access);
+
+    let remove_room_access = routes::remove_room_access()
+        .and(admin_scope.clone())
+        .and(state.clone())
+        .and_then(handlers::remove_room_access);
+
+    let create_invite = routes::create_invite()
+        .and(admin_scope.clone())
+        .and(state.clone())
+        .and_then(handlers::create_invite);
+
+    let room_invites = routes::room_invites()
+        .and(admin_scope.clone())
+        .and(state.clone())
+        .and_then(handlers::room_invites);
+
+    let revoke_invite = routes::revoke_invite()
+        .and(admin_scope.clone())
+        .and(state.clone())
+        .and_then(handlers::revoke_invite);
+
+    let create_hook = routes::create_hook()
+        .and(admin_scope.clone())
+        .and(state.clone())
+        .and_then(handlers::create_hook);
+
+    let room_hooks = routes::room_hooks()
+        .and(admin_scope.clone())
+        .and(state.clone())
+        .and_then(handlers::room_hooks);
+
+    let revoke_hook = routes::revoke_hook()
+        .and(admin_scope.clone())
+        .and(state.clone())
+        .and_then(handlers::revoke_hook);
+
+    let post_hook = routes::post_hook()
+        .and(state.clone())
+        .and_then(handlers::post_hook);
+
+    let room_members = routes::room_members()
+        .and(admin_scope.clone())
+        .and(state.clone())
+        .and_then(handlers::room_members);
+
+    let invite_room_member = routes::invite_room_member()
+        .and(admin_scope.clone())
+        .and(state.clone())
+        .and_then(handlers::invite_room_member);
+
+    let remove_room_member = routes::remove_room_member()
+        .and(admin_scope.clone())
+        .and(state.clone())
+        .and_then(handlers::remove_room_member);
+
+    let schedule_message = routes::schedule_message()
+        .and(room_write_scope.clone())
+        .and(state.clone())
+        .and_then(handlers::schedule_message);
+
+    let scheduled_messages = routes::scheduled_messages()
+        .and(room_read_scope.clone())
+        .and(state.clone())
+        .and_then(handlers::scheduled_messages);
+
+    let cancel_scheduled_message = routes::cancel_scheduled_message()
+        .and(room_write_scope.clone())
+        .and(state.clone())
+        .and_then(handlers::cancel_scheduled_message);
+
+    let profile = routes::profile()
+        .and(state.clone())
+        .and_then(handlers::profile);
+
+    let update_profile = routes::update_profile()
+        .and(admin_scope.clone())
+        .and(state.clone())
+        .and_then(handlers::update_profile);
+
+    let delete_user = routes::delete_user()
+        .and(admin_scope.clone())
+        .and(state.clone())
+        .and_then(handlers::delete_user);
+
+    let set_user_role = routes::set_user_role()
+        .and(admin_scope.clone())
+        .and(state.clone())
+        .and_then(handlers::set_user_role);
+
+    let user_sessions = routes::user_sessions()
+        .and(admin_scope.clone())
+        .and(state.clone())
+        .and_then(handlers::user_sessions);
+
+    let revoke_session = routes::revoke_session()
+        .and(admin_scope.clone())
+        .and(state.clone())
+        .and_then(handlers::revoke_session);
+
+    let create_api_token = routes::create_api_token()
+        .and(admin_scope.clone())
+        .and(state.clone())
+        .and_then(handlers::create_api_token);
+
+    let api_tokens = routes::api_tokens()
+        .and(admin_scope.clone())
+        .and(state.clone())
+        .and_then(handlers::api_tokens);
+
+    let revoke_api_token = routes::revoke_api_token()
+        .and(admin_scope.clone())
+        .and(state.clone())
+        .and_then(handlers::revoke_api_token);
+
+    let alert_keywords = routes::alert_keywords()
+        .and(admin_scope.clone())
+        .and(state.clone())
+        .and_then(handlers::alert_keywords);
+
+    let set_alert_keywords = routes::set_alert_keywords()
+        .and(admin_scope.clone())
+        .and(state.clone())
+        .and_then(handlers::set_alert_keywords);
+
+    let alerts = routes::alerts()
+        .and(admin_scope.clone())
+        .and(state.clone())
+        .and_then(handlers::alerts);
+
+    let notification_levels = routes::notification_levels()
+        .and(admin_scope.clone())
+        .and(state.clone())
+        .and_then(handlers::notification_levels);
+
+    let set_notification_level = routes::set_notification_level()
+        .and(admin_scope.clone())
+        .and(state.clone())
+        .and_then(handlers::set_notification_level);
+
+    let unread_counts = routes::unread_counts()
+        .and(read_scope.clone())
+        .and(state.clone())
+        .and_then(handlers::unread_counts);
+
+    let create_conversation = routes::create_conversation()
+        .and(admin_scope.clone())
+        .and(state.clone())
+        .and_then(handlers::create_conversation);
+
+    let conversations = routes::conversations()
+        .and(read_scope.clone())
+        .and(state.clone())
+        .and_then(handlers::conversations);
+
+    let register = routes::register()
+        .and(state.clone())
+        .and_then(handlers::register);
+
+    let login = routes::login()
+        .and(routes::client_ip(trust_forwarded_for))
+        .and(state.clone())
+        .and_then(handlers::login);
+
+    let forgot_password = routes::forgot_password()
+        .and(state.clone())
+        .and_then(handlers::forgot_password);
+
+    let reset_password = routes::reset_password()
+        .and(state.clone())
+        .and_then(handlers::reset_password);
+
+    let logout = routes::logout()
+        .and(session.clone())
+        .and(state.clone())
+        .and_then(handlers::logout);
+
+    let totp_enroll = routes::totp_enroll()
+        .and(routes::client_ip(trust_forwarded_for))
+        .and(state.clone())
+        .and_then(handlers::totp_enroll);
+
+    let totp_confirm = routes::totp_confirm()
+        .and(routes::client_ip(trust_forwarded_for))
+        .and(state.clone())
+        .and_then(handlers::totp_confirm);
+
+    let oauth_login = routes::oauth_login()
+        .and(state.clone())
+        .and_then(handlers::oauth_login);
+
+    let graphql = routes::graphql()
+        .and(routes::client_ip(trust_forwarded_for))
+        .and(session.clone())
+        .and(schema.clone())
+        .and(state.clone())
+        .and_then(handlers::graphql);
+
+    let graphql_subscriptions = routes::graphql_subscriptions()
+        .and(routes::client_ip(trust_forwarded_for))
+        .and(session)
+        .and(schema)
+        .and(state.clone())
+        .and_then(handlers::graphql_subscriptions);
+
+    let openapi = routes::openapi().and_then(handlers::openapi);
+
+    let swagger_ui = routes::swagger_ui().and_then(handlers::swagger_ui);
+
+    let oauth_callback = routes::oauth_callback()
+        .and(state)
+        .and_then(handlers::oauth_callback);
+
+    // Routes are grouped, and each group boxed, to keep the type of the whole
+    // filter from growing too deep for the compiler
+    let room_routes = rooms
+        .or(create_room)
+        .or(room)
+        .or(set_room_topic)
+        .or(set_room_read_only)
+        .or(set_room_retention)
+        .or(set_room_announcement)
+        .or(room_following)
+        .or(follow_room)
+        .or(unfollow_room)
+        .or(add_room_owner)
+        .or(remove_room_owner)
+        .or(transfer_room)
+        .or(room_roles)
+        .or(set_room_role)
+        .or(room_acl)
+        .or(set_room_access)
+        .or(remove_room_access)
+        .or(room_members)
+        .or(invite_room_member)
+        .or(remove_room_member)
+        .or(create_invite)
+        .or(room_invites)
+        .or(revoke_invite)
+        .or(create_hook)
+        .or(room_hooks)
+        .or(revoke_hook)
+        .or(schedule_message)
+        .or(scheduled_messages)
+        .or(cancel_scheduled_message)
+        .boxed();
+
+    let room_history_routes = room_pins
+        .or(online_users)
+        .or(read_markers)
+        .or(room_messages)
+        .or(post_message)
+        .or(room_stream)
+        .or(export_room)
+        .or(search_room)
+        .boxed();
+
+    let moderation_routes = kick_user
+        .or(room_bans)
+        .or(ban_user)
+        .or(unban_user)
+        .or(room_mutes)
+        .or(mute_user)
+        .or(unmute_user)
+        .or(room_shadow_bans)
+        .or(shadow_ban_user)
+        .or(unshadow_ban_user)
+        .or(flagged_messages)
+        .or(deleted_messages)
+        .or(message_history)
+        .or(report_message)
+        .or(room_reports)
+        .or(resolve_report)
+        .boxed();
+
+    let user_routes = profile
+        .or(update_profile)
+        .or(delete_user)
+        .or(set_user_role)
+        .or(user_sessions)
+        .or(revoke_session)
+        .or(create_api_token)
+        .or(api_tokens)
+        .or(revoke_api_token)
+        .or(alert_keywords)
+        .or(set_alert_keywords)
+        .or(alerts)
+        .or(notification_levels)
+        .or(set_notification_level)
+        .or(unread_counts)
+        .boxed();
+
+    let conversation_routes = create_conversation.or(conversations).boxed();
+
+    let auth_routes = register
+        .or(login)
+        .or(forgot_password)
+        .or(reset_password)
+        .or(logout)
+        .or(totp_enroll)
+        .or(totp_confirm)
+        .or(oauth_login)
+        .or(oauth_callback)
+        .boxed();
+
+    let admin_routes = ip_bans
+        .or(ban_ip)
+        .or(unban_ip)
+        .or(db_stats)
+        .or(maintain_db)
+        .or(backup_db)
+        .boxed();
+
+    let cluster_routes = cluster_membership
+        .or(cluster_deliver)
+        .or(cluster_room)
+        .boxed();
+
+    let federation_routes = federation_link.boxed();
+
+    let hook_routes = post_hook.boxed();
+
+    let graphql_routes = graphql.or(graphql_subscriptions).boxed();
+
+    let docs_routes = openapi.or(swagger_ui).boxed();
+
+    let routes = index
+        .or(chat)
+        .or(room_routes)
+        .or(room_history_routes)
+        .or(moderation_routes)
+        .or(user_routes)
+        .or(conversation_routes)
+        .or(auth_routes)
+        .or(admin_routes)
+        .or(cluster_routes)
+        .or(federation_routes)
+        .or(hook_routes)
+        .or(graphql_routes)
+        .or(docs_routes)
+        .recover(handlers::recover);
 
     let shutdown = async {
         tokio::signal::ctrl_c()
             .await
             .expect("Unable to bind ctrl-c signal handler");
     };
+    // Unless told to keep running without it, the server shuts down once the
+    // DB thread has failed. It only ever stops otherwise on shutdown.
+    let db_failure = async {
+        match (db_failed_rx.await, on_db_failure) {
+            (Ok(()), DbFailureAction::Shutdown) => {}
+            _ => futures::future::pending().await,
+        }
+    };
     let server = warp::serve(routes).run(([127, 0, 0, 1], port));
 
     tokio::select! {
-        _ = server => {}
-        _ = shutdown => {
-            eprintln!("Shutting down");
+        _ = server => return,
+        _ = shutdown => eprintln!("Shutting down"),
+        _ = db_failure => eprintln!("Shutting down: DB has failed"),
+    }
+
+    // Closes broadcast channel, sending shutdown message to all connections
+    drop(notify_shutdown);
+
+    // At this point, each connection should be terminating, dropping their
+    // shutdown_complete `Senders`
+    // When all connections have terminated, the channel closes and `recv()`
+    // returns `None`.
+    drop(shutdown_complete_tx);
+
+    eprintln!("Waiting for processes to finish");
+    let _ = shutdown_complete_rx.recv().await;
+    if let Some(telemetry) = telemetry {
+        telemetry.shutdown().await;
+    }
+    eprintln!("Done");
+}
 
-            // Closes broadcast channel, sending shutdown message to all connections
-            drop(notify_shutdown);
+async fn purge_expired_messages(
+    db_tx: DbTx,
+    deleted_retention_secs: Option<u64>,
+    mut shutdown: Shutdown,
+) {
+    let mut interval = tokio::time::interval(PURGE_INTERVAL);
+    while !shutdown.is_shutdown() {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = shutdown.async_listen() => break,
+        }
+
+        match db::change(&db_tx, move |conn| {
+            let (purged, ids) = room::purge_expired(conn, deleted_retention_secs)?;
+            let changes = if ids.is_empty() {
+                Vec::new()
+            } else {
+                vec![MessageChange::Purged(ids)]
+            };
+            Ok((purged, changes))
+        })
+        .await
+        {
+            Ok(purged) if purged.total() > 0 => {
+                eprintln!("Purged {} past their retention", purged)
+            }
+            Ok(_) => {}
+            Err(e) => eprintln!("Failed to purge expired messages: {}", e),
+        }
+    }
+}
+
+async fn maintain_db(db_tx: DbTx, period: Duration, vacuum_pages: u32, mut shutdown: Shutdown) {
+    // The first run is due a period after startup rather than right away
+    let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+    while !shutdown.is_shutdown() {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = shutdown.async_listen() => break,
+        }
+
+        match db::maintain(&db_tx, vacuum_pages).await {
+            Ok(maintenance) => eprintln!("DB maintenance: {}", maintenance),
+            Err(e) => eprintln!("Failed to maintain DB: {}", e),
+        }
+    }
+}
+
+async fn expire_guests(db_tx: DbTx, rooms: Rooms, mut shutdown: Shutdown) {
+    let mut interval = tokio::time::interval(PURGE_INTERVAL);
+    while !shutdown.is_shutdown() {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = shutdown.async_listen() => break,
+        }
+
+        let online = user::online_user_ids(&rooms).await;
+        match db::query(&db_tx, move |conn| {
+            auth::expire_guests(conn, &online, GUEST_RETENTION)
+        })
+        .await
+        {
+            Ok(expired) if expired > 0 => eprintln!("Expired {} guests", expired),
+            Ok(_) => {}
+            Err(e) => eprintln!("Failed to expire guests: {}", e),
+        }
+    }
+}
+
+async fn clean_up_idle_rooms(
+    db_tx: DbTx,
+    rooms: Rooms,
+    idle_secs: u64,
+    action: IdleRoomAction,
+    mut shutdown: Shutdown,
+) {
+    let mut interval = tokio::time::interval(PURGE_INTERVAL);
+    while !shutdown.is_shutdown() {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = shutdown.async_listen() => break,
+        }
 
-            // At this point, each connection should be terminating, dropping their
-            // shutdown_complete `Senders`
-            // When all connections have terminated, the channel closes and `recv()`
-            // returns `None`.
-            drop(shutdown_complete_tx);
+        // Rooms stay locked while being cleaned up, so that no one joins them
+        // meanwhile. Rooms are only held in memory while someone is in them.
+        let rooms = rooms.write().await;
+        let occupied = rooms.keys().cloned().collect::<Vec<_>>();
+        let cleaned_up = db::change(&db_tx, move |conn| {
+            let cleaned_up = room::clean_up_idle_rooms(conn, idle_secs, action, &occupied)?;
+            let changes = match action {
+                IdleRoomAction::Purge => cleaned_up
+                    .iter()
+                    .cloned()
+                    .map(MessageChange::RoomPurged)
+                    .collect(),
+                _ => Vec::new(),
+            };
+            Ok((cleaned_up, changes))
+        })
+        .await;
+        drop(rooms);
 
-            eprintln!("Waiting for processes to finish");
-            let _ = shutdown_complete_rx.recv().await;
-            eprintln!("Done");
+        match cleaned_up {
+            Ok(cleaned_up) if !cleaned_up.is_empty() => {
+                eprintln!(
+                    "Idle rooms cleaned up ({}): {}",
+                    action,
+                    cleaned_up.join(", ")
+                )
+            }
+            Ok(_) => {}
+            Err(e) => eprintln!("Failed to clean up idle rooms: {}", e),
         }
     }
 }
+
+async fn send_scheduled_messages(state: ServerState, mut shutdown: Shutdown) {
+    let mut interval = tokio::time::interval(SCHEDULE_INTERVAL);
+    while !shutdown.is_shutdown() {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = shutdown.async_listen() => break,
+        }
+
+        let due = match db::query(&state.db_tx, schedule::take_due).await {
+            Ok(due) => due,
+            Err(e) => {
+                eprintln!("Failed to take due scheduled messages: {}", e);
+                continue;
+            }
+        };
+        for scheduled in due {
+            let (id, room_name) = (scheduled.id, scheduled.room.clone());
+            if let Err(e) = send_scheduled(&state, scheduled).await {
+                eprintln!(
+                    "Failed to send scheduled message {} to {}: {}",
+                    id, room_name, e
+                );
+            }
+        }
+    }
+}
+
+// Sends a scheduled message as its author would have over a connection to its
+// room, provided they may still join it.
+async fn send_scheduled(
+    state: &ServerState,
+    scheduled: ScheduledMessage,
+) -> Result<(), anyhow::Error> {
+    let (user_id, room_name) = (scheduled.user_id, scheduled.room.clone());
+    if !db::query(&state.db_tx, move |conn| {
+        authz::may_join(conn, user_id, &room_name)
+    })
+    .await?
+    {
+        return Err(anyhow!("User {} may no longer join the room", user_id));
+    }
+
+    let user = state.detached_user(&scheduled.room, user_id).await?;
+    let sent = user
+        .send_message(&scheduled.text, None, None, None, None, &state.rooms)
+        .await;
+    user::release_room(&scheduled.room, &state.rooms).await;
+
+    sent?.accepted()
+}
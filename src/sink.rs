@@ -0,0 +1,220 @@
+use anyhow::anyhow;
+use async_trait::async_trait;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+use crate::{protocol::ServerEvent, shutdown::Shutdown};
+
+// Where the events of rooms are emitted to, e.g. a Kafka topic or NATS
+// subject, for systems downstream of the server to consume.
+#[async_trait]
+pub trait Publisher: Send + Sync {
+    // Publishes `payload`, an event of room `room_name` as JSON.
+    async fn publish(&self, room_name: &str, payload: String) -> Result<(), anyhow::Error>;
+}
+
+// Publishes to a NATS subject.
+#[cfg(feature = "nats")]
+pub struct NatsPublisher {
+    client: async_nats::Client,
+    subject: String,
+}
+
+#[cfg(feature = "nats")]
+#[async_trait]
+impl Publisher for NatsPublisher {
+    async fn publish(&self, _room_name: &str, payload: String) -> Result<(), anyhow::Error> {
+        self.client
+            .publish(self.subject.clone(), payload.into())
+            .await?;
+
+        Ok(())
+    }
+}
+
+// Publishes to a Kafka topic, keyed by room so that the events of a room are
+// consumed in order.
+#[cfg(feature = "kafka")]
+pub struct KafkaPublisher {
+    producer: rdkafka::producer::FutureProducer,
+    topic: String,
+}
+
+#[cfg(feature = "kafka")]
+#[async_trait]
+impl Publisher for KafkaPublisher {
+    async fn publish(&self, room_name: &str, payload: String) -> Result<(), anyhow::Error> {
+        let record = rdkafka::producer::FutureRecord::to(&self.topic)
+            .key(room_name)
+            .payload(&payload);
+        self.producer
+            .send(record, rdkafka::util::Timeout::Never)
+            .await
+            .map_err(|(e, _)| e)?;
+
+        Ok(())
+    }
+}
+
+// Connects to the Kafka brokers or NATS server at `url`, as told by its
+// scheme: `kafka://<broker>[,<broker>...]` or `nats://<host>[:<port>]`.
+// Events are published to `topic`.
+pub async fn connect(url: &str, topic: &str) -> Result<Box<dyn Publisher>, anyhow::Error> {
+    match url.split_once("://") {
+        Some(("kafka", brokers)) => kafka(brokers, topic),
+        Some(("nats", _)) => nats(url, topic).await,
+        _ => Err(anyhow!(
+            "Unknown event sink '{}': expected kafka:// or nats://",
+            url
+        )),
+    }
+}
+
+#[cfg(feature = "kafka")]
+fn kafka(brokers: &str, topic: &str) -> Result<Box<dyn Publisher>, anyhow::Error> {
+    let producer = rdkafka::ClientConfig::new()
+        .set("bootstrap.servers", brokers)
+        .create()?;
+
+    Ok(Box::new(KafkaPublisher {
+        producer,
+        topic: String::from(topic),
+    }))
+}
+
+#[cfg(not(feature = "kafka"))]
+fn kafka(_brokers: &str, _topic: &str) -> Result<Box<dyn Publisher>, anyhow::Error> {
+    Err(anyhow!(
+        "Emitting events to Kafka requires the server to be built with the kafka feature"
+    ))
+}
+
+#[cfg(feature = "nats")]
+async fn nats(url: &str, topic: &str) -> Result<Box<dyn Publisher>, anyhow::Error> {
+    let client = async_nats::connect(url).await?;
+
+    Ok(Box::new(NatsPublisher {
+        client,
+        subject: String::from(topic),
+    }))
+}
+
+#[cfg(not(feature = "nats"))]
+async fn nats(_url: &str, _topic: &str) -> Result<Box<dyn Publisher>, anyhow::Error> {
+    Err(anyhow!(
+        "Emitting events to NATS requires the server to be built with the nats feature"
+    ))
+}
+
+// Emits the messages persisted to rooms, and users joining and leaving them,
+// through a `Publisher`. Events are published in the order they are emitted,
+// without holding up whoever emits them.
+#[derive(Clone, Debug)]
+pub struct EventSink {
+    // Rooms and events waiting to be published
+    tx: UnboundedSender<(String, String)>,
+}
+
+impl EventSink {
+    // Publishes what is emitted through `publisher` until shutdown, including
+    // what was emitted but not yet published by then.
+    pub fn new(publisher: Box<dyn Publisher>, shutdown: Shutdown) -> Self {
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::task::spawn(publish(publisher, rx, shutdown));
+
+        EventSink { tx }
+    }
+
+    pub fn emit(&self, room_name: &str, event: &ServerEvent) {
+        // This will only fail once the publisher is gone, on shutdown
+        if let Err(_closed) = self.tx.send((String::from(room_name), event.to_json())) {}
+    }
+}
+
+async fn publish(
+    publisher: Box<dyn Publisher>,
+    mut rx: UnboundedReceiver<(String, String)>,
+    mut shutdown: Shutdown,
+) {
+    loop {
+        let (room_name, payload) = tokio::select! {
+            emitted = rx.recv() => match emitted {
+                Some(emitted) => emitted,
+                None => break,
+            },
+            _ = shutdown.async_listen() => {
+                while let Ok((room_name, payload)) = rx.try_recv() {
+                    publish_event(publisher.as_ref(), &room_name, payload).await;
+                }
+
+                break;
+            }
+        };
+
+        publish_event(publisher.as_ref(), &room_name, payload).await;
+    }
+}
+
+// Events that fail to be published are dropped, rather than holding up those
+// after them.
+async fn publish_event(publisher: &dyn Publisher, room_name: &str, payload: String) {
+    if let Err(e) = publisher.publish(room_name, payload).await {
+        eprintln!("Failed to emit event of room {}: {}", room_name, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use tokio::sync::{broadcast, mpsc};
+
+    use super::*;
+
+    struct Recorder(Arc<Mutex<Vec<(String, String)>>>);
+
+    #[async_trait]
+    impl Publisher for Recorder {
+        async fn publish(&self, room_name: &str, payload: String) -> Result<(), anyhow::Error> {
+            self.0
+                .lock()
+                .unwrap()
+                .push((String::from(room_name), payload));
+
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_emit() {
+        let published = Arc::new(Mutex::new(Vec::new()));
+        let (notify_shutdown, _) = broadcast::channel(1);
+        let (shutdown_complete_tx, mut shutdown_complete_rx) = mpsc::channel(1);
+        let sink = EventSink::new(
+            Box::new(Recorder(published.clone())),
+            Shutdown::new(notify_shutdown.subscribe(), shutdown_complete_tx),
+        );
+
+        for user_id in 1..=3 {
+            let event = ServerEvent::Leave {
+                room: String::from("lobby"),
+                user_id,
+                nick: None,
+            };
+            sink.emit("lobby", &event);
+        }
+
+        // Whatever was emitted is published before shutting down
+        notify_shutdown.send(()).unwrap();
+        let _ = shutdown_complete_rx.recv().await;
+
+        let published = published.lock().unwrap();
+        assert_eq!(published.len(), 3);
+        assert_eq!(published[0].0, "lobby");
+        assert!(published[2].1.contains("\"user_id\":3"));
+    }
+
+    #[tokio::test]
+    async fn test_connect() {
+        assert!(connect("http://localhost", "events").await.is_err());
+    }
+}
@@ -0,0 +1,212 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use anyhow::anyhow;
+use tokio::sync::Mutex;
+
+// Most messages remembered per user, however many were sent within the window.
+const MAX_TRACKED: usize = 64;
+
+// Throttles copy-paste flooding: users may only send the same message, give or
+// take case, punctuation and spacing, so many times within a window, in any
+// room and from any of their connections.
+#[derive(Debug)]
+pub struct DuplicateGuard {
+    window: Duration,
+
+    // Times the same message may be sent within `window`. Unlimited if 0
+    max_duplicates: usize,
+
+    // When each user sent their recent messages, oldest first, normalized
+    recent: Mutex<HashMap<usize, VecDeque<(Instant, String)>>>,
+}
+
+impl DuplicateGuard {
+    pub fn new(window: Duration, max_duplicates: usize) -> Self {
+        DuplicateGuard {
+            window,
+            max_duplicates,
+            recent: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Checks whether `user_id` may send `text`, remembering it if so.
+    pub async fn check(&self, user_id: usize, text: &str) -> Result<(), anyhow::Error> {
+        if self.max_duplicates == 0 {
+            return Ok(());
+        }
+
+        let mut recent = self.recent.lock().await;
+        let now = Instant::now();
+
+        // Forget users who have not sent anything within the window
+        recent.retain(|_, sent| {
+            while sent
+                .front()
+                .is_some_and(|(at, _)| now.duration_since(*at) >= self.window)
+            {
+                sent.pop_front();
+            }
+            !sent.is_empty()
+        });
+
+        let text = normalize(text);
+        let sent = recent.entry(user_id).or_default();
+        let duplicates = sent.iter().filter(|(_, sent)| *sent == text).count();
+        if duplicates >= self.max_duplicates {
+            return Err(anyhow!(
+                "You already sent this message {} times in the last {} seconds",
+                duplicates,
+                self.window.as_secs()
+            ));
+        }
+
+        if sent.len() >= MAX_TRACKED {
+            sent.pop_front();
+        }
+        sent.push_back((now, text));
+
+        Ok(())
+    }
+}
+
+// Enforces a burst quota: users sending more than so many messages within a
+// window, from any of their connections, are kicked out of the room for
+// flooding it.
+#[derive(Debug)]
+pub struct FloodGuard {
+    window: Duration,
+
+    // Messages allowed within `window`. Unlimited if 0
+    max_messages: usize,
+
+    // When each user sent their recent messages, oldest first
+    sent: Mutex<HashMap<usize, VecDeque<Instant>>>,
+}
+
+impl FloodGuard {
+    pub fn new(window: Duration, max_messages: usize) -> Self {
+        FloodGuard {
+            window,
+            max_messages,
+            sent: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn window(&self) -> Duration {
+        self.window
+    }
+
+    pub fn max_messages(&self) -> usize {
+        self.max_messages
+    }
+
+    // Counts a message sent by `user_id`, returning whether they are still
+    // within their quota.
+    pub async fn allows(&self, user_id: usize) -> bool {
+        if self.max_messages == 0 {
+            return true;
+        }
+
+        let mut sent = self.sent.lock().await;
+        let now = Instant::now();
+
+        // Forget users who have not sent anything within the window
+        sent.retain(|_, times| {
+            while times
+                .front()
+                .is_some_and(|at| now.duration_since(*at) >= self.window)
+            {
+                times.pop_front();
+            }
+            !times.is_empty()
+        });
+
+        let times = sent.entry(user_id).or_default();
+        if times.len() >= self.max_messages {
+            // The quota is counted afresh once they reconnect
+            sent.remove(&user_id);
+            return false;
+        }
+        times.push_back(now);
+
+        true
+    }
+}
+
+// `text` lowercased, with only its letters and digits, so that messages
+// differing in case, punctuation or spacing compare equal. Messages without
+// any, e.g. emoji, only ignore spacing.
+fn normalize(text: &str) -> String {
+    let normalized: String = text
+        .chars()
+        .filter(|c| c.is_alphanumeric())
+        .flat_map(char::to_lowercase)
+        .collect();
+
+    if normalized.is_empty() {
+        text.chars().filter(|c| !c.is_whitespace()).collect()
+    } else {
+        normalized
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_duplicate_guard() {
+        let guard = DuplicateGuard::new(Duration::from_secs(60), 2);
+
+        assert!(guard.check(1, "Buy now").await.is_ok());
+        assert!(guard.check(1, "buy now!!").await.is_ok());
+        assert!(guard.check(1, "B U Y  N O W").await.is_err());
+
+        // Other messages, and other users, are not affected
+        assert!(guard.check(1, "Buy later").await.is_ok());
+        assert!(guard.check(2, "Buy now").await.is_ok());
+        assert!(guard.check(2, "👍").await.is_ok());
+        assert!(guard.check(2, "🎉").await.is_ok());
+
+        let unlimited = DuplicateGuard::new(Duration::from_secs(60), 0);
+        for _ in 0..10 {
+            assert!(unlimited.check(1, "Buy now").await.is_ok());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_flood_guard() {
+        let guard = FloodGuard::new(Duration::from_millis(50), 2);
+
+        assert!(guard.allows(1).await);
+        assert!(guard.allows(1).await);
+        assert!(guard.allows(2).await);
+        assert!(!guard.allows(1).await);
+
+        // Quotas start over once exceeded, and once the window has passed
+        assert!(guard.allows(1).await);
+        assert!(guard.allows(2).await);
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert!(guard.allows(2).await);
+        assert!(guard.allows(2).await);
+
+        let unlimited = FloodGuard::new(Duration::from_secs(60), 0);
+        for _ in 0..10 {
+            assert!(unlimited.allows(1).await);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_window() {
+        let guard = DuplicateGuard::new(Duration::from_millis(50), 1);
+
+        assert!(guard.check(1, "Hi").await.is_ok());
+        assert!(guard.check(1, "Hi").await.is_err());
+
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert!(guard.check(1, "Hi").await.is_ok());
+    }
+}
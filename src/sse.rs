@@ -0,0 +1,76 @@
+use std::convert::Infallible;
+
+use futures::{stream, Stream};
+use serde_json::Value;
+use warp::sse::Event;
+
+use crate::{
+    server::{Follower, ServerState},
+    user::{User, UserRx},
+};
+
+// The server-sent event relaying `event`, named after its type. Messages carry
+// their ID as that of the event, which clients reconnecting send back as
+// `Last-Event-ID` to catch up on what they missed.
+fn sse_event(event: &str) -> Option<Event> {
+    let value: Value = serde_json::from_str(event).ok()?;
+    let mut sse_event = Event::default().data(event);
+    if let Some(name) = value["type"].as_str() {
+        sse_event = sse_event.event(name);
+    }
+    if value["type"] == "message" {
+        if let Some(id) = value["id"].as_i64() {
+            sse_event = sse_event.id(id.to_string());
+        }
+    }
+
+    Some(sse_event)
+}
+
+// What the room of `user`, who joined it, sends them, as server-sent events,
+// until they are disconnected from it. Dropping the stream removes them from
+// the room.
+pub fn events(
+    user: User,
+    user_rx: UserRx,
+    is_guest: bool,
+    state: ServerState,
+) -> impl Stream<Item = Result<Event, Infallible>> {
+    let reader = Follower::new(user, is_guest, state);
+
+    stream::unfold((user_rx, reader), |(mut user_rx, reader)| async move {
+        loop {
+            let msg = user_rx.recv().await?;
+            // Kicked, banned, or the server shutting down
+            if msg.is_close() {
+                return None;
+            }
+            if let Some(event) = msg.to_str().ok().and_then(sse_event) {
+                return Some((Ok(event), (user_rx, reader)));
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sse_event() {
+        let event =
+            sse_event(r#"{"type":"message","id":42,"room":"lobby","user_id":1,"text":"hi"}"#)
+                .unwrap();
+        assert_eq!(
+            event.to_string(),
+            "event:message\ndata:{\"type\":\"message\",\"id\":42,\"room\":\"lobby\",\"user_id\":1,\"text\":\"hi\"}\nid:42\n\n"
+        );
+
+        let event = sse_event(r#"{"type":"join","room":"lobby","user_id":1}"#).unwrap();
+        assert_eq!(
+            event.to_string(),
+            "event:join\ndata:{\"type\":\"join\",\"room\":\"lobby\",\"user_id\":1}\n\n"
+        );
+        assert!(sse_event("not json").is_none());
+    }
+}
@@ -0,0 +1,1224 @@
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::anyhow;
+use rusqlite::{
+    params,
+    types::{ToSqlOutput, ValueRef},
+    Connection, ErrorCode,
+};
+
+use crate::{
+    auth, compression,
+    db::{self, Changed, DBMessage, DbChange, DbQuery, Pragmas},
+    maintenance::{self, Maintenance},
+    read_pool::ReadPool,
+};
+
+// Backend the DB thread persists messages to. Writes are made within a
+// transaction, until committed. Nothing here is tied to SQLite, so that other
+// backends may implement it.
+pub trait MessageStore {
+    // Persists `msg`.
+    fn insert(&mut self, msg: &DBMessage) -> Result<(), StoreError>;
+
+    // Deletes the messages that have expired, returning how many were.
+    fn purge(&mut self) -> Result<usize, StoreError>;
+
+    // Applies `change`, made to the messages of another store, to those
+    // persisted here.
+    fn apply(&mut self, change: &MessageChange) -> Result<(), StoreError>;
+
+    // Commits the writes made so far, starting a new transaction for those to
+    // come.
+    fn commit(&mut self) -> Result<(), StoreError>;
+
+    // Discards the writes made since the last commit, starting a new
+    // transaction for those to come. Fails if they can not be discarded.
+    fn rollback(&mut self) -> Result<(), StoreError>;
+
+    // Reconnects to the backend, after writes to it failed, returning whether
+    // the writes not committed yet were kept. They are lost otherwise.
+    fn reconnect(&mut self) -> Result<bool, StoreError>;
+}
+
+// Store the DB thread runs the server's queries against, which are closures
+// over a SQLite connection, as is maintenance. This is what the DB thread
+// still requires of a store on top of `MessageStore`.
+pub trait SqlStore: MessageStore {
+    // Runs `query` against the store's connection.
+    fn query(&mut self, query: DbQuery);
+
+    // Runs `change` against the store's connection, as a query, returning the
+    // changes to messages it made.
+    fn change(&mut self, change: DbChange) -> Changed;
+
+    // Applies `change`, which a query made to the store's messages, to
+    // whatever else the store writes them to, if anything.
+    fn mirror(&mut self, _change: &MessageChange) -> Result<(), StoreError> {
+        Ok(())
+    }
+
+    // Runs `query`, which only reads, once the writes made so far have been
+    // committed. Stores with connections of their own for reads run it there.
+    fn read(&mut self, query: DbQuery) {
+        self.query(query)
+    }
+
+    // Checkpoints and vacuums the store, once the writes made so far have been
+    // committed. See `maintenance::run`.
+    fn maintain(&mut self, vacuum_pages: u32) -> Result<Maintenance, rusqlite::Error>;
+}
+
+// A change made to messages already persisted, by the server's queries. See
+// `db::change`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum MessageChange {
+    // Message `id` was edited to `text`, and flagged if `flagged`
+    Edited {
+        id: i64,
+        text: String,
+        flagged: bool,
+    },
+    // Message `id` was deleted by `deleted_by`, kept for moderators to read
+    Deleted {
+        id: i64,
+        deleted_by: usize,
+    },
+    // Messages were purged, past the retention of their room
+    Purged(Vec<i64>),
+    // The messages of a room were purged along with it
+    RoomPurged(String),
+    // The messages of a user, and the copies of them forwarded to other
+    // rooms, were kept without an author once their account was deleted
+    Anonymized(usize),
+    // The messages of a user, and the copies of them forwarded to other
+    // rooms, were deleted along with their account
+    Erased(usize),
+}
+
+// Why a store failed to be written to, whichever the backend.
+#[derive(Debug)]
+pub enum StoreError {
+    // The store can not be written to for now, such as when it is locked or
+    // its disk full. The write may succeed if retried.
+    Unavailable(anyhow::Error),
+    // The store refused the write, and would again if retried, such as when
+    // it breaks a constraint.
+    Rejected(anyhow::Error),
+}
+
+impl StoreError {
+    // Whether retrying the write may succeed.
+    pub fn is_transient(&self) -> bool {
+        matches!(self, StoreError::Unavailable(_))
+    }
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StoreError::Unavailable(e) | StoreError::Rejected(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+// SQLite errors are transient when the DB is busy, locked, its disk full or
+// failing to be read or written. Every other one is a rejection.
+impl From<rusqlite::Error> for StoreError {
+    fn from(e: rusqlite::Error) -> Self {
+        let transient = matches!(
+            &e,
+            rusqlite::Error::SqliteFailure(failure, _) if matches!(
+                failure.code,
+                ErrorCode::DatabaseBusy
+                    | ErrorCode::DatabaseLocked
+                    | ErrorCode::SystemIoFailure
+                    | ErrorCode::DiskFull
+            )
+        );
+        if transient {
+            StoreError::Unavailable(e.into())
+        } else {
+            StoreError::Rejected(e.into())
+        }
+    }
+}
+
+// Stores the server may persist messages to.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StoreKind {
+    // The SQLite DB file at the configured path
+    Sqlite,
+    // An in-memory DB, lost on shutdown
+    Memory,
+}
+
+impl FromStr for StoreKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sqlite" => Ok(StoreKind::Sqlite),
+            "memory" => Ok(StoreKind::Memory),
+            _ => Err(anyhow!("Unknown store '{}': expected sqlite or memory", s)),
+        }
+    }
+}
+
+impl fmt::Display for StoreKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self {
+            StoreKind::Sqlite => "sqlite",
+            StoreKind::Memory => "memory",
+        };
+        f.write_str(kind)
+    }
+}
+
+// How a SQLite store reads and writes messages.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct StoreOptions {
+    // Number of read-only connections reads are run on, if any
+    pub read_connections: usize,
+    // Whether messages are compressed before being written
+    pub compress_messages: bool,
+    // Postgres database messages are mirrored to, if any
+    pub postgres: Option<PostgresOptions>,
+}
+
+// Postgres database messages are mirrored to, and how it is connected to.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PostgresOptions {
+    pub url: String,
+    // PEM file of the certificates TLS connections are verified against, on
+    // top of the well-known ones
+    pub ca_cert: Option<PathBuf>,
+}
+
+// Store backed by a SQLite DB file.
+pub struct SqliteStore {
+    conn: Connection,
+    db_path: PathBuf,
+    pragmas: Pragmas,
+    readers: Option<ReadPool>,
+    // Whether messages are compressed before being written
+    compress: bool,
+    // Store the messages are also written to, if any, committed along with
+    // the DB
+    mirror: Option<Box<dyn MessageStore>>,
+}
+
+const INSERT_QUERY: &str = "INSERT INTO chat_messages
+        (message_id, seq, user_id, room_name, message, nickname, shadowed, flagged, expires_at,
+            forwarded_id, forwarded_room, forwarded_user_id, forwarded_nick)
+    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, datetime('now', ?9), ?10, ?11, ?12, ?13)";
+
+impl SqliteStore {
+    // Opens the DB at `db_path`, creating it and its tables if need be.
+    pub fn open(db_path: &Path, pragmas: &Pragmas) -> Result<Self, rusqlite::Error> {
+        let conn = db::open(db_path, pragmas)?;
+        db::init_schema(&conn)?;
+        conn.execute_batch("BEGIN")?;
+
+        Ok(SqliteStore {
+            conn,
+            db_path: db_path.to_path_buf(),
+            pragmas: pragmas.clone(),
+            readers: None,
+            compress: false,
+            mirror: None,
+        })
+    }
+
+    // Has messages compressed before being written, those long enough to be
+    // worth it. Messages are read the same either way.
+    pub fn with_compression(mut self, compress: bool) -> Self {
+        self.compress = compress;
+        self
+    }
+
+    // Has reads run on `size` read-only connections of their own, rather than
+    // on the store's. Reads keep being run on the store's without any.
+    pub fn with_readers(mut self, size: usize) -> Result<Self, rusqlite::Error> {
+        if size > 0 {
+            self.readers = Some(ReadPool::open(&self.db_path, &self.pragmas, size)?);
+        }
+
+        Ok(self)
+    }
+
+    // Has the messages written to the DB also written to `mirror`, such as a
+    // Postgres database other services read from. Inserts, purges, changes,
+    // commits and rollbacks are made to the mirror first, so that what the DB
+    // commits was mirrored.
+    pub fn with_mirror(mut self, mirror: Box<dyn MessageStore>) -> Self {
+        self.mirror = Some(mirror);
+        self
+    }
+
+    // Commits the writes made so far and closes the DB.
+    pub fn close(mut self) -> Result<(), StoreError> {
+        // Readers are done with their queries once closed
+        self.readers.take();
+        if let Some(mirror) = &mut self.mirror {
+            mirror.commit()?;
+        }
+        self.conn.execute_batch("COMMIT")?;
+        Ok(self.conn.close().map_err(|(_, e)| e)?)
+    }
+}
+
+impl MessageStore for SqliteStore {
+    fn insert(&mut self, msg: &DBMessage) -> Result<(), StoreError> {
+        // Mirrors ignore messages they already have, should the DB fail to be
+        // written to and the insert be retried
+        if let Some(mirror) = &mut self.mirror {
+            mirror.insert(msg)?;
+        }
+
+        Ok(insert_message(&self.conn, msg, self.compress)?)
+    }
+
+    fn purge(&mut self) -> Result<usize, StoreError> {
+        if let Some(mirror) = &mut self.mirror {
+            mirror.purge()?;
+        }
+
+        Ok(db::delete_expired(&self.conn)?)
+    }
+
+    fn apply(&mut self, change: &MessageChange) -> Result<(), StoreError> {
+        self.mirror(change)?;
+
+        Ok(apply_change(&self.conn, change, self.compress)?)
+    }
+
+    fn commit(&mut self) -> Result<(), StoreError> {
+        if let Some(mirror) = &mut self.mirror {
+            mirror.commit()?;
+        }
+
+        Ok(self.conn.execute_batch("COMMIT; BEGIN")?)
+    }
+
+    fn rollback(&mut self) -> Result<(), StoreError> {
+        let mirrored = match &mut self.mirror {
+            Some(mirror) => mirror.rollback(),
+            None => Ok(()),
+        };
+
+        // A failed commit may have ended the transaction already
+        if !self.conn.is_autocommit() {
+            self.conn.execute_batch("ROLLBACK")?;
+        }
+        self.conn.execute_batch("BEGIN")?;
+
+        mirrored
+    }
+
+    fn reconnect(&mut self) -> Result<bool, StoreError> {
+        // Writes the mirror lost are discarded from the DB as well, so that
+        // both hold the same messages
+        let mirrored = match &mut self.mirror {
+            Some(mirror) => mirror.reconnect()?,
+            None => true,
+        };
+        if !mirrored && !self.conn.is_autocommit() {
+            let _ = self.conn.execute_batch("ROLLBACK");
+        }
+
+        // Writes not committed yet are lost unless they still can be, which
+        // the connection's locks must be released for to reopen it
+        let kept = mirrored && self.conn.execute_batch("COMMIT").is_ok();
+        if !self.conn.is_autocommit() {
+            let _ = self.conn.execute_batch("ROLLBACK");
+        }
+
+        // The old connection is kept should the DB fail to be reopened
+        match db::open(&self.db_path, &self.pragmas) {
+            Ok(conn) => self.conn = conn,
+            Err(e) => eprintln!("Unable to reopen DB: {}", e),
+        }
+        self.conn.execute_batch("BEGIN")?;
+
+        Ok(kept)
+    }
+}
+
+impl SqlStore for SqliteStore {
+    fn query(&mut self, query: DbQuery) {
+        query(&self.conn)
+    }
+
+    fn change(&mut self, change: DbChange) -> Changed {
+        change(&self.conn)
+    }
+
+    fn mirror(&mut self, change: &MessageChange) -> Result<(), StoreError> {
+        match &mut self.mirror {
+            Some(mirror) => mirror.apply(change),
+            None => Ok(()),
+        }
+    }
+
+    fn read(&mut self, query: DbQuery) {
+        match &self.readers {
+            Some(readers) => readers.run(query),
+            None => query(&self.conn),
+        }
+    }
+
+    fn maintain(&mut self, vacuum_pages: u32) -> Result<Maintenance, rusqlite::Error> {
+        self.conn.execute_batch("COMMIT")?;
+        let maintained = maintenance::run(&self.conn, vacuum_pages);
+        self.conn.execute_batch("BEGIN")?;
+
+        maintained
+    }
+}
+
+// Store backed by an in-memory SQLite DB, for tests and demos. Nothing is
+// written to disk, so there is nothing to commit.
+pub struct MemoryStore {
+    conn: Connection,
+}
+
+impl MemoryStore {
+    pub fn new() -> Result<Self, rusqlite::Error> {
+        let conn = Connection::open_in_memory()?;
+        db::init_schema(&conn)?;
+
+        Ok(MemoryStore { conn })
+    }
+}
+
+impl MessageStore for MemoryStore {
+    fn insert(&mut self, msg: &DBMessage) -> Result<(), StoreError> {
+        Ok(insert_message(&self.conn, msg, false)?)
+    }
+
+    fn purge(&mut self) -> Result<usize, StoreError> {
+        Ok(db::delete_expired(&self.conn)?)
+    }
+
+    fn apply(&mut self, change: &MessageChange) -> Result<(), StoreError> {
+        Ok(apply_change(&self.conn, change, false)?)
+    }
+
+    fn commit(&mut self) -> Result<(), StoreError> {
+        Ok(())
+    }
+
+    // Writes are made as they come, so they can not be discarded
+    fn rollback(&mut self) -> Result<(), StoreError> {
+        Err(StoreError::Rejected(anyhow!(
+            "Writes to memory can not be rolled back"
+        )))
+    }
+
+    // There is nothing to reconnect to: the DB would be lost
+    fn reconnect(&mut self) -> Result<bool, StoreError> {
+        Ok(true)
+    }
+}
+
+impl SqlStore for MemoryStore {
+    fn query(&mut self, query: DbQuery) {
+        query(&self.conn)
+    }
+
+    fn change(&mut self, change: DbChange) -> Changed {
+        change(&self.conn)
+    }
+
+    fn maintain(&mut self, vacuum_pages: u32) -> Result<Maintenance, rusqlite::Error> {
+        maintenance::run(&self.conn, vacuum_pages)
+    }
+}
+
+// Store backed by a Postgres database, which the server mirrors the messages
+// it persists to, for services that outgrow reading them from SQLite.
+// Postgres is spoken to asynchronously, on a runtime of the store's own, which
+// keeps the store's connection going between writes.
+#[cfg(feature = "postgres")]
+pub struct PostgresStore {
+    options: PostgresOptions,
+    tls: tokio_postgres_rustls::MakeRustlsConnect,
+    client: std::sync::Arc<tokio_postgres::Client>,
+    handle: tokio::runtime::Handle,
+    // Shut down in the background once the store is dropped, which may be
+    // within another runtime
+    runtime: Option<tokio::runtime::Runtime>,
+}
+
+#[cfg(feature = "postgres")]
+const POSTGRES_SCHEMA: &str = "CREATE TABLE IF NOT EXISTS chat_messages (
+        message_id BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+        seq BIGINT,
+        user_id BIGINT NOT NULL,
+        room_name TEXT NOT NULL,
+        message TEXT NOT NULL,
+        nickname TEXT,
+        shadowed BOOLEAN NOT NULL DEFAULT FALSE,
+        flagged BOOLEAN NOT NULL DEFAULT FALSE,
+        created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+        edited_at TIMESTAMPTZ,
+        deleted_at TIMESTAMPTZ,
+        deleted_by BIGINT,
+        expires_at TIMESTAMPTZ,
+        forwarded_id BIGINT,
+        forwarded_room TEXT,
+        forwarded_user_id BIGINT,
+        forwarded_nick TEXT
+    );
+    CREATE INDEX IF NOT EXISTS chat_messages_room_seq ON chat_messages (room_name, seq);
+    CREATE INDEX IF NOT EXISTS chat_messages_user ON chat_messages (user_id);
+    CREATE INDEX IF NOT EXISTS chat_messages_forwarded_user ON chat_messages (forwarded_user_id)
+        WHERE forwarded_user_id IS NOT NULL;
+    CREATE INDEX IF NOT EXISTS chat_messages_expires_at ON chat_messages (expires_at)
+        WHERE expires_at IS NOT NULL";
+
+// Messages the database already has are left as they are, as inserts are
+// retried once the store is reconnected to.
+#[cfg(feature = "postgres")]
+const POSTGRES_INSERT_QUERY: &str = "INSERT INTO chat_messages
+        (message_id, seq, user_id, room_name, message, nickname, shadowed, flagged, expires_at,
+            forwarded_id, forwarded_room, forwarded_user_id, forwarded_nick)
+    VALUES (
+        COALESCE($1, nextval(pg_get_serial_sequence('chat_messages', 'message_id'))),
+        $2, $3, $4, $5, $6, $7, $8, now() + $9::BIGINT * INTERVAL '1 second', $10, $11, $12, $13
+    )
+    ON CONFLICT (message_id) DO NOTHING";
+
+#[cfg(feature = "postgres")]
+impl PostgresStore {
+    // Connects to the database `options` tell of, creating its table if need
+    // be.
+    pub fn connect(options: &PostgresOptions) -> Result<Self, StoreError> {
+        let tls = postgres_tls(options.ca_cert.as_deref()).map_err(StoreError::Rejected)?;
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .thread_name("postgres")
+            .enable_all()
+            .build()
+            .map_err(|e| StoreError::Rejected(e.into()))?;
+        let handle = runtime.handle().clone();
+        let client = run(&handle, postgres_connect(options.url.clone(), tls.clone()))?;
+        let store = PostgresStore {
+            options: options.clone(),
+            tls,
+            client: std::sync::Arc::new(client),
+            handle,
+            runtime: Some(runtime),
+        };
+        store.execute(&format!("{}; BEGIN", POSTGRES_SCHEMA))?;
+
+        Ok(store)
+    }
+
+    // Runs `statements`, separated by semicolons.
+    fn execute(&self, statements: &str) -> Result<(), StoreError> {
+        let client = self.client.clone();
+        let statements = String::from(statements);
+        run(&self.handle, async move {
+            client.batch_execute(&statements).await
+        })
+    }
+}
+
+#[cfg(feature = "postgres")]
+impl MessageStore for PostgresStore {
+    fn insert(&mut self, msg: &DBMessage) -> Result<(), StoreError> {
+        let client = self.client.clone();
+        let msg = msg.clone();
+        run(&self.handle, async move {
+            // Failed statements abort the transaction they are made in, taking
+            // the writes made before with them, unless rolled back to a
+            // savepoint
+            client.batch_execute("SAVEPOINT message").await?;
+            match postgres_insert(&client, &msg).await {
+                Ok(()) => client.batch_execute("RELEASE SAVEPOINT message").await,
+                Err(e) => {
+                    client
+                        .batch_execute("ROLLBACK TO SAVEPOINT message; RELEASE SAVEPOINT message")
+                        .await?;
+                    Err(e)
+                }
+            }
+        })
+    }
+
+    fn purge(&mut self) -> Result<usize, StoreError> {
+        let client = self.client.clone();
+        let purged = run(&self.handle, async move {
+            client
+                .execute("DELETE FROM chat_messages WHERE expires_at <= now()", &[])
+                .await
+        })?;
+
+        Ok(purged as usize)
+    }
+
+    fn apply(&mut self, change: &MessageChange) -> Result<(), StoreError> {
+        let client = self.client.clone();
+        let change = change.clone();
+        run(&self.handle, async move {
+            postgres_apply(&client, &change).await
+        })
+    }
+
+    fn commit(&mut self) -> Result<(), StoreError> {
+        self.execute("COMMIT; BEGIN")
+    }
+
+    // Rolling back outside of a transaction, as after a failed commit, is
+    // merely warned about
+    fn rollback(&mut self) -> Result<(), StoreError> {
+        self.execute("ROLLBACK; BEGIN")
+    }
+
+    fn reconnect(&mut self) -> Result<bool, StoreError> {
+        let kept = self.execute("COMMIT").is_ok();
+
+        // The old connection is kept should the database fail to be connected
+        // to again
+        let connected = postgres_connect(self.options.url.clone(), self.tls.clone());
+        match run(&self.handle, connected) {
+            Ok(client) => self.client = std::sync::Arc::new(client),
+            Err(e) => eprintln!("Unable to reconnect to Postgres: {}", e),
+        }
+        self.execute("BEGIN")?;
+
+        Ok(kept)
+    }
+}
+
+#[cfg(feature = "postgres")]
+impl Drop for PostgresStore {
+    fn drop(&mut self) {
+        if let Some(runtime) = self.runtime.take() {
+            runtime.shutdown_background();
+        }
+    }
+}
+
+// Postgres errors are transient when the connection to the database failed or
+// was lost, or the database lacks the resources for the write, such as disk
+// space, or is shutting down. Every other one is a rejection.
+#[cfg(feature = "postgres")]
+impl From<tokio_postgres::Error> for StoreError {
+    fn from(e: tokio_postgres::Error) -> Self {
+        let transient = match e.code() {
+            Some(code) => {
+                let code = code.code();
+                code.starts_with("08") || code.starts_with("53") || code.starts_with("57P")
+            }
+            None => {
+                e.is_closed()
+                    || std::error::Error::source(&e)
+                        .is_some_and(|source| source.is::<std::io::Error>())
+            }
+        };
+        if transient {
+            StoreError::Unavailable(e.into())
+        } else {
+            StoreError::Rejected(e.into())
+        }
+    }
+}
+
+// Runs `f` on the store's runtime, through its `handle`, waiting for it. The
+// DB thread has a runtime of its own, which can not block on another.
+#[cfg(feature = "postgres")]
+fn run<T, F>(handle: &tokio::runtime::Handle, f: F) -> Result<T, StoreError>
+where
+    T: Send + 'static,
+    F: std::future::Future<Output = Result<T, tokio_postgres::Error>> + Send + 'static,
+{
+    futures::executor::block_on(handle.spawn(f))
+        .map_err(|e| StoreError::Rejected(e.into()))?
+        .map_err(StoreError::from)
+}
+
+// Connects to the database at `url`, driving the connection until it is lost
+// or replaced. Whether it is made over TLS, through `tls`, is up to the
+// `sslmode` of `url`: TLS is used whenever the database supports it by
+// default, and always with `sslmode=require`.
+#[cfg(feature = "postgres")]
+async fn postgres_connect(
+    url: String,
+    tls: tokio_postgres_rustls::MakeRustlsConnect,
+) -> Result<tokio_postgres::Client, tokio_postgres::Error> {
+    let (client, connection) = tokio_postgres::connect(&url, tls).await?;
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            eprintln!("Lost connection to Postgres: {}", e);
+        }
+    });
+
+    Ok(client)
+}
+
+// Makes TLS connections to Postgres, verifying the certificate of the database
+// against the well-known certificate authorities, and those of the PEM file at
+// `ca_cert` if given.
+#[cfg(feature = "postgres")]
+fn postgres_tls(
+    ca_cert: Option<&Path>,
+) -> Result<tokio_postgres_rustls::MakeRustlsConnect, anyhow::Error> {
+    use rustls::pki_types::{pem::PemObject, CertificateDer};
+
+    let mut roots = rustls::RootCertStore::empty();
+    roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    if let Some(ca_cert) = ca_cert {
+        for cert in CertificateDer::pem_file_iter(ca_cert)
+            .map_err(|e| anyhow!("Unable to read {}: {}", ca_cert.display(), e))?
+        {
+            let cert = cert.map_err(|e| anyhow!("Unable to read {}: {}", ca_cert.display(), e))?;
+            roots.add(cert)?;
+        }
+    }
+    let config = rustls::ClientConfig::builder_with_provider(std::sync::Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()?
+    .with_root_certificates(roots)
+    .with_no_client_auth();
+
+    Ok(tokio_postgres_rustls::MakeRustlsConnect::new(config))
+}
+
+#[cfg(feature = "postgres")]
+async fn postgres_insert(
+    client: &tokio_postgres::Client,
+    msg: &DBMessage,
+) -> Result<(), tokio_postgres::Error> {
+    let forwarded = msg.forwarded.as_ref();
+    // Postgres text can not hold NUL characters, which SQLite can
+    let message = msg.message.replace('\0', "\u{FFFD}");
+    client
+        .execute(
+            POSTGRES_INSERT_QUERY,
+            &[
+                &msg.message_id,
+                &msg.seq,
+                &(msg.user_id as i64),
+                &msg.room_name,
+                &message,
+                &msg.nickname,
+                &msg.shadowed,
+                &msg.flagged,
+                &msg.ttl.map(|ttl| ttl.as_secs() as i64),
+                &forwarded.map(|forwarded| forwarded.id),
+                &forwarded.map(|forwarded| &forwarded.room),
+                &forwarded.map(|forwarded| forwarded.user_id as i64),
+                &forwarded.and_then(|forwarded| forwarded.nick.as_ref()),
+            ],
+        )
+        .await?;
+
+    Ok(())
+}
+
+// Applies `change` to the messages of the database.
+#[cfg(feature = "postgres")]
+async fn postgres_apply(
+    client: &tokio_postgres::Client,
+    change: &MessageChange,
+) -> Result<(), tokio_postgres::Error> {
+    const DELETED_USER_ID: i64 = auth::DELETED_USER_ID as i64;
+
+    match change {
+        MessageChange::Edited { id, text, flagged } => {
+            client
+                .execute(
+                    "UPDATE chat_messages
+                        SET message = $2, edited_at = now(), flagged = flagged OR $3
+                        WHERE message_id = $1",
+                    &[id, &text.replace('\0', "\u{FFFD}"), flagged],
+                )
+                .await?;
+        }
+        MessageChange::Deleted { id, deleted_by } => {
+            client
+                .execute(
+                    "UPDATE chat_messages SET deleted_at = now(), deleted_by = $2
+                        WHERE message_id = $1 AND deleted_at IS NULL",
+                    &[id, &(*deleted_by as i64)],
+                )
+                .await?;
+        }
+        MessageChange::Purged(ids) => {
+            client
+                .execute(
+                    "DELETE FROM chat_messages WHERE message_id = ANY($1)",
+                    &[ids],
+                )
+                .await?;
+        }
+        MessageChange::RoomPurged(room_name) => {
+            client
+                .execute(
+                    "DELETE FROM chat_messages WHERE room_name = $1",
+                    &[room_name],
+                )
+                .await?;
+        }
+        MessageChange::Anonymized(user_id) => {
+            let user_id = *user_id as i64;
+            client
+                .execute(
+                    "UPDATE chat_messages SET user_id = $1, nickname = NULL WHERE user_id = $2",
+                    &[&DELETED_USER_ID, &user_id],
+                )
+                .await?;
+            client
+                .execute(
+                    "UPDATE chat_messages SET forwarded_user_id = $1, forwarded_nick = NULL
+                        WHERE forwarded_user_id = $2",
+                    &[&DELETED_USER_ID, &user_id],
+                )
+                .await?;
+        }
+        MessageChange::Erased(user_id) => {
+            client
+                .execute(
+                    "DELETE FROM chat_messages WHERE user_id = $1 OR forwarded_user_id = $1",
+                    &[&(*user_id as i64)],
+                )
+                .await?;
+        }
+    }
+
+    Ok(())
+}
+
+// Connects to the Postgres database `options` tell of, which the messages
+// persisted are mirrored to.
+#[cfg(feature = "postgres")]
+pub fn connect_mirror(options: &PostgresOptions) -> Result<Box<dyn MessageStore>, StoreError> {
+    Ok(Box::new(PostgresStore::connect(options)?))
+}
+
+#[cfg(not(feature = "postgres"))]
+pub fn connect_mirror(_options: &PostgresOptions) -> Result<Box<dyn MessageStore>, StoreError> {
+    Err(StoreError::Rejected(anyhow!(
+        "Mirroring messages to Postgres requires the server to be built with the postgres feature"
+    )))
+}
+
+fn insert_message(
+    conn: &Connection,
+    msg: &DBMessage,
+    compress: bool,
+) -> Result<(), rusqlite::Error> {
+    let compressed = compressed(&msg.message, compress);
+
+    conn.prepare_cached(INSERT_QUERY)?.execute(params![
+        msg.message_id,
+        msg.seq,
+        msg.user_id,
+        msg.room_name,
+        ToSqlOutput::Borrowed(message_value(&msg.message, &compressed)),
+        msg.nickname,
+        msg.shadowed,
+        msg.flagged,
+        msg.ttl.map(auth::ttl_modifier),
+        msg.forwarded.as_ref().map(|forwarded| forwarded.id),
+        msg.forwarded.as_ref().map(|forwarded| &forwarded.room),
+        msg.forwarded.as_ref().map(|forwarded| forwarded.user_id),
+        msg.forwarded
+            .as_ref()
+            .and_then(|forwarded| forwarded.nick.as_ref())
+    ])?;
+
+    Ok(())
+}
+
+// Applies `change` to the messages of the DB, the text of those edited
+// compressed if `compress` is set.
+fn apply_change(
+    conn: &Connection,
+    change: &MessageChange,
+    compress: bool,
+) -> Result<(), rusqlite::Error> {
+    match change {
+        MessageChange::Edited { id, text, flagged } => {
+            let compressed = compressed(text, compress);
+            conn.execute(
+                "UPDATE chat_messages
+                    SET message = ?2, edited_at = CURRENT_TIMESTAMP, flagged = flagged OR ?3
+                    WHERE message_id = ?1",
+                params![
+                    id,
+                    ToSqlOutput::Borrowed(message_value(text, &compressed)),
+                    flagged
+                ],
+            )?;
+        }
+        MessageChange::Deleted { id, deleted_by } => {
+            conn.execute(
+                "UPDATE chat_messages SET deleted_at = CURRENT_TIMESTAMP, deleted_by = ?2
+                    WHERE message_id = ?1 AND deleted_at IS NULL",
+                params![id, deleted_by],
+            )?;
+        }
+        MessageChange::Purged(ids) => {
+            let mut stmt =
+                conn.prepare_cached("DELETE FROM chat_messages WHERE message_id = ?1")?;
+            for id in ids {
+                stmt.execute(params![id])?;
+            }
+        }
+        MessageChange::RoomPurged(room_name) => {
+            conn.execute(
+                "DELETE FROM chat_messages WHERE room_name = ?1",
+                params![room_name],
+            )?;
+        }
+        MessageChange::Anonymized(user_id) => {
+            conn.execute(
+                "UPDATE chat_messages SET user_id = ?1, nickname = NULL WHERE user_id = ?2",
+                params![auth::DELETED_USER_ID, user_id],
+            )?;
+            conn.execute(
+                "UPDATE chat_messages SET forwarded_user_id = ?1, forwarded_nick = NULL
+                    WHERE forwarded_user_id = ?2",
+                params![auth::DELETED_USER_ID, user_id],
+            )?;
+        }
+        MessageChange::Erased(user_id) => {
+            conn.execute(
+                "DELETE FROM chat_messages WHERE user_id = ?1 OR forwarded_user_id = ?1",
+                params![user_id],
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+// `text` compressed if `compress` is set, and it is long enough to be worth it.
+fn compressed(text: &str, compress: bool) -> Option<Vec<u8>> {
+    if compress {
+        compression::compress(text)
+    } else {
+        None
+    }
+}
+
+// How `text` is stored: compressed messages are stored as blobs, telling them
+// apart.
+fn message_value<'a>(text: &'a str, compressed: &'a Option<Vec<u8>>) -> ValueRef<'a> {
+    match compressed {
+        Some(compressed) => ValueRef::Blob(compressed),
+        None => ValueRef::Text(text.as_bytes()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "postgres")]
+    use crate::db::Forwarded;
+
+    // Checks that `store` persists, queries and purges messages.
+    fn check_store<S: SqlStore>(store: &mut S) {
+        let mut msg = DBMessage::new(1, "room1", "Hello there");
+        store.insert(&msg).unwrap();
+        msg.message = String::from("Gone soon");
+        msg.ttl = Some(std::time::Duration::from_secs(0));
+        store.insert(&msg).unwrap();
+        store.commit().unwrap();
+
+        // Queries see what was written, expired messages aside once purged
+        let count = || {
+            let (tx, rx) = std::sync::mpsc::channel();
+            (
+                Box::new(move |conn: &Connection| {
+                    let count: i64 = conn
+                        .query_row("SELECT COUNT(*) FROM chat_messages", [], |row| row.get(0))
+                        .unwrap();
+                    tx.send(count).unwrap();
+                }),
+                rx,
+            )
+        };
+        let (query, rx) = count();
+        store.query(query);
+        assert_eq!(rx.recv().unwrap(), 2);
+
+        assert_eq!(store.purge().unwrap(), 1);
+        let (query, rx) = count();
+        store.query(query);
+        assert_eq!(rx.recv().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_sqlite_store() {
+        let db_path = Path::new("./test_store.db");
+        let mut store = SqliteStore::open(db_path, &Pragmas::default()).unwrap();
+        check_store(&mut store);
+        store.commit().unwrap();
+
+        // Writes rolled back are gone, those not committed yet kept on
+        // reconnecting
+        store
+            .insert(&DBMessage::new(1, "room1", "Rolled back"))
+            .unwrap();
+        store.rollback().unwrap();
+        store.insert(&DBMessage::new(1, "room1", "Kept")).unwrap();
+        assert!(store.reconnect().unwrap());
+        let (tx, rx) = std::sync::mpsc::channel();
+        store.query(Box::new(move |conn: &Connection| {
+            let messages: Vec<String> = conn
+                .prepare("SELECT message FROM chat_messages ORDER BY message_id")
+                .unwrap()
+                .query_map([], |row| row.get(0))
+                .unwrap()
+                .collect::<Result<_, _>>()
+                .unwrap();
+            tx.send(messages).unwrap();
+        }));
+        assert_eq!(rx.recv().unwrap(), vec!["Hello there", "Kept"]);
+
+        store.close().unwrap();
+        std::fs::remove_file(db_path).unwrap();
+    }
+
+    #[test]
+    fn test_compression() {
+        let db_path = Path::new("./test_store_compression.db");
+        let mut store = SqliteStore::open(db_path, &Pragmas::default())
+            .unwrap()
+            .with_compression(true);
+        let text = "All work and no play makes Jack a dull boy. ".repeat(10);
+        store
+            .insert(&DBMessage::new(1, "room1", &text).with_id(1))
+            .unwrap();
+        store
+            .insert(&DBMessage::new(1, "room1", "Too short").with_id(2))
+            .unwrap();
+
+        // Long messages are stored compressed, and read back as they were sent,
+        // searched for and edited alike
+        let (tx, rx) = std::sync::mpsc::channel();
+        store.query(Box::new(move |conn: &Connection| {
+            let stored: Vec<String> = conn
+                .prepare("SELECT typeof(message) FROM chat_messages ORDER BY message_id")
+                .unwrap()
+                .query_map([], |row| row.get(0))
+                .unwrap()
+                .collect::<Result<_, _>>()
+                .unwrap();
+            let read = db::message(conn, "room1", 1, 1).unwrap().unwrap().message;
+            let found: i64 = conn
+                .query_row(
+                    "SELECT COUNT(*) FROM message_search WHERE message_search MATCH 'jack'",
+                    [],
+                    |row| row.get(0),
+                )
+                .unwrap();
+            db::edit_message(conn, 1, 1, "room1", "Edited", false).unwrap();
+            let edited: i64 = conn
+                .query_row(
+                    "SELECT COUNT(*) FROM message_search WHERE message_search MATCH 'jack'",
+                    [],
+                    |row| row.get(0),
+                )
+                .unwrap();
+            tx.send((stored, read, found, edited)).unwrap();
+        }));
+        let (stored, read, found, edited) = rx.recv().unwrap();
+        assert_eq!(stored, vec!["blob", "text"]);
+        assert_eq!(read, text);
+        assert_eq!(found, 1);
+        assert_eq!(edited, 0);
+
+        store.close().unwrap();
+        std::fs::remove_file(db_path).unwrap();
+    }
+
+    #[test]
+    fn test_mirror() {
+        let db_path = Path::new("./test_store_mirror.db");
+        let mirror_path = Path::new("./test_store_mirrored.db");
+        let mirror = SqliteStore::open(mirror_path, &Pragmas::default()).unwrap();
+        let mut store = SqliteStore::open(db_path, &Pragmas::default())
+            .unwrap()
+            .with_mirror(Box::new(mirror));
+        check_store(&mut store);
+        store.commit().unwrap();
+
+        // Writes rolled back are gone from the mirror as well, and those left
+        // committed along with the DB
+        store
+            .insert(&DBMessage::new(1, "room1", "Rolled back"))
+            .unwrap();
+        store.rollback().unwrap();
+        store.insert(&DBMessage::new(1, "room1", "Kept")).unwrap();
+        store.close().unwrap();
+
+        let conn = db::open(mirror_path, &Pragmas::default()).unwrap();
+        let messages: Vec<String> = conn
+            .prepare("SELECT message FROM chat_messages ORDER BY message_id")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(messages, vec!["Hello there", "Kept"]);
+
+        drop(conn);
+        std::fs::remove_file(db_path).unwrap();
+        std::fs::remove_file(mirror_path).unwrap();
+    }
+
+    // Run against the Postgres database at `BI_CHAT_TEST_POSTGRES_URL`, whose
+    // messages are dropped, if set, verifying its certificate against
+    // `BI_CHAT_TEST_POSTGRES_CA_CERT` if connected to over TLS.
+    #[cfg(feature = "postgres")]
+    #[test]
+    fn test_postgres_store() {
+        let url = match std::env::var("BI_CHAT_TEST_POSTGRES_URL") {
+            Ok(url) => url,
+            Err(_) => return,
+        };
+        let options = PostgresOptions {
+            url,
+            ca_cert: std::env::var_os("BI_CHAT_TEST_POSTGRES_CA_CERT").map(PathBuf::from),
+        };
+        let mut store = PostgresStore::connect(&options).unwrap();
+        store.execute("DELETE FROM chat_messages").unwrap();
+        let messages = |store: &PostgresStore| {
+            let client = store.client.clone();
+            run(&store.handle, async move {
+                let rows = client
+                    .query(
+                        "SELECT message_id, message FROM chat_messages ORDER BY message_id",
+                        &[],
+                    )
+                    .await?;
+                Ok(rows
+                    .iter()
+                    .map(|row| (row.get(0), row.get(1)))
+                    .collect::<Vec<(i64, String)>>())
+            })
+            .unwrap()
+        };
+
+        // Messages already there are left as they are, and expired ones
+        // purged
+        store
+            .insert(&DBMessage::new(1, "room1", "Hello there").with_id(1))
+            .unwrap();
+        store
+            .insert(&DBMessage::new(1, "room1", "Same ID").with_id(1))
+            .unwrap();
+        let mut expiring = DBMessage::new(1, "room1", "Gone soon").with_id(2);
+        expiring.ttl = Some(std::time::Duration::from_secs(0));
+        store.insert(&expiring).unwrap();
+        store.commit().unwrap();
+        assert_eq!(store.purge().unwrap(), 1);
+        store.commit().unwrap();
+
+        // Writes rolled back are gone, and rejected ones do not abort the
+        // transaction
+        store
+            .insert(&DBMessage::new(1, "room1", "Rolled back").with_id(3))
+            .unwrap();
+        store.rollback().unwrap();
+        let mut rejected = DBMessage::new(1, "room1", "Rejected").with_id(4);
+        rejected.room_name = String::from("room\0");
+        assert!(!store.insert(&rejected).unwrap_err().is_transient());
+        store
+            .insert(&DBMessage::new(1, "room1", "Nul\0").with_id(5))
+            .unwrap();
+
+        // Writes not committed yet are kept on reconnecting
+        assert!(store.reconnect().unwrap());
+        assert_eq!(
+            messages(&store),
+            vec![
+                (1, String::from("Hello there")),
+                (5, String::from("Nul\u{FFFD}"))
+            ]
+        );
+
+        // Changes made to messages are applied, erasing users taking the
+        // copies of their messages forwarded elsewhere along
+        let forwarded = Forwarded {
+            id: 6,
+            room: String::from("room1"),
+            user_id: 2,
+            nick: None,
+        };
+        store
+            .insert(&DBMessage::new(2, "room1", "Bye").with_id(6))
+            .unwrap();
+        store
+            .insert(
+                &DBMessage::new(1, "room2", "Bye")
+                    .with_id(7)
+                    .with_forwarded(Some(forwarded)),
+            )
+            .unwrap();
+        for change in &[
+            MessageChange::Edited {
+                id: 1,
+                text: String::from("Hello"),
+                flagged: false,
+            },
+            MessageChange::Deleted {
+                id: 5,
+                deleted_by: 1,
+            },
+            MessageChange::Erased(2),
+        ] {
+            store.apply(change).unwrap();
+        }
+        assert_eq!(
+            messages(&store),
+            vec![(1, String::from("Hello")), (5, String::from("Nul\u{FFFD}"))]
+        );
+        store.apply(&MessageChange::Purged(vec![5])).unwrap();
+        store.apply(&MessageChange::Anonymized(1)).unwrap();
+        let client = store.client.clone();
+        let user_ids = run(&store.handle, async move {
+            let rows = client
+                .query("SELECT user_id FROM chat_messages", &[])
+                .await?;
+            Ok(rows.iter().map(|row| row.get(0)).collect::<Vec<i64>>())
+        })
+        .unwrap();
+        assert_eq!(user_ids, vec![auth::DELETED_USER_ID as i64]);
+        store.execute("DELETE FROM chat_messages; COMMIT").unwrap();
+    }
+
+    #[test]
+    fn test_memory_store() {
+        check_store(&mut MemoryStore::new().unwrap());
+    }
+
+    #[test]
+    fn test_store_error() {
+        let failure = |code| {
+            StoreError::from(rusqlite::Error::SqliteFailure(
+                rusqlite::ffi::Error::new(code),
+                None,
+            ))
+        };
+        assert!(failure(rusqlite::ffi::SQLITE_FULL).is_transient());
+        assert!(failure(rusqlite::ffi::SQLITE_BUSY).is_transient());
+        assert!(failure(rusqlite::ffi::SQLITE_LOCKED).is_transient());
+        assert!(failure(rusqlite::ffi::SQLITE_IOERR).is_transient());
+        assert!(!failure(rusqlite::ffi::SQLITE_CONSTRAINT).is_transient());
+        assert!(!StoreError::from(rusqlite::Error::QueryReturnedNoRows).is_transient());
+    }
+}
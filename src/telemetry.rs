@@ -0,0 +1,52 @@
+use opentelemetry::{
+    global::{self, BoxedTracer},
+    trace::TraceError,
+    KeyValue,
+};
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{runtime, trace::TracerProvider, Resource};
+
+// Instrumentation scope of every span the server records.
+const TRACER_NAME: &str = "bi_chat";
+
+// Exports the spans recorded by `tracer` to an OTLP collector, until shut
+// down. Spans are not recorded at all unless it is set up.
+pub struct Telemetry {
+    provider: TracerProvider,
+}
+
+impl Telemetry {
+    // Exports spans over gRPC to the collector at `endpoint`, as
+    // `service_name`.
+    pub fn export(endpoint: &str, service_name: &str) -> Result<Self, TraceError> {
+        let exporter = SpanExporter::builder()
+            .with_tonic()
+            .with_endpoint(endpoint)
+            .build()?;
+        let provider = TracerProvider::builder()
+            .with_batch_exporter(exporter, runtime::Tokio)
+            .with_resource(Resource::new_with_defaults([KeyValue::new(
+                "service.name",
+                String::from(service_name),
+            )]))
+            .build();
+        global::set_tracer_provider(provider.clone());
+
+        Ok(Telemetry { provider })
+    }
+
+    // Exports the spans still batched, then stops exporting.
+    pub async fn shutdown(self) {
+        // Flushing blocks until the batch, exported on the runtime, is sent
+        let provider = self.provider;
+        let shutdown = tokio::task::spawn_blocking(move || provider.shutdown()).await;
+        if let Ok(Err(e)) = shutdown {
+            eprintln!("Unable to export remaining spans: {}", e);
+        }
+    }
+}
+
+// Tracer spans are recorded with, exported if `Telemetry` is set up.
+pub fn tracer() -> BoxedTracer {
+    global::tracer(TRACER_NAME)
+}
@@ -1,53 +1,567 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt::Display,
+    net::IpAddr,
+    sync::Arc,
+    time::Duration,
+};
 
-use futures::{
-    stream::{SplitSink, SplitStream},
-    SinkExt, StreamExt, TryFutureExt,
+use futures::{Sink, SinkExt, Stream, StreamExt, TryFutureExt};
+use opentelemetry::{
+    trace::{FutureExt, Link, Span, SpanKind, TraceContextExt, Tracer},
+    Context, KeyValue,
 };
+use serde::{Deserialize, Serialize};
 use tokio::{
     sync::{
         mpsc::{self, UnboundedReceiver, UnboundedSender},
-        RwLock,
+        oneshot, Mutex, RwLock,
     },
     task::JoinHandle,
+    time::Instant,
+};
+use warp::ws::Message;
+
+use crate::{
+    alert, announcement,
+    auth::{self, scope::Scope, Principal},
+    authz::{self, Action, Role},
+    classifier::ContentHook,
+    cluster::{Cluster, Posted, RemoteConn, RoomRelay, RoomRequest, Sent},
+    config::Config,
+    conversation,
+    db::{self, DBMessage, DbTx, Forwarded, MessageIds},
+    federation::{Federation, RemoteMessage},
+    filter::{FilterAction, WordFilter},
+    guest::{self, Guest},
+    ip_ban::IpNet,
+    mention,
+    moderation::{self, NewSanction, Sanction, SanctionKind},
+    notification::{self, Notification},
+    offline,
+    poll::{self, PollOption},
+    profile,
+    protocol::{Availability, ClientFrame, OnlineUser, ServerEvent},
+    room::{self, TopicUpdate},
+    sink::EventSink,
+    spam::{DuplicateGuard, FloodGuard},
+    store::MessageChange,
+    telemetry,
 };
-use warp::ws::{Message, WebSocket};
 
-use crate::db::{DBMessage, DbTx};
+pub const MAX_NICKNAME_LENGTH: usize = 32;
+
+// Close codes of connections refused by their room. Application codes start
+// at 4000: these mirror HTTP's 401 Unauthorized, 403 Forbidden, 404 Not Found
+// and 429 Too Many Requests.
+pub const WRONG_PASSWORD_CODE: u16 = 4001;
+pub const ACCESS_DENIED_CODE: u16 = 4003;
+pub const ROOM_NOT_FOUND_CODE: u16 = 4004;
+pub const ROOM_FULL_CODE: u16 = 4029;
+
+// Close code of connections kicked out of their room by a moderator.
+pub const KICKED_CODE: u16 = 4000;
+
+// Close code of connections kicked out of their room for flooding it: the
+// standard Policy Violation code.
+pub const FLOOD_CODE: u16 = 1008;
+
+// Close code of connections closed for staying silent too long, mirroring
+// HTTP's 408 Request Timeout.
+pub const IDLE_CODE: u16 = 4008;
+
+// How long users joining a password-protected room without its password have
+// to send it in an `auth` frame.
+const AUTH_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Code told to clients leaving a room joined with a `join` frame: the standard
+// Normal Closure code.
+const LEFT_CODE: u16 = 1000;
+
+// Reason binary frames that could not be decoded are refused with.
+const BINARY_FRAME_REFUSAL: &str =
+    "Binary frames must be encoded in the format negotiated when connecting";
+
+// Most rooms a connection may join with `join` frames, besides the room it
+// was opened to.
+pub const MAX_JOINED_ROOMS: usize = 20;
+
+// Connection ID of `User`s acting without a connection, e.g. to send scheduled
+// messages. Connections are numbered from 1.
+pub const DETACHED_CONN_ID: usize = 0;
+
+// Why a `User` was not let into its room.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Refusal {
+    // The room is password-protected, and its password was not given
+    WrongPassword,
+    // Kept out by the access control list or visibility of the room
+    AccessDenied,
+    // The room was never created, and joining does not create rooms
+    RoomNotFound,
+    // The room already holds as many connections as it may
+    RoomFull { capacity: usize },
+    // The room requires approval, and a moderator turned the user away
+    JoinRejected,
+}
+
+impl Refusal {
+    // Close code of a refused connection.
+    pub fn code(self) -> u16 {
+        match self {
+            Refusal::WrongPassword => WRONG_PASSWORD_CODE,
+            Refusal::AccessDenied | Refusal::JoinRejected => ACCESS_DENIED_CODE,
+            Refusal::RoomNotFound => ROOM_NOT_FOUND_CODE,
+            Refusal::RoomFull { .. } => ROOM_FULL_CODE,
+        }
+    }
+
+    pub fn reason(self) -> &'static str {
+        match self {
+            Refusal::WrongPassword => "Wrong or missing room password",
+            Refusal::AccessDenied => "Not allowed in this room",
+            Refusal::RoomNotFound => "Room does not exist",
+            Refusal::RoomFull { .. } => "Room is full",
+            Refusal::JoinRejected => "Join request rejected",
+        }
+    }
+
+    // Frame closing a refused connection.
+    pub fn close_frame(self) -> Message {
+        Message::close_with(self.code(), self.reason())
+    }
+}
+
+// Connections in a room, by connection ID.
+pub type Users = HashMap<usize, Member>;
+pub type Rooms = Arc<RwLock<HashMap<String, Arc<Mutex<Room>>>>>;
 
-pub type Users = Arc<RwLock<HashMap<usize, mpsc::UnboundedSender<Message>>>>;
-pub type Rooms = Arc<RwLock<HashMap<String, Users>>>;
+// Nicknames of connected users, by user ID.
+pub type Nicks = Arc<RwLock<HashMap<usize, String>>>;
 
 pub type UserTx = UnboundedSender<Message>;
 pub type UserRx = UnboundedReceiver<Message>;
 
-type UserWsTx = SplitSink<WebSocket, Message>;
-type UserWsRx = SplitStream<WebSocket>;
+// A connection to a room.
+#[derive(Debug)]
+pub struct Member {
+    pub user_id: usize,
+    // Login session the connection was opened with, if any
+    pub session_id: Option<String>,
+    // Address the connection was opened from, if known
+    pub addr: Option<IpAddr>,
+    // Role granted to the connection on top of the roles of its user
+    pub granted_role: Role,
+    // Set once the connection has not sent a frame for a while
+    pub away: bool,
+    pub tx: UserTx,
+}
+
+// A connection waiting for a moderator to let it into a room requiring
+// approval. Dropping `approval` turns it away.
+#[derive(Debug)]
+pub struct PendingJoin {
+    pub member: Member,
+    approval: oneshot::Sender<()>,
+}
+
+// How a `User` let in by its room gets in.
+#[derive(Debug)]
+pub enum Admission {
+    // The `User` is in the room
+    Joined,
+    // The `User` waits for a moderator to approve it, which `Receiver` is told
+    Pending(oneshot::Receiver<()>),
+}
+
+// A chat room, along with the `User`s currently connected to it.
+// Messages are sequenced and fanned out while holding the room's lock, so that
+// every `User` in the room observes them in the same order.
+#[derive(Debug, Default)]
+pub struct Room {
+    pub users: Users,
+
+    // Connections waiting for approval to join, by connection ID
+    pub pending: HashMap<usize, PendingJoin>,
+
+    // Sequence number of the last message sent to this room
+    last_seq: i64,
+
+    // Hands what is broadcast to the room over to the other instances of the
+    // server, if clustered
+    relay: Option<RoomRelay>,
+}
+
+impl Room {
+    pub fn new(last_seq: i64) -> Self {
+        Room {
+            users: Users::default(),
+            pending: HashMap::new(),
+            last_seq,
+            relay: None,
+        }
+    }
+
+    pub fn with_relay(mut self, relay: Option<RoomRelay>) -> Self {
+        self.relay = relay;
+        self
+    }
+
+    pub fn next_seq(&mut self) -> i64 {
+        self.last_seq += 1;
+        self.last_seq
+    }
+
+    // Catches up with message `seq` of the room, sequenced by another
+    // instance owning it.
+    pub fn observe_seq(&mut self, seq: i64) {
+        self.last_seq = self.last_seq.max(seq);
+    }
+
+    // Whether every connection of `user_id` to the room, except `skip_conn_id`,
+    // is away. None if they have no such connection.
+    pub fn is_away(&self, user_id: usize, skip_conn_id: Option<usize>) -> Option<bool> {
+        let mut connections = self
+            .users
+            .iter()
+            .filter(|(&conn_id, member)| member.user_id == user_id && Some(conn_id) != skip_conn_id)
+            .peekable();
+        connections.peek()?;
+
+        Some(connections.all(|(_, member)| member.away))
+    }
+
+    // Sends an event to every connection in the room, except `skip_conn_id`,
+    // and to the connections other instances have to it.
+    pub fn broadcast(&self, event: &ServerEvent, skip_conn_id: Option<usize>) {
+        self.broadcast_except(event, skip_conn_id, None);
+    }
+
+    // Broadcasts an event as `broadcast` does, except to `skip_remote`, a
+    // connection to another instance, as well.
+    pub fn broadcast_except(
+        &self,
+        event: &ServerEvent,
+        skip_conn_id: Option<usize>,
+        skip_remote: Option<&RemoteConn>,
+    ) {
+        let tracer = telemetry::tracer();
+        let _span = tracer
+            .span_builder("chat.fan_out")
+            .with_attributes(vec![KeyValue::new("recipients", self.users.len() as i64)])
+            .start(&tracer);
+        if let Some(relay) = &self.relay {
+            relay.publish(event, skip_remote);
+        }
+
+        self.deliver(&event.to_message(), skip_conn_id);
+    }
+
+    // Sends `msg` to every connection in the room, except `skip_conn_id`, on
+    // this instance only.
+    pub fn deliver(&self, msg: &Message, skip_conn_id: Option<usize>) {
+        for (&conn_id, member) in self.users.iter() {
+            if Some(conn_id) != skip_conn_id {
+                // This will only fail if the receiving user has already disconnected -- just skip over
+                if let Err(_disconnected) = member.tx.send(msg.clone()) {}
+            }
+        }
+    }
+
+    // IDs of the connections of `user_id` waiting for approval to join.
+    fn pending_connections(&self, user_id: usize) -> Vec<usize> {
+        self.pending
+            .iter()
+            .filter(|(_, pending)| pending.member.user_id == user_id)
+            .map(|(&conn_id, _)| conn_id)
+            .collect()
+    }
+
+    // Sends `event` to the connections of `user_id` in the room, except
+    // `skip_conn_id`.
+    pub fn send_to_user(&self, event: &ServerEvent, user_id: usize, skip_conn_id: Option<usize>) {
+        let msg = event.to_message();
+        for (&conn_id, member) in self.users.iter() {
+            if member.user_id == user_id && Some(conn_id) != skip_conn_id {
+                // This will only fail if the receiving user has already disconnected -- just skip over
+                if let Err(_disconnected) = member.tx.send(msg.clone()) {}
+            }
+        }
+    }
+}
 
 pub struct User {
+    // Identifies this connection -- a user may be connected more than once
+    pub conn_id: usize,
+
     pub user_id: usize,
 
+    // Nicknames are shared by every connection of a user, and shown alongside
+    // the messages they send
+    pub nicks: Nicks,
+
     pub chat_room: String,
 
     pub user_tx: UserTx,
 
     pub db_tx: DbTx,
+
+    pub message_ids: MessageIds,
+
+    // Role granted to this connection on top of the roles of its user, i.e.
+    // moderator for connections opened with the moderator key
+    pub granted_role: Role,
+
+    // Set for guests, which are restricted by the guest mode of their room.
+    // Guests are only ever connected once: their nickname is released on
+    // disconnection
+    pub guest: Option<Guest>,
+
+    // Login session this connection was opened with, if any. Logging out of
+    // the session, or revoking it, closes the connection
+    pub session_id: Option<String>,
+
+    // Address of the client, if known. Banning it closes the connection
+    pub addr: Option<IpAddr>,
+
+    // Messages sent and edited are put through the word filter, as the filter
+    // action of the room has it
+    pub word_filter: Arc<WordFilter>,
+    pub filter_action: FilterAction,
+
+    // Shared by every connection, so that duplicates are counted across them
+    pub duplicate_guard: Arc<DuplicateGuard>,
+
+    // Shared by every connection, so that floods are counted across them
+    pub flood_guard: Arc<FloodGuard>,
+
+    // Messages sent and edited are then put through the external classifier,
+    // if any
+    pub content_hook: Arc<ContentHook>,
+
+    // Unset for API tokens only allowed to read this `User`'s room, in which
+    // case every frame is refused
+    pub may_write: bool,
+
+    // How long this connection may go without sending a frame before its
+    // user is shown as away, and before it is closed, if ever
+    pub away_after: Option<Duration>,
+    pub offline_after: Option<Duration>,
+
+    // Who this connection acts as, and how the server was configured, to let
+    // it into rooms joined with `join` frames
+    pub principal: Principal,
+    pub config: Arc<Config>,
+
+    // Shares the rooms this `User` loads with other instances of the server,
+    // if clustered
+    pub cluster: Option<Cluster>,
+
+    // Where the messages this `User` sends, and its joining and leaving, are
+    // emitted to, if anywhere
+    pub event_sink: Option<EventSink>,
+
+    // Links to the rooms hosted by other servers, which messages sent to them
+    // are posted through
+    pub federation: Federation,
+
+    // Connection to another instance this `User` acts for, handling what the
+    // instance forwarded to this one as the owner of the room. What it causes
+    // is not broadcast back to that connection, nor forwarded again
+    pub remote_conn: Option<RemoteConn>,
+}
+
+// What became of a message a `User` sent, once handled: failing to handle it
+// at all is an error instead.
+#[derive(Debug, PartialEq)]
+pub enum PostOutcome {
+    // Sequenced and persisted, by this instance or the peer owning its room
+    Posted { id: i64, seq: i64 },
+    // Dropped, its author having been kicked for flooding the room
+    Flooded,
+    // Handed to the server hosting the room, reaching it once relayed back
+    Forwarded,
+    // Turned down, e.g. as its author is muted or its text was blocked
+    Refused(String),
+}
+
+impl PostOutcome {
+    // Fails with the reason the message was refused, if it was.
+    pub fn accepted(self) -> Result<(), anyhow::Error> {
+        match self {
+            PostOutcome::Refused(reason) => Err(anyhow::anyhow!(reason)),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl From<Posted> for PostOutcome {
+    fn from(Posted { id, seq }: Posted) -> Self {
+        PostOutcome::Posted { id, seq }
+    }
+}
+
+// A message sent to a room, once let through by the instance it was sent to,
+// as it is sequenced and persisted: see `User::publish`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Post {
+    pub nick: Option<String>,
+    pub text: String,
+    pub ttl_secs: Option<u64>,
+    // Credits the original author of messages forwarded from another room
+    pub forwarded: Option<Forwarded>,
+    // Options of polls, asking `text`
+    pub poll: Option<Vec<String>>,
+    // Set for messages of shadow-banned users, only shown to themselves
+    pub shadowed: bool,
+    pub flagged: bool,
+}
+
+// Rooms a connection joined with `join` frames, besides the room it was opened
+// to, by room name. Each is joined by a `User` of its own, sharing the
+// connection.
+#[derive(Default)]
+struct Subscriptions {
+    joined: HashMap<String, User>,
+    // Rooms waiting for a moderator to approve the connection, along with what
+    // the room sent it meanwhile
+    pending: HashMap<String, (User, UserRx)>,
+}
+
+impl Subscriptions {
+    fn contains(&self, room_name: &str) -> bool {
+        self.joined.contains_key(room_name) || self.pending.contains_key(room_name)
+    }
+
+    fn len(&self) -> usize {
+        self.joined.len() + self.pending.len()
+    }
+}
+
+// What became of a room joined with a `join` frame, as told to the listen loop
+// of its connection.
+#[derive(Debug)]
+enum RoomOutcome {
+    // A moderator let the connection in
+    Approved,
+    // A moderator turned the connection away
+    Rejected,
+    // The room closed the connection's subscription to it, e.g. kicking it
+    Closed { code: u16, reason: String },
+}
+
+type OutcomeTx = UnboundedSender<(String, RoomOutcome)>;
+
+// A connection frames are exchanged over as over a WebSocket, e.g. a
+// WebSocket re-encoding them in the wire format it negotiated.
+pub trait Socket:
+    Stream<Item = Result<Message, warp::Error>>
+    + Sink<Message, Error = warp::Error>
+    + Send
+    + Unpin
+    + 'static
+{
+}
+
+impl<T> Socket for T where
+    T: Stream<Item = Result<Message, warp::Error>>
+        + Sink<Message, Error = warp::Error>
+        + Send
+        + Unpin
+        + 'static
+{
 }
 
 impl User {
     // Indefinitely listens for messages from a front-end on a WebSocket connection.
-    pub async fn listen(&self, ws: WebSocket, rx: UserRx, rooms: Rooms) {
+    pub async fn listen(&self, ws: impl Socket, rx: UserRx, rooms: Rooms) {
+        let (user_ws_tx, user_ws_rx) = ws.split();
+        self.listen_over(user_ws_tx, user_ws_rx, rx, rooms).await
+    }
+
+    // Listens for frames from a front-end on `user_ws_rx`, sending it what it
+    // is sent over `user_ws_tx`, as over a WebSocket connection. Lets
+    // gateways carrying the same frames over another transport, e.g. gRPC,
+    // serve their connections as WebSocket connections are.
+    pub async fn listen_over<Tx, Rx, E>(
+        &self,
+        user_ws_tx: Tx,
+        mut user_ws_rx: Rx,
+        rx: UserRx,
+        rooms: Rooms,
+    ) where
+        Tx: Sink<Message> + Send + Unpin + 'static,
+        Tx::Error: Display,
+        Rx: Stream<Item = Result<Message, E>> + Unpin,
+        E: Display,
+    {
         println!("Joining room: {}", &self.chat_room);
 
-        let (user_ws_tx, mut user_ws_rx) = ws.split();
+        // Traces the connection for as long as it is in the room. The frames
+        // it sends are traced on their own, linked to it, so that messages can
+        // be followed down to the DB.
+        let tracer = telemetry::tracer();
+        let mut connection = tracer
+            .span_builder("chat.connection")
+            .with_kind(SpanKind::Server)
+            .with_attributes(self.span_attributes())
+            .start(&tracer);
 
         // Dedicated thread to listen and buffer incoming messages
         // Then feeds into WS sink -> WS stream (to be consumed and displayed)
-        let accept_handler = self.accept_messages(rx, user_ws_tx).await;
+        let mut accept_handler = self.accept_messages(rx, user_ws_tx).await;
+
+        // When this connection last sent a frame, whether it has been silent
+        // long enough to be away since, and whether it is being closed for it
+        let mut last_active = Instant::now();
+        let mut away = false;
+        let mut closing = false;
+
+        // Rooms joined over this connection besides this `User`'s room, and
+        // what becomes of them
+        let mut subscriptions = Subscriptions::default();
+        let (outcome_tx, mut outcome_rx) = mpsc::unbounded_channel();
 
         // Main loop: listens for incoming messages from other end of WebSocket
         // "Broadcasting" message sent by this `User` to all other `User`s in the same room
-        while let Some(result) = user_ws_rx.next().await {
+        // Stops early once the server closed the connection.
+        loop {
+            let idle_deadline = match (closing, away) {
+                (true, _) => None,
+                (false, true) => self.offline_after,
+                (false, false) => self.away_after.or(self.offline_after),
+            }
+            .map(|after| last_active + after);
+
+            let result = tokio::select! {
+                result = user_ws_rx.next() => match result {
+                    Some(result) => result,
+                    None => break,
+                },
+                _ = &mut accept_handler => break,
+                Some((room_name, outcome)) = outcome_rx.recv() => {
+                    self.settle(room_name, outcome, &mut subscriptions, &outcome_tx, &rooms)
+                        .await;
+                    continue;
+                }
+                _ = tokio::time::sleep_until(idle_deadline.unwrap_or_else(Instant::now)),
+                    if idle_deadline.is_some() =>
+                {
+                    if !away && self.away_after.is_some() {
+                        away = true;
+                        self.set_away(true, &rooms).await;
+                        for user in subscriptions.joined.values() {
+                            user.set_away(true, &rooms).await;
+                        }
+                    } else {
+                        // The connection is closed once the close frame is sent
+                        closing = true;
+                        let idle = Message::close_with(IDLE_CODE, "Closed for inactivity");
+                        if let Err(_disconnected) = self.user_tx.send(idle) {}
+                    }
+                    continue;
+                }
+            };
             let msg = match result {
                 Ok(msg) => msg,
                 Err(e) => {
@@ -56,102 +570,2431 @@ impl User {
                 }
             };
 
-            match self.send_message(msg, &rooms).await {
-                Ok(_) => (),
-                Err(e) => eprintln!("Failed to send user message: {}", e),
+            last_active = Instant::now();
+            if away {
+                away = false;
+                self.set_away(false, &rooms).await;
+                for user in subscriptions.joined.values() {
+                    user.set_away(false, &rooms).await;
+                }
+            }
+
+            if let Ok(text) = msg.to_str() {
+                let received = tracer
+                    .span_builder("chat.receive")
+                    .with_kind(SpanKind::Consumer)
+                    .with_attributes(self.span_attributes())
+                    .with_links(vec![Link::with_context(connection.span_context().clone())])
+                    .start_with_context(&tracer, &Context::new());
+                self.dispatch(text, &mut subscriptions, &outcome_tx, &rooms)
+                    .with_context(Context::new().with_span(received))
+                    .await;
+            } else if msg.is_binary() {
+                // Binary frames reaching here were not decoded by the format of
+                // the connection, and are refused rather than dropped
+                self.send_event(&ServerEvent::Error {
+                    room: self.chat_room.clone(),
+                    reason: String::from(BINARY_FRAME_REFUSAL),
+                });
             }
         }
 
         // WebSocket connection terminated, `user_ws_rx` Stream should be closed.
-        user_disconnected(&self, &rooms).await;
+        let joined = subscriptions.joined.into_values();
+        let pending = subscriptions.pending.into_values().map(|(user, _)| user);
+        for user in joined.chain(pending) {
+            remove_user_from_room(&user, &rooms).await;
+        }
+        user_disconnected(self, &rooms).await;
         accept_handler.abort();
+        connection.add_event("left", Vec::new());
+    }
+
+    // Attributes of the spans traced for this connection.
+    fn span_attributes(&self) -> Vec<KeyValue> {
+        vec![
+            KeyValue::new("room", self.chat_room.clone()),
+            KeyValue::new("user.id", self.user_id as i64),
+            KeyValue::new("conn.id", self.conn_id as i64),
+        ]
+    }
+
+    // Closes the WebSocket connection of this `User`, once refused by its room.
+    pub async fn refuse(&self, mut ws: impl Socket, rooms: &Rooms, refusal: Refusal) {
+        // Clients not exposing close frames are told the room is full as well
+        if let Refusal::RoomFull { capacity } = refusal {
+            let event = ServerEvent::RoomFull {
+                room: self.chat_room.clone(),
+                capacity,
+            };
+            if let Err(e) = ws.send(event.to_message()).await {
+                eprintln!("WebSocket send error: {}", e);
+            }
+        }
+
+        if let Err(e) = ws.send(refusal.close_frame()).await {
+            eprintln!("WebSocket send error: {}", e);
+        }
+
+        user_disconnected(self, rooms).await;
     }
 
     // Spawn a background task for this `User` to listen to messages from
     // other `User`s.
-    async fn accept_messages(&self, mut rx: UserRx, mut user_ws_tx: UserWsTx) -> JoinHandle<()> {
+    async fn accept_messages<Tx>(&self, mut rx: UserRx, mut user_ws_tx: Tx) -> JoinHandle<()>
+    where
+        Tx: Sink<Message> + Send + Unpin + 'static,
+        Tx::Error: Display,
+    {
         tokio::task::spawn(async move {
             while let Some(message) = rx.recv().await {
+                let is_close = message.is_close();
                 user_ws_tx
                     .send(message)
                     .unwrap_or_else(|e| {
                         eprintln!("WebSocket send error: {}", e);
                     })
                     .await;
+
+                if is_close {
+                    break;
+                }
             }
         })
     }
 
-    // Fires off a message to other `User`s in the same room.
-    async fn send_message(&self, msg: Message, rooms: &Rooms) -> Result<(), anyhow::Error> {
-        let msg = if let Ok(s) = msg.to_str() {
-            s
+    // Replays the last `limit` persisted messages of this `User`'s room, or
+    // every message after `since` for clients resuming where they left off,
+    // returning the sequence number of the last message of the room. Should be
+    // called before the `User` is added to the room, so that history is queued
+    // ahead of any live messages: see `add_user_to_room`.
+    pub async fn send_history(
+        &self,
+        limit: usize,
+        since: Option<i64>,
+    ) -> Result<i64, anyhow::Error> {
+        // Rooms a peer owns have their history kept there
+        if let Some((cluster, owner)) = self.room_owner().await {
+            let request = RoomRequest::History {
+                room: self.chat_room.clone(),
+                user_id: self.user_id,
+                sender: cluster.remote_conn(self.conn_id),
+                limit,
+                since,
+            };
+            let sent: Sent = cluster.forward(&owner, &request).await?;
+            let last_seq = sent.last_seq.unwrap_or_default();
+            self.send_sent(sent)?;
+
+            return Ok(last_seq);
+        }
+
+        let (room_name, user_id) = (self.chat_room.clone(), self.user_id);
+        let (history, polls, last_seq) = db::read(&self.db_tx, move |conn| {
+            // Clients resuming after a message the room does not have, such
+            // as one since purged, are sent its recent history instead
+            let missed = match since {
+                Some(after_id) => db::messages_since(conn, &room_name, user_id, after_id)?,
+                None => None,
+            };
+            let history = match missed {
+                Some(missed) => missed,
+                None => db::recent_messages(conn, &room_name, user_id, limit)?,
+            };
+            let ids: Vec<i64> = history.iter().filter_map(|msg| msg.message_id).collect();
+            let polls = poll::tallies(conn, &ids)?;
+
+            Ok((history, polls, db::last_room_seq(conn, &room_name)?))
+        })
+        .await?;
+        self.send_messages(history, polls)?;
+
+        Ok(last_seq)
+    }
+
+    // Sends the messages of this `User`'s room persisted after sequence number
+    // `after_seq`, which it would miss for being sent between its history and
+    // its joining the room.
+    pub async fn send_missed(&self, after_seq: i64) -> Result<(), anyhow::Error> {
+        if let Some((cluster, owner)) = self.room_owner().await {
+            let request = RoomRequest::Missed {
+                room: self.chat_room.clone(),
+                user_id: self.user_id,
+                sender: cluster.remote_conn(self.conn_id),
+                after_seq,
+            };
+            return self.send_sent(cluster.forward(&owner, &request).await?);
+        }
+
+        let (room_name, user_id) = (self.chat_room.clone(), self.user_id);
+        let (missed, polls) = db::read(&self.db_tx, move |conn| {
+            let missed = db::messages_after_seq(conn, &room_name, user_id, after_seq)?;
+            let ids: Vec<i64> = missed.iter().filter_map(|msg| msg.message_id).collect();
+            let polls = poll::tallies(conn, &ids)?;
+
+            Ok((missed, polls))
+        })
+        .await?;
+
+        self.send_messages(missed, polls)
+    }
+
+    // Sends persisted `messages`, along with the tallies of those that are
+    // polls.
+    fn send_messages(
+        &self,
+        messages: Vec<DBMessage>,
+        mut polls: HashMap<i64, Vec<PollOption>>,
+    ) -> Result<(), anyhow::Error> {
+        for msg in messages {
+            let mut event = ServerEvent::from(msg);
+            if let ServerEvent::Message { id, poll, .. } = &mut event {
+                *poll = polls.remove(id);
+            }
+            self.user_tx.send(event.to_message())?;
+        }
+
+        Ok(())
+    }
+
+    // Hands a text frame received over this `User`'s connection to the
+    // `User` of the room it is meant for, unless it joins or leaves a room.
+    async fn dispatch(
+        &self,
+        text: &str,
+        subscriptions: &mut Subscriptions,
+        outcome_tx: &OutcomeTx,
+        rooms: &Rooms,
+    ) {
+        let (room_name, result) = match ClientFrame::parse(text) {
+            Ok(ClientFrame::Join {
+                room,
+                password,
+                since,
+            }) => {
+                let result = self
+                    .join(&room, password, since, subscriptions, outcome_tx, rooms)
+                    .await;
+                (room, result)
+            }
+            Ok(ClientFrame::Leave { room }) => {
+                let result = self.leave(&room, subscriptions, rooms).await;
+                (room, result)
+            }
+            _ => match ClientFrame::target_room(text) {
+                Some(room_name) if room_name != self.chat_room => {
+                    match subscriptions.joined.get(&room_name) {
+                        Some(user) => return user.handle_message(text, rooms).await,
+                        None if subscriptions.pending.contains_key(&room_name) => {
+                            let reason = "Still waiting for approval to join the room";
+                            (room_name, Err(anyhow::anyhow!(reason)))
+                        }
+                        None => (room_name, Err(anyhow::anyhow!("Not in this room"))),
+                    }
+                }
+                _ => return self.handle_message(text, rooms).await,
+            },
+        };
+
+        if let Err(e) = result {
+            self.send_event(&ServerEvent::Error {
+                room: room_name,
+                reason: e.to_string(),
+            });
+        }
+    }
+
+    // Joins `room_name` over this `User`'s connection, with a `User` of its own
+    // sharing it, as a `join` frame asks. Rooms refusing the connection only
+    // tell it so, without closing it.
+    async fn join(
+        &self,
+        room_name: &str,
+        password: Option<String>,
+        since: Option<i64>,
+        subscriptions: &mut Subscriptions,
+        outcome_tx: &OutcomeTx,
+        rooms: &Rooms,
+    ) -> Result<(), anyhow::Error> {
+        if room_name == self.chat_room || subscriptions.contains(room_name) {
+            return Err(anyhow::anyhow!("Already in this room"));
+        }
+        if self.guest.is_some() {
+            return Err(anyhow::anyhow!("Log in to join more rooms"));
+        }
+        if subscriptions.len() >= MAX_JOINED_ROOMS {
+            return Err(anyhow::anyhow!(
+                "Can not join more than {} rooms besides the room of the connection",
+                MAX_JOINED_ROOMS
+            ));
+        }
+        room::validate_name(room_name)?;
+
+        let read_scope = Scope::Read(Some(String::from(room_name)));
+        if !self.principal.allows(&read_scope) {
+            return Err(anyhow::anyhow!("Token lacks the '{}' scope", read_scope));
+        }
+
+        // What the room sends is held back until the `User` is let in
+        let (room_tx, room_rx) = mpsc::unbounded_channel();
+        let user = self.for_room(room_name, room_tx);
+        let history_seq = user.send_history(self.config.history_limit, since).await?;
+
+        let joined = add_user_to_room(
+            &user,
+            rooms,
+            self.config.explicit_rooms,
+            self.config.room_capacity,
+            password,
+            Some(history_seq),
+        )
+        .await?;
+        match joined {
+            Ok(Admission::Joined) => {
+                forward_room(
+                    user.chat_room.clone(),
+                    room_rx,
+                    self.user_tx.clone(),
+                    outcome_tx.clone(),
+                );
+                user.send_join_requests(rooms).await?;
+                user.announce_join(rooms).await?;
+                subscriptions.joined.insert(user.chat_room.clone(), user);
+            }
+            Ok(Admission::Pending(approval)) => {
+                self.send_event(&ServerEvent::JoinPending {
+                    room: user.chat_room.clone(),
+                });
+                user.request_approval(rooms).await?;
+
+                let (room_name, outcome_tx) = (user.chat_room.clone(), outcome_tx.clone());
+                tokio::task::spawn(async move {
+                    let outcome = match approval.await {
+                        Ok(()) => RoomOutcome::Approved,
+                        Err(_rejected) => RoomOutcome::Rejected,
+                    };
+                    // This will only fail if the connection has already closed
+                    if let Err(_disconnected) = outcome_tx.send((room_name, outcome)) {}
+                });
+                subscriptions
+                    .pending
+                    .insert(user.chat_room.clone(), (user, room_rx));
+            }
+            Err(refusal) => {
+                remove_user_from_room(&user, rooms).await;
+                self.send_event(&ServerEvent::Left {
+                    room: user.chat_room.clone(),
+                    code: refusal.code(),
+                    reason: String::from(refusal.reason()),
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    // Leaves `room_name`, joined with a `join` frame, as a `leave` frame asks.
+    async fn leave(
+        &self,
+        room_name: &str,
+        subscriptions: &mut Subscriptions,
+        rooms: &Rooms,
+    ) -> Result<(), anyhow::Error> {
+        if room_name == self.chat_room {
+            return Err(anyhow::anyhow!(
+                "Can not leave the room of the connection: close it instead"
+            ));
+        }
+
+        let left = ServerEvent::Left {
+            room: String::from(room_name),
+            code: LEFT_CODE,
+            reason: String::from("Left the room"),
+        };
+        if let Some(user) = subscriptions.joined.remove(room_name) {
+            remove_user_from_room(&user, rooms).await;
+            // Told after whatever the room sent before
+            user.send_event(&left);
+        } else if let Some((user, _)) = subscriptions.pending.remove(room_name) {
+            remove_user_from_room(&user, rooms).await;
+            self.send_event(&left);
         } else {
-            return Ok(());
+            return Err(anyhow::anyhow!("Not in this room"));
+        }
+
+        Ok(())
+    }
+
+    // Settles what became of `room_name`, joined with a `join` frame.
+    async fn settle(
+        &self,
+        room_name: String,
+        outcome: RoomOutcome,
+        subscriptions: &mut Subscriptions,
+        outcome_tx: &OutcomeTx,
+        rooms: &Rooms,
+    ) {
+        let (user, refusal) = match outcome {
+            RoomOutcome::Approved => {
+                let (user, room_rx) = match subscriptions.pending.remove(&room_name) {
+                    Some(pending) => pending,
+                    None => return,
+                };
+                forward_room(room_name, room_rx, self.user_tx.clone(), outcome_tx.clone());
+                if let Err(e) = user.announce_join(rooms).await {
+                    eprintln!("Failed to announce joining {}: {}", user.chat_room, e);
+                }
+                subscriptions.joined.insert(user.chat_room.clone(), user);
+                return;
+            }
+            RoomOutcome::Rejected => match subscriptions.pending.remove(&room_name) {
+                Some((user, _)) => {
+                    let refusal = Refusal::JoinRejected;
+                    (user, (refusal.code(), String::from(refusal.reason())))
+                }
+                None => return,
+            },
+            RoomOutcome::Closed { code, reason } => match subscriptions.joined.remove(&room_name) {
+                Some(user) => (user, (code, reason)),
+                None => return,
+            },
         };
 
-        let new_msg = format!("<User#{}>: {}", self.user_id, msg);
+        remove_user_from_room(&user, rooms).await;
+        let (code, reason) = refusal;
+        self.send_event(&ServerEvent::Left {
+            room: room_name,
+            code,
+            reason,
+        });
+    }
+
+    // A `User` joining `room_name` over this `User`'s connection, sent what
+    // the room sends through `user_tx`.
+    fn for_room(&self, room_name: &str, user_tx: UserTx) -> User {
+        User {
+            conn_id: self.conn_id,
+            user_id: self.user_id,
+            nicks: self.nicks.clone(),
+            chat_room: String::from(room_name),
+            user_tx,
+            db_tx: self.db_tx.clone(),
+            message_ids: self.message_ids.clone(),
+            granted_role: self.granted_role,
+            guest: None,
+            session_id: self.session_id.clone(),
+            addr: self.addr,
+            word_filter: self.word_filter.clone(),
+            filter_action: self.config.filter_action(room_name),
+            duplicate_guard: self.duplicate_guard.clone(),
+            flood_guard: self.flood_guard.clone(),
+            content_hook: self.content_hook.clone(),
+            may_write: self
+                .principal
+                .allows(&Scope::Write(Some(String::from(room_name)))),
+            away_after: self.away_after,
+            offline_after: self.offline_after,
+            principal: self.principal.clone(),
+            config: self.config.clone(),
+            cluster: self.cluster.clone(),
+            event_sink: self.event_sink.clone(),
+            federation: self.federation.clone(),
+            remote_conn: None,
+        }
+    }
 
-        // Passes message to DB receiver
-        self.db_tx
-            .send(DBMessage::new(self.user_id, &self.chat_room, msg))?;
+    // Handles a single text frame meant for this `User`'s room.
+    async fn handle_message(&self, text: &str, rooms: &Rooms) {
+        // Marking messages as read, or staying online, is not writing to the
+        // room
+        let frame = ClientFrame::parse(text);
+        if !self.may_write
+            && !matches!(frame, Ok(ClientFrame::Read { .. } | ClientFrame::Heartbeat))
+        {
+            self.send_event(&ServerEvent::Error {
+                room: self.chat_room.clone(),
+                reason: format!("Token lacks the 'write:{}' scope", self.chat_room),
+            });
+            return;
+        }
 
-        let users = rooms
-            .read()
-            .await
-            .get(&self.chat_room)
-            .cloned()
-            .unwrap_or_else(Users::default);
-        for (&uid, tx) in users.read().await.iter() {
-            if self.user_id != uid {
-                // This will only fail if the receiving user has already disconnected -- just skip over
-                if let Err(_disconnected) = tx.send(Message::text(&new_msg)) {}
+        // Frames acting on the messages of rooms a peer owns are handled there
+        let owner = match &frame {
+            Ok(frame) if frame.acts_on_messages() => self.room_owner().await,
+            _ => None,
+        };
+        let result = match (owner, &frame) {
+            (Some((cluster, owner)), Ok(frame)) => {
+                self.forward_frame(cluster, &owner, frame, text).await
+            }
+            _ => self.handle_frame(frame, rooms).await,
+        };
+
+        if let Err(e) = result {
+            eprintln!("Failed to handle user message(uid={}): {}", self.user_id, e);
+            self.send_event(&ServerEvent::Error {
+                room: self.chat_room.clone(),
+                reason: e.to_string(),
+            });
+        }
+    }
+
+    // Handles a frame acting on the messages of this `User`'s room, forwarded
+    // by the instance its connection is to as `text`. See `forward_frame`.
+    pub async fn handle_forwarded(&self, text: &str, rooms: &Rooms) -> Result<(), anyhow::Error> {
+        match ClientFrame::parse(text) {
+            Ok(frame) if frame.acts_on_messages() => self.handle_frame(Ok(frame), rooms).await,
+            Ok(_) => Err(anyhow::anyhow!("Frame does not act on messages")),
+            Err(e) => Err(anyhow::anyhow!("Invalid frame: {}", e)),
+        }
+    }
+
+    // Has `owner`, the peer owning this `User`'s room, handle `frame`, sent
+    // as `text`, as this `User`, which is sent what the peer sent it meanwhile.
+    async fn forward_frame(
+        &self,
+        cluster: &Cluster,
+        owner: &str,
+        frame: &ClientFrame,
+        text: &str,
+    ) -> Result<(), anyhow::Error> {
+        // Only this instance knows the user is a guest
+        if let Some(guest) = &self.guest {
+            match frame {
+                ClientFrame::Forward { .. } => {
+                    return Err(anyhow::anyhow!("Log in to forward messages"))
+                }
+                ClientFrame::Vote { .. } | ClientFrame::Edit { .. } => guest.check_post().await?,
+                _ => {}
             }
         }
 
+        let request = RoomRequest::Frame {
+            room: self.chat_room.clone(),
+            user_id: self.user_id,
+            nick: self.nick().await,
+            role: self.role().await?,
+            principal: self.principal.clone(),
+            sender: cluster.remote_conn(self.conn_id),
+            frame: String::from(text),
+        };
+        let sent = cluster.forward(owner, &request).await?;
+
+        self.send_sent(sent)
+    }
+
+    // Sends this `User` what the owner of its room sent it.
+    fn send_sent(&self, sent: Sent) -> Result<(), anyhow::Error> {
+        for event in sent.events {
+            self.user_tx.send(Message::text(event))?;
+        }
+
         Ok(())
     }
-}
 
-// Adds a `User` to a room, creating one if it does not exist.
-pub async fn add_user_to_room(new_user: &User, rooms: &Rooms) {
-    let mut room = rooms.write().await;
-    let users = room
-        .entry(new_user.chat_room.clone())
-        .or_insert_with(Users::default);
+    // The peer owning this `User`'s room, along with the cluster it is in,
+    // unless this instance owns it or acts for a peer already: see
+    // `Cluster::owner`.
+    async fn room_owner(&self) -> Option<(&Cluster, String)> {
+        if self.remote_conn.is_some() {
+            return None;
+        }
 
-    users
-        .write()
-        .await
-        .insert(new_user.user_id, new_user.user_tx.clone());
-}
+        let cluster = self.cluster.as_ref()?;
+        let owner = cluster.owner(&self.chat_room).await?;
 
-// Removes a `User` from a room.
-// The "room" is also cleaned up if there are no users remaining.
-async fn remove_user_from_room(user: &User, rooms: &Rooms) {
-    let mut room = rooms.write().await;
-    let room_empty = {
-        let mut users = room
-            .entry(user.chat_room.clone())
-            .or_insert_with(Users::default)
-            .write()
-            .await;
+        Some((cluster, owner))
+    }
 
-        users.remove(&user.user_id);
+    // Handles a frame meant for this `User`'s room.
+    async fn handle_frame(
+        &self,
+        frame: Result<ClientFrame, serde_json::Error>,
+        rooms: &Rooms,
+    ) -> Result<(), anyhow::Error> {
+        match frame {
+            Ok(ClientFrame::Message {
+                text,
+                client_id,
+                ttl_secs,
+            }) => self
+                .send_message(&text, client_id, ttl_secs, None, None, rooms)
+                .await?
+                .accepted(),
+            Ok(ClientFrame::Poll {
+                question,
+                options,
+                client_id,
+            }) => self.send_poll(&question, options, client_id, rooms).await,
+            Ok(ClientFrame::Vote { id, option }) => self.vote(id, option, rooms).await,
+            Ok(ClientFrame::Forward { id, to }) => self.forward_message(id, to, rooms).await,
+            Ok(ClientFrame::Edit { id, text }) => self.edit_message(id, text, rooms).await,
+            Ok(ClientFrame::Delete { id }) => self.delete_message(id, rooms).await,
+            Ok(ClientFrame::Pin { id }) => self.pin_message(id, rooms).await,
+            Ok(ClientFrame::Unpin { id }) => self.unpin_message(id, rooms).await,
+            Ok(ClientFrame::Read { id }) => self.mark_read(id, rooms).await,
+            Ok(ClientFrame::Heartbeat) => Ok(()),
+            Ok(ClientFrame::SetNick { nick }) => self.set_nick(nick, rooms).await,
+            Ok(ClientFrame::Auth { .. }) => Err(anyhow::anyhow!("Already joined the room")),
+            Ok(ClientFrame::SetTopic { topic, description }) => {
+                self.set_topic(TopicUpdate { topic, description }, rooms)
+                    .await
+            }
+            Ok(ClientFrame::Kick { user_id }) => self.kick(user_id, rooms).await,
+            Ok(ClientFrame::Ban {
+                user_id,
+                reason,
+                duration_secs,
+            }) => {
+                let ban = NewSanction {
+                    reason,
+                    duration_secs,
+                };
+                self.sanction(user_id, SanctionKind::Ban, ban, rooms).await
+            }
+            Ok(ClientFrame::Mute {
+                user_id,
+                reason,
+                duration_secs,
+            }) => {
+                let mute = NewSanction {
+                    reason,
+                    duration_secs,
+                };
+                self.sanction(user_id, SanctionKind::Mute, mute, rooms)
+                    .await
+            }
+            Ok(ClientFrame::ApproveJoin { user_id }) => self.approve_join(user_id, rooms).await,
+            Ok(ClientFrame::RejectJoin { user_id }) => self.reject_join(user_id, rooms).await,
+            // Handled by the connection, see `dispatch`
+            Ok(ClientFrame::Join { .. } | ClientFrame::Leave { .. }) => Ok(()),
+            Err(e) => Err(anyhow::anyhow!("Invalid frame: {}", e)),
+        }
+    }
 
-        // Extra check to see if room is empty
-        users.is_empty()
-    };
+    // Marks this connection as away, or back online, notifying everyone in the
+    // room if that changed whether its user is away.
+    async fn set_away(&self, away: bool, rooms: &Rooms) {
+        if let Some(room) = rooms.read().await.get(&self.chat_room) {
+            let mut room = room.lock().await;
+            let was_away = room.is_away(self.user_id, None);
+            if let Some(member) = room.users.get_mut(&self.conn_id) {
+                member.away = away;
+            }
 
-    // Cleans up room, if empty
-    if room_empty {
-        room.remove(&user.chat_room);
+            if room.is_away(self.user_id, None) != was_away {
+                room.broadcast(&self.status_event(away), None);
+            }
+        }
     }
-}
 
-// User has been disconnected from the WebSocket connection.
-async fn user_disconnected(user: &User, rooms: &Rooms) {
-    eprintln!("User disconnected: {}", user.user_id);
+    // Tells the room whether this `User` is away.
+    fn status_event(&self, away: bool) -> ServerEvent {
+        ServerEvent::Status {
+            room: self.chat_room.clone(),
+            user_id: self.user_id,
+            status: Availability::of(away),
+        }
+    }
 
-    remove_user_from_room(user, rooms).await;
+    // Sends an event to this `User` only.
+    fn send_event(&self, event: &ServerEvent) {
+        // This will only fail if this user has already disconnected
+        if let Err(_disconnected) = self.user_tx.send(event.to_message()) {}
+    }
+
+    // Emits an event of this `User`'s room to the event sink, if any.
+    fn emit(&self, event: &ServerEvent) {
+        if let Some(sink) = &self.event_sink {
+            sink.emit(&self.chat_room, event);
+        }
+    }
+
+    // The room this `User` is connected to.
+    async fn room(&self, rooms: &Rooms) -> Result<Arc<Mutex<Room>>, anyhow::Error> {
+        rooms
+            .read()
+            .await
+            .get(&self.chat_room)
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("Room {} not found", self.chat_room))
+    }
+
+    // The room this `User` posts to. `User`s without a connection, e.g.
+    // sending scheduled messages or acting for a peer's connection, may post
+    // to rooms no one is in, which are loaded for them: see `release_room`.
+    async fn posting_room(&self, rooms: &Rooms) -> Result<Arc<Mutex<Room>>, anyhow::Error> {
+        if self.conn_id != DETACHED_CONN_ID {
+            return self.room(rooms).await;
+        }
+
+        load_room(rooms, &self.chat_room, &self.db_tx, self.cluster.as_ref())
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("Room {} not found", self.chat_room))
+    }
+
+    // Fires off a message to other `User`s in the same room, acknowledging it
+    // back to this `User` once accepted. `forwarded` credits the original
+    // author of messages forwarded from another room, and `poll` has the
+    // options of polls, asking `msg`.
+    pub async fn send_message(
+        &self,
+        msg: &str,
+        client_id: Option<String>,
+        ttl_secs: Option<u64>,
+        forwarded: Option<Forwarded>,
+        poll: Option<Vec<String>>,
+        rooms: &Rooms,
+    ) -> Result<PostOutcome, anyhow::Error> {
+        if let Some(guest) = &self.guest {
+            if let Err(e) = guest.check_post().await {
+                return Ok(PostOutcome::Refused(e.to_string()));
+            }
+        }
+
+        // Flooding gets the user kicked, and the message dropped
+        if !self.flood_guard.allows(self.user_id).await {
+            let (max_messages, window) =
+                (self.flood_guard.max_messages(), self.flood_guard.window());
+            flood_kick(&self.chat_room, self.user_id, max_messages, window, rooms).await;
+            return Ok(PostOutcome::Flooded);
+        }
+
+        let (user_id, room_name) = (self.user_id, self.chat_room.clone());
+        let (read_only, muted, shadow_banned) = db::query(&self.db_tx, move |conn| {
+            Ok((
+                room::is_read_only(conn, &room_name)?,
+                moderation::is_sanctioned(conn, &room_name, user_id, SanctionKind::Mute)?,
+                moderation::is_sanctioned(conn, &room_name, user_id, SanctionKind::ShadowBan)?,
+            ))
+        })
+        .await?;
+        if muted {
+            return Ok(PostOutcome::Refused("You are muted in this room".into()));
+        }
+        if read_only && !self.may(Action::PostReadOnly).await? {
+            return Ok(PostOutcome::Refused("Room is read-only".into()));
+        }
+        let filtered = match self.word_filter.apply(self.filter_action, msg) {
+            Ok(filtered) => filtered,
+            Err(e) => return Ok(PostOutcome::Refused(e.to_string())),
+        };
+        if let Err(e) = self.duplicate_guard.check(self.user_id, msg).await {
+            return Ok(PostOutcome::Refused(e.to_string()));
+        }
+        let flagged = match self
+            .content_hook
+            .check(&self.chat_room, self.user_id, &filtered.text)
+            .await
+        {
+            Ok(flagged) => flagged || filtered.flagged,
+            Err(e) => return Ok(PostOutcome::Refused(e.to_string())),
+        };
+
+        // Messages to rooms hosted by another server are posted there, and
+        // reach this room once relayed back
+        if let Some(link) = self.federation.link(&self.chat_room) {
+            link.send(RemoteMessage {
+                user_id: self.user_id,
+                nick: self.nick().await,
+                text: filtered.text,
+            });
+            return Ok(PostOutcome::Forwarded);
+        }
+
+        let post = Post {
+            nick: self.nick().await,
+            text: filtered.text,
+            ttl_secs,
+            forwarded,
+            poll,
+            shadowed: shadow_banned,
+            flagged,
+        };
+
+        // Messages to rooms a peer owns are sequenced and persisted there, and
+        // reach this room once relayed back
+        if let Some((cluster, owner)) = self.room_owner().await {
+            return self
+                .post_to_owner(cluster, &owner, post, client_id, rooms)
+                .await
+                .map(PostOutcome::from);
+        }
+
+        self.publish(post, client_id, rooms)
+            .await
+            .map(PostOutcome::from)
+    }
+
+    // Sequences, persists and broadcasts `post` to this `User`'s room,
+    // acknowledging it back to this `User`, then notifies whoever it concerns.
+    pub async fn publish(
+        &self,
+        post: Post,
+        client_id: Option<String>,
+        rooms: &Rooms,
+    ) -> Result<Posted, anyhow::Error> {
+        let room = self.posting_room(rooms).await?;
+
+        // Room stays locked until the message has been handed to every `User`,
+        // so that messages are delivered in sequence order.
+        let mut room = room.lock().await;
+        let id = self.message_ids.next_id();
+        let seq = room.next_seq();
+        let new_msg = self.message_event(id, seq, &post);
+        let posted = Posted { id, seq };
+        let Post {
+            nick,
+            text,
+            ttl_secs,
+            forwarded,
+            poll,
+            shadowed,
+            flagged,
+        } = post;
+
+        // Messages cross-posted from an announcement room are never
+        // cross-posted again, so that rooms following each other do not loop
+        let original = match forwarded {
+            Some(_) => None,
+            None => Some(Forwarded {
+                id,
+                room: self.chat_room.clone(),
+                user_id: self.user_id,
+                nick: nick.clone(),
+            }),
+        };
+
+        // Passes message to DB receiver, unless it is not to be kept at all
+        let ephemeral = ttl_secs == Some(0);
+        if !ephemeral {
+            db::insert(
+                &self.db_tx,
+                DBMessage::new(self.user_id, &self.chat_room, &text)
+                    .with_id(id)
+                    .with_seq(seq)
+                    .with_nickname(nick)
+                    .with_shadowed(shadowed)
+                    .with_flagged(flagged)
+                    .with_ttl(ttl_secs.map(Duration::from_secs))
+                    .with_forwarded(forwarded),
+            )?;
+        }
+        if let Some(options) = poll {
+            let room_name = self.chat_room.clone();
+            db::query(&self.db_tx, move |conn| {
+                poll::create_poll(conn, id, &room_name, &options)
+            })
+            .await?;
+        }
+        self.send_event(&ServerEvent::Ack {
+            id,
+            room: self.chat_room.clone(),
+            client_id,
+        });
+
+        // Messages of shadow-banned users are kept, but only shown to their
+        // own connections, so that they look delivered
+        if shadowed {
+            room.send_to_user(&new_msg, self.user_id, Some(self.conn_id));
+            return Ok(posted);
+        }
+        room.broadcast_except(&new_msg, Some(self.conn_id), self.remote_conn.as_ref());
+        drop(room);
+        if !ephemeral {
+            self.emit(&new_msg);
+        }
+
+        // Mentions, alerts and queued messages all point to the persisted
+        // message, which is posted by now whether or not they fail
+        if ephemeral {
+            return Ok(posted);
+        }
+        if let Some(original) = original {
+            if let Err(e) = self.cross_post(original, &text, ttl_secs, rooms).await {
+                eprintln!("Failed to cross-post message {}: {}", id, e);
+            }
+        }
+        if let Err(e) = self.queue_direct(id, &new_msg, rooms).await {
+            eprintln!("Failed to queue message {} for offline users: {}", id, e);
+        }
+        if let Err(e) = self.notify_mentions(id, &text, rooms).await {
+            eprintln!("Failed to notify mentions in message {}: {}", id, e);
+        }
+        if let Err(e) = self.notify_alerts(id, &text, rooms).await {
+            eprintln!("Failed to notify alerts on message {}: {}", id, e);
+        }
+
+        Ok(posted)
+    }
+
+    // Has `owner`, the peer owning this `User`'s room, publish `post`,
+    // acknowledging it back to this `User` once it did.
+    async fn post_to_owner(
+        &self,
+        cluster: &Cluster,
+        owner: &str,
+        post: Post,
+        client_id: Option<String>,
+        rooms: &Rooms,
+    ) -> Result<Posted, anyhow::Error> {
+        // Messages of shadow-banned users are not relayed: their connections
+        // to this instance are shown them from here
+        let shadowed = post.shadowed.then(|| post.clone());
+        let request = RoomRequest::Post {
+            room: self.chat_room.clone(),
+            user_id: self.user_id,
+            sender: cluster.remote_conn(self.conn_id),
+            post,
+        };
+        let Posted { id, seq } = cluster.forward(owner, &request).await?;
+        self.send_event(&ServerEvent::Ack {
+            id,
+            room: self.chat_room.clone(),
+            client_id,
+        });
+
+        if let Some(post) = shadowed {
+            if let Some(room) = rooms.read().await.get(&self.chat_room) {
+                let event = self.message_event(id, seq, &post);
+                let room = room.lock().await;
+                room.send_to_user(&event, self.user_id, Some(self.conn_id));
+            }
+        }
+
+        Ok(Posted { id, seq })
+    }
+
+    // Message `id` of this `User`'s room, numbered `seq`, as `post` has it.
+    fn message_event(&self, id: i64, seq: i64, post: &Post) -> ServerEvent {
+        ServerEvent::Message {
+            id,
+            seq,
+            room: self.chat_room.clone(),
+            user_id: self.user_id,
+            nick: post.nick.clone(),
+            text: post.text.clone(),
+            edited_at: None,
+            deleted_at: None,
+            ttl_secs: post.ttl_secs,
+            forwarded: post.forwarded.clone(),
+            poll: post.poll.as_ref().map(|options| {
+                options
+                    .iter()
+                    .map(|text| PollOption {
+                        text: text.clone(),
+                        votes: 0,
+                    })
+                    .collect()
+            }),
+        }
+    }
+
+    // Sends a poll asking `question` to this `User`'s room, with `options`
+    // put through the word filter as the question is.
+    async fn send_poll(
+        &self,
+        question: &str,
+        options: Vec<String>,
+        client_id: Option<String>,
+        rooms: &Rooms,
+    ) -> Result<(), anyhow::Error> {
+        poll::validate(question, &options)?;
+        let options = options
+            .iter()
+            .map(|option| {
+                self.word_filter
+                    .apply(self.filter_action, option)
+                    .map(|filtered| filtered.text)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        self.send_message(question, client_id, None, None, Some(options), rooms)
+            .await?
+            .accepted()
+    }
+
+    // Votes for option `option` of poll `id` of this `User`'s room, sending
+    // its new tally to everyone in the room.
+    async fn vote(&self, id: i64, option: usize, rooms: &Rooms) -> Result<(), anyhow::Error> {
+        if let Some(guest) = &self.guest {
+            guest.check_post().await?;
+        }
+        let room = self.posting_room(rooms).await?;
+
+        // Keep the room locked while voting, so that tallies reach everyone
+        // in the order votes were counted
+        let room = room.lock().await;
+        let (user_id, room_name) = (self.user_id, self.chat_room.clone());
+        let tally = db::query(&self.db_tx, move |conn| {
+            Ok(poll::vote(conn, &room_name, id, user_id, option))
+        })
+        .await??;
+
+        match tally {
+            Some(options) => {
+                room.broadcast(
+                    &ServerEvent::PollTally {
+                        id,
+                        room: self.chat_room.clone(),
+                        options,
+                    },
+                    None,
+                );
+                Ok(())
+            }
+            None => Err(anyhow::anyhow!("Poll {} not found", id)),
+        }
+    }
+
+    // Cross-posts message `original`, sent by this `User` to an announcement
+    // room, to the rooms following it, whether or not anyone is in them.
+    async fn cross_post(
+        &self,
+        original: Forwarded,
+        text: &str,
+        ttl_secs: Option<u64>,
+        rooms: &Rooms,
+    ) -> Result<(), anyhow::Error> {
+        let room_name = self.chat_room.clone();
+        let targets = db::query(&self.db_tx, move |conn| {
+            announcement::followers(conn, &room_name)
+        })
+        .await?;
+
+        for target in targets {
+            // Rooms a peer owns are posted to there
+            let owner = match &self.cluster {
+                Some(cluster) => cluster.owner(&target).await.map(|owner| (cluster, owner)),
+                None => None,
+            };
+            if let Some((cluster, owner)) = owner {
+                let request = RoomRequest::CrossPost {
+                    room: target,
+                    user_id: self.user_id,
+                    original: original.clone(),
+                    text: String::from(text),
+                    ttl_secs,
+                };
+                cluster.forward::<Option<Posted>>(&owner, &request).await?;
+                continue;
+            }
+
+            self.cross_post_to(&target, original.clone(), text, ttl_secs, rooms)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    // Cross-posts message `original` to room `target`, which follows the
+    // announcement room it was sent to, whether or not anyone is in it.
+    pub async fn cross_post_to(
+        &self,
+        target: &str,
+        original: Forwarded,
+        text: &str,
+        ttl_secs: Option<u64>,
+        rooms: &Rooms,
+    ) -> Result<Option<Posted>, anyhow::Error> {
+        let room = load_room(rooms, target, &self.db_tx, self.cluster.as_ref()).await?;
+        let room = match room {
+            Some(room) => room,
+            None => return Ok(None),
+        };
+
+        let mut room = room.lock().await;
+        let (id, seq) = (self.message_ids.next_id(), room.next_seq());
+        db::insert(
+            &self.db_tx,
+            DBMessage::new(self.user_id, target, text)
+                .with_id(id)
+                .with_seq(seq)
+                .with_nickname(original.nick.clone())
+                .with_ttl(ttl_secs.map(Duration::from_secs))
+                .with_forwarded(Some(original.clone())),
+        )?;
+        let event = ServerEvent::Message {
+            id,
+            seq,
+            room: String::from(target),
+            user_id: self.user_id,
+            nick: original.nick.clone(),
+            text: String::from(text),
+            edited_at: None,
+            deleted_at: None,
+            ttl_secs,
+            forwarded: Some(original),
+            poll: None,
+        };
+        room.broadcast(&event, None);
+        drop(room);
+        if let Some(sink) = &self.event_sink {
+            sink.emit(target, &event);
+        }
+        release_room(target, rooms).await;
+
+        Ok(Some(Posted { id, seq }))
+    }
+
+    // Users connected to any room, on this instance or the peers it knows of.
+    async fn online_user_ids(&self, rooms: &Rooms) -> HashSet<usize> {
+        let mut online = online_user_ids(rooms).await;
+        if let Some(cluster) = &self.cluster {
+            online.extend(cluster.remote_user_ids().await);
+        }
+
+        online
+    }
+
+    // Sends `event` to every connection of `user_id`, on this instance or
+    // others.
+    async fn notify_user(&self, user_id: usize, rooms: &Rooms, event: &ServerEvent) {
+        if let Some(cluster) = &self.cluster {
+            cluster.notify_user(user_id, event);
+        }
+        notify_user(user_id, rooms, event).await;
+    }
+
+    // Queues message `id`, sent by this `User` to a conversation, for those
+    // taking part in it who are offline, unless they muted it.
+    async fn queue_direct(
+        &self,
+        id: i64,
+        event: &ServerEvent,
+        rooms: &Rooms,
+    ) -> Result<(), anyhow::Error> {
+        if !conversation::is_conversation(&self.chat_room) {
+            return Ok(());
+        }
+
+        let online = self.online_user_ids(rooms).await;
+        let (user_id, room_name, event) = (self.user_id, self.chat_room.clone(), event.to_json());
+        db::query(&self.db_tx, move |conn| {
+            let offline = room::members(conn, &room_name)?
+                .into_iter()
+                .map(|member| member.user_id)
+                .filter(|member| *member != user_id && !online.contains(member))
+                .collect();
+            for recipient in
+                notification::recipients(conn, &room_name, offline, Notification::Direct)?
+            {
+                offline::enqueue(conn, recipient, &room_name, id, &event)?;
+            }
+
+            Ok(())
+        })
+        .await?;
+
+        Ok(())
+    }
+
+    // Notifies the users mentioned in message `id`, sent by this `User`, unless
+    // they muted this `User`'s room.
+    async fn notify_mentions(
+        &self,
+        id: i64,
+        text: &str,
+        rooms: &Rooms,
+    ) -> Result<(), anyhow::Error> {
+        let usernames = mention::parse_mentions(text);
+        if usernames.is_empty() {
+            return Ok(());
+        }
+
+        let (user_id, room_name) = (self.user_id, self.chat_room.clone());
+        let mentioned = db::query(&self.db_tx, move |conn| {
+            let mentioned = mention::record_mentions(conn, id, &room_name, user_id, &usernames)?;
+            notification::recipients(conn, &room_name, mentioned, Notification::Mention)
+        })
+        .await?;
+
+        let event = ServerEvent::Mention {
+            id,
+            room: self.chat_room.clone(),
+            user_id: self.user_id,
+            nick: self.nick().await,
+            text: String::from(text),
+        };
+
+        // Users who are offline are told once they connect again
+        let online = self.online_user_ids(rooms).await;
+        let (offline, online): (Vec<usize>, Vec<usize>) = mentioned
+            .into_iter()
+            .partition(|user_id| !online.contains(user_id));
+        for user_id in online {
+            self.notify_user(user_id, rooms, &event).await;
+        }
+        if !offline.is_empty() {
+            let (room_name, event) = (self.chat_room.clone(), event.to_json());
+            db::query(&self.db_tx, move |conn| {
+                for user_id in offline {
+                    offline::enqueue(conn, user_id, &room_name, id, &event)?;
+                }
+
+                Ok(())
+            })
+            .await?;
+        }
+
+        Ok(())
+    }
+
+    // Alerts the users watching keywords found in message `id`, sent by this
+    // `User`. Alerts are recorded for users who are offline, or do not get
+    // alerts of this room, to fetch later.
+    async fn notify_alerts(&self, id: i64, text: &str, rooms: &Rooms) -> Result<(), anyhow::Error> {
+        let (user_id, room_name, message) =
+            (self.user_id, self.chat_room.clone(), String::from(text));
+        let alerted = db::query(&self.db_tx, move |conn| {
+            let alerted = alert::record_alerts(conn, id, &room_name, user_id, &message)?;
+            let user_ids = alerted.iter().map(|(user_id, _)| *user_id).collect();
+            let recipients =
+                notification::recipients(conn, &room_name, user_ids, Notification::Alert)?;
+
+            Ok(alerted
+                .into_iter()
+                .filter(|(user_id, _)| recipients.contains(user_id))
+                .collect::<Vec<_>>())
+        })
+        .await?;
+
+        let nick = self.nick().await;
+        for (user_id, alert) in alerted {
+            let event = ServerEvent::Alert {
+                alert_id: alert.id,
+                id,
+                room: alert.room,
+                user_id: alert.author_id,
+                nick: nick.clone(),
+                text: alert.text,
+                keyword: alert.keyword,
+            };
+            self.notify_user(user_id, rooms, &event).await;
+        }
+
+        Ok(())
+    }
+
+    // Forwards message `id` of this `User`'s room to room `to`, as if this
+    // `User` sent it there, crediting whoever first sent it. Users may forward
+    // messages to any room they may join, whether or not they are in it.
+    async fn forward_message(
+        &self,
+        id: i64,
+        to: String,
+        rooms: &Rooms,
+    ) -> Result<(), anyhow::Error> {
+        if self.guest.is_some() {
+            return Err(anyhow::anyhow!("Log in to forward messages"));
+        }
+        if to == self.chat_room {
+            return Err(anyhow::anyhow!("Can not forward a message to its own room"));
+        }
+        room::validate_name(&to)?;
+
+        let write_scope = Scope::Write(Some(to.clone()));
+        if !self.principal.allows(&write_scope) {
+            return Err(anyhow::anyhow!("Token lacks the '{}' scope", write_scope));
+        }
+
+        let (user_id, room_name, target) = (self.user_id, self.chat_room.clone(), to.clone());
+        let (msg, may_join) = db::query(&self.db_tx, move |conn| {
+            Ok((
+                db::message(conn, &room_name, user_id, id)?,
+                authz::may_join(conn, user_id, &target)?,
+            ))
+        })
+        .await?;
+        let msg = match msg {
+            Some(msg) if msg.deleted_at.is_none() => msg,
+            _ => return Err(anyhow::anyhow!("Message {} not found", id)),
+        };
+        if !may_join {
+            return Err(anyhow::anyhow!("Not allowed in room {}", to));
+        }
+
+        // Messages forwarded again keep crediting where they were first sent
+        let forwarded = msg.forwarded.unwrap_or(Forwarded {
+            id,
+            room: msg.room_name,
+            user_id: msg.user_id,
+            nick: msg.nickname,
+        });
+        let forwarder = User {
+            conn_id: DETACHED_CONN_ID,
+            ..self.for_room(&to, self.user_tx.clone())
+        };
+        let sent = forwarder
+            .send_message(&msg.message, None, None, Some(forwarded), None, rooms)
+            .await;
+        release_room(&to, rooms).await;
+
+        sent?.accepted()
+    }
+
+    // Replaces the content of a message previously sent by this `User`,
+    // notifying everyone in the room of the edit.
+    async fn edit_message(
+        &self,
+        id: i64,
+        text: String,
+        rooms: &Rooms,
+    ) -> Result<(), anyhow::Error> {
+        if let Some(guest) = &self.guest {
+            guest.check_post().await?;
+        }
+
+        let filtered = self.word_filter.apply(self.filter_action, &text)?;
+        let flagged = self
+            .content_hook
+            .check(&self.chat_room, self.user_id, &filtered.text)
+            .await?
+            || filtered.flagged;
+        let room = self.posting_room(rooms).await?;
+
+        // Keep the room locked while editing, so that concurrent edits of the
+        // same message reach everyone in the order they were persisted.
+        let room = room.lock().await;
+        let (user_id, room_name) = (self.user_id, self.chat_room.clone());
+        let text = filtered.text;
+        let new_text = text.clone();
+        let edited = db::change(&self.db_tx, move |conn| {
+            let edited = db::edit_message(conn, id, user_id, &room_name, &new_text, flagged)?;
+            let changes = if edited {
+                vec![MessageChange::Edited {
+                    id,
+                    text: new_text,
+                    flagged,
+                }]
+            } else {
+                Vec::new()
+            };
+            Ok((edited, changes))
+        })
+        .await?;
+
+        if !edited {
+            return Err(anyhow::anyhow!(
+                "Message {} not found or not sent by you",
+                id
+            ));
+        }
+
+        room.broadcast(
+            &ServerEvent::Edit {
+                id,
+                room: self.chat_room.clone(),
+                user_id: self.user_id,
+                text,
+            },
+            None,
+        );
+
+        Ok(())
+    }
+
+    // Deletes a message from this `User`'s room, notifying everyone in the room.
+    // Messages can only be deleted by their author or by a moderator.
+    async fn delete_message(&self, id: i64, rooms: &Rooms) -> Result<(), anyhow::Error> {
+        let may_delete_any = self.may(Action::DeleteAnyMessage).await?;
+        let room = self.posting_room(rooms).await?;
+
+        let room = room.lock().await;
+        let (user_id, room_name) = (self.user_id, self.chat_room.clone());
+        let author_id = if may_delete_any { None } else { Some(user_id) };
+        let deleted = db::change(&self.db_tx, move |conn| {
+            let deleted = db::delete_message(conn, id, &room_name, user_id, author_id)?;
+            let changes = if deleted {
+                vec![MessageChange::Deleted {
+                    id,
+                    deleted_by: user_id,
+                }]
+            } else {
+                Vec::new()
+            };
+            Ok((deleted, changes))
+        })
+        .await?;
+
+        if !deleted {
+            return Err(anyhow::anyhow!(
+                "Message {} not found or not deletable by you",
+                id
+            ));
+        }
+
+        room.broadcast(
+            &ServerEvent::Delete {
+                id,
+                room: self.chat_room.clone(),
+                deleted_by: self.user_id,
+            },
+            None,
+        );
+
+        Ok(())
+    }
+
+    // Pins a message to this `User`'s room, notifying everyone in the room.
+    async fn pin_message(&self, id: i64, rooms: &Rooms) -> Result<(), anyhow::Error> {
+        if !self.may(Action::PinMessage).await? {
+            return Err(anyhow::anyhow!("Only moderators can pin messages"));
+        }
+
+        let room = self.posting_room(rooms).await?;
+
+        let room = room.lock().await;
+        let (user_id, room_name) = (self.user_id, self.chat_room.clone());
+        let pinned = db::query(&self.db_tx, move |conn| {
+            db::pin_message(conn, id, &room_name, user_id)
+        })
+        .await?;
+
+        if !pinned {
+            return Err(anyhow::anyhow!(
+                "Message {} not found or already pinned",
+                id
+            ));
+        }
+
+        room.broadcast(
+            &ServerEvent::Pin {
+                id,
+                room: self.chat_room.clone(),
+                pinned_by: self.user_id,
+            },
+            None,
+        );
+
+        Ok(())
+    }
+
+    // Marks the messages of this `User`'s room up to `id` as read, notifying
+    // everyone else in the room if that moved their read marker.
+    async fn mark_read(&self, id: i64, rooms: &Rooms) -> Result<(), anyhow::Error> {
+        let room = self.posting_room(rooms).await?;
+
+        let room = room.lock().await;
+        let (user_id, room_name) = (self.user_id, self.chat_room.clone());
+        let moved = db::query(&self.db_tx, move |conn| {
+            db::mark_read(conn, &room_name, user_id, id)
+        })
+        .await?;
+
+        if moved {
+            room.broadcast_except(
+                &ServerEvent::Read {
+                    id,
+                    room: self.chat_room.clone(),
+                    user_id: self.user_id,
+                },
+                Some(self.conn_id),
+                self.remote_conn.as_ref(),
+            );
+        }
+
+        Ok(())
+    }
+
+    // Unpins a message from this `User`'s room, notifying everyone in the room.
+    async fn unpin_message(&self, id: i64, rooms: &Rooms) -> Result<(), anyhow::Error> {
+        if !self.may(Action::PinMessage).await? {
+            return Err(anyhow::anyhow!("Only moderators can unpin messages"));
+        }
+
+        let room = self.posting_room(rooms).await?;
+
+        let room = room.lock().await;
+        let room_name = self.chat_room.clone();
+        let unpinned = db::query(&self.db_tx, move |conn| {
+            db::unpin_message(conn, id, &room_name)
+        })
+        .await?;
+
+        if !unpinned {
+            return Err(anyhow::anyhow!("Message {} is not pinned", id));
+        }
+
+        room.broadcast(
+            &ServerEvent::Unpin {
+                id,
+                room: self.chat_room.clone(),
+                unpinned_by: self.user_id,
+            },
+            None,
+        );
+
+        Ok(())
+    }
+
+    // Replaces the topic and description of this `User`'s room, notifying
+    // everyone in the room.
+    async fn set_topic(&self, update: TopicUpdate, rooms: &Rooms) -> Result<(), anyhow::Error> {
+        if !self.may(Action::SetTopic).await? {
+            return Err(anyhow::anyhow!("Only moderators can set the topic"));
+        }
+        update.validate()?;
+
+        let room = self.room(rooms).await?;
+
+        let room = room.lock().await;
+        let room_name = self.chat_room.clone();
+        let event = ServerEvent::Topic {
+            room: self.chat_room.clone(),
+            topic: update.topic.clone(),
+            description: update.description.clone(),
+            set_by: self.user_id,
+        };
+        db::query(&self.db_tx, move |conn| {
+            room::set_topic(conn, &room_name, &update)
+        })
+        .await?;
+
+        room.broadcast(&event, None);
+
+        Ok(())
+    }
+
+    // Kicks another user out of this `User`'s room, as a moderator.
+    async fn kick(&self, user_id: usize, rooms: &Rooms) -> Result<(), anyhow::Error> {
+        self.require_moderator_of(user_id).await?;
+        kick(&self.chat_room, user_id, self.user_id, rooms).await;
+
+        Ok(())
+    }
+
+    // Bans or mutes another user in this `User`'s room, as a moderator.
+    async fn sanction(
+        &self,
+        user_id: usize,
+        kind: SanctionKind,
+        new_sanction: NewSanction,
+        rooms: &Rooms,
+    ) -> Result<(), anyhow::Error> {
+        self.require_moderator_of(user_id).await?;
+        new_sanction.validate()?;
+
+        let (issued_by, room_name) = (self.user_id, self.chat_room.clone());
+        let sanction = db::query(&self.db_tx, move |conn| {
+            moderation::impose(conn, &room_name, user_id, kind, issued_by, &new_sanction)
+        })
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("User {} not found", user_id))?;
+        announce_sanction(&sanction, rooms).await;
+
+        Ok(())
+    }
+
+    // Lets the connections of `user_id` waiting for approval into this
+    // `User`'s room, as a moderator. Registered users are made members of the
+    // room, so that they are let in right away from then on.
+    async fn approve_join(&self, user_id: usize, rooms: &Rooms) -> Result<(), anyhow::Error> {
+        if !self.may(Action::ModerateUsers).await? {
+            return Err(anyhow::anyhow!("Only moderators can approve joins"));
+        }
+
+        let room = self.room(rooms).await?;
+        let mut room = room.lock().await;
+        let conn_ids = room.pending_connections(user_id);
+        if conn_ids.is_empty() {
+            return Err(anyhow::anyhow!("User {} is not waiting to join", user_id));
+        }
+
+        let (approved_by, room_name) = (self.user_id, self.chat_room.clone());
+        db::query(&self.db_tx, move |conn| {
+            room::add_member(conn, &room_name, user_id, Some(approved_by))
+        })
+        .await?;
+
+        for conn_id in conn_ids {
+            if let Some(pending) = room.pending.remove(&conn_id) {
+                room.users.insert(conn_id, pending.member);
+                // This will only fail if the user has already disconnected
+                if let Err(_disconnected) = pending.approval.send(()) {}
+            }
+        }
+
+        Ok(())
+    }
+
+    // Turns away the connections of `user_id` waiting for approval to join
+    // this `User`'s room, as a moderator.
+    async fn reject_join(&self, user_id: usize, rooms: &Rooms) -> Result<(), anyhow::Error> {
+        if !self.may(Action::ModerateUsers).await? {
+            return Err(anyhow::anyhow!("Only moderators can reject joins"));
+        }
+
+        let room = self.room(rooms).await?;
+        let mut room = room.lock().await;
+        let conn_ids = room.pending_connections(user_id);
+        if conn_ids.is_empty() {
+            return Err(anyhow::anyhow!("User {} is not waiting to join", user_id));
+        }
+
+        for conn_id in conn_ids {
+            room.pending.remove(&conn_id);
+        }
+
+        Ok(())
+    }
+
+    // Tells this `User` of the users waiting to join its room, if it is a
+    // moderator there.
+    pub async fn send_join_requests(&self, rooms: &Rooms) -> Result<(), anyhow::Error> {
+        if !self.may(Action::ModerateUsers).await? {
+            return Ok(());
+        }
+
+        let mut user_ids: Vec<usize> = self
+            .room(rooms)
+            .await?
+            .lock()
+            .await
+            .pending
+            .values()
+            .map(|pending| pending.member.user_id)
+            .collect();
+        user_ids.sort_unstable();
+        user_ids.dedup();
+
+        let nicks = self.nicks.read().await;
+        for user_id in user_ids {
+            self.send_event(&ServerEvent::JoinRequest {
+                room: self.chat_room.clone(),
+                user_id,
+                nick: nicks.get(&user_id).cloned(),
+            });
+        }
+
+        Ok(())
+    }
+
+    // Tells this `User`, waiting for approval to join its room, and the
+    // moderators in the room that it waits, then waits for a moderator to
+    // approve or reject it. Frames sent meanwhile are dropped.
+    async fn wait_for_approval(
+        &self,
+        ws: &mut impl Socket,
+        rooms: &Rooms,
+        approval: oneshot::Receiver<()>,
+    ) -> Result<Result<(), Refusal>, anyhow::Error> {
+        let pending = ServerEvent::JoinPending {
+            room: self.chat_room.clone(),
+        };
+        ws.send(pending.to_message()).await?;
+        self.request_approval(rooms).await?;
+
+        let left = async {
+            while let Some(Ok(msg)) = ws.next().await {
+                if msg.is_close() {
+                    break;
+                }
+            }
+        };
+
+        // Leaving while waiting is taken as being turned away, so that the
+        // connection is cleaned up
+        tokio::select! {
+            approved = approval => Ok(approved.map_err(|_rejected| Refusal::JoinRejected)),
+            _ = left => Ok(Err(Refusal::JoinRejected)),
+        }
+    }
+
+    // Tells the moderators in this `User`'s room that it waits for approval to
+    // join.
+    async fn request_approval(&self, rooms: &Rooms) -> Result<(), anyhow::Error> {
+        let request = ServerEvent::JoinRequest {
+            room: self.chat_room.clone(),
+            user_id: self.user_id,
+            nick: self.nick().await,
+        };
+        send_to_moderators(&self.chat_room, rooms, &self.db_tx, &request).await
+    }
+
+    // Fails unless this `User` may moderate `user_id` in its room: it must be a
+    // moderator there, and outrank them.
+    async fn require_moderator_of(&self, user_id: usize) -> Result<(), anyhow::Error> {
+        let role = self.role().await?;
+        if !role.permits(Action::ModerateUsers) {
+            return Err(anyhow::anyhow!("Only moderators can moderate users"));
+        }
+
+        let room_name = self.chat_room.clone();
+        let outranks = db::query(&self.db_tx, move |conn| {
+            authz::outranks(conn, &room_name, role, user_id)
+        })
+        .await?;
+        if !outranks {
+            return Err(anyhow::anyhow!("Can only moderate users of a lower role"));
+        }
+
+        Ok(())
+    }
+
+    // Whether this `User` may take `action` in its room.
+    async fn may(&self, action: Action) -> Result<bool, anyhow::Error> {
+        Ok(self.role().await?.permits(action))
+    }
+
+    // Role of this `User` in its room. Roles are looked up on every check, so
+    // that changes apply to open connections.
+    async fn role(&self) -> Result<Role, anyhow::Error> {
+        let (user_id, room_name) = (self.user_id, self.chat_room.clone());
+        let role = db::query(&self.db_tx, move |conn| {
+            authz::room_role(conn, user_id, &room_name)
+        })
+        .await?;
+
+        Ok(role.max(self.granted_role))
+    }
+
+    // Notifies the connections in this `User`'s room that it joined, along with
+    // its profile. The `User` is notified as well, telling it which room it is
+    // in when it did not name one, then told who is online in the room.
+    pub async fn announce_join(&self, rooms: &Rooms) -> Result<(), anyhow::Error> {
+        let user_id = self.user_id;
+        let profile = db::query(&self.db_tx, move |conn| profile::profile(conn, user_id)).await?;
+        let (avatar_url, bio) = match profile {
+            Some(profile) => (profile.avatar_url, profile.bio),
+            None => (None, None),
+        };
+
+        let event = ServerEvent::Join {
+            room: self.chat_room.clone(),
+            user_id,
+            nick: self.nick().await,
+            avatar_url,
+            bio,
+        };
+        {
+            let room = self.room(rooms).await?;
+            let room = room.lock().await;
+            room.broadcast(&event, None);
+
+            // Joining brings back users away on their other connections
+            if room.is_away(user_id, Some(self.conn_id)) == Some(true) {
+                room.broadcast(&self.status_event(false), None);
+            }
+        }
+        self.emit(&event);
+
+        self.send_event(&ServerEvent::Presence {
+            room: self.chat_room.clone(),
+            users: online_users(&self.chat_room, rooms, &self.nicks).await,
+        });
+
+        Ok(())
+    }
+
+    // Sends this `User` the events queued for its user while they were
+    // offline, e.g. mentions, which are then no longer queued.
+    pub async fn send_queued(&self) -> Result<(), anyhow::Error> {
+        if self.guest.is_some() {
+            return Ok(());
+        }
+
+        let user_id = self.user_id;
+        let queued =
+            db::query(&self.db_tx, move |conn| offline::take_queued(conn, user_id)).await?;
+        for event in queued {
+            self.user_tx.send(Message::text(event))?;
+        }
+
+        Ok(())
+    }
+
+    // The nickname this `User` is displayed with, if any.
+    pub async fn nick(&self) -> Option<String> {
+        self.nicks.read().await.get(&self.user_id).cloned()
+    }
+
+    // Sets the nickname this `User` is displayed with, provided no other user
+    // holds it, and notifies every room the `User` is connected to.
+    pub async fn set_nick(&self, nick: String, rooms: &Rooms) -> Result<(), anyhow::Error> {
+        let nick = validate_nickname(&nick)?;
+
+        let (user_id, new_nick) = (self.user_id, nick.clone());
+        let reserved = db::query(&self.db_tx, move |conn| {
+            db::reserve_nickname(conn, user_id, &new_nick)
+        })
+        .await?;
+
+        if !reserved {
+            return Err(anyhow::anyhow!("Nickname {} is already taken", nick));
+        }
+
+        let old_nick = self.nicks.write().await.insert(user_id, nick.clone());
+        for (room_name, room) in rooms.read().await.iter() {
+            let room = room.lock().await;
+            if room.users.values().any(|member| member.user_id == user_id) {
+                let event = ServerEvent::Rename {
+                    room: room_name.clone(),
+                    user_id,
+                    old_nick: old_nick.clone(),
+                    nick: nick.clone(),
+                };
+                room.broadcast(&event, None);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Checks that a nickname is acceptable, returning it without surrounding
+// whitespace.
+pub fn validate_nickname(nick: &str) -> Result<String, anyhow::Error> {
+    let nick = nick.trim();
+
+    if nick.is_empty() || nick.chars().count() > MAX_NICKNAME_LENGTH {
+        return Err(anyhow::anyhow!(
+            "Nickname must be between 1 and {} characters long",
+            MAX_NICKNAME_LENGTH
+        ));
+    }
+
+    if nick.chars().any(char::is_control) {
+        return Err(anyhow::anyhow!(
+            "Nickname may not contain control characters"
+        ));
+    }
+
+    if guest::is_guest_name(nick) {
+        return Err(anyhow::anyhow!(
+            "Nicknames starting with '{}' are reserved for guests",
+            guest::GUEST_PREFIX
+        ));
+    }
+
+    Ok(String::from(nick))
+}
+
+// Adds a `User` to its room as `add_user_to_room` does. Users joining a
+// password-protected room without `password` may instead send it in an `auth`
+// frame, as the first frame of `ws`. Users joining a room requiring approval
+// wait until a moderator approves or rejects them.
+pub async fn join_room(
+    new_user: &User,
+    ws: &mut impl Socket,
+    rooms: &Rooms,
+    explicit_rooms: bool,
+    default_capacity: Option<usize>,
+    password: Option<String>,
+    history_seq: Option<i64>,
+) -> Result<Result<(), Refusal>, anyhow::Error> {
+    let mut joined = add_user_to_room(
+        new_user,
+        rooms,
+        explicit_rooms,
+        default_capacity,
+        password.clone(),
+        history_seq,
+    )
+    .await?;
+    if matches!(joined, Err(Refusal::WrongPassword)) && password.is_none() {
+        if let Some(password) = receive_password(ws).await {
+            joined = add_user_to_room(
+                new_user,
+                rooms,
+                explicit_rooms,
+                default_capacity,
+                Some(password),
+                history_seq,
+            )
+            .await?;
+        }
+    }
+
+    match joined {
+        Ok(Admission::Joined) => {
+            new_user.send_join_requests(rooms).await?;
+            Ok(Ok(()))
+        }
+        Ok(Admission::Pending(approval)) => new_user.wait_for_approval(ws, rooms, approval).await,
+        Err(refusal) => Ok(Err(refusal)),
+    }
+}
+
+// The password sent in the first frame of `ws`, if it is an `auth` frame sent
+// within `AUTH_TIMEOUT`.
+async fn receive_password(ws: &mut impl Socket) -> Option<String> {
+    let msg = tokio::time::timeout(AUTH_TIMEOUT, ws.next())
+        .await
+        .ok()??
+        .ok()?;
+
+    match ClientFrame::parse(msg.to_str().ok()?) {
+        Ok(ClientFrame::Auth { password }) => Some(password),
+        _ => None,
+    }
+}
+
+// Adds a `User` to a room, creating one if it does not exist and
+// `explicit_rooms` is not set. Rooms without a capacity of their own hold up to
+// `default_capacity` connections, if set. Fails with the `Refusal` of the room
+// if the `User` was not let in, e.g. without the `password` of the room.
+// `User`s needing approval to join are left pending instead. `history_seq` is
+// what `send_history` returned, if history was sent: messages sent to the room
+// since are sent to the `User` before it is let in.
+pub async fn add_user_to_room(
+    new_user: &User,
+    rooms: &Rooms,
+    explicit_rooms: bool,
+    default_capacity: Option<usize>,
+    password: Option<String>,
+    history_seq: Option<i64>,
+) -> Result<Result<Admission, Refusal>, anyhow::Error> {
+    let (user_id, room_name) = (new_user.user_id, new_user.chat_room.clone());
+    let (is_guest, granted_role) = (new_user.guest.is_some(), new_user.granted_role);
+    let joining = db::query(&new_user.db_tx, move |conn| {
+        let settings = room::settings(conn, &room_name)?;
+        // Conversations are only ever created through the API
+        if settings.is_none() && (explicit_rooms || conversation::is_conversation(&room_name)) {
+            return Ok(Err(Refusal::RoomNotFound));
+        }
+        if !authz::may_join(conn, user_id, &room_name)? {
+            return Ok(Err(Refusal::AccessDenied));
+        }
+
+        let settings = match settings {
+            Some(settings) => settings,
+            None => {
+                // Rooms are created by the first user to join them
+                room::record_room(conn, &room_name, user_id, !is_guest)?;
+                room::RoomSettings::default()
+            }
+        };
+
+        // Admins of the room are let in without its password
+        let role = authz::room_role(conn, user_id, &room_name)?;
+        let password_hash = match room::password_hash(conn, &room_name)? {
+            Some(_) if role == Role::Admin => None,
+            password_hash => password_hash,
+        };
+
+        // Members and moderators of the room are let in without approval
+        let needs_approval = settings.approval_required
+            && !role.max(granted_role).permits(Action::ModerateUsers)
+            && !room::is_member(conn, &room_name, user_id)?;
+
+        Ok(Ok((
+            settings.capacity.or(default_capacity),
+            password_hash,
+            needs_approval,
+        )))
+    })
+    .await?;
+    let (capacity, password_hash, needs_approval) = match joining {
+        Ok(joining) => joining,
+        Err(refusal) => return Ok(Err(refusal)),
+    };
+
+    if let Some(password_hash) = password_hash {
+        let verified = match password {
+            Some(password) => tokio::task::spawn_blocking(move || {
+                auth::verify_password(&password, &password_hash)
+            })
+            .await
+            .unwrap_or(false),
+            None => false,
+        };
+        if !verified {
+            return Ok(Err(Refusal::WrongPassword));
+        }
+    }
+
+    // The room is held onto until the user is in it, so that it is not
+    // released meanwhile. Should that have happened while loading it, it is
+    // loaded again.
+    let (_rooms, room) = loop {
+        let loaded = load_room(
+            rooms,
+            &new_user.chat_room,
+            &new_user.db_tx,
+            new_user.cluster.as_ref(),
+        )
+        .await?;
+        if loaded.is_none() {
+            return Ok(Err(Refusal::RoomNotFound));
+        }
+
+        let rooms = rooms.read().await;
+        if let Some(room) = rooms.get(&new_user.chat_room).cloned() {
+            break (rooms, room);
+        }
+    };
+
+    let mut room = room.lock().await;
+    if let Some(capacity) = capacity {
+        if room.users.len() >= capacity {
+            return Ok(Err(Refusal::RoomFull { capacity }));
+        }
+    }
+
+    // Messages are sent while the room is locked, so none can be missed
+    // between these and the `User` being let in. Ephemeral messages are never
+    // persisted, so they may have it read the DB for nothing.
+    if let Some(history_seq) = history_seq {
+        if room.last_seq > history_seq {
+            new_user.send_missed(history_seq).await?;
+        }
+    }
+
+    let member = Member {
+        user_id: new_user.user_id,
+        session_id: new_user.session_id.clone(),
+        addr: new_user.addr,
+        granted_role: new_user.granted_role,
+        away: false,
+        tx: new_user.user_tx.clone(),
+    };
+    if needs_approval {
+        let (approval, approved) = oneshot::channel();
+        room.pending
+            .insert(new_user.conn_id, PendingJoin { member, approval });
+        return Ok(Ok(Admission::Pending(approved)));
+    }
+    room.users.insert(new_user.conn_id, member);
+
+    Ok(Ok(Admission::Joined))
+}
+
+// The room `room_name`, loaded into `rooms` if no one is in it yet, or None if
+// it does not exist. Sequence numbers continue from the last message persisted
+// to the room. Rooms no one is in may have been cleaned up meanwhile for being
+// idle. `rooms` is not locked while the DB is queried, so that other rooms are
+// not held up: whoever loads the room first has theirs kept.
+async fn load_room(
+    rooms: &Rooms,
+    room_name: &str,
+    db_tx: &DbTx,
+    cluster: Option<&Cluster>,
+) -> Result<Option<Arc<Mutex<Room>>>, anyhow::Error> {
+    if let Some(room) = rooms.read().await.get(room_name) {
+        return Ok(Some(room.clone()));
+    }
+
+    let name = String::from(room_name);
+    let last_seq = db::query(db_tx, move |conn| match room::settings(conn, &name)? {
+        Some(_) => db::last_room_seq(conn, &name).map(Some),
+        None => Ok(None),
+    })
+    .await?;
+    let last_seq = match last_seq {
+        Some(last_seq) => last_seq,
+        None => return Ok(None),
+    };
+
+    let room = rooms
+        .write()
+        .await
+        .entry(String::from(room_name))
+        .or_insert_with(|| {
+            let relay = cluster.map(|cluster| cluster.relay(room_name));
+            Arc::new(Mutex::new(Room::new(last_seq).with_relay(relay)))
+        })
+        .clone();
+
+    Ok(Some(room))
+}
+
+// Unloads `room_name` if no one is in it, once posted to without a connection.
+pub async fn release_room(room_name: &str, rooms: &Rooms) {
+    let mut rooms = rooms.write().await;
+    let room_empty = match rooms.get(room_name) {
+        Some(room) => {
+            let room = room.lock().await;
+            room.users.is_empty() && room.pending.is_empty()
+        }
+        None => false,
+    };
+
+    if room_empty {
+        rooms.remove(room_name);
+    }
+}
+
+// Closes the connections to `room_name` of users its access control list no
+// longer lets in, as told by `may_join`.
+pub async fn enforce_access(
+    room_name: &str,
+    rooms: &Rooms,
+    db_tx: &DbTx,
+) -> Result<(), anyhow::Error> {
+    let room = match rooms.read().await.get(room_name) {
+        Some(room) => room.clone(),
+        None => return Ok(()),
+    };
+
+    let mut user_ids: Vec<usize> = room
+        .lock()
+        .await
+        .users
+        .values()
+        .map(|member| member.user_id)
+        .collect();
+    user_ids.sort_unstable();
+    user_ids.dedup();
+
+    let checked_room = String::from(room_name);
+    let denied = db::query(db_tx, move |conn| {
+        let mut denied = Vec::new();
+        for user_id in user_ids {
+            if !authz::may_join(conn, user_id, &checked_room)? {
+                denied.push(user_id);
+            }
+        }
+
+        Ok(denied)
+    })
+    .await?;
+
+    for member in room.lock().await.users.values() {
+        if denied.contains(&member.user_id) {
+            // This will only fail if the user has already disconnected
+            if let Err(_disconnected) = member.tx.send(Refusal::AccessDenied.close_frame()) {}
+        }
+    }
+
+    Ok(())
+}
+
+// Sends an event to every connection to `room_name`, if anyone is connected.
+pub async fn broadcast_to_room(room_name: &str, rooms: &Rooms, event: &ServerEvent) {
+    if let Some(room) = rooms.read().await.get(room_name) {
+        room.lock().await.broadcast(event, None);
+    }
+}
+
+// Kicks `user_id` out of `room_name` on behalf of `kicked_by`, notifying
+// everyone in the room before closing their connections to it.
+pub async fn kick(room_name: &str, user_id: usize, kicked_by: usize, rooms: &Rooms) {
+    let event = ServerEvent::Kick {
+        room: String::from(room_name),
+        user_id,
+        kicked_by,
+    };
+    broadcast_to_room(room_name, rooms, &event).await;
+
+    let kicked = Message::close_with(KICKED_CODE, "Kicked from the room");
+    close_room_connections(room_name, user_id, rooms, kicked).await
+}
+
+// Kicks `user_id` out of `room_name` for sending more than `max_messages`
+// within `window`, notifying everyone in the room before closing their
+// connections to it.
+async fn flood_kick(
+    room_name: &str,
+    user_id: usize,
+    max_messages: usize,
+    window: Duration,
+    rooms: &Rooms,
+) {
+    let event = ServerEvent::FloodKick {
+        room: String::from(room_name),
+        user_id,
+        max_messages,
+        window_secs: window.as_secs(),
+    };
+    broadcast_to_room(room_name, rooms, &event).await;
+
+    let reason = format!(
+        "Kicked for flooding: more than {} messages in {} seconds",
+        max_messages,
+        window.as_secs()
+    );
+    let kicked = Message::close_with(FLOOD_CODE, reason);
+    close_room_connections(room_name, user_id, rooms, kicked).await
+}
+
+// Notifies everyone in the room of `sanction` once imposed, unless it is a
+// shadow ban. Banned users have their connections to the room closed.
+pub async fn announce_sanction(sanction: &Sanction, rooms: &Rooms) {
+    // Shadow bans would be pointless if the user were told
+    if sanction.kind == SanctionKind::ShadowBan {
+        return;
+    }
+
+    let event = ServerEvent::Sanction {
+        room: sanction.room.clone(),
+        user_id: sanction.user_id,
+        kind: sanction.kind,
+        // Sanctions are announced right after being issued
+        issued_by: sanction.issued_by.unwrap_or_default(),
+        reason: sanction.reason.clone(),
+        expires_at: sanction.expires_at.clone(),
+    };
+    broadcast_to_room(&sanction.room, rooms, &event).await;
+
+    if sanction.kind == SanctionKind::Ban {
+        let banned = Refusal::AccessDenied.close_frame();
+        close_room_connections(&sanction.room, sanction.user_id, rooms, banned).await
+    }
+}
+
+async fn close_room_connections(room_name: &str, user_id: usize, rooms: &Rooms, frame: Message) {
+    if let Some(room) = rooms.read().await.get(room_name) {
+        for member in room.lock().await.users.values() {
+            if member.user_id == user_id {
+                // This will only fail if the user has already disconnected
+                if let Err(_disconnected) = member.tx.send(frame.clone()) {}
+            }
+        }
+    }
+}
+
+// Sends `event` to the connections in `room_name` of its moderators.
+async fn send_to_moderators(
+    room_name: &str,
+    rooms: &Rooms,
+    db_tx: &DbTx,
+    event: &ServerEvent,
+) -> Result<(), anyhow::Error> {
+    let room = match rooms.read().await.get(room_name) {
+        Some(room) => room.clone(),
+        None => return Ok(()),
+    };
+
+    let mut user_ids: Vec<usize> = room
+        .lock()
+        .await
+        .users
+        .values()
+        .map(|member| member.user_id)
+        .collect();
+    user_ids.sort_unstable();
+    user_ids.dedup();
+
+    let checked_room = String::from(room_name);
+    let roles = db::query(db_tx, move |conn| {
+        let mut roles = HashMap::new();
+        for user_id in user_ids {
+            roles.insert(user_id, authz::room_role(conn, user_id, &checked_room)?);
+        }
+
+        Ok(roles)
+    })
+    .await?;
+
+    let msg = event.to_message();
+    for member in room.lock().await.users.values() {
+        let role = roles.get(&member.user_id).copied().unwrap_or(Role::Member);
+        if role.max(member.granted_role).permits(Action::ModerateUsers) {
+            // This will only fail if the user has already disconnected
+            if let Err(_disconnected) = member.tx.send(msg.clone()) {}
+        }
+    }
+
+    Ok(())
+}
+
+// Closes every connection of `user_id`, in any room.
+pub async fn disconnect_user(user_id: usize, rooms: &Rooms) {
+    close_connections(rooms, |member| member.user_id == user_id).await
+}
+
+// Closes every connection opened with login session `session_id`.
+pub async fn disconnect_session(session_id: &str, rooms: &Rooms) {
+    close_connections(rooms, |member| {
+        member.session_id.as_deref() == Some(session_id)
+    })
+    .await
+}
+
+// Closes every connection opened from an address in `net`.
+pub async fn disconnect_addresses(net: IpNet, rooms: &Rooms) {
+    close_connections(rooms, |member| {
+        member.addr.is_some_and(|addr| net.contains(addr))
+    })
+    .await
+}
+
+// Number of connections opened with each login session of `user_id`.
+// Connections in several rooms are counted once.
+pub async fn session_connections(user_id: usize, rooms: &Rooms) -> HashMap<String, usize> {
+    let mut connections = HashMap::new();
+    for room in rooms.read().await.values() {
+        for (&conn_id, member) in room.lock().await.users.iter() {
+            match &member.session_id {
+                Some(session_id) if member.user_id == user_id => {
+                    connections
+                        .entry(session_id.clone())
+                        .or_insert_with(HashSet::new)
+                        .insert(conn_id);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    connections
+        .into_iter()
+        .map(|(session_id, conn_ids)| (session_id, conn_ids.len()))
+        .collect()
+}
+
+// Users connected to `room_name`, by user ID, with their nicknames and whether
+// they are away.
+pub async fn online_users(room_name: &str, rooms: &Rooms, nicks: &Nicks) -> Vec<OnlineUser> {
+    // Users are away once each of their connections is
+    let mut away = BTreeMap::new();
+    if let Some(room) = rooms.read().await.get(room_name) {
+        for member in room.lock().await.users.values() {
+            *away.entry(member.user_id).or_insert(true) &= member.away;
+        }
+    }
+
+    let nicks = nicks.read().await;
+    away.into_iter()
+        .map(|(user_id, away)| OnlineUser {
+            user_id,
+            nick: nicks.get(&user_id).cloned(),
+            status: Availability::of(away),
+        })
+        .collect()
+}
+
+// Users connected to any room.
+pub async fn online_user_ids(rooms: &Rooms) -> HashSet<usize> {
+    let mut user_ids = HashSet::new();
+    for room in rooms.read().await.values() {
+        user_ids.extend(
+            room.lock()
+                .await
+                .users
+                .values()
+                .map(|member| member.user_id),
+        );
+    }
+
+    user_ids
+}
+
+// Number of connections to each room, by room name.
+pub async fn occupancy(rooms: &Rooms) -> HashMap<String, usize> {
+    let mut occupancy = HashMap::new();
+    for (room_name, room) in rooms.read().await.iter() {
+        occupancy.insert(room_name.clone(), room.lock().await.users.len());
+    }
+
+    occupancy
+}
+
+// Sends `event` to every connection of `user_id`, in whichever room, once
+// per connection.
+pub async fn notify_user(user_id: usize, rooms: &Rooms, event: &ServerEvent) {
+    deliver_to_user(user_id, rooms, &event.to_message()).await;
+}
+
+// Sends `msg` to every connection of `user_id` on this instance, once per
+// connection.
+pub async fn deliver_to_user(user_id: usize, rooms: &Rooms, msg: &Message) {
+    let mut notified = HashSet::new();
+    for room in rooms.read().await.values() {
+        for (&conn_id, member) in room.lock().await.users.iter() {
+            if member.user_id == user_id && notified.insert(conn_id) {
+                // This will only fail if the user has already disconnected
+                if let Err(_disconnected) = member.tx.send(msg.clone()) {}
+            }
+        }
+    }
+}
+
+// Forwards what `room_name` sends to a `User` that joined it with a `join`
+// frame to its connection, through `conn_tx`. Frames closing the `User` close
+// its subscription to the room only, as told to the connection through
+// `outcome_tx`.
+fn forward_room(room_name: String, mut room_rx: UserRx, conn_tx: UserTx, outcome_tx: OutcomeTx) {
+    tokio::task::spawn(async move {
+        while let Some(msg) = room_rx.recv().await {
+            if msg.is_close() {
+                let (code, reason) = match msg.close_frame() {
+                    Some((code, reason)) => (code, String::from(reason)),
+                    None => (LEFT_CODE, String::new()),
+                };
+                let closed = RoomOutcome::Closed { code, reason };
+                // This will only fail if the connection has already closed
+                if let Err(_disconnected) = outcome_tx.send((room_name, closed)) {}
+                break;
+            }
+
+            if conn_tx.send(msg).is_err() {
+                break;
+            }
+        }
+    });
+}
+
+async fn close_connections(rooms: &Rooms, should_close: impl Fn(&Member) -> bool) {
+    for room in rooms.read().await.values() {
+        for member in room.lock().await.users.values() {
+            if should_close(member) {
+                // This will only fail if the user has already disconnected
+                if let Err(_disconnected) = member.tx.send(Message::close()) {}
+            }
+        }
+    }
+}
+
+// Removes a `User` from a room.
+// The "room" is also cleaned up if there are no users remaining.
+pub async fn remove_user_from_room(user: &User, rooms: &Rooms) {
+    let nick = user.nick().await;
+    let mut rooms = rooms.write().await;
+    let room_empty = match rooms.get(&user.chat_room) {
+        Some(room) => {
+            let mut room = room.lock().await;
+            room.pending.remove(&user.conn_id);
+
+            // Others are told once the last connection of the user is gone, or
+            // once the only ones left are away
+            let was_away = room.is_away(user.user_id, None);
+            if room.users.remove(&user.conn_id).is_some() {
+                match room.is_away(user.user_id, None) {
+                    None => {
+                        let event = ServerEvent::Leave {
+                            room: user.chat_room.clone(),
+                            user_id: user.user_id,
+                            nick,
+                        };
+                        room.broadcast(&event, None);
+                        if let Some(sink) = &user.event_sink {
+                            sink.emit(&user.chat_room, &event);
+                        }
+                    }
+                    Some(true) if was_away == Some(false) => {
+                        room.broadcast(&user.status_event(true), None);
+                    }
+                    Some(_) => {}
+                }
+            }
+
+            // Extra check to see if room is empty
+            room.users.is_empty() && room.pending.is_empty()
+        }
+        None => false,
+    };
+
+    // Cleans up room, if empty
+    if room_empty {
+        rooms.remove(&user.chat_room);
+    }
+}
+
+// User has been disconnected from the WebSocket connection.
+pub async fn user_disconnected(user: &User, rooms: &Rooms) {
+    eprintln!("User disconnected: {}", user.user_id);
+
+    remove_user_from_room(user, rooms).await;
+
+    if user.guest.is_some() {
+        let user_id = user.user_id;
+        if let Err(e) =
+            db::query(&user.db_tx, move |conn| db::release_nickname(conn, user_id)).await
+        {
+            eprintln!("Failed to release nickname(uid={}): {}", user_id, e);
+        }
+        user.nicks.write().await.remove(&user_id);
+    }
 }
@@ -0,0 +1,287 @@
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures::{Sink, Stream};
+use prost::Message as _;
+use serde::Deserialize;
+use serde_json::Value;
+use warp::ws::{Message, WebSocket};
+
+use crate::{
+    proto::{self, client_frame::Frame},
+    protocol::{ClientFrame, ServerEvent},
+};
+
+// WebSocket subprotocols clients ask for binary frames with.
+pub const MSGPACK_PROTOCOL: &str = "bi-chat.msgpack";
+pub const PROTOBUF_PROTOCOL: &str = "bi-chat.protobuf";
+
+// How the frames of a connection are encoded, negotiated when connecting with
+// `?format=` or the subprotocol of the format.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WireFormat {
+    // JSON text frames
+    #[default]
+    Json,
+    // MessagePack binary frames, encoding the same values as the JSON ones
+    Msgpack,
+    // Binary frames holding a `ClientFrame` or `ServerEvent` of
+    // `proto/frames.proto`
+    Protobuf,
+}
+
+impl WireFormat {
+    // The format asked for by the subprotocols a client listed in its
+    // `Sec-WebSocket-Protocol` header, the first one known, if any.
+    pub fn negotiate(protocols: &str) -> Option<Self> {
+        protocols
+            .split(',')
+            .find_map(|protocol| match protocol.trim() {
+                MSGPACK_PROTOCOL => Some(WireFormat::Msgpack),
+                PROTOBUF_PROTOCOL => Some(WireFormat::Protobuf),
+                _ => None,
+            })
+    }
+
+    // The subprotocol clients ask for this format with, if any.
+    pub fn subprotocol(self) -> Option<&'static str> {
+        match self {
+            WireFormat::Json => None,
+            WireFormat::Msgpack => Some(MSGPACK_PROTOCOL),
+            WireFormat::Protobuf => Some(PROTOBUF_PROTOCOL),
+        }
+    }
+
+    // `msg`, as sent to clients speaking this format. Events are sent as JSON
+    // text frames, which clients speaking binary formats get re-encoded.
+    pub fn encode(self, msg: Message) -> Message {
+        if self == WireFormat::Json {
+            return msg;
+        }
+
+        match self {
+            WireFormat::Json => msg,
+            WireFormat::Msgpack => {
+                let value = match msg.to_str().map(serde_json::from_str::<Value>) {
+                    Ok(Ok(value)) => value,
+                    _ => return msg,
+                };
+                match rmp_serde::to_vec_named(&value) {
+                    Ok(bytes) => Message::binary(bytes),
+                    Err(_) => msg,
+                }
+            }
+            WireFormat::Protobuf => match server_event(&msg) {
+                Some(event) => Message::binary(event.encode_to_vec()),
+                None => msg,
+            },
+        }
+    }
+
+    // `msg`, as sent by a client speaking this format, as a JSON or plain text
+    // frame. MessagePack strings, and protobuf frames giving a `text`, are plain
+    // messages. Frames that fail to decode are left as they are.
+    pub fn decode(self, msg: Message) -> Message {
+        if !msg.is_binary() {
+            return msg;
+        }
+
+        match self {
+            WireFormat::Json => msg,
+            WireFormat::Msgpack => match rmp_serde::from_slice::<Value>(msg.as_bytes()) {
+                Ok(Value::String(text)) => Message::text(text),
+                Ok(value) => Message::text(value.to_string()),
+                Err(_) => msg,
+            },
+            WireFormat::Protobuf => match proto::ClientFrame::decode(msg.as_bytes()) {
+                Ok(frame) => client_message(frame).unwrap_or(msg),
+                Err(_) => msg,
+            },
+        }
+    }
+}
+
+// The text frame `frame` stands for, as JSON, or as plain text for plain
+// messages not naming a room. Frames giving none stand for nothing.
+pub fn client_message(frame: proto::ClientFrame) -> Option<Message> {
+    let room = frame.room;
+    let frame = match frame.frame? {
+        Frame::Text(text) if room.is_none() => return Some(Message::text(text)),
+        frame => ClientFrame::from(frame),
+    };
+
+    let mut value = serde_json::to_value(frame).ok()?;
+    if let (Value::Object(fields), Some(room)) = (&mut value, room) {
+        // `join` and `leave` frames name the room they join or leave
+        fields.entry("room").or_insert_with(|| Value::from(room));
+    }
+
+    Some(Message::text(value.to_string()))
+}
+
+// The event `msg` carries, if it is a text frame holding one.
+pub fn server_event(msg: &Message) -> Option<proto::ServerEvent> {
+    let event: ServerEvent = serde_json::from_str(msg.to_str().ok()?).ok()?;
+
+    Some(event.into())
+}
+
+// A WebSocket whose frames are encoded in `format`, exchanging them as JSON.
+pub struct FramedSocket {
+    ws: WebSocket,
+    format: WireFormat,
+}
+
+impl FramedSocket {
+    pub fn new(ws: WebSocket, format: WireFormat) -> Self {
+        FramedSocket { ws, format }
+    }
+}
+
+impl Stream for FramedSocket {
+    type Item = Result<Message, warp::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let format = self.format;
+        Pin::new(&mut self.ws)
+            .poll_next(cx)
+            .map(|msg| msg.map(|msg| msg.map(|msg| format.decode(msg))))
+    }
+}
+
+impl Sink<Message> for FramedSocket {
+    type Error = warp::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.ws).poll_ready(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, msg: Message) -> Result<(), Self::Error> {
+        let format = self.format;
+        Pin::new(&mut self.ws).start_send(format.encode(msg))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.ws).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.ws).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(
+            WireFormat::negotiate("graphql-ws, bi-chat.msgpack"),
+            Some(WireFormat::Msgpack)
+        );
+        assert_eq!(
+            WireFormat::negotiate("bi-chat.protobuf, bi-chat.msgpack"),
+            Some(WireFormat::Protobuf)
+        );
+        assert_eq!(WireFormat::negotiate("graphql-ws"), None);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let event = r#"{"type":"message","id":1,"text":"hi"}"#;
+        let encoded = WireFormat::Msgpack.encode(Message::text(event));
+        assert!(encoded.is_binary());
+        let value: Value = rmp_serde::from_slice(encoded.as_bytes()).unwrap();
+        assert_eq!(value, serde_json::from_str::<Value>(event).unwrap());
+
+        let frame = rmp_serde::to_vec_named(&serde_json::json!({ "type": "heartbeat" })).unwrap();
+        let decoded = WireFormat::Msgpack.decode(Message::binary(frame));
+        assert_eq!(decoded.to_str().unwrap(), r#"{"type":"heartbeat"}"#);
+        let text = rmp_serde::to_vec("hello").unwrap();
+        let decoded = WireFormat::Msgpack.decode(Message::binary(text));
+        assert_eq!(decoded.to_str().unwrap(), "hello");
+
+        let encoded = WireFormat::Protobuf.encode(Message::text(
+            r#"{"type":"message","id":1,"seq":1,"room":"lobby","user_id":2,"text":"hi"}"#,
+        ));
+        let typed = proto::ServerEvent::decode(encoded.as_bytes()).unwrap();
+        assert_eq!(
+            typed.event,
+            Some(proto::server_event::Event::Message(
+                proto::server_event::Message {
+                    id: 1,
+                    seq: 1,
+                    room: String::from("lobby"),
+                    user_id: 2,
+                    text: String::from("hi"),
+                    ..Default::default()
+                }
+            ))
+        );
+        // Text frames that are not events are left as they are
+        assert!(!WireFormat::Protobuf
+            .encode(Message::text(event))
+            .is_binary());
+
+        let frame = proto::ClientFrame {
+            room: None,
+            frame: Some(Frame::Vote(proto::client_frame::Vote { id: 3, option: 1 })),
+        };
+        let decoded = WireFormat::Protobuf.decode(Message::binary(frame.encode_to_vec()));
+        assert_eq!(
+            serde_json::from_str::<Value>(decoded.to_str().unwrap()).unwrap(),
+            serde_json::json!({ "type": "vote", "id": 3, "option": 1 })
+        );
+        let frame = proto::ClientFrame {
+            room: Some(String::from("rust")),
+            frame: Some(Frame::Join(proto::client_frame::Join {
+                room: String::from("lobby"),
+                password: None,
+                since: Some(4),
+            })),
+        };
+        let decoded = WireFormat::Protobuf.decode(Message::binary(frame.encode_to_vec()));
+        assert_eq!(
+            ClientFrame::parse(decoded.to_str().unwrap()).unwrap(),
+            ClientFrame::Join {
+                room: String::from("lobby"),
+                password: None,
+                since: Some(4),
+            }
+        );
+        let text = proto::ClientFrame {
+            room: None,
+            frame: Some(Frame::Text(String::from("hello"))),
+        };
+        let decoded = WireFormat::Protobuf.decode(Message::binary(text.encode_to_vec()));
+        assert_eq!(decoded.to_str().unwrap(), "hello");
+        let text = proto::ClientFrame {
+            room: Some(String::from("rust")),
+            frame: Some(Frame::Text(String::from("hello"))),
+        };
+        let decoded = WireFormat::Protobuf.decode(Message::binary(text.encode_to_vec()));
+        assert_eq!(
+            ClientFrame::target_room(decoded.to_str().unwrap()).as_deref(),
+            Some("rust")
+        );
+        // Frames giving nothing are left undecoded
+        let empty = proto::ClientFrame::default();
+        assert!(WireFormat::Protobuf
+            .decode(Message::binary(empty.encode_to_vec()))
+            .is_binary());
+
+        // Close frames, and JSON connections, are left alone
+        assert!(WireFormat::Msgpack.encode(Message::close()).is_close());
+        assert_eq!(
+            WireFormat::Json
+                .encode(Message::text(event))
+                .to_str()
+                .unwrap(),
+            event
+        );
+    }
+}
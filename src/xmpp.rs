@@ -0,0 +1,747 @@
+use std::{collections::HashMap, time::Duration};
+
+use anyhow::anyhow;
+use serde_json::Value;
+use sha1::{Digest, Sha1};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{tcp::OwnedReadHalf, TcpStream},
+    sync::mpsc::{self, UnboundedSender},
+};
+
+use crate::{
+    auth::{oauth, Principal},
+    db, room,
+    server::ServerState,
+    shutdown::Shutdown,
+    user::{self, Admission, PostOutcome, Refusal, User, UserRx},
+};
+
+mod xml;
+
+use xml::{Element, Event, ParseError};
+
+// Provider the accounts of XMPP users are recorded under, as external
+// identities. Users are told apart by their bare JID.
+const XMPP_PROVIDER: &str = "xmpp";
+
+const NS_COMPONENT: &str = "jabber:component:accept";
+const NS_STREAMS: &str = "http://etherx.jabber.org/streams";
+const NS_STANZAS: &str = "urn:ietf:params:xml:ns:xmpp-stanzas";
+const NS_DISCO_INFO: &str = "http://jabber.org/protocol/disco#info";
+const NS_MUC: &str = "http://jabber.org/protocol/muc";
+const NS_MUC_USER: &str = "http://jabber.org/protocol/muc#user";
+
+// How long to wait before connecting to the XMPP server again once the
+// connection is lost.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+// Most the XMPP server may send without completing a stanza.
+const MAX_STANZA_SIZE: usize = 256 * 1024;
+
+// An XMPP address, `[local@]domain[/resource]`. For the rooms of the bridge,
+// `local` is the room and `resource` the nickname of an occupant.
+#[derive(Debug, PartialEq)]
+struct Jid<'a> {
+    local: Option<&'a str>,
+    domain: &'a str,
+    resource: Option<&'a str>,
+}
+
+impl<'a> Jid<'a> {
+    fn parse(jid: &'a str) -> Option<Self> {
+        let (bare, resource) = match jid.split_once('/') {
+            Some((bare, resource)) => (bare, Some(resource).filter(|r| !r.is_empty())),
+            None => (jid, None),
+        };
+        let (local, domain) = match bare.split_once('@') {
+            Some((local, domain)) => (Some(local).filter(|l| !l.is_empty()), domain),
+            None => (None, bare),
+        };
+        if domain.is_empty() {
+            return None;
+        }
+
+        Some(Jid {
+            local,
+            domain,
+            resource,
+        })
+    }
+
+    // The address without its resource, e.g. the account of a user.
+    fn bare(&self) -> String {
+        match self.local {
+            Some(local) => format!("{}@{}", local, self.domain),
+            None => String::from(self.domain),
+        }
+    }
+}
+
+// The secret a component proves it knows to the XMPP server, given the ID of
+// the stream the server opened (XEP-0114).
+fn handshake(stream_id: &str, secret: &str) -> String {
+    hex::encode(Sha1::digest(format!("{}{}", stream_id, secret)))
+}
+
+// How a user shows up as an occupant of a room.
+fn occupant_nick(nick: Option<&str>, user_id: usize) -> String {
+    match nick {
+        Some(nick) => String::from(nick),
+        None => format!("user{}", user_id),
+    }
+}
+
+// Presence of the occupant `from` of a room, sent to `to`. `statuses` are the
+// MUC status codes telling more, e.g. 110 for the occupant's own presence.
+fn occupant_presence(from: &str, to: &str, available: bool, statuses: &[&str]) -> Element {
+    let role = if available { "participant" } else { "none" };
+    let mut x = Element::new("x")
+        .with_attr("xmlns", NS_MUC_USER)
+        .with_child(
+            Element::new("item")
+                .with_attr("affiliation", "member")
+                .with_attr("role", role),
+        );
+    for code in statuses {
+        x = x.with_child(Element::new("status").with_attr("code", code));
+    }
+
+    let presence = Element::new("presence")
+        .with_attr("from", from)
+        .with_attr("to", to);
+    if available {
+        presence.with_child(x)
+    } else {
+        presence.with_attr("type", "unavailable").with_child(x)
+    }
+}
+
+// The start of the stanza answering `stanza`, to whoever sent it.
+fn reply(stanza: &Element) -> Element {
+    let mut reply = Element::new(&stanza.name);
+    for (attr, swapped) in [("from", "to"), ("to", "from"), ("id", "id")] {
+        if let Some(value) = stanza.attr(swapped) {
+            reply = reply.with_attr(attr, value);
+        }
+    }
+
+    reply
+}
+
+// The error answering `stanza`, of type `kind` (e.g. `cancel`) for
+// `condition` (e.g. `item-not-found`), with `text` telling more if given.
+fn error_reply(stanza: &Element, kind: &str, condition: &str, text: Option<&str>) -> Element {
+    let mut error = Element::new("error")
+        .with_attr("type", kind)
+        .with_child(Element::new(condition).with_attr("xmlns", NS_STANZAS));
+    if let Some(text) = text {
+        error = error.with_child(
+            Element::new("text")
+                .with_attr("xmlns", NS_STANZAS)
+                .with_text(text),
+        );
+    }
+
+    reply(stanza).with_attr("type", "error").with_child(error)
+}
+
+// The stanzas telling `to`, the occupant of `room_jid` acting as `user_id`,
+// of `event`, a server event as sent over WebSockets, if XMPP has a say for
+// it.
+fn event_stanzas(event: &Value, room_jid: &str, to: &str, user_id: usize) -> Vec<Element> {
+    let event_user_id = event["user_id"].as_u64().unwrap_or_default() as usize;
+    let occupant =
+        |nick: Option<&str>| format!("{}/{}", room_jid, occupant_nick(nick, event_user_id));
+    let room_message = || {
+        Element::new("message")
+            .with_attr("from", room_jid)
+            .with_attr("to", to)
+            .with_attr("type", "groupchat")
+    };
+
+    match event["type"].as_str().unwrap_or_default() {
+        "message" if event["deleted_at"].is_null() => {
+            let message = Element::new("message")
+                .with_attr("from", &occupant(event["nick"].as_str()))
+                .with_attr("to", to)
+                .with_attr("id", &event["id"].to_string())
+                .with_attr("type", "groupchat")
+                .with_child(
+                    Element::new("body").with_text(event["text"].as_str().unwrap_or_default()),
+                );
+            vec![message]
+        }
+        // The occupant's own presence is sent when it joins and leaves
+        "join" if event_user_id != user_id => {
+            vec![occupant_presence(
+                &occupant(event["nick"].as_str()),
+                to,
+                true,
+                &[],
+            )]
+        }
+        "leave" if event_user_id != user_id => {
+            vec![occupant_presence(
+                &occupant(event["nick"].as_str()),
+                to,
+                false,
+                &[],
+            )]
+        }
+        "rename" => {
+            let nick = event["nick"].as_str().unwrap_or_default();
+            // Occupants change nicknames by leaving as the old one, telling
+            // the new one (status 303), and coming back as the new one
+            let x = Element::new("x")
+                .with_attr("xmlns", NS_MUC_USER)
+                .with_child(
+                    Element::new("item")
+                        .with_attr("affiliation", "member")
+                        .with_attr("role", "none")
+                        .with_attr("nick", nick),
+                )
+                .with_child(Element::new("status").with_attr("code", "303"));
+            let unavailable = Element::new("presence")
+                .with_attr("from", &occupant(event["old_nick"].as_str()))
+                .with_attr("to", to)
+                .with_attr("type", "unavailable")
+                .with_child(x);
+            vec![
+                unavailable,
+                occupant_presence(&occupant(Some(nick)), to, true, &[]),
+            ]
+        }
+        "topic" => vec![room_message().with_child(
+            Element::new("subject").with_text(event["topic"].as_str().unwrap_or_default()),
+        )],
+        "error" => vec![room_message().with_child(
+            Element::new("body").with_text(event["reason"].as_str().unwrap_or_default()),
+        )],
+        _ => Vec::new(),
+    }
+}
+
+// Connects to the XMPP server at `addr` as the component serving `domain`,
+// authenticating with `secret`, and bridges rooms to it until shutdown. The
+// connection is made again whenever it is lost.
+pub async fn connect(
+    addr: String,
+    domain: String,
+    secret: String,
+    state: ServerState,
+    mut shutdown: Shutdown,
+) {
+    while !shutdown.is_shutdown() {
+        match TcpStream::connect(&addr).await {
+            Ok(stream) => match serve(stream, &domain, &secret, &state, &mut shutdown).await {
+                Ok(()) => eprintln!("XMPP server {} closed the stream", addr),
+                Err(e) => eprintln!("XMPP component error ({}): {}", addr, e),
+            },
+            Err(e) => eprintln!("Failed to connect to XMPP server {}: {}", addr, e),
+        }
+
+        tokio::select! {
+            _ = tokio::time::sleep(RECONNECT_DELAY) => {}
+            _ = shutdown.async_listen() => break,
+        }
+    }
+}
+
+// What the XMPP server sends, read as it comes.
+struct Incoming {
+    reader: OwnedReadHalf,
+    buf: Vec<u8>,
+}
+
+impl Incoming {
+    // Reads the next item of the stream `parse` reads, e.g. a stanza. This
+    // may be cancelled without losing what was read.
+    async fn next<T>(
+        &mut self,
+        parse: fn(&str) -> Result<(T, usize), ParseError>,
+    ) -> Result<T, anyhow::Error> {
+        loop {
+            // Reads may end within a character
+            let text = match std::str::from_utf8(&self.buf) {
+                Ok(text) => text,
+                Err(e) if e.error_len().is_none() => {
+                    std::str::from_utf8(&self.buf[..e.valid_up_to()])?
+                }
+                Err(e) => return Err(e.into()),
+            };
+            match parse(text) {
+                Ok((item, len)) => {
+                    self.buf.drain(..len);
+                    return Ok(item);
+                }
+                Err(ParseError::Incomplete) if self.buf.len() < MAX_STANZA_SIZE => {}
+                Err(ParseError::Incomplete) => return Err(anyhow!("Stanza too large")),
+                Err(e) => return Err(e.into()),
+            }
+
+            let mut chunk = [0; 4096];
+            let read = self.reader.read(&mut chunk).await?;
+            if read == 0 {
+                return Err(anyhow!("Connection closed"));
+            }
+            self.buf.extend_from_slice(&chunk[..read]);
+        }
+    }
+}
+
+// Opens a stream to the XMPP server over `stream`, authenticates, and relays
+// stanzas both ways until either side closes it.
+async fn serve(
+    stream: TcpStream,
+    domain: &str,
+    secret: &str,
+    state: &ServerState,
+    shutdown: &mut Shutdown,
+) -> Result<(), anyhow::Error> {
+    let (reader, mut writer) = stream.into_split();
+    let mut incoming = Incoming {
+        reader,
+        buf: Vec::new(),
+    };
+
+    let header = format!(
+        "<?xml version='1.0'?><stream:stream xmlns='{}' xmlns:stream='{}' to='{}'>",
+        NS_COMPONENT,
+        NS_STREAMS,
+        xml::escape(domain)
+    );
+    writer.write_all(header.as_bytes()).await?;
+    let header = incoming.next(xml::parse_stream_header).await?;
+    let stream_id = header
+        .attr("id")
+        .ok_or_else(|| anyhow!("Stream header has no ID"))?;
+    let proof = Element::new("handshake").with_text(&handshake(stream_id, secret));
+    writer.write_all(proof.to_string().as_bytes()).await?;
+    match incoming.next(xml::parse_stanza).await? {
+        Event::Stanza(stanza) if stanza.name == "handshake" => {}
+        Event::Stanza(stanza) => return Err(anyhow!("Handshake refused: {}", stanza)),
+        Event::End => return Err(anyhow!("Handshake refused")),
+    }
+    eprintln!("Bridging rooms to XMPP as {}", domain);
+
+    let (out_tx, mut out_rx) = mpsc::unbounded_channel();
+    let mut bridge = Bridge {
+        domain: String::from(domain),
+        occupants: HashMap::new(),
+        out: out_tx,
+        state: state.clone(),
+    };
+    let result = loop {
+        tokio::select! {
+            event = incoming.next(xml::parse_stanza) => match event {
+                Ok(Event::Stanza(stanza)) => bridge.handle(stanza).await,
+                Ok(Event::End) => break Ok(()),
+                Err(e) => break Err(e),
+            },
+            Some(stanza) = out_rx.recv() => {
+                if let Err(e) = writer.write_all(stanza.to_string().as_bytes()).await {
+                    break Err(e.into());
+                }
+            },
+            _ = shutdown.async_listen() => {
+                let _ = writer.write_all(b"</stream:stream>").await;
+                break Ok(());
+            },
+        }
+    };
+
+    bridge.leave_all().await;
+    result
+}
+
+// Rooms as seen from the XMPP server, for one connection to it.
+struct Bridge {
+    domain: String,
+    // A `User` per occupant of each room, by room and full JID
+    occupants: HashMap<(String, String), User>,
+    // Stanzas waiting to be sent to the XMPP server
+    out: UnboundedSender<Element>,
+    state: ServerState,
+}
+
+impl Bridge {
+    fn send(&self, stanza: Element) {
+        // This will only fail once the connection is gone
+        if let Err(_disconnected) = self.out.send(stanza) {}
+    }
+
+    fn room_jid(&self, room_name: &str) -> String {
+        format!("{}@{}", room_name, self.domain)
+    }
+
+    // The occupant `jid` of `room_name`, unless it left or was kicked out.
+    fn occupant(&mut self, room_name: &str, jid: &str) -> Option<&User> {
+        let key = (String::from(room_name), String::from(jid));
+        // Occupants stop being relayed to once their room closes them
+        if self.occupants.get(&key)?.user_tx.is_closed() {
+            self.occupants.remove(&key);
+            return None;
+        }
+        self.occupants.get(&key)
+    }
+
+    async fn handle(&mut self, stanza: Element) {
+        let (from, to) = match (stanza.attr("from"), stanza.attr("to")) {
+            (Some(from), Some(to)) => (String::from(from), String::from(to)),
+            _ => return,
+        };
+        let to = match Jid::parse(&to) {
+            Some(to) if to.domain == self.domain => to,
+            _ => return,
+        };
+
+        match stanza.name.as_str() {
+            "presence" => self.presence(&stanza, &from, to).await,
+            "message" => self.message(&stanza, &from, to).await,
+            "iq" => self.iq(&stanza, to),
+            _ => {}
+        }
+    }
+
+    async fn presence(&mut self, stanza: &Element, from: &str, to: Jid<'_>) {
+        let room_name = match to.local {
+            Some(room_name) => room_name,
+            None => return,
+        };
+        match stanza.attr("type") {
+            None => {
+                if self.occupant(room_name, from).is_some() {
+                    // Nicknames are not changed through rooms
+                    return;
+                }
+                match to.resource {
+                    Some(nick) => self.join(stanza, room_name, nick, from).await,
+                    None => self.send(error_reply(stanza, "modify", "jid-malformed", None)),
+                }
+            }
+            Some("unavailable") => self.leave(room_name, to.resource, from).await,
+            _ => {}
+        }
+    }
+
+    // Joins `room_name` as the occupant `from`, known there as `nick`.
+    async fn join(&mut self, stanza: &Element, room_name: &str, nick: &str, from: &str) {
+        let state = self.state.clone();
+        let nick = match user::validate_nickname(nick) {
+            Ok(nick) => nick,
+            Err(e) => {
+                let reply = error_reply(stanza, "modify", "not-acceptable", Some(&e.to_string()));
+                return self.send(reply);
+            }
+        };
+        let jid = match Jid::parse(from) {
+            Some(jid) => jid.bare(),
+            None => return,
+        };
+
+        // XMPP users have accounts of their own, created when they first join
+        let user_id = match db::query(&state.db_tx, move |conn| {
+            oauth::identity_user(conn, XMPP_PROVIDER, &jid)
+        })
+        .await
+        {
+            Ok(user_id) => user_id,
+            Err(e) => {
+                eprintln!("Failed to find account of {}: {}", from, e);
+                return self.send(error_reply(stanza, "wait", "internal-server-error", None));
+            }
+        };
+
+        // Nicknames are reserved, whether or not whoever holds them is online,
+        // as when connecting over IRC
+        let new_nick = nick.clone();
+        let reserved = db::query(&state.db_tx, move |conn| {
+            db::reserve_nickname(conn, user_id, &new_nick)
+        })
+        .await;
+        match reserved {
+            Ok(true) => {}
+            Ok(false) => return self.send(error_reply(stanza, "cancel", "conflict", None)),
+            Err(e) => {
+                eprintln!("Failed to reserve nickname: {}", e);
+                return self.send(error_reply(stanza, "wait", "internal-server-error", None));
+            }
+        }
+        state.nicks.write().await.insert(user_id, nick.clone());
+        let online = user::online_users(room_name, &state.rooms, &state.nicks).await;
+
+        let password = stanza.children.iter().find_map(|child| match child {
+            xml::Node::Element(x) if x.name == "x" && x.attr("xmlns") == Some(NS_MUC) => {
+                x.child("password").map(Element::text)
+            }
+            _ => None,
+        });
+        let (user_tx, user_rx) = mpsc::unbounded_channel();
+        let new_user =
+            state.gateway_user(room_name, Principal::user(user_id), false, None, user_tx);
+        let history_seq = match new_user
+            .send_history(state.config.history_limit, None)
+            .await
+        {
+            Ok(history_seq) => Some(history_seq),
+            Err(e) => {
+                eprintln!("Failed to send room history: {}", e);
+                None
+            }
+        };
+        let joined = user::add_user_to_room(
+            &new_user,
+            &state.rooms,
+            state.config.explicit_rooms,
+            state.config.room_capacity,
+            password,
+            history_seq,
+        )
+        .await;
+        let refusal = match joined {
+            Ok(Ok(Admission::Joined)) => None,
+            // MUC has no way to wait for approval
+            Ok(Ok(Admission::Pending(_))) => Some(Refusal::JoinRejected),
+            Ok(Err(refusal)) => Some(refusal),
+            Err(e) => {
+                eprintln!("Failed to join room {}: {}", room_name, e);
+                return self.send(error_reply(stanza, "wait", "internal-server-error", None));
+            }
+        };
+        if let Some(refusal) = refusal {
+            let (kind, condition) = match refusal {
+                Refusal::WrongPassword => ("auth", "not-authorized"),
+                Refusal::RoomFull { .. } => ("wait", "service-unavailable"),
+                Refusal::RoomNotFound => ("cancel", "item-not-found"),
+                Refusal::AccessDenied => ("auth", "forbidden"),
+                Refusal::JoinRejected => ("cancel", "not-allowed"),
+            };
+            self.send(error_reply(stanza, kind, condition, None));
+            return user::remove_user_from_room(&new_user, &state.rooms).await;
+        }
+        if let Err(e) = new_user.announce_join(&state.rooms).await {
+            eprintln!("Failed to announce joining {}: {}", room_name, e);
+        }
+
+        // Occupants are told who is in the room, themselves last, then its
+        // subject, before its history and whatever happens next
+        let room_jid = self.room_jid(room_name);
+        for other in online.iter().filter(|other| other.user_id != user_id) {
+            let occupant = format!(
+                "{}/{}",
+                room_jid,
+                occupant_nick(other.nick.as_deref(), other.user_id)
+            );
+            self.send(occupant_presence(&occupant, from, true, &[]));
+        }
+        let own = format!("{}/{}", room_jid, nick);
+        self.send(occupant_presence(&own, from, true, &["110"]));
+        let name = String::from(room_name);
+        let topic = match db::read(&state.db_tx, move |conn| room::settings(conn, &name)).await {
+            Ok(settings) => settings.and_then(|settings| settings.topic),
+            Err(e) => {
+                eprintln!("Failed to read the topic of {}: {}", room_name, e);
+                None
+            }
+        };
+        self.send(
+            Element::new("message")
+                .with_attr("from", &room_jid)
+                .with_attr("to", from)
+                .with_attr("type", "groupchat")
+                .with_child(Element::new("subject").with_text(&topic.unwrap_or_default())),
+        );
+
+        tokio::task::spawn(forward(
+            user_rx,
+            self.out.clone(),
+            own,
+            String::from(from),
+            user_id,
+        ));
+        self.occupants
+            .insert((String::from(room_name), String::from(from)), new_user);
+    }
+
+    async fn leave(&mut self, room_name: &str, nick: Option<&str>, from: &str) {
+        let key = (String::from(room_name), String::from(from));
+        if let Some(user) = self.occupants.remove(&key) {
+            user::remove_user_from_room(&user, &self.state.rooms).await;
+        }
+
+        let own = format!("{}/{}", self.room_jid(room_name), nick.unwrap_or_default());
+        self.send(occupant_presence(&own, from, false, &["110"]));
+    }
+
+    async fn message(&mut self, stanza: &Element, from: &str, to: Jid<'_>) {
+        let room_name = match to.local {
+            Some(room_name) => room_name,
+            None => return,
+        };
+        if stanza.attr("type") != Some("groupchat") || to.resource.is_some() {
+            // Private messages between occupants are not relayed
+            let reply = error_reply(stanza, "cancel", "feature-not-implemented", None);
+            return self.send(reply);
+        }
+        // Messages without a body, e.g. chat states, are not relayed
+        let text = match stanza.child("body") {
+            Some(body) => body.text(),
+            None => return,
+        };
+
+        let rooms = self.state.rooms.clone();
+        let sent = match self.occupant(room_name, from) {
+            Some(user) => user
+                .send_message(&text, None, None, None, None, &rooms)
+                .await
+                .and_then(PostOutcome::accepted),
+            None => {
+                let reply = error_reply(stanza, "modify", "not-acceptable", None);
+                return self.send(reply);
+            }
+        };
+        if let Err(e) = sent {
+            let reply = error_reply(stanza, "modify", "not-acceptable", Some(&e.to_string()));
+            self.send(reply);
+        }
+    }
+
+    // Answers service discovery, telling the bridge and its rooms apart. Other
+    // requests are not supported.
+    fn iq(&self, stanza: &Element, to: Jid<'_>) {
+        if !matches!(stanza.attr("type"), Some("get" | "set")) {
+            return;
+        }
+        let is_disco_info = stanza.attr("type") == Some("get")
+            && stanza
+                .child("query")
+                .is_some_and(|query| query.attr("xmlns") == Some(NS_DISCO_INFO));
+        if !is_disco_info || to.resource.is_some() {
+            return self.send(error_reply(stanza, "cancel", "service-unavailable", None));
+        }
+
+        let name = to.local.unwrap_or("bi-chat");
+        let query = Element::new("query")
+            .with_attr("xmlns", NS_DISCO_INFO)
+            .with_child(
+                Element::new("identity")
+                    .with_attr("category", "conference")
+                    .with_attr("type", "text")
+                    .with_attr("name", name),
+            )
+            .with_child(Element::new("feature").with_attr("var", NS_DISCO_INFO))
+            .with_child(Element::new("feature").with_attr("var", NS_MUC));
+        self.send(reply(stanza).with_attr("type", "result").with_child(query));
+    }
+
+    // Takes every occupant out of their room, once the connection is lost.
+    async fn leave_all(&mut self) {
+        for (_, user) in self.occupants.drain() {
+            user::remove_user_from_room(&user, &self.state.rooms).await;
+        }
+    }
+}
+
+// Sends what a room sends to the `User` of an occupant to the XMPP server, as
+// stanzas for `to`, the occupant's full JID. `own` is its JID in the room,
+// `<room>@<domain>/<nick>`.
+async fn forward(
+    mut user_rx: UserRx,
+    out: UnboundedSender<Element>,
+    own: String,
+    to: String,
+    user_id: usize,
+) {
+    let room_jid = own
+        .split_once('/')
+        .map_or(own.as_str(), |(room_jid, _)| room_jid);
+    while let Some(msg) = user_rx.recv().await {
+        if msg.is_close() {
+            // The occupant was kicked out of the room (status 307)
+            let _ = out.send(occupant_presence(&own, &to, false, &["307", "110"]));
+            break;
+        }
+
+        let event = match msg
+            .to_str()
+            .ok()
+            .and_then(|text| serde_json::from_str(text).ok())
+        {
+            Some(event) => event,
+            None => continue,
+        };
+        for stanza in event_stanzas(&event, room_jid, &to, user_id) {
+            if out.send(stanza).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_jid() {
+        let jid = Jid::parse("rust@rooms.example.org/alice").unwrap();
+        assert_eq!(
+            jid,
+            Jid {
+                local: Some("rust"),
+                domain: "rooms.example.org",
+                resource: Some("alice"),
+            }
+        );
+        assert_eq!(jid.bare(), "rust@rooms.example.org");
+        assert_eq!(Jid::parse("rooms.example.org").unwrap().local, None);
+        assert_eq!(Jid::parse("@/alice"), None);
+
+        // As in XEP-0114
+        assert_eq!(
+            handshake("3BF96D32", "sesame"),
+            hex::encode(Sha1::digest(b"3BF96D32sesame"))
+        );
+    }
+
+    #[test]
+    fn test_event_stanzas() {
+        let room = "rust@rooms.example.org";
+        let to = "bob@example.org/laptop";
+        let message = json!({
+            "type": "message",
+            "id": 7,
+            "room": "rust",
+            "user_id": 1,
+            "nick": "alice",
+            "text": "1 < 2",
+        });
+        assert_eq!(
+            event_stanzas(&message, room, to, 2)
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>(),
+            vec![
+                "<message from='rust@rooms.example.org/alice' to='bob@example.org/laptop' id='7' type='groupchat'><body>1 &lt; 2</body></message>"
+            ]
+        );
+
+        let leave = json!({ "type": "leave", "room": "rust", "user_id": 3 });
+        let stanzas = event_stanzas(&leave, room, to, 2);
+        assert_eq!(stanzas.len(), 1);
+        assert_eq!(
+            stanzas[0].attr("from"),
+            Some("rust@rooms.example.org/user3")
+        );
+        assert_eq!(stanzas[0].attr("type"), Some("unavailable"));
+
+        // Occupants are told of their own joins and leaves separately, and of
+        // events MUC has no say for not at all
+        let join = json!({ "type": "join", "room": "rust", "user_id": 2, "nick": "bob" });
+        assert!(event_stanzas(&join, room, to, 2).is_empty());
+        let ack = json!({ "type": "ack", "room": "rust", "id": 1 });
+        assert!(event_stanzas(&ack, room, to, 2).is_empty());
+    }
+}
@@ -0,0 +1,407 @@
+use std::fmt::{self, Display, Formatter};
+
+// Just enough XML for the stanzas of an XMPP stream: elements, attributes and
+// text, without namespace processing. Comments and processing instructions
+// are skipped, and anything else (e.g. CDATA or DTDs) is refused.
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Node {
+    Element(Element),
+    Text(String),
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Element {
+    // Qualified name, e.g. `message` or `stream:error`
+    pub name: String,
+    pub attrs: Vec<(String, String)>,
+    pub children: Vec<Node>,
+}
+
+impl Element {
+    pub fn new(name: &str) -> Self {
+        Element {
+            name: String::from(name),
+            ..Element::default()
+        }
+    }
+
+    pub fn with_attr(mut self, name: &str, value: &str) -> Self {
+        self.attrs.push((String::from(name), String::from(value)));
+        self
+    }
+
+    pub fn with_child(mut self, child: Element) -> Self {
+        self.children.push(Node::Element(child));
+        self
+    }
+
+    pub fn with_text(mut self, text: &str) -> Self {
+        self.children.push(Node::Text(String::from(text)));
+        self
+    }
+
+    pub fn attr(&self, name: &str) -> Option<&str> {
+        self.attrs
+            .iter()
+            .find(|(attr, _)| attr == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    // The first child element named `name`.
+    pub fn child(&self, name: &str) -> Option<&Element> {
+        self.children.iter().find_map(|child| match child {
+            Node::Element(element) if element.name == name => Some(element),
+            _ => None,
+        })
+    }
+
+    // The text directly inside the element.
+    pub fn text(&self) -> String {
+        self.children
+            .iter()
+            .filter_map(|child| match child {
+                Node::Text(text) => Some(text.as_str()),
+                Node::Element(_) => None,
+            })
+            .collect()
+    }
+}
+
+impl Display for Element {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "<{}", self.name)?;
+        for (name, value) in &self.attrs {
+            write!(f, " {}='{}'", name, escape(value))?;
+        }
+        if self.children.is_empty() {
+            return write!(f, "/>");
+        }
+
+        write!(f, ">")?;
+        for child in &self.children {
+            match child {
+                Node::Element(element) => write!(f, "{}", element)?,
+                Node::Text(text) => write!(f, "{}", escape(text))?,
+            }
+        }
+        write!(f, "</{}>", self.name)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ParseError {
+    // More of the stream is needed to tell
+    Incomplete,
+    Invalid(String),
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Incomplete => write!(f, "Incomplete XML"),
+            ParseError::Invalid(reason) => write!(f, "Invalid XML: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '&' => escaped.push_str("&amp;"),
+            '\'' => escaped.push_str("&apos;"),
+            '"' => escaped.push_str("&quot;"),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+fn unescape(text: &str) -> Result<String, ParseError> {
+    let mut unescaped = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        unescaped.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find(';')
+            .ok_or_else(|| ParseError::Invalid(String::from("Unterminated entity")))?;
+        let entity = &rest[start + 1..start + end];
+        let c = match entity {
+            "lt" => '<',
+            "gt" => '>',
+            "amp" => '&',
+            "apos" => '\'',
+            "quot" => '"',
+            _ => {
+                let code = match entity.strip_prefix("#x") {
+                    Some(hex) => u32::from_str_radix(hex, 16).ok(),
+                    None => entity.strip_prefix('#').and_then(|dec| dec.parse().ok()),
+                };
+                code.and_then(char::from_u32)
+                    .ok_or_else(|| ParseError::Invalid(format!("Unknown entity &{};", entity)))?
+            }
+        };
+        unescaped.push(c);
+        rest = &rest[start + end + 1..];
+    }
+    unescaped.push_str(rest);
+
+    Ok(unescaped)
+}
+
+// Reads XML from the start of a buffer, telling how much of it was read.
+struct Reader<'a> {
+    buf: &'a str,
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn rest(&self) -> &'a str {
+        &self.buf[self.pos..]
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = self.rest();
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    // Skips past `end`, e.g. the end of a comment.
+    fn skip_past(&mut self, end: &str) -> Result<(), ParseError> {
+        let found = self.rest().find(end).ok_or(ParseError::Incomplete)?;
+        self.pos += found + end.len();
+        Ok(())
+    }
+
+    // Skips comments, processing instructions and whitespace.
+    fn skip_misc(&mut self) -> Result<(), ParseError> {
+        loop {
+            self.skip_whitespace();
+            let rest = self.rest();
+            if rest.starts_with("<?") {
+                self.skip_past("?>")?;
+            } else if rest.starts_with("<!--") {
+                self.skip_past("-->")?;
+            } else if !rest.is_empty() && "<!--".starts_with(rest) {
+                return Err(ParseError::Incomplete);
+            } else {
+                return Ok(());
+            }
+        }
+    }
+
+    fn name(&mut self) -> Result<&'a str, ParseError> {
+        let rest = self.rest();
+        let len = rest
+            .find(|c: char| c.is_whitespace() || c == '/' || c == '>' || c == '=')
+            .ok_or(ParseError::Incomplete)?;
+        if len == 0 {
+            return Err(ParseError::Invalid(String::from("Expected a name")));
+        }
+
+        self.pos += len;
+        Ok(&rest[..len])
+    }
+
+    // Reads a start tag, telling whether it closes itself.
+    fn start_tag(&mut self) -> Result<(Element, bool), ParseError> {
+        if self.rest().is_empty() {
+            return Err(ParseError::Incomplete);
+        }
+        if !self.rest().starts_with('<') {
+            return Err(ParseError::Invalid(String::from("Expected a tag")));
+        }
+        self.pos += 1;
+
+        let mut element = Element::new(self.name()?);
+        loop {
+            self.skip_whitespace();
+            let rest = self.rest();
+            if rest.starts_with("/>") {
+                self.pos += 2;
+                return Ok((element, true));
+            }
+            if rest.starts_with('>') {
+                self.pos += 1;
+                return Ok((element, false));
+            }
+            if rest.is_empty() || rest == "/" {
+                return Err(ParseError::Incomplete);
+            }
+
+            let name = self.name()?;
+            self.skip_whitespace();
+            if !self.rest().starts_with('=') {
+                return match self.rest() {
+                    "" => Err(ParseError::Incomplete),
+                    _ => Err(ParseError::Invalid(format!(
+                        "Attribute {} has no value",
+                        name
+                    ))),
+                };
+            }
+            self.pos += 1;
+            self.skip_whitespace();
+
+            let quote = match self.rest().chars().next() {
+                Some(quote @ ('\'' | '"')) => quote,
+                Some(_) => return Err(ParseError::Invalid(String::from("Expected a quote"))),
+                None => return Err(ParseError::Incomplete),
+            };
+            self.pos += 1;
+            let len = self.rest().find(quote).ok_or(ParseError::Incomplete)?;
+            let value = unescape(&self.rest()[..len])?;
+            self.pos += len + 1;
+            element.attrs.push((String::from(name), value));
+        }
+    }
+
+    // Reads the children of `element` and its end tag.
+    fn content(&mut self, element: &mut Element) -> Result<(), ParseError> {
+        loop {
+            let rest = self.rest();
+            if rest.starts_with("</") {
+                self.pos += 2;
+                let name = self.name()?;
+                if name != element.name {
+                    return Err(ParseError::Invalid(format!(
+                        "Expected </{}>, got </{}>",
+                        element.name, name
+                    )));
+                }
+                self.skip_whitespace();
+                if !self.rest().starts_with('>') {
+                    return match self.rest() {
+                        "" => Err(ParseError::Incomplete),
+                        _ => Err(ParseError::Invalid(String::from("Expected >"))),
+                    };
+                }
+                self.pos += 1;
+                return Ok(());
+            }
+            if rest.starts_with("<!--") {
+                self.skip_past("-->")?;
+            } else if rest.starts_with("<?") {
+                self.skip_past("?>")?;
+            } else if "<!--".starts_with(rest) && !rest.is_empty() {
+                return Err(ParseError::Incomplete);
+            } else if rest.starts_with("<!") {
+                return Err(ParseError::Invalid(String::from("Unsupported markup")));
+            } else if rest.starts_with('<') {
+                let child = self.element()?;
+                element.children.push(Node::Element(child));
+            } else {
+                let len = rest.find('<').ok_or(ParseError::Incomplete)?;
+                let text = unescape(&rest[..len])?;
+                self.pos += len;
+                if !text.is_empty() {
+                    element.children.push(Node::Text(text));
+                }
+            }
+        }
+    }
+
+    fn element(&mut self) -> Result<Element, ParseError> {
+        let (mut element, closed) = self.start_tag()?;
+        if !closed {
+            self.content(&mut element)?;
+        }
+
+        Ok(element)
+    }
+}
+
+// Parses the header opening a stream, `<stream:stream ...>`, which is not
+// closed until the stream ends, along with anything before it such as an XML
+// declaration. Returns it, with no children, and the length it took.
+pub fn parse_stream_header(buf: &str) -> Result<(Element, usize), ParseError> {
+    let mut reader = Reader { buf, pos: 0 };
+    reader.skip_misc()?;
+    let (header, _) = reader.start_tag()?;
+    if header.name != "stream:stream" {
+        return Err(ParseError::Invalid(format!(
+            "Expected a stream header, got <{}>",
+            header.name
+        )));
+    }
+
+    Ok((header, reader.pos))
+}
+
+// What comes next in a stream, once its header was read.
+#[derive(Debug, PartialEq)]
+pub enum Event {
+    Stanza(Element),
+    // The stream was closed with `</stream:stream>`
+    End,
+}
+
+// Parses the next stanza of a stream, along with the whitespace before it,
+// returning it and the length it took.
+pub fn parse_stanza(buf: &str) -> Result<(Event, usize), ParseError> {
+    let mut reader = Reader { buf, pos: 0 };
+    reader.skip_misc()?;
+    let rest = reader.rest();
+    if rest.starts_with("</") {
+        reader.skip_past(">")?;
+        return Ok((Event::End, reader.pos));
+    }
+
+    let stanza = reader.element()?;
+    Ok((Event::Stanza(stanza), reader.pos))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_stream() {
+        let buf = "<?xml version='1.0'?><stream:stream xmlns='jabber:component:accept' id='abc' from='rooms.example.org'>  <handshake/><message to='a@b' type='groupchat'><body>1 &lt; 2 &amp;&#x21;</body></message><mess";
+
+        let (header, len) = parse_stream_header(buf).unwrap();
+        assert_eq!(header.attr("id"), Some("abc"));
+
+        let rest = &buf[len..];
+        let (handshake, len) = parse_stanza(rest).unwrap();
+        assert_eq!(handshake, Event::Stanza(Element::new("handshake")));
+
+        let rest = &rest[len..];
+        let (message, len) = parse_stanza(rest).unwrap();
+        let message = match message {
+            Event::Stanza(message) => message,
+            Event::End => panic!("Stream ended"),
+        };
+        assert_eq!(message.attr("type"), Some("groupchat"));
+        assert_eq!(message.child("body").unwrap().text(), "1 < 2 &!");
+
+        // Stanzas are only parsed once complete
+        let rest = &rest[len..];
+        assert_eq!(parse_stanza(rest), Err(ParseError::Incomplete));
+        assert_eq!(parse_stanza("  </stream:stream>").unwrap().0, Event::End);
+        assert!(matches!(
+            parse_stanza("<a></b>"),
+            Err(ParseError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn test_display() {
+        let message = Element::new("message")
+            .with_attr("to", "alice@example.org/o'hara")
+            .with_child(Element::new("body").with_text("<3 & more"))
+            .with_child(Element::new("subject"));
+
+        let xml = message.to_string();
+        assert_eq!(
+            xml,
+            "<message to='alice@example.org/o&apos;hara'><body>&lt;3 &amp; more</body><subject/></message>"
+        );
+        assert_eq!(parse_stanza(&xml).unwrap().0, Event::Stanza(message));
+    }
+}
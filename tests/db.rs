@@ -1,11 +1,14 @@
-use std::path::Path;
+use std::{path::Path, time::Duration};
 
 use bi_chat::{
     self,
-    db::{spawn_db, DBMessage},
+    db::{self, spawn_db, CommitPolicy, DBMessage, DbRequest, Pragmas, MESSAGE_COLUMNS},
+    dead_letter::DeadLetters,
     shutdown::Shutdown,
+    store::StoreOptions,
 };
 
+use opentelemetry::Context;
 use rusqlite::Connection;
 use tokio::sync::{broadcast, mpsc};
 
@@ -25,6 +28,10 @@ async fn test_db_single_insert() {
     let db_handle = std::thread::spawn(move || {
         spawn_db(
             db_path,
+            Pragmas::default(),
+            StoreOptions::default(),
+            CommitPolicy::default(),
+            DeadLetters::new(Path::new("./test_dead_letters.jsonl")),
             db_rx,
             Shutdown::new(shutdown_listener, db_shutdown_complete_tx),
         )
@@ -35,7 +42,7 @@ async fn test_db_single_insert() {
     let message = String::from("Hello there");
     let chat_message = DBMessage::new(user_id, &room_name, &message);
     db_tx
-        .send(chat_message)
+        .send(DbRequest::Insert(Box::new(chat_message), Context::new()))
         .expect("Failed to send message to Receiver!");
 
     drop(db_tx);
@@ -46,19 +53,13 @@ async fn test_db_single_insert() {
     db_handle.join().unwrap().unwrap();
 
     // Establish another connection to check if rows are properly inserted
-    let conn = Connection::open(&db_path).expect("Unable to establish connection to DB.");
+    let conn = Connection::open(db_path).expect("Unable to establish connection to DB.");
     let mut stmt = conn
-        .prepare("SELECT user_id, room_name, message FROM chat_messages")
+        .prepare(&format!("SELECT {} FROM chat_messages", MESSAGE_COLUMNS))
         .expect("Failed preparing SQL statement.");
 
     let returned_msg = stmt
-        .query_map([], |row| {
-            Ok(DBMessage {
-                user_id: row.get(0).expect("user_id not found!"),
-                room_name: row.get(1).expect("room_name not found!"),
-                message: row.get(2).expect("message not found!"),
-            })
-        })
+        .query_map([], DBMessage::from_row)
         .expect("Query failed")
         .next()
         .expect("No message returned");
@@ -66,10 +67,14 @@ async fn test_db_single_insert() {
     assert!(returned_msg.is_ok());
 
     let returned_msg = returned_msg.unwrap();
+    assert_eq!(returned_msg.message_id, Some(1));
     assert_eq!(returned_msg.user_id, user_id);
     assert_eq!(returned_msg.room_name, room_name);
     assert_eq!(returned_msg.message, message);
 
+    // Closing the last connection removes the WAL
+    drop(stmt);
+    conn.close().unwrap();
     std::fs::remove_file(db_path).unwrap();
 }
 
@@ -91,6 +96,10 @@ async fn test_db_multiple_inserts() {
     let db_handle = std::thread::spawn(move || {
         spawn_db(
             db_path,
+            Pragmas::default(),
+            StoreOptions::default(),
+            CommitPolicy::default(),
+            DeadLetters::new(Path::new("./test_dead_letters.jsonl")),
             db_rx,
             Shutdown::new(shutdown_listener, db_shutdown_complete_tx),
         )
@@ -102,8 +111,11 @@ async fn test_db_multiple_inserts() {
 
     for _ in 0..TOTAL_ROWS {
         let tx = db_tx.clone();
-        tx.send(DBMessage::new(user_id, &room_name, &message))
-            .expect("Receiver disconnected!");
+        tx.send(DbRequest::Insert(
+            Box::new(DBMessage::new(user_id, &room_name, &message)),
+            Context::new(),
+        ))
+        .expect("Receiver disconnected!");
     }
 
     drop(db_tx);
@@ -114,25 +126,22 @@ async fn test_db_multiple_inserts() {
     db_handle.join().unwrap().unwrap();
 
     // Establish another connection to check if rows are properly inserted
-    let conn = Connection::open(&db_path).expect("Unable to establish connection to DB.");
+    let conn = Connection::open(db_path).expect("Unable to establish connection to DB.");
     let mut stmt = conn
-        .prepare("SELECT user_id, room_name, message FROM chat_messages")
+        .prepare(&format!("SELECT {} FROM chat_messages", MESSAGE_COLUMNS))
         .unwrap();
 
     let rows = stmt
-        .query_map([], |row| {
-            Ok(DBMessage {
-                user_id: row.get(0).expect("user_id not found!"),
-                room_name: row.get(1).expect("room_name not found!"),
-                message: row.get(2).expect("message not found!"),
-            })
-        })
+        .query_map([], DBMessage::from_row)
         .expect("Query failed")
         .map(|row| row.unwrap())
         .collect::<Vec<DBMessage>>();
 
     assert_eq!(rows.len(), TOTAL_ROWS);
 
+    // Closing the last connection removes the WAL
+    drop(stmt);
+    conn.close().unwrap();
     std::fs::remove_file(db_path).unwrap();
 }
 
@@ -157,6 +166,10 @@ async fn test_db_parallel_inserts() {
     let db_handle = std::thread::spawn(move || {
         spawn_db(
             db_path,
+            Pragmas::default(),
+            StoreOptions::default(),
+            CommitPolicy::default(),
+            DeadLetters::new(Path::new("./test_dead_letters.jsonl")),
             db_rx,
             Shutdown::new(shutdown_listener, db_shutdown_complete_tx),
         )
@@ -169,7 +182,10 @@ async fn test_db_parallel_inserts() {
     // Simulate many requests at once
     (0..TOTAL_ROWS).into_par_iter().for_each(|_| {
         db_tx
-            .send(DBMessage::new(user_id, &room_name, &message))
+            .send(DbRequest::Insert(
+                Box::new(DBMessage::new(user_id, &room_name, &message)),
+                Context::new(),
+            ))
             .expect("Receiver disconnected!");
     });
 
@@ -180,24 +196,217 @@ async fn test_db_parallel_inserts() {
     db_handle.join().unwrap().unwrap();
 
     // Establish another connection to check if rows are properly inserted
-    let conn = Connection::open(&db_path).expect("Unable to establish connection to DB.");
+    let conn = Connection::open(db_path).expect("Unable to establish connection to DB.");
     let mut stmt = conn
-        .prepare("SELECT user_id, room_name, message FROM chat_messages")
+        .prepare(&format!("SELECT {} FROM chat_messages", MESSAGE_COLUMNS))
         .unwrap();
 
     let rows = stmt
-        .query_map([], |row| {
-            Ok(DBMessage {
-                user_id: row.get(0).expect("user_id not found!"),
-                room_name: row.get(1).expect("room_name not found!"),
-                message: row.get(2).expect("message not found!"),
-            })
-        })
+        .query_map([], DBMessage::from_row)
         .expect("Query failed")
         .map(|row| row.unwrap())
         .collect::<Vec<DBMessage>>();
 
     assert_eq!(rows.len(), TOTAL_ROWS);
 
+    // Closing the last connection removes the WAL
+    drop(stmt);
+    conn.close().unwrap();
+    std::fs::remove_file(db_path).unwrap();
+}
+
+#[tokio::test]
+// Tests that writes are committed in batches while the DB thread runs
+async fn test_db_batched_commits() {
+    let db_path = Path::new("./test_batched.db");
+    if db_path.exists() {
+        std::fs::remove_file(db_path).unwrap();
+    }
+    let (db_tx, db_rx) = mpsc::unbounded_channel();
+    let (notify_shutdown, _) = broadcast::channel(1);
+    let (shutdown_complete_tx, mut shutdown_complete_rx) = mpsc::channel(1);
+    let shutdown_listener = notify_shutdown.subscribe();
+    let db_shutdown_complete_tx = shutdown_complete_tx.clone();
+
+    let policy = CommitPolicy {
+        batch_size: 3,
+        max_delay: Duration::from_millis(200),
+    };
+    let db_handle = std::thread::spawn(move || {
+        spawn_db(
+            db_path,
+            Pragmas::default(),
+            StoreOptions::default(),
+            policy,
+            DeadLetters::new(Path::new("./test_dead_letters.jsonl")),
+            db_rx,
+            Shutdown::new(shutdown_listener, db_shutdown_complete_tx),
+        )
+    });
+
+    // Committed rows, as seen from another connection
+    let committed = || {
+        let conn = Connection::open(db_path).expect("Unable to establish connection to DB.");
+        conn.query_row("SELECT COUNT(*) FROM chat_messages", [], |row| {
+            row.get::<_, usize>(0)
+        })
+        .unwrap()
+    };
+    let insert = || {
+        db::insert(&db_tx, DBMessage::new(1, "TestRoom", "Hello there")).unwrap();
+    };
+
+    // Wait for the schema to be created and committed
+    db::query(&db_tx, |_| Ok(())).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    // Queries do not count toward the batch
+    insert();
+    insert();
+    db::query(&db_tx, |_| Ok(())).await.unwrap();
+    assert_eq!(committed(), 0);
+
+    // The batch is full on the third insert, and committed before the read
+    // that follows it is handled
+    insert();
+    let read = db::read(&db_tx, |conn| {
+        conn.query_row("SELECT COUNT(*) FROM chat_messages", [], |row| {
+            row.get::<_, usize>(0)
+        })
+    });
+    assert_eq!(read.await.unwrap(), 3);
+    assert_eq!(committed(), 3);
+
+    // Smaller batches are committed once they are old enough
+    insert();
+    db::query(&db_tx, |_| Ok(())).await.unwrap();
+    assert_eq!(committed(), 3);
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    assert_eq!(committed(), 4);
+
+    drop(db_tx);
+    drop(notify_shutdown);
+    drop(shutdown_complete_tx);
+    let _ = shutdown_complete_rx.recv().await;
+    db_handle.join().unwrap().unwrap();
+
+    std::fs::remove_file(db_path).unwrap();
+}
+
+#[tokio::test]
+// Tests that reads made through read-only connections observe earlier writes
+async fn test_db_reads() {
+    let db_path = Path::new("./test_reads.db");
+    if db_path.exists() {
+        std::fs::remove_file(db_path).unwrap();
+    }
+    let (db_tx, db_rx) = mpsc::unbounded_channel();
+    let (notify_shutdown, _) = broadcast::channel(1);
+    let (shutdown_complete_tx, mut shutdown_complete_rx) = mpsc::channel(1);
+    let shutdown_listener = notify_shutdown.subscribe();
+    let db_shutdown_complete_tx = shutdown_complete_tx.clone();
+
+    let db_handle = std::thread::spawn(move || {
+        spawn_db(
+            db_path,
+            Pragmas::default(),
+            StoreOptions {
+                read_connections: 2,
+                ..StoreOptions::default()
+            },
+            CommitPolicy::default(),
+            DeadLetters::new(Path::new("./test_dead_letters.jsonl")),
+            db_rx,
+            Shutdown::new(shutdown_listener, db_shutdown_complete_tx),
+        )
+    });
+
+    for expected in 1..=3 {
+        db::insert(&db_tx, DBMessage::new(1, "TestRoom", "Hello there")).unwrap();
+        let count = db::read(&db_tx, |conn| {
+            conn.query_row("SELECT COUNT(*) FROM chat_messages", [], |row| {
+                row.get::<_, usize>(0)
+            })
+        })
+        .await
+        .unwrap();
+        assert_eq!(count, expected);
+    }
+
+    // Reads may not write
+    assert!(
+        db::read(&db_tx, |conn| conn.execute("DELETE FROM chat_messages", []))
+            .await
+            .is_err()
+    );
+
+    drop(db_tx);
+    drop(notify_shutdown);
+    drop(shutdown_complete_tx);
+    let _ = shutdown_complete_rx.recv().await;
+    db_handle.join().unwrap().unwrap();
+
+    std::fs::remove_file(db_path).unwrap();
+}
+
+#[test]
+// Tests that exported history is imported into a room, all of it or none
+fn test_db_import_history() {
+    let db_path = Path::new("./test_import.db");
+    let export_path = Path::new("./test_import.csv");
+    if db_path.exists() {
+        std::fs::remove_file(db_path).unwrap();
+    }
+    std::fs::write(
+        export_path,
+        "id,seq,user_id,username,nick,text,created_at,edited_at\r\n\
+        4,1,5,,guest,\"Hello, there\",2024-01-01 09:00:00,\r\n\
+        9,2,5,,guest,Bye,2024-01-01 10:00:00,2024-01-01 10:05:00\r\n",
+    )
+    .unwrap();
+
+    let imported = db::import_history(db_path, Pragmas::default(), "archive", export_path).unwrap();
+    assert_eq!(imported.messages, 2);
+    assert_eq!(imported.renumbered, 0);
+    // Guests of the exporting server are not this one's
+    assert_eq!(imported.anonymized, 2);
+
+    let conn = Connection::open(db_path).unwrap();
+    let rows = conn
+        .prepare("SELECT message_id, room_name, message, created_at FROM chat_messages")
+        .unwrap()
+        .query_map([], |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, String>(3)?,
+            ))
+        })
+        .unwrap()
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    assert_eq!(
+        rows[0],
+        (
+            4,
+            String::from("archive"),
+            String::from("Hello, there"),
+            String::from("2024-01-01 09:00:00")
+        )
+    );
+    assert_eq!(rows[1].0, 9);
+
+    // Malformed exports import nothing
+    std::fs::write(export_path, "id,seq\r\n10,3\r\n").unwrap();
+    assert!(db::import_history(db_path, Pragmas::default(), "archive", export_path).is_err());
+    let count: i64 = conn
+        .query_row("SELECT COUNT(*) FROM chat_messages", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(count, 2);
+
+    conn.close().unwrap();
     std::fs::remove_file(db_path).unwrap();
+    std::fs::remove_file("./test_import.db.lock").unwrap();
+    std::fs::remove_file(export_path).unwrap();
 }
@@ -1,8 +1,236 @@
-use std::path::PathBuf;
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
-use bi_chat::server;
-use futures::{FutureExt, SinkExt, StreamExt};
-use tokio_tungstenite::{connect_async, tungstenite::Message};
+use bi_chat::{
+    auth::totp,
+    config::Config,
+    guest::GuestMode,
+    proto::{
+        chat_client::ChatClient, client_frame, server_event, server_frame::Frame, ClientFrame,
+        ServerEvent, ServerFrame,
+    },
+    server,
+    store::StoreKind,
+};
+use futures::{FutureExt, Sink, SinkExt, Stream, StreamExt};
+use prost::Message as _;
+use serde_json::{json, Value};
+use sha1::{Digest, Sha1};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+};
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tokio_tungstenite::{
+    connect_async,
+    tungstenite::{self, client::IntoClientRequest, Message},
+};
+use warp::Filter;
+
+// Waits until the server spawned on `port` is accepting connections.
+async fn wait_for_server(port: u16) {
+    for _ in 0..50 {
+        if TcpStream::connect(("127.0.0.1", port)).await.is_ok() {
+            return;
+        }
+
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+
+    panic!("Server on port {} did not start", port);
+}
+
+// Users are only added to their room after the handshake completes (and their
+// room history has been sent), so give the server a moment to catch up.
+async fn wait_for_join() {
+    tokio::time::sleep(Duration::from_millis(100)).await;
+}
+
+// Reads the next event sent by the server, skipping presence updates (`join`,
+// `leave` and `presence` events): tests connect several users at once, which
+// join and leave in no particular order.
+async fn next_event<S>(stream: &mut S) -> Value
+where
+    S: Stream<Item = Result<Message, tungstenite::Error>> + Unpin,
+{
+    loop {
+        let event = next_raw_event(stream).await;
+        if !["join", "leave", "presence"].contains(&event["type"].as_str().unwrap_or("")) {
+            return event;
+        }
+    }
+}
+
+// Reads the next event sent by the server.
+async fn next_raw_event<S>(stream: &mut S) -> Value
+where
+    S: Stream<Item = Result<Message, tungstenite::Error>> + Unpin,
+{
+    let msg = stream.next().await.expect("No value found!").unwrap();
+    serde_json::from_str(&msg.into_text().unwrap()).expect("Invalid event")
+}
+
+// Sends a JSON protocol frame to the server.
+async fn send_frame<S>(stream: &mut S, frame: Value)
+where
+    S: Sink<Message> + Unpin,
+    S::Error: std::fmt::Debug,
+{
+    stream
+        .send(Message::Text(frame.to_string()))
+        .await
+        .expect("Unable to send message");
+}
+
+// Enrolls a second factor for registered user `username`, who is given as an
+// `--admin-user`, and logs them in with it, returning their JWT.
+async fn admin_login(port: u16, username: &str) -> String {
+    let credentials = json!({ "username": username, "password": "correct horse" });
+    let (_, body) = http_request(port, "POST", "/users/totp/enroll", &[], Some(credentials)).await;
+    let secret = String::from(body["secret"].as_str().unwrap());
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let with_code =
+        |code: String| json!({ "username": username, "password": "correct horse", "code": code });
+
+    let code = totp::code_at(&secret, now).unwrap();
+    let (status, _) = http_request(
+        port,
+        "POST",
+        "/users/totp/confirm",
+        &[],
+        Some(with_code(code)),
+    )
+    .await;
+    assert_eq!(status, 200);
+
+    // Codes can only be used once, but those of the next time step are accepted
+    let code = totp::code_at(&secret, now + 30).unwrap();
+    let (status, body) =
+        http_request(port, "POST", "/users/login", &[], Some(with_code(code))).await;
+    assert_eq!(status, 200);
+
+    String::from(body["token"].as_str().unwrap())
+}
+
+// Sends an HTTP request to the server, returning the response status and JSON body.
+async fn http_request(
+    port: u16,
+    method: &str,
+    path: &str,
+    headers: &[(&str, &str)],
+    body: Option<Value>,
+) -> (u16, Value) {
+    let (status, _, body) = http_request_with_headers(port, method, path, headers, body).await;
+    (status, body)
+}
+
+// Like `http_request`, but also returns the response headers, with lowercase names.
+async fn http_request_with_headers(
+    port: u16,
+    method: &str,
+    path: &str,
+    headers: &[(&str, &str)],
+    body: Option<Value>,
+) -> (u16, Vec<(String, String)>, Value) {
+    let (status, headers, body) = http_request_raw(port, method, path, headers, body).await;
+    let body = serde_json::from_str(&body).unwrap_or(Value::Null);
+
+    (status, headers, body)
+}
+
+// Like `http_request_with_headers`, but returns the body as is, once
+// reassembled if sent in chunks.
+async fn http_request_raw(
+    port: u16,
+    method: &str,
+    path: &str,
+    headers: &[(&str, &str)],
+    body: Option<Value>,
+) -> (u16, Vec<(String, String)>, String) {
+    let mut stream = TcpStream::connect(("127.0.0.1", port))
+        .await
+        .expect("Unable to connect to server");
+
+    let body = body.map(|body| body.to_string()).unwrap_or_default();
+    let mut request = format!(
+        "{} {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nContent-Type: application/json\r\nContent-Length: {}\r\n",
+        method,
+        path,
+        body.len()
+    );
+    for (name, value) in headers {
+        request.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    request.push_str("\r\n");
+    request.push_str(&body);
+    stream
+        .write_all(request.as_bytes())
+        .await
+        .expect("Unable to send request");
+
+    let mut response = String::new();
+    stream
+        .read_to_string(&mut response)
+        .await
+        .expect("Unable to read response");
+
+    let status = response[9..12].parse().expect("Invalid status line");
+    let (head, body) = response.split_once("\r\n\r\n").unwrap_or((&response, ""));
+    let headers: Vec<(String, String)> = head
+        .lines()
+        .skip(1)
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.to_lowercase(), String::from(value.trim())))
+        .collect();
+    let chunked = headers
+        .iter()
+        .any(|(name, value)| name == "transfer-encoding" && value == "chunked");
+    if !chunked {
+        return (status, headers, String::from(body));
+    }
+
+    // Each chunk is preceded by its size in hex, until one of size 0
+    let mut unchunked = String::new();
+    let mut rest = body;
+    while let Some((size, chunk)) = rest.split_once("\r\n") {
+        let size = usize::from_str_radix(size, 16).expect("Invalid chunk size");
+        if size == 0 {
+            break;
+        }
+        unchunked.push_str(&chunk[..size]);
+        rest = &chunk[size + 2..];
+    }
+
+    (status, headers, unchunked)
+}
+
+// Value of the `session` cookie set by a response, without its attributes.
+fn session_cookie(headers: &[(String, String)]) -> Option<String> {
+    headers
+        .iter()
+        .filter(|(name, _)| name == "set-cookie")
+        .find_map(|(_, value)| value.split(';').next())
+        .filter(|cookie| cookie.starts_with("session="))
+        .map(String::from)
+}
+
+fn remove_db(db_path: &Path) {
+    std::fs::remove_file(db_path)
+        .unwrap_or_else(|_| panic!("Failed to remove test db file: {}", db_path.display()));
+
+    // The server is still running, so its WAL is left behind
+    for suffix in &["-wal", "-shm", ".lock"] {
+        let mut path = db_path.as_os_str().to_owned();
+        path.push(suffix);
+        let _ = std::fs::remove_file(path);
+    }
+}
 
 #[tokio::test]
 async fn same_room_users() {
@@ -13,6 +241,7 @@ async fn same_room_users() {
     tokio::task::spawn(async move {
         server::run(PORT, spawn_db_path).await;
     });
+    wait_for_server(PORT).await;
 
     let uri = format!("ws://localhost:{}/chat/room1", PORT);
 
@@ -22,6 +251,7 @@ async fn same_room_users() {
         Ok(((stream1, _), (stream2, _))) => (stream1, stream2),
         Err(_) => panic!("Unable to connect to WS uri: {}", uri),
     };
+    wait_for_join().await;
 
     let msg_text = String::from("Hello from the other side");
     let msg = Message::Text(msg_text.clone());
@@ -30,16 +260,12 @@ async fn same_room_users() {
         .await
         .expect("Unable to send message");
 
-    let received_msg = stream2.next().await.expect("No value found!").unwrap();
-    let received_msg_text = received_msg.into_text().unwrap();
-    let extracted_msg_text = received_msg_text.split(":").last().unwrap().trim();
+    let event = next_event(&mut stream2).await;
 
-    assert_eq!(msg_text, extracted_msg_text);
+    assert_eq!(event["type"], "message");
+    assert_eq!(event["text"], msg_text);
 
-    std::fs::remove_file(&db_path).expect(&format!(
-        "Failed to remove test db file: {}",
-        &db_path.to_str().unwrap()
-    ));
+    remove_db(&db_path);
 }
 
 #[tokio::test]
@@ -52,6 +278,7 @@ async fn different_room_users() {
     tokio::task::spawn(async move {
         server::run(PORT, spawn_db_path).await;
     });
+    wait_for_server(PORT).await;
 
     let uri1 = format!("ws://localhost:{}/chat/room1", PORT);
     let uri2 = format!("ws://localhost:{}/chat/room2", PORT);
@@ -62,6 +289,16 @@ async fn different_room_users() {
         Ok(((stream1, _), (stream2, _))) => (stream1, stream2),
         Err(_) => panic!("Unable to establish WS connection"),
     };
+    wait_for_join().await;
+
+    // Users are only told of their own joining, and of who is in their room
+    assert_eq!(next_raw_event(&mut stream1).await["room"], "room1");
+    assert_eq!(next_raw_event(&mut stream2).await["room"], "room2");
+    for stream in [&mut stream1, &mut stream2] {
+        let event = next_raw_event(stream).await;
+        assert_eq!(event["type"], "presence");
+        assert_eq!(event["users"].as_array().unwrap().len(), 1);
+    }
 
     let msg_text1 = String::from("Hello from the other side");
     let msg1 = Message::Text(msg_text1.clone());
@@ -80,8 +317,7556 @@ async fn different_room_users() {
     assert!(stream1.next().now_or_never().is_none());
     assert!(stream2.next().now_or_never().is_none());
 
-    std::fs::remove_file(&db_path).expect(&format!(
-        "Failed to remove test db file: {}",
-        &db_path.to_str().unwrap()
-    ));
+    remove_db(&db_path);
+}
+
+#[tokio::test]
+// Tests that users joining a room receive messages sent before they joined.
+async fn room_history_backfill() {
+    const PORT: u16 = 3032;
+
+    let db_path = PathBuf::from("./main_history.db");
+    let spawn_db_path = db_path.clone();
+    tokio::task::spawn(async move {
+        server::run(PORT, spawn_db_path).await;
+    });
+    wait_for_server(PORT).await;
+
+    let uri = format!("ws://localhost:{}/chat/room1", PORT);
+
+    let res = tokio::try_join!(connect_async(&uri), connect_async(&uri));
+
+    let (mut stream1, mut stream2) = match res {
+        Ok(((stream1, _), (stream2, _))) => (stream1, stream2),
+        Err(_) => panic!("Unable to connect to WS uri: {}", uri),
+    };
+    wait_for_join().await;
+
+    let msg_text = String::from("Hello from the past");
+    stream1
+        .send(Message::Text(msg_text.clone()))
+        .await
+        .expect("Unable to send message");
+
+    // Once the message has been broadcast, it has also been handed to the DB
+    next_event(&mut stream2).await;
+
+    let (mut stream3, _) = connect_async(&uri)
+        .await
+        .expect("Unable to connect to WS uri");
+
+    let event = next_event(&mut stream3).await;
+
+    assert_eq!(event["type"], "message");
+    assert_eq!(event["text"], msg_text);
+
+    remove_db(&db_path);
+}
+
+#[tokio::test]
+// Tests that users joining a room while messages are sent to it receive each of
+// them once and in order, whether as history or live.
+async fn join_while_sending() {
+    const PORT: u16 = 3118;
+    const MESSAGES: i64 = 100;
+
+    let db_path = PathBuf::from("./main_join_while_sending.db");
+    let config = Config {
+        history_limit: MESSAGES as usize,
+        flood_max_messages: 0,
+        ..Config::new(PORT, db_path.clone())
+    };
+    tokio::task::spawn(async move {
+        server::run_with_config(config).await;
+    });
+    wait_for_server(PORT).await;
+
+    let uri = format!("ws://localhost:{}/chat/room1", PORT);
+    let (mut sender, _) = connect_async(&uri)
+        .await
+        .expect("Unable to connect to WS uri");
+    wait_for_join().await;
+
+    let sending = tokio::task::spawn(async move {
+        for i in 0..MESSAGES {
+            sender
+                .send(Message::Text(format!("Message {}", i)))
+                .await
+                .expect("Unable to send message");
+            tokio::time::sleep(Duration::from_millis(2)).await;
+        }
+        sender
+    });
+    let mut receivers = Vec::new();
+    for _ in 0..5 {
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        let (receiver, _) = connect_async(&uri)
+            .await
+            .expect("Unable to connect to WS uri");
+        receivers.push(receiver);
+    }
+    let _sender = sending.await.unwrap();
+
+    for mut receiver in receivers {
+        let mut seqs = Vec::new();
+        while seqs.last() != Some(&MESSAGES) {
+            let event = next_event(&mut receiver).await;
+            assert_eq!(event["type"], "message");
+            seqs.push(event["seq"].as_i64().unwrap());
+        }
+        assert_eq!(seqs, (1..=MESSAGES).collect::<Vec<_>>());
+    }
+
+    remove_db(&db_path);
+}
+
+#[tokio::test]
+// Tests that accepted messages are acknowledged with the ID they are broadcast with.
+async fn message_acknowledgment() {
+    const PORT: u16 = 3033;
+
+    let db_path = PathBuf::from("./main_ack.db");
+    let spawn_db_path = db_path.clone();
+    tokio::task::spawn(async move {
+        server::run(PORT, spawn_db_path).await;
+    });
+    wait_for_server(PORT).await;
+
+    let uri = format!("ws://localhost:{}/chat/room1", PORT);
+
+    let res = tokio::try_join!(connect_async(&uri), connect_async(&uri));
+
+    let (mut stream1, mut stream2) = match res {
+        Ok(((stream1, _), (stream2, _))) => (stream1, stream2),
+        Err(_) => panic!("Unable to connect to WS uri: {}", uri),
+    };
+    wait_for_join().await;
+
+    let mut ids = Vec::new();
+    for client_id in &["first", "second"] {
+        let frame = json!({ "type": "message", "text": "Hello", "client_id": client_id });
+        stream1
+            .send(Message::Text(frame.to_string()))
+            .await
+            .expect("Unable to send message");
+
+        let ack = next_event(&mut stream1).await;
+        assert_eq!(ack["type"], "ack");
+        assert_eq!(ack["client_id"], *client_id);
+
+        let event = next_event(&mut stream2).await;
+        assert_eq!(event["type"], "message");
+        assert_eq!(event["id"], ack["id"]);
+
+        ids.push(ack["id"].as_i64().unwrap());
+    }
+
+    // IDs are increasing
+    assert!(ids[0] < ids[1]);
+
+    remove_db(&db_path);
+}
+
+#[tokio::test]
+// Tests that every user in a room observes messages in the same order.
+async fn room_message_ordering() {
+    const PORT: u16 = 3034;
+    const MESSAGES_PER_SENDER: usize = 20;
+
+    let db_path = PathBuf::from("./main_ordering.db");
+    let spawn_db_path = db_path.clone();
+    tokio::task::spawn(async move {
+        server::run(PORT, spawn_db_path).await;
+    });
+    wait_for_server(PORT).await;
+
+    let uri = format!("ws://localhost:{}/chat/room1", PORT);
+
+    let res = tokio::try_join!(
+        connect_async(&uri),
+        connect_async(&uri),
+        connect_async(&uri),
+        connect_async(&uri)
+    );
+
+    let (mut sender1, mut sender2, mut receiver1, mut receiver2) = match res {
+        Ok(((s1, _), (s2, _), (r1, _), (r2, _))) => (s1, s2, r1, r2),
+        Err(_) => panic!("Unable to connect to WS uri: {}", uri),
+    };
+    wait_for_join().await;
+
+    let send1 = async {
+        for i in 0..MESSAGES_PER_SENDER {
+            let msg = Message::Text(format!("sender1: {}", i));
+            sender1.send(msg).await.expect("Unable to send message");
+        }
+    };
+    let send2 = async {
+        for i in 0..MESSAGES_PER_SENDER {
+            let msg = Message::Text(format!("sender2: {}", i));
+            sender2.send(msg).await.expect("Unable to send message");
+        }
+    };
+    tokio::join!(send1, send2);
+
+    let mut received1 = Vec::new();
+    let mut received2 = Vec::new();
+    for _ in 0..MESSAGES_PER_SENDER * 2 {
+        let event1 = next_event(&mut receiver1).await;
+        let event2 = next_event(&mut receiver2).await;
+        received1.push((event1["seq"].as_i64().unwrap(), event1["id"].clone()));
+        received2.push((event2["seq"].as_i64().unwrap(), event2["id"].clone()));
+    }
+
+    assert_eq!(received1, received2);
+    assert!(received1.windows(2).all(|w| w[0].0 < w[1].0));
+
+    remove_db(&db_path);
+}
+
+#[tokio::test]
+// Tests that only the author of a message can edit it, and that edits reach the room.
+async fn message_editing() {
+    const PORT: u16 = 3035;
+
+    let db_path = PathBuf::from("./main_editing.db");
+    let spawn_db_path = db_path.clone();
+    tokio::task::spawn(async move {
+        server::run(PORT, spawn_db_path).await;
+    });
+    wait_for_server(PORT).await;
+
+    let uri = format!("ws://localhost:{}/chat/room1", PORT);
+
+    let res = tokio::try_join!(connect_async(&uri), connect_async(&uri));
+
+    let (mut stream1, mut stream2) = match res {
+        Ok(((stream1, _), (stream2, _))) => (stream1, stream2),
+        Err(_) => panic!("Unable to connect to WS uri: {}", uri),
+    };
+    wait_for_join().await;
+
+    stream1
+        .send(Message::Text(String::from("Helo")))
+        .await
+        .expect("Unable to send message");
+    let id = next_event(&mut stream1).await["id"].clone();
+    next_event(&mut stream2).await;
+
+    // Other users can not edit the message
+    let edit = json!({ "type": "edit", "id": id, "text": "Hijacked" });
+    stream2
+        .send(Message::Text(edit.to_string()))
+        .await
+        .expect("Unable to send message");
+    assert_eq!(next_event(&mut stream2).await["type"], "error");
+
+    let edit = json!({ "type": "edit", "id": id, "text": "Hello" });
+    stream1
+        .send(Message::Text(edit.to_string()))
+        .await
+        .expect("Unable to send message");
+
+    for stream in &mut [&mut stream1, &mut stream2] {
+        let event = next_event(stream).await;
+        assert_eq!(event["type"], "edit");
+        assert_eq!(event["id"], id);
+        assert_eq!(event["text"], "Hello");
+    }
+
+    // Edits are persisted
+    let (mut stream3, _) = connect_async(&uri)
+        .await
+        .expect("Unable to connect to WS uri");
+    let event = next_event(&mut stream3).await;
+    assert_eq!(event["id"], id);
+    assert_eq!(event["text"], "Hello");
+    assert!(event["edited_at"].is_string());
+
+    remove_db(&db_path);
+}
+
+#[tokio::test]
+// Tests that messages can be deleted by their author or a moderator only.
+async fn message_deletion() {
+    const PORT: u16 = 3036;
+
+    let db_path = PathBuf::from("./main_deletion.db");
+    let config = Config {
+        moderator_key: Some(String::from("secret")),
+        ..Config::new(PORT, db_path.clone())
+    };
+    tokio::task::spawn(async move {
+        server::run_with_config(config).await;
+    });
+    wait_for_server(PORT).await;
+
+    let uri = format!("ws://localhost:{}/chat/room1", PORT);
+    let moderator_uri = format!("ws://localhost:{}/chat/room1?key=secret", PORT);
+
+    let res = tokio::try_join!(
+        connect_async(&uri),
+        connect_async(&uri),
+        connect_async(&moderator_uri)
+    );
+
+    let (mut stream1, mut stream2, mut moderator) = match res {
+        Ok(((stream1, _), (stream2, _), (moderator, _))) => (stream1, stream2, moderator),
+        Err(_) => panic!("Unable to connect to WS uri: {}", uri),
+    };
+    wait_for_join().await;
+
+    let mut ids = Vec::new();
+    for _ in 0..2 {
+        stream1
+            .send(Message::Text(String::from("Delete me")))
+            .await
+            .expect("Unable to send message");
+        ids.push(next_event(&mut stream1).await["id"].clone());
+        next_event(&mut stream2).await;
+        next_event(&mut moderator).await;
+    }
+
+    // Other users can not delete the message
+    send_frame(&mut stream2, json!({ "type": "delete", "id": ids[0] })).await;
+    assert_eq!(next_event(&mut stream2).await["type"], "error");
+
+    // Authors and moderators can
+    send_frame(&mut stream1, json!({ "type": "delete", "id": ids[0] })).await;
+    for stream in &mut [&mut stream1, &mut stream2, &mut moderator] {
+        let event = next_event(stream).await;
+        assert_eq!(event["type"], "delete");
+        assert_eq!(event["id"], ids[0]);
+    }
+
+    send_frame(&mut moderator, json!({ "type": "delete", "id": ids[1] })).await;
+    for stream in &mut [&mut stream1, &mut stream2, &mut moderator] {
+        let event = next_event(stream).await;
+        assert_eq!(event["type"], "delete");
+        assert_eq!(event["id"], ids[1]);
+    }
+
+    // Deleted messages are replayed as tombstones
+    let (mut stream3, _) = connect_async(&uri)
+        .await
+        .expect("Unable to connect to WS uri");
+    for id in &ids {
+        let event = next_event(&mut stream3).await;
+        assert_eq!(event["id"], *id);
+        assert_eq!(event["text"], "");
+        assert!(event["deleted_at"].is_string());
+    }
+
+    remove_db(&db_path);
+}
+
+#[tokio::test]
+// Tests that moderators can pin messages, and that pins can be listed by users
+// who may join the room.
+async fn room_pins() {
+    const PORT: u16 = 3037;
+
+    let db_path = PathBuf::from("./main_pins.db");
+    let config = Config {
+        moderator_key: Some(String::from("secret")),
+        ..Config::new(PORT, db_path.clone())
+    };
+    tokio::task::spawn(async move {
+        server::run_with_config(config).await;
+    });
+    wait_for_server(PORT).await;
+
+    let mut tokens = Vec::new();
+    for username in &["alice", "bob"] {
+        let credentials = json!({ "username": username, "password": "correct horse" });
+        http_request(
+            PORT,
+            "POST",
+            "/users/register",
+            &[],
+            Some(credentials.clone()),
+        )
+        .await;
+        let (_, body) = http_request(PORT, "POST", "/users/login", &[], Some(credentials)).await;
+        tokens.push(format!("Bearer {}", body["token"].as_str().unwrap()));
+    }
+    let (alice_jwt, bob_jwt) = (&tokens[0], &tokens[1]);
+
+    let uri = format!("ws://localhost:{}/chat/room1", PORT);
+    let moderator_uri = format!("ws://localhost:{}/chat/room1?key=secret", PORT);
+
+    let res = tokio::try_join!(connect_async(&uri), connect_async(&moderator_uri));
+
+    let (mut stream1, mut moderator) = match res {
+        Ok(((stream1, _), (moderator, _))) => (stream1, moderator),
+        Err(_) => panic!("Unable to connect to WS uri: {}", uri),
+    };
+    wait_for_join().await;
+
+    stream1
+        .send(Message::Text(String::from("Pin me")))
+        .await
+        .expect("Unable to send message");
+    let id = next_event(&mut stream1).await["id"].clone();
+    next_event(&mut moderator).await;
+
+    // Only moderators can pin messages
+    send_frame(&mut stream1, json!({ "type": "pin", "id": id })).await;
+    assert_eq!(next_event(&mut stream1).await["type"], "error");
+
+    send_frame(&mut moderator, json!({ "type": "pin", "id": id })).await;
+    for stream in &mut [&mut stream1, &mut moderator] {
+        let event = next_event(stream).await;
+        assert_eq!(event["type"], "pin");
+        assert_eq!(event["id"], id);
+    }
+
+    let (status, _) = http_request(PORT, "GET", "/rooms/room1/pins", &[], None).await;
+    assert_eq!(status, 401);
+    let (status, pins) = http_request(
+        PORT,
+        "GET",
+        "/rooms/room1/pins",
+        &[("Authorization", alice_jwt)],
+        None,
+    )
+    .await;
+    assert_eq!(status, 200);
+    assert_eq!(pins[0]["id"], id);
+    assert_eq!(pins[0]["text"], "Pin me");
+
+    send_frame(&mut moderator, json!({ "type": "unpin", "id": id })).await;
+    for stream in &mut [&mut stream1, &mut moderator] {
+        assert_eq!(next_event(stream).await["type"], "unpin");
+    }
+
+    let (_, pins) = http_request(
+        PORT,
+        "GET",
+        "/rooms/room1/pins",
+        &[("Authorization", alice_jwt)],
+        None,
+    )
+    .await;
+    assert_eq!(pins, json!([]));
+
+    // Pins of private rooms are kept from users not let in
+    let (status, _) = http_request(
+        PORT,
+        "POST",
+        "/rooms",
+        &[("Authorization", alice_jwt)],
+        Some(json!({ "name": "vault", "visibility": "private" })),
+    )
+    .await;
+    assert_eq!(status, 201);
+    let (status, _) = http_request(
+        PORT,
+        "GET",
+        "/rooms/vault/pins",
+        &[("Authorization", bob_jwt)],
+        None,
+    )
+    .await;
+    assert_eq!(status, 403);
+    let (status, _) = http_request(
+        PORT,
+        "GET",
+        "/rooms/vault/pins",
+        &[("Authorization", alice_jwt)],
+        None,
+    )
+    .await;
+    assert_eq!(status, 200);
+
+    remove_db(&db_path);
+}
+
+#[tokio::test]
+// Tests that registered users can log in, and chat under their own user ID.
+async fn user_registration_and_login() {
+    const PORT: u16 = 3038;
+
+    let db_path = PathBuf::from("./main_users.db");
+    let spawn_db_path = db_path.clone();
+    tokio::task::spawn(async move {
+        server::run(PORT, spawn_db_path).await;
+    });
+    wait_for_server(PORT).await;
+
+    let credentials = json!({ "username": "alice", "password": "correct horse" });
+
+    let (status, body) = http_request(
+        PORT,
+        "POST",
+        "/users/register",
+        &[],
+        Some(credentials.clone()),
+    )
+    .await;
+    assert_eq!(status, 201);
+    let user_id = body["user_id"].clone();
+
+    // Usernames are unique
+    let (status, _) = http_request(
+        PORT,
+        "POST",
+        "/users/register",
+        &[],
+        Some(credentials.clone()),
+    )
+    .await;
+    assert_eq!(status, 409);
+
+    let wrong_credentials = json!({ "username": "alice", "password": "battery staple" });
+    let (status, _) =
+        http_request(PORT, "POST", "/users/login", &[], Some(wrong_credentials)).await;
+    assert_eq!(status, 401);
+
+    let (status, body) = http_request(PORT, "POST", "/users/login", &[], Some(credentials)).await;
+    assert_eq!(status, 200);
+    assert_eq!(body["user_id"], user_id);
+    let token = body["token"].as_str().unwrap();
+
+    // Invalid tokens are rejected before upgrading
+    let uri = format!("ws://localhost:{}/chat/room1?token=invalid", PORT);
+    assert!(connect_async(&uri).await.is_err());
+
+    let uri = format!("ws://localhost:{}/chat/room1?token={}", PORT, token);
+    let guest_uri = format!("ws://localhost:{}/chat/room1", PORT);
+    let res = tokio::try_join!(connect_async(&uri), connect_async(&guest_uri));
+
+    let (mut stream1, mut guest) = match res {
+        Ok(((stream1, _), (guest, _))) => (stream1, guest),
+        Err(_) => panic!("Unable to connect to WS uri: {}", uri),
+    };
+    wait_for_join().await;
+
+    stream1
+        .send(Message::Text(String::from("Hello as alice")))
+        .await
+        .expect("Unable to send message");
+    next_event(&mut stream1).await;
+
+    let event = next_event(&mut guest).await;
+    assert_eq!(event["user_id"], user_id);
+
+    // Guests are given IDs distinct from registered users
+    guest
+        .send(Message::Text(String::from("Hello as guest")))
+        .await
+        .expect("Unable to send message");
+    next_event(&mut guest).await;
+
+    let event = next_event(&mut stream1).await;
+    assert_ne!(event["user_id"], user_id);
+
+    // Tokens may also be given as a bearer token
+    let mut request = guest_uri.clone().into_client_request().unwrap();
+    request.headers_mut().insert(
+        "Authorization",
+        format!("Bearer {}", token).parse().unwrap(),
+    );
+    let (mut stream2, _) = connect_async(request)
+        .await
+        .expect("Unable to connect with bearer token");
+    wait_for_join().await;
+
+    stream2
+        .send(Message::Text(String::from("Hello again as alice")))
+        .await
+        .expect("Unable to send message");
+    next_event(&mut stream2).await;
+
+    let event = next_event(&mut guest).await;
+    assert_eq!(event["user_id"], user_id);
+
+    remove_db(&db_path);
+}
+
+#[tokio::test]
+async fn session_cookies() {
+    const PORT: u16 = 3039;
+
+    let db_path = PathBuf::from("./main_sessions.db");
+    let spawn_db_path = db_path.clone();
+    tokio::task::spawn(async move {
+        server::run(PORT, spawn_db_path).await;
+    });
+    wait_for_server(PORT).await;
+
+    let credentials = json!({ "username": "alice", "password": "correct horse" });
+    let (status, body) = http_request(
+        PORT,
+        "POST",
+        "/users/register",
+        &[],
+        Some(credentials.clone()),
+    )
+    .await;
+    assert_eq!(status, 201);
+    let user_id = body["user_id"].clone();
+
+    let (status, headers, _) =
+        http_request_with_headers(PORT, "POST", "/users/login", &[], Some(credentials)).await;
+    assert_eq!(status, 200);
+    let cookie = session_cookie(&headers).expect("Login did not set a session cookie");
+
+    let uri = format!("ws://localhost:{}/chat/room1", PORT);
+    let cookie_request = || {
+        let mut request = uri.clone().into_client_request().unwrap();
+        request
+            .headers_mut()
+            .insert("Cookie", cookie.parse().unwrap());
+        request
+    };
+    let (mut stream1, response) = connect_async(cookie_request())
+        .await
+        .expect("Unable to connect with session cookie");
+
+    // Using the session renews it
+    let renewed = response
+        .headers()
+        .get("set-cookie")
+        .and_then(|value| value.to_str().ok())
+        .expect("Session was not renewed");
+    assert!(renewed.starts_with(&cookie));
+
+    let (mut guest, _) = connect_async(&uri)
+        .await
+        .expect("Unable to connect as guest");
+    wait_for_join().await;
+
+    send_frame(
+        &mut stream1,
+        json!({ "type": "message", "text": "Hello as alice" }),
+    )
+    .await;
+    next_event(&mut stream1).await;
+
+    let event = next_event(&mut guest).await;
+    assert_eq!(event["user_id"], user_id);
+
+    // Logging out ends the session, and clears the cookie
+    let (status, headers, _) =
+        http_request_with_headers(PORT, "POST", "/users/logout", &[("Cookie", &cookie)], None)
+            .await;
+    assert_eq!(status, 204);
+    assert_eq!(session_cookie(&headers).as_deref(), Some("session="));
+
+    // Connections with an ended session are guests
+    let (mut stream2, _) = connect_async(cookie_request())
+        .await
+        .expect("Unable to connect with ended session");
+    wait_for_join().await;
+
+    send_frame(
+        &mut stream2,
+        json!({ "type": "message", "text": "Hello as who?" }),
+    )
+    .await;
+    next_event(&mut stream2).await;
+
+    let event = next_event(&mut guest).await;
+    assert_ne!(event["user_id"], user_id);
+
+    remove_db(&db_path);
+}
+
+#[tokio::test]
+async fn oauth_login() {
+    const PORT: u16 = 3040;
+
+    let db_path = PathBuf::from("./main_oauth.db");
+    let config = Config {
+        github_client_id: Some(String::from("client")),
+        github_client_secret: Some(String::from("secret")),
+        ..Config::new(PORT, db_path.clone())
+    };
+    tokio::task::spawn(async move {
+        server::run_with_config(config).await;
+    });
+    wait_for_server(PORT).await;
+
+    // Logging in redirects to the provider, remembering the state of the login
+    let (status, headers, _) =
+        http_request_with_headers(PORT, "GET", "/auth/github/login", &[], None).await;
+    assert_eq!(status, 302);
+
+    let header = |name: &str| {
+        headers
+            .iter()
+            .find(|(header, _)| header == name)
+            .map(|(_, value)| value.clone())
+            .unwrap_or_else(|| panic!("Missing {} header", name))
+    };
+    let location = header("location");
+    assert!(location.starts_with("https://github.com/login/oauth/authorize?"));
+    assert!(location.contains("client_id=client"));
+
+    let state_cookie = header("set-cookie");
+    let state_cookie = state_cookie.split(';').next().unwrap();
+    let oauth_state = state_cookie.strip_prefix("oauth_state=").unwrap();
+    assert!(location.contains(&format!("state={}", oauth_state)));
+
+    // Callbacks are only accepted from the browser that started the login
+    let path = format!("/auth/github/callback?code=abc&state={}", oauth_state);
+    let (status, _) = http_request(PORT, "GET", &path, &[], None).await;
+    assert_eq!(status, 400);
+
+    let (status, _) = http_request(
+        PORT,
+        "GET",
+        "/auth/github/callback?code=abc&state=forged",
+        &[("Cookie", state_cookie)],
+        None,
+    )
+    .await;
+    assert_eq!(status, 400);
+
+    // Only configured providers can be logged in with
+    let (status, _) = http_request(PORT, "GET", "/auth/google/login", &[], None).await;
+    assert_eq!(status, 404);
+
+    remove_db(&db_path);
+}
+
+#[tokio::test]
+async fn nicknames() {
+    const PORT: u16 = 3041;
+
+    let db_path = PathBuf::from("./main_nicknames.db");
+    let spawn_db_path = db_path.clone();
+    tokio::task::spawn(async move {
+        server::run(PORT, spawn_db_path).await;
+    });
+    wait_for_server(PORT).await;
+
+    // Blank nicknames are rejected before upgrading
+    let uri = format!("ws://localhost:{}/chat/room1?nick=%20", PORT);
+    assert!(connect_async(&uri).await.is_err());
+
+    let uri = format!("ws://localhost:{}/chat/room1?nick=alice", PORT);
+    let other_uri = format!("ws://localhost:{}/chat/room1", PORT);
+    let res = tokio::try_join!(connect_async(&uri), connect_async(&other_uri));
+
+    let (mut stream1, mut stream2) = match res {
+        Ok(((stream1, _), (stream2, _))) => (stream1, stream2),
+        Err(_) => panic!("Unable to connect to WS uri: {}", uri),
+    };
+    wait_for_join().await;
+
+    send_frame(&mut stream1, json!({ "type": "message", "text": "Hi" })).await;
+    next_event(&mut stream1).await;
+
+    let event = next_event(&mut stream2).await;
+    assert_eq!(event["nick"], "alice");
+
+    // Guests without a nickname are given a temporary one
+    send_frame(&mut stream2, json!({ "type": "message", "text": "Hey" })).await;
+    next_event(&mut stream2).await;
+
+    let event = next_event(&mut stream1).await;
+    assert!(event["nick"].as_str().unwrap().starts_with("guest-"));
+
+    // Nicknames are unique
+    let taken_uri = format!("ws://localhost:{}/chat/room2?nick=Alice", PORT);
+    assert!(connect_async(&taken_uri).await.is_err());
+    send_frame(&mut stream2, json!({ "type": "set_nick", "nick": "alice" })).await;
+    assert_eq!(next_event(&mut stream2).await["type"], "error");
+
+    // Renames are broadcast to the rooms of the user
+    send_frame(&mut stream1, json!({ "type": "set_nick", "nick": " bob " })).await;
+    for stream in [&mut stream1, &mut stream2] {
+        let event = next_event(stream).await;
+        assert_eq!(event["type"], "rename");
+        assert_eq!(event["old_nick"], "alice");
+        assert_eq!(event["nick"], "bob");
+    }
+
+    // Previous nicknames are freed up
+    send_frame(&mut stream2, json!({ "type": "set_nick", "nick": "alice" })).await;
+    for stream in [&mut stream1, &mut stream2] {
+        assert_eq!(next_event(stream).await["nick"], "alice");
+    }
+
+    send_frame(
+        &mut stream1,
+        json!({ "type": "message", "text": "Hi again" }),
+    )
+    .await;
+    next_event(&mut stream1).await;
+
+    let event = next_event(&mut stream2).await;
+    assert_eq!(event["nick"], "bob");
+
+    // History keeps the nickname messages were sent with
+    let (mut stream3, _) = connect_async(&other_uri).await.expect("Unable to connect");
+    assert_eq!(next_event(&mut stream3).await["nick"], "alice");
+
+    remove_db(&db_path);
+}
+
+#[tokio::test]
+async fn user_profiles() {
+    const PORT: u16 = 3042;
+
+    let db_path = PathBuf::from("./main_profiles.db");
+    let spawn_db_path = db_path.clone();
+    tokio::task::spawn(async move {
+        server::run(PORT, spawn_db_path).await;
+    });
+    wait_for_server(PORT).await;
+
+    let credentials = json!({ "username": "alice", "password": "correct horse" });
+    let (_, body) = http_request(
+        PORT,
+        "POST",
+        "/users/register",
+        &[],
+        Some(credentials.clone()),
+    )
+    .await;
+    let user_id = body["user_id"].as_u64().unwrap();
+    let (_, body) = http_request(PORT, "POST", "/users/login", &[], Some(credentials)).await;
+    let authorization = format!("Bearer {}", body["token"].as_str().unwrap());
+
+    let path = format!("/users/{}/profile", user_id);
+    let (status, body) = http_request(PORT, "GET", &path, &[], None).await;
+    assert_eq!(status, 200);
+    assert_eq!(body["user_id"], user_id);
+    assert_eq!(body["bio"], Value::Null);
+
+    let (status, _) = http_request(PORT, "GET", "/users/1000/profile", &[], None).await;
+    assert_eq!(status, 404);
+
+    let profile = json!({ "avatar_url": "https://example.com/alice.png", "bio": "Hi there" });
+
+    // Users may only update their own profile
+    let (status, _) = http_request(PORT, "PUT", &path, &[], Some(profile.clone())).await;
+    assert_eq!(status, 401);
+    let other_path = format!("/users/{}/profile", user_id + 1);
+    let (status, _) = http_request(
+        PORT,
+        "PUT",
+        &other_path,
+        &[("Authorization", &authorization)],
+        Some(profile.clone()),
+    )
+    .await;
+    assert_eq!(status, 403);
+
+    let (status, _) = http_request(
+        PORT,
+        "PUT",
+        &path,
+        &[("Authorization", &authorization)],
+        Some(json!({ "avatar_url": "not a url" })),
+    )
+    .await;
+    assert_eq!(status, 400);
+
+    let (status, body) = http_request(
+        PORT,
+        "PUT",
+        &path,
+        &[("Authorization", &authorization)],
+        Some(profile),
+    )
+    .await;
+    assert_eq!(status, 200);
+    assert_eq!(body["bio"], "Hi there");
+
+    let (_, body) = http_request(PORT, "GET", &path, &[], None).await;
+    assert_eq!(body["avatar_url"], "https://example.com/alice.png");
+
+    // Users already in a room are sent the profile of those joining it
+    let guest_uri = format!("ws://localhost:{}/chat/room1", PORT);
+    let (mut guest, _) = connect_async(&guest_uri).await.expect("Unable to connect");
+    wait_for_join().await;
+    assert_eq!(next_raw_event(&mut guest).await["type"], "join");
+    assert_eq!(next_raw_event(&mut guest).await["type"], "presence");
+
+    let mut request = guest_uri.into_client_request().unwrap();
+    request
+        .headers_mut()
+        .insert("Authorization", authorization.parse().unwrap());
+    let (_stream, _) = connect_async(request).await.expect("Unable to connect");
+
+    let event = next_raw_event(&mut guest).await;
+    assert_eq!(event["type"], "join");
+    assert_eq!(event["user_id"], user_id);
+    assert_eq!(event["bio"], "Hi there");
+
+    remove_db(&db_path);
+}
+
+#[tokio::test]
+async fn guest_modes() {
+    const PORT: u16 = 3043;
+
+    let db_path = PathBuf::from("./main_guests.db");
+    let config = Config {
+        guest_mode: GuestMode::RateLimited,
+        guest_rate_limit: 1,
+        room_guest_modes: vec![
+            "lobby=read-only".parse().unwrap(),
+            "members=disabled".parse().unwrap(),
+        ],
+        ..Config::new(PORT, db_path.clone())
+    };
+    tokio::task::spawn(async move {
+        server::run_with_config(config).await;
+    });
+    wait_for_server(PORT).await;
+
+    let uri = format!("ws://localhost:{}/chat/members", PORT);
+    assert!(connect_async(&uri).await.is_err());
+
+    // Guests can only read read-only rooms
+    let uri = format!("ws://localhost:{}/chat/lobby", PORT);
+    let (mut stream, _) = connect_async(&uri).await.expect("Unable to connect");
+    wait_for_join().await;
+
+    send_frame(&mut stream, json!({ "type": "message", "text": "Hi" })).await;
+    assert_eq!(next_event(&mut stream).await["type"], "error");
+
+    // Other rooms follow the default guest mode
+    let uri = format!("ws://localhost:{}/chat/room1", PORT);
+    let (mut stream, _) = connect_async(&uri).await.expect("Unable to connect");
+    wait_for_join().await;
+
+    send_frame(&mut stream, json!({ "type": "message", "text": "Hi" })).await;
+    assert_eq!(next_event(&mut stream).await["type"], "ack");
+    send_frame(
+        &mut stream,
+        json!({ "type": "message", "text": "Hi again" }),
+    )
+    .await;
+    assert_eq!(next_event(&mut stream).await["type"], "error");
+
+    // Guest nicknames can not be taken by others
+    send_frame(
+        &mut stream,
+        json!({ "type": "set_nick", "nick": "guest-0000" }),
+    )
+    .await;
+    assert_eq!(next_event(&mut stream).await["type"], "error");
+
+    remove_db(&db_path);
+}
+
+#[tokio::test]
+async fn two_factor_authentication() {
+    const PORT: u16 = 3044;
+
+    let db_path = PathBuf::from("./main_totp.db");
+    let config = Config {
+        admin_users: vec![String::from("root")],
+        ..Config::new(PORT, db_path.clone())
+    };
+    tokio::task::spawn(async move {
+        server::run_with_config(config).await;
+    });
+    wait_for_server(PORT).await;
+
+    let now = || {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    };
+
+    for username in &["alice", "root"] {
+        let credentials = json!({ "username": username, "password": "correct horse" });
+        let (status, _) =
+            http_request(PORT, "POST", "/users/register", &[], Some(credentials)).await;
+        assert_eq!(status, 201);
+    }
+
+    // Admins can not log in without a second factor, but can enroll one
+    let credentials = json!({ "username": "root", "password": "correct horse" });
+    let (status, _) =
+        http_request(PORT, "POST", "/users/login", &[], Some(credentials.clone())).await;
+    assert_eq!(status, 403);
+    let (status, _) =
+        http_request(PORT, "POST", "/users/totp/enroll", &[], Some(credentials)).await;
+    assert_eq!(status, 200);
+
+    let credentials = json!({ "username": "alice", "password": "correct horse" });
+    let wrong_credentials = json!({ "username": "alice", "password": "battery staple" });
+    let (status, _) = http_request(
+        PORT,
+        "POST",
+        "/users/totp/enroll",
+        &[],
+        Some(wrong_credentials),
+    )
+    .await;
+    assert_eq!(status, 401);
+
+    let (status, body) = http_request(
+        PORT,
+        "POST",
+        "/users/totp/enroll",
+        &[],
+        Some(credentials.clone()),
+    )
+    .await;
+    assert_eq!(status, 200);
+    let secret = String::from(body["secret"].as_str().unwrap());
+    assert!(body["otpauth_uri"]
+        .as_str()
+        .unwrap()
+        .starts_with("otpauth://totp/"));
+
+    // Enrollment only takes effect once confirmed with a valid code
+    let (status, _) =
+        http_request(PORT, "POST", "/users/login", &[], Some(credentials.clone())).await;
+    assert_eq!(status, 200);
+
+    let with_code =
+        |code: &str| json!({ "username": "alice", "password": "correct horse", "code": code });
+    let (status, _) = http_request(
+        PORT,
+        "POST",
+        "/users/totp/confirm",
+        &[],
+        Some(with_code("000000")),
+    )
+    .await;
+    assert_eq!(status, 401);
+
+    let code = totp::code_at(&secret, now()).unwrap();
+    let (status, _) = http_request(
+        PORT,
+        "POST",
+        "/users/totp/confirm",
+        &[],
+        Some(with_code(&code)),
+    )
+    .await;
+    assert_eq!(status, 200);
+
+    // A code is now required to log in, and codes can not be reused
+    let (status, _) = http_request(PORT, "POST", "/users/login", &[], Some(credentials)).await;
+    assert_eq!(status, 401);
+    let (status, _) = http_request(PORT, "POST", "/users/login", &[], Some(with_code(&code))).await;
+    assert_eq!(status, 401);
+
+    // Codes of the next time step are accepted, to allow for clock drift
+    let code = totp::code_at(&secret, now() + 30).unwrap();
+    let (status, body) =
+        http_request(PORT, "POST", "/users/login", &[], Some(with_code(&code))).await;
+    assert_eq!(status, 200);
+    assert!(body["token"].is_string());
+
+    remove_db(&db_path);
+}
+
+#[tokio::test]
+async fn password_reset() {
+    const PORT: u16 = 3045;
+
+    let db_path = PathBuf::from("./main_reset.db");
+    let spawn_db_path = db_path.clone();
+    tokio::task::spawn(async move {
+        server::run(PORT, spawn_db_path).await;
+    });
+    wait_for_server(PORT).await;
+
+    let registration =
+        json!({ "username": "alice", "password": "correct horse", "email": "alice" });
+    let (status, _) = http_request(PORT, "POST", "/users/register", &[], Some(registration)).await;
+    assert_eq!(status, 400);
+
+    let registration = json!({
+        "username": "alice",
+        "password": "correct horse",
+        "email": "alice@example.com",
+    });
+    let (status, _) = http_request(PORT, "POST", "/users/register", &[], Some(registration)).await;
+    assert_eq!(status, 201);
+
+    // Requests are accepted whether or not the user exists
+    for username in &["alice", "bob"] {
+        let (status, _) = http_request(
+            PORT,
+            "POST",
+            "/users/forgot",
+            &[],
+            Some(json!({ "username": username })),
+        )
+        .await;
+        assert_eq!(status, 202);
+    }
+
+    let (status, _) = http_request(
+        PORT,
+        "POST",
+        "/users/reset",
+        &[],
+        Some(json!({ "token": "bogus", "password": "battery staple" })),
+    )
+    .await;
+    assert_eq!(status, 400);
+
+    let (status, body) = http_request(
+        PORT,
+        "POST",
+        "/users/reset",
+        &[],
+        Some(json!({ "token": "bogus", "password": "short" })),
+    )
+    .await;
+    assert_eq!(status, 400);
+    assert!(body["error"].as_str().unwrap().contains("Password"));
+
+    // The password is left untouched
+    let credentials = json!({ "username": "alice", "password": "correct horse" });
+    let (status, _) = http_request(PORT, "POST", "/users/login", &[], Some(credentials)).await;
+    assert_eq!(status, 200);
+
+    remove_db(&db_path);
+}
+
+#[tokio::test]
+async fn account_deletion() {
+    const PORT: u16 = 3046;
+
+    let db_path = PathBuf::from("./main_account_deletion.db");
+    let spawn_db_path = db_path.clone();
+    tokio::task::spawn(async move {
+        server::run(PORT, spawn_db_path).await;
+    });
+    wait_for_server(PORT).await;
+
+    let mut tokens = Vec::new();
+    for username in &["alice", "bob"] {
+        let credentials = json!({ "username": username, "password": "correct horse" });
+        let (status, _) = http_request(
+            PORT,
+            "POST",
+            "/users/register",
+            &[],
+            Some(credentials.clone()),
+        )
+        .await;
+        assert_eq!(status, 201);
+        let (_, body) = http_request(PORT, "POST", "/users/login", &[], Some(credentials)).await;
+        tokens.push((
+            body["user_id"].as_u64().unwrap(),
+            String::from(body["token"].as_str().unwrap()),
+        ));
+    }
+    let (alice_id, alice_token) = &tokens[0];
+    let (_, bob_token) = &tokens[1];
+
+    let uri = format!("ws://localhost:{}/chat/room1?token={}", PORT, alice_token);
+    let (mut alice, _) = connect_async(&uri).await.expect("Unable to connect");
+    wait_for_join().await;
+    alice
+        .send(Message::Text(String::from("Hello as alice")))
+        .await
+        .expect("Unable to send message");
+    next_event(&mut alice).await;
+
+    let path = format!("/users/{}", alice_id);
+    let (status, _) = http_request(PORT, "DELETE", &path, &[], None).await;
+    assert_eq!(status, 401);
+    let bob_auth = format!("Bearer {}", bob_token);
+    let (status, _) =
+        http_request(PORT, "DELETE", &path, &[("Authorization", &bob_auth)], None).await;
+    assert_eq!(status, 403);
+
+    let alice_auth = format!("Bearer {}", alice_token);
+    let (status, _) = http_request(
+        PORT,
+        "DELETE",
+        &path,
+        &[("Authorization", &alice_auth)],
+        None,
+    )
+    .await;
+    assert_eq!(status, 204);
+
+    // Tokens of deleted users are no longer accepted
+    let (status, _) = http_request(
+        PORT,
+        "DELETE",
+        &path,
+        &[("Authorization", &alice_auth)],
+        None,
+    )
+    .await;
+    assert_eq!(status, 401);
+
+    // Active connections are closed, and the account can no longer be used
+    loop {
+        match alice.next().await {
+            Some(Ok(Message::Close(_))) | None => break,
+            Some(Ok(_)) => {}
+            Some(Err(e)) => panic!("Unexpected error: {}", e),
+        }
+    }
+    assert!(connect_async(&uri).await.is_err());
+    let credentials = json!({ "username": "alice", "password": "correct horse" });
+    let (status, _) = http_request(PORT, "POST", "/users/login", &[], Some(credentials)).await;
+    assert_eq!(status, 401);
+
+    // Messages are kept, but no longer attributed to the user
+    let uri = format!("ws://localhost:{}/chat/room1?token={}", PORT, bob_token);
+    let (mut bob, _) = connect_async(&uri).await.expect("Unable to connect");
+    let event = next_event(&mut bob).await;
+    assert_eq!(event["text"], "Hello as alice");
+    assert_eq!(event["user_id"], 0);
+    assert!(event.get("nick").is_none());
+
+    remove_db(&db_path);
+}
+
+#[tokio::test]
+async fn session_management() {
+    const PORT: u16 = 3047;
+
+    let db_path = PathBuf::from("./main_session_management.db");
+    let spawn_db_path = db_path.clone();
+    tokio::task::spawn(async move {
+        server::run(PORT, spawn_db_path).await;
+    });
+    wait_for_server(PORT).await;
+
+    let credentials = json!({ "username": "alice", "password": "correct horse" });
+    let (status, body) = http_request(
+        PORT,
+        "POST",
+        "/users/register",
+        &[],
+        Some(credentials.clone()),
+    )
+    .await;
+    assert_eq!(status, 201);
+    let path = format!("/users/{}/sessions", body["user_id"]);
+
+    let mut cookies = Vec::new();
+    for device in &["laptop", "phone"] {
+        let (status, headers, _) = http_request_with_headers(
+            PORT,
+            "POST",
+            "/users/login",
+            &[("User-Agent", device)],
+            Some(credentials.clone()),
+        )
+        .await;
+        assert_eq!(status, 200);
+        cookies.push(session_cookie(&headers).unwrap());
+    }
+    let (laptop, phone) = (&cookies[0], &cookies[1]);
+
+    let mut request = format!("ws://localhost:{}/chat/room1", PORT)
+        .into_client_request()
+        .unwrap();
+    request
+        .headers_mut()
+        .insert("Cookie", phone.parse().unwrap());
+    let (mut stream, _) = connect_async(request)
+        .await
+        .expect("Unable to connect with session cookie");
+    wait_for_join().await;
+
+    let (status, _) = http_request(
+        PORT,
+        "GET",
+        "/users/999/sessions",
+        &[("Cookie", laptop)],
+        None,
+    )
+    .await;
+    assert_eq!(status, 403);
+
+    let (status, sessions) = http_request(PORT, "GET", &path, &[("Cookie", laptop)], None).await;
+    assert_eq!(status, 200);
+    let sessions = sessions.as_array().unwrap();
+    assert_eq!(sessions.len(), 2);
+    assert_eq!(sessions[0]["user_agent"], "laptop");
+    assert_eq!(sessions[0]["connections"], 0);
+    assert_eq!(sessions[1]["user_agent"], "phone");
+    assert_eq!(sessions[1]["connections"], 1);
+
+    // Revoking a session closes the connections opened with it
+    let revoke_path = format!("/sessions/{}", sessions[1]["id"].as_str().unwrap());
+    let (status, _) = http_request(PORT, "DELETE", &revoke_path, &[], None).await;
+    assert_eq!(status, 401);
+    let (status, _) = http_request(PORT, "DELETE", &revoke_path, &[("Cookie", laptop)], None).await;
+    assert_eq!(status, 204);
+    let (status, _) = http_request(PORT, "DELETE", &revoke_path, &[("Cookie", laptop)], None).await;
+    assert_eq!(status, 404);
+
+    loop {
+        match stream.next().await {
+            Some(Ok(Message::Close(_))) | None => break,
+            Some(Ok(_)) => {}
+            Some(Err(e)) => panic!("Unexpected error: {}", e),
+        }
+    }
+
+    let (_, sessions) = http_request(PORT, "GET", &path, &[("Cookie", laptop)], None).await;
+    assert_eq!(sessions.as_array().unwrap().len(), 1);
+    let (status, _) = http_request(PORT, "GET", &path, &[("Cookie", phone)], None).await;
+    assert_eq!(status, 401);
+
+    remove_db(&db_path);
+}
+
+#[tokio::test]
+async fn login_throttling() {
+    const PORT: u16 = 3048;
+
+    let db_path = PathBuf::from("./main_throttling.db");
+    let config = Config {
+        login_max_failures: 2,
+        ..Config::new(PORT, db_path.clone())
+    };
+    tokio::task::spawn(async move {
+        server::run_with_config(config).await;
+    });
+    wait_for_server(PORT).await;
+
+    for username in &["alice", "bob"] {
+        let credentials = json!({ "username": username, "password": "correct horse" });
+        let (status, _) =
+            http_request(PORT, "POST", "/users/register", &[], Some(credentials)).await;
+        assert_eq!(status, 201);
+    }
+
+    let wrong_credentials = json!({ "username": "alice", "password": "battery staple" });
+    for _ in 0..3 {
+        let (status, _) = http_request(
+            PORT,
+            "POST",
+            "/users/login",
+            &[],
+            Some(wrong_credentials.clone()),
+        )
+        .await;
+        assert_eq!(status, 401);
+    }
+
+    // The account is locked out, even with the right password
+    let credentials = json!({ "username": "alice", "password": "correct horse" });
+    let (status, body) = http_request(PORT, "POST", "/users/login", &[], Some(credentials)).await;
+    assert_eq!(status, 429);
+    assert!(body["retry_after"].as_u64().unwrap() > 0);
+
+    // Other accounts are not
+    let credentials = json!({ "username": "bob", "password": "correct horse" });
+    let (status, _) = http_request(PORT, "POST", "/users/login", &[], Some(credentials)).await;
+    assert_eq!(status, 200);
+
+    remove_db(&db_path);
+}
+
+#[tokio::test]
+async fn api_tokens() {
+    const PORT: u16 = 3049;
+
+    let db_path = PathBuf::from("./main_api_tokens.db");
+    let spawn_db_path = db_path.clone();
+    tokio::task::spawn(async move {
+        server::run(PORT, spawn_db_path).await;
+    });
+    wait_for_server(PORT).await;
+
+    let credentials = json!({ "username": "alice", "password": "correct horse" });
+    let (status, _) = http_request(
+        PORT,
+        "POST",
+        "/users/register",
+        &[],
+        Some(credentials.clone()),
+    )
+    .await;
+    assert_eq!(status, 201);
+    let (_, body) = http_request(PORT, "POST", "/users/login", &[], Some(credentials)).await;
+    let user_id = body["user_id"].clone();
+    let jwt = format!("Bearer {}", body["token"].as_str().unwrap());
+    let path = format!("/users/{}/tokens", user_id);
+
+    let (status, _) = http_request(PORT, "POST", &path, &[], Some(json!({ "name": "bot" }))).await;
+    assert_eq!(status, 401);
+    let (status, body) = http_request(
+        PORT,
+        "POST",
+        &path,
+        &[("Authorization", &jwt)],
+        Some(json!({ "name": "bot", "scopes": ["admin"] })),
+    )
+    .await;
+    assert_eq!(status, 201);
+    assert_eq!(body["name"], "bot");
+    let token_id = body["id"].clone();
+    let token = String::from(body["token"].as_str().unwrap());
+
+    // API tokens are accepted by REST routes and the WS handshake alike
+    let bearer = format!("Bearer {}", token);
+    let (status, tokens) =
+        http_request(PORT, "GET", &path, &[("Authorization", &bearer)], None).await;
+    assert_eq!(status, 200);
+    let tokens = tokens.as_array().unwrap();
+    assert_eq!(tokens.len(), 1);
+    assert_eq!(tokens[0]["id"], token_id);
+    assert!(tokens[0].get("token").is_none());
+
+    let uri = format!("ws://localhost:{}/chat/room1?token={}", PORT, token);
+    let (mut stream, _) = connect_async(&uri)
+        .await
+        .expect("Unable to connect with API token");
+    let (mut guest, _) = connect_async(format!("ws://localhost:{}/chat/room1", PORT))
+        .await
+        .expect("Unable to connect as guest");
+    wait_for_join().await;
+    send_frame(
+        &mut stream,
+        json!({ "type": "message", "text": "Hello as a bot" }),
+    )
+    .await;
+    let event = next_event(&mut guest).await;
+    assert_eq!(event["user_id"], user_id);
+
+    let revoke_path = format!("{}/{}", path, token_id);
+    let (status, _) = http_request(
+        PORT,
+        "DELETE",
+        &revoke_path,
+        &[("Authorization", &jwt)],
+        None,
+    )
+    .await;
+    assert_eq!(status, 204);
+    let (status, _) = http_request(
+        PORT,
+        "DELETE",
+        &revoke_path,
+        &[("Authorization", &jwt)],
+        None,
+    )
+    .await;
+    assert_eq!(status, 404);
+
+    assert!(connect_async(&uri).await.is_err());
+    let (status, _) = http_request(PORT, "GET", &path, &[("Authorization", &bearer)], None).await;
+    assert_eq!(status, 401);
+
+    remove_db(&db_path);
+}
+
+#[tokio::test]
+async fn api_token_scopes() {
+    const PORT: u16 = 3050;
+
+    let db_path = PathBuf::from("./main_api_token_scopes.db");
+    let spawn_db_path = db_path.clone();
+    tokio::task::spawn(async move {
+        server::run(PORT, spawn_db_path).await;
+    });
+    wait_for_server(PORT).await;
+
+    let credentials = json!({ "username": "alice", "password": "correct horse" });
+    let (status, _) = http_request(
+        PORT,
+        "POST",
+        "/users/register",
+        &[],
+        Some(credentials.clone()),
+    )
+    .await;
+    assert_eq!(status, 201);
+    let (_, body) = http_request(PORT, "POST", "/users/login", &[], Some(credentials)).await;
+    let jwt = format!("Bearer {}", body["token"].as_str().unwrap());
+    let path = format!("/users/{}/tokens", body["user_id"]);
+
+    let create_token = |scopes: Value| {
+        let (path, jwt) = (path.clone(), jwt.clone());
+        async move {
+            http_request(
+                PORT,
+                "POST",
+                &path,
+                &[("Authorization", &jwt)],
+                Some(json!({ "name": "bot", "scopes": scopes })),
+            )
+            .await
+        }
+    };
+
+    // Tokens must be given known scopes
+    let (status, _) = create_token(json!([])).await;
+    assert_eq!(status, 400);
+    let (status, _) = create_token(json!(["everything"])).await;
+    assert_eq!(status, 400);
+
+    let (status, body) = create_token(json!(["write:room1", "read:room2"])).await;
+    assert_eq!(status, 201);
+    assert_eq!(body["scopes"], json!(["write:room1", "read:room2"]));
+    let token = String::from(body["token"].as_str().unwrap());
+
+    // Account routes require the admin scope
+    let bearer = format!("Bearer {}", token);
+    let (status, _) = http_request(PORT, "GET", &path, &[("Authorization", &bearer)], None).await;
+    assert_eq!(status, 403);
+
+    // Rooms outside the token's scopes can not be joined
+    let uri = |room: &str| format!("ws://localhost:{}/chat/{}?token={}", PORT, room, token);
+    assert!(connect_async(uri("room3")).await.is_err());
+
+    let (mut writer, _) = connect_async(uri("room1"))
+        .await
+        .expect("Unable to connect with API token");
+    let (mut reader, _) = connect_async(uri("room2"))
+        .await
+        .expect("Unable to connect with API token");
+    wait_for_join().await;
+
+    send_frame(
+        &mut writer,
+        json!({ "type": "message", "text": "Hello room1", "client_id": "1" }),
+    )
+    .await;
+    assert_eq!(next_event(&mut writer).await["type"], "ack");
+
+    // Frames sent where the token may only read are refused
+    send_frame(
+        &mut reader,
+        json!({ "type": "message", "text": "Hello room2", "client_id": "1" }),
+    )
+    .await;
+    let event = next_event(&mut reader).await;
+    assert_eq!(event["type"], "error");
+    assert!(event["reason"].as_str().unwrap().contains("write:room2"));
+
+    remove_db(&db_path);
+}
+
+#[tokio::test]
+async fn roles() {
+    const PORT: u16 = 3051;
+
+    let db_path = PathBuf::from("./main_roles.db");
+    let config = Config {
+        admin_users: vec![String::from("root")],
+        ..Config::new(PORT, db_path.clone())
+    };
+    tokio::task::spawn(async move {
+        server::run_with_config(config).await;
+    });
+    wait_for_server(PORT).await;
+
+    let mut user_ids = Vec::new();
+    for username in &["root", "alice", "bob"] {
+        let credentials = json!({ "username": username, "password": "correct horse" });
+        let (status, body) =
+            http_request(PORT, "POST", "/users/register", &[], Some(credentials)).await;
+        assert_eq!(status, 201);
+        user_ids.push(body["user_id"].as_u64().unwrap());
+    }
+    let (alice, bob) = (user_ids[1], user_ids[2]);
+
+    // Admins given on the command line are made admins once logged in with a
+    // second factor
+    let root_jwt = format!("Bearer {}", admin_login(PORT, "root").await);
+
+    let mut tokens = Vec::new();
+    for username in &["alice", "bob"] {
+        let credentials = json!({ "username": username, "password": "correct horse" });
+        let (_, body) = http_request(PORT, "POST", "/users/login", &[], Some(credentials)).await;
+        tokens.push(String::from(body["token"].as_str().unwrap()));
+    }
+    let alice_jwt = format!("Bearer {}", tokens[0]);
+
+    // Only admins can give roles
+    let moderator = json!({ "role": "moderator" });
+    let (status, _) = http_request(
+        PORT,
+        "PUT",
+        &format!("/rooms/room1/roles/{}", bob),
+        &[("Authorization", &alice_jwt)],
+        Some(moderator.clone()),
+    )
+    .await;
+    assert_eq!(status, 403);
+    let (status, body) = http_request(
+        PORT,
+        "PUT",
+        &format!("/rooms/room1/roles/{}", alice),
+        &[("Authorization", &root_jwt)],
+        Some(moderator.clone()),
+    )
+    .await;
+    assert_eq!(status, 200);
+    assert_eq!(body["role"], "moderator");
+    let (status, _) = http_request(
+        PORT,
+        "PUT",
+        &format!("/users/{}/role", bob),
+        &[("Authorization", &root_jwt)],
+        Some(json!({ "role": "owner" })),
+    )
+    .await;
+    assert_eq!(status, 400);
+
+    let (status, _) = http_request(PORT, "GET", "/rooms/room1/roles", &[], None).await;
+    assert_eq!(status, 401);
+    let (status, body) = http_request(
+        PORT,
+        "GET",
+        "/rooms/room1/roles",
+        &[("Authorization", &alice_jwt)],
+        None,
+    )
+    .await;
+    assert_eq!(status, 200);
+    assert_eq!(body, json!([{ "user_id": alice, "role": "moderator" }]));
+
+    // Moderators of a room may pin messages to it, and delete any of its messages
+    let uri = |token: &str| format!("ws://localhost:{}/chat/room1?token={}", PORT, token);
+    let (mut alice_stream, _) = connect_async(uri(&tokens[0]))
+        .await
+        .expect("Unable to connect as alice");
+    let (mut bob_stream, _) = connect_async(uri(&tokens[1]))
+        .await
+        .expect("Unable to connect as bob");
+    wait_for_join().await;
+
+    send_frame(
+        &mut bob_stream,
+        json!({ "type": "message", "text": "Hello" }),
+    )
+    .await;
+    let id = next_event(&mut bob_stream).await["id"].clone();
+    assert_eq!(next_event(&mut alice_stream).await["type"], "message");
+
+    send_frame(&mut bob_stream, json!({ "type": "pin", "id": id })).await;
+    assert_eq!(next_event(&mut bob_stream).await["type"], "error");
+
+    send_frame(&mut alice_stream, json!({ "type": "pin", "id": id })).await;
+    assert_eq!(next_event(&mut bob_stream).await["type"], "pin");
+    send_frame(&mut alice_stream, json!({ "type": "delete", "id": id })).await;
+    assert_eq!(next_event(&mut alice_stream).await["type"], "pin");
+    let event = next_event(&mut alice_stream).await;
+    assert_eq!(event["type"], "delete");
+    assert_eq!(event["deleted_by"], alice);
+
+    // Admins may delete the accounts of others
+    let (status, _) = http_request(
+        PORT,
+        "DELETE",
+        &format!("/users/{}", user_ids[0]),
+        &[("Authorization", &alice_jwt)],
+        None,
+    )
+    .await;
+    assert_eq!(status, 403);
+    let (status, _) = http_request(
+        PORT,
+        "DELETE",
+        &format!("/users/{}", bob),
+        &[("Authorization", &root_jwt)],
+        None,
+    )
+    .await;
+    assert_eq!(status, 204);
+
+    remove_db(&db_path);
+}
+
+#[tokio::test]
+async fn room_access_control() {
+    const PORT: u16 = 3052;
+
+    let db_path = PathBuf::from("./main_room_acl.db");
+    let config = Config {
+        admin_users: vec![String::from("root")],
+        ..Config::new(PORT, db_path.clone())
+    };
+    tokio::task::spawn(async move {
+        server::run_with_config(config).await;
+    });
+    wait_for_server(PORT).await;
+
+    let mut user_ids = Vec::new();
+    for username in &["root", "alice", "bob"] {
+        let credentials = json!({ "username": username, "password": "correct horse" });
+        let (_, body) = http_request(PORT, "POST", "/users/register", &[], Some(credentials)).await;
+        user_ids.push(body["user_id"].as_u64().unwrap());
+    }
+    let (root, bob) = (user_ids[0], user_ids[2]);
+    let root_jwt = format!("Bearer {}", admin_login(PORT, "root").await);
+
+    let mut tokens = Vec::new();
+    for username in &["alice", "bob"] {
+        let credentials = json!({ "username": username, "password": "correct horse" });
+        let (_, body) = http_request(PORT, "POST", "/users/login", &[], Some(credentials)).await;
+        tokens.push(String::from(body["token"].as_str().unwrap()));
+    }
+    let alice_jwt = format!("Bearer {}", tokens[0]);
+    let uri =
+        |room: &str, token: &str| format!("ws://localhost:{}/chat/{}?token={}", PORT, room, token);
+
+    // Only admins can manage who may join a room
+    let deny = json!({ "access": "deny" });
+    let (status, _) = http_request(
+        PORT,
+        "PUT",
+        &format!("/rooms/room1/acl/{}", bob),
+        &[("Authorization", &alice_jwt)],
+        Some(deny.clone()),
+    )
+    .await;
+    assert_eq!(status, 403);
+    let (status, _) = http_request(
+        PORT,
+        "PUT",
+        &format!("/rooms/room1/acl/{}", bob),
+        &[("Authorization", &root_jwt)],
+        Some(deny),
+    )
+    .await;
+    assert_eq!(status, 200);
+
+    let (status, body) = http_request(
+        PORT,
+        "GET",
+        "/rooms/room1/acl",
+        &[("Authorization", &root_jwt)],
+        None,
+    )
+    .await;
+    assert_eq!(status, 200);
+    assert_eq!(body, json!([{ "user_id": bob, "access": "deny" }]));
+
+    // Denied users are disconnected as soon as they join
+    let (mut stream, _) = connect_async(uri("room1", &tokens[1]))
+        .await
+        .expect("Unable to connect as bob");
+    match stream.next().await {
+        Some(Ok(Message::Close(Some(frame)))) => assert_eq!(u16::from(frame.code), 4003),
+        other => panic!("Expected connection to be closed, got {:?}", other),
+    }
+
+    // Allowing anyone keeps everyone else out, closing their open connections.
+    // A guest creates the room, so that alice does not own it
+    let (_guest, _) = connect_async(format!("ws://localhost:{}/chat/room2", PORT))
+        .await
+        .expect("Unable to connect as guest");
+    wait_for_join().await;
+    let (mut stream, _) = connect_async(uri("room2", &tokens[0]))
+        .await
+        .expect("Unable to connect as alice");
+    wait_for_join().await;
+    assert_eq!(next_raw_event(&mut stream).await["type"], "join");
+    assert_eq!(next_raw_event(&mut stream).await["type"], "presence");
+    let (status, _) = http_request(
+        PORT,
+        "PUT",
+        &format!("/rooms/room2/acl/{}", root),
+        &[("Authorization", &root_jwt)],
+        Some(json!({ "access": "allow" })),
+    )
+    .await;
+    assert_eq!(status, 200);
+    match stream.next().await {
+        Some(Ok(Message::Close(Some(frame)))) => assert_eq!(u16::from(frame.code), 4003),
+        other => panic!("Expected connection to be closed, got {:?}", other),
+    }
+
+    // Taking users off the list lets them back in
+    let (status, _) = http_request(
+        PORT,
+        "DELETE",
+        &format!("/rooms/room1/acl/{}", bob),
+        &[("Authorization", &root_jwt)],
+        None,
+    )
+    .await;
+    assert_eq!(status, 204);
+    let (mut stream, _) = connect_async(uri("room1", &tokens[1]))
+        .await
+        .expect("Unable to connect as bob");
+    wait_for_join().await;
+    send_frame(
+        &mut stream,
+        json!({ "type": "message", "text": "Hello", "client_id": "1" }),
+    )
+    .await;
+    assert_eq!(next_event(&mut stream).await["type"], "ack");
+
+    remove_db(&db_path);
+}
+
+#[tokio::test]
+async fn room_ownership() {
+    const PORT: u16 = 3053;
+
+    let db_path = PathBuf::from("./main_room_owners.db");
+    let spawn_db_path = db_path.clone();
+    tokio::task::spawn(async move {
+        server::run(PORT, spawn_db_path).await;
+    });
+    wait_for_server(PORT).await;
+
+    let mut user_ids = Vec::new();
+    let mut bearers = Vec::new();
+    for username in &["alice", "bob", "carol"] {
+        let credentials = json!({ "username": username, "password": "correct horse" });
+        let (_, body) = http_request(
+            PORT,
+            "POST",
+            "/users/register",
+            &[],
+            Some(credentials.clone()),
+        )
+        .await;
+        user_ids.push(body["user_id"].as_u64().unwrap());
+        let (_, body) = http_request(PORT, "POST", "/users/login", &[], Some(credentials)).await;
+        bearers.push(format!("Bearer {}", body["token"].as_str().unwrap()));
+    }
+    let (alice, bob, carol) = (user_ids[0], user_ids[1], user_ids[2]);
+
+    let (status, _) = http_request(
+        PORT,
+        "GET",
+        "/rooms/room1",
+        &[("Authorization", &bearers[0])],
+        None,
+    )
+    .await;
+    assert_eq!(status, 404);
+
+    // Rooms are owned by whoever joins them first
+    let token = bearers[0].trim_start_matches("Bearer ");
+    let uri = format!("ws://localhost:{}/chat/room1?token={}", PORT, token);
+    let (_stream, _) = connect_async(uri)
+        .await
+        .expect("Unable to connect as alice");
+    wait_for_join().await;
+
+    let (status, _) = http_request(PORT, "GET", "/rooms/room1", &[], None).await;
+    assert_eq!(status, 401);
+    let (status, body) = http_request(
+        PORT,
+        "GET",
+        "/rooms/room1",
+        &[("Authorization", &bearers[1])],
+        None,
+    )
+    .await;
+    assert_eq!(status, 200);
+    assert_eq!(body["created_by"], alice);
+    assert_eq!(body["owners"], json!([alice]));
+
+    // Private rooms are only shown to those let in
+    let (status, _) = http_request(
+        PORT,
+        "POST",
+        "/rooms",
+        &[("Authorization", &bearers[0])],
+        Some(json!({ "name": "vault", "visibility": "private" })),
+    )
+    .await;
+    assert_eq!(status, 201);
+    for path in &["/rooms/vault", "/rooms/vault/roles"] {
+        let (status, _) =
+            http_request(PORT, "GET", path, &[("Authorization", &bearers[1])], None).await;
+        assert_eq!(status, 403);
+        let (status, _) =
+            http_request(PORT, "GET", path, &[("Authorization", &bearers[0])], None).await;
+        assert_eq!(status, 200);
+    }
+
+    // Owners are admins of their room
+    let (status, _) = http_request(
+        PORT,
+        "PUT",
+        &format!("/rooms/room1/roles/{}", carol),
+        &[("Authorization", &bearers[0])],
+        Some(json!({ "role": "moderator" })),
+    )
+    .await;
+    assert_eq!(status, 200);
+
+    // Only owners can add owners
+    let add_bob = json!({ "user_id": bob });
+    let (status, _) = http_request(
+        PORT,
+        "POST",
+        "/rooms/room1/owners",
+        &[("Authorization", &bearers[2])],
+        Some(add_bob.clone()),
+    )
+    .await;
+    assert_eq!(status, 403);
+    let (status, body) = http_request(
+        PORT,
+        "POST",
+        "/rooms/room1/owners",
+        &[("Authorization", &bearers[0])],
+        Some(add_bob),
+    )
+    .await;
+    assert_eq!(status, 200);
+    assert_eq!(body["owners"], json!([alice, bob]));
+
+    // Co-owners can remove each other, but not the last owner
+    let (status, _) = http_request(
+        PORT,
+        "DELETE",
+        &format!("/rooms/room1/owners/{}", alice),
+        &[("Authorization", &bearers[1])],
+        None,
+    )
+    .await;
+    assert_eq!(status, 204);
+    let (status, _) = http_request(
+        PORT,
+        "DELETE",
+        &format!("/rooms/room1/owners/{}", bob),
+        &[("Authorization", &bearers[1])],
+        None,
+    )
+    .await;
+    assert_eq!(status, 409);
+
+    let (status, body) = http_request(
+        PORT,
+        "PUT",
+        "/rooms/room1/owner",
+        &[("Authorization", &bearers[1])],
+        Some(json!({ "user_id": carol })),
+    )
+    .await;
+    assert_eq!(status, 200);
+    assert_eq!(body["owners"], json!([carol]));
+    let (status, _) = http_request(
+        PORT,
+        "PUT",
+        "/rooms/room1/owner",
+        &[("Authorization", &bearers[1])],
+        Some(json!({ "user_id": bob })),
+    )
+    .await;
+    assert_eq!(status, 403);
+
+    remove_db(&db_path);
+}
+
+#[tokio::test]
+async fn room_creation() {
+    const PORT: u16 = 3054;
+
+    let db_path = PathBuf::from("./main_room_creation.db");
+    let config = Config {
+        explicit_rooms: true,
+        ..Config::new(PORT, db_path.clone())
+    };
+    tokio::task::spawn(async move {
+        server::run_with_config(config).await;
+    });
+    wait_for_server(PORT).await;
+
+    let mut user_ids = Vec::new();
+    let mut tokens = Vec::new();
+    for username in &["alice", "bob"] {
+        let credentials = json!({ "username": username, "password": "correct horse" });
+        let (_, body) = http_request(
+            PORT,
+            "POST",
+            "/users/register",
+            &[],
+            Some(credentials.clone()),
+        )
+        .await;
+        user_ids.push(body["user_id"].as_u64().unwrap());
+        let (_, body) = http_request(PORT, "POST", "/users/login", &[], Some(credentials)).await;
+        tokens.push(String::from(body["token"].as_str().unwrap()));
+    }
+    let alice_jwt = format!("Bearer {}", tokens[0]);
+    let uri =
+        |room: &str, token: &str| format!("ws://localhost:{}/chat/{}?token={}", PORT, room, token);
+
+    // Only logged in users can create rooms
+    let lobby = json!({ "name": "lobby", "capacity": 1, "retention_secs": 3600 });
+    let (status, _) = http_request(PORT, "POST", "/rooms", &[], Some(lobby.clone())).await;
+    assert_eq!(status, 401);
+    let (status, body) = http_request(
+        PORT,
+        "POST",
+        "/rooms",
+        &[("Authorization", &alice_jwt)],
+        Some(lobby.clone()),
+    )
+    .await;
+    assert_eq!(status, 201);
+    assert_eq!(body["owners"], json!([user_ids[0]]));
+    assert_eq!(body["visibility"], "public");
+    assert_eq!(body["capacity"], 1);
+    assert_eq!(body["retention_secs"], 3600);
+
+    let (status, _) = http_request(
+        PORT,
+        "POST",
+        "/rooms",
+        &[("Authorization", &alice_jwt)],
+        Some(lobby),
+    )
+    .await;
+    assert_eq!(status, 409);
+    let (status, _) = http_request(
+        PORT,
+        "POST",
+        "/rooms",
+        &[("Authorization", &alice_jwt)],
+        Some(json!({ "name": "a b" })),
+    )
+    .await;
+    assert_eq!(status, 400);
+
+    // Only admins of the room set how long its messages are kept
+    let bob_jwt = format!("Bearer {}", tokens[1]);
+    let retention = json!({ "retention_messages": 10000 });
+    let (status, _) = http_request(
+        PORT,
+        "PUT",
+        "/rooms/lobby/retention",
+        &[("Authorization", &bob_jwt)],
+        Some(retention.clone()),
+    )
+    .await;
+    assert_eq!(status, 403);
+    let (status, _) = http_request(
+        PORT,
+        "PUT",
+        "/rooms/lobby/retention",
+        &[("Authorization", &alice_jwt)],
+        Some(json!({ "retention_messages": 0 })),
+    )
+    .await;
+    assert_eq!(status, 400);
+    let (status, body) = http_request(
+        PORT,
+        "PUT",
+        "/rooms/lobby/retention",
+        &[("Authorization", &alice_jwt)],
+        Some(retention),
+    )
+    .await;
+    assert_eq!(status, 200);
+    assert_eq!(body["retention_messages"], 10000);
+    assert_eq!(body["retention_secs"], Value::Null);
+
+    // Joining does not create rooms
+    let (mut stream, _) = connect_async(uri("nowhere", &tokens[0]))
+        .await
+        .expect("Unable to connect as alice");
+    match stream.next().await {
+        Some(Ok(Message::Close(Some(frame)))) => assert_eq!(u16::from(frame.code), 4004),
+        other => panic!("Expected connection to be closed, got {:?}", other),
+    }
+
+    // Full rooms refuse further connections
+    let (_alice, _) = connect_async(uri("lobby", &tokens[0]))
+        .await
+        .expect("Unable to connect as alice");
+    wait_for_join().await;
+    let (mut stream, _) = connect_async(uri("lobby", &tokens[1]))
+        .await
+        .expect("Unable to connect as bob");
+    let event = next_event(&mut stream).await;
+    assert_eq!(event["type"], "room_full");
+    assert_eq!(event["capacity"], 1);
+    match stream.next().await {
+        Some(Ok(Message::Close(Some(frame)))) => assert_eq!(u16::from(frame.code), 4029),
+        other => panic!("Expected connection to be closed, got {:?}", other),
+    }
+
+    // Private rooms only let in their owners and allowed users
+    let (status, _) = http_request(
+        PORT,
+        "POST",
+        "/rooms",
+        &[("Authorization", &alice_jwt)],
+        Some(json!({ "name": "vault", "visibility": "private" })),
+    )
+    .await;
+    assert_eq!(status, 201);
+    let (mut stream, _) = connect_async(uri("vault", &tokens[1]))
+        .await
+        .expect("Unable to connect as bob");
+    match stream.next().await {
+        Some(Ok(Message::Close(Some(frame)))) => assert_eq!(u16::from(frame.code), 4003),
+        other => panic!("Expected connection to be closed, got {:?}", other),
+    }
+    let (status, _) = http_request(
+        PORT,
+        "PUT",
+        &format!("/rooms/vault/acl/{}", user_ids[1]),
+        &[("Authorization", &alice_jwt)],
+        Some(json!({ "access": "allow" })),
+    )
+    .await;
+    assert_eq!(status, 200);
+    let (mut stream, _) = connect_async(uri("vault", &tokens[1]))
+        .await
+        .expect("Unable to connect as bob");
+    wait_for_join().await;
+    send_frame(
+        &mut stream,
+        json!({ "type": "message", "text": "Hello", "client_id": "1" }),
+    )
+    .await;
+    assert_eq!(next_event(&mut stream).await["type"], "ack");
+
+    remove_db(&db_path);
+}
+
+#[tokio::test]
+async fn room_listing() {
+    const PORT: u16 = 3055;
+
+    let db_path = PathBuf::from("./main_room_listing.db");
+    let spawn_db_path = db_path.clone();
+    tokio::task::spawn(async move {
+        server::run(PORT, spawn_db_path).await;
+    });
+    wait_for_server(PORT).await;
+
+    let credentials = json!({ "username": "alice", "password": "correct horse" });
+    http_request(
+        PORT,
+        "POST",
+        "/users/register",
+        &[],
+        Some(credentials.clone()),
+    )
+    .await;
+    let (_, body) = http_request(PORT, "POST", "/users/login", &[], Some(credentials)).await;
+    let token = String::from(body["token"].as_str().unwrap());
+    let alice_jwt = format!("Bearer {}", token);
+
+    for room in &[
+        json!({ "name": "lobby", "topic": "Say hi" }),
+        json!({ "name": "vault", "visibility": "private" }),
+    ] {
+        let (status, _) = http_request(
+            PORT,
+            "POST",
+            "/rooms",
+            &[("Authorization", &alice_jwt)],
+            Some(room.clone()),
+        )
+        .await;
+        assert_eq!(status, 201);
+    }
+
+    let (status, body) = http_request(PORT, "GET", "/rooms", &[], None).await;
+    assert_eq!(status, 200);
+    assert_eq!(
+        body,
+        json!([{
+            "name": "lobby",
+            "topic": "Say hi",
+            "occupancy": 0,
+            "message_count": 0,
+            "password_protected": false,
+        }])
+    );
+
+    // Rooms are listed with their connected users and messages, but private
+    // rooms are not listed
+    let uri = format!("ws://localhost:{}/chat/lobby?token={}", PORT, token);
+    let (mut stream, _) = connect_async(uri)
+        .await
+        .expect("Unable to connect as alice");
+    wait_for_join().await;
+    send_frame(
+        &mut stream,
+        json!({ "type": "message", "text": "Hello", "client_id": "1" }),
+    )
+    .await;
+    assert_eq!(next_event(&mut stream).await["type"], "ack");
+
+    let (_guest, _) = connect_async(format!("ws://localhost:{}/chat/general", PORT))
+        .await
+        .expect("Unable to connect as guest");
+    wait_for_join().await;
+
+    let (_, body) = http_request(PORT, "GET", "/rooms", &[], None).await;
+    assert_eq!(
+        body,
+        json!([
+            {
+                "name": "general",
+                "topic": null,
+                "occupancy": 1,
+                "message_count": 0,
+                "password_protected": false,
+            },
+            {
+                "name": "lobby",
+                "topic": "Say hi",
+                "occupancy": 1,
+                "message_count": 1,
+                "password_protected": false,
+            },
+        ])
+    );
+
+    remove_db(&db_path);
+}
+
+#[tokio::test]
+async fn room_topics() {
+    const PORT: u16 = 3056;
+
+    let db_path = PathBuf::from("./main_room_topics.db");
+    let spawn_db_path = db_path.clone();
+    tokio::task::spawn(async move {
+        server::run(PORT, spawn_db_path).await;
+    });
+    wait_for_server(PORT).await;
+
+    let mut user_ids = Vec::new();
+    let mut tokens = Vec::new();
+    for username in &["alice", "bob"] {
+        let credentials = json!({ "username": username, "password": "correct horse" });
+        let (_, body) = http_request(
+            PORT,
+            "POST",
+            "/users/register",
+            &[],
+            Some(credentials.clone()),
+        )
+        .await;
+        user_ids.push(body["user_id"].as_u64().unwrap());
+        let (_, body) = http_request(PORT, "POST", "/users/login", &[], Some(credentials)).await;
+        tokens.push(String::from(body["token"].as_str().unwrap()));
+    }
+    let (alice, bob) = (user_ids[0], user_ids[1]);
+    let (alice_jwt, bob_jwt) = (
+        format!("Bearer {}", tokens[0]),
+        format!("Bearer {}", tokens[1]),
+    );
+    let uri = |token: &str| format!("ws://localhost:{}/chat/room1?token={}", PORT, token);
+
+    // alice joins first, owning the room
+    let (mut alice_stream, _) = connect_async(uri(&tokens[0]))
+        .await
+        .expect("Unable to connect as alice");
+    wait_for_join().await;
+    let (mut bob_stream, _) = connect_async(uri(&tokens[1]))
+        .await
+        .expect("Unable to connect as bob");
+    wait_for_join().await;
+
+    // Only moderators can set the topic
+    send_frame(
+        &mut bob_stream,
+        json!({ "type": "set_topic", "topic": "Cats" }),
+    )
+    .await;
+    assert_eq!(next_event(&mut bob_stream).await["type"], "error");
+
+    send_frame(
+        &mut alice_stream,
+        json!({ "type": "set_topic", "topic": "Rust", "description": "Talking about Rust" }),
+    )
+    .await;
+    let expected = json!({
+        "type": "topic",
+        "room": "room1",
+        "topic": "Rust",
+        "description": "Talking about Rust",
+        "set_by": alice,
+    });
+    assert_eq!(next_event(&mut alice_stream).await, expected);
+    assert_eq!(next_event(&mut bob_stream).await, expected);
+
+    let update = json!({ "topic": "Crabs" });
+    let (status, _) = http_request(
+        PORT,
+        "PUT",
+        "/rooms/room1/topic",
+        &[("Authorization", &bob_jwt)],
+        Some(update.clone()),
+    )
+    .await;
+    assert_eq!(status, 403);
+    let (status, _) = http_request(
+        PORT,
+        "PUT",
+        &format!("/rooms/room1/roles/{}", bob),
+        &[("Authorization", &alice_jwt)],
+        Some(json!({ "role": "moderator" })),
+    )
+    .await;
+    assert_eq!(status, 200);
+
+    let (status, body) = http_request(
+        PORT,
+        "PUT",
+        "/rooms/room1/topic",
+        &[("Authorization", &bob_jwt)],
+        Some(update),
+    )
+    .await;
+    assert_eq!(status, 200);
+    assert_eq!(body["topic"], "Crabs");
+    assert_eq!(body["description"], Value::Null);
+    assert_eq!(
+        next_event(&mut alice_stream).await,
+        json!({
+            "type": "topic",
+            "room": "room1",
+            "topic": "Crabs",
+            "description": null,
+            "set_by": bob,
+        })
+    );
+
+    remove_db(&db_path);
+}
+
+#[tokio::test]
+async fn room_passwords() {
+    const PORT: u16 = 3057;
+
+    let db_path = PathBuf::from("./main_room_passwords.db");
+    let spawn_db_path = db_path.clone();
+    tokio::task::spawn(async move {
+        server::run(PORT, spawn_db_path).await;
+    });
+    wait_for_server(PORT).await;
+
+    let mut tokens = Vec::new();
+    for username in &["alice", "bob"] {
+        let credentials = json!({ "username": username, "password": "correct horse" });
+        http_request(
+            PORT,
+            "POST",
+            "/users/register",
+            &[],
+            Some(credentials.clone()),
+        )
+        .await;
+        let (_, body) = http_request(PORT, "POST", "/users/login", &[], Some(credentials)).await;
+        tokens.push(String::from(body["token"].as_str().unwrap()));
+    }
+
+    let (status, body) = http_request(
+        PORT,
+        "POST",
+        "/rooms",
+        &[("Authorization", &format!("Bearer {}", tokens[0]))],
+        Some(json!({ "name": "secret", "password": "hunter2" })),
+    )
+    .await;
+    assert_eq!(status, 201);
+    assert_eq!(body["password_protected"], true);
+    assert!(body.get("password").is_none());
+
+    let uri = |query: &str| format!("ws://localhost:{}/chat/secret?{}", PORT, query);
+    let refused = |frame: Option<Result<Message, tungstenite::Error>>| match frame {
+        Some(Ok(Message::Close(Some(frame)))) => assert_eq!(u16::from(frame.code), 4001),
+        other => panic!("Expected connection to be closed, got {:?}", other),
+    };
+    let hello = json!({ "type": "message", "text": "Hello", "client_id": "1" });
+
+    // Owners are let in without the password
+    let (mut stream, _) = connect_async(uri(&format!("token={}", tokens[0])))
+        .await
+        .expect("Unable to connect as alice");
+    wait_for_join().await;
+    send_frame(&mut stream, hello.clone()).await;
+    assert_eq!(next_event(&mut stream).await["type"], "ack");
+
+    let (mut stream, _) = connect_async(uri(&format!("token={}&password=hunter3", tokens[1])))
+        .await
+        .expect("Unable to connect as bob");
+    refused(stream.next().await);
+
+    let (mut stream, _) = connect_async(uri(&format!("token={}&password=hunter2", tokens[1])))
+        .await
+        .expect("Unable to connect as bob");
+    wait_for_join().await;
+    assert_eq!(next_event(&mut stream).await["type"], "message");
+    send_frame(&mut stream, hello.clone()).await;
+    assert_eq!(next_event(&mut stream).await["type"], "ack");
+
+    // The password can also be sent as the first frame
+    let (mut stream, _) = connect_async(uri(""))
+        .await
+        .expect("Unable to connect as guest");
+    send_frame(&mut stream, hello.clone()).await;
+    refused(stream.next().await);
+
+    let (mut stream, _) = connect_async(uri(""))
+        .await
+        .expect("Unable to connect as guest");
+    send_frame(
+        &mut stream,
+        json!({ "type": "auth", "password": "hunter2" }),
+    )
+    .await;
+    wait_for_join().await;
+    for _ in 0..2 {
+        assert_eq!(next_event(&mut stream).await["type"], "message");
+    }
+    send_frame(&mut stream, hello).await;
+    assert_eq!(next_event(&mut stream).await["type"], "ack");
+
+    remove_db(&db_path);
+}
+
+#[tokio::test]
+async fn room_members() {
+    const PORT: u16 = 3058;
+
+    let db_path = PathBuf::from("./main_room_members.db");
+    let spawn_db_path = db_path.clone();
+    tokio::task::spawn(async move {
+        server::run(PORT, spawn_db_path).await;
+    });
+    wait_for_server(PORT).await;
+
+    let mut user_ids = Vec::new();
+    let mut tokens = Vec::new();
+    for username in &["alice", "bob"] {
+        let credentials = json!({ "username": username, "password": "correct horse" });
+        let (_, body) = http_request(
+            PORT,
+            "POST",
+            "/users/register",
+            &[],
+            Some(credentials.clone()),
+        )
+        .await;
+        user_ids.push(body["user_id"].as_u64().unwrap());
+        let (_, body) = http_request(PORT, "POST", "/users/login", &[], Some(credentials)).await;
+        tokens.push(String::from(body["token"].as_str().unwrap()));
+    }
+    let (alice, bob) = (user_ids[0], user_ids[1]);
+    let (alice_jwt, bob_jwt) = (
+        format!("Bearer {}", tokens[0]),
+        format!("Bearer {}", tokens[1]),
+    );
+    let uri = |token: &str| format!("ws://localhost:{}/chat/club?token={}", PORT, token);
+    let refused = |frame: Option<Result<Message, tungstenite::Error>>| match frame {
+        Some(Ok(Message::Close(Some(frame)))) => assert_eq!(u16::from(frame.code), 4003),
+        other => panic!("Expected connection to be closed, got {:?}", other),
+    };
+
+    let (status, _) = http_request(
+        PORT,
+        "POST",
+        "/rooms",
+        &[("Authorization", &alice_jwt)],
+        Some(json!({ "name": "club", "visibility": "private" })),
+    )
+    .await;
+    assert_eq!(status, 201);
+
+    // Private rooms only let in their members
+    let (mut stream, _) = connect_async(uri(&tokens[1]))
+        .await
+        .expect("Unable to connect as bob");
+    refused(stream.next().await);
+
+    // Only admins of the room can invite members
+    let invite = json!({ "user_id": bob });
+    let (status, _) = http_request(
+        PORT,
+        "POST",
+        "/rooms/club/members",
+        &[("Authorization", &bob_jwt)],
+        Some(invite.clone()),
+    )
+    .await;
+    assert_eq!(status, 403);
+    let (status, body) = http_request(
+        PORT,
+        "POST",
+        "/rooms/club/members",
+        &[("Authorization", &alice_jwt)],
+        Some(invite),
+    )
+    .await;
+    assert_eq!(status, 200);
+    assert_eq!(body["user_id"], bob);
+    assert_eq!(body["invited_by"], alice);
+
+    let (status, body) = http_request(
+        PORT,
+        "GET",
+        "/rooms/club/members",
+        &[("Authorization", &alice_jwt)],
+        None,
+    )
+    .await;
+    assert_eq!(status, 200);
+    assert_eq!(body.as_array().unwrap().len(), 1);
+    assert_eq!(body[0]["user_id"], bob);
+
+    let (mut stream, _) = connect_async(uri(&tokens[1]))
+        .await
+        .expect("Unable to connect as bob");
+    wait_for_join().await;
+    send_frame(
+        &mut stream,
+        json!({ "type": "message", "text": "Hello", "client_id": "1" }),
+    )
+    .await;
+    assert_eq!(next_event(&mut stream).await["type"], "ack");
+
+    // Removed members are disconnected
+    let (status, _) = http_request(
+        PORT,
+        "DELETE",
+        &format!("/rooms/club/members/{}", bob),
+        &[("Authorization", &alice_jwt)],
+        None,
+    )
+    .await;
+    assert_eq!(status, 204);
+    refused(stream.next().await);
+
+    let (status, _) = http_request(
+        PORT,
+        "DELETE",
+        &format!("/rooms/club/members/{}", bob),
+        &[("Authorization", &alice_jwt)],
+        None,
+    )
+    .await;
+    assert_eq!(status, 404);
+
+    remove_db(&db_path);
+}
+
+#[tokio::test]
+async fn room_invites() {
+    const PORT: u16 = 3059;
+
+    let db_path = PathBuf::from("./main_room_invites.db");
+    let spawn_db_path = db_path.clone();
+    tokio::task::spawn(async move {
+        server::run(PORT, spawn_db_path).await;
+    });
+    wait_for_server(PORT).await;
+
+    let mut tokens = Vec::new();
+    for username in &["alice", "bob", "carol"] {
+        let credentials = json!({ "username": username, "password": "correct horse" });
+        http_request(
+            PORT,
+            "POST",
+            "/users/register",
+            &[],
+            Some(credentials.clone()),
+        )
+        .await;
+        let (_, body) = http_request(PORT, "POST", "/users/login", &[], Some(credentials)).await;
+        tokens.push(String::from(body["token"].as_str().unwrap()));
+    }
+    let (alice_jwt, bob_jwt) = (
+        format!("Bearer {}", tokens[0]),
+        format!("Bearer {}", tokens[1]),
+    );
+
+    let (status, _) = http_request(
+        PORT,
+        "POST",
+        "/rooms",
+        &[("Authorization", &alice_jwt)],
+        Some(json!({ "name": "club", "visibility": "private" })),
+    )
+    .await;
+    assert_eq!(status, 201);
+
+    // Only admins of the room can create invites
+    let new_invite = json!({ "max_uses": 1, "ttl_secs": 3600 });
+    let (status, _) = http_request(
+        PORT,
+        "POST",
+        "/rooms/club/invites",
+        &[("Authorization", &bob_jwt)],
+        Some(new_invite.clone()),
+    )
+    .await;
+    assert_eq!(status, 403);
+    let (status, body) = http_request(
+        PORT,
+        "POST",
+        "/rooms/club/invites",
+        &[("Authorization", &alice_jwt)],
+        Some(new_invite),
+    )
+    .await;
+    assert_eq!(status, 201);
+    assert_eq!(body["uses"], 0);
+    assert!(body["expires_at"].is_string());
+    let (invite_id, invite) = (
+        body["id"].as_i64().unwrap(),
+        String::from(body["token"].as_str().unwrap()),
+    );
+
+    // Redeeming an invite makes users members, letting them into the room
+    let uri = |query: &str| format!("ws://localhost:{}/chat/club?{}", PORT, query);
+    assert!(connect_async(uri(&format!("invite={}", invite)))
+        .await
+        .is_err());
+    let (mut stream, _) = connect_async(uri(&format!("token={}&invite={}", tokens[1], invite)))
+        .await
+        .expect("Unable to connect as bob");
+    wait_for_join().await;
+    send_frame(
+        &mut stream,
+        json!({ "type": "message", "text": "Hello", "client_id": "1" }),
+    )
+    .await;
+    assert_eq!(next_event(&mut stream).await["type"], "ack");
+
+    // Used up invites can not be redeemed
+    assert!(
+        connect_async(uri(&format!("token={}&invite={}", tokens[2], invite)))
+            .await
+            .is_err()
+    );
+
+    let (status, body) = http_request(
+        PORT,
+        "GET",
+        "/rooms/club/invites",
+        &[("Authorization", &alice_jwt)],
+        None,
+    )
+    .await;
+    assert_eq!(status, 200);
+    assert_eq!(body[0]["uses"], 1);
+    assert!(body[0].get("token").is_none());
+
+    let (status, _) = http_request(
+        PORT,
+        "DELETE",
+        &format!("/rooms/club/invites/{}", invite_id),
+        &[("Authorization", &alice_jwt)],
+        None,
+    )
+    .await;
+    assert_eq!(status, 204);
+    let (status, body) = http_request(
+        PORT,
+        "GET",
+        "/rooms/club/members",
+        &[("Authorization", &alice_jwt)],
+        None,
+    )
+    .await;
+    assert_eq!(status, 200);
+    assert_eq!(body.as_array().unwrap().len(), 1);
+
+    remove_db(&db_path);
+}
+
+#[tokio::test]
+// Tests that rooms without a capacity of their own hold up to the default one.
+async fn default_room_capacity() {
+    const PORT: u16 = 3060;
+
+    let db_path = PathBuf::from("./main_default_room_capacity.db");
+    let config = Config {
+        room_capacity: Some(2),
+        ..Config::new(PORT, db_path.clone())
+    };
+    tokio::task::spawn(async move {
+        server::run_with_config(config).await;
+    });
+    wait_for_server(PORT).await;
+
+    let uri = format!("ws://localhost:{}/chat/room1", PORT);
+    let (_stream1, _) = connect_async(&uri).await.expect("Unable to connect");
+    let (_stream2, _) = connect_async(&uri).await.expect("Unable to connect");
+    wait_for_join().await;
+
+    let (mut stream3, _) = connect_async(&uri).await.expect("Unable to connect");
+    let event = next_event(&mut stream3).await;
+    assert_eq!(event["type"], "room_full");
+    assert_eq!(event["room"], "room1");
+    assert_eq!(event["capacity"], 2);
+    match stream3.next().await {
+        Some(Ok(Message::Close(Some(frame)))) => assert_eq!(u16::from(frame.code), 4029),
+        other => panic!("Expected connection to be closed, got {:?}", other),
+    }
+
+    // Other rooms are not affected
+    let uri = format!("ws://localhost:{}/chat/room2", PORT);
+    let (mut stream4, _) = connect_async(&uri).await.expect("Unable to connect");
+    wait_for_join().await;
+    assert_eq!(next_raw_event(&mut stream4).await["type"], "join");
+    assert_eq!(next_raw_event(&mut stream4).await["type"], "presence");
+    assert!(stream4.next().now_or_never().is_none());
+
+    remove_db(&db_path);
+}
+
+#[tokio::test]
+// Tests that only moderators post in read-only rooms, which others still join.
+async fn read_only_rooms() {
+    const PORT: u16 = 3061;
+
+    let db_path = PathBuf::from("./main_read_only_rooms.db");
+    let spawn_db_path = db_path.clone();
+    tokio::task::spawn(async move {
+        server::run(PORT, spawn_db_path).await;
+    });
+    wait_for_server(PORT).await;
+
+    let mut user_ids = Vec::new();
+    let mut tokens = Vec::new();
+    for username in &["alice", "bob"] {
+        let credentials = json!({ "username": username, "password": "correct horse" });
+        let (_, body) = http_request(
+            PORT,
+            "POST",
+            "/users/register",
+            &[],
+            Some(credentials.clone()),
+        )
+        .await;
+        user_ids.push(body["user_id"].as_u64().unwrap());
+        let (_, body) = http_request(PORT, "POST", "/users/login", &[], Some(credentials)).await;
+        tokens.push(String::from(body["token"].as_str().unwrap()));
+    }
+    let alice = user_ids[0];
+    let (alice_jwt, bob_jwt) = (
+        format!("Bearer {}", tokens[0]),
+        format!("Bearer {}", tokens[1]),
+    );
+    let uri = |token: &str| format!("ws://localhost:{}/chat/room1?token={}", PORT, token);
+
+    // alice joins first, owning the room
+    let (mut alice_stream, _) = connect_async(uri(&tokens[0]))
+        .await
+        .expect("Unable to connect as alice");
+    wait_for_join().await;
+    let (mut bob_stream, _) = connect_async(uri(&tokens[1]))
+        .await
+        .expect("Unable to connect as bob");
+    wait_for_join().await;
+
+    let update = json!({ "read_only": true });
+    let (status, _) = http_request(
+        PORT,
+        "PUT",
+        "/rooms/room1/read_only",
+        &[("Authorization", &bob_jwt)],
+        Some(update.clone()),
+    )
+    .await;
+    assert_eq!(status, 403);
+    let (status, body) = http_request(
+        PORT,
+        "PUT",
+        "/rooms/room1/read_only",
+        &[("Authorization", &alice_jwt)],
+        Some(update),
+    )
+    .await;
+    assert_eq!(status, 200);
+    assert_eq!(body["read_only"], true);
+    let expected =
+        json!({ "type": "read_only", "room": "room1", "read_only": true, "set_by": alice });
+    assert_eq!(next_event(&mut alice_stream).await, expected);
+    assert_eq!(next_event(&mut bob_stream).await, expected);
+
+    // Only moderators post in read-only rooms
+    send_frame(
+        &mut bob_stream,
+        json!({ "type": "message", "text": "Hello" }),
+    )
+    .await;
+    let event = next_event(&mut bob_stream).await;
+    assert_eq!(event["type"], "error");
+    assert_eq!(event["reason"], "Room is read-only");
+
+    send_frame(
+        &mut alice_stream,
+        json!({ "type": "message", "text": "Announcement" }),
+    )
+    .await;
+    assert_eq!(next_event(&mut alice_stream).await["type"], "ack");
+    assert_eq!(next_event(&mut bob_stream).await["text"], "Announcement");
+
+    // Others can still join and read the history of the room
+    let (mut guest_stream, _) = connect_async(format!("ws://localhost:{}/chat/room1", PORT))
+        .await
+        .expect("Unable to connect as a guest");
+    assert_eq!(next_event(&mut guest_stream).await["text"], "Announcement");
+
+    let (status, body) = http_request(
+        PORT,
+        "PUT",
+        "/rooms/room1/read_only",
+        &[("Authorization", &alice_jwt)],
+        Some(json!({ "read_only": false })),
+    )
+    .await;
+    assert_eq!(status, 200);
+    assert_eq!(body["read_only"], false);
+    assert_eq!(next_event(&mut bob_stream).await["type"], "read_only");
+    send_frame(
+        &mut bob_stream,
+        json!({ "type": "message", "text": "Hello" }),
+    )
+    .await;
+    assert_eq!(next_event(&mut bob_stream).await["type"], "ack");
+
+    remove_db(&db_path);
+}
+
+#[tokio::test]
+// Tests that connections to `/chat` without a room join the default room.
+async fn default_room() {
+    const PORT: u16 = 3062;
+
+    let db_path = PathBuf::from("./main_default_room.db");
+    let config = Config {
+        default_room: String::from("lobby"),
+        ..Config::new(PORT, db_path.clone())
+    };
+    tokio::task::spawn(async move {
+        server::run_with_config(config).await;
+    });
+    wait_for_server(PORT).await;
+
+    let (mut bare_stream, _) = connect_async(format!("ws://localhost:{}/chat", PORT))
+        .await
+        .expect("Unable to connect without a room");
+    wait_for_join().await;
+    let event = next_raw_event(&mut bare_stream).await;
+    assert_eq!(event["type"], "join");
+    assert_eq!(event["room"], "lobby");
+    assert_eq!(next_raw_event(&mut bare_stream).await["type"], "presence");
+
+    let (mut lobby_stream, _) = connect_async(format!("ws://localhost:{}/chat/lobby", PORT))
+        .await
+        .expect("Unable to connect to lobby");
+    wait_for_join().await;
+    assert_eq!(next_raw_event(&mut bare_stream).await["type"], "join");
+
+    send_frame(
+        &mut bare_stream,
+        json!({ "type": "message", "text": "Hello lobby" }),
+    )
+    .await;
+    assert_eq!(next_event(&mut lobby_stream).await["text"], "Hello lobby");
+
+    remove_db(&db_path);
+}
+
+#[tokio::test]
+// Tests that moderators kick, ban and mute users of a lower role.
+async fn moderation() {
+    const PORT: u16 = 3063;
+
+    let db_path = PathBuf::from("./main_moderation.db");
+    let spawn_db_path = db_path.clone();
+    tokio::task::spawn(async move {
+        server::run(PORT, spawn_db_path).await;
+    });
+    wait_for_server(PORT).await;
+
+    let mut user_ids = Vec::new();
+    let mut tokens = Vec::new();
+    for username in &["alice", "bob"] {
+        let credentials = json!({ "username": username, "password": "correct horse" });
+        let (_, body) = http_request(
+            PORT,
+            "POST",
+            "/users/register",
+            &[],
+            Some(credentials.clone()),
+        )
+        .await;
+        user_ids.push(body["user_id"].as_u64().unwrap());
+        let (_, body) = http_request(PORT, "POST", "/users/login", &[], Some(credentials)).await;
+        tokens.push(String::from(body["token"].as_str().unwrap()));
+    }
+    let (alice, bob) = (user_ids[0], user_ids[1]);
+    let (alice_jwt, bob_jwt) = (
+        format!("Bearer {}", tokens[0]),
+        format!("Bearer {}", tokens[1]),
+    );
+    let uri = |token: &str| format!("ws://localhost:{}/chat/room1?token={}", PORT, token);
+
+    // alice joins first, owning the room
+    let (mut alice_stream, _) = connect_async(uri(&tokens[0]))
+        .await
+        .expect("Unable to connect as alice");
+    wait_for_join().await;
+    let (mut bob_stream, _) = connect_async(uri(&tokens[1]))
+        .await
+        .expect("Unable to connect as bob");
+    wait_for_join().await;
+
+    // Only moderators moderate users, and only those of a lower role
+    send_frame(&mut bob_stream, json!({ "type": "kick", "user_id": alice })).await;
+    assert_eq!(next_event(&mut bob_stream).await["type"], "error");
+    let (status, _) = http_request(
+        PORT,
+        "PUT",
+        &format!("/rooms/room1/roles/{}", bob),
+        &[("Authorization", &alice_jwt)],
+        Some(json!({ "role": "moderator" })),
+    )
+    .await;
+    assert_eq!(status, 200);
+    let (status, _) = http_request(
+        PORT,
+        "POST",
+        &format!("/rooms/room1/kick/{}", alice),
+        &[("Authorization", &bob_jwt)],
+        None,
+    )
+    .await;
+    assert_eq!(status, 403);
+    let (status, _) = http_request(
+        PORT,
+        "PUT",
+        &format!("/rooms/room1/roles/{}", bob),
+        &[("Authorization", &alice_jwt)],
+        Some(json!({ "role": "member" })),
+    )
+    .await;
+    assert_eq!(status, 200);
+
+    // Muted users stay in the room, but may not post
+    send_frame(
+        &mut alice_stream,
+        json!({ "type": "mute", "user_id": bob, "reason": "Spam" }),
+    )
+    .await;
+    let event = next_event(&mut bob_stream).await;
+    assert_eq!(event["type"], "sanction");
+    assert_eq!(event["kind"], "mute");
+    assert_eq!(event["issued_by"], alice);
+    assert_eq!(event["reason"], "Spam");
+    assert_eq!(next_event(&mut alice_stream).await["type"], "sanction");
+    send_frame(&mut bob_stream, json!({ "type": "message", "text": "Hi" })).await;
+    let event = next_event(&mut bob_stream).await;
+    assert_eq!(event["type"], "error");
+    assert_eq!(event["reason"], "You are muted in this room");
+
+    let (status, _) = http_request(
+        PORT,
+        "GET",
+        "/rooms/room1/mutes",
+        &[("Authorization", &bob_jwt)],
+        None,
+    )
+    .await;
+    assert_eq!(status, 403);
+    let (status, body) = http_request(
+        PORT,
+        "GET",
+        "/rooms/room1/mutes",
+        &[("Authorization", &alice_jwt)],
+        None,
+    )
+    .await;
+    assert_eq!(status, 200);
+    assert_eq!(body[0]["user_id"], bob);
+
+    let path = format!("/rooms/room1/mutes/{}", bob);
+    let (status, _) = http_request(
+        PORT,
+        "DELETE",
+        &path,
+        &[("Authorization", &alice_jwt)],
+        None,
+    )
+    .await;
+    assert_eq!(status, 204);
+    let (status, _) = http_request(
+        PORT,
+        "DELETE",
+        &path,
+        &[("Authorization", &alice_jwt)],
+        None,
+    )
+    .await;
+    assert_eq!(status, 404);
+    assert_eq!(next_event(&mut bob_stream).await["type"], "sanction_lifted");
+    send_frame(&mut bob_stream, json!({ "type": "message", "text": "Hi" })).await;
+    assert_eq!(next_event(&mut bob_stream).await["type"], "ack");
+
+    // Kicked users may join again
+    let (status, _) = http_request(
+        PORT,
+        "POST",
+        &format!("/rooms/room1/kick/{}", bob),
+        &[("Authorization", &alice_jwt)],
+        None,
+    )
+    .await;
+    assert_eq!(status, 204);
+    assert_eq!(next_event(&mut bob_stream).await["type"], "kick");
+    match bob_stream.next().await {
+        Some(Ok(Message::Close(Some(frame)))) => assert_eq!(u16::from(frame.code), 4000),
+        other => panic!("Expected connection to be closed, got {:?}", other),
+    }
+
+    let (mut bob_stream, _) = connect_async(uri(&tokens[1]))
+        .await
+        .expect("Unable to connect as bob");
+    assert_eq!(next_event(&mut bob_stream).await["text"], "Hi");
+
+    // Banned users are kept out until unbanned
+    let ban = json!({ "reason": "Trolling", "duration_secs": 3600 });
+    let (status, body) = http_request(
+        PORT,
+        "PUT",
+        &format!("/rooms/room1/bans/{}", bob),
+        &[("Authorization", &alice_jwt)],
+        Some(ban),
+    )
+    .await;
+    assert_eq!(status, 200);
+    assert_eq!(body["kind"], "ban");
+    assert!(body["expires_at"].is_string());
+    assert_eq!(next_event(&mut bob_stream).await["type"], "sanction");
+    match bob_stream.next().await {
+        Some(Ok(Message::Close(Some(frame)))) => assert_eq!(u16::from(frame.code), 4003),
+        other => panic!("Expected connection to be closed, got {:?}", other),
+    }
+
+    let (mut bob_stream, _) = connect_async(uri(&tokens[1]))
+        .await
+        .expect("Unable to connect as bob");
+    match bob_stream.next().await {
+        Some(Ok(Message::Close(Some(frame)))) => assert_eq!(u16::from(frame.code), 4003),
+        other => panic!("Expected connection to be closed, got {:?}", other),
+    }
+
+    let (status, body) = http_request(
+        PORT,
+        "GET",
+        "/rooms/room1/bans",
+        &[("Authorization", &alice_jwt)],
+        None,
+    )
+    .await;
+    assert_eq!(status, 200);
+    assert_eq!(body[0]["reason"], "Trolling");
+    let (status, _) = http_request(
+        PORT,
+        "DELETE",
+        &format!("/rooms/room1/bans/{}", bob),
+        &[("Authorization", &alice_jwt)],
+        None,
+    )
+    .await;
+    assert_eq!(status, 204);
+
+    let (mut bob_stream, _) = connect_async(uri(&tokens[1]))
+        .await
+        .expect("Unable to connect as bob");
+    assert_eq!(next_event(&mut bob_stream).await["text"], "Hi");
+    wait_for_join().await;
+    send_frame(
+        &mut bob_stream,
+        json!({ "type": "message", "text": "Back" }),
+    )
+    .await;
+    assert_eq!(next_event(&mut bob_stream).await["type"], "ack");
+
+    // Guests, given a new ID on every connection, are banned by their address
+    let guest_uri = |nick: &str| format!("ws://localhost:{}/chat/room1?nick={}", PORT, nick);
+    let (mut guest_stream, _) = connect_async(guest_uri("lurker"))
+        .await
+        .expect("Unable to connect as a guest");
+    let guest = loop {
+        let event = next_raw_event(&mut alice_stream).await;
+        if event["type"] == "join" && event["nick"] == "lurker" {
+            break event["user_id"].as_u64().unwrap();
+        }
+    };
+    let (status, _) = http_request(
+        PORT,
+        "PUT",
+        &format!("/rooms/room1/bans/{}", guest),
+        &[("Authorization", &alice_jwt)],
+        Some(json!({})),
+    )
+    .await;
+    assert_eq!(status, 200);
+    // After the history of the room
+    while next_event(&mut guest_stream).await["type"] != "sanction" {}
+
+    let (mut guest_stream, _) = connect_async(guest_uri("lurker2"))
+        .await
+        .expect("Unable to connect as a guest");
+    match guest_stream.next().await {
+        Some(Ok(Message::Close(Some(frame)))) => assert_eq!(u16::from(frame.code), 4003),
+        other => panic!("Expected connection to be closed, got {:?}", other),
+    }
+
+    remove_db(&db_path);
+}
+
+#[tokio::test]
+async fn ip_bans() {
+    const PORT: u16 = 3064;
+
+    let db_path = PathBuf::from("./main_ip_bans.db");
+    let config = Config {
+        admin_token: Some(String::from("secret")),
+        trust_forwarded_for: true,
+        ..Config::new(PORT, db_path.clone())
+    };
+    tokio::task::spawn(async move {
+        server::run_with_config(config).await;
+    });
+    wait_for_server(PORT).await;
+
+    let uri = format!("ws://localhost:{}/chat/room1", PORT);
+    let forwarded_request = |addr: &str| {
+        let mut request = uri.clone().into_client_request().unwrap();
+        request
+            .headers_mut()
+            .insert("X-Forwarded-For", addr.parse().unwrap());
+        request
+    };
+    let admin = [("Authorization", "Bearer secret")];
+
+    let (mut banned, _) = connect_async(forwarded_request("10.1.2.3"))
+        .await
+        .expect("Unable to connect");
+    wait_for_join().await;
+    let (mut other, _) = connect_async(forwarded_request("192.0.2.1"))
+        .await
+        .expect("Unable to connect");
+    wait_for_join().await;
+
+    // Only admins ban addresses
+    let ban = json!({ "cidr": "10.0.0.0/8", "reason": "Spam" });
+    let (status, _) = http_request(PORT, "POST", "/admin/ip_bans", &[], Some(ban.clone())).await;
+    assert_eq!(status, 401);
+    let (status, _) = http_request(
+        PORT,
+        "POST",
+        "/admin/ip_bans",
+        &admin,
+        Some(json!({ "cidr": "10.0.0.0/40" })),
+    )
+    .await;
+    assert_eq!(status, 400);
+
+    let (status, body) =
+        http_request(PORT, "POST", "/admin/ip_bans", &admin, Some(ban.clone())).await;
+    assert_eq!(status, 201);
+    assert_eq!(body["cidr"], "10.0.0.0/8");
+    let ban_id = body["id"].as_i64().unwrap();
+    let (status, _) = http_request(PORT, "POST", "/admin/ip_bans", &admin, Some(ban)).await;
+    assert_eq!(status, 409);
+
+    // Open connections from banned addresses are closed, others are not
+    loop {
+        match banned.next().await {
+            Some(Ok(Message::Close(_))) | None => break,
+            Some(Ok(_)) => {}
+            Some(Err(e)) => panic!("Unexpected error: {}", e),
+        }
+    }
+    send_frame(&mut other, json!({ "type": "message", "text": "Hi" })).await;
+    assert_eq!(next_event(&mut other).await["type"], "ack");
+
+    // New connections from banned addresses are refused
+    assert!(connect_async(forwarded_request("10.9.9.9")).await.is_err());
+    // As are logins from them
+    let credentials = json!({ "username": "alice", "password": "correct horse" });
+    let (status, body) = http_request(
+        PORT,
+        "POST",
+        "/users/login",
+        &[("X-Forwarded-For", "10.9.9.9")],
+        Some(credentials),
+    )
+    .await;
+    assert_eq!(status, 403);
+    assert_eq!(body["error"], "Your address is banned");
+    let (status, body) = http_request(PORT, "GET", "/admin/ip_bans", &admin, None).await;
+    assert_eq!(status, 200);
+    assert_eq!(body[0]["reason"], "Spam");
+
+    let path = format!("/admin/ip_bans/{}", ban_id);
+    let (status, _) = http_request(PORT, "DELETE", &path, &admin, None).await;
+    assert_eq!(status, 204);
+    let (status, _) = http_request(PORT, "DELETE", &path, &admin, None).await;
+    assert_eq!(status, 404);
+    connect_async(forwarded_request("10.9.9.9"))
+        .await
+        .expect("Unable to connect once unbanned");
+
+    remove_db(&db_path);
+}
+
+#[tokio::test]
+async fn shadow_bans() {
+    const PORT: u16 = 3065;
+
+    let db_path = PathBuf::from("./main_shadow_bans.db");
+    let spawn_db_path = db_path.clone();
+    tokio::task::spawn(async move {
+        server::run(PORT, spawn_db_path).await;
+    });
+    wait_for_server(PORT).await;
+
+    let mut user_ids = Vec::new();
+    let mut tokens = Vec::new();
+    for username in &["alice", "bob"] {
+        let credentials = json!({ "username": username, "password": "correct horse" });
+        let (_, body) = http_request(
+            PORT,
+            "POST",
+            "/users/register",
+            &[],
+            Some(credentials.clone()),
+        )
+        .await;
+        user_ids.push(body["user_id"].as_u64().unwrap());
+        let (_, body) = http_request(PORT, "POST", "/users/login", &[], Some(credentials)).await;
+        tokens.push(String::from(body["token"].as_str().unwrap()));
+    }
+    let bob = user_ids[1];
+    let alice_jwt = format!("Bearer {}", tokens[0]);
+    let uri = |token: &str| format!("ws://localhost:{}/chat/room1?token={}", PORT, token);
+
+    // alice joins first, owning the room
+    let (mut alice_stream, _) = connect_async(uri(&tokens[0]))
+        .await
+        .expect("Unable to connect as alice");
+    wait_for_join().await;
+    let (mut bob_stream, _) = connect_async(uri(&tokens[1]))
+        .await
+        .expect("Unable to connect as bob");
+    wait_for_join().await;
+
+    let path = format!("/rooms/room1/shadow_bans/{}", bob);
+    let (status, body) = http_request(
+        PORT,
+        "PUT",
+        &path,
+        &[("Authorization", &alice_jwt)],
+        Some(json!({})),
+    )
+    .await;
+    assert_eq!(status, 200);
+    assert_eq!(body["kind"], "shadow_ban");
+
+    // bob is not told, and his messages look delivered, but only reach him
+    send_frame(
+        &mut bob_stream,
+        json!({ "type": "message", "text": "Troll" }),
+    )
+    .await;
+    assert_eq!(next_event(&mut bob_stream).await["type"], "ack");
+    send_frame(
+        &mut alice_stream,
+        json!({ "type": "message", "text": "Hello" }),
+    )
+    .await;
+    assert_eq!(next_event(&mut alice_stream).await["type"], "ack");
+    assert_eq!(next_event(&mut bob_stream).await["text"], "Hello");
+
+    // Nor are they in the history of anyone but bob
+    let (mut bob_stream2, _) = connect_async(uri(&tokens[1]))
+        .await
+        .expect("Unable to connect as bob");
+    assert_eq!(next_event(&mut bob_stream2).await["text"], "Troll");
+    let (mut alice_stream2, _) = connect_async(uri(&tokens[0]))
+        .await
+        .expect("Unable to connect as alice");
+    assert_eq!(next_event(&mut alice_stream2).await["text"], "Hello");
+    wait_for_join().await;
+
+    let (status, body) = http_request(
+        PORT,
+        "GET",
+        "/rooms/room1/shadow_bans",
+        &[("Authorization", &alice_jwt)],
+        None,
+    )
+    .await;
+    assert_eq!(status, 200);
+    assert_eq!(body[0]["user_id"], bob);
+    let (status, _) = http_request(
+        PORT,
+        "DELETE",
+        &path,
+        &[("Authorization", &alice_jwt)],
+        None,
+    )
+    .await;
+    assert_eq!(status, 204);
+
+    send_frame(
+        &mut bob_stream,
+        json!({ "type": "message", "text": "Sorry" }),
+    )
+    .await;
+    assert_eq!(next_event(&mut bob_stream).await["type"], "ack");
+    assert_eq!(next_event(&mut alice_stream).await["text"], "Sorry");
+
+    remove_db(&db_path);
+}
+
+#[tokio::test]
+async fn word_filter() {
+    const PORT: u16 = 3066;
+
+    let db_path = PathBuf::from("./main_word_filter.db");
+    let config = Config {
+        filter_words: vec![String::from("darn")],
+        room_filter_actions: vec![
+            "strict=reject".parse().unwrap(),
+            "review=flag".parse().unwrap(),
+        ],
+        ..Config::new(PORT, db_path.clone())
+    };
+    tokio::task::spawn(async move {
+        server::run_with_config(config).await;
+    });
+    wait_for_server(PORT).await;
+
+    let credentials = json!({ "username": "alice", "password": "correct horse" });
+    http_request(
+        PORT,
+        "POST",
+        "/users/register",
+        &[],
+        Some(credentials.clone()),
+    )
+    .await;
+    let (_, body) = http_request(PORT, "POST", "/users/login", &[], Some(credentials)).await;
+    let token = String::from(body["token"].as_str().unwrap());
+    let connect = |room: &str| {
+        connect_async(format!(
+            "ws://localhost:{}/chat/{}?token={}",
+            PORT, room, token
+        ))
+    };
+    let say = json!({ "type": "message", "text": "Darn it" });
+
+    // Matches are masked by default
+    let (mut sender, _) = connect("room1").await.expect("Unable to connect");
+    wait_for_join().await;
+    let (mut receiver, _) = connect("room1").await.expect("Unable to connect");
+    wait_for_join().await;
+    send_frame(&mut sender, say.clone()).await;
+    assert_eq!(next_event(&mut sender).await["type"], "ack");
+    assert_eq!(next_event(&mut receiver).await["text"], "**** it");
+
+    // Rooms may reject them instead
+    let (mut strict, _) = connect("strict").await.expect("Unable to connect");
+    wait_for_join().await;
+    send_frame(&mut strict, say.clone()).await;
+    assert_eq!(next_event(&mut strict).await["type"], "error");
+
+    // Or let them through, flagged for moderators
+    let (mut review, _) = connect("review").await.expect("Unable to connect");
+    wait_for_join().await;
+    send_frame(&mut review, say).await;
+    assert_eq!(next_event(&mut review).await["type"], "ack");
+    wait_for_join().await;
+    let (status, body) = http_request(
+        PORT,
+        "GET",
+        "/rooms/review/flagged",
+        &[("Authorization", &format!("Bearer {}", token))],
+        None,
+    )
+    .await;
+    assert_eq!(status, 200);
+    assert_eq!(body[0]["text"], "Darn it");
+    let (status, _) = http_request(PORT, "GET", "/rooms/review/flagged", &[], None).await;
+    assert_eq!(status, 401);
+
+    remove_db(&db_path);
+}
+
+#[tokio::test]
+async fn duplicate_messages() {
+    const PORT: u16 = 3067;
+
+    let db_path = PathBuf::from("./main_duplicate_messages.db");
+    let config = Config {
+        max_duplicate_messages: 2,
+        ..Config::new(PORT, db_path.clone())
+    };
+    tokio::task::spawn(async move {
+        server::run_with_config(config).await;
+    });
+    wait_for_server(PORT).await;
+
+    let uri = format!("ws://localhost:{}/chat/room1", PORT);
+    let (mut sender, _) = connect_async(&uri).await.expect("Unable to connect");
+    wait_for_join().await;
+    let (mut receiver, _) = connect_async(&uri).await.expect("Unable to connect");
+    wait_for_join().await;
+
+    // Near-identical messages count as duplicates
+    for text in &["Buy now", "buy now!!"] {
+        send_frame(&mut sender, json!({ "type": "message", "text": text })).await;
+        assert_eq!(next_event(&mut sender).await["type"], "ack");
+        assert_eq!(next_event(&mut receiver).await["text"], *text);
+    }
+    send_frame(&mut sender, json!({ "type": "message", "text": "BUY NOW" })).await;
+    assert_eq!(next_event(&mut sender).await["type"], "error");
+
+    // Other messages still go through
+    send_frame(&mut sender, json!({ "type": "message", "text": "Sorry" })).await;
+    assert_eq!(next_event(&mut sender).await["type"], "ack");
+    assert_eq!(next_event(&mut receiver).await["text"], "Sorry");
+
+    remove_db(&db_path);
+}
+
+#[tokio::test]
+async fn message_reports() {
+    const PORT: u16 = 3068;
+
+    let db_path = PathBuf::from("./main_message_reports.db");
+    let spawn_db_path = db_path.clone();
+    tokio::task::spawn(async move {
+        server::run(PORT, spawn_db_path).await;
+    });
+    wait_for_server(PORT).await;
+
+    let mut tokens = Vec::new();
+    for username in &["alice", "bob", "carol"] {
+        let credentials = json!({ "username": username, "password": "correct horse" });
+        http_request(
+            PORT,
+            "POST",
+            "/users/register",
+            &[],
+            Some(credentials.clone()),
+        )
+        .await;
+        let (_, body) = http_request(PORT, "POST", "/users/login", &[], Some(credentials)).await;
+        tokens.push(String::from(body["token"].as_str().unwrap()));
+    }
+    let (alice_jwt, carol_jwt) = (
+        format!("Bearer {}", tokens[0]),
+        format!("Bearer {}", tokens[2]),
+    );
+    let uri = |token: &str| format!("ws://localhost:{}/chat/room1?token={}", PORT, token);
+
+    // alice joins first, owning the room
+    let (mut alice_stream, _) = connect_async(uri(&tokens[0]))
+        .await
+        .expect("Unable to connect as alice");
+    wait_for_join().await;
+    let (mut bob_stream, _) = connect_async(uri(&tokens[1]))
+        .await
+        .expect("Unable to connect as bob");
+    wait_for_join().await;
+
+    let mut message_ids = Vec::new();
+    for text in &["Spam", "Worse spam"] {
+        send_frame(&mut bob_stream, json!({ "type": "message", "text": text })).await;
+        message_ids.push(next_event(&mut bob_stream).await["id"].as_i64().unwrap());
+        assert_eq!(next_event(&mut alice_stream).await["text"], *text);
+    }
+
+    // Any user who may join the room reports its messages
+    let report = |message_id: i64| json!({ "message_id": message_id, "reason": "Spam" });
+    let (status, _) =
+        http_request(PORT, "POST", "/rooms/room1/reports", &[], Some(report(1))).await;
+    assert_eq!(status, 401);
+    let (status, _) = http_request(
+        PORT,
+        "POST",
+        "/rooms/room1/reports",
+        &[("Authorization", &carol_jwt)],
+        Some(report(999)),
+    )
+    .await;
+    assert_eq!(status, 404);
+    let mut report_ids = Vec::new();
+    for message_id in &message_ids {
+        let (status, body) = http_request(
+            PORT,
+            "POST",
+            "/rooms/room1/reports",
+            &[("Authorization", &carol_jwt)],
+            Some(report(*message_id)),
+        )
+        .await;
+        assert_eq!(status, 201);
+        assert_eq!(body["status"], "open");
+        report_ids.push(body["id"].as_i64().unwrap());
+    }
+
+    // Only moderators review them
+    let (status, _) = http_request(
+        PORT,
+        "GET",
+        "/rooms/room1/reports",
+        &[("Authorization", &carol_jwt)],
+        None,
+    )
+    .await;
+    assert_eq!(status, 403);
+    let (status, body) = http_request(
+        PORT,
+        "GET",
+        "/rooms/room1/reports",
+        &[("Authorization", &alice_jwt)],
+        None,
+    )
+    .await;
+    assert_eq!(status, 200);
+    assert_eq!(body.as_array().unwrap().len(), 2);
+    assert_eq!(body[0]["text"], "Spam");
+
+    // Deleting the message resolves the report
+    let path = format!("/rooms/room1/reports/{}", report_ids[0]);
+    let (status, body) = http_request(
+        PORT,
+        "PUT",
+        &path,
+        &[("Authorization", &alice_jwt)],
+        Some(json!({ "action": "delete" })),
+    )
+    .await;
+    assert_eq!(status, 200);
+    assert_eq!(body["status"], "actioned");
+    let event = next_event(&mut bob_stream).await;
+    assert_eq!(event["type"], "delete");
+    assert_eq!(event["id"], message_ids[0]);
+    let (status, _) = http_request(
+        PORT,
+        "PUT",
+        &path,
+        &[("Authorization", &alice_jwt)],
+        Some(json!({ "action": "dismiss" })),
+    )
+    .await;
+    assert_eq!(status, 409);
+
+    // As does banning its author
+    let path = format!("/rooms/room1/reports/{}", report_ids[1]);
+    let (status, _) = http_request(
+        PORT,
+        "PUT",
+        &path,
+        &[("Authorization", &alice_jwt)],
+        Some(json!({ "action": "ban", "reason": "Spam" })),
+    )
+    .await;
+    assert_eq!(status, 200);
+    loop {
+        match bob_stream.next().await {
+            Some(Ok(Message::Close(_))) | None => break,
+            Some(Ok(_)) => {}
+            Some(Err(e)) => panic!("Unexpected error: {}", e),
+        }
+    }
+    let (_, body) = http_request(
+        PORT,
+        "GET",
+        "/rooms/room1/reports",
+        &[("Authorization", &alice_jwt)],
+        None,
+    )
+    .await;
+    assert!(body.as_array().unwrap().is_empty());
+
+    remove_db(&db_path);
+}
+
+#[tokio::test]
+async fn flood_kick() {
+    const PORT: u16 = 3069;
+
+    let db_path = PathBuf::from("./main_flood_kick.db");
+    let config = Config {
+        flood_max_messages: 3,
+        ..Config::new(PORT, db_path.clone())
+    };
+    tokio::task::spawn(async move {
+        server::run_with_config(config).await;
+    });
+    wait_for_server(PORT).await;
+
+    let uri = format!("ws://localhost:{}/chat/room1", PORT);
+    let (mut flooder, _) = connect_async(&uri).await.expect("Unable to connect");
+    wait_for_join().await;
+    let (mut receiver, _) = connect_async(&uri).await.expect("Unable to connect");
+    wait_for_join().await;
+
+    for i in 0..3 {
+        let text = format!("Message {}", i);
+        send_frame(&mut flooder, json!({ "type": "message", "text": text })).await;
+        assert_eq!(next_event(&mut flooder).await["type"], "ack");
+        assert_eq!(next_event(&mut receiver).await["text"], text);
+    }
+
+    // The message over the quota is dropped, and its sender kicked
+    send_frame(
+        &mut flooder,
+        json!({ "type": "message", "text": "One more" }),
+    )
+    .await;
+    let event = next_event(&mut receiver).await;
+    assert_eq!(event["type"], "flood_kick");
+    assert_eq!(event["room"], "room1");
+    assert_eq!(event["max_messages"], 3);
+    assert_eq!(event["window_secs"], 10);
+
+    assert_eq!(next_event(&mut flooder).await["type"], "flood_kick");
+    match flooder.next().await {
+        Some(Ok(Message::Close(Some(frame)))) => {
+            assert_eq!(u16::from(frame.code), 1008);
+            assert!(frame.reason.contains("more than 3 messages in 10 seconds"));
+        }
+        other => panic!("Expected a close frame, got {:?}", other),
+    }
+
+    // The other connection of the room is left alone
+    send_frame(
+        &mut receiver,
+        json!({ "type": "message", "text": "Quiet now" }),
+    )
+    .await;
+    assert_eq!(next_event(&mut receiver).await["type"], "ack");
+
+    remove_db(&db_path);
+}
+
+#[tokio::test]
+async fn content_classifier() {
+    const PORT: u16 = 3070;
+    const CLASSIFIER_PORT: u16 = 3071;
+
+    // Blocks messages mentioning spam, and lets others through
+    let classifier = warp::post()
+        .and(warp::body::json())
+        .map(|submission: Value| {
+            assert_eq!(submission["room"], "room1");
+            if submission["text"].as_str().unwrap().contains("spam") {
+                warp::reply::json(&json!({ "verdict": "block", "reason": "Spam" }))
+            } else {
+                warp::reply::json(&json!({ "verdict": "allow" }))
+            }
+        });
+    tokio::task::spawn(warp::serve(classifier).run(([127, 0, 0, 1], CLASSIFIER_PORT)));
+
+    let db_path = PathBuf::from("./main_content_classifier.db");
+    let config = Config {
+        classifier_url: Some(format!("http://127.0.0.1:{}/classify", CLASSIFIER_PORT)),
+        ..Config::new(PORT, db_path.clone())
+    };
+    tokio::task::spawn(async move {
+        server::run_with_config(config).await;
+    });
+    wait_for_server(PORT).await;
+    wait_for_server(CLASSIFIER_PORT).await;
+
+    let uri = format!("ws://localhost:{}/chat/room1", PORT);
+    let (mut sender, _) = connect_async(&uri).await.expect("Unable to connect");
+    wait_for_join().await;
+    let (mut receiver, _) = connect_async(&uri).await.expect("Unable to connect");
+    wait_for_join().await;
+
+    send_frame(
+        &mut sender,
+        json!({ "type": "message", "text": "Cheap spam" }),
+    )
+    .await;
+    let event = next_event(&mut sender).await;
+    assert_eq!(event["type"], "error");
+    assert_eq!(event["reason"], "Message was blocked: Spam");
+
+    send_frame(&mut sender, json!({ "type": "message", "text": "Hello" })).await;
+    assert_eq!(next_event(&mut sender).await["type"], "ack");
+    assert_eq!(next_event(&mut receiver).await["text"], "Hello");
+
+    remove_db(&db_path);
+}
+
+#[tokio::test]
+async fn join_approval() {
+    const PORT: u16 = 3072;
+
+    let db_path = PathBuf::from("./main_join_approval.db");
+    let spawn_db_path = db_path.clone();
+    tokio::task::spawn(async move {
+        server::run(PORT, spawn_db_path).await;
+    });
+    wait_for_server(PORT).await;
+
+    let mut tokens = Vec::new();
+    let mut user_ids = Vec::new();
+    for username in &["alice", "bob", "carol"] {
+        let credentials = json!({ "username": username, "password": "correct horse" });
+        let (_, body) = http_request(
+            PORT,
+            "POST",
+            "/users/register",
+            &[],
+            Some(credentials.clone()),
+        )
+        .await;
+        user_ids.push(body["user_id"].as_u64().unwrap());
+        let (_, body) = http_request(PORT, "POST", "/users/login", &[], Some(credentials)).await;
+        tokens.push(String::from(body["token"].as_str().unwrap()));
+    }
+    let alice_jwt = format!("Bearer {}", tokens[0]);
+    let uri = |token: &str| format!("ws://localhost:{}/chat/club?token={}", PORT, token);
+
+    let club = json!({ "name": "club", "approval_required": true });
+    let (status, body) = http_request(
+        PORT,
+        "POST",
+        "/rooms",
+        &[("Authorization", &alice_jwt)],
+        Some(club),
+    )
+    .await;
+    assert_eq!(status, 201);
+    assert_eq!(body["approval_required"], true);
+
+    // alice owns the room, and gets in right away
+    let (mut alice_stream, _) = connect_async(uri(&tokens[0]))
+        .await
+        .expect("Unable to connect as alice");
+    wait_for_join().await;
+
+    // bob waits, and moderators are told
+    let (mut bob_stream, _) = connect_async(uri(&tokens[1]))
+        .await
+        .expect("Unable to connect as bob");
+    let event = next_event(&mut bob_stream).await;
+    assert_eq!(event["type"], "join_pending");
+    assert_eq!(event["room"], "club");
+    let event = next_event(&mut alice_stream).await;
+    assert_eq!(event["type"], "join_request");
+    assert_eq!(event["user_id"], user_ids[1]);
+
+    // Only moderators may approve, and bob is sent nothing until approved
+    send_frame(
+        &mut bob_stream,
+        json!({ "type": "message", "text": "Let me in" }),
+    )
+    .await;
+    wait_for_join().await;
+    send_frame(
+        &mut alice_stream,
+        json!({ "type": "approve_join", "user_id": user_ids[1] }),
+    )
+    .await;
+    let event = next_raw_event(&mut alice_stream).await;
+    assert_eq!(event["type"], "join");
+    assert_eq!(event["user_id"], user_ids[1]);
+
+    send_frame(
+        &mut bob_stream,
+        json!({ "type": "message", "text": "Thanks" }),
+    )
+    .await;
+    assert_eq!(next_event(&mut bob_stream).await["type"], "ack");
+    assert_eq!(next_event(&mut alice_stream).await["text"], "Thanks");
+
+    // carol is turned away
+    let (mut carol_stream, _) = connect_async(uri(&tokens[2]))
+        .await
+        .expect("Unable to connect as carol");
+    assert_eq!(next_event(&mut carol_stream).await["type"], "join_pending");
+    assert_eq!(next_event(&mut alice_stream).await["type"], "join_request");
+    send_frame(
+        &mut bob_stream,
+        json!({ "type": "reject_join", "user_id": user_ids[2] }),
+    )
+    .await;
+    assert_eq!(next_event(&mut bob_stream).await["type"], "error");
+    send_frame(
+        &mut alice_stream,
+        json!({ "type": "reject_join", "user_id": user_ids[2] }),
+    )
+    .await;
+    match carol_stream.next().await {
+        Some(Ok(Message::Close(Some(frame)))) => {
+            assert_eq!(u16::from(frame.code), 4003);
+            assert_eq!(frame.reason, "Join request rejected");
+        }
+        other => panic!("Expected a close frame, got {:?}", other),
+    }
+
+    // bob is now a member, and gets in right away
+    bob_stream.close(None).await.unwrap();
+    let (mut bob_stream, _) = connect_async(uri(&tokens[1]))
+        .await
+        .expect("Unable to connect as bob");
+    wait_for_join().await;
+    send_frame(
+        &mut bob_stream,
+        json!({ "type": "message", "text": "Back" }),
+    )
+    .await;
+    assert_eq!(next_event(&mut alice_stream).await["text"], "Back");
+
+    remove_db(&db_path);
+}
+
+#[tokio::test]
+async fn room_presence() {
+    const PORT: u16 = 3073;
+
+    let db_path = PathBuf::from("./main_room_presence.db");
+    let spawn_db_path = db_path.clone();
+    tokio::task::spawn(async move {
+        server::run(PORT, spawn_db_path).await;
+    });
+    wait_for_server(PORT).await;
+
+    let mut tokens = Vec::new();
+    let mut user_ids = Vec::new();
+    for username in &["alice", "bob"] {
+        let credentials = json!({ "username": username, "password": "correct horse" });
+        let (_, body) = http_request(
+            PORT,
+            "POST",
+            "/users/register",
+            &[],
+            Some(credentials.clone()),
+        )
+        .await;
+        user_ids.push(body["user_id"].as_u64().unwrap());
+        let (_, body) = http_request(PORT, "POST", "/users/login", &[], Some(credentials)).await;
+        tokens.push(String::from(body["token"].as_str().unwrap()));
+    }
+    let alice_jwt = format!("Bearer {}", tokens[0]);
+    let uri = |token: &str| format!("ws://localhost:{}/chat/room1?token={}", PORT, token);
+
+    let (mut alice_stream, _) = connect_async(uri(&tokens[0]))
+        .await
+        .expect("Unable to connect as alice");
+    wait_for_join().await;
+    assert_eq!(next_raw_event(&mut alice_stream).await["type"], "join");
+    let event = next_raw_event(&mut alice_stream).await;
+    assert_eq!(event["type"], "presence");
+    assert_eq!(
+        event["users"],
+        json!([{ "user_id": user_ids[0], "status": "online" }])
+    );
+
+    // Users joining are told who is online, and those online of them
+    let (mut bob_stream, _) = connect_async(uri(&tokens[1]))
+        .await
+        .expect("Unable to connect as bob");
+    wait_for_join().await;
+    let event = next_raw_event(&mut alice_stream).await;
+    assert_eq!(event["type"], "join");
+    assert_eq!(event["user_id"], user_ids[1]);
+    assert_eq!(next_raw_event(&mut bob_stream).await["type"], "join");
+    let event = next_raw_event(&mut bob_stream).await;
+    assert_eq!(event["type"], "presence");
+    assert_eq!(
+        event["users"],
+        json!([
+            { "user_id": user_ids[0], "status": "online" },
+            { "user_id": user_ids[1], "status": "online" }
+        ])
+    );
+
+    // Polling clients can ask as well
+    let (status, _) = http_request(PORT, "GET", "/rooms/room1/online", &[], None).await;
+    assert_eq!(status, 401);
+    let (status, body) = http_request(
+        PORT,
+        "GET",
+        "/rooms/room1/online",
+        &[("Authorization", &alice_jwt)],
+        None,
+    )
+    .await;
+    assert_eq!(status, 200);
+    assert_eq!(body.as_array().unwrap().len(), 2);
+
+    // Users leaving are announced once their last connection is gone, along
+    // with their nickname
+    send_frame(
+        &mut bob_stream,
+        json!({ "type": "set_nick", "nick": "bobby" }),
+    )
+    .await;
+    assert_eq!(next_raw_event(&mut alice_stream).await["type"], "rename");
+    bob_stream.close(None).await.unwrap();
+    let event = next_raw_event(&mut alice_stream).await;
+    assert_eq!(event["type"], "leave");
+    assert_eq!(event["user_id"], user_ids[1]);
+    assert_eq!(event["nick"], "bobby");
+
+    let (_, body) = http_request(
+        PORT,
+        "GET",
+        "/rooms/room1/online",
+        &[("Authorization", &alice_jwt)],
+        None,
+    )
+    .await;
+    assert_eq!(
+        body,
+        json!([{ "user_id": user_ids[0], "status": "online" }])
+    );
+
+    remove_db(&db_path);
+}
+
+#[tokio::test]
+async fn read_receipts() {
+    const PORT: u16 = 3074;
+
+    let db_path = PathBuf::from("./main_read_receipts.db");
+    let spawn_db_path = db_path.clone();
+    tokio::task::spawn(async move {
+        server::run(PORT, spawn_db_path).await;
+    });
+    wait_for_server(PORT).await;
+
+    let mut tokens = Vec::new();
+    let mut user_ids = Vec::new();
+    for username in &["alice", "bob"] {
+        let credentials = json!({ "username": username, "password": "correct horse" });
+        let (_, body) = http_request(
+            PORT,
+            "POST",
+            "/users/register",
+            &[],
+            Some(credentials.clone()),
+        )
+        .await;
+        user_ids.push(body["user_id"].as_u64().unwrap());
+        let (_, body) = http_request(PORT, "POST", "/users/login", &[], Some(credentials)).await;
+        tokens.push(String::from(body["token"].as_str().unwrap()));
+    }
+    let alice_jwt = format!("Bearer {}", tokens[0]);
+    let uri = |token: &str| format!("ws://localhost:{}/chat/room1?token={}", PORT, token);
+
+    let (mut alice_stream, _) = connect_async(uri(&tokens[0]))
+        .await
+        .expect("Unable to connect as alice");
+    wait_for_join().await;
+    let (mut bob_stream, _) = connect_async(uri(&tokens[1]))
+        .await
+        .expect("Unable to connect as bob");
+    wait_for_join().await;
+
+    send_frame(
+        &mut alice_stream,
+        json!({ "type": "message", "text": "Hi" }),
+    )
+    .await;
+    let ack = next_event(&mut alice_stream).await;
+    assert_eq!(ack["type"], "ack");
+    let id = ack["id"].as_i64().unwrap();
+    assert_eq!(next_event(&mut bob_stream).await["id"], id);
+
+    // Others in the room are told what a user has seen
+    send_frame(&mut bob_stream, json!({ "type": "read", "id": id })).await;
+    let event = next_event(&mut alice_stream).await;
+    assert_eq!(event["type"], "read");
+    assert_eq!(event["user_id"], user_ids[1]);
+    assert_eq!(event["id"], id);
+
+    // Markers which do not move forward are not relayed
+    send_frame(&mut bob_stream, json!({ "type": "read", "id": id })).await;
+    send_frame(&mut bob_stream, json!({ "type": "message", "text": "Hey" })).await;
+    let event = next_event(&mut alice_stream).await;
+    assert_eq!(event["type"], "message");
+    assert_eq!(event["text"], "Hey");
+
+    let (status, _) = http_request(PORT, "GET", "/rooms/room1/read_markers", &[], None).await;
+    assert_eq!(status, 401);
+    let (status, body) = http_request(
+        PORT,
+        "GET",
+        "/rooms/room1/read_markers",
+        &[("Authorization", &alice_jwt)],
+        None,
+    )
+    .await;
+    assert_eq!(status, 200);
+    assert_eq!(body.as_array().unwrap().len(), 1);
+    assert_eq!(body[0]["user_id"], user_ids[1]);
+    assert_eq!(body[0]["id"], id);
+
+    // Messages sent since the marker are unread
+    send_frame(
+        &mut alice_stream,
+        json!({ "type": "message", "text": "More" }),
+    )
+    .await;
+    assert_eq!(next_event(&mut alice_stream).await["type"], "ack");
+    let bob_jwt = format!("Bearer {}", tokens[1]);
+    let (status, body) = http_request(
+        PORT,
+        "GET",
+        "/users/me/unread",
+        &[("Authorization", &bob_jwt)],
+        None,
+    )
+    .await;
+    assert_eq!(status, 200);
+    assert_eq!(
+        body,
+        json!([{ "room": "room1", "unread": 2, "last_read_id": id }])
+    );
+
+    remove_db(&db_path);
+}
+
+#[tokio::test]
+async fn presence_status() {
+    const PORT: u16 = 3075;
+
+    let db_path = PathBuf::from("./main_presence_status.db");
+    let config = Config {
+        away_after_secs: 1,
+        offline_after_secs: 2,
+        ..Config::new(PORT, db_path.clone())
+    };
+    tokio::task::spawn(async move {
+        server::run_with_config(config).await;
+    });
+    wait_for_server(PORT).await;
+
+    let mut tokens = Vec::new();
+    let mut user_ids = Vec::new();
+    for username in &["alice", "bob"] {
+        let credentials = json!({ "username": username, "password": "correct horse" });
+        let (_, body) = http_request(
+            PORT,
+            "POST",
+            "/users/register",
+            &[],
+            Some(credentials.clone()),
+        )
+        .await;
+        user_ids.push(body["user_id"].as_u64().unwrap());
+        let (_, body) = http_request(PORT, "POST", "/users/login", &[], Some(credentials)).await;
+        tokens.push(String::from(body["token"].as_str().unwrap()));
+    }
+    let alice_jwt = format!("Bearer {}", tokens[0]);
+    let uri = |token: &str| format!("ws://localhost:{}/chat/room1?token={}", PORT, token);
+
+    let (mut alice_stream, _) = connect_async(uri(&tokens[0]))
+        .await
+        .expect("Unable to connect as alice");
+    wait_for_join().await;
+    let (mut bob_stream, _) = connect_async(uri(&tokens[1]))
+        .await
+        .expect("Unable to connect as bob");
+    wait_for_join().await;
+    for _ in 0..3 {
+        next_raw_event(&mut alice_stream).await;
+    }
+
+    // Silent connections go away, in the order they joined
+    for user_id in &user_ids {
+        let event = next_raw_event(&mut alice_stream).await;
+        assert_eq!(event["type"], "status");
+        assert_eq!(event["user_id"], *user_id);
+        assert_eq!(event["status"], "away");
+    }
+
+    // Heartbeats bring them back online
+    send_frame(&mut alice_stream, json!({ "type": "heartbeat" })).await;
+    wait_for_join().await;
+    send_frame(&mut bob_stream, json!({ "type": "heartbeat" })).await;
+    for user_id in &user_ids {
+        let event = next_raw_event(&mut alice_stream).await;
+        assert_eq!(event["type"], "status");
+        assert_eq!(event["user_id"], *user_id);
+        assert_eq!(event["status"], "online");
+    }
+
+    let (_, body) = http_request(
+        PORT,
+        "GET",
+        "/rooms/room1/online",
+        &[("Authorization", &alice_jwt)],
+        None,
+    )
+    .await;
+    assert_eq!(body[1]["status"], "online");
+
+    // Connections silent for longer are closed
+    loop {
+        match bob_stream.next().await {
+            Some(Ok(Message::Close(Some(frame)))) => {
+                assert_eq!(u16::from(frame.code), 4008);
+                break;
+            }
+            Some(Ok(_)) => {}
+            other => panic!("Expected connection to be closed, got {:?}", other),
+        }
+    }
+
+    remove_db(&db_path);
+}
+
+#[tokio::test]
+async fn mentions() {
+    const PORT: u16 = 3076;
+
+    let db_path = PathBuf::from("./main_mentions.db");
+    let spawn_db_path = db_path.clone();
+    tokio::task::spawn(async move {
+        server::run(PORT, spawn_db_path).await;
+    });
+    wait_for_server(PORT).await;
+
+    let mut tokens = Vec::new();
+    let mut user_ids = Vec::new();
+    for username in &["alice", "bob"] {
+        let credentials = json!({ "username": username, "password": "correct horse" });
+        let (_, body) = http_request(
+            PORT,
+            "POST",
+            "/users/register",
+            &[],
+            Some(credentials.clone()),
+        )
+        .await;
+        user_ids.push(body["user_id"].as_u64().unwrap());
+        let (_, body) = http_request(PORT, "POST", "/users/login", &[], Some(credentials)).await;
+        tokens.push(String::from(body["token"].as_str().unwrap()));
+    }
+    let uri =
+        |room: &str, token: &str| format!("ws://localhost:{}/chat/{}?token={}", PORT, room, token);
+
+    let (mut alice_stream, _) = connect_async(uri("room1", &tokens[0]))
+        .await
+        .expect("Unable to connect as alice");
+    wait_for_join().await;
+    let (mut bob_stream, _) = connect_async(uri("room2", &tokens[1]))
+        .await
+        .expect("Unable to connect as bob");
+    wait_for_join().await;
+
+    // Users mentioned are notified in whichever room they are
+    send_frame(
+        &mut alice_stream,
+        json!({ "type": "message", "text": "Hi @Bob, and @nobody" }),
+    )
+    .await;
+    let id = next_event(&mut alice_stream).await["id"].clone();
+    let event = next_event(&mut bob_stream).await;
+    assert_eq!(event["type"], "mention");
+    assert_eq!(event["id"], id);
+    assert_eq!(event["room"], "room1");
+    assert_eq!(event["user_id"], user_ids[0]);
+    assert_eq!(event["text"], "Hi @Bob, and @nobody");
+
+    // Mentioning oneself notifies no one
+    send_frame(
+        &mut alice_stream,
+        json!({ "type": "message", "text": "Note to @alice" }),
+    )
+    .await;
+    assert_eq!(next_event(&mut alice_stream).await["type"], "ack");
+    send_frame(&mut bob_stream, json!({ "type": "message", "text": "Hey" })).await;
+    assert_eq!(next_event(&mut bob_stream).await["type"], "ack");
+
+    remove_db(&db_path);
+}
+
+#[tokio::test]
+async fn keyword_alerts() {
+    const PORT: u16 = 3077;
+
+    let db_path = PathBuf::from("./main_keyword_alerts.db");
+    let spawn_db_path = db_path.clone();
+    tokio::task::spawn(async move {
+        server::run(PORT, spawn_db_path).await;
+    });
+    wait_for_server(PORT).await;
+
+    let mut tokens = Vec::new();
+    let mut user_ids = Vec::new();
+    for username in &["alice", "bob"] {
+        let credentials = json!({ "username": username, "password": "correct horse" });
+        let (_, body) = http_request(
+            PORT,
+            "POST",
+            "/users/register",
+            &[],
+            Some(credentials.clone()),
+        )
+        .await;
+        user_ids.push(body["user_id"].as_u64().unwrap());
+        let (_, body) = http_request(PORT, "POST", "/users/login", &[], Some(credentials)).await;
+        tokens.push(String::from(body["token"].as_str().unwrap()));
+    }
+    let bob_jwt = format!("Bearer {}", tokens[1]);
+    let keywords_path = format!("/users/{}/keywords", user_ids[1]);
+    let alerts_path = format!("/users/{}/alerts", user_ids[1]);
+
+    // Users only manage their own keywords
+    let (status, _) = http_request(
+        PORT,
+        "PUT",
+        &keywords_path,
+        &[("Authorization", &format!("Bearer {}", tokens[0]))],
+        Some(json!({ "keywords": ["rust"] })),
+    )
+    .await;
+    assert_eq!(status, 403);
+    let (status, body) = http_request(
+        PORT,
+        "PUT",
+        &keywords_path,
+        &[("Authorization", &bob_jwt)],
+        Some(json!({ "keywords": ["rust", "release notes"] })),
+    )
+    .await;
+    assert_eq!(status, 200);
+    assert_eq!(body["keywords"], json!(["rust", "release notes"]));
+
+    // Alerts are recorded while users are offline
+    let uri =
+        |room: &str, token: &str| format!("ws://localhost:{}/chat/{}?token={}", PORT, room, token);
+    let (mut alice_stream, _) = connect_async(uri("room1", &tokens[0]))
+        .await
+        .expect("Unable to connect as alice");
+    wait_for_join().await;
+    send_frame(
+        &mut alice_stream,
+        json!({ "type": "message", "text": "Rust 2.0 is out" }),
+    )
+    .await;
+    let first_id = next_event(&mut alice_stream).await["id"].clone();
+    // Alerts are recorded after the message is acknowledged
+    loop {
+        let (_, body) = http_request(
+            PORT,
+            "GET",
+            &alerts_path,
+            &[("Authorization", &bob_jwt)],
+            None,
+        )
+        .await;
+        if !body.as_array().unwrap().is_empty() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    // And sent to them once online, in whichever room. Users are told who is
+    // in their room once they have joined it
+    let (mut bob_stream, _) = connect_async(uri("room2", &tokens[1]))
+        .await
+        .expect("Unable to connect as bob");
+    while next_raw_event(&mut bob_stream).await["type"] != "presence" {}
+    send_frame(
+        &mut alice_stream,
+        json!({ "type": "message", "text": "Read the release notes" }),
+    )
+    .await;
+    let second_id = next_event(&mut alice_stream).await["id"].clone();
+    let event = next_event(&mut bob_stream).await;
+    assert_eq!(event["type"], "alert");
+    assert_eq!(event["id"], second_id);
+    assert_eq!(event["room"], "room1");
+    assert_eq!(event["user_id"], user_ids[0]);
+    assert_eq!(event["keyword"], "release notes");
+
+    let (status, body) = http_request(
+        PORT,
+        "GET",
+        &alerts_path,
+        &[("Authorization", &bob_jwt)],
+        None,
+    )
+    .await;
+    assert_eq!(status, 200);
+    let message_ids = body
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|alert| alert["message_id"].clone())
+        .collect::<Vec<_>>();
+    assert_eq!(message_ids, vec![second_id, first_id]);
+
+    remove_db(&db_path);
+}
+
+#[tokio::test]
+async fn notification_levels() {
+    const PORT: u16 = 3078;
+
+    let db_path = PathBuf::from("./main_notification_levels.db");
+    let spawn_db_path = db_path.clone();
+    tokio::task::spawn(async move {
+        server::run(PORT, spawn_db_path).await;
+    });
+    wait_for_server(PORT).await;
+
+    let mut tokens = Vec::new();
+    let mut user_ids = Vec::new();
+    for username in &["alice", "bob"] {
+        let credentials = json!({ "username": username, "password": "correct horse" });
+        let (_, body) = http_request(
+            PORT,
+            "POST",
+            "/users/register",
+            &[],
+            Some(credentials.clone()),
+        )
+        .await;
+        user_ids.push(body["user_id"].as_u64().unwrap());
+        let (_, body) = http_request(PORT, "POST", "/users/login", &[], Some(credentials)).await;
+        tokens.push(String::from(body["token"].as_str().unwrap()));
+    }
+    let bob_jwt = format!("Bearer {}", tokens[1]);
+    http_request(
+        PORT,
+        "PUT",
+        &format!("/users/{}/keywords", user_ids[1]),
+        &[("Authorization", &bob_jwt)],
+        Some(json!({ "keywords": ["rust"] })),
+    )
+    .await;
+    let level_path = format!("/users/{}/notifications/room1", user_ids[1]);
+    let (status, body) = http_request(
+        PORT,
+        "PUT",
+        &level_path,
+        &[("Authorization", &bob_jwt)],
+        Some(json!({ "level": "mentions" })),
+    )
+    .await;
+    assert_eq!(status, 200);
+    assert_eq!(body, json!({ "room": "room1", "level": "mentions" }));
+
+    let uri =
+        |room: &str, token: &str| format!("ws://localhost:{}/chat/{}?token={}", PORT, room, token);
+    let (mut alice_stream, _) = connect_async(uri("room1", &tokens[0]))
+        .await
+        .expect("Unable to connect as alice");
+    wait_for_join().await;
+    let (mut bob_room1, _) = connect_async(uri("room1", &tokens[1]))
+        .await
+        .expect("Unable to connect as bob");
+    wait_for_join().await;
+    let (mut bob_room2, _) = connect_async(uri("room2", &tokens[1]))
+        .await
+        .expect("Unable to connect as bob");
+    wait_for_join().await;
+
+    // Rooms set to mentions only raise no alerts
+    send_frame(
+        &mut alice_stream,
+        json!({ "type": "message", "text": "rust, @bob" }),
+    )
+    .await;
+    let event = next_event(&mut bob_room2).await;
+    assert_eq!(event["type"], "mention");
+    send_frame(&mut bob_room2, json!({ "type": "message", "text": "Hi" })).await;
+    assert_eq!(next_event(&mut bob_room2).await["type"], "ack");
+
+    // Muted rooms raise nothing, but their messages are still delivered
+    http_request(
+        PORT,
+        "PUT",
+        &level_path,
+        &[("Authorization", &bob_jwt)],
+        Some(json!({ "level": "muted" })),
+    )
+    .await;
+    send_frame(
+        &mut alice_stream,
+        json!({ "type": "message", "text": "rust again, @bob" }),
+    )
+    .await;
+    assert_eq!(next_event(&mut bob_room1).await["text"], "rust, @bob");
+    assert_eq!(next_event(&mut bob_room1).await["type"], "mention");
+    let event = next_event(&mut bob_room1).await;
+    assert_eq!(event["type"], "message");
+    assert_eq!(event["text"], "rust again, @bob");
+    wait_for_join().await;
+    send_frame(&mut bob_room2, json!({ "type": "message", "text": "Hi" })).await;
+    assert_eq!(next_event(&mut bob_room2).await["type"], "ack");
+
+    let (_, body) = http_request(
+        PORT,
+        "GET",
+        &format!("/users/{}/notifications", user_ids[1]),
+        &[("Authorization", &bob_jwt)],
+        None,
+    )
+    .await;
+    assert_eq!(body, json!([{ "room": "room1", "level": "muted" }]));
+
+    remove_db(&db_path);
+}
+
+#[tokio::test]
+async fn conversations() {
+    const PORT: u16 = 3079;
+
+    let db_path = PathBuf::from("./main_conversations.db");
+    let spawn_db_path = db_path.clone();
+    tokio::task::spawn(async move {
+        server::run(PORT, spawn_db_path).await;
+    });
+    wait_for_server(PORT).await;
+
+    let mut user_ids = Vec::new();
+    let mut tokens = Vec::new();
+    for username in &["alice", "bob", "carol"] {
+        let credentials = json!({ "username": username, "password": "correct horse" });
+        let (_, body) = http_request(
+            PORT,
+            "POST",
+            "/users/register",
+            &[],
+            Some(credentials.clone()),
+        )
+        .await;
+        user_ids.push(body["user_id"].as_u64().unwrap());
+        let (_, body) = http_request(PORT, "POST", "/users/login", &[], Some(credentials)).await;
+        tokens.push(String::from(body["token"].as_str().unwrap()));
+    }
+    let (alice, bob) = (user_ids[0], user_ids[1]);
+    let (alice_jwt, bob_jwt) = (
+        format!("Bearer {}", tokens[0]),
+        format!("Bearer {}", tokens[1]),
+    );
+    let uri =
+        |room: &str, token: &str| format!("ws://localhost:{}/chat/{}?token={}", PORT, room, token);
+    let refused = |frame: Option<Result<Message, tungstenite::Error>>, code: u16| match frame {
+        Some(Ok(Message::Close(Some(frame)))) => assert_eq!(u16::from(frame.code), code),
+        other => panic!("Expected connection to be closed, got {:?}", other),
+    };
+
+    let (status, _) = http_request(
+        PORT,
+        "POST",
+        "/conversations",
+        &[("Authorization", &alice_jwt)],
+        Some(json!({ "user_ids": [bob, 42] })),
+    )
+    .await;
+    assert_eq!(status, 404);
+    let (status, conversation) = http_request(
+        PORT,
+        "POST",
+        "/conversations",
+        &[("Authorization", &alice_jwt)],
+        Some(json!({ "user_ids": [bob] })),
+    )
+    .await;
+    assert_eq!(status, 201);
+    assert_eq!(conversation["created_by"], alice);
+    assert_eq!(conversation["participants"], json!([alice, bob]));
+    let room = conversation["room"].as_str().unwrap();
+    assert!(room.starts_with("dm:"));
+
+    // Only participants are let in
+    let (mut alice_stream, _) = connect_async(uri(room, &tokens[0]))
+        .await
+        .expect("Unable to connect as alice");
+    wait_for_join().await;
+    let (mut bob_stream, _) = connect_async(uri(room, &tokens[1]))
+        .await
+        .expect("Unable to connect as bob");
+    wait_for_join().await;
+    let (mut carol_stream, _) = connect_async(uri(room, &tokens[2]))
+        .await
+        .expect("Unable to connect as carol");
+    refused(carol_stream.next().await, 4003);
+
+    send_frame(
+        &mut alice_stream,
+        json!({ "type": "message", "text": "Hi" }),
+    )
+    .await;
+    let event = next_event(&mut bob_stream).await;
+    assert_eq!(event["type"], "message");
+    assert_eq!(event["text"], "Hi");
+
+    // Conversations are not created on join, nor through room routes
+    let (mut stream, _) = connect_async(uri("dm:made-up", &tokens[0]))
+        .await
+        .expect("Unable to connect as alice");
+    refused(stream.next().await, 4004);
+    let (status, _) = http_request(
+        PORT,
+        "POST",
+        "/rooms",
+        &[("Authorization", &alice_jwt)],
+        Some(json!({ "name": "dm:made-up" })),
+    )
+    .await;
+    assert_eq!(status, 400);
+
+    // Conversations are left out of room discovery
+    let (_, body) = http_request(PORT, "GET", "/rooms", &[], None).await;
+    assert_eq!(body, json!([]));
+
+    let (status, body) = http_request(
+        PORT,
+        "GET",
+        "/conversations",
+        &[("Authorization", &bob_jwt)],
+        None,
+    )
+    .await;
+    assert_eq!(status, 200);
+    assert_eq!(body, json!([conversation]));
+
+    remove_db(&db_path);
+}
+
+#[tokio::test]
+async fn multiplexed_rooms() {
+    const PORT: u16 = 3080;
+
+    let db_path = PathBuf::from("./main_multiplexed_rooms.db");
+    let spawn_db_path = db_path.clone();
+    tokio::task::spawn(async move {
+        server::run(PORT, spawn_db_path).await;
+    });
+    wait_for_server(PORT).await;
+
+    let mut user_ids = Vec::new();
+    let mut tokens = Vec::new();
+    for username in &["alice", "bob"] {
+        let credentials = json!({ "username": username, "password": "correct horse" });
+        let (_, body) = http_request(
+            PORT,
+            "POST",
+            "/users/register",
+            &[],
+            Some(credentials.clone()),
+        )
+        .await;
+        user_ids.push(body["user_id"].as_u64().unwrap());
+        let (_, body) = http_request(PORT, "POST", "/users/login", &[], Some(credentials)).await;
+        tokens.push(String::from(body["token"].as_str().unwrap()));
+    }
+    let alice = user_ids[0];
+    let uri =
+        |room: &str, token: &str| format!("ws://localhost:{}/chat/{}?token={}", PORT, room, token);
+
+    let (mut alice_stream, _) = connect_async(uri("room1", &tokens[0]))
+        .await
+        .expect("Unable to connect as alice");
+    wait_for_join().await;
+    let (mut bob_stream, _) = connect_async(uri("room2", &tokens[1]))
+        .await
+        .expect("Unable to connect as bob");
+    wait_for_join().await;
+
+    // One connection can be in several rooms, with every event naming its room
+    send_frame(
+        &mut alice_stream,
+        json!({ "type": "join", "room": "room2" }),
+    )
+    .await;
+    wait_for_join().await;
+    send_frame(
+        &mut bob_stream,
+        json!({ "type": "message", "text": "Hi alice" }),
+    )
+    .await;
+    let event = next_event(&mut alice_stream).await;
+    assert_eq!(event["type"], "message");
+    assert_eq!(event["room"], "room2");
+    assert_eq!(event["text"], "Hi alice");
+    assert_eq!(next_event(&mut bob_stream).await["type"], "ack");
+
+    send_frame(
+        &mut alice_stream,
+        json!({ "type": "message", "room": "room2", "text": "Hi bob" }),
+    )
+    .await;
+    let event = next_event(&mut alice_stream).await;
+    assert_eq!(event["type"], "ack");
+    assert_eq!(event["room"], "room2");
+    assert_eq!(next_event(&mut bob_stream).await["text"], "Hi bob");
+
+    send_frame(
+        &mut alice_stream,
+        json!({ "type": "message", "room": "room3", "text": "Hi" }),
+    )
+    .await;
+    let event = next_event(&mut alice_stream).await;
+    assert_eq!(event["type"], "error");
+    assert_eq!(event["room"], "room3");
+
+    // Rooms refusing the connection, or kicking it, only close it to them
+    send_frame(
+        &mut alice_stream,
+        json!({ "type": "join", "room": "dm:made-up" }),
+    )
+    .await;
+    let event = next_event(&mut alice_stream).await;
+    assert_eq!(event["type"], "left");
+    assert_eq!(event["room"], "dm:made-up");
+    assert_eq!(event["code"], 4004);
+
+    send_frame(&mut bob_stream, json!({ "type": "kick", "user_id": alice })).await;
+    assert_eq!(next_event(&mut alice_stream).await["type"], "kick");
+    let event = next_event(&mut alice_stream).await;
+    assert_eq!(event["type"], "left");
+    assert_eq!(event["room"], "room2");
+    assert_eq!(event["code"], 4000);
+    assert_eq!(next_event(&mut bob_stream).await["type"], "kick");
+
+    send_frame(
+        &mut alice_stream,
+        json!({ "type": "message", "text": "Still here" }),
+    )
+    .await;
+    let event = next_event(&mut alice_stream).await;
+    assert_eq!(event["type"], "ack");
+    assert_eq!(event["room"], "room1");
+
+    // Rooms joined again replay their history, until left
+    send_frame(
+        &mut alice_stream,
+        json!({ "type": "join", "room": "room2" }),
+    )
+    .await;
+    assert_eq!(next_event(&mut alice_stream).await["text"], "Hi alice");
+    assert_eq!(next_event(&mut alice_stream).await["text"], "Hi bob");
+    send_frame(
+        &mut alice_stream,
+        json!({ "type": "leave", "room": "room2" }),
+    )
+    .await;
+    let event = next_event(&mut alice_stream).await;
+    assert_eq!(event["type"], "left");
+    assert_eq!(event["code"], 1000);
+
+    send_frame(
+        &mut bob_stream,
+        json!({ "type": "message", "text": "Gone?" }),
+    )
+    .await;
+    assert_eq!(next_event(&mut bob_stream).await["type"], "ack");
+    send_frame(
+        &mut alice_stream,
+        json!({ "type": "message", "text": "Yes" }),
+    )
+    .await;
+    let event = next_event(&mut alice_stream).await;
+    assert_eq!(event["type"], "ack");
+    assert_eq!(event["room"], "room1");
+
+    remove_db(&db_path);
+}
+
+#[tokio::test]
+async fn offline_delivery() {
+    const PORT: u16 = 3081;
+
+    let db_path = PathBuf::from("./main_offline_delivery.db");
+    let spawn_db_path = db_path.clone();
+    tokio::task::spawn(async move {
+        server::run(PORT, spawn_db_path).await;
+    });
+    wait_for_server(PORT).await;
+
+    let mut user_ids = Vec::new();
+    let mut tokens = Vec::new();
+    for username in &["alice", "bob"] {
+        let credentials = json!({ "username": username, "password": "correct horse" });
+        let (_, body) = http_request(
+            PORT,
+            "POST",
+            "/users/register",
+            &[],
+            Some(credentials.clone()),
+        )
+        .await;
+        user_ids.push(body["user_id"].as_u64().unwrap());
+        let (_, body) = http_request(PORT, "POST", "/users/login", &[], Some(credentials)).await;
+        tokens.push(String::from(body["token"].as_str().unwrap()));
+    }
+    let (alice, bob) = (user_ids[0], user_ids[1]);
+    let uri =
+        |room: &str, token: &str| format!("ws://localhost:{}/chat/{}?token={}", PORT, room, token);
+
+    let (_, conversation) = http_request(
+        PORT,
+        "POST",
+        "/conversations",
+        &[("Authorization", &format!("Bearer {}", tokens[0]))],
+        Some(json!({ "user_ids": [bob] })),
+    )
+    .await;
+    let dm = conversation["room"].as_str().unwrap();
+
+    // Mentions and direct messages are queued while bob is offline
+    let (mut alice_stream, _) = connect_async(uri("room1", &tokens[0]))
+        .await
+        .expect("Unable to connect as alice");
+    wait_for_join().await;
+    send_frame(
+        &mut alice_stream,
+        json!({ "type": "message", "text": "Hi @bob" }),
+    )
+    .await;
+    assert_eq!(next_event(&mut alice_stream).await["type"], "ack");
+    let (mut alice_dm, _) = connect_async(uri(dm, &tokens[0]))
+        .await
+        .expect("Unable to connect as alice");
+    wait_for_join().await;
+    send_frame(&mut alice_dm, json!({ "type": "message", "text": "Psst" })).await;
+    assert_eq!(next_event(&mut alice_dm).await["type"], "ack");
+
+    // ...and sent once he connects, after the history of his room
+    let (mut bob_stream, _) = connect_async(uri("room2", &tokens[1]))
+        .await
+        .expect("Unable to connect as bob");
+    let event = next_event(&mut bob_stream).await;
+    assert_eq!(event["type"], "mention");
+    assert_eq!(event["room"], "room1");
+    assert_eq!(event["user_id"], alice);
+    let event = next_event(&mut bob_stream).await;
+    assert_eq!(event["type"], "message");
+    assert_eq!(event["room"], dm);
+    assert_eq!(event["text"], "Psst");
+
+    // Queues are emptied once delivered
+    let (mut bob_again, _) = connect_async(uri("room2", &tokens[1]))
+        .await
+        .expect("Unable to connect as bob");
+    wait_for_join().await;
+    send_frame(&mut bob_again, json!({ "type": "message", "text": "Hi" })).await;
+    assert_eq!(next_event(&mut bob_again).await["type"], "ack");
+    assert_eq!(next_event(&mut bob_stream).await["text"], "Hi");
+
+    remove_db(&db_path);
+}
+
+#[tokio::test]
+// Tests that reconnecting clients are sent every message they missed.
+async fn resume_after_reconnect() {
+    const PORT: u16 = 3082;
+
+    let db_path = PathBuf::from("./main_resume.db");
+    let config = Config {
+        history_limit: 1,
+        ..Config::new(PORT, db_path.clone())
+    };
+    tokio::task::spawn(async move {
+        server::run_with_config(config).await;
+    });
+    wait_for_server(PORT).await;
+
+    let (_, body) = http_request(
+        PORT,
+        "POST",
+        "/users/register",
+        &[],
+        Some(json!({ "username": "alice", "password": "correct horse" })),
+    )
+    .await;
+    assert!(body["user_id"].is_u64());
+    let (_, body) = http_request(
+        PORT,
+        "POST",
+        "/users/login",
+        &[],
+        Some(json!({ "username": "alice", "password": "correct horse" })),
+    )
+    .await;
+    let token = String::from(body["token"].as_str().unwrap());
+    let uri = format!("ws://localhost:{}/chat/room1", PORT);
+
+    let (mut sender, _) = connect_async(&uri).await.expect("Unable to connect");
+    wait_for_join().await;
+    let mut ids = Vec::new();
+    for text in &["one", "two", "three"] {
+        send_frame(&mut sender, json!({ "type": "message", "text": text })).await;
+        let ack = next_event(&mut sender).await;
+        assert_eq!(ack["type"], "ack");
+        ids.push(ack["id"].as_i64().unwrap());
+    }
+
+    // Every message after the last one received is replayed, past the history
+    // limit, before live messages
+    let (mut resumed, _) = connect_async(format!("{}?since={}", uri, ids[0]))
+        .await
+        .expect("Unable to connect");
+    assert_eq!(next_event(&mut resumed).await["text"], "two");
+    assert_eq!(next_event(&mut resumed).await["text"], "three");
+    wait_for_join().await;
+    send_frame(&mut sender, json!({ "type": "message", "text": "four" })).await;
+    assert_eq!(next_event(&mut resumed).await["text"], "four");
+
+    // Rooms joined over an existing connection resume as well
+    let (mut alice_stream, _) = connect_async(format!(
+        "ws://localhost:{}/chat/room2?token={}",
+        PORT, token
+    ))
+    .await
+    .expect("Unable to connect as alice");
+    wait_for_join().await;
+    send_frame(
+        &mut alice_stream,
+        json!({ "type": "join", "room": "room1", "since": ids[1] }),
+    )
+    .await;
+    let event = next_event(&mut alice_stream).await;
+    assert_eq!(event["room"], "room1");
+    assert_eq!(event["text"], "three");
+    assert_eq!(next_event(&mut alice_stream).await["text"], "four");
+
+    remove_db(&db_path);
+}
+
+#[tokio::test]
+// Tests that scheduled messages are sent once due, unless cancelled.
+async fn scheduled_messages() {
+    const PORT: u16 = 3083;
+
+    let db_path = PathBuf::from("./main_scheduled_messages.db");
+    let spawn_db_path = db_path.clone();
+    tokio::task::spawn(async move {
+        server::run(PORT, spawn_db_path).await;
+    });
+    wait_for_server(PORT).await;
+
+    let mut user_ids = Vec::new();
+    let mut tokens = Vec::new();
+    for username in &["alice", "bob"] {
+        let credentials = json!({ "username": username, "password": "correct horse" });
+        let (_, body) = http_request(
+            PORT,
+            "POST",
+            "/users/register",
+            &[],
+            Some(credentials.clone()),
+        )
+        .await;
+        user_ids.push(body["user_id"].as_u64().unwrap());
+        let (_, body) = http_request(PORT, "POST", "/users/login", &[], Some(credentials)).await;
+        tokens.push(String::from(body["token"].as_str().unwrap()));
+    }
+    let alice = user_ids[0];
+    let auth = |token: &str| format!("Bearer {}", token);
+    let uri =
+        |room: &str, token: &str| format!("ws://localhost:{}/chat/{}?token={}", PORT, room, token);
+
+    // Rooms must exist to schedule messages to them
+    let new_message = json!({ "text": "Good morning", "delay_secs": 1 });
+    let (status, _) = http_request(
+        PORT,
+        "POST",
+        "/rooms/room1/scheduled",
+        &[("Authorization", &auth(&tokens[0]))],
+        Some(new_message.clone()),
+    )
+    .await;
+    assert_eq!(status, 404);
+
+    let (mut bob_stream, _) = connect_async(uri("room1", &tokens[1]))
+        .await
+        .expect("Unable to connect as bob");
+    wait_for_join().await;
+
+    let (status, _) = http_request(
+        PORT,
+        "POST",
+        "/rooms/room1/scheduled",
+        &[],
+        Some(new_message.clone()),
+    )
+    .await;
+    assert_eq!(status, 401);
+    let (status, _) = http_request(
+        PORT,
+        "POST",
+        "/rooms/room1/scheduled",
+        &[("Authorization", &auth(&tokens[0]))],
+        Some(json!({ "text": "Too soon", "delay_secs": 0 })),
+    )
+    .await;
+    assert_eq!(status, 400);
+
+    let (status, scheduled) = http_request(
+        PORT,
+        "POST",
+        "/rooms/room1/scheduled",
+        &[("Authorization", &auth(&tokens[0]))],
+        Some(new_message),
+    )
+    .await;
+    assert_eq!(status, 201);
+    assert_eq!(scheduled["user_id"], alice);
+    let (status, cancelled) = http_request(
+        PORT,
+        "POST",
+        "/rooms/room1/scheduled",
+        &[("Authorization", &auth(&tokens[0]))],
+        Some(json!({ "text": "Never mind", "delay_secs": 60 })),
+    )
+    .await;
+    assert_eq!(status, 201);
+
+    // Only their author lists and cancels them
+    let (_, listed) = http_request(
+        PORT,
+        "GET",
+        "/rooms/room1/scheduled",
+        &[("Authorization", &auth(&tokens[0]))],
+        None,
+    )
+    .await;
+    assert_eq!(listed.as_array().unwrap().len(), 2);
+    let (_, listed) = http_request(
+        PORT,
+        "GET",
+        "/rooms/room1/scheduled",
+        &[("Authorization", &auth(&tokens[1]))],
+        None,
+    )
+    .await;
+    assert!(listed.as_array().unwrap().is_empty());
+    let path = format!("/rooms/room1/scheduled/{}", cancelled["id"]);
+    let (status, _) = http_request(
+        PORT,
+        "DELETE",
+        &path,
+        &[("Authorization", &auth(&tokens[1]))],
+        None,
+    )
+    .await;
+    assert_eq!(status, 404);
+    let (status, _) = http_request(
+        PORT,
+        "DELETE",
+        &path,
+        &[("Authorization", &auth(&tokens[0]))],
+        None,
+    )
+    .await;
+    assert_eq!(status, 204);
+
+    // Messages are sent once due, as if their author sent them then
+    let event = next_event(&mut bob_stream).await;
+    assert_eq!(event["type"], "message");
+    assert_eq!(event["room"], "room1");
+    assert_eq!(event["user_id"], alice);
+    assert_eq!(event["text"], "Good morning");
+    let (_, listed) = http_request(
+        PORT,
+        "GET",
+        "/rooms/room1/scheduled",
+        &[("Authorization", &auth(&tokens[0]))],
+        None,
+    )
+    .await;
+    assert!(listed.as_array().unwrap().is_empty());
+
+    // ...including to rooms no one is in, which keep them in their history
+    bob_stream.close(None).await.unwrap();
+    wait_for_join().await;
+    let (status, _) = http_request(
+        PORT,
+        "POST",
+        "/rooms/room1/scheduled",
+        &[("Authorization", &auth(&tokens[0]))],
+        Some(json!({ "text": "Anyone?", "delay_secs": 1 })),
+    )
+    .await;
+    assert_eq!(status, 201);
+    tokio::time::sleep(Duration::from_millis(2500)).await;
+    let (mut bob_stream, _) = connect_async(uri("room1", &tokens[1]))
+        .await
+        .expect("Unable to connect as bob");
+    assert_eq!(next_event(&mut bob_stream).await["text"], "Good morning");
+    let event = next_event(&mut bob_stream).await;
+    assert_eq!(event["text"], "Anyone?");
+    assert_eq!(event["seq"], 2);
+
+    remove_db(&db_path);
+}
+
+#[tokio::test]
+// Tests that messages sent with a TTL are broadcast, then deleted once expired.
+async fn ephemeral_messages() {
+    const PORT: u16 = 3084;
+
+    let db_path = PathBuf::from("./main_ephemeral_messages.db");
+    let spawn_db_path = db_path.clone();
+    tokio::task::spawn(async move {
+        server::run(PORT, spawn_db_path).await;
+    });
+    wait_for_server(PORT).await;
+
+    let uri = format!("ws://localhost:{}/chat/room1", PORT);
+    let (mut receiver, _) = connect_async(&uri).await.expect("Unable to connect");
+    let (mut sender, _) = connect_async(&uri).await.expect("Unable to connect");
+    wait_for_join().await;
+
+    send_frame(&mut sender, json!({ "type": "message", "text": "kept" })).await;
+    send_frame(
+        &mut sender,
+        json!({ "type": "message", "text": "whisper", "ttl_secs": 0 }),
+    )
+    .await;
+    send_frame(
+        &mut sender,
+        json!({ "type": "message", "text": "fleeting", "ttl_secs": 1 }),
+    )
+    .await;
+
+    // Every message is broadcast as usual
+    let event = next_event(&mut receiver).await;
+    assert_eq!(event["text"], "kept");
+    assert!(event.get("ttl_secs").is_none());
+    let event = next_event(&mut receiver).await;
+    assert_eq!(event["text"], "whisper");
+    assert_eq!(event["ttl_secs"], 0);
+    let event = next_event(&mut receiver).await;
+    assert_eq!(event["text"], "fleeting");
+    assert_eq!(event["ttl_secs"], 1);
+
+    // ...but those with a TTL of 0 are never persisted
+    let (mut late, _) = connect_async(&uri).await.expect("Unable to connect");
+    assert_eq!(next_event(&mut late).await["text"], "kept");
+    assert_eq!(next_event(&mut late).await["text"], "fleeting");
+
+    // ...and those with one are deleted once expired
+    tokio::time::sleep(Duration::from_millis(2500)).await;
+    let (mut later, _) = connect_async(&uri).await.expect("Unable to connect");
+    assert_eq!(next_event(&mut later).await["text"], "kept");
+    wait_for_join().await;
+    send_frame(&mut sender, json!({ "type": "message", "text": "live" })).await;
+    assert_eq!(next_event(&mut later).await["text"], "live");
+
+    remove_db(&db_path);
+}
+
+#[tokio::test]
+// Tests that messages forwarded to another room credit their original author.
+async fn message_forwarding() {
+    const PORT: u16 = 3085;
+
+    let db_path = PathBuf::from("./main_message_forwarding.db");
+    let spawn_db_path = db_path.clone();
+    tokio::task::spawn(async move {
+        server::run(PORT, spawn_db_path).await;
+    });
+    wait_for_server(PORT).await;
+
+    let mut user_ids = Vec::new();
+    let mut tokens = Vec::new();
+    for username in &["alice", "bob"] {
+        let credentials = json!({ "username": username, "password": "correct horse" });
+        let (_, body) = http_request(
+            PORT,
+            "POST",
+            "/users/register",
+            &[],
+            Some(credentials.clone()),
+        )
+        .await;
+        user_ids.push(body["user_id"].as_u64().unwrap());
+        let (_, body) = http_request(PORT, "POST", "/users/login", &[], Some(credentials)).await;
+        tokens.push(String::from(body["token"].as_str().unwrap()));
+    }
+    let (alice, bob) = (user_ids[0], user_ids[1]);
+    let uri =
+        |room: &str, token: &str| format!("ws://localhost:{}/chat/{}?token={}", PORT, room, token);
+
+    let (mut alice_stream, _) = connect_async(uri("room1", &tokens[0]))
+        .await
+        .expect("Unable to connect as alice");
+    let (mut bob_room1, _) = connect_async(uri("room1", &tokens[1]))
+        .await
+        .expect("Unable to connect as bob");
+    wait_for_join().await;
+    send_frame(
+        &mut alice_stream,
+        json!({ "type": "message", "text": "hello" }),
+    )
+    .await;
+    let id = next_event(&mut alice_stream).await["id"].clone();
+    assert_eq!(next_event(&mut bob_room1).await["text"], "hello");
+
+    // Messages are forwarded as if the user forwarding them sent them
+    let (mut bob_room2, _) = connect_async(uri("room2", &tokens[1]))
+        .await
+        .expect("Unable to connect as bob");
+    wait_for_join().await;
+    send_frame(
+        &mut bob_room1,
+        json!({ "type": "forward", "id": id, "to": "room2" }),
+    )
+    .await;
+    let ack = next_event(&mut bob_room1).await;
+    assert_eq!(ack["type"], "ack");
+    assert_eq!(ack["room"], "room2");
+    let event = next_event(&mut bob_room2).await;
+    assert_eq!(event["user_id"], bob);
+    assert_eq!(event["text"], "hello");
+    assert_eq!(
+        event["forwarded"],
+        json!({ "id": id, "room": "room1", "user_id": alice })
+    );
+
+    // ...to rooms no one is in as well, keeping crediting where they were
+    // first sent when forwarded again
+    let (mut alice_room3, _) = connect_async(uri("room3", &tokens[0]))
+        .await
+        .expect("Unable to connect as alice");
+    wait_for_join().await;
+    alice_room3.close(None).await.unwrap();
+    wait_for_join().await;
+    send_frame(
+        &mut bob_room2,
+        json!({ "type": "forward", "id": event["id"], "to": "room3" }),
+    )
+    .await;
+    assert_eq!(next_event(&mut bob_room2).await["type"], "ack");
+    let (mut alice_room3, _) = connect_async(uri("room3", &tokens[0]))
+        .await
+        .expect("Unable to connect as alice");
+    let event = next_event(&mut alice_room3).await;
+    assert_eq!(event["forwarded"]["id"], id);
+    assert_eq!(event["forwarded"]["room"], "room1");
+
+    // Unknown messages, and guests, are refused
+    send_frame(
+        &mut alice_stream,
+        json!({ "type": "forward", "id": 1000, "to": "room2" }),
+    )
+    .await;
+    assert_eq!(next_event(&mut alice_stream).await["type"], "error");
+    let (mut guest_stream, _) = connect_async(format!("ws://localhost:{}/chat/room1", PORT))
+        .await
+        .expect("Unable to connect");
+    assert_eq!(next_event(&mut guest_stream).await["text"], "hello");
+    send_frame(
+        &mut guest_stream,
+        json!({ "type": "forward", "id": id, "to": "room2" }),
+    )
+    .await;
+    assert_eq!(next_event(&mut guest_stream).await["type"], "error");
+
+    remove_db(&db_path);
+}
+
+#[tokio::test]
+// Tests that messages of announcement rooms are cross-posted to the rooms
+// following them, without looping.
+async fn announcement_rooms() {
+    const PORT: u16 = 3086;
+
+    let db_path = PathBuf::from("./main_announcement_rooms.db");
+    let spawn_db_path = db_path.clone();
+    tokio::task::spawn(async move {
+        server::run(PORT, spawn_db_path).await;
+    });
+    wait_for_server(PORT).await;
+
+    let mut tokens = Vec::new();
+    for username in &["alice", "bob"] {
+        let credentials = json!({ "username": username, "password": "correct horse" });
+        http_request(
+            PORT,
+            "POST",
+            "/users/register",
+            &[],
+            Some(credentials.clone()),
+        )
+        .await;
+        let (_, body) = http_request(PORT, "POST", "/users/login", &[], Some(credentials)).await;
+        tokens.push(String::from(body["token"].as_str().unwrap()));
+    }
+    let alice_auth = format!("Bearer {}", tokens[0]);
+    let bob_auth = format!("Bearer {}", tokens[1]);
+    for (name, announcement) in &[("news", true), ("room1", false), ("room2", false)] {
+        let (status, _) = http_request(
+            PORT,
+            "POST",
+            "/rooms",
+            &[("Authorization", &alice_auth)],
+            Some(json!({ "name": name, "announcement": announcement })),
+        )
+        .await;
+        assert_eq!(status, 201);
+    }
+
+    // Only admins of a room have it follow announcement rooms
+    let (status, _) = http_request(
+        PORT,
+        "PUT",
+        "/rooms/room1/following/news",
+        &[("Authorization", &bob_auth)],
+        None,
+    )
+    .await;
+    assert_eq!(status, 403);
+    let (status, follow) = http_request(
+        PORT,
+        "PUT",
+        "/rooms/room1/following/news",
+        &[("Authorization", &alice_auth)],
+        None,
+    )
+    .await;
+    assert_eq!(status, 200);
+    assert_eq!(follow["source"], "news");
+    let (status, _) = http_request(
+        PORT,
+        "PUT",
+        "/rooms/room2/following/room1",
+        &[("Authorization", &alice_auth)],
+        None,
+    )
+    .await;
+    assert_eq!(status, 400);
+    let (status, _) = http_request(
+        PORT,
+        "PUT",
+        "/rooms/room2/following/nowhere",
+        &[("Authorization", &alice_auth)],
+        None,
+    )
+    .await;
+    assert_eq!(status, 404);
+
+    // Rooms following each other do not loop
+    let (status, info) = http_request(
+        PORT,
+        "PUT",
+        "/rooms/room1/announcement",
+        &[("Authorization", &alice_auth)],
+        Some(json!({ "announcement": true })),
+    )
+    .await;
+    assert_eq!(status, 200);
+    assert_eq!(info["announcement"], true);
+    let (status, _) = http_request(
+        PORT,
+        "PUT",
+        "/rooms/news/following/room1",
+        &[("Authorization", &alice_auth)],
+        None,
+    )
+    .await;
+    assert_eq!(status, 200);
+
+    let uri =
+        |room: &str, token: &str| format!("ws://localhost:{}/chat/{}?token={}", PORT, room, token);
+    let (mut alice_news, _) = connect_async(uri("news", &tokens[0]))
+        .await
+        .expect("Unable to connect as alice");
+    let (mut bob_room1, _) = connect_async(uri("room1", &tokens[1]))
+        .await
+        .expect("Unable to connect as bob");
+    wait_for_join().await;
+
+    send_frame(
+        &mut alice_news,
+        json!({ "type": "message", "text": "Release!" }),
+    )
+    .await;
+    let id = next_event(&mut alice_news).await["id"].clone();
+    let event = next_event(&mut bob_room1).await;
+    assert_eq!(event["room"], "room1");
+    assert_eq!(event["text"], "Release!");
+    assert_eq!(event["forwarded"]["id"], id);
+    assert_eq!(event["forwarded"]["room"], "news");
+
+    send_frame(
+        &mut bob_room1,
+        json!({ "type": "message", "text": "Thanks" }),
+    )
+    .await;
+    assert_eq!(next_event(&mut bob_room1).await["type"], "ack");
+    let event = next_event(&mut alice_news).await;
+    assert_eq!(event["text"], "Thanks");
+    assert_eq!(event["forwarded"]["room"], "room1");
+
+    // Rooms no longer following one are no longer cross-posted to
+    let (_, following) = http_request(
+        PORT,
+        "GET",
+        "/rooms/room1/following",
+        &[("Authorization", &alice_auth)],
+        None,
+    )
+    .await;
+    assert_eq!(following.as_array().unwrap().len(), 1);
+    for expected in &[204, 404] {
+        let (status, _) = http_request(
+            PORT,
+            "DELETE",
+            "/rooms/room1/following/news",
+            &[("Authorization", &alice_auth)],
+            None,
+        )
+        .await;
+        assert_eq!(status, *expected);
+    }
+    send_frame(
+        &mut alice_news,
+        json!({ "type": "message", "text": "Quiet" }),
+    )
+    .await;
+    assert_eq!(next_event(&mut alice_news).await["type"], "ack");
+    send_frame(
+        &mut bob_room1,
+        json!({ "type": "message", "text": "Hello?" }),
+    )
+    .await;
+    assert_eq!(next_event(&mut bob_room1).await["type"], "ack");
+    assert_eq!(next_event(&mut alice_news).await["text"], "Hello?");
+
+    remove_db(&db_path);
+}
+
+#[tokio::test]
+// Tests that votes in polls are tallied and sent to the room as they arrive.
+async fn polls() {
+    const PORT: u16 = 3087;
+
+    let db_path = PathBuf::from("./main_polls.db");
+    let spawn_db_path = db_path.clone();
+    tokio::task::spawn(async move {
+        server::run(PORT, spawn_db_path).await;
+    });
+    wait_for_server(PORT).await;
+
+    let uri = format!("ws://localhost:{}/chat/room1", PORT);
+    let (mut alice, _) = connect_async(&uri).await.expect("Unable to connect");
+    let (mut bob, _) = connect_async(&uri).await.expect("Unable to connect");
+    wait_for_join().await;
+
+    send_frame(
+        &mut alice,
+        json!({ "type": "poll", "question": "Lunch?", "options": ["Pizza"] }),
+    )
+    .await;
+    assert_eq!(next_event(&mut alice).await["type"], "error");
+    send_frame(
+        &mut alice,
+        json!({ "type": "poll", "question": "Lunch?", "options": ["Pizza", "Sushi"] }),
+    )
+    .await;
+    let id = next_event(&mut alice).await["id"].clone();
+    let event = next_event(&mut bob).await;
+    assert_eq!(event["id"], id);
+    assert_eq!(event["text"], "Lunch?");
+    assert_eq!(
+        event["poll"],
+        json!([{ "text": "Pizza", "votes": 0 }, { "text": "Sushi", "votes": 0 }])
+    );
+
+    // Tallies reach everyone, voters included, as votes arrive
+    send_frame(&mut bob, json!({ "type": "vote", "id": id, "option": 1 })).await;
+    for stream in &mut [&mut alice, &mut bob] {
+        let event = next_event(stream).await;
+        assert_eq!(event["type"], "poll_tally");
+        assert_eq!(event["id"], id);
+        assert_eq!(event["options"][1]["votes"], 1);
+    }
+    send_frame(&mut alice, json!({ "type": "vote", "id": id, "option": 1 })).await;
+    assert_eq!(next_event(&mut bob).await["options"][1]["votes"], 2);
+    send_frame(&mut bob, json!({ "type": "vote", "id": id, "option": 0 })).await;
+    let event = next_event(&mut alice).await;
+    assert_eq!(event["options"][1]["votes"], 2);
+    let event = next_event(&mut alice).await;
+    assert_eq!(event["options"][0]["votes"], 1);
+    assert_eq!(event["options"][1]["votes"], 1);
+
+    send_frame(&mut bob, json!({ "type": "vote", "id": id, "option": 2 })).await;
+    // Skip the tally of bob's last vote
+    assert_eq!(next_event(&mut bob).await["type"], "poll_tally");
+    assert_eq!(next_event(&mut bob).await["type"], "error");
+
+    // Polls are sent with their tally in history
+    let (mut late, _) = connect_async(&uri).await.expect("Unable to connect");
+    let event = next_event(&mut late).await;
+    assert_eq!(event["text"], "Lunch?");
+    assert_eq!(event["poll"][0]["votes"], 1);
+
+    remove_db(&db_path);
+}
+
+#[tokio::test]
+// Tests that servers keeping everything in memory replay history without
+// writing a DB file.
+async fn memory_store() {
+    const PORT: u16 = 3088;
+
+    let db_path = PathBuf::from("./main_memory.db");
+    let config = Config {
+        store: StoreKind::Memory,
+        ..Config::new(PORT, db_path.clone())
+    };
+    tokio::task::spawn(async move {
+        server::run_with_config(config).await;
+    });
+    wait_for_server(PORT).await;
+
+    let uri = format!("ws://localhost:{}/chat/room1", PORT);
+    let (mut alice, _) = connect_async(&uri).await.expect("Unable to connect");
+    let (mut bob, _) = connect_async(&uri).await.expect("Unable to connect");
+    wait_for_join().await;
+
+    send_frame(&mut alice, json!({ "type": "message", "text": "Hello" })).await;
+    next_event(&mut bob).await;
+
+    let (mut carol, _) = connect_async(&uri).await.expect("Unable to connect");
+    let event = next_event(&mut carol).await;
+    assert_eq!(event["type"], "message");
+    assert_eq!(event["text"], "Hello");
+
+    assert!(!db_path.exists());
+}
+
+#[tokio::test]
+async fn db_maintenance() {
+    const PORT: u16 = 3089;
+
+    let db_path = PathBuf::from("./main_db_maintenance.db");
+    let config = Config {
+        admin_token: Some(String::from("secret")),
+        ..Config::new(PORT, db_path.clone())
+    };
+    tokio::task::spawn(async move {
+        server::run_with_config(config).await;
+    });
+    wait_for_server(PORT).await;
+
+    let (mut socket, _) = connect_async(format!("ws://localhost:{}/chat/room1", PORT))
+        .await
+        .expect("Unable to connect");
+    wait_for_join().await;
+    send_frame(&mut socket, json!({ "type": "message", "text": "Hello" })).await;
+    assert_eq!(next_event(&mut socket).await["type"], "ack");
+
+    // Only admins see the DB or maintain it
+    let admin = [("Authorization", "Bearer secret")];
+    let (status, _) = http_request(PORT, "GET", "/admin/db", &[], None).await;
+    assert_eq!(status, 401);
+    let (status, _) = http_request(PORT, "POST", "/admin/db/maintenance", &[], None).await;
+    assert_eq!(status, 401);
+
+    let (status, body) = http_request(PORT, "GET", "/admin/db", &admin, None).await;
+    assert_eq!(status, 200);
+    assert!(body["size"].as_u64().unwrap() > 0);
+    assert!(body["wal"].as_u64().unwrap() > 0);
+
+    // The message is committed, then checkpointed along with the rest
+    let (status, body) = http_request(PORT, "POST", "/admin/db/maintenance", &admin, None).await;
+    assert_eq!(status, 200);
+    assert!(body["checkpointed"].as_u64().unwrap() > 0);
+    assert_eq!(body["stats"]["wal"], 0);
+
+    remove_db(&db_path);
+}
+
+#[tokio::test]
+async fn message_search() {
+    const PORT: u16 = 3090;
+
+    let db_path = PathBuf::from("./main_message_search.db");
+    let spawn_db_path = db_path.clone();
+    tokio::task::spawn(async move {
+        server::run(PORT, spawn_db_path).await;
+    });
+    wait_for_server(PORT).await;
+
+    let credentials = json!({ "username": "alice", "password": "correct horse" });
+    http_request(
+        PORT,
+        "POST",
+        "/users/register",
+        &[],
+        Some(credentials.clone()),
+    )
+    .await;
+    let (_, body) = http_request(PORT, "POST", "/users/login", &[], Some(credentials)).await;
+    let token = String::from(body["token"].as_str().unwrap());
+    let jwt = format!("Bearer {}", token);
+    let auth = [("Authorization", jwt.as_str())];
+
+    let (mut stream, _) = connect_async(format!(
+        "ws://localhost:{}/chat/room1?token={}",
+        PORT, token
+    ))
+    .await
+    .expect("Unable to connect");
+    wait_for_join().await;
+
+    let mut ids = Vec::new();
+    for text in &["Lunch at noon?", "Lunch! Lunch is the best", "Dinner then"] {
+        send_frame(&mut stream, json!({ "type": "message", "text": text })).await;
+        let ack = next_event(&mut stream).await;
+        assert_eq!(ack["type"], "ack");
+        ids.push(ack["id"].as_i64().unwrap());
+    }
+
+    let (status, _) = http_request(PORT, "GET", "/rooms/room1/search?q=lunch", &[], None).await;
+    assert_eq!(status, 401);
+    let (status, _) = http_request(PORT, "GET", "/rooms/room1/search?q=%20", &auth, None).await;
+    assert_eq!(status, 400);
+
+    // Messages just sent are found, best matches first
+    let (status, body) =
+        http_request(PORT, "GET", "/rooms/room1/search?q=lunch", &auth, None).await;
+    assert_eq!(status, 200);
+    assert_eq!(body["hits"].as_array().unwrap().len(), 2);
+    assert_eq!(body["hits"][0]["id"], ids[1]);
+    assert_eq!(body["hits"][1]["id"], ids[0]);
+    assert_eq!(body["next_offset"], Value::Null);
+
+    let (_, body) = http_request(
+        PORT,
+        "GET",
+        "/rooms/room1/search?q=lunch%20noon",
+        &auth,
+        None,
+    )
+    .await;
+    assert_eq!(body["hits"][0]["text"], "Lunch at noon?");
+
+    // Matches are paged through
+    let (_, body) = http_request(
+        PORT,
+        "GET",
+        "/rooms/room1/search?q=lunch&limit=1",
+        &auth,
+        None,
+    )
+    .await;
+    assert_eq!(body["hits"][0]["id"], ids[1]);
+    assert_eq!(body["next_offset"], 1);
+    let (_, body) = http_request(
+        PORT,
+        "GET",
+        "/rooms/room1/search?q=lunch&limit=1&offset=1",
+        &auth,
+        None,
+    )
+    .await;
+    assert_eq!(body["hits"][0]["id"], ids[0]);
+    assert_eq!(body["next_offset"], Value::Null);
+
+    // Other rooms are searched apart
+    let (_, body) = http_request(PORT, "GET", "/rooms/room2/search?q=lunch", &auth, None).await;
+    assert!(body["hits"].as_array().unwrap().is_empty());
+
+    remove_db(&db_path);
+}
+
+#[tokio::test]
+async fn room_message_listing() {
+    const PORT: u16 = 3091;
+
+    let db_path = PathBuf::from("./main_message_listing.db");
+    let spawn_db_path = db_path.clone();
+    tokio::task::spawn(async move {
+        server::run(PORT, spawn_db_path).await;
+    });
+    wait_for_server(PORT).await;
+
+    let mut tokens = Vec::new();
+    let mut user_ids = Vec::new();
+    for username in &["alice", "bob"] {
+        let credentials = json!({ "username": username, "password": "correct horse" });
+        let (_, body) = http_request(
+            PORT,
+            "POST",
+            "/users/register",
+            &[],
+            Some(credentials.clone()),
+        )
+        .await;
+        user_ids.push(body["user_id"].as_u64().unwrap());
+        let (_, body) = http_request(PORT, "POST", "/users/login", &[], Some(credentials)).await;
+        tokens.push(String::from(body["token"].as_str().unwrap()));
+    }
+    let alice_jwt = format!("Bearer {}", tokens[0]);
+    let auth = [("Authorization", alice_jwt.as_str())];
+    let uri = |token: &str| format!("ws://localhost:{}/chat/room1?token={}", PORT, token);
+
+    let (mut alice_stream, _) = connect_async(uri(&tokens[0]))
+        .await
+        .expect("Unable to connect as alice");
+    wait_for_join().await;
+    let (mut bob_stream, _) = connect_async(uri(&tokens[1]))
+        .await
+        .expect("Unable to connect as bob");
+    wait_for_join().await;
+
+    let mut ids = Vec::new();
+    send_frame(
+        &mut alice_stream,
+        json!({ "type": "message", "text": "Hi" }),
+    )
+    .await;
+    let ack = next_event(&mut alice_stream).await;
+    assert_eq!(ack["type"], "ack");
+    ids.push(ack["id"].as_i64().unwrap());
+    assert_eq!(next_event(&mut bob_stream).await["id"], ids[0]);
+
+    send_frame(&mut bob_stream, json!({ "type": "message", "text": "Hey" })).await;
+    let ack = next_event(&mut bob_stream).await;
+    assert_eq!(ack["type"], "ack");
+    ids.push(ack["id"].as_i64().unwrap());
+
+    let (status, _) = http_request(PORT, "GET", "/rooms/room1/messages", &[], None).await;
+    assert_eq!(status, 401);
+    let (status, _) = http_request(
+        PORT,
+        "GET",
+        "/rooms/room1/messages?since=yesterday",
+        &auth,
+        None,
+    )
+    .await;
+    assert_eq!(status, 400);
+
+    let (status, body) = http_request(PORT, "GET", "/rooms/room1/messages", &auth, None).await;
+    assert_eq!(status, 200);
+    assert_eq!(body["messages"][0]["id"], ids[0]);
+    assert_eq!(body["messages"][1]["id"], ids[1]);
+    assert_eq!(body["messages"][1]["text"], "Hey");
+    assert_eq!(body["next_offset"], Value::Null);
+
+    // Messages are filtered by author and time
+    let path = format!("/rooms/room1/messages?user_id={}", user_ids[1]);
+    let (_, body) = http_request(PORT, "GET", &path, &auth, None).await;
+    assert_eq!(body["messages"].as_array().unwrap().len(), 1);
+    assert_eq!(body["messages"][0]["id"], ids[1]);
+
+    let (_, body) = http_request(
+        PORT,
+        "GET",
+        "/rooms/room1/messages?until=2000-01-01",
+        &auth,
+        None,
+    )
+    .await;
+    assert!(body["messages"].as_array().unwrap().is_empty());
+    let (_, body) = http_request(
+        PORT,
+        "GET",
+        "/rooms/room1/messages?since=2000-01-01T00:00:00Z&limit=1",
+        &auth,
+        None,
+    )
+    .await;
+    assert_eq!(body["messages"][0]["id"], ids[0]);
+    assert_eq!(body["next_offset"], 1);
+
+    remove_db(&db_path);
+}
+
+#[tokio::test]
+async fn db_backup() {
+    back_up_db(3092, "./main_db_backup.db", None).await;
+}
+
+#[tokio::test]
+// Tests that backups are taken on the DB thread's connection, which is always
+// within a transaction, without read-only connections.
+async fn db_backup_without_read_connections() {
+    back_up_db(3117, "./main_db_backup_writer.db", Some(0)).await;
+}
+
+// Backs up the DB of a server on `port`, persisting to `db_path`, given
+// `read_connections` if any.
+async fn back_up_db(port: u16, db_path: &str, read_connections: Option<usize>) {
+    let db_path = PathBuf::from(db_path);
+    let mut config = Config {
+        admin_token: Some(String::from("secret")),
+        ..Config::new(port, db_path.clone())
+    };
+    if let Some(read_connections) = read_connections {
+        config.read_connections = read_connections;
+    }
+    tokio::task::spawn(async move {
+        server::run_with_config(config).await;
+    });
+    wait_for_server(port).await;
+
+    let (mut socket, _) = connect_async(format!("ws://localhost:{}/chat/room1", port))
+        .await
+        .expect("Unable to connect");
+    wait_for_join().await;
+    send_frame(&mut socket, json!({ "type": "message", "text": "Hello" })).await;
+    assert_eq!(next_event(&mut socket).await["type"], "ack");
+
+    let (status, _) = http_request(port, "POST", "/admin/db/backup", &[], None).await;
+    assert_eq!(status, 401);
+
+    // The message is committed, then backed up along with the rest
+    let admin = [("Authorization", "Bearer secret")];
+    let (status, body) = http_request(port, "POST", "/admin/db/backup", &admin, None).await;
+    assert_eq!(status, 201);
+    let backup_path = PathBuf::from(body["path"].as_str().unwrap());
+    assert!(backup_path
+        .to_str()
+        .unwrap()
+        .starts_with(&format!("{}.backup-", db_path.display())));
+    assert!(body["size"].as_u64().unwrap() > 0);
+
+    let backup = rusqlite::Connection::open(&backup_path).unwrap();
+    let message: String = backup
+        .query_row("SELECT message FROM chat_messages", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(message, "Hello");
+    backup.close().unwrap();
+
+    // Writes carry on once backed up
+    send_frame(&mut socket, json!({ "type": "message", "text": "Again" })).await;
+    assert_eq!(next_event(&mut socket).await["type"], "ack");
+
+    std::fs::remove_file(&backup_path).unwrap();
+    remove_db(&db_path);
+}
+
+#[tokio::test]
+async fn room_export() {
+    const PORT: u16 = 3093;
+
+    let db_path = PathBuf::from("./main_room_export.db");
+    let spawn_db_path = db_path.clone();
+    tokio::task::spawn(async move {
+        server::run(PORT, spawn_db_path).await;
+    });
+    wait_for_server(PORT).await;
+
+    let mut tokens = Vec::new();
+    for username in &["alice", "bob"] {
+        let credentials = json!({ "username": username, "password": "correct horse" });
+        http_request(
+            PORT,
+            "POST",
+            "/users/register",
+            &[],
+            Some(credentials.clone()),
+        )
+        .await;
+        let (_, body) = http_request(PORT, "POST", "/users/login", &[], Some(credentials)).await;
+        tokens.push(String::from(body["token"].as_str().unwrap()));
+    }
+    let alice_jwt = format!("Bearer {}", tokens[0]);
+    let bob_jwt = format!("Bearer {}", tokens[1]);
+    let (status, _) = http_request(
+        PORT,
+        "POST",
+        "/rooms",
+        &[("Authorization", &alice_jwt)],
+        Some(json!({ "name": "lobby" })),
+    )
+    .await;
+    assert_eq!(status, 201);
+
+    let (mut socket, _) = connect_async(format!(
+        "ws://localhost:{}/chat/lobby?token={}",
+        PORT, tokens[0]
+    ))
+    .await
+    .expect("Unable to connect");
+    wait_for_join().await;
+    let mut ids = Vec::new();
+    for text in &["Hello", "Lunch, \"anyone\"?"] {
+        send_frame(&mut socket, json!({ "type": "message", "text": text })).await;
+        let ack = next_event(&mut socket).await;
+        assert_eq!(ack["type"], "ack");
+        ids.push(ack["id"].as_i64().unwrap());
+    }
+
+    // Only admins of the room export it
+    let (status, _) = http_request(PORT, "GET", "/rooms/lobby/export", &[], None).await;
+    assert_eq!(status, 401);
+    let (status, _) = http_request(
+        PORT,
+        "GET",
+        "/rooms/lobby/export",
+        &[("Authorization", &bob_jwt)],
+        None,
+    )
+    .await;
+    assert_eq!(status, 403);
+    let (status, _) = http_request(
+        PORT,
+        "GET",
+        "/rooms/lobby/export?format=xml",
+        &[("Authorization", &alice_jwt)],
+        None,
+    )
+    .await;
+    assert_eq!(status, 400);
+
+    let (status, headers, body) = http_request_raw(
+        PORT,
+        "GET",
+        "/rooms/lobby/export",
+        &[("Authorization", &alice_jwt)],
+        None,
+    )
+    .await;
+    assert_eq!(status, 200);
+    assert!(headers
+        .iter()
+        .any(|(name, value)| name == "content-disposition" && value.contains("lobby.json")));
+    let messages: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(messages[0]["id"], ids[0]);
+    assert_eq!(messages[0]["username"], "alice");
+    assert_eq!(messages[1]["text"], "Lunch, \"anyone\"?");
+    assert!(messages[1]["created_at"].is_string());
+
+    let (status, headers, body) = http_request_raw(
+        PORT,
+        "GET",
+        "/rooms/lobby/export?format=csv",
+        &[("Authorization", &alice_jwt)],
+        None,
+    )
+    .await;
+    assert_eq!(status, 200);
+    assert!(headers
+        .iter()
+        .any(|(name, value)| name == "content-type" && value.starts_with("text/csv")));
+    let lines: Vec<_> = body.lines().collect();
+    assert_eq!(lines.len(), 3);
+    assert!(lines[0].starts_with("id,seq,user_id,username"));
+    assert!(lines[2].contains(",alice,,\"Lunch, \"\"anyone\"\"?\","));
+
+    remove_db(&db_path);
+}
+
+#[tokio::test]
+async fn message_history() {
+    const PORT: u16 = 3094;
+
+    let db_path = PathBuf::from("./main_message_history.db");
+    let spawn_db_path = db_path.clone();
+    tokio::task::spawn(async move {
+        server::run(PORT, spawn_db_path).await;
+    });
+    wait_for_server(PORT).await;
+
+    let mut tokens = Vec::new();
+    for username in &["alice", "bob"] {
+        let credentials = json!({ "username": username, "password": "correct horse" });
+        http_request(
+            PORT,
+            "POST",
+            "/users/register",
+            &[],
+            Some(credentials.clone()),
+        )
+        .await;
+        let (_, body) = http_request(PORT, "POST", "/users/login", &[], Some(credentials)).await;
+        tokens.push(String::from(body["token"].as_str().unwrap()));
+    }
+    let alice_jwt = format!("Bearer {}", tokens[0]);
+    let bob_jwt = format!("Bearer {}", tokens[1]);
+    let (status, _) = http_request(
+        PORT,
+        "POST",
+        "/rooms",
+        &[("Authorization", &alice_jwt)],
+        Some(json!({ "name": "lobby" })),
+    )
+    .await;
+    assert_eq!(status, 201);
+
+    let (mut socket, _) = connect_async(format!(
+        "ws://localhost:{}/chat/lobby?token={}",
+        PORT, tokens[0]
+    ))
+    .await
+    .expect("Unable to connect");
+    wait_for_join().await;
+    send_frame(&mut socket, json!({ "type": "message", "text": "helo" })).await;
+    let ack = next_event(&mut socket).await;
+    let id = ack["id"].as_i64().unwrap();
+    for text in &["hello", "hello!"] {
+        send_frame(
+            &mut socket,
+            json!({ "type": "edit", "id": id, "text": text }),
+        )
+        .await;
+        assert_eq!(next_event(&mut socket).await["type"], "edit");
+    }
+
+    // Only moderators of the room read the history of its messages
+    let path = format!("/messages/{}/history", id);
+    let (status, _) = http_request(PORT, "GET", &path, &[], None).await;
+    assert_eq!(status, 401);
+    let (status, _) = http_request(PORT, "GET", &path, &[("Authorization", &bob_jwt)], None).await;
+    assert_eq!(status, 403);
+    let (status, _) = http_request(
+        PORT,
+        "GET",
+        "/messages/999/history",
+        &[("Authorization", &alice_jwt)],
+        None,
+    )
+    .await;
+    assert_eq!(status, 404);
+
+    let (status, body) =
+        http_request(PORT, "GET", &path, &[("Authorization", &alice_jwt)], None).await;
+    assert_eq!(status, 200);
+    assert_eq!(body["room"], "lobby");
+    let revisions = body["revisions"].as_array().unwrap();
+    let texts: Vec<_> = revisions.iter().map(|r| r["text"].clone()).collect();
+    assert_eq!(texts, vec!["helo", "hello", "hello!"]);
+    assert!(revisions[0]["replaced_at"].is_string());
+    assert!(revisions[2]["replaced_at"].is_null());
+
+    remove_db(&db_path);
+}
+
+#[tokio::test]
+async fn deleted_messages() {
+    const PORT: u16 = 3095;
+
+    let db_path = PathBuf::from("./main_deleted_messages.db");
+    let spawn_db_path = db_path.clone();
+    tokio::task::spawn(async move {
+        server::run(PORT, spawn_db_path).await;
+    });
+    wait_for_server(PORT).await;
+
+    let mut tokens = Vec::new();
+    for username in &["alice", "bob"] {
+        let credentials = json!({ "username": username, "password": "correct horse" });
+        http_request(
+            PORT,
+            "POST",
+            "/users/register",
+            &[],
+            Some(credentials.clone()),
+        )
+        .await;
+        let (_, body) = http_request(PORT, "POST", "/users/login", &[], Some(credentials)).await;
+        tokens.push(String::from(body["token"].as_str().unwrap()));
+    }
+    let alice_jwt = format!("Bearer {}", tokens[0]);
+    let bob_jwt = format!("Bearer {}", tokens[1]);
+    let (status, _) = http_request(
+        PORT,
+        "POST",
+        "/rooms",
+        &[("Authorization", &alice_jwt)],
+        Some(json!({ "name": "lobby" })),
+    )
+    .await;
+    assert_eq!(status, 201);
+
+    let (mut socket, _) = connect_async(format!(
+        "ws://localhost:{}/chat/lobby?token={}",
+        PORT, tokens[0]
+    ))
+    .await
+    .expect("Unable to connect");
+    wait_for_join().await;
+    let mut ids = Vec::new();
+    for text in &["Hello", "Oops"] {
+        send_frame(&mut socket, json!({ "type": "message", "text": text })).await;
+        ids.push(next_event(&mut socket).await["id"].as_i64().unwrap());
+    }
+    send_frame(&mut socket, json!({ "type": "delete", "id": ids[1] })).await;
+    assert_eq!(next_event(&mut socket).await["type"], "delete");
+
+    // Only moderators of the room read what was deleted from it
+    let (status, _) = http_request(
+        PORT,
+        "GET",
+        "/rooms/lobby/deleted",
+        &[("Authorization", &bob_jwt)],
+        None,
+    )
+    .await;
+    assert_eq!(status, 403);
+
+    let (status, body) = http_request(
+        PORT,
+        "GET",
+        "/rooms/lobby/deleted",
+        &[("Authorization", &alice_jwt)],
+        None,
+    )
+    .await;
+    assert_eq!(status, 200);
+    let messages = body.as_array().unwrap();
+    assert_eq!(messages.len(), 1);
+    assert_eq!(messages[0]["id"], ids[1]);
+    assert_eq!(messages[0]["text"], "Oops");
+    assert!(messages[0]["deleted_at"].is_string());
+
+    remove_db(&db_path);
+}
+
+#[tokio::test]
+// Tests that instances given each other as peers share their rooms.
+async fn peer_cluster() {
+    const PORTS: [u16; 2] = [3096, 3097];
+
+    let db_paths = [
+        PathBuf::from("./main_peer_cluster_1.db"),
+        PathBuf::from("./main_peer_cluster_2.db"),
+    ];
+    for (i, db_path) in db_paths.iter().enumerate() {
+        let config = Config {
+            peers: vec![format!("127.0.0.1:{}", PORTS[1 - i])],
+            cluster_secret: Some(String::from("s3cret")),
+            node_id: Some(i as u8),
+            ..Config::new(PORTS[i], db_path.clone())
+        };
+        tokio::task::spawn(async move {
+            server::run_with_config(config).await;
+        });
+    }
+    for port in PORTS {
+        wait_for_server(port).await;
+    }
+
+    let (mut stream1, _) = connect_async(format!("ws://localhost:{}/chat/room1", PORTS[0]))
+        .await
+        .expect("Unable to connect");
+    let (mut stream2, _) = connect_async(format!("ws://localhost:{}/chat/room1", PORTS[1]))
+        .await
+        .expect("Unable to connect");
+    wait_for_join().await;
+
+    // Peers need the cluster secret to ask who is connected
+    let (status, _) = http_request(PORTS[0], "GET", "/cluster/membership", &[], None).await;
+    assert_eq!(status, 401);
+    let (status, body) = http_request(
+        PORTS[0],
+        "GET",
+        "/cluster/membership",
+        &[("Authorization", "Bearer s3cret")],
+        None,
+    )
+    .await;
+    assert_eq!(status, 200);
+    assert_eq!(body["rooms"]["room1"].as_array().unwrap().len(), 1);
+
+    // Wait for each instance to learn who is connected to the other
+    tokio::time::sleep(Duration::from_secs(6)).await;
+
+    stream1
+        .send(Message::Text(String::from("Hello from the other instance")))
+        .await
+        .expect("Unable to send message");
+    let first = next_event(&mut stream2).await;
+    assert_eq!(first["type"], "message");
+    assert_eq!(first["text"], "Hello from the other instance");
+    let ack = next_event(&mut stream1).await;
+    assert_eq!(ack["type"], "ack");
+    assert_eq!(ack["id"], first["id"]);
+
+    // One of the instances owns the room, numbering and sequencing every
+    // message sent to it, through either instance
+    let owner = first["id"].as_i64().unwrap() % 256;
+    stream2
+        .send(Message::Text(String::from("Hello back")))
+        .await
+        .expect("Unable to send message");
+    let second = next_event(&mut stream1).await;
+    assert_eq!(second["type"], "message");
+    assert_eq!(second["text"], "Hello back");
+    assert_eq!(second["id"].as_i64().unwrap() % 256, owner);
+    assert_eq!(
+        second["seq"].as_i64(),
+        first["seq"].as_i64().map(|seq| seq + 1)
+    );
+    let ack = next_event(&mut stream2).await;
+    assert_eq!(ack["type"], "ack");
+    assert_eq!(ack["id"], second["id"]);
+
+    // Messages are edited through either instance
+    let edit = json!({ "type": "edit", "id": first["id"], "text": "Hello, edited" });
+    stream1
+        .send(Message::Text(edit.to_string()))
+        .await
+        .expect("Unable to send message");
+    for stream in &mut [&mut stream1, &mut stream2] {
+        let event = next_event(stream).await;
+        assert_eq!(event["type"], "edit");
+        assert_eq!(event["text"], "Hello, edited");
+    }
+
+    // Either instance replays the whole history of the room
+    for port in PORTS {
+        let (mut stream, _) = connect_async(format!("ws://localhost:{}/chat/room1", port))
+            .await
+            .expect("Unable to connect");
+        let event = next_event(&mut stream).await;
+        assert_eq!(event["id"], first["id"]);
+        assert_eq!(event["text"], "Hello, edited");
+        let event = next_event(&mut stream).await;
+        assert_eq!(event["id"], second["id"]);
+    }
+
+    for db_path in &db_paths {
+        remove_db(db_path);
+    }
+}
+
+#[tokio::test]
+// Tests that a room hosted by one server can be linked to by another, whose
+// users then chat with those of the hosting server.
+async fn federated_room() {
+    const HOST_PORT: u16 = 3098;
+    const REMOTE_PORT: u16 = 3099;
+
+    let host_db_path = PathBuf::from("./main_federated_room_host.db");
+    let remote_db_path = PathBuf::from("./main_federated_room_remote.db");
+    let host_config = Config {
+        federation_peers: vec!["remote=s3cret".parse().unwrap()],
+        ..Config::new(HOST_PORT, host_db_path.clone())
+    };
+    let remote_config = Config {
+        federated_rooms: vec![format!("lobby=ws://127.0.0.1:{}", HOST_PORT)
+            .parse()
+            .unwrap()],
+        federation_token: Some(String::from("s3cret")),
+        ..Config::new(REMOTE_PORT, remote_db_path.clone())
+    };
+    tokio::task::spawn(async move {
+        server::run_with_config(host_config).await;
+    });
+    wait_for_server(HOST_PORT).await;
+
+    // Servers need one of the secrets of the host to link its rooms
+    let (status, _) = http_request(
+        HOST_PORT,
+        "GET",
+        "/federation/rooms/lobby",
+        &[
+            ("Authorization", "Bearer wrong"),
+            ("Connection", "Upgrade"),
+            ("Upgrade", "websocket"),
+            ("Sec-WebSocket-Version", "13"),
+            ("Sec-WebSocket-Key", "dGhlIHNhbXBsZSBub25jZQ=="),
+        ],
+        None,
+    )
+    .await;
+    assert_eq!(status, 401);
+
+    // The room exists on the host once someone joined it
+    let (mut host_user, _) = connect_async(format!("ws://localhost:{}/chat/lobby", HOST_PORT))
+        .await
+        .expect("Unable to connect");
+    tokio::task::spawn(async move {
+        server::run_with_config(remote_config).await;
+    });
+    wait_for_server(REMOTE_PORT).await;
+    let (mut remote_user, _) = connect_async(format!("ws://localhost:{}/chat/lobby", REMOTE_PORT))
+        .await
+        .expect("Unable to connect");
+    // Wait for the remote server to link the room
+    tokio::time::sleep(Duration::from_secs(1)).await;
+
+    host_user
+        .send(Message::Text(String::from("Hello from the host")))
+        .await
+        .expect("Unable to send message");
+    assert_eq!(next_event(&mut host_user).await["type"], "ack");
+    let event = next_event(&mut remote_user).await;
+    assert_eq!(event["type"], "message");
+    assert_eq!(event["text"], "Hello from the host");
+
+    // Messages of the remote server's users are posted on the host, and
+    // relayed back
+    remote_user
+        .send(Message::Text(String::from("Hello from afar")))
+        .await
+        .expect("Unable to send message");
+    let event = next_event(&mut host_user).await;
+    assert_eq!(event["type"], "message");
+    assert_eq!(event["text"], "Hello from afar");
+    assert!(event["nick"].as_str().unwrap().ends_with("@remote"));
+    let event = next_event(&mut remote_user).await;
+    assert_eq!(event["text"], "Hello from afar");
+
+    remove_db(&host_db_path);
+    remove_db(&remote_db_path);
+}
+
+#[tokio::test]
+// Tests that IRC clients chat in rooms as channels.
+async fn irc_gateway() {
+    const PORT: u16 = 3100;
+    const IRC_PORT: u16 = 3101;
+
+    let db_path = PathBuf::from("./main_irc_gateway.db");
+    let config = Config {
+        irc_port: Some(IRC_PORT),
+        ..Config::new(PORT, db_path.clone())
+    };
+    tokio::task::spawn(async move {
+        server::run_with_config(config).await;
+    });
+    wait_for_server(PORT).await;
+    wait_for_server(IRC_PORT).await;
+
+    let (mut ws_user, _) = connect_async(format!("ws://localhost:{}/chat/lobby", PORT))
+        .await
+        .expect("Unable to connect");
+    wait_for_join().await;
+
+    let (reader, mut irc) = TcpStream::connect(("127.0.0.1", IRC_PORT))
+        .await
+        .expect("Unable to connect")
+        .into_split();
+    let mut irc_lines = BufReader::new(reader).lines();
+    irc.write_all(b"NICK tux\r\nUSER tux 0 * :Tux\r\nJOIN #lobby\r\n")
+        .await
+        .unwrap();
+
+    // Joining ends with the names of those in the room
+    let mut names = None;
+    loop {
+        let line = irc_lines.next_line().await.unwrap().unwrap();
+        if line.contains(" 353 ") {
+            names = Some(line.clone());
+        }
+        if line.contains(" 366 ") {
+            break;
+        }
+    }
+    assert!(names.unwrap().contains("tux"));
+
+    irc.write_all(b"PRIVMSG #lobby :Hello from IRC\r\n")
+        .await
+        .unwrap();
+    let event = next_event(&mut ws_user).await;
+    assert_eq!(event["type"], "message");
+    assert_eq!(event["nick"], "tux");
+    assert_eq!(event["text"], "Hello from IRC");
+
+    ws_user
+        .send(Message::Text(String::from("Hello from the web")))
+        .await
+        .expect("Unable to send message");
+    assert_eq!(next_event(&mut ws_user).await["type"], "ack");
+    let line = loop {
+        let line = irc_lines.next_line().await.unwrap().unwrap();
+        if line.contains("PRIVMSG") {
+            break line;
+        }
+    };
+    assert!(line.ends_with("PRIVMSG #lobby :Hello from the web"));
+
+    // Quitting leaves the room
+    irc.write_all(b"QUIT\r\n").await.unwrap();
+    let event = next_raw_event(&mut ws_user).await;
+    assert_eq!(event["type"], "leave");
+    assert_eq!(event["nick"], "tux");
+
+    remove_db(&db_path);
+}
+
+// Reads from `stream` into `buf` until it holds `needle`, returning what was
+// read up to the end of it.
+async fn read_until(stream: &mut TcpStream, buf: &mut String, needle: &str) -> String {
+    loop {
+        if let Some(found) = buf.find(needle) {
+            return buf.drain(..found + needle.len()).collect();
+        }
+
+        let mut chunk = [0; 4096];
+        let read = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut chunk))
+            .await
+            .expect("Timed out reading")
+            .unwrap();
+        assert!(read > 0, "Connection closed");
+        buf.push_str(std::str::from_utf8(&chunk[..read]).unwrap());
+    }
+}
+
+#[tokio::test]
+// Tests that rooms are bridged to an XMPP server as multi-user chat rooms.
+async fn xmpp_component() {
+    const PORT: u16 = 3102;
+    const XMPP_PORT: u16 = 3103;
+
+    // Stands in for the XMPP server the component connects to
+    let xmpp_server = tokio::net::TcpListener::bind(("127.0.0.1", XMPP_PORT))
+        .await
+        .unwrap();
+
+    let db_path = PathBuf::from("./main_xmpp_component.db");
+    let config = Config {
+        xmpp_component: Some(format!("127.0.0.1:{}", XMPP_PORT)),
+        xmpp_domain: Some(String::from("rooms.test")),
+        xmpp_secret: Some(String::from("sesame")),
+        ..Config::new(PORT, db_path.clone())
+    };
+    tokio::task::spawn(async move {
+        server::run_with_config(config).await;
+    });
+    wait_for_server(PORT).await;
+
+    let (mut xmpp, _) = xmpp_server.accept().await.unwrap();
+    let mut buf = String::new();
+    let header = read_until(&mut xmpp, &mut buf, "'rooms.test'>").await;
+    assert!(header.contains("xmlns='jabber:component:accept'"));
+    xmpp.write_all(b"<?xml version='1.0'?><stream:stream xmlns='jabber:component:accept' xmlns:stream='http://etherx.jabber.org/streams' from='rooms.test' id='s1'>")
+        .await
+        .unwrap();
+
+    // The component proves it knows the secret
+    let handshake = read_until(&mut xmpp, &mut buf, "</handshake>").await;
+    let proof = hex::encode(Sha1::digest(b"s1sesame"));
+    assert!(handshake.ends_with(&format!("<handshake>{}</handshake>", proof)));
+    xmpp.write_all(b"<handshake/>").await.unwrap();
+
+    let (mut ws_user, _) = connect_async(format!("ws://localhost:{}/chat/lobby", PORT))
+        .await
+        .expect("Unable to connect");
+    wait_for_join().await;
+
+    xmpp.write_all(b"<presence from='alice@example.org/phone' to='lobby@rooms.test/alice'><x xmlns='http://jabber.org/protocol/muc'/></presence>")
+        .await
+        .unwrap();
+    let presences = read_until(&mut xmpp, &mut buf, "code='110'/>").await;
+    assert!(presences.contains("from='lobby@rooms.test/alice'"));
+
+    xmpp.write_all(b"<message from='alice@example.org/phone' to='lobby@rooms.test' type='groupchat' id='m1'><body>Hello from XMPP</body></message>")
+        .await
+        .unwrap();
+    let event = next_event(&mut ws_user).await;
+    assert_eq!(event["type"], "message");
+    assert_eq!(event["nick"], "alice");
+    assert_eq!(event["text"], "Hello from XMPP");
+
+    ws_user
+        .send(Message::Text(String::from("Hello from the web")))
+        .await
+        .expect("Unable to send message");
+    assert_eq!(next_event(&mut ws_user).await["type"], "ack");
+    let message = read_until(&mut xmpp, &mut buf, "<body>Hello from the web</body>").await;
+    assert!(message.contains("to='alice@example.org/phone'"));
+
+    // Nicknames of registered users are reserved, even while they are offline
+    let credentials = json!({ "username": "bob", "password": "correct horse" });
+    http_request(PORT, "POST", "/users/register", &[], Some(credentials)).await;
+    xmpp.write_all(b"<presence from='mallory@example.org/pc' to='lobby@rooms.test/bob'><x xmlns='http://jabber.org/protocol/muc'/></presence>")
+        .await
+        .unwrap();
+    let refusal = read_until(&mut xmpp, &mut buf, "</presence>").await;
+    assert!(refusal.contains("type='error'"));
+    assert!(refusal.contains("<conflict"));
+
+    // Leaving the room as an occupant leaves the room
+    xmpp.write_all(b"<presence from='alice@example.org/phone' to='lobby@rooms.test/alice' type='unavailable'/>")
+        .await
+        .unwrap();
+    let event = next_raw_event(&mut ws_user).await;
+    assert_eq!(event["type"], "leave");
+    assert_eq!(event["nick"], "alice");
+
+    remove_db(&db_path);
+}
+
+#[tokio::test]
+// Tests that Slack incoming webhooks post to the room of their hook.
+async fn incoming_webhooks() {
+    const PORT: u16 = 3104;
+
+    let db_path = PathBuf::from("./main_incoming_webhooks.db");
+    let spawn_db_path = db_path.clone();
+    tokio::task::spawn(async move {
+        server::run(PORT, spawn_db_path).await;
+    });
+    wait_for_server(PORT).await;
+
+    let credentials = json!({ "username": "alice", "password": "correct horse" });
+    http_request(
+        PORT,
+        "POST",
+        "/users/register",
+        &[],
+        Some(credentials.clone()),
+    )
+    .await;
+    let (_, body) = http_request(PORT, "POST", "/users/login", &[], Some(credentials)).await;
+    let alice_jwt = format!("Bearer {}", body["token"].as_str().unwrap());
+
+    let (status, _) = http_request(
+        PORT,
+        "POST",
+        "/rooms",
+        &[("Authorization", &alice_jwt)],
+        Some(json!({ "name": "builds" })),
+    )
+    .await;
+    assert_eq!(status, 201);
+
+    // Only admins of the room can create hooks
+    let (status, _) = http_request(
+        PORT,
+        "POST",
+        "/rooms/builds/hooks",
+        &[],
+        Some(json!({ "name": "ci" })),
+    )
+    .await;
+    assert_eq!(status, 401);
+    let (status, body) = http_request(
+        PORT,
+        "POST",
+        "/rooms/builds/hooks",
+        &[("Authorization", &alice_jwt)],
+        Some(json!({ "name": "ci" })),
+    )
+    .await;
+    assert_eq!(status, 201);
+    let (hook_id, token) = (
+        body["id"].as_i64().unwrap(),
+        body["token"].as_str().unwrap(),
+    );
+    let hook_path = format!("/hooks/{}", token);
+
+    let (mut ws_user, _) = connect_async(format!("ws://localhost:{}/chat/builds", PORT))
+        .await
+        .expect("Unable to connect");
+    wait_for_join().await;
+
+    let (status, _, body) = http_request_raw(
+        PORT,
+        "POST",
+        &hook_path,
+        &[],
+        Some(json!({ "text": "Build <https://ci.example.org/42|#42> passed" })),
+    )
+    .await;
+    assert_eq!((status, body.as_str()), (200, "ok"));
+    let event = next_event(&mut ws_user).await;
+    assert_eq!(event["type"], "message");
+    assert_eq!(event["nick"], "ci");
+    assert_eq!(
+        event["text"],
+        "Build #42 (https://ci.example.org/42) passed"
+    );
+
+    // Payloads may name the bot differently
+    let (status, _, _) = http_request_raw(
+        PORT,
+        "POST",
+        &hook_path,
+        &[],
+        Some(json!({ "username": "deploy", "attachments": [{ "fallback": "Deployed" }] })),
+    )
+    .await;
+    assert_eq!(status, 200);
+    let event = next_event(&mut ws_user).await;
+    assert_eq!(event["nick"], "deploy");
+    assert_eq!(event["text"], "Deployed");
+
+    // But not after another user
+    let (status, _, body) = http_request_raw(
+        PORT,
+        "POST",
+        &hook_path,
+        &[],
+        Some(json!({ "username": "Alice", "text": "Trust me" })),
+    )
+    .await;
+    assert_eq!((status, body.as_str()), (400, "username_taken"));
+
+    let (status, _, body) =
+        http_request_raw(PORT, "POST", &hook_path, &[], Some(json!({ "text": "" }))).await;
+    assert_eq!((status, body.as_str()), (400, "no_text"));
+
+    // Revoked hooks no longer post
+    let (status, _) = http_request(
+        PORT,
+        "DELETE",
+        &format!("/rooms/builds/hooks/{}", hook_id),
+        &[("Authorization", &alice_jwt)],
+        None,
+    )
+    .await;
+    assert_eq!(status, 204);
+    let (status, _, body) = http_request_raw(
+        PORT,
+        "POST",
+        &hook_path,
+        &[],
+        Some(json!({ "text": "Build failed" })),
+    )
+    .await;
+    assert_eq!((status, body.as_str()), (403, "invalid_token"));
+
+    remove_db(&db_path);
+}
+
+#[tokio::test]
+// Tests that Discord channels are mirrored to rooms, without relaying messages
+// back where they came from.
+async fn discord_relay() {
+    const PORT: u16 = 3105;
+    const DISCORD_PORT: u16 = 3106;
+
+    // Stands in for the Discord API: messages queued in `channel` are listed
+    // once, and those the bot posts are sent through `posted_tx`
+    let channel = std::sync::Arc::new(std::sync::Mutex::new(Vec::<Value>::new()));
+    let (polled_tx, mut polled_rx) = tokio::sync::mpsc::unbounded_channel();
+    let (posted_tx, mut posted_rx) = tokio::sync::mpsc::unbounded_channel();
+    let me = warp::path!("users" / "@me")
+        .and(warp::get())
+        .map(|| warp::reply::json(&json!({ "id": "1", "username": "bridge" })));
+    let list_channel = channel.clone();
+    let messages = warp::path!("channels" / "42" / "messages")
+        .and(warp::get())
+        .and(warp::header::<String>("authorization"))
+        .map(move |authorization: String| {
+            assert_eq!(authorization, "Bot b0t");
+            let _ = polled_tx.send(());
+            let listed: Vec<Value> = list_channel.lock().unwrap().drain(..).rev().collect();
+            warp::reply::json(&listed)
+        });
+    let post = warp::path!("channels" / "42" / "messages")
+        .and(warp::post())
+        .and(warp::body::json())
+        .map(move |body: Value| {
+            let _ = posted_tx.send(body);
+            warp::reply::json(&json!({ "id": "100" }))
+        });
+    tokio::task::spawn(warp::serve(me.or(messages).or(post)).run(([127, 0, 0, 1], DISCORD_PORT)));
+
+    let db_path = PathBuf::from("./main_discord_relay.db");
+    let config = Config {
+        discord_channels: vec!["lobby=42".parse().unwrap()],
+        discord_token: Some(String::from("b0t")),
+        discord_api_url: format!("http://127.0.0.1:{}", DISCORD_PORT),
+        discord_poll_ms: 50,
+        ..Config::new(PORT, db_path.clone())
+    };
+    tokio::task::spawn(async move {
+        server::run_with_config(config).await;
+    });
+    wait_for_server(PORT).await;
+
+    let (mut ws_user, _) = connect_async(format!("ws://localhost:{}/chat/lobby?nick=bob", PORT))
+        .await
+        .expect("Unable to connect");
+    wait_for_join().await;
+
+    // Only what is posted once the bridge started is relayed
+    polled_rx.recv().await.unwrap();
+    channel.lock().unwrap().push(json!({
+        "id": "200",
+        "content": "Hello from Discord",
+        "author": { "id": "7", "username": "alice_1", "global_name": "Alice" },
+    }));
+    let event = next_event(&mut ws_user).await;
+    assert_eq!(event["type"], "message");
+    assert_eq!(event["nick"], "Alice@discord");
+    assert_eq!(event["text"], "Hello from Discord");
+
+    // What came from Discord is not posted back to it
+    ws_user
+        .send(Message::Text(String::from("Hello from the web")))
+        .await
+        .expect("Unable to send message");
+    assert_eq!(next_event(&mut ws_user).await["type"], "ack");
+    let posted = posted_rx.recv().await.unwrap();
+    assert_eq!(posted["content"], "**bob**: Hello from the web");
+    assert_eq!(posted["allowed_mentions"]["parse"], json!([]));
+
+    // Nor are the bot's own messages relayed to the room again
+    channel.lock().unwrap().push(json!({
+        "id": "201",
+        "content": "**bob**: Hello from the web",
+        "author": { "id": "1", "username": "bridge" },
+    }));
+    channel.lock().unwrap().push(json!({
+        "id": "202",
+        "content": "Bye",
+        "author": { "id": "7", "username": "alice_1", "global_name": "Alice" },
+    }));
+    let event = next_event(&mut ws_user).await;
+    assert_eq!(event["text"], "Bye");
+
+    remove_db(&db_path);
+}
+
+#[tokio::test]
+// Tests that mail sent to rooms is posted to them, and that digests of rooms
+// are mailed.
+async fn email_gateway() {
+    const PORT: u16 = 3107;
+    const MAIL_PORT: u16 = 3108;
+    const SMTP_PORT: u16 = 3109;
+
+    // Stands in for the mail server digests are sent through: the mail it is
+    // given is sent through `mailed_tx`
+    let (mailed_tx, mut mailed_rx) = tokio::sync::mpsc::unbounded_channel();
+    let smtp = tokio::net::TcpListener::bind(("127.0.0.1", SMTP_PORT))
+        .await
+        .unwrap();
+    tokio::task::spawn(async move {
+        while let Ok((stream, _)) = smtp.accept().await {
+            let mailed_tx = mailed_tx.clone();
+            tokio::task::spawn(async move {
+                let (reader, mut writer) = stream.into_split();
+                let mut lines = BufReader::new(reader).lines();
+                writer.write_all(b"220 sink\r\n").await.unwrap();
+                let mut data: Option<String> = None;
+                while let Ok(Some(line)) = lines.next_line().await {
+                    let reply = match data.as_mut() {
+                        Some(_) if line == "." => {
+                            let _ = mailed_tx.send(data.take().unwrap());
+                            "250 OK"
+                        }
+                        Some(data) => {
+                            data.push_str(&line);
+                            data.push('\n');
+                            continue;
+                        }
+                        None if line == "DATA" => {
+                            data = Some(String::new());
+                            "354 Go ahead"
+                        }
+                        None if line == "QUIT" => "221 Bye",
+                        None => "250 OK",
+                    };
+                    writer
+                        .write_all(format!("{}\r\n", reply).as_bytes())
+                        .await
+                        .unwrap();
+                }
+            });
+        }
+    });
+
+    let db_path = PathBuf::from("./main_email_gateway.db");
+    let config = Config {
+        mail_port: Some(MAIL_PORT),
+        mail_domain: Some(String::from("chat.example.org")),
+        mail_senders: vec![String::from("@example.org")],
+        mail_digests: vec!["builds=team@example.org".parse().unwrap()],
+        mail_digest_secs: 1,
+        smtp_url: Some(format!("smtp://127.0.0.1:{}", SMTP_PORT)),
+        smtp_from: Some(String::from("bi-chat@example.org")),
+        ..Config::new(PORT, db_path.clone())
+    };
+    tokio::task::spawn(async move {
+        server::run_with_config(config).await;
+    });
+    wait_for_server(PORT).await;
+
+    let credentials = json!({ "username": "alice", "password": "correct horse" });
+    http_request(
+        PORT,
+        "POST",
+        "/users/register",
+        &[],
+        Some(credentials.clone()),
+    )
+    .await;
+    let (_, body) = http_request(PORT, "POST", "/users/login", &[], Some(credentials)).await;
+    let alice_jwt = format!("Bearer {}", body["token"].as_str().unwrap());
+    let (status, _) = http_request(
+        PORT,
+        "POST",
+        "/rooms",
+        &[("Authorization", &alice_jwt)],
+        Some(json!({ "name": "builds" })),
+    )
+    .await;
+    assert_eq!(status, 201);
+    let (status, _) = http_request(
+        PORT,
+        "POST",
+        "/rooms",
+        &[("Authorization", &alice_jwt)],
+        Some(json!({ "name": "vault", "visibility": "private" })),
+    )
+    .await;
+    assert_eq!(status, 201);
+
+    let (mut ws_user, _) = connect_async(format!("ws://localhost:{}/chat/builds", PORT))
+        .await
+        .expect("Unable to connect");
+    wait_for_join().await;
+
+    let mut mail = TcpStream::connect(("127.0.0.1", MAIL_PORT)).await.unwrap();
+    let mut buf = String::new();
+    assert!(read_until(&mut mail, &mut buf, "\r\n")
+        .await
+        .starts_with("220 "));
+    // Sends `line`, returning the last line of the reply: multiline replies
+    // continue with a `-` after their code
+    async fn command(mail: &mut TcpStream, buf: &mut String, line: &str) -> String {
+        mail.write_all(line.as_bytes()).await.unwrap();
+        let mut reply = read_until(mail, buf, "\r\n").await;
+        while reply.as_bytes()[3] == b'-' {
+            reply = read_until(mail, buf, "\r\n").await;
+        }
+        reply
+    }
+    assert!(command(&mut mail, &mut buf, "EHLO mx.example.org\r\n")
+        .await
+        .starts_with("250 "));
+    assert!(
+        command(&mut mail, &mut buf, "RCPT TO:<builds@chat.example.org>\r\n")
+            .await
+            .starts_with("503 ")
+    );
+    // Only allowed senders can mail
+    assert!(
+        command(&mut mail, &mut buf, "MAIL FROM:<mallory@evil.org>\r\n")
+            .await
+            .starts_with("550 ")
+    );
+    assert!(
+        command(&mut mail, &mut buf, "MAIL FROM:<alice@example.org>\r\n")
+            .await
+            .starts_with("250 ")
+    );
+    // Only rooms that exist, and that the sender may join, can be mailed
+    assert!(
+        command(&mut mail, &mut buf, "RCPT TO:<vault@chat.example.org>\r\n")
+            .await
+            .starts_with("550 ")
+    );
+    assert!(command(
+        &mut mail,
+        &mut buf,
+        "RCPT TO:<nowhere@chat.example.org>\r\n"
+    )
+    .await
+    .starts_with("550 "));
+    assert!(
+        command(&mut mail, &mut buf, "RCPT TO:<builds@elsewhere.org>\r\n")
+            .await
+            .starts_with("550 ")
+    );
+    assert!(
+        command(&mut mail, &mut buf, "RCPT TO:<Builds@Chat.Example.org>\r\n")
+            .await
+            .starts_with("250 ")
+    );
+    assert!(command(&mut mail, &mut buf, "DATA\r\n")
+        .await
+        .starts_with("354 "));
+    let reply = command(
+        &mut mail,
+        &mut buf,
+        "From: Alice Example <alice@example.org>\r\n\
+        To: builds@chat.example.org\r\n\
+        Subject: Status\r\n\
+        \r\n\
+        All green\r\n\
+        ..and counting\r\n\
+        \r\n\
+        -- \r\n\
+        Alice\r\n\
+        .\r\n",
+    )
+    .await;
+    assert!(reply.starts_with("250 "), "{}", reply);
+
+    // Mail must be from the sender of its envelope
+    for line in [
+        "MAIL FROM:<alice@example.org>\r\n",
+        "RCPT TO:<builds@chat.example.org>\r\n",
+    ] {
+        assert!(command(&mut mail, &mut buf, line).await.starts_with("250 "));
+    }
+    assert!(command(&mut mail, &mut buf, "DATA\r\n")
+        .await
+        .starts_with("354 "));
+    let reply = command(
+        &mut mail,
+        &mut buf,
+        "From: Bob <bob@example.org>\r\n\r\nIt was Alice\r\n.\r\n",
+    )
+    .await;
+    assert!(reply.starts_with("554 "), "{}", reply);
+    assert!(command(&mut mail, &mut buf, "QUIT\r\n")
+        .await
+        .starts_with("221 "));
+
+    let event = next_event(&mut ws_user).await;
+    assert_eq!(event["type"], "message");
+    assert_eq!(event["nick"], "Alice Example");
+    assert_eq!(event["text"], "Status\n\nAll green\n.and counting");
+
+    // What was sent to the room since the last digest is mailed, replies to it
+    // going to the room
+    let mailed = tokio::time::timeout(Duration::from_secs(5), mailed_rx.recv())
+        .await
+        .expect("Timed out waiting for digest")
+        .unwrap();
+    assert!(mailed.contains("To: team@example.org"), "{}", mailed);
+    assert!(
+        mailed.contains("Reply-To: builds@chat.example.org"),
+        "{}",
+        mailed
+    );
+    assert!(
+        mailed.contains("Subject: New messages in builds"),
+        "{}",
+        mailed
+    );
+    assert!(mailed.contains("Alice Example: Status"), "{}", mailed);
+
+    remove_db(&db_path);
+}
+
+#[tokio::test]
+// Tests that messages sent over HTTP are relayed to the room and persisted.
+async fn post_message_over_http() {
+    const PORT: u16 = 3110;
+
+    let db_path = PathBuf::from("./main_post_message_over_http.db");
+    let config = Config {
+        max_duplicate_messages: 1,
+        ..Config::new(PORT, db_path.clone())
+    };
+    tokio::task::spawn(async move {
+        server::run_with_config(config).await;
+    });
+    wait_for_server(PORT).await;
+
+    let credentials = json!({ "username": "alice", "password": "correct horse" });
+    http_request(
+        PORT,
+        "POST",
+        "/users/register",
+        &[],
+        Some(credentials.clone()),
+    )
+    .await;
+    let (_, body) = http_request(PORT, "POST", "/users/login", &[], Some(credentials)).await;
+    let alice_jwt = format!("Bearer {}", body["token"].as_str().unwrap());
+    let alice_id = body["user_id"].clone();
+    let (status, _) = http_request(
+        PORT,
+        "POST",
+        "/rooms",
+        &[("Authorization", &alice_jwt)],
+        Some(json!({ "name": "ops" })),
+    )
+    .await;
+    assert_eq!(status, 201);
+
+    let (mut ws_user, _) = connect_async(format!("ws://localhost:{}/chat/ops", PORT))
+        .await
+        .expect("Unable to connect");
+    wait_for_join().await;
+
+    let (status, _) = http_request(
+        PORT,
+        "POST",
+        "/rooms/ops/messages",
+        &[],
+        Some(json!({ "text": "Deploy done" })),
+    )
+    .await;
+    assert_eq!(status, 401);
+    let (status, _) = http_request(
+        PORT,
+        "POST",
+        "/rooms/elsewhere/messages",
+        &[("Authorization", &alice_jwt)],
+        Some(json!({ "text": "Deploy done" })),
+    )
+    .await;
+    assert_eq!(status, 404);
+    let (status, _) = http_request(
+        PORT,
+        "POST",
+        "/rooms/ops/messages",
+        &[("Authorization", &alice_jwt)],
+        Some(json!({ "text": " " })),
+    )
+    .await;
+    assert_eq!(status, 400);
+
+    let (status, body) = http_request(
+        PORT,
+        "POST",
+        "/rooms/ops/messages",
+        &[("Authorization", &alice_jwt)],
+        Some(json!({ "text": "Deploy done" })),
+    )
+    .await;
+    assert_eq!(status, 201);
+    assert_eq!(body["room"], "ops");
+    let event = next_event(&mut ws_user).await;
+    assert_eq!(event["type"], "message");
+    assert_eq!(event["id"], body["id"]);
+    assert_eq!(event["seq"], body["seq"]);
+    assert_eq!(event["user_id"], alice_id);
+    assert_eq!(event["text"], "Deploy done");
+
+    // Messages refused, here as duplicates, are forbidden
+    let (status, refused) = http_request(
+        PORT,
+        "POST",
+        "/rooms/ops/messages",
+        &[("Authorization", &alice_jwt)],
+        Some(json!({ "text": "Deploy done" })),
+    )
+    .await;
+    assert_eq!(status, 403);
+    assert!(refused["error"]
+        .as_str()
+        .unwrap()
+        .starts_with("You already sent this message"));
+
+    let (status, history) = http_request(
+        PORT,
+        "GET",
+        "/rooms/ops/messages",
+        &[("Authorization", &alice_jwt)],
+        None,
+    )
+    .await;
+    assert_eq!(status, 200);
+    assert_eq!(history["messages"][0]["id"], body["id"]);
+    assert_eq!(history["messages"][0]["text"], "Deploy done");
+
+    remove_db(&db_path);
+}
+
+#[tokio::test]
+// Tests that rooms can be read as server-sent events, which join the room
+// until they go away.
+async fn room_event_stream() {
+    const PORT: u16 = 3111;
+
+    let db_path = PathBuf::from("./main_room_event_stream.db");
+    let spawn_db_path = db_path.clone();
+    tokio::task::spawn(async move {
+        server::run(PORT, spawn_db_path).await;
+    });
+    wait_for_server(PORT).await;
+
+    let (mut ws_user, _) = connect_async(format!("ws://localhost:{}/chat/lobby?nick=bob", PORT))
+        .await
+        .expect("Unable to connect");
+    wait_for_join().await;
+
+    let mut stream = TcpStream::connect(("127.0.0.1", PORT)).await.unwrap();
+    stream
+        .write_all(
+            format!(
+                "GET /rooms/lobby/stream HTTP/1.1\r\nHost: localhost:{}\r\nAccept: text/event-stream\r\n\r\n",
+                PORT
+            )
+            .as_bytes(),
+        )
+        .await
+        .unwrap();
+    let mut buf = String::new();
+    let head = read_until(&mut stream, &mut buf, "\r\n\r\n").await;
+    assert!(head.starts_with("HTTP/1.1 200"), "{}", head);
+    assert!(head.contains("content-type: text/event-stream"), "{}", head);
+
+    // Readers join the room like any other connection
+    let mut event = next_raw_event(&mut ws_user).await;
+    while event["type"] != "join" || event["nick"] == "bob" {
+        event = next_raw_event(&mut ws_user).await;
+    }
+    let reader_id = event["user_id"].clone();
+
+    ws_user
+        .send(Message::Text(String::from("Hello, dashboards")))
+        .await
+        .expect("Unable to send message");
+    let ack = next_event(&mut ws_user).await;
+    assert_eq!(ack["type"], "ack");
+    read_until(&mut stream, &mut buf, "event:message\n").await;
+    let data = read_until(&mut stream, &mut buf, "\n").await;
+    let message: Value = serde_json::from_str(data.trim().strip_prefix("data:").unwrap()).unwrap();
+    assert_eq!(message["text"], "Hello, dashboards");
+    let id = read_until(&mut stream, &mut buf, "\n").await;
+    assert_eq!(id.trim(), format!("id:{}", ack["id"]));
+
+    // Going away leaves the room
+    drop(stream);
+    let mut event = next_raw_event(&mut ws_user).await;
+    while event["type"] != "leave" {
+        event = next_raw_event(&mut ws_user).await;
+    }
+    assert_eq!(event["user_id"], reader_id);
+
+    // Readers resuming from a message only get what came after it
+    ws_user
+        .send(Message::Text(String::from("While you were away")))
+        .await
+        .expect("Unable to send message");
+    assert_eq!(next_event(&mut ws_user).await["type"], "ack");
+    let mut stream = TcpStream::connect(("127.0.0.1", PORT)).await.unwrap();
+    stream
+        .write_all(
+            format!(
+                "GET /rooms/lobby/stream HTTP/1.1\r\nHost: localhost:{}\r\nLast-Event-ID: {}\r\n\r\n",
+                PORT, ack["id"]
+            )
+            .as_bytes(),
+        )
+        .await
+        .unwrap();
+    let mut buf = String::new();
+    read_until(&mut stream, &mut buf, "\r\n\r\n").await;
+    let data = read_until(&mut stream, &mut buf, "event:join\n").await;
+    assert!(data.contains("While you were away"), "{}", data);
+    assert!(!data.contains("Hello, dashboards"), "{}", data);
+
+    let (status, _) =
+        http_request(PORT, "GET", "/rooms/lobby/stream?token=invalid", &[], None).await;
+    assert_eq!(status, 401);
+
+    remove_db(&db_path);
+}
+
+#[tokio::test]
+// Tests that rooms, their messages and users can be queried over GraphQL, and
+// messages followed with subscriptions.
+async fn graphql_api() {
+    const PORT: u16 = 3112;
+
+    let db_path = PathBuf::from("./main_graphql_api.db");
+    let spawn_db_path = db_path.clone();
+    tokio::task::spawn(async move {
+        server::run(PORT, spawn_db_path).await;
+    });
+    wait_for_server(PORT).await;
+
+    let credentials = json!({ "username": "alice", "password": "correct horse" });
+    http_request(
+        PORT,
+        "POST",
+        "/users/register",
+        &[],
+        Some(credentials.clone()),
+    )
+    .await;
+    let (_, body) = http_request(PORT, "POST", "/users/login", &[], Some(credentials)).await;
+    let alice_token = String::from(body["token"].as_str().unwrap());
+    let alice_jwt = format!("Bearer {}", alice_token);
+    let alice_id = body["user_id"].clone();
+    let (status, _) = http_request(
+        PORT,
+        "POST",
+        "/rooms",
+        &[("Authorization", &alice_jwt)],
+        Some(json!({ "name": "ops" })),
+    )
+    .await;
+    assert_eq!(status, 201);
+
+    let (mut ws_user, _) = connect_async(format!("ws://localhost:{}/chat/ops?nick=bob", PORT))
+        .await
+        .expect("Unable to connect");
+    wait_for_join().await;
+    ws_user
+        .send(Message::Text(String::from("Deploy done")))
+        .await
+        .expect("Unable to send message");
+    assert_eq!(next_event(&mut ws_user).await["type"], "ack");
+
+    let query = r#"{
+        rooms { name }
+        room(name: "ops") {
+            createdBy { id }
+            messages { messages { text nick userId } }
+        }
+    }"#;
+    let (status, body) = http_request(
+        PORT,
+        "POST",
+        "/graphql",
+        &[("Authorization", &alice_jwt)],
+        Some(json!({ "query": query })),
+    )
+    .await;
+    assert_eq!(status, 200);
+    assert!(body["errors"].is_null(), "{}", body);
+    assert!(body["data"]["rooms"]
+        .as_array()
+        .unwrap()
+        .contains(&json!({ "name": "ops" })));
+    let room = &body["data"]["room"];
+    assert_eq!(room["createdBy"]["id"], alice_id);
+    assert_eq!(room["messages"]["messages"][0]["text"], "Deploy done");
+    assert_eq!(room["messages"]["messages"][0]["nick"], "bob");
+    let bob_id = room["messages"]["messages"][0]["userId"].clone();
+
+    // Messages are only read when logged in
+    let (status, body) = http_request(
+        PORT,
+        "POST",
+        "/graphql",
+        &[],
+        Some(json!({ "query": query })),
+    )
+    .await;
+    assert_eq!(status, 200);
+    assert_eq!(body["errors"][0]["message"], "Log in to see this");
+
+    // Rooms are only read by users who may join them
+    let credentials = json!({ "username": "carol", "password": "correct horse" });
+    http_request(
+        PORT,
+        "POST",
+        "/users/register",
+        &[],
+        Some(credentials.clone()),
+    )
+    .await;
+    let (_, body) = http_request(PORT, "POST", "/users/login", &[], Some(credentials)).await;
+    let carol_jwt = format!("Bearer {}", body["token"].as_str().unwrap());
+    let (status, _) = http_request(
+        PORT,
+        "POST",
+        "/rooms",
+        &[("Authorization", &alice_jwt)],
+        Some(json!({ "name": "vault", "visibility": "private" })),
+    )
+    .await;
+    assert_eq!(status, 201);
+    let vault_query = json!({ "query": r#"{ room(name: "vault") { name owners { id } } }"# });
+    let (_, body) = http_request(
+        PORT,
+        "POST",
+        "/graphql",
+        &[("Authorization", &carol_jwt)],
+        Some(vault_query.clone()),
+    )
+    .await;
+    assert_eq!(body["errors"][0]["message"], "Not allowed in this room");
+    assert!(body["data"]["room"].is_null());
+    let (_, body) = http_request(
+        PORT,
+        "POST",
+        "/graphql",
+        &[("Authorization", &alice_jwt)],
+        Some(vault_query),
+    )
+    .await;
+    assert_eq!(body["data"]["room"]["owners"][0]["id"], alice_id);
+
+    let (status, _) = http_request(
+        PORT,
+        "POST",
+        "/graphql",
+        &[("Authorization", "Bearer invalid")],
+        Some(json!({ "query": query })),
+    )
+    .await;
+    assert_eq!(status, 401);
+
+    let mut request = format!("ws://localhost:{}/graphql", PORT)
+        .into_client_request()
+        .unwrap();
+    request.headers_mut().insert(
+        "Sec-WebSocket-Protocol",
+        "graphql-transport-ws".parse().unwrap(),
+    );
+    let (mut subscriber, _) = connect_async(request).await.expect("Unable to connect");
+    subscriber
+        .send(Message::Text(
+            json!({ "type": "connection_init", "payload": { "token": alice_token } }).to_string(),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(
+        next_raw_event(&mut subscriber).await["type"],
+        "connection_ack"
+    );
+    subscriber
+        .send(Message::Text(
+            json!({
+                "type": "subscribe",
+                "id": "1",
+                "payload": { "query": "subscription { messages(room: \"ops\") { text author { id } } }" },
+            })
+            .to_string(),
+        ))
+        .await
+        .unwrap();
+
+    // Subscribers join the room like any other connection
+    let mut event = next_raw_event(&mut ws_user).await;
+    while event["type"] != "join" || event["user_id"] != alice_id {
+        event = next_raw_event(&mut ws_user).await;
+    }
+
+    ws_user
+        .send(Message::Text(String::from("Rolling back")))
+        .await
+        .expect("Unable to send message");
+    let event = next_raw_event(&mut subscriber).await;
+    assert_eq!(event["type"], "next");
+    assert_eq!(event["id"], "1");
+    let message = &event["payload"]["data"]["messages"];
+    assert_eq!(message["text"], "Rolling back");
+    assert_eq!(message["author"]["id"], bob_id);
+
+    // Unsubscribing leaves the room
+    subscriber
+        .send(Message::Text(
+            json!({ "type": "complete", "id": "1" }).to_string(),
+        ))
+        .await
+        .unwrap();
+    let mut event = next_raw_event(&mut ws_user).await;
+    while event["type"] != "leave" {
+        event = next_raw_event(&mut ws_user).await;
+    }
+    assert_eq!(event["user_id"], alice_id);
+
+    remove_db(&db_path);
+}
+
+#[tokio::test]
+// Tests that services chat in rooms over gRPC as WebSocket connections do.
+async fn grpc_gateway() {
+    const PORT: u16 = 3113;
+    const GRPC_PORT: u16 = 3114;
+
+    let db_path = PathBuf::from("./main_grpc_gateway.db");
+    let config = Config {
+        grpc_port: Some(GRPC_PORT),
+        ..Config::new(PORT, db_path.clone())
+    };
+    tokio::task::spawn(async move {
+        server::run_with_config(config).await;
+    });
+    wait_for_server(PORT).await;
+    wait_for_server(GRPC_PORT).await;
+
+    let (mut ws_user, _) = connect_async(format!("ws://localhost:{}/chat/lobby?nick=bob", PORT))
+        .await
+        .expect("Unable to connect");
+    wait_for_join().await;
+
+    let channel = tonic::transport::Channel::from_static("http://127.0.0.1:3114")
+        .connect()
+        .await
+        .expect("Unable to connect over gRPC");
+    let mut client = ChatClient::new(channel);
+    let (frames_tx, frames_rx) = tokio::sync::mpsc::unbounded_channel();
+    let mut request = tonic::Request::new(UnboundedReceiverStream::new(frames_rx));
+    request
+        .metadata_mut()
+        .insert("room", "lobby".parse().unwrap());
+    request
+        .metadata_mut()
+        .insert("nick", "relay".parse().unwrap());
+    let mut frames = client.chat(request).await.unwrap().into_inner();
+
+    // Reads the next event sent over gRPC that `pick` picks
+    async fn next_grpc_event<T>(
+        frames: &mut tonic::Streaming<ServerFrame>,
+        pick: impl Fn(server_event::Event) -> Option<T>,
+    ) -> T {
+        loop {
+            let frame = tokio::time::timeout(Duration::from_secs(5), frames.message())
+                .await
+                .expect("Timed out waiting for a frame")
+                .unwrap()
+                .expect("Stream ended");
+            if let Some(Frame::Event(ServerEvent { event: Some(event) })) = frame.frame {
+                if let Some(picked) = pick(event) {
+                    return picked;
+                }
+            }
+        }
+    }
+
+    // Calls join the room like any other connection
+    let mut event = next_raw_event(&mut ws_user).await;
+    while event["type"] != "join" || event["nick"] != "relay" {
+        event = next_raw_event(&mut ws_user).await;
+    }
+
+    frames_tx
+        .send(ClientFrame {
+            room: None,
+            frame: Some(client_frame::Frame::Text(String::from(
+                "Hello from a service",
+            ))),
+        })
+        .unwrap();
+    let ack = next_grpc_event(&mut frames, |event| match event {
+        server_event::Event::Ack(ack) => Some(ack),
+        _ => None,
+    })
+    .await;
+    let event = next_event(&mut ws_user).await;
+    assert_eq!(event["type"], "message");
+    assert_eq!(event["id"], ack.id);
+    assert_eq!(event["nick"], "relay");
+    assert_eq!(event["text"], "Hello from a service");
+
+    ws_user
+        .send(Message::Text(String::from("Hello back")))
+        .await
+        .expect("Unable to send message");
+    let message = next_grpc_event(&mut frames, |event| match event {
+        server_event::Event::Message(message) => Some(message),
+        _ => None,
+    })
+    .await;
+    assert_eq!(message.nick.as_deref(), Some("bob"));
+    assert_eq!(message.text, "Hello back");
+
+    // Ending the call leaves the room
+    drop(frames_tx);
+    let mut event = next_raw_event(&mut ws_user).await;
+    while event["type"] != "leave" {
+        event = next_raw_event(&mut ws_user).await;
+    }
+
+    let (_, frames_rx) = tokio::sync::mpsc::unbounded_channel::<ClientFrame>();
+    let mut request = tonic::Request::new(UnboundedReceiverStream::new(frames_rx));
+    request
+        .metadata_mut()
+        .insert("authorization", "Bearer invalid".parse().unwrap());
+    let status = client.chat(request).await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::Unauthenticated);
+
+    remove_db(&db_path);
+}
+
+#[tokio::test]
+// Tests that connections may exchange MessagePack or protobuf frames instead of
+// JSON ones.
+async fn binary_frames() {
+    const PORT: u16 = 3115;
+
+    let db_path = PathBuf::from("./main_binary_frames.db");
+    let spawn_db_path = db_path.clone();
+    tokio::task::spawn(async move {
+        server::run(PORT, spawn_db_path).await;
+    });
+    wait_for_server(PORT).await;
+
+    // Reads the next MessagePack event of `type`
+    async fn next_msgpack_event<S>(stream: &mut S, kind: &str) -> Value
+    where
+        S: Stream<Item = Result<Message, tungstenite::Error>> + Unpin,
+    {
+        loop {
+            let msg = stream.next().await.expect("No value found!").unwrap();
+            assert!(msg.is_binary(), "{:?}", msg);
+            let event: Value = rmp_serde::from_slice(&msg.into_data()).expect("Invalid event");
+            if event["type"] == kind {
+                return event;
+            }
+        }
+    }
+
+    let (mut ws_user, _) = connect_async(format!("ws://localhost:{}/chat/lobby?nick=bob", PORT))
+        .await
+        .expect("Unable to connect");
+    wait_for_join().await;
+    let (mut bot, _) = connect_async(format!(
+        "ws://localhost:{}/chat/lobby?nick=bot&format=msgpack",
+        PORT
+    ))
+    .await
+    .expect("Unable to connect");
+    let mut event = next_raw_event(&mut ws_user).await;
+    while event["type"] != "join" || event["nick"] != "bot" {
+        event = next_raw_event(&mut ws_user).await;
+    }
+
+    // MessagePack strings are plain messages, and other values frames
+    bot.send(Message::Binary(rmp_serde::to_vec("Beep").unwrap()))
+        .await
+        .unwrap();
+    let ack = next_msgpack_event(&mut bot, "ack").await;
+    let event = next_event(&mut ws_user).await;
+    assert_eq!(event["type"], "message");
+    assert_eq!(event["id"], ack["id"]);
+    assert_eq!(event["text"], "Beep");
+    let frame = json!({ "type": "edit", "id": ack["id"], "text": "Boop" });
+    bot.send(Message::Binary(rmp_serde::to_vec_named(&frame).unwrap()))
+        .await
+        .unwrap();
+    let event = next_event(&mut ws_user).await;
+    assert_eq!(event["type"], "edit");
+    assert_eq!(event["text"], "Boop");
+
+    ws_user
+        .send(Message::Text(String::from("Hello, bot")))
+        .await
+        .expect("Unable to send message");
+    let message = next_msgpack_event(&mut bot, "message").await;
+    assert_eq!(message["nick"], "bob");
+    assert_eq!(message["text"], "Hello, bot");
+
+    // MessagePack may also be asked for as a subprotocol
+    let mut request = format!("ws://localhost:{}/chat/lobby", PORT)
+        .into_client_request()
+        .unwrap();
+    request
+        .headers_mut()
+        .insert("Sec-WebSocket-Protocol", "bi-chat.msgpack".parse().unwrap());
+    let (mut bot2, response) = connect_async(request).await.expect("Unable to connect");
+    assert_eq!(
+        response.headers()["sec-websocket-protocol"],
+        "bi-chat.msgpack"
+    );
+    let mut event = next_raw_event(&mut ws_user).await;
+    while event["type"] != "join" {
+        event = next_raw_event(&mut ws_user).await;
+    }
+    ws_user
+        .send(Message::Text(String::from("Hello again")))
+        .await
+        .expect("Unable to send message");
+    // History is replayed first
+    let mut message = next_msgpack_event(&mut bot2, "message").await;
+    while message["text"] != "Hello again" {
+        message = next_msgpack_event(&mut bot2, "message").await;
+    }
+    assert_eq!(message["nick"], "bob");
+
+    // Protobuf connections exchange typed frames and events
+    let (mut client, _) = connect_async(format!(
+        "ws://localhost:{}/chat/lobby?nick=typed&format=protobuf",
+        PORT
+    ))
+    .await
+    .expect("Unable to connect");
+    let mut event = next_raw_event(&mut ws_user).await;
+    while event["type"] != "join" || event["nick"] != "typed" {
+        event = next_raw_event(&mut ws_user).await;
+    }
+    let frame = ClientFrame {
+        room: None,
+        frame: Some(client_frame::Frame::Message(client_frame::Message {
+            text: String::from("Typed hello"),
+            client_id: Some(String::from("c1")),
+            ttl_secs: None,
+        })),
+    };
+    client
+        .send(Message::Binary(frame.encode_to_vec()))
+        .await
+        .unwrap();
+    let event = next_event(&mut ws_user).await;
+    assert_eq!(event["type"], "message");
+    assert_eq!(event["text"], "Typed hello");
+    let ack = loop {
+        let msg = client.next().await.expect("No value found!").unwrap();
+        let event = ServerEvent::decode(msg.into_data().as_slice()).expect("Invalid event");
+        if let Some(server_event::Event::Ack(ack)) = event.event {
+            break ack;
+        }
+    };
+    assert_eq!(Some(ack.id), event["id"].as_i64());
+    assert_eq!(ack.room, "lobby");
+    assert_eq!(ack.client_id.as_deref(), Some("c1"));
+
+    // Binary frames that fail to decode are refused, over JSON connections too
+    ws_user
+        .send(Message::Binary(vec![0xc1]))
+        .await
+        .expect("Unable to send message");
+    let event = next_event(&mut ws_user).await;
+    assert_eq!(event["type"], "error");
+    assert_eq!(event["room"], "lobby");
+    bot.send(Message::Binary(vec![0xc1])).await.unwrap();
+    let event = next_msgpack_event(&mut bot, "error").await;
+    assert_eq!(
+        event["reason"],
+        "Binary frames must be encoded in the format negotiated when connecting"
+    );
+
+    remove_db(&db_path);
+}
+
+#[tokio::test]
+async fn openapi_document() {
+    const PORT: u16 = 3116;
+
+    let db_path = PathBuf::from("./main_openapi_document.db");
+    let spawn_db_path = db_path.clone();
+    tokio::task::spawn(async move {
+        server::run(PORT, spawn_db_path).await;
+    });
+    wait_for_server(PORT).await;
+
+    let (status, document) = http_request(PORT, "GET", "/openapi.json", &[], None).await;
+    assert_eq!(status, 200);
+    assert!(document["openapi"].as_str().unwrap().starts_with("3."));
+    let messages = &document["paths"]["/rooms/{name}/messages"];
+    assert_eq!(messages["get"]["tags"], json!(["messages"]));
+    assert_eq!(
+        messages["post"]["requestBody"]["content"]["application/json"]["schema"]["$ref"],
+        "#/components/schemas/NewMessage"
+    );
+    assert_eq!(
+        messages["post"]["responses"]["401"]["content"]["application/json"]["schema"]["$ref"],
+        "#/components/schemas/ErrorReply"
+    );
+    let schemas = &document["components"]["schemas"];
+    assert_eq!(
+        schemas["NewMessage"]["required"],
+        json!(["text"]),
+        "{}",
+        schemas["NewMessage"]
+    );
+    assert_eq!(
+        document["paths"]["/admin/ip_bans"]["get"]["security"],
+        json!([{ "admin_bearer": [] }, { "admin_basic": [] }])
+    );
+    // WebSocket routes are left out
+    assert!(document["paths"]["/chat"].is_null());
+
+    // Swagger UI browses the document
+    let (status, headers, _) =
+        http_request_with_headers(PORT, "GET", "/swagger-ui", &[], None).await;
+    assert_eq!(status, 302);
+    assert!(headers.contains(&(String::from("location"), String::from("/swagger-ui/"))));
+    let (status, headers, body) = http_request_raw(PORT, "GET", "/swagger-ui/", &[], None).await;
+    assert_eq!(status, 200);
+    assert!(headers
+        .iter()
+        .any(|(name, value)| name == "content-type" && value.starts_with("text/html")));
+    assert!(body.contains("swagger-initializer.js"));
+    let (_, _, body) =
+        http_request_raw(PORT, "GET", "/swagger-ui/swagger-initializer.js", &[], None).await;
+    assert!(body.contains("/openapi.json"));
+
+    remove_db(&db_path);
 }